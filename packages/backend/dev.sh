@@ -3,8 +3,10 @@
 
 # Set environment variables for deployed DynamoDB tables
 export CHAT_ROOMS_TABLE="chat-rooms"
-export CHAT_MESSAGES_TABLE="chat-messages"
+export CHAT_MESSAGES_TABLE="chat-messages-v2"
 export CONNECTIONS_TABLE="chat-connections"
+export CHAT_USERS_TABLE="chat-users"
+export CHAT_FLAGS_TABLE="chat-flags"
 export AWS_REGION="us-east-1"
 export AWS_PROFILE="sb-beta"
 
@@ -23,6 +25,8 @@ echo "📊 DynamoDB Tables:"
 echo "   - Rooms: $CHAT_ROOMS_TABLE"
 echo "   - Messages: $CHAT_MESSAGES_TABLE"
 echo "   - Connections: $CONNECTIONS_TABLE"
+echo "   - Users: $CHAT_USERS_TABLE"
+echo "   - Flags: $CHAT_FLAGS_TABLE"
 echo "🌐 Region: $AWS_REGION"
 echo "👤 Profile: $AWS_PROFILE"
 if [ -n "$DEV_BROADCAST_URL" ]; then
@@ -0,0 +1,53 @@
+use std::{collections::HashSet, env};
+use tracing::{debug, info};
+
+/// Header carrying a request's id: the caller's when it sent one, otherwise one we assign.
+/// Echoed on the response so a client report can be matched to its access log line.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const DEFAULT_QUIET_PATHS: &str = "/health";
+
+/// One structured line per request at info level, with `method`, `path`, `status`, `latency_ms`
+/// and `request_id` fields. On unless `ACCESS_LOG=false`. Paths in `ACCESS_LOG_QUIET_PATHS`
+/// (default `/health`) log at debug instead, so load balancer probes don't drown out the rest.
+#[derive(Debug, Clone)]
+pub struct AccessLogger {
+    enabled: bool,
+    quiet_paths: HashSet<String>,
+}
+
+impl AccessLogger {
+    pub fn new(enabled: bool, quiet_paths: &str) -> Self {
+        let quiet_paths = quiet_paths
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self { enabled, quiet_paths }
+    }
+
+    pub fn from_env() -> Self {
+        let enabled = env::var("ACCESS_LOG")
+            .map(|value| !(value == "0" || value.eq_ignore_ascii_case("false")))
+            .unwrap_or(true);
+        let quiet_paths =
+            env::var("ACCESS_LOG_QUIET_PATHS").unwrap_or_else(|_| DEFAULT_QUIET_PATHS.to_string());
+        Self::new(enabled, &quiet_paths)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn log(&self, method: &str, path: &str, status: u16, latency_ms: u64, request_id: &str) {
+        if !self.enabled {
+            return;
+        }
+        if self.quiet_paths.contains(path) {
+            debug!(method, path, status, latency_ms, request_id, "request");
+        } else {
+            info!(method, path, status, latency_ms, request_id, "request");
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::{env, sync::LazyLock};
+
+/// Header carrying the admin token on admin-only endpoints
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+// Shared secret for admin-only endpoints; when unset they reject every request
+static ADMIN_TOKEN: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()));
+
+/// Whether the presented `X-Admin-Token` value matches `ADMIN_TOKEN`
+pub fn is_authorized(presented: Option<&str>) -> bool {
+    token_matches(ADMIN_TOKEN.as_deref(), presented)
+}
+
+fn token_matches(expected: Option<&str>, presented: Option<&str>) -> bool {
+    let (Some(expected), Some(presented)) = (expected, presented) else {
+        return false;
+    };
+    // Compare every byte so the response time doesn't reveal how much of the token matched
+    expected.len() == presented.len()
+        && expected.bytes().zip(presented.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_token_matching() {
+        assert!(token_matches(Some("s3cret"), Some("s3cret")));
+        assert!(!token_matches(Some("s3cret"), Some("s3cres")));
+        assert!(!token_matches(Some("s3cret"), Some("s3cret!")));
+        assert!(!token_matches(Some("s3cret"), None));
+        // No configured token disables admin endpoints entirely
+        assert!(!token_matches(None, Some("")));
+        assert!(!token_matches(None, None));
+    }
+}
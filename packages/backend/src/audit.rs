@@ -0,0 +1,216 @@
+use crate::{clock::Clock, identity::Identity, origin::OriginCapture};
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
+use std::{collections::HashMap, env, net::IpAddr, sync::Arc};
+use types::{ConnectionId, RoomId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    Connect,
+    Disconnect,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::Connect => "connect",
+            AuditEvent::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// One row of the connection audit log. Connection ids and IPs are only kept as salted hashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub event: AuditEvent,
+    pub user_id: String,
+    pub username: String,
+    pub room_id: String,
+    pub connection_id_hash: String,
+    // Epoch millis
+    pub ts: i64,
+    // Same form as a message's origin_hash; None when origin capture is off or the IP is unknown
+    pub ip_hash: Option<String>,
+}
+
+/// Append-only log of WebSocket connects and disconnects, for deployments that must retain one
+/// (`AUDIT_TABLE`). Rows are keyed by connection hash and event, written once and never given a
+/// TTL; the live connections table stays small and disposable. Hashes use `ORIGIN_HASH_SALT`,
+/// so an `ip_hash` can be matched against the origin of messages sent from the same network.
+pub struct AuditLog {
+    table: String,
+    hasher: OriginCapture,
+    // Stamps each record's `ts`
+    clock: Arc<dyn Clock>,
+}
+
+impl AuditLog {
+    pub fn new(table: String, hasher: OriginCapture, clock: Arc<dyn Clock>) -> Self {
+        Self { table, hasher, clock }
+    }
+
+    /// None when `AUDIT_TABLE` is unset or empty
+    pub fn from_env(clock: Arc<dyn Clock>) -> Option<Self> {
+        let table = env::var("AUDIT_TABLE").ok().filter(|table| !table.is_empty())?;
+        Some(Self::new(table, OriginCapture::from_env(), clock))
+    }
+
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        connection_id: &ConnectionId,
+        identity: &Identity,
+        room_id: &RoomId,
+        ip: Option<IpAddr>,
+    ) -> AuditRecord {
+        AuditRecord {
+            event,
+            user_id: identity.user_id.clone(),
+            username: identity.username.clone(),
+            room_id: room_id.to_string(),
+            connection_id_hash: self.hasher.keyed_hash(connection_id),
+            ts: self.clock.now().timestamp_millis(),
+            ip_hash: self.hasher.capture(ip).map(|origin| origin.hash),
+        }
+    }
+
+    /// Write the record. Existing rows are never overwritten.
+    pub async fn append(&self, ddb: &DynamoDbClient, record: &AuditRecord) -> Result<(), String> {
+        let mut item = HashMap::from([
+            (
+                "connection_id_hash".to_string(),
+                AttributeValue::S(record.connection_id_hash.clone()),
+            ),
+            ("event".to_string(), AttributeValue::S(record.event.as_str().to_string())),
+            ("user_id".to_string(), AttributeValue::S(record.user_id.clone())),
+            ("username".to_string(), AttributeValue::S(record.username.clone())),
+            ("room_id".to_string(), AttributeValue::S(record.room_id.clone())),
+            ("ts".to_string(), AttributeValue::N(record.ts.to_string())),
+        ]);
+        if let Some(ip_hash) = &record.ip_hash {
+            item.insert("ip_hash".to_string(), AttributeValue::S(ip_hash.clone()));
+        }
+
+        ddb.put_item()
+            .table_name(&self.table)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(connection_id_hash)")
+            .send()
+            .await
+            .map_err(|e| format!("Failed to write audit record: {:?}", e))?;
+        Ok(())
+    }
+}
+
+/// Record a connection event when auditing is configured; does nothing otherwise
+pub async fn audit_connection(
+    ddb: &DynamoDbClient,
+    log: Option<&AuditLog>,
+    event: AuditEvent,
+    connection_id: &ConnectionId,
+    identity: &Identity,
+    room_id: &RoomId,
+    ip: Option<IpAddr>,
+) -> Result<(), String> {
+    let Some(log) = log else {
+        return Ok(());
+    };
+    let record = log.record(event, connection_id, identity, room_id, ip);
+    log.append(ddb, &record).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock,
+        test_support::{create_table, local_ddb},
+    };
+    use aws_sdk_dynamodb::types::KeyType;
+
+    async fn audit_rows(ddb: &DynamoDbClient, table: &str) -> Vec<HashMap<String, AttributeValue>> {
+        ddb.scan().table_name(table).send().await.unwrap().items.unwrap_or_default()
+    }
+
+    fn alice() -> Identity {
+        Identity { user_id: "user-1".to_string(), username: "alice".to_string() }
+    }
+
+    fn attr<'a>(row: &'a HashMap<String, AttributeValue>, name: &str) -> Option<&'a str> {
+        row.get(name).and_then(|value| value.as_s().ok()).map(String::as_str)
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_connect_and_disconnect_are_audited() {
+        let ddb = local_ddb().await;
+        let table = "audit-test-connections";
+        create_table(
+            &ddb,
+            table,
+            &[("connection_id_hash", KeyType::Hash), ("event", KeyType::Range)],
+        )
+        .await;
+        let hasher = OriginCapture::new(true, "salt".to_string(), None);
+        let log = AuditLog::new(table.to_string(), hasher, clock::system());
+        let ip = "203.0.113.7".parse().ok();
+        let who = alice();
+        let (conn, room) = (ConnectionId::from("conn-1"), RoomId::from("general"));
+
+        for event in [AuditEvent::Connect, AuditEvent::Disconnect] {
+            audit_connection(&ddb, Some(&log), event, &conn, &who, &room, ip).await.unwrap();
+        }
+
+        let mut rows = audit_rows(&ddb, table).await;
+        rows.sort_by_key(|row| attr(row, "event").map(String::from));
+        let events: Vec<_> = rows.iter().map(|row| attr(row, "event")).collect();
+        assert_eq!(events, vec![Some("connect"), Some("disconnect")]);
+        for row in &rows {
+            assert_eq!(attr(row, "user_id"), Some("user-1"));
+            assert_eq!(attr(row, "username"), Some("alice"));
+            assert_eq!(attr(row, "room_id"), Some("general"));
+            assert_eq!(
+                attr(row, "connection_id_hash"),
+                Some(log.hasher.keyed_hash("conn-1").as_str())
+            );
+            assert_eq!(attr(row, "ip_hash"), log.hasher.capture(ip).map(|o| o.hash).as_deref());
+            assert!(row.contains_key("ts"));
+            assert!(!row.contains_key("ttl"));
+            // Raw identifiers are never written
+            assert!(!row
+                .values()
+                .any(|value| value.as_s().is_ok_and(|s| s == "conn-1" || s.contains("203.0.113"))));
+        }
+
+        // Audit rows are immutable
+        let repeat =
+            audit_connection(&ddb, Some(&log), AuditEvent::Connect, &conn, &who, &room, ip);
+        assert!(repeat.await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_nothing_is_audited_without_a_table() {
+        let ddb = local_ddb().await;
+        let table = "audit-test-unconfigured";
+        create_table(
+            &ddb,
+            table,
+            &[("connection_id_hash", KeyType::Hash), ("event", KeyType::Range)],
+        )
+        .await;
+        for event in [AuditEvent::Connect, AuditEvent::Disconnect] {
+            audit_connection(
+                &ddb,
+                None,
+                event,
+                &"conn-1".into(),
+                &alice(),
+                &"general".into(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        assert!(audit_rows(&ddb, table).await.is_empty());
+    }
+}
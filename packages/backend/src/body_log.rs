@@ -0,0 +1,165 @@
+use serde_json::Value;
+use std::{collections::HashSet, env};
+use tracing::debug;
+
+const REDACTED: &str = "[REDACTED]";
+const DEFAULT_REDACT_FIELDS: &str = "token,room_key,password,secret,authorization";
+const DEFAULT_MESSAGE_TEXT_CHARS: usize = 32;
+
+/// Debug-level logging of request/response bodies, enabled with `LOG_BODIES=true`. JSON fields
+/// named in `LOG_REDACT_FIELDS` are masked and `message_text` is cut to `LOG_MESSAGE_TEXT_CHARS`.
+/// Headers are never logged, so credentials in `Authorization` and friends stay out of the logs.
+#[derive(Debug, Clone)]
+pub struct BodyLogger {
+    enabled: bool,
+    redact_fields: HashSet<String>,
+    max_message_text_chars: usize,
+}
+
+impl BodyLogger {
+    pub fn new(enabled: bool, redact_fields: &str, max_message_text_chars: usize) -> Self {
+        let redact_fields = redact_fields
+            .split(',')
+            .map(|field| field.trim().to_ascii_lowercase())
+            .filter(|field| !field.is_empty())
+            .collect();
+        Self { enabled, redact_fields, max_message_text_chars }
+    }
+
+    pub fn from_env() -> Self {
+        let enabled = env::var("LOG_BODIES")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let redact_fields =
+            env::var("LOG_REDACT_FIELDS").unwrap_or_else(|_| DEFAULT_REDACT_FIELDS.to_string());
+        let max_message_text_chars = env::var("LOG_MESSAGE_TEXT_CHARS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MESSAGE_TEXT_CHARS);
+        Self::new(enabled, &redact_fields, max_message_text_chars)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn log_request(&self, method: &str, path: &str, body: &[u8]) {
+        if self.enabled {
+            debug!("Request body {} {}: {}", method, path, self.render(body));
+        }
+    }
+
+    pub fn log_response(&self, method: &str, path: &str, status: u16, body: &[u8]) {
+        if self.enabled {
+            debug!("Response body {} {} ({}): {}", method, path, status, self.render(body));
+        }
+    }
+
+    /// The body as it should appear in the log. Anything that isn't JSON is summarised by size
+    /// rather than echoed, since it can't be redacted field by field.
+    pub fn render(&self, body: &[u8]) -> String {
+        if body.is_empty() {
+            return "<empty>".to_string();
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                self.scrub(&mut value);
+                value.to_string()
+            }
+            Err(_) => format!("<{} byte non-JSON body>", body.len()),
+        }
+    }
+
+    fn scrub(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    if self.redact_fields.contains(&name.to_ascii_lowercase()) {
+                        *field = Value::String(REDACTED.to_string());
+                    } else if name == "message_text" {
+                        if let Value::String(text) = field {
+                            truncate(text, self.max_message_text_chars);
+                        }
+                    } else {
+                        self.scrub(field);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.scrub(item)),
+            _ => {}
+        }
+    }
+}
+
+fn truncate(text: &mut String, max_chars: usize) {
+    if let Some((cut, _)) = text.char_indices().nth(max_chars) {
+        let total = text.chars().count();
+        text.truncate(cut);
+        text.push_str(&format!("…(+{} chars)", total - max_chars));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    // Collects formatted log output so tests can inspect it
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logged(logger: &BodyLogger, max_level: tracing::Level, body: &str) -> String {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(max_level)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            logger.log_request("POST", "/chat/messages", body.as_bytes())
+        });
+        let output = capture.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_token_is_redacted_in_logged_body() {
+        let logger = BodyLogger::new(true, DEFAULT_REDACT_FIELDS, 5);
+        let body = r#"{"room_id":"general","token":"hunter2","meta":{"Room_Key":"k1"},"message_text":"Hello, world!"}"#;
+
+        let output = logged(&logger, tracing::Level::DEBUG, body);
+        assert!(output.contains(r#""token":"[REDACTED]""#), "{}", output);
+        assert!(output.contains(r#""Room_Key":"[REDACTED]""#), "{}", output);
+        assert!(output.contains(r#""message_text":"Hello…(+8 chars)""#), "{}", output);
+        assert!(!output.contains("hunter2") && !output.contains("k1"), "{}", output);
+    }
+
+    #[test]
+    fn test_bodies_stay_out_of_info_logs() {
+        let body = r#"{"token":"hunter2"}"#;
+        assert!(logged(&BodyLogger::new(true, "", 5), tracing::Level::INFO, body).is_empty());
+        assert!(logged(&BodyLogger::new(false, "", 5), tracing::Level::DEBUG, body).is_empty());
+    }
+
+    #[test]
+    fn test_non_json_bodies_are_summarised() {
+        let logger = BodyLogger::new(true, DEFAULT_REDACT_FIELDS, 5);
+        assert_eq!(logger.render(b"token=hunter2"), "<13 byte non-JSON body>");
+        assert_eq!(logger.render(b""), "<empty>");
+    }
+}
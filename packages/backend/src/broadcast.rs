@@ -0,0 +1,1428 @@
+use crate::{
+    handlers,
+    retry_queue::{RetryEntry, RetryQueue},
+    send_order::SendOrder,
+    webhook::Webhook,
+    MetricsHelper,
+};
+use aws_config::SdkConfig;
+use aws_sdk_apigatewaymanagement::{
+    error::{ProvideErrorMetadata, SdkError},
+    primitives::Blob,
+    Client as ApiGatewayClient,
+};
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, DeleteRequest, WriteRequest},
+    Client as DynamoDbClient,
+};
+use futures_util::{stream, StreamExt};
+#[cfg(feature = "dev")]
+use reqwest::Client as HttpClient;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, error, info, warn};
+use types::{
+    ChatMessage, ConnectionId, DeliveryReceipt, MessageStatus, MessageVisibility, WsServerMessage,
+};
+
+// The deployed API's management endpoint, from WS_API_ID, AWS_REGION and WS_STAGE. Lambdas
+// check it at startup (`check_endpoint_config`), so a bad value never reaches a post.
+static DEFAULT_ENDPOINT: LazyLock<Result<String, String>> = LazyLock::new(|| {
+    let var =
+        |name| env::var(name).map_err(|_| format!("{} environment variable must be set", name));
+    default_endpoint(&var("WS_API_ID")?, &var("AWS_REGION")?, &var("WS_STAGE")?)
+});
+
+// Optional HTTP client for dev per-connection push
+#[cfg(feature = "dev")]
+static HTTP_CLIENT: LazyLock<HttpClient> = LazyLock::new(HttpClient::new);
+
+// How room size shapes the API Gateway fan-out (BROADCAST_LARGE_ROOM_THRESHOLD,
+// BROADCAST_CHUNK_SIZE, BROADCAST_CHUNK_DELAY_MS)
+static PACING: LazyLock<BroadcastPacing> = LazyLock::new(BroadcastPacing::from_env);
+
+// Strict per-connection ordering of broadcasts, when enabled (BROADCAST_ORDERED)
+static SEND_ORDER: LazyLock<Option<SendOrder>> = LazyLock::new(SendOrder::from_env);
+
+// How failed posts to a connection are retried (BROADCAST_POST_ATTEMPTS,
+// BROADCAST_POST_BACKOFF_MS)
+static POST_RETRY: LazyLock<PostRetry> = LazyLock::new(PostRetry::from_env);
+
+// DynamoDB's cap on one BatchWriteItem, and tries per batch of stale connection deletes
+const BATCH_WRITE_LIMIT: usize = 25;
+const STALE_DELETE_ATTEMPTS: u32 = 2;
+
+// Posts in flight at once for a room at or under the large-room threshold
+const SMALL_ROOM_CONCURRENCY: usize = 5;
+
+/// Fan-out pacing. Rooms up to `large_room_threshold` connections are posted to in one pass as
+/// soon as the message arrives. Larger rooms are posted to in chunks of `chunk_size`, with
+/// `chunk_concurrency` posts in flight within a chunk and `chunk_delay` between chunks, so a
+/// big room doesn't run into API Gateway's management API throttling all at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastPacing {
+    pub large_room_threshold: usize,
+    pub chunk_size: usize,
+    pub chunk_concurrency: usize,
+    pub chunk_delay: Duration,
+}
+
+impl Default for BroadcastPacing {
+    fn default() -> Self {
+        Self {
+            large_room_threshold: 100,
+            chunk_size: 50,
+            chunk_concurrency: 25,
+            chunk_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// How one broadcast's posts are sent: in passes of `chunk_size`, `concurrency` at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanOutPlan {
+    pub chunk_size: usize,
+    pub concurrency: usize,
+}
+
+impl BroadcastPacing {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let read = |name: &str| {
+            env::var(name).ok().and_then(|value| value.parse::<usize>().ok()).filter(|n| *n > 0)
+        };
+        Self {
+            large_room_threshold: read("BROADCAST_LARGE_ROOM_THRESHOLD")
+                .unwrap_or(defaults.large_room_threshold),
+            chunk_size: read("BROADCAST_CHUNK_SIZE").unwrap_or(defaults.chunk_size),
+            chunk_concurrency: defaults.chunk_concurrency,
+            chunk_delay: read("BROADCAST_CHUNK_DELAY_MS")
+                .map_or(defaults.chunk_delay, |ms| Duration::from_millis(ms as u64)),
+        }
+    }
+
+    pub fn plan(&self, deliveries: usize) -> FanOutPlan {
+        if deliveries > self.large_room_threshold {
+            FanOutPlan { chunk_size: self.chunk_size, concurrency: self.chunk_concurrency }
+        } else {
+            FanOutPlan { chunk_size: deliveries.max(1), concurrency: SMALL_ROOM_CONCURRENCY }
+        }
+    }
+}
+
+/// Retries of a post that API Gateway turned away for now (throttled, timed out, or
+/// unreachable). Each connection gets up to `max_attempts` posts, the wait before each retry
+/// doubling from `base_delay`, plus up to as much again at random so a throttled fan-out doesn't
+/// come back in lockstep. A gone connection or any other refusal isn't retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PostRetry {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for PostRetry {
+    fn default() -> Self {
+        Self { max_attempts: 3, base_delay: Duration::from_millis(50) }
+    }
+}
+
+impl PostRetry {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let read = |name: &str| env::var(name).ok().and_then(|value| value.parse::<u32>().ok());
+        Self {
+            max_attempts: read("BROADCAST_POST_ATTEMPTS")
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(defaults.max_attempts),
+            base_delay: read("BROADCAST_POST_BACKOFF_MS")
+                .map_or(defaults.base_delay, |ms| Duration::from_millis(ms.into())),
+        }
+    }
+
+    /// The wait after failed attempt `attempt` (from 1), `jitter` (0 to 1) of the way from the
+    /// doubled delay to twice that
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let doubled = self.base_delay.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        doubled.mul_f64(1.0 + jitter.clamp(0.0, 1.0))
+    }
+}
+
+// Whether a failed post may go through if tried again: the API is throttling us, or the call
+// never got an answer
+fn is_retryable<E: ProvideErrorMetadata, R>(err: &SdkError<E, R>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        _ => matches!(
+            err.as_service_error().and_then(|service| service.code()),
+            Some(
+                "LimitExceededException"
+                    | "ThrottlingException"
+                    | "TooManyRequestsException"
+                    | "InternalServerErrorException"
+                    | "ServiceUnavailableException"
+            )
+        ),
+    }
+}
+
+// What became of one post to a connection
+enum Delivery {
+    Sent,
+    Gone,
+    Failed,
+}
+
+// Delivery counts for one broadcast
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BroadcastStats {
+    pub connections: i32,
+    pub successful_sends: i32,
+    // API Gateway posts by the stage and domain each connection came in through
+    pub stages: Vec<StageDeliveries>,
+    // The author's own API Gateway connections that got the message, as (endpoint, connection
+    // id), for the delivery receipt
+    #[serde(skip)]
+    pub author_connections: Vec<(Option<String>, ConnectionId)>,
+}
+
+/// API Gateway posts for one broadcast through one stage and domain. Stale connections are
+/// removed as usual and counted as neither. Connections that didn't record an endpoint go
+/// through the default one and are labeled `default`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StageDeliveries {
+    pub stage: String,
+    pub domain: String,
+    pub successes: i32,
+    pub failures: i32,
+}
+
+/// WebSocket management client for the deployed API, cached for the life of the container
+pub async fn api_gateway_client(aws_config: &SdkConfig) -> &'static ApiGatewayClient {
+    static API_GATEWAY: OnceCell<ApiGatewayClient> = OnceCell::const_new();
+
+    API_GATEWAY
+        .get_or_init(|| async {
+            let ws_endpoint = DEFAULT_ENDPOINT.as_ref().unwrap_or_else(|e| panic!("{}", e));
+            endpoint_client(aws_config, ws_endpoint)
+        })
+        .await
+}
+
+/// `https://{api_id}.execute-api.{region}.amazonaws.com/{stage}`, once each part is known to be
+/// a bare name. An empty value or a stray space or slash is refused, naming its variable,
+/// rather than building a URL every post would then fail against.
+pub fn default_endpoint(api_id: &str, region: &str, stage: &str) -> Result<String, String> {
+    for (name, value) in [("WS_API_ID", api_id), ("AWS_REGION", region), ("WS_STAGE", stage)] {
+        let bare = value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if value.is_empty() || !bare {
+            return Err(format!("{} '{}' can't be used in the management endpoint", name, value));
+        }
+    }
+    let endpoint = format!("https://{}.execute-api.{}.amazonaws.com/{}", api_id, region, stage);
+    reqwest::Url::parse(&endpoint)
+        .map_err(|e| format!("Management endpoint {} is malformed: {}", endpoint, e))?;
+    Ok(endpoint)
+}
+
+/// Check the management endpoint the environment describes, at a Lambda's cold start. A
+/// malformed one is counted (BroadcastConfigError) and returned before anything is posted.
+pub async fn check_endpoint_config(metrics: &MetricsHelper) -> Result<(), String> {
+    check_endpoint(&DEFAULT_ENDPOINT, metrics).await
+}
+
+async fn check_endpoint(
+    endpoint: &Result<String, String>,
+    metrics: &MetricsHelper,
+) -> Result<(), String> {
+    match endpoint {
+        Ok(endpoint) => {
+            debug!("Posting to connections through {}", endpoint);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Broadcast isn't configured correctly: {}", e);
+            metrics.emit_broadcast_config_error().await;
+            Err(e.clone())
+        }
+    }
+}
+
+/// Management clients for the deployed API and for every other endpoint connections were
+/// made through, cached for the life of the container
+pub async fn management_clients(aws_config: &SdkConfig) -> &'static ManagementClients {
+    static CLIENTS: OnceCell<ManagementClients> = OnceCell::const_new();
+
+    CLIENTS
+        .get_or_init(|| async {
+            ManagementClients::new(aws_config, api_gateway_client(aws_config).await.clone())
+        })
+        .await
+}
+
+/// Management client for one endpoint, signed for the endpoint's own region when it names one
+pub fn endpoint_client(aws_config: &SdkConfig, endpoint: &str) -> ApiGatewayClient {
+    let mut builder =
+        aws_sdk_apigatewaymanagement::config::Builder::from(aws_config).endpoint_url(endpoint);
+    if let Some(region) = endpoint_region(endpoint) {
+        builder = builder.region(aws_config::Region::new(region.to_string()));
+    }
+    ApiGatewayClient::from_conf(builder.build())
+}
+
+// `us-west-2` from `https://abc123.execute-api.us-west-2.amazonaws.com/prod`
+fn endpoint_region(endpoint: &str) -> Option<&str> {
+    let host = endpoint.split("://").nth(1)?.split('/').next()?;
+    let (_, rest) = host.split_once(".execute-api.")?;
+    rest.strip_suffix(".amazonaws.com")
+}
+
+/// Management endpoint for a connection made through `domain` and `stage`. Local API Gateway
+/// emulators listen on plain HTTP.
+pub fn management_endpoint(domain: &str, stage: &str) -> String {
+    let scheme = if domain.starts_with("localhost") || domain.starts_with("127.0.0.1") {
+        "http"
+    } else {
+        "https"
+    };
+    format!("{}://{}/{}", scheme, domain, stage)
+}
+
+// Endpoint recorded on a connection at connect time, if it recorded a usable one
+fn connection_endpoint(connection: &HashMap<String, AttributeValue>) -> Option<String> {
+    let domain = connection.get("domain")?.as_s().ok()?;
+    let stage = connection.get("stage")?.as_s().ok()?;
+    if ["unknown", "local", ""].contains(&domain.as_str()) || stage == "unknown" {
+        return None;
+    }
+    Some(management_endpoint(domain, stage))
+}
+
+// Stage and domain a connection's posts are reported under
+fn connection_stage(connection: &HashMap<String, AttributeValue>) -> (String, String) {
+    match connection_endpoint(connection) {
+        Some(_) => {
+            let label = |name: &str| connection[name].as_s().cloned().unwrap_or_default();
+            (label("stage"), label("domain"))
+        }
+        None => ("default".to_string(), "default".to_string()),
+    }
+}
+
+/// Management API clients keyed by endpoint. Each connection stores the API Gateway `domain`
+/// and `stage` it connected through, so connections made against another region (after a
+/// failover) or a custom domain are posted to where they live, not to the endpoint from env.
+pub struct ManagementClients {
+    aws_config: SdkConfig,
+    // For connections that didn't record an endpoint
+    default: ApiGatewayClient,
+    by_endpoint: Mutex<HashMap<String, ApiGatewayClient>>,
+}
+
+impl ManagementClients {
+    pub fn new(aws_config: &SdkConfig, default: ApiGatewayClient) -> Self {
+        Self { aws_config: aws_config.clone(), default, by_endpoint: Mutex::default() }
+    }
+
+    /// Client for `endpoint`, built on first use; the default client when there is none
+    pub fn client(&self, endpoint: Option<&str>) -> ApiGatewayClient {
+        match endpoint {
+            None => self.default.clone(),
+            Some(endpoint) => self
+                .by_endpoint
+                .lock()
+                .unwrap()
+                .entry(endpoint.to_string())
+                .or_insert_with(|| endpoint_client(&self.aws_config, endpoint))
+                .clone(),
+        }
+    }
+}
+
+/// What connections receive: the stored message, marked as broadcast
+pub fn broadcast_envelope(message: &ChatMessage) -> ChatMessage {
+    ChatMessage { status: MessageStatus::Broadcast, ..message.clone() }
+}
+
+/// The text of the `message` frame that delivers `message`'s broadcast envelope
+pub fn message_frame(message: &ChatMessage) -> String {
+    serde_json::to_string(&WsServerMessage::Message(Box::new(broadcast_envelope(message))))
+        .expect("ChatMessage always serializes")
+}
+
+/// The text of the `message_deleted` frame that tells clients to drop `message`
+pub fn deletion_frame(message: &ChatMessage) -> String {
+    serde_json::to_string(&WsServerMessage::MessageDeleted {
+        room_id: message.core.room_id.clone(),
+        message_id: message.core.id.clone(),
+    })
+    .expect("frames always serialize")
+}
+
+/// Deliver a message to every connection currently in its room (via the `room-index` GSI),
+/// removing connections that turn out to be gone. With a retry queue, API Gateway deliveries
+/// are recorded before posting and cleared as they succeed, so failures get redelivered.
+pub async fn broadcast_message(
+    ddb: &DynamoDbClient,
+    api_gateway: &ManagementClients,
+    connections_table: &str,
+    message: &ChatMessage,
+    retry: Option<&RetryQueue>,
+) -> Result<BroadcastStats, String> {
+    let frame = WsServerMessage::Message(Box::new(broadcast_envelope(message)));
+    broadcast_frame(ddb, api_gateway, connections_table, message, &frame, retry).await
+}
+
+/// Tell every connection that could see `message` that it's been deleted, the same way
+/// `broadcast_message` delivered it
+pub async fn broadcast_deletion(
+    ddb: &DynamoDbClient,
+    api_gateway: &ManagementClients,
+    connections_table: &str,
+    message: &ChatMessage,
+    retry: Option<&RetryQueue>,
+) -> Result<BroadcastStats, String> {
+    let frame = WsServerMessage::MessageDeleted {
+        room_id: message.core.room_id.clone(),
+        message_id: message.core.id.clone(),
+    };
+    broadcast_frame(ddb, api_gateway, connections_table, message, &frame, retry).await
+}
+
+// Deliver `frame`, which is about `message`, to the connections in its room that may see it.
+// Dev push URLs only take messages, so other frames skip them.
+async fn broadcast_frame(
+    ddb: &DynamoDbClient,
+    api_gateway: &ManagementClients,
+    connections_table: &str,
+    message: &ChatMessage,
+    frame: &WsServerMessage,
+    retry: Option<&RetryQueue>,
+) -> Result<BroadcastStats, String> {
+    let room_id = &message.core.room_id;
+    info!("Broadcasting to room {}: {:?}", room_id, frame);
+    // Taken before anything is awaited, so a room's broadcasts queue up in the order they
+    // started. Held only until this one has its turns on each connection.
+    let mut room_turn =
+        SEND_ORDER.as_ref().map(|order| order.take_turn(&format!("room#{}", room_id)));
+    if let Some(turn) = &mut room_turn {
+        turn.ready().await;
+    }
+
+    // Query for all connections in this room using GSI
+    let mut connections = ddb
+        .query()
+        .table_name(connections_table)
+        .index_name("room-index")
+        .key_condition_expression("room_id = :room_id")
+        .expression_attribute_values(":room_id", AttributeValue::S(room_id.clone()))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to query connections: {:?}", e))?
+        .items
+        .unwrap_or_default();
+    info!("Found {} connections in room {}", connections.len(), room_id);
+
+    // A direct message only goes to its sender's and recipient's connections
+    connections.retain(|connection| {
+        let user_id = connection.get("user_id").and_then(|v| v.as_s().ok());
+        handlers::visible_to(message, user_id.map(String::as_str))
+    });
+
+    let message_json =
+        serde_json::to_string(frame).map_err(|e| format!("Failed to encode frame: {}", e))?;
+    let message_blob = Blob::new(message_json.as_bytes());
+
+    let mut stats =
+        BroadcastStats { connections: connections.len() as i32, ..BroadcastStats::default() };
+
+    // API Gateway connections are grouped by the endpoint they connected through, so each
+    // group is posted via its own client; dev connections carry their own push URL
+    let mut by_endpoint: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+    let mut stage_of: HashMap<String, (String, String)> = HashMap::new();
+    let mut author_endpoints: HashMap<String, Option<String>> = HashMap::new();
+    // Connections that turned out to be gone, deleted together once every post is done
+    let mut stale: Vec<String> = Vec::new();
+    for connection in &connections {
+        // Determine transport; default to apigw if missing
+        let transport = connection
+            .get("transport")
+            .and_then(|v| v.as_s().ok())
+            .map(|s| s.as_str())
+            .unwrap_or("apigw");
+
+        match transport {
+            "apigw" => {
+                if let Some(AttributeValue::S(connection_id)) = connection.get("connection_id") {
+                    let endpoint = connection_endpoint(connection);
+                    if connection.get("user_id").and_then(|v| v.as_s().ok())
+                        == Some(&message.core.user_id)
+                    {
+                        author_endpoints.insert(connection_id.clone(), endpoint.clone());
+                    }
+                    by_endpoint.entry(endpoint).or_default().push(connection_id.clone());
+                    stage_of.insert(connection_id.clone(), connection_stage(connection));
+                }
+            }
+            #[cfg(feature = "dev")]
+            "dev" => {
+                let WsServerMessage::Message(envelope) = frame else {
+                    continue;
+                };
+                // Use per-connection push_url
+                if let Some(AttributeValue::S(push_url)) = connection.get("push_url") {
+                    match HTTP_CLIENT.post(push_url).json(envelope).send().await {
+                        Ok(resp) => {
+                            if resp.status().is_success() {
+                                info!("Sent via dev push_url to {}", push_url);
+                                stats.successful_sends += 1;
+                            } else if resp.status().as_u16() == 404 || resp.status().as_u16() == 410
+                            {
+                                // Stale connection, removed with the rest after the fan-out
+                                if let Some(AttributeValue::S(connection_id)) =
+                                    connection.get("connection_id")
+                                {
+                                    stale.push(connection_id.clone());
+                                }
+                            } else {
+                                error!("Dev push_url responded with status {}", resp.status());
+                            }
+                        }
+                        Err(e) => {
+                            error!("HTTP error sending to dev push_url {}: {:?}", push_url, e);
+                        }
+                    }
+                } else {
+                    error!("Missing push_url for dev transport connection");
+                }
+            }
+            _ => {
+                // Unknown transport; skip
+                info!("Skipping connection with unknown transport: {}", transport);
+            }
+        }
+    }
+
+    // Written ahead of the fan-out so a crash part way through still leaves the rest queued.
+    // The queue never blocks delivery: if it can't be written, the broadcast is best-effort.
+    let retry = match retry {
+        Some(queue) if !by_endpoint.is_empty() => {
+            let entries: Vec<RetryEntry> = by_endpoint
+                .iter()
+                .flat_map(|(endpoint, connection_ids)| {
+                    connection_ids.iter().map(|connection_id| RetryEntry {
+                        connection_id: connection_id.clone(),
+                        message_id: message.core.id.clone(),
+                        room_id: room_id.clone(),
+                        endpoint: endpoint.clone(),
+                        payload: message_json.clone(),
+                    })
+                })
+                .collect();
+            match queue.enqueue(ddb, &entries).await {
+                Ok(()) => Some(queue),
+                Err(e) => {
+                    error!("Failed to queue retries for message {}: {}", message.core.id, e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    // Connections that no longer need a retry: delivered, or gone
+    let mut settled = Vec::new();
+
+    let deliveries: Vec<(ApiGatewayClient, String)> = by_endpoint
+        .into_iter()
+        .flat_map(|(endpoint, connection_ids)| {
+            let client = api_gateway.client(endpoint.as_deref());
+            connection_ids.into_iter().map(move |connection_id| (client.clone(), connection_id))
+        })
+        .collect();
+    let mut turns = SEND_ORDER.as_ref().map_or_else(HashMap::new, |order| {
+        order.take_turns(deliveries.iter().map(|(_, connection_id)| connection_id.as_str()))
+    });
+    drop(room_turn);
+
+    let plan = PACING.plan(deliveries.len());
+    let started = Instant::now();
+    let mut chunks = 0;
+    let mut stages: BTreeMap<(String, String), StageDeliveries> = BTreeMap::new();
+    for chunk in deliveries.chunks(plan.chunk_size) {
+        if chunks > 0 {
+            tokio::time::sleep(PACING.chunk_delay).await;
+        }
+        chunks += 1;
+
+        let outcomes: Vec<(String, Delivery)> = stream::iter(chunk.to_vec())
+            .map(|(client, connection_id)| {
+                let payload = message_blob.clone();
+                let turn = turns.remove(&connection_id);
+                async move {
+                    if let Some(mut turn) = turn {
+                        turn.ready().await;
+                    }
+                    let outcome = post_to_connection(&client, &connection_id, &payload).await;
+                    (connection_id, outcome)
+                }
+            })
+            .buffer_unordered(plan.concurrency)
+            .collect()
+            .await;
+        for (connection_id, outcome) in outcomes {
+            let (stage, domain) = stage_of.remove(&connection_id).unwrap_or_default();
+            let tally = stages
+                .entry((stage.clone(), domain.clone()))
+                .or_insert_with(|| StageDeliveries { stage, domain, ..StageDeliveries::default() });
+            match outcome {
+                Delivery::Sent => {
+                    stats.successful_sends += 1;
+                    tally.successes += 1;
+                    if let Some(endpoint) = author_endpoints.remove(&connection_id) {
+                        stats
+                            .author_connections
+                            .push((endpoint, ConnectionId::from(connection_id.clone())));
+                    }
+                    settled.push(connection_id);
+                }
+                Delivery::Gone => {
+                    info!("Connection {} is gone", connection_id);
+                    stale.push(connection_id.clone());
+                    settled.push(connection_id);
+                }
+                Delivery::Failed => tally.failures += 1,
+            }
+        }
+    }
+    stats.stages = stages.into_values().collect();
+    if chunks > 0 {
+        MetricsHelper::new().await.emit_broadcast_pacing(room_id, chunks, started.elapsed()).await;
+    }
+    if !stale.is_empty() {
+        let reaped = remove_connections(ddb, connections_table, &stale).await;
+        info!("Removed {} of {} stale connections in room {}", reaped, stale.len(), room_id);
+        MetricsHelper::new().await.emit_stale_connections_reaped(room_id, reaped).await;
+    }
+
+    if let Some(queue) = retry {
+        if let Err(e) = queue.remove(ddb, &message.core.id, &settled).await {
+            error!("Failed to clear delivered retries for message {}: {}", message.core.id, e);
+        }
+    }
+
+    info!("Finished broadcasting message {} to room {}", message.core.id, room_id);
+    Ok(stats)
+}
+
+/// Broadcast a new message and, when configured, push it to the outbound webhook. The webhook
+/// runs alongside the fan-out, so a slow or failing endpoint never holds up chat delivery.
+pub async fn broadcast_and_notify(
+    ddb: &DynamoDbClient,
+    api_gateway: &ManagementClients,
+    connections_table: &str,
+    message: &ChatMessage,
+    webhook: Option<&Webhook>,
+    retry: Option<&RetryQueue>,
+    metrics: &MetricsHelper,
+) -> Result<BroadcastStats, String> {
+    // Direct messages stay between their two parties, so they aren't pushed out either
+    let webhook = webhook.filter(|_| message.visibility == MessageVisibility::Room);
+    let notify = async {
+        if let Some(webhook) = webhook {
+            match webhook.deliver(message).await {
+                Ok(_) => metrics.emit_webhook_delivery(true).await,
+                Err(e) => {
+                    error!("Webhook delivery failed for message {}: {}", message.core.id, e);
+                    metrics.emit_webhook_delivery(false).await;
+                }
+            }
+        }
+    };
+
+    let (stats, ()) = tokio::join!(
+        broadcast_message(ddb, api_gateway, connections_table, message, retry),
+        notify
+    );
+    stats
+}
+
+/// Tell the author's connections how many connections their message reached. Best effort: a
+/// missed receipt only leaves the indicator blank.
+pub async fn send_delivery_receipt(
+    api_gateway: &ManagementClients,
+    author_connections: &[(Option<String>, ConnectionId)],
+    receipt: &DeliveryReceipt,
+) {
+    let payload = match serde_json::to_vec(receipt) {
+        Ok(json) => Blob::new(json),
+        Err(e) => {
+            error!("Failed to serialize delivery receipt for {}: {}", receipt.message_id, e);
+            return;
+        }
+    };
+    for (endpoint, connection_id) in author_connections {
+        let client = api_gateway.client(endpoint.as_deref());
+        post_to_connection(&client, connection_id, &payload).await;
+    }
+}
+
+/// Tell everyone in a room who's there now, after a join or a leave. `joining` is skipped, as
+/// API Gateway won't post to a connection until its $connect returns. Only API Gateway
+/// connections hear about it (dev push URLs take messages alone), and ones found gone are
+/// removed as in a message broadcast. Returns how many connections were told.
+pub async fn broadcast_presence(
+    ddb: &DynamoDbClient,
+    api_gateway: &ManagementClients,
+    connections_table: &str,
+    room_id: &str,
+    joining: Option<&str>,
+    now: i64,
+) -> Result<usize, String> {
+    let connections = handlers::live_room_connections(ddb, connections_table, room_id, now).await?;
+    let frame = WsServerMessage::Presence {
+        room_id: room_id.to_string(),
+        users: handlers::presence_users(&connections),
+    };
+    let payload = Blob::new(
+        serde_json::to_vec(&frame).map_err(|e| format!("Failed to encode presence: {}", e))?,
+    );
+
+    let deliveries: Vec<(ApiGatewayClient, String)> = connections
+        .iter()
+        .filter(|connection| {
+            connection.get("transport").and_then(|v| v.as_s().ok()).is_none_or(|t| t == "apigw")
+        })
+        .filter_map(|connection| {
+            let connection_id = connection.get("connection_id")?.as_s().ok()?;
+            if joining == Some(connection_id.as_str()) {
+                return None;
+            }
+            let client = api_gateway.client(connection_endpoint(connection).as_deref());
+            Some((client, connection_id.clone()))
+        })
+        .collect();
+
+    let concurrency = PACING.plan(deliveries.len()).concurrency;
+    let outcomes: Vec<(String, Delivery)> = stream::iter(deliveries)
+        .map(|(client, connection_id)| {
+            let payload = payload.clone();
+            async move {
+                let outcome = post_to_connection(&client, &connection_id, &payload).await;
+                (connection_id, outcome)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut told = 0;
+    let mut stale = Vec::new();
+    for (connection_id, outcome) in outcomes {
+        match outcome {
+            Delivery::Sent => told += 1,
+            Delivery::Gone => stale.push(connection_id),
+            Delivery::Failed => {}
+        }
+    }
+    if !stale.is_empty() {
+        let reaped = remove_connections(ddb, connections_table, &stale).await;
+        info!("Removed {} of {} stale connections in room {}", reaped, stale.len(), room_id);
+        MetricsHelper::new().await.emit_stale_connections_reaped(room_id, reaped).await;
+    }
+    Ok(told)
+}
+
+// Post to a connection, retrying per POST_RETRY while the failure is one that may pass
+async fn post_to_connection(
+    client: &ApiGatewayClient,
+    connection_id: &str,
+    payload: &Blob,
+) -> Delivery {
+    let retry = *POST_RETRY;
+    let mut attempt = 1;
+    loop {
+        let err = match client
+            .post_to_connection()
+            .connection_id(connection_id)
+            .data(payload.clone())
+            .send()
+            .await
+        {
+            Ok(_) => {
+                info!("Sent via API Gateway to connection {}", connection_id);
+                return Delivery::Sent;
+            }
+            Err(e) => e,
+        };
+        if err.as_service_error().is_some_and(|service_err| service_err.is_gone_exception()) {
+            return Delivery::Gone;
+        }
+        if attempt >= retry.max_attempts || !is_retryable(&err) {
+            error!(
+                "Failed to send via API Gateway to {} after {} attempt(s): {:?}",
+                connection_id, attempt, err
+            );
+            return Delivery::Failed;
+        }
+        warn!(
+            "Retrying send via API Gateway to {} (attempt {}): {:?}",
+            connection_id, attempt, err
+        );
+        let jitter = uuid::Uuid::new_v4().as_u64_pair().0 as f64 / u64::MAX as f64;
+        tokio::time::sleep(retry.delay(attempt, jitter)).await;
+        attempt += 1;
+    }
+}
+
+// Delete stale connections in batches of BATCH_WRITE_LIMIT, giving what DynamoDB leaves
+// unprocessed one more try. Best effort: a connection left behind is found gone again next
+// time, or expires. Returns how many were deleted.
+async fn remove_connections(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    connection_ids: &[String],
+) -> usize {
+    let mut reaped = 0;
+    for chunk in connection_ids.chunks(BATCH_WRITE_LIMIT) {
+        let requests: Vec<WriteRequest> = chunk
+            .iter()
+            .filter_map(|connection_id| {
+                let delete = DeleteRequest::builder()
+                    .key("connection_id", AttributeValue::S(connection_id.clone()))
+                    .build()
+                    .ok()?;
+                Some(WriteRequest::builder().delete_request(delete).build())
+            })
+            .collect();
+        let mut pending = HashMap::from([(connections_table.to_string(), requests)]);
+        for attempt in 1..=STALE_DELETE_ATTEMPTS {
+            let sent: usize = pending.values().map(Vec::len).sum();
+            let output = match ddb.batch_write_item().set_request_items(Some(pending)).send().await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    error!("Failed to delete {} stale connections: {:?}", sent, e);
+                    break;
+                }
+            };
+            pending = output.unprocessed_items.unwrap_or_default();
+            let left: usize = pending.values().map(Vec::len).sum();
+            reaped += sent - left;
+            if left == 0 {
+                break;
+            }
+            if attempt == STALE_DELETE_ATTEMPTS {
+                error!("{} stale connections were left unprocessed", left);
+            }
+        }
+    }
+    reaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::{self, Tables},
+        request_context::RequestContext,
+        store::DynamoMessageStore,
+        test_support::{
+            create_connections_table, create_messages_table, create_table, local_config, serve,
+            Capture,
+        },
+    };
+    use aws_sdk_dynamodb::types::KeyType;
+    use axum::{extract::Path, http::StatusCode, routing::post, Router};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use types::{MessageCore, MessageFormat, SendMessageRequest};
+
+    async fn put_connections(
+        ddb: &DynamoDbClient,
+        connections_table: &str,
+        rooms: &[(&str, &str)],
+    ) {
+        for (connection_id, room_id) in rooms {
+            ddb.put_item()
+                .table_name(connections_table)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S(room_id.to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .item("transport", AttributeValue::S("apigw".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB (and API Gateway management mock) at DYNAMODB_ENDPOINT
+    async fn test_rebroadcast_reaches_current_connections() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let api_gateway = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let tables = Tables {
+            rooms: "rebroadcast-test-rooms".to_string(),
+            messages: "rebroadcast-test-messages".to_string(),
+        };
+        let connections_table = "rebroadcast-test-connections";
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        create_connections_table(&ddb, connections_table).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables);
+
+        put_connections(
+            &ddb,
+            connections_table,
+            &[("conn-1", "general"), ("conn-2", "general"), ("conn-3", "random")],
+        )
+        .await;
+
+        let request = SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: "Hello!".to_string(),
+            client_message_id: None,
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        };
+        let posted =
+            handlers::post_message_handler(&store, &RequestContext::default(), request, None)
+                .await
+                .unwrap()
+                .message;
+
+        let message = handlers::find_message_by_id(&store, &posted.core.id)
+            .await
+            .unwrap()
+            .expect("posted message should be found by id");
+        assert_eq!(message.core.room_id, "general");
+
+        let stats =
+            broadcast_message(&ddb, &api_gateway, connections_table, &message, None).await.unwrap();
+        assert_eq!(stats.successful_sends, 2);
+        assert_eq!(stats.connections, 2);
+        assert_eq!(
+            stats.stages,
+            vec![StageDeliveries {
+                stage: "default".to_string(),
+                domain: "default".to_string(),
+                successes: 2,
+                failures: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_broadcast_envelope_is_marked_broadcast() {
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: Some("client-1".to_string()),
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+
+        let envelope = broadcast_envelope(&message);
+        assert_eq!(envelope.status, MessageStatus::Broadcast);
+        assert_eq!(envelope.core.id, message.core.id);
+        assert_eq!(envelope.client_message_id, message.client_message_id);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB (and API Gateway management mock) at DYNAMODB_ENDPOINT
+    async fn test_failing_webhook_does_not_block_fan_out() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let api_gateway = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let connections_table = "webhook-test-connections";
+        create_connections_table(&ddb, connections_table).await;
+        put_connections(&ddb, connections_table, &[("conn-1", "general"), ("conn-2", "general")])
+            .await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let url = serve(Router::new().route(
+            "/hook",
+            post(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }),
+        ))
+        .await;
+        let webhook = Webhook::new(format!("{}/hook", url), None, Duration::from_secs(1), 2);
+
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let stats = broadcast_and_notify(
+            &ddb,
+            &api_gateway,
+            connections_table,
+            &message,
+            Some(&webhook),
+            None,
+            &MetricsHelper::new().await,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stats.successful_sends, 2);
+        assert_eq!(stats.connections, 2);
+        assert_eq!(
+            stats.stages,
+            vec![StageDeliveries {
+                stage: "default".to_string(),
+                domain: "default".to_string(),
+                successes: 2,
+                failures: 0,
+            }]
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_large_rooms_are_sent_in_paced_chunks() {
+        let pacing = BroadcastPacing::default();
+        let chunk_count = |deliveries: usize| {
+            let plan = pacing.plan(deliveries);
+            (0..deliveries).collect::<Vec<_>>().chunks(plan.chunk_size).count()
+        };
+
+        let small = pacing.plan(pacing.large_room_threshold);
+        assert_eq!(small.concurrency, SMALL_ROOM_CONCURRENCY);
+        assert_eq!(chunk_count(pacing.large_room_threshold), 1);
+        assert_eq!(chunk_count(3), 1);
+        assert_eq!(chunk_count(0), 0);
+
+        let large = pacing.plan(pacing.large_room_threshold + 1);
+        assert_eq!(large, FanOutPlan { chunk_size: 50, concurrency: 25 });
+        assert!(large.concurrency > small.concurrency);
+        assert_eq!(chunk_count(pacing.large_room_threshold + 1), 3);
+        assert_eq!(chunk_count(250), 5);
+    }
+
+    #[tokio::test]
+    async fn test_posts_retry_only_what_may_pass() {
+        use aws_sdk_apigatewaymanagement::config::retry::RetryConfig;
+
+        let retry = PostRetry::default();
+        assert_eq!(retry.delay(1, 0.0), Duration::from_millis(50));
+        assert_eq!(retry.delay(2, 0.0), Duration::from_millis(100));
+        assert_eq!(retry.delay(3, 1.0), Duration::from_millis(400));
+
+        // A stand-in management API that answers each connection's posts from a script, the
+        // last answer repeating
+        let calls: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+        let log = calls.clone();
+        let url = serve(Router::new().route(
+            "/prod/@connections/:connection_id",
+            post(move |Path(connection_id): Path<String>| async move {
+                let script: &[(u16, &str)] = match connection_id.as_str() {
+                    "throttled-twice" => &[(429, "LimitExceededException"), (200, "")],
+                    "throttled" => &[(429, "LimitExceededException")],
+                    "gone" => &[(410, "GoneException")],
+                    _ => &[(403, "ForbiddenException")],
+                };
+                let mut calls = log.lock().unwrap();
+                let call = calls.entry(connection_id).or_default();
+                *call += 1;
+                let (status, error_type) = script[(*call - 1).min(script.len() - 1)];
+                (StatusCode::from_u16(status).unwrap(), [("x-amzn-ErrorType", error_type)], "{}")
+            }),
+        ))
+        .await;
+        // The SDK's own retries are off, so every post here is one of ours
+        let config = aws_sdk_apigatewaymanagement::Config::from(&local_config().await)
+            .to_builder()
+            .endpoint_url(format!("{}/prod", url))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = ApiGatewayClient::from_conf(config);
+        let payload = Blob::new("{}");
+
+        let outcome = post_to_connection(&client, "throttled-twice", &payload).await;
+        assert!(matches!(outcome, Delivery::Sent));
+        let outcome = post_to_connection(&client, "throttled", &payload).await;
+        assert!(matches!(outcome, Delivery::Failed));
+        let outcome = post_to_connection(&client, "gone", &payload).await;
+        assert!(matches!(outcome, Delivery::Gone));
+        let outcome = post_to_connection(&client, "forbidden", &payload).await;
+        assert!(matches!(outcome, Delivery::Failed));
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls["throttled-twice"], 2);
+        assert_eq!(calls["throttled"], retry.max_attempts as usize);
+        assert_eq!(calls["gone"], 1);
+        assert_eq!(calls["forbidden"], 1);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_stale_connections_are_deleted_in_batches() {
+        let ddb = DynamoDbClient::new(&local_config().await);
+        let connections_table = "stale-test-connections";
+        create_connections_table(&ddb, connections_table).await;
+        let ids: Vec<String> = (0..30).map(|i| format!("conn-{}", i)).collect();
+        let rooms: Vec<(&str, &str)> = ids.iter().map(|id| (id.as_str(), "general")).collect();
+        put_connections(&ddb, connections_table, &rooms).await;
+        put_connections(&ddb, connections_table, &[("conn-live", "general")]).await;
+
+        // More than one batch's worth
+        assert_eq!(remove_connections(&ddb, connections_table, &ids).await, 30);
+        let left = ddb.scan().table_name(connections_table).send().await.unwrap();
+        let left: Vec<_> = left
+            .items()
+            .iter()
+            .filter_map(|item| item.get("connection_id")?.as_s().ok().cloned())
+            .collect();
+        assert_eq!(left, vec!["conn-live"]);
+    }
+
+    #[test]
+    fn test_management_endpoints() {
+        assert_eq!(
+            management_endpoint("abc123.execute-api.us-west-2.amazonaws.com", "prod"),
+            "https://abc123.execute-api.us-west-2.amazonaws.com/prod"
+        );
+        assert_eq!(management_endpoint("127.0.0.1:4510", "dev"), "http://127.0.0.1:4510/dev");
+        assert_eq!(
+            endpoint_region("https://abc123.execute-api.us-west-2.amazonaws.com/prod"),
+            Some("us-west-2")
+        );
+        assert_eq!(endpoint_region("https://chat.example.com/prod"), None);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_endpoint_is_a_config_error_before_any_send() {
+        assert_eq!(
+            default_endpoint("abc123", "us-west-2", "prod").as_deref(),
+            Ok("https://abc123.execute-api.us-west-2.amazonaws.com/prod")
+        );
+        let capture = Arc::new(Capture::default());
+        let metrics = MetricsHelper::with_own_counters(capture.clone());
+        assert_eq!(
+            check_endpoint(&default_endpoint("abc123", "us-west-2", "prod"), &metrics).await,
+            Ok(())
+        );
+        assert!(capture.0.lock().unwrap().is_empty());
+
+        // Refused at the check, so no client is ever built for it
+        for (api_id, stage, variable) in [
+            ("", "prod", "WS_API_ID"),
+            ("abc123", "prod ", "WS_STAGE"),
+            ("abc123", "a/b", "WS_STAGE"),
+        ] {
+            let endpoint = default_endpoint(api_id, "us-west-2", stage);
+            let error = check_endpoint(&endpoint, &metrics).await.unwrap_err();
+            assert!(error.starts_with(variable), "{}", error);
+        }
+        let emitted: Vec<_> =
+            capture.0.lock().unwrap().iter().map(|(name, ..)| name.clone()).collect();
+        assert_eq!(emitted, vec!["BroadcastConfigError"; 3]);
+        assert_eq!(metrics.snapshot().errors["BroadcastConfigError"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_posted_via_their_own_endpoints() {
+        // Two stand-in management APIs, recording which connections each was asked to post to
+        let posted: Arc<Mutex<Vec<(String, String)>>> = Arc::default();
+        let mut domains = Vec::new();
+        for region in ["east", "west"] {
+            let log = posted.clone();
+            let url = serve(Router::new().route(
+                "/prod/@connections/:connection_id",
+                post(move |Path(connection_id): Path<String>| async move {
+                    log.lock().unwrap().push((region.to_string(), connection_id));
+                    StatusCode::OK
+                }),
+            ))
+            .await;
+            domains.push(url.trim_start_matches("http://").to_string());
+        }
+
+        // A stand-in DynamoDB answering the room-index query with one connection per domain
+        let items: Vec<_> = [("conn-east", &domains[0]), ("conn-west", &domains[1])]
+            .into_iter()
+            .map(|(connection_id, domain)| {
+                serde_json::json!({
+                    "connection_id": { "S": connection_id },
+                    "room_id": { "S": "general" },
+                    "connected_at": { "N": "1" },
+                    "transport": { "S": "apigw" },
+                    "domain": { "S": domain },
+                    "stage": { "S": "prod" },
+                })
+            })
+            .collect();
+        let body = serde_json::json!({ "Items": items, "Count": 2, "ScannedCount": 2 });
+        let url = serve(Router::new().route(
+            "/",
+            post(move || async move {
+                ([("content-type", "application/x-amz-json-1.0")], body.to_string())
+            }),
+        ))
+        .await;
+        let config = local_config().await;
+        let ddb = DynamoDbClient::from_conf(
+            aws_sdk_dynamodb::Config::from(&config).to_builder().endpoint_url(url).build(),
+        );
+        let connections_table = "endpoint-test-connections";
+
+        let clients = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let stats =
+            broadcast_message(&ddb, &clients, connections_table, &message, None).await.unwrap();
+        assert_eq!((stats.connections, stats.successful_sends), (2, 2));
+        assert_eq!(stats.stages.len(), 2);
+
+        let mut posted = posted.lock().unwrap().clone();
+        posted.sort();
+        assert_eq!(
+            posted,
+            vec![
+                ("east".to_string(), "conn-east".to_string()),
+                ("west".to_string(), "conn-west".to_string()),
+            ]
+        );
+        assert_eq!(clients.by_endpoint.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_whisper_reaches_only_its_two_parties() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let connections_table = "whisper-test-connections";
+        create_connections_table(&ddb, connections_table).await;
+
+        let posted: Arc<Mutex<Vec<String>>> = Arc::default();
+        let log = posted.clone();
+        let url = serve(Router::new().route(
+            "/prod/@connections/:connection_id",
+            post(move |Path(connection_id): Path<String>| async move {
+                log.lock().unwrap().push(connection_id);
+                StatusCode::OK
+            }),
+        ))
+        .await;
+        let domain = url.trim_start_matches("http://").to_string();
+
+        for (connection_id, user_id) in
+            [("conn-alice", "alice"), ("conn-bob", "bob"), ("conn-carol", "carol")]
+        {
+            ddb.put_item()
+                .table_name(connections_table)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S("general".to_string()))
+                .item("user_id", AttributeValue::S(user_id.to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .item("transport", AttributeValue::S("apigw".to_string()))
+                .item("domain", AttributeValue::S(domain.clone()))
+                .item("stage", AttributeValue::S("prod".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let clients = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "alice".to_string(),
+                username: "alice".to_string(),
+                message_text: "Just between us".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Direct,
+            to_user_id: Some("bob".to_string()),
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let stats =
+            broadcast_message(&ddb, &clients, connections_table, &message, None).await.unwrap();
+        assert_eq!((stats.connections, stats.successful_sends), (2, 2));
+
+        let mut posted = posted.lock().unwrap().clone();
+        posted.sort();
+        assert_eq!(posted, vec!["conn-alice".to_string(), "conn-bob".to_string()]);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_broadcast_metrics_are_split_by_stage() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let connections_table = "stage-metrics-test-connections";
+        create_connections_table(&ddb, connections_table).await;
+
+        // One stand-in management API serving two stages, one of which refuses every post
+        let url = serve(Router::new().route(
+            "/:stage/@connections/:connection_id",
+            post(|Path((stage, _)): Path<(String, String)>| async move {
+                if stage == "beta" {
+                    StatusCode::FORBIDDEN
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        ))
+        .await;
+        let domain = url.trim_start_matches("http://").to_string();
+        for (connection_id, stage) in [("conn-1", "prod"), ("conn-2", "prod"), ("conn-3", "beta")] {
+            ddb.put_item()
+                .table_name(connections_table)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S("general".to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .item("transport", AttributeValue::S("apigw".to_string()))
+                .item("domain", AttributeValue::S(domain.clone()))
+                .item("stage", AttributeValue::S(stage.to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let clients = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let stats =
+            broadcast_message(&ddb, &clients, connections_table, &message, None).await.unwrap();
+        assert_eq!(stats.successful_sends, 2);
+
+        let capture = Arc::new(Capture::default());
+        MetricsHelper::with_sink(capture.clone())
+            .emit_broadcast_stages("general", &stats.stages)
+            .await;
+
+        let mut emitted: Vec<(String, String, f64)> = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value, dimensions)| {
+                assert_eq!(dimensions["RoomId"], "general");
+                assert_eq!(dimensions["Domain"], domain);
+                (dimensions["ApiStage"].clone(), name.clone(), *value)
+            })
+            .collect();
+        emitted.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        assert_eq!(
+            emitted,
+            vec![
+                ("beta".to_string(), "BroadcastFailures".to_string(), 1.0),
+                ("beta".to_string(), "BroadcastSuccesses".to_string(), 0.0),
+                ("prod".to_string(), "BroadcastFailures".to_string(), 0.0),
+                ("prod".to_string(), "BroadcastSuccesses".to_string(), 2.0),
+            ]
+        );
+    }
+}
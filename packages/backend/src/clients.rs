@@ -0,0 +1,73 @@
+use crate::{
+    encryption::TextCipher, request_context::RequestContext, write_limiter::WriteLimiter,
+    MetricsHelper,
+};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::OnceCell;
+
+// AWS config and clients shared by every invocation in a Lambda container. Building them is the
+// bulk of cold-start setup, so it happens once and warm invocations reuse the cached instances.
+pub struct SharedClients {
+    pub aws_config: SdkConfig,
+    pub ddb: DynamoDbClient,
+    pub metrics: MetricsHelper,
+    // The system clock, and new message ids (MESSAGE_ID_FORMAT)
+    pub context: RequestContext,
+    // Adaptive cap on concurrent message writes (WRITE_CONCURRENCY_MAX)
+    pub write_limiter: Option<Arc<WriteLimiter>>,
+    // Message text encryption at rest (ENCRYPT_AT_REST, KMS_KEY_ID)
+    pub cipher: Option<Arc<TextCipher>>,
+}
+
+static CLIENTS: OnceCell<SharedClients> = OnceCell::const_new();
+static INITIALIZATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Shared clients for this container, initialized on first use. Concurrent first callers wait
+/// on the same initialization rather than racing to build their own.
+pub async fn shared() -> &'static SharedClients {
+    CLIENTS.get_or_init(init).await
+}
+
+/// How many times the shared clients have been built in this process
+pub fn initializations() -> usize {
+    INITIALIZATIONS.load(Ordering::SeqCst)
+}
+
+async fn init() -> SharedClients {
+    INITIALIZATIONS.fetch_add(1, Ordering::SeqCst);
+
+    let context = RequestContext::from_env();
+    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+    let metrics = MetricsHelper::with_clock(context.clock.clone());
+    let ddb = DynamoDbClient::new(&aws_config);
+    let write_limiter =
+        WriteLimiter::from_env().map(|limiter| Arc::new(limiter.with_metrics(metrics.clone())));
+    // Storing plaintext when encryption was asked for is worse than not starting
+    let cipher =
+        TextCipher::from_env(&aws_config).unwrap_or_else(|err| panic!("{}", err)).map(Arc::new);
+
+    SharedClients { aws_config, ddb, metrics, context, write_limiter, cipher }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shared_clients_are_reused() {
+        // Keep the region lookup off the network
+        std::env::set_var("AWS_REGION", "us-east-1");
+
+        let (first, second) = tokio::join!(shared(), shared());
+        let third = shared().await;
+
+        assert!(std::ptr::eq(first, second));
+        assert!(std::ptr::eq(first, third));
+        assert_eq!(initializations(), 1);
+    }
+}
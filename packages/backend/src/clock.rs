@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex};
+
+/// Source of the current time for anything that's stored or reported (message timestamps,
+/// TTLs, metric timestamps), so tests can pin it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock. What every store, handler and sink uses unless given another.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The system clock, shared
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that reads whatever time it was last set to
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock_only_moves_when_told() {
+        let start = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(chrono::Duration::seconds(90));
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(90));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// Wire format for request and response bodies, negotiated from headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    /// Pick the response format from an `Accept` header value, defaulting to JSON
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(value) if value.split(',').any(is_msgpack) => Format::MessagePack,
+            _ => Format::Json,
+        }
+    }
+
+    /// Pick the request body format from a `Content-Type` header value, defaulting to JSON
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(value) if is_msgpack(value) => Format::MessagePack,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => JSON_CONTENT_TYPE,
+            Format::MessagePack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            // Named encoding keeps field names so the payload mirrors the JSON shape
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+fn is_msgpack(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE)
+        || essence.eq_ignore_ascii_case("application/x-msgpack")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use types::{ChatMessage, MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+
+    fn sample_message() -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: Utc::now(),
+            },
+            client_message_id: Some("client-1".to_string()),
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[test]
+    fn test_negotiation_defaults_to_json() {
+        assert_eq!(Format::from_accept(None), Format::Json);
+        assert_eq!(Format::from_accept(Some("*/*")), Format::Json);
+        assert_eq!(Format::from_accept(Some("application/json")), Format::Json);
+        assert_eq!(Format::from_content_type(Some("application/json")), Format::Json);
+        assert_eq!(
+            Format::from_accept(Some("text/html, application/msgpack;q=0.9")),
+            Format::MessagePack
+        );
+        assert_eq!(Format::from_content_type(Some("application/x-msgpack")), Format::MessagePack);
+    }
+
+    #[test]
+    fn test_msgpack_round_trip_matches_json_content() {
+        let message = sample_message();
+
+        let packed = Format::MessagePack.encode(&message).unwrap();
+        let from_msgpack: ChatMessage = Format::MessagePack.decode(&packed).unwrap();
+
+        let json = Format::Json.encode(&message).unwrap();
+        let from_json: ChatMessage = Format::Json.decode(&json).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&from_msgpack).unwrap(),
+            serde_json::to_value(&from_json).unwrap()
+        );
+        assert!(packed.len() < json.len());
+    }
+}
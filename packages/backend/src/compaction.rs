@@ -0,0 +1,201 @@
+use crate::ddb::{Item, ItemBuilder, ItemReader};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
+use std::{env, io::Read, io::Write};
+use types::ChatMessage;
+
+const DEFAULT_SEGMENT_SIZE: usize = 500;
+const DEFAULT_KEEP_RECENT: usize = 1000;
+
+// Items are capped at 400KB; a segment that compresses to more than this is split in half
+const MAX_SEGMENT_BYTES: usize = 300 * 1024;
+
+/// Attribute holding a segment's gzipped messages; an item with it is a segment, not a message
+pub const SEGMENT_ATTRIBUTE: &str = "segment";
+/// Sort key of the oldest message in a segment
+pub const SEGMENT_FIRST_SK_ATTRIBUTE: &str = "segment_first_sk";
+/// How many messages a segment holds
+pub const SEGMENT_COUNT_ATTRIBUTE: &str = "segment_count";
+
+const SEGMENT_SK_SUFFIX: &str = "#segment";
+
+/// Rolling old history into segments (`compact-history`). A segment is one item in the
+/// messages table holding a run of consecutive messages as gzipped JSON, oldest first, in
+/// place of an item per message. Its sort key is its newest message's with `#segment`
+/// appended, so it sorts straight after the messages it replaced and reads page into it as
+/// they would have into them.
+///
+/// The newest `keep_recent` messages (`COMPACTION_KEEP_RECENT`, default 1000) are never
+/// compacted, so new writes, delivery counts and link previews only ever touch plain items.
+/// Runs are `segment_size` messages (`COMPACTION_SEGMENT_SIZE`, default 500) and a shorter
+/// one waits for more history; ephemeral, encrypted or unreadable messages break a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compaction {
+    pub segment_size: usize,
+    pub keep_recent: usize,
+}
+
+impl Compaction {
+    // The newest message always stays a plain item, so there's somewhere to count back from
+    pub fn new(segment_size: usize, keep_recent: usize) -> Self {
+        Self { segment_size: segment_size.max(1), keep_recent: keep_recent.max(1) }
+    }
+
+    pub fn from_env() -> Self {
+        let var = |name, default| {
+            env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        };
+        Self::new(
+            var("COMPACTION_SEGMENT_SIZE", DEFAULT_SEGMENT_SIZE),
+            var("COMPACTION_KEEP_RECENT", DEFAULT_KEEP_RECENT),
+        )
+    }
+}
+
+/// What compacting one room did
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct CompactionReport {
+    pub segments_written: usize,
+    pub messages_compacted: usize,
+    // Plain items already in a segment, left behind by a run that stopped partway
+    pub leftovers_removed: usize,
+}
+
+impl CompactionReport {
+    pub fn add(&mut self, other: &CompactionReport) {
+        self.segments_written += other.segments_written;
+        self.messages_compacted += other.messages_compacted;
+        self.leftovers_removed += other.leftovers_removed;
+    }
+}
+
+pub fn is_segment(item: &Item) -> bool {
+    item.contains_key(SEGMENT_ATTRIBUTE)
+}
+
+/// Sort key of a segment whose newest message has sort key `last_sk`
+pub fn segment_sort_key(last_sk: &str) -> String {
+    format!("{}{}", last_sk, SEGMENT_SK_SUFFIX)
+}
+
+/// Sort key of a segment's newest message, from the segment's own
+pub fn last_sort_key(segment_sk: &str) -> &str {
+    segment_sk.strip_suffix(SEGMENT_SK_SUFFIX).unwrap_or(segment_sk)
+}
+
+/// A segment item holding `count` messages, encoded, whose sort keys run from `first_sk` to
+/// `last_sk`
+pub fn segment_item(
+    room_id: &str,
+    first_sk: &str,
+    last_sk: &str,
+    count: usize,
+    segment: Vec<u8>,
+) -> Item {
+    ItemBuilder::new()
+        .string("room_id", room_id)
+        .string("sk", segment_sort_key(last_sk))
+        .binary(SEGMENT_ATTRIBUTE, segment)
+        .string(SEGMENT_FIRST_SK_ATTRIBUTE, first_sk)
+        .number(SEGMENT_COUNT_ATTRIBUTE, count as i64)
+        .build()
+}
+
+/// The messages in a segment item, oldest first
+pub fn read_segment(item: &Item) -> Result<Vec<ChatMessage>, String> {
+    let segment = ItemReader::new(item)
+        .binary(SEGMENT_ATTRIBUTE)
+        .map_err(|e| format!("`{}` {}", e.field, e.error))?
+        .ok_or("Not a segment")?;
+    decode_segment(segment)
+}
+
+pub fn encode_segment(messages: &[ChatMessage]) -> Result<Vec<u8>, String> {
+    let json =
+        serde_json::to_vec(messages).map_err(|e| format!("Failed to encode segment: {}", e))?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json).map_err(|e| format!("Failed to compress segment: {}", e))?;
+    encoder.finish().map_err(|e| format!("Failed to compress segment: {}", e))
+}
+
+pub fn decode_segment(bytes: &[u8]) -> Result<Vec<ChatMessage>, String> {
+    let mut json = Vec::new();
+    GzDecoder::new(bytes)
+        .read_to_end(&mut json)
+        .map_err(|e| format!("Failed to decompress segment: {}", e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("Failed to decode segment: {}", e))
+}
+
+/// `messages` encoded as one segment or, when that would be too big for an item, as several
+/// consecutive ones, each with how many of the messages it holds
+pub fn encode_segments(messages: &[ChatMessage]) -> Result<Vec<(usize, Vec<u8>)>, String> {
+    let encoded = encode_segment(messages)?;
+    if encoded.len() <= MAX_SEGMENT_BYTES || messages.len() < 2 {
+        return Ok(vec![(messages.len(), encoded)]);
+    }
+    let (older, newer) = messages.split_at(messages.len() / 2);
+    let mut segments = encode_segments(older)?;
+    segments.extend(encode_segments(newer)?);
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use types::{MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+
+    fn message(i: i64) -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: format!("m{:04}", i),
+                room_id: "general".to_string(),
+                user_id: "u1".to_string(),
+                username: "alice".to_string(),
+                message_text: format!("message {}", i),
+                created_at: DateTime::from_timestamp_millis(1_700_000_000_000 + i).unwrap(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: Some("u1".to_string()),
+            edited_at: None,
+        }
+    }
+
+    #[test]
+    fn test_segment_round_trips_messages_unchanged() {
+        let mut messages: Vec<_> = (0..500).map(message).collect();
+        messages[3].status = MessageStatus::Hidden;
+        messages[4].format = MessageFormat::Code { lang: Some("rust".to_string()) };
+        messages[5].visibility = MessageVisibility::Direct;
+        messages[5].to_user_id = Some("u2".to_string());
+        messages[6].client_seq = Some(7);
+        messages[6].delivered_count = Some(3);
+
+        let encoded = encode_segment(&messages).unwrap();
+        let json = |messages: &[ChatMessage]| serde_json::to_value(messages).unwrap();
+        assert_eq!(json(&decode_segment(&encoded).unwrap()), json(&messages));
+        // Repetitive history is what compaction is for
+        assert!(encoded.len() < serde_json::to_vec(&messages).unwrap().len() / 4);
+        assert!(decode_segment(b"not gzip").is_err());
+    }
+
+    #[test]
+    fn test_segment_sort_key_sorts_after_its_newest_message() {
+        let last = crate::store::sort_key(1_700_000_000_499, None, "m0499");
+        let next = crate::store::sort_key(1_700_000_000_500, None, "m0500");
+        let segment = segment_sort_key(&last);
+        assert!(last < segment && segment < next);
+        assert_eq!(last_sort_key(&segment), last);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{handlers, MetricsHelper};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    sync::LazyLock,
+};
+use tracing::warn;
+
+// Whether connects and disconnects count their room for the gauge (EMIT_CONNECTION_GAUGE, on
+// unless "0" or "false"). Each count is a read per event, which busy deployments can turn off
+// and leave the gauge to the periodic pass.
+static EVENT_GAUGE: LazyLock<bool> = LazyLock::new(|| {
+    env::var("EMIT_CONNECTION_GAUGE")
+        .map_or(true, |value| !(value == "0" || value.eq_ignore_ascii_case("false")))
+});
+
+/// Emit an `ActiveConnections` gauge for every room with a connection, returning the counts
+/// by room id. One scan of the connections table counts them all. Rooms in `last_tick` (the
+/// rooms that had connections the pass before) and absent now report 0, so a room's gauge
+/// drops to zero once instead of stopping on its last count; rooms idle for longer report
+/// nothing.
+pub async fn emit_connection_gauges(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    last_tick: &BTreeSet<String>,
+    metrics: &MetricsHelper,
+) -> Result<BTreeMap<String, u32>, String> {
+    let connected = scan_strings(ddb, connections_table, "room_id").await?;
+    let counts = tally(connected, last_tick);
+    for (room_id, count) in &counts {
+        metrics.emit_active_connections(room_id, *count).await;
+    }
+    Ok(counts)
+}
+
+/// The rooms `counts` saw connections in, for the next pass's `last_tick`
+pub fn connected_rooms(counts: &BTreeMap<String, u32>) -> BTreeSet<String> {
+    counts.iter().filter(|(_, count)| **count > 0).map(|(room_id, _)| room_id.clone()).collect()
+}
+
+// Connections per room, one `room_id` per connection, with 0 for rooms of `last_tick` that
+// have none left
+fn tally(
+    connected: impl IntoIterator<Item = String>,
+    last_tick: &BTreeSet<String>,
+) -> BTreeMap<String, u32> {
+    let mut counts: BTreeMap<String, u32> =
+        last_tick.iter().map(|room_id| (room_id.clone(), 0)).collect();
+    for room_id in connected {
+        *counts.entry(room_id).or_default() += 1;
+    }
+    counts
+}
+
+/// A room's connection count for an event-driven `ActiveConnections` sample, taken after the
+/// connect or disconnect was written. None when `EMIT_CONNECTION_GAUGE` is off, or (logged)
+/// when counting fails; the event is still reported and the next periodic pass fills the gauge
+/// in.
+pub async fn count_after_change(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    room_id: &str,
+) -> Option<u32> {
+    if !*EVENT_GAUGE {
+        return None;
+    }
+    match handlers::count_room_connections(ddb, connections_table, room_id).await {
+        Ok(count) => Some(count),
+        Err(e) => {
+            warn!("Failed to count connections in room {}: {}", room_id, e);
+            None
+        }
+    }
+}
+
+// Every value of one string attribute across a table, one per item that has it
+async fn scan_strings(
+    ddb: &DynamoDbClient,
+    table: &str,
+    attribute: &str,
+) -> Result<Vec<String>, String> {
+    let mut pages = ddb
+        .scan()
+        .table_name(table)
+        .projection_expression("#attr")
+        .expression_attribute_names("#attr", attribute)
+        .into_paginator()
+        .send();
+
+    let mut values = Vec::new();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to scan {}: {:?}", table, e))?;
+        values.extend(
+            page.items().iter().filter_map(|item| item.get(attribute)?.as_s().ok()).cloned(),
+        );
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_connections_table, local_ddb, Capture};
+    use aws_sdk_dynamodb::types::AttributeValue;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rooms_left_empty_report_zero_for_one_pass() {
+        let connected = ["general", "random", "general"].map(str::to_string);
+        let counts = tally(connected, &BTreeSet::new());
+        assert_eq!(counts, BTreeMap::from([("general".into(), 2), ("random".into(), 1)]));
+
+        // Everyone leaves random: it reports 0 once, then nothing
+        let last_tick = connected_rooms(&counts);
+        let counts = tally(["general".to_string()], &last_tick);
+        assert_eq!(counts, BTreeMap::from([("general".into(), 1), ("random".into(), 0)]));
+        let counts = tally(["general".to_string()], &connected_rooms(&counts));
+        assert_eq!(counts, BTreeMap::from([("general".into(), 1)]));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_gauges_match_seeded_connections() {
+        let ddb = local_ddb().await;
+        let connections = "gauge-test-connections";
+        create_connections_table(&ddb, connections).await;
+
+        for (connection_id, room_id) in
+            [("conn-1", "general"), ("conn-2", "general"), ("conn-3", "random"), ("conn-4", "new")]
+        {
+            ddb.put_item()
+                .table_name(connections)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S(room_id.to_string()))
+                .item("user_id", AttributeValue::S(format!("user-{}", connection_id)))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let capture = Arc::new(Capture::default());
+        let metrics = MetricsHelper::with_sink(capture.clone());
+        // Quiet had connections the pass before
+        let last_tick = BTreeSet::from(["general".to_string(), "quiet".to_string()]);
+        let counts = emit_connection_gauges(&ddb, connections, &last_tick, &metrics).await.unwrap();
+
+        let expected = BTreeMap::from([
+            ("general".to_string(), 2),
+            ("new".to_string(), 1),
+            ("quiet".to_string(), 0),
+            ("random".to_string(), 1),
+        ]);
+        assert_eq!(counts, expected);
+
+        let emitted: BTreeMap<String, u32> = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value, dimensions)| {
+                assert_eq!(name, "ActiveConnections");
+                assert_eq!(dimensions.len(), 1);
+                (dimensions["RoomId"].clone(), *value as u32)
+            })
+            .collect();
+        assert_eq!(emitted, expected);
+    }
+}
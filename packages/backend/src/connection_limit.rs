@@ -0,0 +1,252 @@
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, Select},
+    Client as DynamoDbClient,
+};
+use std::env;
+
+const DEFAULT_MAX_CONNECTIONS_PER_USER: u32 = 10;
+
+const DEFAULT_MAX_CONNECTIONS_PER_IP: u32 = 50;
+
+// Per-IP counters live in the connections table under keys no connection id can take
+const IP_COUNTER_PREFIX: &str = "ip#";
+
+// A counter left behind by a missed disconnect expires with the connections it counted
+const IP_COUNTER_TTL_SECS: i64 = 60 * 60 * 24;
+
+/// Close reason (with code 1008, policy violation) for connections over the limit
+pub const TOO_MANY_CONNECTIONS: &str = "too_many_connections";
+
+/// How many connections (and so rooms) one `user_id` may hold at once
+/// (`MAX_CONNECTIONS_PER_USER`). Counted on the connections table's `user-index` GSI when a
+/// connection opens; two connects racing for the last slot may both get in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimit {
+    pub max_per_user: u32,
+}
+
+impl Default for ConnectionLimit {
+    fn default() -> Self {
+        Self { max_per_user: DEFAULT_MAX_CONNECTIONS_PER_USER }
+    }
+}
+
+impl ConnectionLimit {
+    pub fn from_env() -> Self {
+        let max_per_user = env::var("MAX_CONNECTIONS_PER_USER")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_USER);
+        Self { max_per_user }
+    }
+
+    /// Whether `user_id` may open another connection, given those already stored
+    pub async fn admits(
+        &self,
+        ddb: &DynamoDbClient,
+        connections_table: &str,
+        user_id: &str,
+    ) -> Result<bool, String> {
+        let active = count_user_connections(ddb, connections_table, user_id).await?;
+        Ok(active < self.max_per_user)
+    }
+}
+
+/// How many connections one client IP may hold at once (`MAX_CONNECTIONS_PER_IP`), so a
+/// single host can't exhaust the API by minting user ids. Tracked by a counter item per IP in
+/// the connections table, keyed by the IP's salted hash (see `OriginCapture::keyed_hash`) so
+/// no raw address is stored; `acquire` on connect and `release` on disconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpConnectionLimit {
+    pub max_per_ip: u32,
+}
+
+impl Default for IpConnectionLimit {
+    fn default() -> Self {
+        Self { max_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP }
+    }
+}
+
+impl IpConnectionLimit {
+    pub fn from_env() -> Self {
+        let max_per_ip = env::var("MAX_CONNECTIONS_PER_IP")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP);
+        Self { max_per_ip }
+    }
+
+    /// Take one of `ip_hash`'s slots, or false if it already holds `max_per_ip`. The check and
+    /// the increment are one conditional write, so racing connects can't overshoot.
+    pub async fn acquire(
+        &self,
+        ddb: &DynamoDbClient,
+        connections_table: &str,
+        ip_hash: &str,
+        now_secs: i64,
+    ) -> Result<bool, String> {
+        let result = ddb
+            .update_item()
+            .table_name(connections_table)
+            .key("connection_id", ip_counter_key(ip_hash))
+            .update_expression("ADD active :one SET #ttl = :ttl")
+            .condition_expression("attribute_not_exists(active) OR active < :max")
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(":max", AttributeValue::N(self.max_per_ip.to_string()))
+            .expression_attribute_values(
+                ":ttl",
+                AttributeValue::N((now_secs + IP_COUNTER_TTL_SECS).to_string()),
+            )
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(format!("Failed to count IP connections: {:?}", e)),
+        }
+    }
+
+    /// Give back a slot taken by `acquire`. Never takes the counter below zero.
+    pub async fn release(
+        &self,
+        ddb: &DynamoDbClient,
+        connections_table: &str,
+        ip_hash: &str,
+    ) -> Result<(), String> {
+        let result = ddb
+            .update_item()
+            .table_name(connections_table)
+            .key("connection_id", ip_counter_key(ip_hash))
+            .update_expression("ADD active :minus_one")
+            .condition_expression("active > :zero")
+            .expression_attribute_values(":minus_one", AttributeValue::N("-1".to_string()))
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(format!("Failed to release IP connection: {:?}", e)),
+        }
+    }
+}
+
+fn ip_counter_key(ip_hash: &str) -> AttributeValue {
+    AttributeValue::S(format!("{}{}", IP_COUNTER_PREFIX, ip_hash))
+}
+
+/// Connections currently held by the user, counted on the `user-index` GSI
+pub async fn count_user_connections(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    user_id: &str,
+) -> Result<u32, String> {
+    let mut pages = ddb
+        .query()
+        .table_name(connections_table)
+        .index_name("user-index")
+        .key_condition_expression("user_id = :user_id")
+        .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+        .select(Select::Count)
+        .into_paginator()
+        .send();
+
+    let mut count = 0;
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to count user connections: {:?}", e))?;
+        count += page.count() as u32;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_connections_table, local_ddb};
+
+    async fn connect(ddb: &DynamoDbClient, table: &str, connection_id: &str, user_id: &str) {
+        ddb.put_item()
+            .table_name(table)
+            .item("connection_id", AttributeValue::S(connection_id.to_string()))
+            .item("room_id", AttributeValue::S(format!("room-{}", connection_id)))
+            .item("user_id", AttributeValue::S(user_id.to_string()))
+            .item("connected_at", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_connections_beyond_the_limit_are_rejected() {
+        let ddb = local_ddb().await;
+        let table = "limit-test-connections";
+        create_connections_table(&ddb, table).await;
+        let limit = ConnectionLimit { max_per_user: 2 };
+
+        for connection_id in ["conn-1", "conn-2"] {
+            assert!(limit.admits(&ddb, table, "user-1").await.unwrap());
+            connect(&ddb, table, connection_id, "user-1").await;
+        }
+        assert!(!limit.admits(&ddb, table, "user-1").await.unwrap());
+        assert!(limit.admits(&ddb, table, "user-2").await.unwrap());
+
+        // Disconnecting frees a slot
+        ddb.delete_item()
+            .table_name(table)
+            .key("connection_id", AttributeValue::S("conn-1".to_string()))
+            .send()
+            .await
+            .unwrap();
+        assert!(limit.admits(&ddb, table, "user-1").await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_connections_beyond_the_ip_limit_are_rejected() {
+        let ddb = local_ddb().await;
+        let table = "ip-limit-test-connections";
+        create_connections_table(&ddb, table).await;
+        let limit = IpConnectionLimit { max_per_ip: 2 };
+        let now = 1_700_000_000;
+
+        assert!(limit.acquire(&ddb, table, "hash-a", now).await.unwrap());
+        assert!(limit.acquire(&ddb, table, "hash-a", now).await.unwrap());
+        assert!(!limit.acquire(&ddb, table, "hash-a", now).await.unwrap());
+        assert!(limit.acquire(&ddb, table, "hash-b", now).await.unwrap());
+
+        // Disconnecting frees a slot
+        limit.release(&ddb, table, "hash-a").await.unwrap();
+        assert!(limit.acquire(&ddb, table, "hash-a", now).await.unwrap());
+        assert!(!limit.acquire(&ddb, table, "hash-a", now).await.unwrap());
+
+        // Extra releases don't bank slots
+        for _ in 0..3 {
+            limit.release(&ddb, table, "hash-b").await.unwrap();
+        }
+        let counter = ddb
+            .get_item()
+            .table_name(table)
+            .key("connection_id", ip_counter_key("hash-b"))
+            .send()
+            .await
+            .unwrap()
+            .item
+            .unwrap();
+        assert_eq!(counter["active"], AttributeValue::N("0".to_string()));
+        assert_eq!(counter["ttl"], AttributeValue::N((now + IP_COUNTER_TTL_SECS).to_string()));
+    }
+}
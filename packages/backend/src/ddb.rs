@@ -0,0 +1,202 @@
+use aws_sdk_dynamodb::{primitives::Blob, types::AttributeValue};
+use std::{collections::HashMap, fmt};
+
+/// A DynamoDB item as the SDK sends and returns it
+pub type Item = HashMap<String, AttributeValue>;
+
+/// Why an attribute of a stored item couldn't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldError {
+    Missing,
+    // Stored as another DynamoDB type
+    WrongType { expected: &'static str },
+    // The right type, but not a usable value (e.g. an unparsable or out-of-range number)
+    Invalid,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::Missing => write!(f, "is missing"),
+            FieldError::WrongType { expected } => write!(f, "is not of type {}", expected),
+            FieldError::Invalid => write!(f, "has an unusable value"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptField {
+    pub field: &'static str,
+    pub error: FieldError,
+}
+
+/// Builds an item attribute by attribute, so every write site encodes a type the same way.
+/// `None` optionals and empty sets are left out rather than written as nulls, matching what
+/// `ItemReader` treats as absent.
+#[derive(Debug, Clone, Default)]
+pub struct ItemBuilder {
+    item: Item,
+}
+
+impl ItemBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn string(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.item.insert(key.to_string(), AttributeValue::S(value.into()));
+        self
+    }
+
+    pub fn optional_string(self, key: &str, value: Option<impl Into<String>>) -> Self {
+        match value {
+            Some(value) => self.string(key, value),
+            None => self,
+        }
+    }
+
+    pub fn number(mut self, key: &str, value: i64) -> Self {
+        self.item.insert(key.to_string(), AttributeValue::N(value.to_string()));
+        self
+    }
+
+    pub fn optional_number(self, key: &str, value: Option<i64>) -> Self {
+        match value {
+            Some(value) => self.number(key, value),
+            None => self,
+        }
+    }
+
+    pub fn bool(mut self, key: &str, value: bool) -> Self {
+        self.item.insert(key.to_string(), AttributeValue::Bool(value));
+        self
+    }
+
+    pub fn binary(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.item.insert(key.to_string(), AttributeValue::B(Blob::new(value)));
+        self
+    }
+
+    /// DynamoDB rejects empty sets, so an empty one is omitted
+    pub fn string_set<S: Into<String>>(
+        mut self,
+        key: &str,
+        values: impl IntoIterator<Item = S>,
+    ) -> Self {
+        let values: Vec<String> = values.into_iter().map(Into::into).collect();
+        if !values.is_empty() {
+            self.item.insert(key.to_string(), AttributeValue::Ss(values));
+        }
+        self
+    }
+
+    pub fn build(self) -> Item {
+        self.item
+    }
+}
+
+/// Typed access to an item's attributes. Each getter is Ok(None) when the attribute is absent
+/// and a `CorruptField` when it's stored as the wrong type or can't be decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct ItemReader<'a> {
+    item: &'a Item,
+}
+
+impl<'a> ItemReader<'a> {
+    pub fn new(item: &'a Item) -> Self {
+        Self { item }
+    }
+
+    pub fn string(&self, field: &'static str) -> Result<Option<&'a String>, CorruptField> {
+        match self.item.get(field) {
+            None => Ok(None),
+            Some(AttributeValue::S(value)) => Ok(Some(value)),
+            Some(_) => Err(wrong_type(field, "S")),
+        }
+    }
+
+    pub fn number(&self, field: &'static str) -> Result<Option<i64>, CorruptField> {
+        match self.item.get(field) {
+            None => Ok(None),
+            Some(AttributeValue::N(value)) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| CorruptField { field, error: FieldError::Invalid }),
+            Some(_) => Err(wrong_type(field, "N")),
+        }
+    }
+
+    pub fn bool(&self, field: &'static str) -> Result<Option<bool>, CorruptField> {
+        match self.item.get(field) {
+            None => Ok(None),
+            Some(AttributeValue::Bool(value)) => Ok(Some(*value)),
+            Some(_) => Err(wrong_type(field, "BOOL")),
+        }
+    }
+
+    pub fn binary(&self, field: &'static str) -> Result<Option<&'a [u8]>, CorruptField> {
+        match self.item.get(field) {
+            None => Ok(None),
+            Some(AttributeValue::B(value)) => Ok(Some(value.as_ref())),
+            Some(_) => Err(wrong_type(field, "B")),
+        }
+    }
+
+    pub fn string_set(&self, field: &'static str) -> Result<Option<&'a [String]>, CorruptField> {
+        match self.item.get(field) {
+            None => Ok(None),
+            Some(AttributeValue::Ss(values)) => Ok(Some(values)),
+            Some(_) => Err(wrong_type(field, "SS")),
+        }
+    }
+}
+
+fn wrong_type(field: &'static str, expected: &'static str) -> CorruptField {
+    CorruptField { field, error: FieldError::WrongType { expected } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_omits_absent_values() {
+        let item = ItemBuilder::new()
+            .string("id", "m1")
+            .optional_string("client_message_id", None::<String>)
+            .optional_number("ttl", None)
+            .string_set("mentions", Vec::<String>::new())
+            .build();
+
+        assert_eq!(item.len(), 1);
+        assert_eq!(item["id"], AttributeValue::S("m1".to_string()));
+    }
+
+    #[test]
+    fn test_builder_encodes_numbers_and_sets() {
+        let item = ItemBuilder::new()
+            .number("ts", -1_700_000_000_000)
+            .optional_number("ttl", Some(42))
+            .bool("ephemeral", true)
+            .string_set("mentions", ["alice", "bob"])
+            .binary("segment", b"gz".to_vec())
+            .build();
+
+        assert_eq!(item["ts"], AttributeValue::N("-1700000000000".to_string()));
+        assert_eq!(item["ttl"], AttributeValue::N("42".to_string()));
+        assert_eq!(item["ephemeral"], AttributeValue::Bool(true));
+        assert_eq!(
+            item["mentions"],
+            AttributeValue::Ss(vec!["alice".to_string(), "bob".to_string()])
+        );
+
+        // Whatever the builder writes, the reader gets back
+        let reader = ItemReader::new(&item);
+        assert_eq!(reader.number("ts"), Ok(Some(-1_700_000_000_000)));
+        assert_eq!(reader.bool("ephemeral"), Ok(Some(true)));
+        assert_eq!(reader.string_set("mentions").unwrap().map(<[String]>::len), Some(2));
+        assert_eq!(reader.binary("segment"), Ok(Some(&b"gz"[..])));
+        assert_eq!(reader.string("missing"), Ok(None));
+        assert_eq!(reader.string("ts"), Err(wrong_type("ts", "S")));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{broadcast, handlers};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_kms::Client as KmsClient;
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::{env, time::Duration};
+use tokio::time::Instant;
+
+const DEFAULT_TIMEOUT_MS: u64 = 3000;
+
+// No connection has this id, so asking about it reads nothing and posts nothing
+const PROBE_CONNECTION_ID: &str = "dependency-probe";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeStatus {
+    Pass,
+    Fail,
+    // Not configured in this environment, so there was nothing to probe
+    Skipped,
+}
+
+// Outcome of probing one dependency
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyCheck {
+    pub name: &'static str,
+    pub status: ProbeStatus,
+    // None when the probe was skipped
+    pub latency_ms: Option<u64>,
+    pub detail: String,
+}
+
+// Body of `GET /health/dependencies`; healthy unless a probe failed
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyReport {
+    pub healthy: bool,
+    pub checks: Vec<DependencyCheck>,
+}
+
+/// How one dependency is reached
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    /// A DynamoDB table, described
+    Table(String),
+    /// The WebSocket management endpoint, asked about a connection that doesn't exist. An
+    /// answer of "gone" means it's reachable and we may call it.
+    ManagementApi(String),
+    /// A KMS key, described
+    Kms(String),
+    /// Configured, but not in a way that could work; fails without a call
+    Misconfigured(String),
+}
+
+/// A named external dependency, None when this environment doesn't use it
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub name: &'static str,
+    pub probe: Option<Probe>,
+}
+
+/// Reachability and latency of every external dependency, for `GET /health/dependencies`. All
+/// probes run at once and share one deadline (`DEPENDENCY_CHECK_TIMEOUT_MS`, default 3000); a
+/// probe still running when it passes fails as timed out.
+pub struct DependencyHealth {
+    config: SdkConfig,
+    dependencies: Vec<Dependency>,
+    timeout: Duration,
+}
+
+impl DependencyHealth {
+    pub fn new(config: &SdkConfig, dependencies: Vec<Dependency>) -> Self {
+        Self {
+            config: config.clone(),
+            dependencies,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Every table the service may use, the management endpoint and, with `ENCRYPT_AT_REST`,
+    /// the KMS key, each from the variable that configures it
+    pub fn from_env(config: &SdkConfig) -> Self {
+        let var = |name| env::var(name).ok().filter(|value: &String| !value.is_empty());
+        let table = |name, var_name| Dependency { name, probe: var(var_name).map(Probe::Table) };
+
+        let management_api = var("WS_API_ID").map(|api_id| {
+            let region = var("AWS_REGION").unwrap_or_default();
+            let stage = var("WS_STAGE").unwrap_or_default();
+            match broadcast::default_endpoint(&api_id, &region, &stage) {
+                Ok(endpoint) => Probe::ManagementApi(endpoint),
+                Err(e) => Probe::Misconfigured(e),
+            }
+        });
+        // As `TextCipher::from_env` decides it
+        let encrypted = env::var("ENCRYPT_AT_REST")
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+        let kms = encrypted.then(|| {
+            var("KMS_KEY_ID").map(Probe::Kms).unwrap_or_else(|| {
+                Probe::Misconfigured("ENCRYPT_AT_REST needs KMS_KEY_ID to be set".to_string())
+            })
+        });
+
+        let dependencies = vec![
+            table("rooms_table", "CHAT_ROOMS_TABLE"),
+            table("messages_table", "CHAT_MESSAGES_TABLE"),
+            table("connections_table", "CONNECTIONS_TABLE"),
+            table("users_table", "CHAT_USERS_TABLE"),
+            table("flags_table", "CHAT_FLAGS_TABLE"),
+            table("retry_table", "RETRY_TABLE"),
+            table("audit_table", "AUDIT_TABLE"),
+            Dependency { name: "management_api", probe: management_api },
+            Dependency { name: "kms", probe: kms },
+        ];
+        let timeout = var("DEPENDENCY_CHECK_TIMEOUT_MS")
+            .and_then(|value| value.parse().ok())
+            .map_or(Duration::from_millis(DEFAULT_TIMEOUT_MS), Duration::from_millis);
+        Self::new(config, dependencies).with_timeout(timeout)
+    }
+
+    /// Probe everything, in the order the dependencies were given
+    pub async fn check(&self) -> DependencyReport {
+        let deadline = Instant::now() + self.timeout;
+        let ddb = DynamoDbClient::new(&self.config);
+        let checks = join_all(self.dependencies.iter().map(|dependency| {
+            let ddb = &ddb;
+            async move {
+                let Some(probe) = &dependency.probe else {
+                    return DependencyCheck {
+                        name: dependency.name,
+                        status: ProbeStatus::Skipped,
+                        latency_ms: None,
+                        detail: "not configured".to_string(),
+                    };
+                };
+                let started = Instant::now();
+                let outcome = tokio::time::timeout_at(deadline, self.probe(ddb, probe))
+                    .await
+                    .unwrap_or_else(|_| Err(format!("timed out after {:?}", self.timeout)));
+                let (status, detail) = match outcome {
+                    Ok(detail) => (ProbeStatus::Pass, detail),
+                    Err(detail) => (ProbeStatus::Fail, detail),
+                };
+                DependencyCheck {
+                    name: dependency.name,
+                    status,
+                    latency_ms: Some(started.elapsed().as_millis() as u64),
+                    detail,
+                }
+            }
+        }))
+        .await;
+
+        let healthy = checks.iter().all(|check| check.status != ProbeStatus::Fail);
+        DependencyReport { healthy, checks }
+    }
+
+    async fn probe(&self, ddb: &DynamoDbClient, probe: &Probe) -> Result<String, String> {
+        match probe {
+            Probe::Table(table) => {
+                let description = handlers::describe_table(ddb, table).await?;
+                Ok(format!(
+                    "table {} is {}",
+                    table,
+                    description
+                        .table_status()
+                        .map_or("in an unknown state", |status| status.as_str())
+                ))
+            }
+            Probe::ManagementApi(endpoint) => {
+                let client = broadcast::endpoint_client(&self.config, endpoint);
+                match client.get_connection().connection_id(PROBE_CONNECTION_ID).send().await {
+                    Ok(_) => Ok(format!("{} reachable", endpoint)),
+                    Err(e) if e.as_service_error().is_some_and(|e| e.is_gone_exception()) => {
+                        Ok(format!("{} reachable", endpoint))
+                    }
+                    Err(e) => Err(format!("Failed to reach {}: {:?}", endpoint, e)),
+                }
+            }
+            Probe::Kms(key_id) => {
+                let output = KmsClient::new(&self.config)
+                    .describe_key()
+                    .key_id(key_id)
+                    .send()
+                    .await
+                    .map_err(|e| format!("Failed to describe key {}: {:?}", key_id, e))?;
+                let state = output.key_metadata().and_then(|metadata| metadata.key_state());
+                Ok(format!(
+                    "key {} is {}",
+                    key_id,
+                    state.map_or("in an unknown state", |state| state.as_str())
+                ))
+            }
+            Probe::Misconfigured(detail) => Err(detail.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_table, local_config};
+    use aws_sdk_dynamodb::types::KeyType;
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_dependencies_report_tables_and_skip_what_is_unconfigured() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        create_table(&ddb, "dependency-rooms", &[("id", KeyType::Hash)]).await;
+        create_table(
+            &ddb,
+            "dependency-messages",
+            &[("room_id", KeyType::Hash), ("sk", KeyType::Range)],
+        )
+        .await;
+
+        let table =
+            |name, table: &str| Dependency { name, probe: Some(Probe::Table(table.into())) };
+        let dependencies = vec![
+            table("rooms_table", "dependency-rooms"),
+            table("messages_table", "dependency-messages"),
+            Dependency { name: "kms", probe: None },
+        ];
+        let report = DependencyHealth::new(&config, dependencies.clone()).check().await;
+        assert!(report.healthy, "{:?}", report);
+        let statuses: Vec<_> =
+            report.checks.iter().map(|check| (check.name, check.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("rooms_table", ProbeStatus::Pass),
+                ("messages_table", ProbeStatus::Pass),
+                ("kms", ProbeStatus::Skipped),
+            ]
+        );
+        assert!(report.checks[0].latency_ms.is_some());
+        assert_eq!(report.checks[2].latency_ms, None);
+
+        // A missing table fails on its own, without taking the others down with it
+        let mut dependencies = dependencies;
+        dependencies.push(table("flags_table", "dependency-missing"));
+        let report = DependencyHealth::new(&config, dependencies).check().await;
+        assert!(!report.healthy);
+        let failed: Vec<_> =
+            report.checks.iter().filter(|check| check.status == ProbeStatus::Fail).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "flags_table");
+        assert!(failed[0].detail.contains("dependency-missing"), "{}", failed[0].detail);
+    }
+
+    #[tokio::test]
+    async fn test_misconfigured_dependency_fails_without_a_call() {
+        let config = local_config().await;
+        let dependencies = vec![
+            Dependency { name: "management_api", probe: Some(Probe::Misconfigured("bad".into())) },
+            Dependency { name: "kms", probe: None },
+        ];
+        let report = DependencyHealth::new(&config, dependencies).check().await;
+        assert!(!report.healthy);
+        assert_eq!(report.checks[0].status, ProbeStatus::Fail);
+        assert_eq!(report.checks[0].detail, "bad");
+        assert_eq!(report.checks[1].status, ProbeStatus::Skipped);
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Deserialize;
+use std::env;
+
+/// Diagnostic echo for the WebSocket path (`WS_ECHO_ENABLED`, off by default). A frame with
+/// `"type": "echo"` goes straight back to its sender, byte for byte, without being stored or
+/// broadcast, so QA can time round trips and check a socket works both ways.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EchoMode {
+    pub enabled: bool,
+}
+
+// Just enough of a frame to tell whether it asks for an echo
+#[derive(Deserialize)]
+struct FrameType {
+    #[serde(rename = "type")]
+    frame_type: Option<String>,
+}
+
+impl EchoMode {
+    pub fn from_env() -> Self {
+        let enabled = env::var("WS_ECHO_ENABLED")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        Self { enabled }
+    }
+
+    /// What to send back for `frame`: the frame itself when it's an echo request and echoing
+    /// is on, otherwise nothing and the frame is handled as usual
+    pub fn reply<'a>(&self, frame: &'a str) -> Option<&'a str> {
+        if !self.enabled {
+            return None;
+        }
+        let parsed: FrameType = serde_json::from_str(frame).ok()?;
+        (parsed.frame_type.as_deref() == Some("echo")).then_some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_echo_frames_come_back_and_only_when_enabled() {
+        let frame = r#"{ "type": "echo", "sent_at": 1700000000123, "nonce": "a1" }"#;
+        assert_eq!(EchoMode { enabled: true }.reply(frame), Some(frame));
+        assert_eq!(EchoMode { enabled: false }.reply(frame), None);
+
+        let echo = EchoMode { enabled: true };
+        assert_eq!(echo.reply(r#"{"is_typing": true}"#), None);
+        assert_eq!(echo.reply(r#"{"type": "typing"}"#), None);
+        assert_eq!(echo.reply("echo"), None);
+    }
+}
@@ -0,0 +1,198 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use async_trait::async_trait;
+use aws_config::SdkConfig;
+use aws_sdk_kms::{primitives::Blob, types::DataKeySpec, Client as KmsClient};
+use std::{collections::HashMap, env, sync::Mutex};
+use tokio::sync::OnceCell;
+
+/// Stored attribute holding an encrypted message's text, in place of `message_text`
+pub const ENCRYPTED_TEXT_ATTRIBUTE: &str = "message_text_enc";
+
+// Leads every envelope, so the layout can change without misreading older rows
+const ENVELOPE_VERSION: &str = "v1";
+
+/// Where the data keys message text is encrypted under come from
+#[async_trait]
+pub trait DataKeys: Send + Sync {
+    /// A new 256-bit data key, as plaintext and wrapped for storage
+    async fn generate(&self) -> Result<(Vec<u8>, Vec<u8>), String>;
+
+    /// The plaintext of a key `generate` wrapped
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// Data keys generated and unwrapped by KMS under `key_id`
+pub struct KmsDataKeys {
+    client: KmsClient,
+    key_id: String,
+}
+
+impl KmsDataKeys {
+    pub fn new(config: &SdkConfig, key_id: String) -> Self {
+        Self { client: KmsClient::new(config), key_id }
+    }
+}
+
+#[async_trait]
+impl DataKeys for KmsDataKeys {
+    async fn generate(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let output = self
+            .client
+            .generate_data_key()
+            .key_id(&self.key_id)
+            .key_spec(DataKeySpec::Aes256)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to generate data key: {:?}", e))?;
+        match (output.plaintext, output.ciphertext_blob) {
+            (Some(plaintext), Some(wrapped)) => Ok((plaintext.into_inner(), wrapped.into_inner())),
+            _ => Err("KMS returned an incomplete data key".to_string()),
+        }
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        let output = self
+            .client
+            .decrypt()
+            .key_id(&self.key_id)
+            .ciphertext_blob(Blob::new(wrapped))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to unwrap data key: {:?}", e))?;
+        output.plaintext.map(Blob::into_inner).ok_or_else(|| "KMS returned no key".to_string())
+    }
+}
+
+/// Envelope encryption of message text at rest (`ENCRYPT_AT_REST`, with the KMS key in
+/// `KMS_KEY_ID`). Text is sealed with AES-256-GCM under a data key, and stored as
+/// `v1.{wrapped key}.{nonce}.{ciphertext}` in hex, bound to its message id so it can't be
+/// moved to another row. One data key is generated per process and unwrapped keys are kept,
+/// so KMS is called once per key rather than once per message.
+pub struct TextCipher {
+    keys: Box<dyn DataKeys>,
+    // The key new text is sealed under, with its wrapped form
+    current: OnceCell<(Key<Aes256Gcm>, Vec<u8>)>,
+    unwrapped: Mutex<HashMap<Vec<u8>, Key<Aes256Gcm>>>,
+}
+
+impl TextCipher {
+    pub fn new(keys: Box<dyn DataKeys>) -> Self {
+        Self { keys, current: OnceCell::new(), unwrapped: Mutex::default() }
+    }
+
+    /// None unless `ENCRYPT_AT_REST` is set; then `KMS_KEY_ID` must be too
+    pub fn from_env(config: &SdkConfig) -> Result<Option<Self>, String> {
+        let enabled = env::var("ENCRYPT_AT_REST")
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+        if !enabled {
+            return Ok(None);
+        }
+        let key_id = env::var("KMS_KEY_ID")
+            .ok()
+            .filter(|key_id| !key_id.is_empty())
+            .ok_or("ENCRYPT_AT_REST needs KMS_KEY_ID to be set")?;
+        Ok(Some(Self::new(Box::new(KmsDataKeys::new(config, key_id)))))
+    }
+
+    /// Seal `text` for the message `message_id`
+    pub async fn encrypt(&self, message_id: &str, text: &str) -> Result<String, String> {
+        let (key, wrapped) = self
+            .current
+            .get_or_try_init(|| async {
+                let (plaintext, wrapped) = self.keys.generate().await?;
+                Ok::<_, String>((data_key(&plaintext)?, wrapped))
+            })
+            .await?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(key)
+            .encrypt(&nonce, Payload { msg: text.as_bytes(), aad: message_id.as_bytes() })
+            .map_err(|_| "Failed to encrypt message text".to_string())?;
+        Ok(format!(
+            "{}.{}.{}.{}",
+            ENVELOPE_VERSION,
+            hex::encode(wrapped),
+            hex::encode(nonce),
+            hex::encode(ciphertext)
+        ))
+    }
+
+    /// Open what `encrypt` sealed for `message_id`. Fails if the envelope was altered.
+    pub async fn decrypt(&self, message_id: &str, envelope: &str) -> Result<String, String> {
+        let invalid = || format!("Invalid encrypted text for message {}", message_id);
+        let parts: Vec<&str> = envelope.split('.').collect();
+        let [ENVELOPE_VERSION, wrapped, nonce, ciphertext] = parts[..] else {
+            return Err(invalid());
+        };
+        let wrapped = hex::decode(wrapped).map_err(|_| invalid())?;
+        let nonce = hex::decode(nonce).map_err(|_| invalid())?;
+        let ciphertext = hex::decode(ciphertext).map_err(|_| invalid())?;
+        if nonce.len() != 12 {
+            return Err(invalid());
+        }
+
+        let cached = self.unwrapped.lock().unwrap().get(&wrapped).copied();
+        let key = match cached {
+            Some(key) => key,
+            None => {
+                let key = data_key(&self.keys.unwrap_key(&wrapped).await?)?;
+                self.unwrapped.lock().unwrap().insert(wrapped, key);
+                key
+            }
+        };
+        let plaintext = Aes256Gcm::new(&key)
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload { msg: &ciphertext, aad: message_id.as_bytes() },
+            )
+            .map_err(|_| invalid())?;
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+}
+
+fn data_key(plaintext: &[u8]) -> Result<Key<Aes256Gcm>, String> {
+    if plaintext.len() != 32 {
+        return Err(format!("Data key is {} bytes, not 32", plaintext.len()));
+    }
+    Ok(*Key::<Aes256Gcm>::from_slice(plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::StaticDataKeys;
+
+    #[tokio::test]
+    async fn test_encrypted_text_round_trips() {
+        let cipher = TextCipher::new(Box::new(StaticDataKeys));
+        let sealed = cipher.encrypt("m1", "Hello, vault!").await.unwrap();
+        assert!(sealed.starts_with("v1."));
+        assert!(!sealed.contains(&hex::encode("Hello")));
+        // Same key, fresh nonce each time
+        assert_ne!(cipher.encrypt("m1", "Hello, vault!").await.unwrap(), sealed);
+
+        // A process that didn't seal it opens it by unwrapping the stored data key
+        let reader = TextCipher::new(Box::new(StaticDataKeys));
+        assert_eq!(reader.decrypt("m1", &sealed).await.unwrap(), "Hello, vault!");
+        assert_eq!(reader.decrypt("m1", &sealed).await.unwrap(), "Hello, vault!");
+    }
+
+    #[tokio::test]
+    async fn test_tampered_ciphertext_fails_to_decrypt() {
+        let cipher = TextCipher::new(Box::new(StaticDataKeys));
+        let sealed = cipher.encrypt("m1", "Hello, vault!").await.unwrap();
+
+        // Flip the last byte of the ciphertext
+        let mut tampered = sealed.clone();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+        assert!(cipher.decrypt("m1", &tampered).await.is_err());
+
+        // Text sealed for one message doesn't open as another's
+        assert!(cipher.decrypt("m2", &sealed).await.is_err());
+        assert!(cipher.decrypt("m1", "v1.zz.00.00").await.is_err());
+        assert!(cipher.decrypt("m1", "Hello").await.is_err());
+    }
+}
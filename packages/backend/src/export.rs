@@ -0,0 +1,227 @@
+use crate::{
+    handlers::{redact_hidden, validate_room_id, MessageQuery},
+    store::{self, MessageStore},
+};
+use futures_util::{stream, Stream, StreamExt};
+use std::{borrow::Cow, str::FromStr, sync::Arc};
+use types::{ChatMessage, RoomId};
+
+/// Columns of a CSV export, in order
+pub const CSV_HEADER: &str = "id,created_at,username,message_text\r\n";
+
+/// How a room export is written: CSV (the default) or one JSON message per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    /// `Content-Disposition` offering the export as a download named after the room
+    pub fn content_disposition(&self, room_id: &str) -> String {
+        let extension = match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        };
+        format!("attachment; filename=\"{}.{}\"", room_id, extension)
+    }
+
+    fn render(&self, message: &ChatMessage) -> Result<String, String> {
+        match self {
+            ExportFormat::Csv => Ok(csv_row(message)),
+            ExportFormat::Jsonl => {
+                serde_json::to_string(message).map(|line| line + "\n").map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            other => Err(format!("Unknown export format '{}'; expected csv or jsonl", other)),
+        }
+    }
+}
+
+// RFC 4180: fields holding a comma, quote or line break are quoted, with quotes doubled
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// One message as a CSV line, in `CSV_HEADER`'s column order
+pub fn csv_row(message: &ChatMessage) -> String {
+    let core = &message.core;
+    format!(
+        "{},{},{},{}\r\n",
+        csv_field(&core.id),
+        core.created_at.to_rfc3339(),
+        csv_field(&core.username),
+        csv_field(&core.message_text)
+    )
+}
+
+/// The room's messages, oldest first, rendered as `format` a page at a time, so memory stays
+/// bounded however big the room is. Each page starts after the last message of the one before
+/// and the export ends at the first empty page.
+pub fn export_messages(
+    store: Arc<dyn MessageStore>,
+    room_id: RoomId,
+    format: ExportFormat,
+) -> Result<impl Stream<Item = Result<String, String>> + Send + 'static, String> {
+    let room_id = validate_room_id(&room_id)?;
+    let header = match format {
+        ExportFormat::Csv => Some(Ok(CSV_HEADER.to_string())),
+        ExportFormat::Jsonl => None,
+    };
+
+    // The state is the cursor for the next page; None once the room is exhausted
+    let pages = stream::try_unfold(Some(None), move |after| {
+        let (store, room_id) = (store.clone(), room_id.clone());
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let query = MessageQuery { after, ..MessageQuery::default() };
+            let messages = store.get_messages(&room_id, &query).await?;
+            let Some(last) = messages.last() else {
+                return Ok(None);
+            };
+            let cursor = store::message_sort_key(last);
+            let chunk = messages
+                .into_iter()
+                .map(|mut message| {
+                    redact_hidden(&mut message);
+                    format.render(&message)
+                })
+                .collect::<Result<String, String>>()?;
+            Ok(Some((chunk, Some(Some(cursor)))))
+        }
+    });
+    Ok(stream::iter(header).chain(pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock, handlers::post_message_handler, request_context::RequestContext,
+        store::MemoryMessageStore,
+    };
+    use chrono::{DateTime, Duration};
+    use futures_util::TryStreamExt;
+    use types::{MessageFormat, SendMessageRequest};
+
+    // Just enough of RFC 4180 to read an export back
+    fn parse_csv(text: &str) -> Vec<Vec<String>> {
+        let (mut rows, mut row, mut field) = (Vec::new(), Vec::new(), String::new());
+        let mut chars = text.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') => quoted = true,
+                (false, ',') => row.push(std::mem::take(&mut field)),
+                (false, '\r') => {}
+                (false, '\n') => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                (false, c) => field.push(c),
+            }
+        }
+        rows
+    }
+
+    // A store whose clock moves on a millisecond per post, so posts keep their order. Posts are
+    // stamped from the same clock.
+    fn clocked_store() -> (Arc<MemoryMessageStore>, Arc<MockClock>) {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        (Arc::new(MemoryMessageStore::new().with_clock(clock.clone())), clock)
+    }
+
+    async fn post(store: &MemoryMessageStore, clock: &Arc<MockClock>, message_text: &str) {
+        clock.advance(Duration::milliseconds(1));
+        let request = SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice, \"the admin\"".to_string(),
+            message_text: message_text.to_string(),
+            client_message_id: None,
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        };
+        let context = RequestContext::default().with_clock(clock.clone());
+        post_message_handler(store, &context, request, None).await.unwrap();
+    }
+
+    async fn export(store: &Arc<MemoryMessageStore>, format: ExportFormat) -> String {
+        export_messages(store.clone(), "general".into(), format)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_csv_export_parses_back() {
+        let (store, clock) = clocked_store();
+        let awkward = "Commas, \"quotes\"\nand a second line\r\nand a third";
+        post(&store, &clock, awkward).await;
+        // Enough to take several pages
+        for i in 0..store::MESSAGE_PAGE_SIZE * 2 {
+            post(&store, &clock, &format!("message {}", i)).await;
+        }
+
+        let rows = parse_csv(&export(&store, ExportFormat::Csv).await);
+        assert_eq!(rows[0], vec!["id", "created_at", "username", "message_text"]);
+        assert_eq!(rows.len(), 1 + 1 + store::MESSAGE_PAGE_SIZE * 2);
+        assert_eq!(rows[1][2], "alice, \"the admin\"");
+        assert_eq!(rows[1][3], awkward);
+        assert_eq!(rows[2][3], "message 0");
+        assert!(rows.iter().all(|row| row.len() == 4));
+
+        let ids: std::collections::HashSet<_> = rows[1..].iter().map(|row| &row[0]).collect();
+        assert_eq!(ids.len(), rows.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_export_has_a_message_per_line() {
+        let (store, clock) = clocked_store();
+        post(&store, &clock, "first\nline").await;
+        post(&store, &clock, "second").await;
+
+        let jsonl = export(&store, ExportFormat::Jsonl).await;
+        let messages: Vec<ChatMessage> =
+            jsonl.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let texts: Vec<_> = messages.iter().map(|m| m.core.message_text.as_str()).collect();
+        assert_eq!(texts, vec!["first\nline", "second"]);
+
+        assert!(export(&clocked_store().0, ExportFormat::Jsonl).await.is_empty());
+    }
+}
@@ -1,10 +1,237 @@
-use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-use chrono::Utc;
-use std::{collections::HashMap, env};
-use tracing::info;
-use types::{ChatMessage, GetMessagesResponse, HealthCheck, HealthStatus, SendMessageRequest};
+use crate::{
+    id_generator::IdGenerator,
+    identity::{display_name_fallback, AnonymousPolicy},
+    moderation::Blocklist,
+    origin::{MessageOrigin, OriginCapture},
+    page_cursor::{CursorError, PageCursors, PAGINATION_DEPTH_EXCEEDED},
+    request_context::RequestContext,
+    search::SearchIndex,
+    store::{self, MessageStore, ProbeError, PutMessageError, RoomKey, StoreError},
+    text_pipeline::TextPipeline,
+    validation::{Limits, UserIdFormat, Validate, ValidationError},
+    MetricsHelper,
+};
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, KeySchemaElement, KeyType, Select, TableDescription},
+    Client as DynamoDbClient,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    net::IpAddr,
+    sync::LazyLock,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+use types::{
+    AdminMessageView, ChatMessage, EditMessageRequest, FlagMessageRequest, FlagMessageResponse,
+    GetMessagesResponse, HealthCheck, HealthStatus, LatestMessagesRequest, LatestMessagesResponse,
+    ListRoomsResponse, MessageCore, MessageFlag, MessageFormat, MessageId, MessageStatus,
+    MessageVisibility, PollMessagesResponse, RoomArchiveState, RoomId, RoomPresence, RoomStats,
+    SearchMessagesResponse, SendMessageRequest, TopicChanged, UpdateAvatarSeedRequest,
+    UpdateTopicRequest, UpdateUsernameRequest, UserId, UserProfile, UserRenamed,
+};
 use uuid::Uuid;
 
+// Difference between a client's timestamp and the server's that's still taken as agreement
+const DEFAULT_CLOCK_SKEW_THRESHOLD_MS: i64 = 5_000;
+
+// How long the health check waits on the messages table before calling it Degraded
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How far back an identical post is looked for when content dedup is on
+const DEFAULT_CONTENT_DEDUP_SECS: i64 = 3;
+
+// How long after posting a message its poster may still edit it
+const DEFAULT_EDIT_WINDOW_SECS: i64 = 900;
+
+// Opt-in behaviours for post_message_handler, read once from the environment
+#[derive(Debug, Clone, Copy)]
+struct PostOptions {
+    // Derive message ids from (room_id, user_id, client_message_id) so client retries collide
+    deterministic_ids: bool,
+    // Keep at most this many messages per room, evicting the oldest
+    room_message_cap: Option<u64>,
+    // Whether posts without a user_id are accepted under a generated anonymous id
+    anonymous: AnonymousPolicy,
+    // Skew between `client_created_at` and the server's timestamp that gets flagged
+    clock_skew_threshold_ms: i64,
+    // Answer a post identical to one the same user made this recently with the stored message
+    content_dedup: Option<chrono::Duration>,
+}
+
+impl Default for PostOptions {
+    fn default() -> Self {
+        Self {
+            deterministic_ids: false,
+            room_message_cap: None,
+            anonymous: AnonymousPolicy::default(),
+            clock_skew_threshold_ms: DEFAULT_CLOCK_SKEW_THRESHOLD_MS,
+            content_dedup: None,
+        }
+    }
+}
+
+impl PostOptions {
+    fn from_env() -> Self {
+        Self {
+            deterministic_ids: env::var("DETERMINISTIC_MESSAGE_IDS")
+                .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            room_message_cap: env::var("ROOM_MESSAGE_CAP")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|cap| *cap > 0),
+            anonymous: AnonymousPolicy::from_env(),
+            clock_skew_threshold_ms: env::var("CLOCK_SKEW_THRESHOLD_MS")
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .filter(|threshold| *threshold >= 0)
+                .unwrap_or(DEFAULT_CLOCK_SKEW_THRESHOLD_MS),
+            content_dedup: env::var("ENABLE_CONTENT_DEDUP")
+                .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+                .then(|| {
+                    let secs = env::var("CONTENT_DEDUP_SECS")
+                        .ok()
+                        .and_then(|value| value.parse::<i64>().ok())
+                        .filter(|secs| *secs > 0)
+                        .unwrap_or(DEFAULT_CONTENT_DEDUP_SECS);
+                    chrono::Duration::seconds(secs)
+                }),
+        }
+    }
+}
+
+static POST_OPTIONS: LazyLock<PostOptions> = LazyLock::new(PostOptions::from_env);
+
+// Text processing applied to validated message text (TEXT_TRANSFORMS)
+static TEXT_PIPELINE: LazyLock<TextPipeline> = LazyLock::new(TextPipeline::from_env);
+
+// Longest accepted usernames, message text and room topics (MAX_USERNAME_LENGTH,
+// MAX_MESSAGE_LENGTH, MAX_TOPIC_LENGTH)
+static LIMITS: LazyLock<Limits> = LazyLock::new(Limits::from_env);
+
+// Terms message text may not contain (MESSAGE_BLOCKLIST)
+static BLOCKLIST: LazyLock<Blocklist> = LazyLock::new(Blocklist::from_env);
+
+// What user ids may look like (MAX_USER_ID_LENGTH, USER_ID_PUNCTUATION)
+static USER_ID_FORMAT: LazyLock<UserIdFormat> = LazyLock::new(UserIdFormat::from_env);
+
+// Sender origin recorded for moderators (CAPTURE_ORIGIN, ORIGIN_HASH_SALT)
+static ORIGIN_CAPTURE: LazyLock<OriginCapture> = LazyLock::new(OriginCapture::from_env);
+
+// Signed page cursors and how deep they may go (CURSOR_SECRET, MAX_PAGE_DEPTH)
+static PAGE_CURSORS: LazyLock<PageCursors> = LazyLock::new(PageCursors::from_env);
+
+// Room ids no new room may take (RESERVED_ROOM_IDS)
+static RESERVED_ROOM_IDS: LazyLock<ReservedRoomIds> = LazyLock::new(ReservedRoomIds::from_env);
+
+// How long messages stay editable (EDIT_WINDOW_SECS)
+static EDIT_WINDOW: LazyLock<chrono::Duration> = LazyLock::new(|| {
+    chrono::Duration::seconds(
+        env::var("EDIT_WINDOW_SECS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .filter(|secs| *secs >= 0)
+            .unwrap_or(DEFAULT_EDIT_WINDOW_SECS),
+    )
+});
+
+// Who may change a room's topic (ROOM_TOPIC_EDITORS)
+static TOPIC_EDITORS: LazyLock<TopicEditors> = LazyLock::new(TopicEditors::from_env);
+
+// Flags from different clients that hide a message when AUTO_HIDE_FLAGS is unset
+const DEFAULT_AUTO_HIDE_FLAGS: u32 = 3;
+
+// How many clients flagging a message hides it until a moderator looks (AUTO_HIDE_FLAGS).
+// Clients are told apart by their origin hash, as the body's user_id is whatever the caller
+// says; without origins (CAPTURE_ORIGIN off or no ORIGIN_HASH_SALT) nothing is auto-hidden.
+static AUTO_HIDE_FLAGS: LazyLock<u32> = LazyLock::new(|| {
+    env::var("AUTO_HIDE_FLAGS")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .filter(|flags| *flags > 0)
+        .unwrap_or(DEFAULT_AUTO_HIDE_FLAGS)
+});
+
+/// `error` of the 403 answered to a post to an archived room
+pub const ROOM_ARCHIVED: &str = "room_archived";
+
+/// `error` of the 403 answered to an edit made after `EDIT_WINDOW_SECS` has passed
+pub const EDIT_WINDOW_EXPIRED: &str = "edit_window_expired";
+
+// Why a handler failed: bad client input (400), a request refused as a whole (400, with a code
+// for `error`), no identity (401), an identity not allowed to do this (403), a write that clashed
+// with existing or concurrent state (409), a store out of capacity (429) or anything else (500)
+#[derive(Debug)]
+pub enum HandlerError {
+    Validation(Vec<ValidationError>),
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Throttled(String),
+    Internal(String),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Validation(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "Invalid request: {}", messages.join("; "))
+            }
+            HandlerError::BadRequest(message)
+            | HandlerError::Unauthorized(message)
+            | HandlerError::Forbidden(message)
+            | HandlerError::Conflict(message)
+            | HandlerError::Throttled(message)
+            | HandlerError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<String> for HandlerError {
+    fn from(message: String) -> Self {
+        HandlerError::Internal(message)
+    }
+}
+
+impl From<ValidationError> for HandlerError {
+    fn from(error: ValidationError) -> Self {
+        HandlerError::Validation(vec![error])
+    }
+}
+
+impl From<PutMessageError> for HandlerError {
+    fn from(error: PutMessageError) -> Self {
+        match error {
+            PutMessageError::Other(message) => HandlerError::Internal(message),
+            // Still contended after the store's retries: the room is too busy right now, and
+            // nothing about this message clashes with what's stored
+            PutMessageError::Throttled | PutMessageError::Contended => {
+                HandlerError::Throttled(error.to_string())
+            }
+            PutMessageError::RoomArchived(_) => HandlerError::Forbidden(ROOM_ARCHIVED.to_string()),
+            conflict => HandlerError::Conflict(conflict.to_string()),
+        }
+    }
+}
+
+impl From<StoreError> for HandlerError {
+    fn from(error: StoreError) -> Self {
+        match error {
+            StoreError::Other(message) => HandlerError::Internal(message),
+            StoreError::Throttled => HandlerError::Throttled(error.to_string()),
+            StoreError::Conflict => HandlerError::Conflict(error.to_string()),
+        }
+    }
+}
+
 // Table names structure
 #[derive(Clone)]
 pub struct Tables {
@@ -14,36 +241,415 @@ pub struct Tables {
 
 impl Tables {
     pub fn from_env() -> Self {
-        Self {
-            rooms: env::var("CHAT_ROOMS_TABLE").expect("CHAT_ROOMS_TABLE must be set"),
-            messages: env::var("CHAT_MESSAGES_TABLE").expect("CHAT_MESSAGES_TABLE must be set"),
+        Self::try_from_env().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_from_env() -> Result<Self, String> {
+        Ok(Self {
+            rooms: env::var("CHAT_ROOMS_TABLE").map_err(|_| "CHAT_ROOMS_TABLE must be set")?,
+            messages: env::var("CHAT_MESSAGES_TABLE")
+                .map_err(|_| "CHAT_MESSAGES_TABLE must be set")?,
+        })
+    }
+
+    /// Confirm both tables exist with the key schema the handlers rely on. Meant to be called
+    /// once at startup so misconfiguration fails fast instead of deep inside a request.
+    pub async fn verify(&self, ddb: &DynamoDbClient) -> Result<(), String> {
+        // Probe both tables concurrently; this sits on the cold-start path
+        let (rooms, messages) = tokio::try_join!(
+            describe_table(ddb, &self.rooms),
+            describe_table(ddb, &self.messages)
+        )?;
+        check_key_schema(&self.rooms, rooms.key_schema(), "id", None)?;
+        check_key_schema(&self.messages, messages.key_schema(), "room_id", Some("sk"))?;
+
+        Ok(())
+    }
+}
+
+/// Confirm the WebSocket connections table exists and carries the `room-index` GSI used for
+/// fan-out
+pub async fn verify_connections_table(ddb: &DynamoDbClient, table: &str) -> Result<(), String> {
+    let description = describe_table(ddb, table).await?;
+    check_key_schema(table, description.key_schema(), "connection_id", None)?;
+
+    let has_room_index = description
+        .global_secondary_indexes()
+        .iter()
+        .any(|index| index.index_name() == Some("room-index"));
+    if !has_room_index {
+        return Err(format!(
+            "Table '{}' is missing the 'room-index' global secondary index",
+            table
+        ));
+    }
+    Ok(())
+}
+
+/// A table's description, or why it couldn't be read (missing, or no access)
+pub async fn describe_table(ddb: &DynamoDbClient, table: &str) -> Result<TableDescription, String> {
+    ddb.describe_table()
+        .table_name(table)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to describe table '{}': {:?}", table, e))?
+        .table
+        .ok_or_else(|| format!("Table '{}' returned no description", table))
+}
+
+fn check_key_schema(
+    table: &str,
+    key_schema: &[KeySchemaElement],
+    hash_key: &str,
+    range_key: Option<&str>,
+) -> Result<(), String> {
+    let key_of = |key_type: KeyType| {
+        key_schema
+            .iter()
+            .find(|element| *element.key_type() == key_type)
+            .map(|element| element.attribute_name())
+    };
+
+    let actual = (key_of(KeyType::Hash), key_of(KeyType::Range));
+    if actual == (Some(hash_key), range_key) {
+        return Ok(());
+    }
+
+    let describe = |(hash, range): (Option<&str>, Option<&str>)| match range {
+        Some(range) => format!("{} HASH, {} RANGE", hash.unwrap_or("<none>"), range),
+        None => format!("{} HASH", hash.unwrap_or("<none>")),
+    };
+    Err(format!(
+        "Table '{}' has key schema [{}] but expected [{}]",
+        table,
+        describe(actual),
+        describe((Some(hash_key), range_key))
+    ))
+}
+
+/// Keys of a serialized `ChatMessage` that a `fields` projection may ask for
+pub const MESSAGE_FIELDS: [&str; 20] = [
+    "id",
+    "room_id",
+    "user_id",
+    "username",
+    "message_text",
+    "created_at",
+    "client_message_id",
+    "ephemeral",
+    "expires_at",
+    "status",
+    "format",
+    "client_created_at",
+    "clock_skew_ms",
+    "delivered_count",
+    "client_seq",
+    "visibility",
+    "to_user_id",
+    "link_preview",
+    "avatar_seed",
+    "edited_at",
+];
+
+// Optional filters for message retrieval. `created_*` bounds are inclusive epoch millis;
+// `after` is an exclusive cursor: a previous page's `next_cursor` as sent, the bare sort key it
+// carries once get_messages_handler has opened it, continuing past it in `order`; `before` is an
+// exclusive epoch millis cursor, read back from: the newest page older than it; `limit` is the
+// page size, at most MAX_MESSAGE_PAGE_SIZE; `fields` is a
+// comma-separated subset of MESSAGE_FIELDS to return instead of whole messages; `user_id` is
+// the reader, the only one besides the sender direct messages to them are returned to; `order`
+// is which end of the range the page comes from, and the order it's returned in.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MessageQuery {
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    #[serde(default)]
+    pub after: Option<String>,
+    #[serde(default)]
+    pub before: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub fields: Option<String>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    // Readers asking over HTTP get the newest first unless they say otherwise; queries built
+    // in code from `MessageQuery::default()` read oldest first
+    #[serde(default = "SortOrder::requested_default")]
+    pub order: SortOrder,
+}
+
+/// Which way a page of messages runs: `asc` oldest first, `desc` newest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    /// The order a reader gets without asking for one
+    pub fn requested_default() -> Self {
+        SortOrder::Desc
+    }
+
+    /// `asc` or `desc`, as given in a query string
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!("order must be asc or desc, got '{}'", value)),
+        }
+    }
+}
+
+impl MessageQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(after), Some(before)) = (self.created_after, self.created_before) {
+            if after > before {
+                return Err("created_after must not be later than created_before".to_string());
+            }
+        }
+        // The cursor stands in for the bound at the end it continues from
+        match self.order {
+            SortOrder::Asc if self.after.is_some() && self.created_after.is_some() => {
+                return Err("after and created_after cannot be combined".to_string());
+            }
+            SortOrder::Desc if self.after.is_some() && self.created_before.is_some() => {
+                return Err(
+                    "after and created_before cannot be combined with order=desc".to_string()
+                );
+            }
+            _ => {}
+        }
+        if self.before.is_some() && (self.after.is_some() || self.created_before.is_some()) {
+            return Err("before cannot be combined with after or created_before".to_string());
+        }
+        if let (Some(after), Some(before)) = (self.created_after, self.before) {
+            if after >= before {
+                return Err("created_after must be earlier than before".to_string());
+            }
+        }
+        if self.limit.is_some_and(|limit| !(1..=store::MAX_MESSAGE_PAGE_SIZE).contains(&limit)) {
+            return Err(format!("limit must be between 1 and {}", store::MAX_MESSAGE_PAGE_SIZE));
+        }
+        self.fields()?;
+        Ok(())
+    }
+
+    /// The requested projection, deduplicated in MESSAGE_FIELDS order; None returns whole
+    /// messages
+    pub fn fields(&self) -> Result<Option<Vec<&'static str>>, String> {
+        let Some(fields) = &self.fields else {
+            return Ok(None);
+        };
+        let mut requested = HashSet::new();
+        for field in fields.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+            match MESSAGE_FIELDS.iter().find(|known| **known == field) {
+                Some(known) => requested.insert(*known),
+                None => {
+                    return Err(format!(
+                        "Unknown field '{}' in fields; expected any of {}",
+                        field,
+                        MESSAGE_FIELDS.join(",")
+                    ))
+                }
+            };
+        }
+        if requested.is_empty() {
+            return Err("fields must name at least one field".to_string());
+        }
+        Ok(Some(MESSAGE_FIELDS.into_iter().filter(|field| requested.contains(field)).collect()))
+    }
+
+    /// True when no range filter is set, i.e. the request is for the room's default page
+    pub fn is_unbounded(&self) -> bool {
+        self.created_after.is_none()
+            && self.created_before.is_none()
+            && self.after.is_none()
+            && self.before.is_none()
+            && self.limit.is_none()
+            && self.order == SortOrder::requested_default()
+    }
+
+    /// Messages per page: `limit`, or MESSAGE_PAGE_SIZE
+    pub fn page_size(&self) -> usize {
+        self.limit.unwrap_or(store::MESSAGE_PAGE_SIZE)
+    }
+
+    /// True when the range is read newest first: for `order=desc`, and when paging back from
+    /// `before`. Stores return the page oldest first either way.
+    pub fn newest_first(&self) -> bool {
+        self.order == SortOrder::Desc || self.before.is_some()
+    }
+
+    /// Whether a message with this sort key (see `store::sort_key`) falls inside the range
+    pub fn contains(&self, sort_key: &str) -> bool {
+        let (lo, hi) = self.sort_key_bounds();
+        lo.is_none_or(|lo| sort_key >= lo.as_str())
+            && hi.is_none_or(|hi| sort_key <= hi.as_str())
+            // Read newest first, the cursor is the range's inclusive top, but was already read
+            && !(self.order == SortOrder::Desc && self.after.as_deref() == Some(sort_key))
+    }
+
+    // Inclusive sort-key bounds. A key starts with its zero-padded ts and a `#`, so a bare ts
+    // sorts before every key in that millisecond and ts + `$` after them; a NUL appended to
+    // the cursor sorts directly after it.
+    fn sort_key_bounds(&self) -> (Option<String>, Option<String>) {
+        let (after_lo, after_hi) = match self.order {
+            SortOrder::Asc => (self.after.as_ref().map(|after| format!("{}\0", after)), None),
+            SortOrder::Desc => (None, self.after.clone()),
+        };
+        let lo = after_lo.or(self.created_after.map(store::sort_key_prefix));
+        // Nothing from `before`'s own millisecond
+        let hi = after_hi.or(self
+            .created_before
+            .or(self.before.map(|before| before.saturating_sub(1)))
+            .map(|before| format!("{}$", store::sort_key_prefix(before))));
+        (lo, hi)
+    }
+
+    // Key condition for the room partition plus the `sk` sort-key range, if any
+    pub(crate) fn key_condition(&self) -> (&'static str, Vec<(&'static str, String)>) {
+        match self.sort_key_bounds() {
+            (Some(lo), Some(hi)) => {
+                ("room_id = :room_id AND sk BETWEEN :lo AND :hi", vec![(":lo", lo), (":hi", hi)])
+            }
+            (Some(lo), None) => ("room_id = :room_id AND sk >= :lo", vec![(":lo", lo)]),
+            (None, Some(hi)) => ("room_id = :room_id AND sk <= :hi", vec![(":hi", hi)]),
+            (None, None) => ("room_id = :room_id", Vec::new()),
         }
     }
 }
 
-// Shared validation functions
-pub fn validate_username(username: &str) -> Result<String, String> {
+pub fn validate_username(username: &str) -> Result<String, ValidationError> {
     let trimmed = username.trim();
     if trimmed.is_empty() {
-        return Err("Username cannot be empty".to_string());
-    }
-    if trimmed.len() > 50 {
-        return Err("Username cannot be longer than 50 characters".to_string());
+        return Err(ValidationError::new("username", "Username cannot be empty"));
     }
+    Limits::check_length("username", "Username", trimmed, LIMITS.username)?;
     Ok(trimmed.to_string())
 }
 
-pub fn validate_message_text(message_text: &str) -> Result<String, String> {
+/// A client-supplied user id, checked against the configured format before it's stored or
+/// used as a key
+pub fn validate_user_id(user_id: &str) -> Result<String, ValidationError> {
+    USER_ID_FORMAT.check(user_id)
+}
+
+/// The recipient of a direct message, held to the same format as the sender's id
+pub fn validate_to_user_id(to_user_id: Option<&str>) -> Result<Option<String>, ValidationError> {
+    to_user_id
+        .map(|to_user_id| {
+            validate_user_id(to_user_id)
+                .map_err(|error| ValidationError { field: "to_user_id", ..error })
+        })
+        .transpose()
+}
+
+/// Whether `viewer` may see the message. Room messages are visible to anyone; direct messages
+/// only to their sender and recipient, so an unidentified viewer sees none of them.
+pub fn visible_to(message: &ChatMessage, viewer: Option<&str>) -> bool {
+    match message.visibility {
+        MessageVisibility::Room => true,
+        MessageVisibility::Direct => viewer.is_some_and(|viewer| {
+            viewer == message.core.user_id || Some(viewer) == message.to_user_id.as_deref()
+        }),
+    }
+}
+
+/// Blank out a hidden message's text and link preview, leaving the rest for the reader to show
+/// it was hidden. Every read path does this; moderators read the text from the admin view.
+pub fn redact_hidden(message: &mut ChatMessage) {
+    if message.status == MessageStatus::Hidden {
+        message.core.message_text.clear();
+        message.link_preview = None;
+    }
+}
+
+pub fn validate_message_text(message_text: &str) -> Result<String, ValidationError> {
     let trimmed = message_text.trim();
     if trimmed.is_empty() {
-        return Err("Message text cannot be empty".to_string());
+        return Err(ValidationError::new("message_text", "Message text cannot be empty"));
     }
-    if trimmed.len() > 500 {
-        return Err("Message text cannot be longer than 500 characters".to_string());
+    Limits::check_length("message_text", "Message text", trimmed, LIMITS.message_text)?;
+    if let Some(term) = BLOCKLIST.find(trimmed) {
+        return Err(ValidationError {
+            blocked: true,
+            ..ValidationError::new(
+                "message_text",
+                format!("Message text contains a blocked term: \"{}\"", term),
+            )
+        });
     }
     Ok(trimmed.to_string())
 }
 
+// Message text as it's stored, posted or edited: validated, then through `pipeline`. Code is
+// kept exactly as sent, so its indentation and spacing survive: no trim, no transforms.
+fn message_text(
+    text: &str,
+    format: &MessageFormat,
+    pipeline: &TextPipeline,
+) -> Result<String, ValidationError> {
+    let validated = validate_message_text(text)?;
+    match format {
+        MessageFormat::Code { .. } => {
+            Limits::check_length("message_text", "Message text", text, LIMITS.message_text)?;
+            Ok(text.to_string())
+        }
+        _ => Ok(pipeline.apply(validated)),
+    }
+}
+
+// Count a post or edit refused by the blocklist against its room
+async fn count_blocked(room_id: &str, errors: &[ValidationError]) {
+    if errors.iter().any(|error| error.blocked) {
+        if let Ok(room_id) = validate_room_id(room_id) {
+            MetricsHelper::new().await.emit_message_rejected_moderation(&room_id).await;
+        }
+    }
+}
+
+/// The trimmed topic, or None when it's absent or blank (which clears it)
+pub fn validate_topic(topic: Option<&str>) -> Result<Option<String>, ValidationError> {
+    let Some(trimmed) = topic.map(str::trim).filter(|topic| !topic.is_empty()) else {
+        return Ok(None);
+    };
+    Limits::check_length("topic", "Topic", trimmed, LIMITS.topic)?;
+    Ok(Some(trimmed.to_string()))
+}
+
+// Longest reason a flag may give
+const MAX_FLAG_REASON_LENGTH: usize = 500;
+
+pub fn validate_flag_reason(reason: &str) -> Result<String, ValidationError> {
+    let trimmed = reason.trim();
+    if trimmed.is_empty() {
+        return Err(ValidationError::new("reason", "A reason is required"));
+    }
+    Limits::check_length("reason", "Reason", trimmed, MAX_FLAG_REASON_LENGTH)?;
+    Ok(trimmed.to_string())
+}
+
+// Longest avatar seed a profile may carry
+const MAX_AVATAR_SEED_LENGTH: usize = 64;
+
+/// The trimmed seed, or None when it's absent or blank (which goes back to the user id)
+pub fn validate_avatar_seed(avatar_seed: Option<&str>) -> Result<Option<String>, ValidationError> {
+    let Some(trimmed) = avatar_seed.map(str::trim).filter(|seed| !seed.is_empty()) else {
+        return Ok(None);
+    };
+    Limits::check_length("avatar_seed", "Avatar seed", trimmed, MAX_AVATAR_SEED_LENGTH)?;
+    Ok(Some(trimmed.to_string()))
+}
+
+/// The seed a user's avatar and color come from: the one they chose, or else their user id
+pub fn avatar_seed(user_id: &str, chosen: Option<String>) -> String {
+    chosen.unwrap_or_else(|| user_id.to_string())
+}
+
 pub fn validate_room_id(room_id: &str) -> Result<String, String> {
     let trimmed = room_id.trim();
     if trimmed.is_empty() {
@@ -52,170 +658,3374 @@ pub fn validate_room_id(room_id: &str) -> Result<String, String> {
     Ok(trimmed.to_lowercase())
 }
 
-// Shared business logic functions
-pub async fn health_handler() -> Result<HealthCheck, String> {
-    let health_check = HealthCheck {
-        status: HealthStatus::Healthy,
-        version: env!("CARGO_PKG_VERSION").to_string(),
-        timestamp: Utc::now(),
+/// `error` of the 400 answered to a post that would create a room with a reserved id
+pub const RESERVED_ROOM_ID: &str = "reserved_room_id";
+
+/// Room ids held back by default, as they'd read like the service's own route prefixes
+pub const DEFAULT_RESERVED_ROOM_IDS: [&str; 6] = ["admin", "api", "dev", "health", "metrics", "ws"];
+
+/// Room ids posts may not auto-create rooms under: `RESERVED_ROOM_IDS` (comma-separated) or
+/// `DEFAULT_RESERVED_ROOM_IDS`. Only creation is refused; rooms that already have such an id
+/// stay readable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedRoomIds(Vec<String>);
+
+impl Default for ReservedRoomIds {
+    fn default() -> Self {
+        Self(DEFAULT_RESERVED_ROOM_IDS.iter().map(|id| id.to_string()).collect())
+    }
+}
+
+impl ReservedRoomIds {
+    pub fn new(ids: &[&str]) -> Self {
+        Self(ids.iter().filter_map(|id| validate_room_id(id).ok()).collect())
+    }
+
+    pub fn from_env() -> Self {
+        match env::var("RESERVED_ROOM_IDS") {
+            Ok(value) => Self::new(&value.split(',').collect::<Vec<_>>()),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Whether `room_id`, as normalized by `validate_room_id`, is reserved
+    pub fn contains(&self, room_id: &str) -> bool {
+        self.0.iter().any(|reserved| reserved == room_id)
+    }
+}
+
+/// Who may change a room's topic: anyone posting in it, or only callers with the admin token.
+/// `ROOM_TOPIC_EDITORS` is `members` (the default) or `admins`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TopicEditors {
+    #[default]
+    Members,
+    Admins,
+}
+
+impl TopicEditors {
+    pub fn from_env() -> Self {
+        match env::var("ROOM_TOPIC_EDITORS").as_deref() {
+            Ok("admins") => Self::Admins,
+            _ => Self::Members,
+        }
+    }
+}
+
+// Longest self-destruct timer a client may request (7 days)
+const MAX_EXPIRES_IN_SECS: u64 = 7 * 24 * 60 * 60;
+
+pub fn validate_expires_in(expires_in_secs: Option<u64>) -> Result<Option<u64>, String> {
+    match expires_in_secs {
+        Some(0) => Err("expires_in_secs must be greater than zero".to_string()),
+        Some(secs) if secs > MAX_EXPIRES_IN_SECS => {
+            Err(format!("expires_in_secs cannot be longer than {} seconds", MAX_EXPIRES_IN_SECS))
+        }
+        other => Ok(other),
+    }
+}
+
+// Largest client counter, `Number.MAX_SAFE_INTEGER`, since the clients that send it are JavaScript
+const MAX_CLIENT_SEQ: u64 = (1 << 53) - 1;
+
+pub fn validate_client_seq(client_seq: Option<u64>) -> Result<Option<u64>, String> {
+    match client_seq {
+        Some(seq) if seq > MAX_CLIENT_SEQ => {
+            Err(format!("client_seq cannot be larger than {}", MAX_CLIENT_SEQ))
+        }
+        other => Ok(other),
+    }
+}
+
+/// Languages a code message may be highlighted as
+pub const CODE_LANGUAGES: &[&str] = &[
+    "bash",
+    "c",
+    "cpp",
+    "css",
+    "go",
+    "html",
+    "java",
+    "javascript",
+    "json",
+    "python",
+    "rust",
+    "sql",
+    "typescript",
+    "yaml",
+];
+
+/// Check a code message's language against `CODE_LANGUAGES`, normalizing it to lowercase.
+/// A blank language is the same as none.
+pub fn validate_format(format: &MessageFormat) -> Result<MessageFormat, String> {
+    let MessageFormat::Code { lang: Some(lang) } = format else {
+        return Ok(format.clone());
     };
-    Ok(health_check)
+    let lang = lang.trim().to_lowercase();
+    if lang.is_empty() {
+        return Ok(MessageFormat::Code { lang: None });
+    }
+    if !CODE_LANGUAGES.contains(&lang.as_str()) {
+        return Err(format!(
+            "Unsupported code language '{}'; expected one of {}",
+            lang,
+            CODE_LANGUAGES.join(", ")
+        ));
+    }
+    Ok(MessageFormat::Code { lang: Some(lang) })
 }
 
-pub async fn ensure_room_exists(
-    ddb: &DynamoDbClient,
-    tables: &Tables,
-    room_id: &str,
-) -> Result<(), String> {
-    let get_item_result = ddb
-        .get_item()
-        .table_name(&tables.rooms)
-        .key("id", AttributeValue::S(room_id.to_string()))
-        .send()
-        .await;
+/// When a self-destructing message should disappear, if the sender asked for it
+pub fn message_expiry(now: DateTime<Utc>, expires_in_secs: Option<u64>) -> Option<DateTime<Utc>> {
+    expires_in_secs.map(|secs| now + chrono::Duration::seconds(secs as i64))
+}
 
-    match get_item_result {
-        Ok(output) => {
-            if output.item.is_none() {
-                // Room doesn't exist, create it
-                let now = Utc::now();
-                let room_name =
-                    if room_id == "general" { "General".to_string() } else { room_id.to_string() };
-
-                let mut item = HashMap::new();
-                item.insert("id".to_string(), AttributeValue::S(room_id.to_string()));
-                item.insert("name".to_string(), AttributeValue::S(room_name));
-                item.insert("created_at_iso".to_string(), AttributeValue::S(now.to_rfc3339()));
-                item.insert(
-                    "created_at_epoch".to_string(),
-                    AttributeValue::N(now.timestamp().to_string()),
-                );
+/// How far the client's timestamp is from the server's (negative when it's earlier), if that's
+/// more than `threshold_ms`
+pub fn clock_skew(
+    created_at: DateTime<Utc>,
+    client_created_at: Option<DateTime<Utc>>,
+    threshold_ms: i64,
+) -> Option<i64> {
+    let skew_ms = (client_created_at? - created_at).num_milliseconds();
+    (skew_ms.abs() > threshold_ms).then_some(skew_ms)
+}
 
-                ddb.put_item()
-                    .table_name(&tables.rooms)
-                    .set_item(Some(item))
-                    .condition_expression("attribute_not_exists(id)")
-                    .send()
-                    .await
-                    .map_err(|e| format!("Failed to create room: {:?}", e))?;
+// Shared business logic functions
 
-                info!("Created new room: {}", room_id);
-            }
-            Ok(())
+/// Health of the service and of the messages table behind it. The table is Degraded when its
+/// probe fails or takes longer than HEALTH_PROBE_TIMEOUT, and Unhealthy when it doesn't exist.
+/// Never fails itself, so a sick dependency still answers 200 for monitors to read.
+pub async fn health_handler(store: &dyn MessageStore, context: &RequestContext) -> HealthCheck {
+    let messages_table = match tokio::time::timeout(HEALTH_PROBE_TIMEOUT, store.probe()).await {
+        Ok(Ok(())) => HealthStatus::Healthy,
+        Ok(Err(err @ ProbeError::Missing(_))) => {
+            error!("Health probe failed: {}", err);
+            HealthStatus::Unhealthy
+        }
+        Ok(Err(err @ ProbeError::Unreachable(_))) => {
+            warn!("Health probe failed: {}", err);
+            HealthStatus::Degraded
         }
-        Err(e) => Err(format!("DynamoDB error: {:?}", e)),
+        Err(_) => {
+            warn!("Health probe timed out after {:?}", HEALTH_PROBE_TIMEOUT);
+            HealthStatus::Degraded
+        }
+    };
+    let checks = HashMap::from([("messages_table".to_string(), messages_table)]);
+    HealthCheck {
+        status: checks.values().max().cloned().unwrap_or(HealthStatus::Healthy),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp: context.clock.now(),
+        checks,
+    }
+}
+
+/// Id for a new message. In deterministic mode a retry carrying the same `client_message_id`
+/// gets the same id; without a `client_message_id` there is nothing to derive from, so the id
+/// comes from `ids` either way.
+pub fn message_id(
+    room_id: &RoomId,
+    user_id: &UserId,
+    client_message_id: Option<&str>,
+    deterministic: bool,
+    ids: &dyn IdGenerator,
+) -> MessageId {
+    match client_message_id {
+        Some(client_message_id) if deterministic => {
+            // NUL separators keep ("ab", "c") and ("a", "bc") apart
+            let name = format!("{}\0{}\0{}", room_id, user_id, client_message_id);
+            MessageId::new(Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string())
+        }
+        _ => MessageId::new(ids.next_id()),
     }
 }
 
+/// What a post came to: the message it stored, or the one an earlier attempt already had
+#[derive(Debug, Clone)]
+pub struct Posted {
+    pub message: ChatMessage,
+    // Nothing new was stored; `message` is the earlier one
+    pub duplicate: bool,
+}
+
+/// Store a new message. `client_ip` is only used to derive the moderator-visible origin and is
+/// never stored or returned. A retry carrying a `client_message_id` the sender already used in
+/// the room gets the stored message back instead of a second copy.
 pub async fn post_message_handler(
-    ddb: &DynamoDbClient,
-    tables: &Tables,
+    store: &dyn MessageStore,
+    context: &RequestContext,
     request: SendMessageRequest,
-) -> Result<ChatMessage, String> {
-    // Validate input
-    let room_id = validate_room_id(&request.room_id)?;
-    let user_id = request.user_id.clone();
+    client_ip: Option<IpAddr>,
+) -> Result<Posted, HandlerError> {
+    let origin = ORIGIN_CAPTURE.capture(client_ip);
+    store_message(store, context, request, *POST_OPTIONS, origin.as_ref()).await
+}
+
+/// Where a valid post is going and who it's from, with anonymous posters given their generated
+/// id. Per-poster limits and metrics key on this rather than on what the client sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Poster {
+    pub room_id: RoomId,
+    pub user_id: UserId,
+}
+
+/// Validate a post far enough to know its `Poster`, refusing it as `post_message_handler` would
+pub async fn resolve_poster(request: &SendMessageRequest) -> Result<Poster, HandlerError> {
+    let (poster, _) = validate_poster(request, *POST_OPTIONS).await?;
+    Ok(poster)
+}
+
+// The poster and the display name the client sent, once the request passes validation
+async fn validate_poster(
+    request: &SendMessageRequest,
+    options: PostOptions,
+) -> Result<(Poster, String), HandlerError> {
+    // Validate input, reporting every bad field at once
+    if let Err(errors) = request.validate() {
+        count_blocked(&request.room_id, &errors).await;
+        return Err(HandlerError::Validation(errors));
+    }
+    let room_id = RoomId::from(validate_room_id(&request.room_id)?);
+    if RESERVED_ROOM_IDS.contains(&room_id) {
+        return Err(HandlerError::BadRequest(RESERVED_ROOM_ID.to_string()));
+    }
     let username = validate_username(&request.username)?;
-    let message_text = validate_message_text(&request.message_text)?;
+    // Anonymous posters are told apart by display name, the only stable thing they send
+    let user_id = UserId::from(
+        options
+            .anonymous
+            .resolve(Some(&request.user_id), Some(&username), &username)
+            .map_err(HandlerError::Unauthorized)?
+            .user_id,
+    );
+    Ok((Poster { room_id, user_id }, username))
+}
 
-    // Ensure room exists
-    ensure_room_exists(ddb, tables, &room_id).await?;
+// Its id and creation time come from `context`
+async fn store_message(
+    store: &dyn MessageStore,
+    context: &RequestContext,
+    request: SendMessageRequest,
+    options: PostOptions,
+    origin: Option<&MessageOrigin>,
+) -> Result<Posted, HandlerError> {
+    let (Poster { room_id, user_id }, username) = validate_poster(&request, options).await?;
+    // A name set through the user's profile wins over whatever the client sent
+    let profile = store.profile(&user_id).await?;
+    let username =
+        profile.as_ref().and_then(|profile| profile.username.clone()).unwrap_or(username);
+    let avatar_seed = avatar_seed(&user_id, profile.and_then(|profile| profile.avatar_seed));
+    let format = validate_format(&request.format)?;
+    let message_text = message_text(&request.message_text, &format, &TEXT_PIPELINE)?;
+    let expires_in_secs = validate_expires_in(request.expires_in_secs)?;
+    let to_user_id = validate_to_user_id(request.to_user_id.as_deref())?;
 
-    // Create message
-    let now = Utc::now();
-    let message_id = Uuid::new_v4().to_string();
-    let timestamp_millis = now.timestamp_millis();
-
-    let mut item = HashMap::new();
-    item.insert("id".to_string(), AttributeValue::S(message_id.clone()));
-    item.insert("room_id".to_string(), AttributeValue::S(room_id.clone()));
-    item.insert("user_id".to_string(), AttributeValue::S(user_id.clone()));
-    item.insert("username".to_string(), AttributeValue::S(username.clone()));
-    item.insert("message_text".to_string(), AttributeValue::S(message_text.clone()));
-    item.insert("ts".to_string(), AttributeValue::N(timestamp_millis.to_string()));
-    item.insert("created_at_iso".to_string(), AttributeValue::S(now.to_rfc3339()));
-
-    // Store client_message_id if provided
-    if let Some(client_message_id) = &request.client_message_id {
-        item.insert("client_message_id".to_string(), AttributeValue::S(client_message_id.clone()));
-    }
-
-    // Store message in DynamoDB
-    ddb.put_item()
-        .table_name(&tables.messages)
-        .set_item(Some(item))
-        .send()
-        .await
-        .map_err(|e| format!("DynamoDB error: {:?}", e))?;
+    // Ensure room exists, unless this process confirmed it recently
+    let known_rooms = store.known_rooms();
+    if !known_rooms.is_some_and(|known_rooms| known_rooms.contains(&room_id)) {
+        store.ensure_room(&room_id).await?;
+        if let Some(known_rooms) = known_rooms {
+            known_rooms.insert(&room_id);
+        }
+    }
+
+    let message_id = message_id(
+        &room_id,
+        &user_id,
+        request.client_message_id.as_deref(),
+        options.deterministic_ids,
+        context.ids.as_ref(),
+    );
+
+    // The table is keyed by (room_id, sk), so a retry lands on a new key and the put condition
+    // alone can't catch it; look the client's id up first and hand back the original message
+    if let Some(client_message_id) = request.client_message_id.as_deref() {
+        if let Some(existing) =
+            store.find_client_message(&room_id, &user_id, client_message_id).await?
+        {
+            info!(
+                "Message {} already stored in room {}, skipping retry",
+                existing.core.id, room_id
+            );
+            MetricsHelper::new().await.emit_duplicate_suppressed(&room_id).await;
+            return Ok(Posted { message: existing, duplicate: true });
+        }
+    }
 
-    info!("Stored message {} in room {}", message_id, room_id);
+    // A double-click sends the same text twice under different client ids. Best effort: two
+    // posts racing each other both miss, as neither is stored yet.
+    let now = context.clock.now();
+    if let Some(window) = options.content_dedup {
+        let hash = content_hash(&room_id, &user_id, to_user_id.as_deref(), &message_text);
+        if let Some(existing) = recent_duplicate(store, &room_id, &hash, now - window).await? {
+            info!("Message {} repeated within the dedup window, skipping", existing.core.id);
+            return Ok(Posted { message: existing, duplicate: true });
+        }
+    }
 
-    // Create response message
+    // Create message
+    let expires_at = message_expiry(now, expires_in_secs);
     let message = ChatMessage {
-        id: message_id.clone(),
-        room_id: room_id.clone(),
-        user_id: user_id.clone(),
-        username: username.clone(),
-        message_text: message_text.clone(),
-        created_at: now,
-        client_message_id: request.client_message_id.clone(),
+        core: MessageCore {
+            id: message_id.into(),
+            room_id: room_id.into(),
+            user_id: user_id.into(),
+            username,
+            message_text,
+            created_at: now,
+        },
+        client_message_id: request.client_message_id,
+        ephemeral: expires_at.is_some(),
+        expires_at,
+        status: MessageStatus::Stored,
+        format,
+        client_created_at: request.client_created_at,
+        clock_skew_ms: clock_skew(now, request.client_created_at, options.clock_skew_threshold_ms),
+        delivered_count: None,
+        client_seq: request.client_seq,
+        visibility: if to_user_id.is_some() {
+            MessageVisibility::Direct
+        } else {
+            MessageVisibility::Room
+        },
+        to_user_id,
+        link_preview: None,
+        avatar_seed: Some(avatar_seed),
+        edited_at: None,
     };
 
-    Ok(message)
-}
+    match store.put_message(&message, origin).await {
+        Ok(()) => {}
+        // A concurrent attempt of the same post got there first; answer with what it stored
+        Err(PutMessageError::ClientMessageTaken(winner)) => {
+            let Some(existing) = store.get_message(&message.core.room_id, &winner).await? else {
+                return Err(HandlerError::Conflict(format!(
+                    "Message {} was already sent with this client_message_id",
+                    winner
+                )));
+            };
+            info!("Message {} won a race with its retry in room {}", winner, existing.core.room_id);
+            MetricsHelper::new().await.emit_duplicate_suppressed(&existing.core.room_id).await;
+            return Ok(Posted { message: existing, duplicate: true });
+        }
+        Err(err) => return Err(err.into()),
+    }
 
-pub async fn get_messages_handler(
-    ddb: &DynamoDbClient,
-    tables: &Tables,
-    room_id: String,
-) -> Result<GetMessagesResponse, String> {
-    let room_id = validate_room_id(&room_id)?;
+    info!("Stored message {} in room {}", message.core.id, message.core.room_id);
 
-    // Query messages from DynamoDB
-    let result = ddb
-        .query()
-        .table_name(&tables.messages)
-        .key_condition_expression("room_id = :room_id")
-        .expression_attribute_values(":room_id", AttributeValue::S(room_id.clone()))
-        .scan_index_forward(true) // Oldest first
-        .limit(25)
-        .send()
-        .await
-        .map_err(|e| format!("DynamoDB error: {:?}", e))?;
-
-    let messages: Vec<ChatMessage> = result
-        .items
-        .unwrap_or_default()
-        .into_iter()
-        .filter_map(|item| {
-            // Convert DynamoDB item to ChatMessage struct
-            let id = item.get("id")?.as_s().ok()?.clone();
-            let user_id = item
-                .get("user_id")
-                .and_then(|v| v.as_s().ok())
-                .map(|s| s.to_string())
-                .unwrap_or_else(|| "unknown".to_string());
-            let username = item.get("username")?.as_s().ok()?.clone();
-            let message_text = item.get("message_text")?.as_s().ok()?.clone();
-            let ts = item.get("ts")?.as_n().ok()?.parse::<i64>().ok()?;
-            let created_at = chrono::DateTime::from_timestamp_millis(ts)?;
-            let client_message_id =
-                item.get("client_message_id").and_then(|v| v.as_s().ok()).cloned();
-
-            Some(ChatMessage {
-                id,
-                room_id: room_id.clone(),
-                user_id,
-                username,
-                message_text,
-                created_at: created_at.with_timezone(&Utc),
-                client_message_id,
-            })
-        })
-        .collect();
+    // Ephemeral messages leave through TTL on their own, so they stay outside the room budget
+    if let (Some(cap), None) = (options.room_message_cap, expires_at) {
+        let evicted = store.enforce_room_cap(&message.core.room_id, cap).await?;
+        if evicted > 0 {
+            info!(
+                "Evicted {} message(s) from room {} (cap {})",
+                evicted, message.core.room_id, cap
+            );
+            MetricsHelper::new().await.emit_message_evicted(&message.core.room_id, evicted).await;
+        }
+    }
 
-    info!("Retrieved {} messages for room {}", messages.len(), room_id);
+    Ok(Posted { message, duplicate: false })
+}
 
-    let response = GetMessagesResponse { room_id, messages };
-    Ok(response)
+/// Hash of what makes two posts the same message for content dedup: room, sender, recipient
+/// and text
+pub fn content_hash(
+    room_id: &str,
+    user_id: &str,
+    to_user_id: Option<&str>,
+    message_text: &str,
+) -> String {
+    let mut hasher = Sha256::new();
+    for part in [room_id, user_id, to_user_id.unwrap_or_default(), message_text] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+// The newest message in the room since `since` with the given content hash
+async fn recent_duplicate(
+    store: &dyn MessageStore,
+    room_id: &str,
+    hash: &str,
+    since: DateTime<Utc>,
+) -> Result<Option<ChatMessage>, String> {
+    let is_duplicate = |message: &ChatMessage| {
+        let core = &message.core;
+        content_hash(
+            &core.room_id,
+            &core.user_id,
+            message.to_user_id.as_deref(),
+            &core.message_text,
+        ) == hash
+    };
+    // Newest first, back to `since`, as a double-click's first copy is the latest one
+    let mut query = MessageQuery {
+        created_after: Some(since.timestamp_millis()),
+        limit: Some(store::MAX_MESSAGE_PAGE_SIZE),
+        order: SortOrder::Desc,
+        ..Default::default()
+    };
+    loop {
+        // Pages come back oldest first
+        let page = store.get_messages(room_id, &query).await?;
+        if let Some(duplicate) = page.iter().rev().find(|message| is_duplicate(message)) {
+            return Ok(Some(duplicate.clone()));
+        }
+        if page.len() < query.page_size() {
+            return Ok(None);
+        }
+        query.after = page.first().map(store::message_sort_key);
+    }
+}
+
+/// Display names nobody may take, compared case-insensitively. Names starting `anon-` are
+/// held back too, as they would pass for generated anonymous ids.
+pub const RESERVED_USERNAMES: [&str; 5] = ["admin", "moderator", "system", "server", "unknown"];
+
+fn is_reserved_username(username: &str) -> bool {
+    let lowered = username.to_lowercase();
+    RESERVED_USERNAMES.contains(&lowered.as_str()) || lowered.starts_with("anon-")
+}
+
+/// Change the display name on a user's profile. Messages posted from then on carry it;
+/// messages already stored keep the name they were sent with.
+pub async fn rename_user_handler(
+    store: &dyn MessageStore,
+    user_id: UserId,
+    request: UpdateUsernameRequest,
+) -> Result<UserRenamed, HandlerError> {
+    request.validate().map_err(HandlerError::Validation)?;
+    let user_id = validate_user_id(&user_id)?;
+    let username = validate_username(&request.username)?;
+    if is_reserved_username(&username) {
+        return Err(HandlerError::Conflict(format!("Username '{}' is reserved", username)));
+    }
+
+    let old_username = store.set_profile_username(&user_id, &username).await?;
+    info!("Renamed user {} from {:?} to {}", user_id, old_username, username);
+    Ok(UserRenamed { user_id, old_username, username })
+}
+
+/// Set or clear the avatar seed on a user's profile, returning the profile with the seed now
+/// in effect. Messages posted from then on carry it; messages already stored keep theirs.
+pub async fn set_avatar_seed_handler(
+    store: &dyn MessageStore,
+    user_id: UserId,
+    request: UpdateAvatarSeedRequest,
+) -> Result<UserProfile, HandlerError> {
+    request.validate().map_err(HandlerError::Validation)?;
+    let user_id = validate_user_id(&user_id)?;
+    let seed = validate_avatar_seed(request.avatar_seed.as_deref())?;
+
+    let profile = store.set_profile_avatar_seed(&user_id, seed.as_deref()).await?;
+    info!("Set avatar seed of user {} to {:?}", user_id, seed);
+    Ok(UserProfile { avatar_seed: Some(avatar_seed(&user_id, profile.avatar_seed)), ..profile })
+}
+
+/// Set or clear a room's topic, for callers `TOPIC_EDITORS` allows (`is_admin` when the admin
+/// token came with the request). None when the room doesn't exist.
+pub async fn set_room_topic_handler(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    request: UpdateTopicRequest,
+    is_admin: bool,
+) -> Result<Option<TopicChanged>, HandlerError> {
+    request.validate().map_err(HandlerError::Validation)?;
+    if *TOPIC_EDITORS == TopicEditors::Admins && !is_admin {
+        return Err(HandlerError::Forbidden("Only admins may change room topics".to_string()));
+    }
+    let room_id = validate_room_id(&room_id).map_err(|message| {
+        HandlerError::Validation(vec![ValidationError::new("room_id", message)])
+    })?;
+    let by = validate_user_id(&request.user_id)?;
+    let topic = validate_topic(request.topic.as_deref())?;
+
+    if !store.set_room_topic(&room_id, topic.as_deref()).await? {
+        info!("Room {} not found; topic not set", room_id);
+        return Ok(None);
+    }
+    info!("{} set the topic of room {} to {:?}", by, room_id, topic);
+    Ok(Some(TopicChanged { room_id, topic, by }))
+}
+
+/// Archive a room (admins only), or reopen it. Archived rooms keep their history readable but
+/// refuse new messages. None if the room doesn't exist.
+pub async fn set_room_archived_handler(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    archived: bool,
+) -> Result<Option<RoomArchiveState>, HandlerError> {
+    let room_id = validate_room_id(&room_id).map_err(|message| {
+        HandlerError::Validation(vec![ValidationError::new("room_id", message)])
+    })?;
+    if !store.set_room_archived(&room_id, archived).await? {
+        info!("Room {} not found; archive state unchanged", room_id);
+        return Ok(None);
+    }
+    info!("Room {} is now {}", room_id, if archived { "archived" } else { "open" });
+    Ok(Some(RoomArchiveState { room_id, archived }))
+}
+
+// Most rooms `GET /chat/rooms` returns at once, and how many it returns by default
+pub const MAX_ROOMS_PAGE: usize = 50;
+
+// Query of `GET /chat/rooms`. `after` is a previous page's `next_cursor`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoomQuery {
+    #[serde(default)]
+    pub include_archived: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub after: Option<String>,
+}
+
+// `{created_at epoch seconds}.{id}` of the last room on a page. Ids may hold dots, so they go last.
+fn room_cursor((created_at, id): &RoomKey) -> String {
+    format!("{}.{}", created_at, id)
+}
+
+fn parse_room_cursor(cursor: &str) -> Option<RoomKey> {
+    let (created_at, id) = cursor.split_once('.')?;
+    Some((created_at.parse().ok()?, id.to_string()))
+}
+
+/// A page of the rooms there are, newest first, leaving out archived ones unless
+/// `include_archived`. Up to `limit` rooms (capped at `MAX_ROOMS_PAGE`); `next_cursor` is set
+/// when there are more. Rows of the rooms table that can't be read are left out.
+pub async fn list_rooms_handler(
+    store: &dyn MessageStore,
+    query: RoomQuery,
+) -> Result<ListRoomsResponse, HandlerError> {
+    let limit = query.limit.unwrap_or(MAX_ROOMS_PAGE).clamp(1, MAX_ROOMS_PAGE);
+    let after = match &query.after {
+        Some(cursor) => Some(
+            parse_room_cursor(cursor)
+                .ok_or_else(|| HandlerError::BadRequest("Invalid after cursor".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let page = store.rooms_page(query.include_archived, after.as_ref(), limit).await?;
+    Ok(ListRoomsResponse { rooms: page.rooms, next_cursor: page.next.as_ref().map(room_cursor) })
+}
+
+// Largest request body read when MAX_BODY_BYTES is unset
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Largest request body the REST endpoints read (`MAX_BODY_BYTES`). Anything bigger is
+/// answered with 413 before it's buffered or parsed.
+pub fn max_body_bytes_from_env() -> usize {
+    env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|bytes| *bytes > 0)
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// Why a REST request body was refused before it reached a handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyError {
+    // Bigger than the limit, in bytes
+    TooLarge(usize),
+    // Empty, or not JSON of the expected shape
+    Malformed(String),
+}
+
+impl BodyError {
+    pub fn status(&self) -> u16 {
+        match self {
+            BodyError::TooLarge(_) => 413,
+            BodyError::Malformed(_) => 400,
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(_) => f.write_str("Request body too large"),
+            BodyError::Malformed(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Refuse a body over `max_bytes` (see `max_body_bytes_from_env`) before anything parses it
+pub fn check_body_size(len: usize, max_bytes: usize) -> Result<(), BodyError> {
+    if len > max_bytes {
+        return Err(BodyError::TooLarge(max_bytes));
+    }
+    Ok(())
+}
+
+/// Parse a JSON request body, describing what's wrong with it when it can't be
+pub fn parse_json_body<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BodyError> {
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Err(BodyError::Malformed("Request body is empty".to_string()));
+    }
+    serde_json::from_slice(bytes)
+        .map_err(|e| BodyError::Malformed(format!("Request body is not valid JSON: {}", e)))
+}
+
+/// Response header mirroring `GetMessagesResponse::server_time` as RFC 3339
+pub const SERVER_TIME_HEADER: &str = "x-server-time";
+
+/// Path of a single message, used as the `Location` of a newly created message
+pub fn message_location(message: &ChatMessage) -> String {
+    format!("/chat/messages/{}/{}", message.core.room_id, message.core.id)
+}
+
+/// A page of the room's messages. `query.after` is a previous page's `next_cursor`, refused
+/// once it's `MAX_PAGE_DEPTH` pages deep.
+pub async fn get_messages_handler(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    query: MessageQuery,
+) -> Result<GetMessagesResponse, HandlerError> {
+    list_messages(store, room_id, query, &PAGE_CURSORS).await
+}
+
+async fn list_messages(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    mut query: MessageQuery,
+    cursors: &PageCursors,
+) -> Result<GetMessagesResponse, HandlerError> {
+    let room_id = validate_room_id(&room_id)?;
+    // Pages read so far, this one included
+    let depth = match query.after.take() {
+        Some(cursor) => {
+            let (sort_key, depth) = cursors.open(&cursor).map_err(|error| match error {
+                CursorError::Invalid => ValidationError::new("after", "Invalid cursor").into(),
+                CursorError::DepthExceeded { max_depth } => {
+                    warn!(
+                        "Refusing page {} of room {} (max {})",
+                        max_depth + 1,
+                        room_id,
+                        max_depth
+                    );
+                    HandlerError::BadRequest(PAGINATION_DEPTH_EXCEEDED.to_string())
+                }
+            })?;
+            query.after = Some(sort_key);
+            depth + 1
+        }
+        None => 1,
+    };
+    let mut messages = store.get_messages(&room_id, &query).await?;
+
+    info!("Retrieved {} messages for room {}", messages.len(), room_id);
+
+    // Taken before other users' direct messages are dropped, so the page may come back short
+    let full = messages.len() == query.page_size();
+    // Read back from `before`, a full page may have more before it. The cursor is a
+    // millisecond, so the oldest one is left for the next page, where it'll be whole; unless
+    // it's the whole page, as then there'd be nothing left to return.
+    let before_cursor = (query.before.is_some() && full)
+        .then(|| {
+            let millis = |message: &ChatMessage| message.core.created_at.timestamp_millis();
+            let oldest = millis(messages.first()?);
+            if messages.iter().any(|message| millis(message) != oldest) {
+                messages.retain(|message| millis(message) != oldest);
+            }
+            messages.first().map(millis)
+        })
+        .flatten();
+    if query.order == SortOrder::Desc {
+        messages.reverse();
+    }
+    // A full page may have more behind it; its last key, in the order asked for, is where the
+    // next one starts
+    let next_cursor = full
+        .then(|| messages.last().map(|last| cursors.issue(&store::message_sort_key(last), depth)))
+        .flatten();
+    messages.retain(|message| visible_to(message, query.user_id.as_deref()));
+    messages.iter_mut().for_each(redact_hidden);
+    let response = GetMessagesResponse {
+        room_id,
+        messages,
+        server_time: store.clock().now(),
+        next_cursor,
+        before_cursor,
+    };
+    Ok(response)
+}
+
+/// A page with each message cut down to `fields`; the page's own keys are kept as they are
+pub fn project_messages(
+    response: &GetMessagesResponse,
+    fields: &[&str],
+) -> Result<serde_json::Value, String> {
+    let mut page = serde_json::to_value(response).map_err(|e| e.to_string())?;
+    if let Some(messages) = page.get_mut("messages").and_then(serde_json::Value::as_array_mut) {
+        for message in messages.iter_mut().filter_map(serde_json::Value::as_object_mut) {
+            message.retain(|key, _| fields.contains(&key.as_str()));
+        }
+    }
+    Ok(page)
+}
+
+// How far behind the clock an empty poll may move the cursor. A message's timestamp is taken
+// just before it is stored, so the newest instants can still fill in.
+const POLL_CURSOR_LAG_MS: i64 = 5_000;
+
+/// A poll cursor past every message created by `millis` (epoch millis). It sorts after every
+/// sort key in that millisecond, as `sort_key_bounds` does for `created_before`.
+pub fn poll_cursor(millis: i64) -> String {
+    format!("{}$", store::sort_key_prefix(millis))
+}
+
+/// Messages in the room after `cursor` that `viewer` may see, with the cursor for the next
+/// poll. The cursor is the sort key of the newest message seen (see `store::sort_key`), so
+/// one stored later in the same millisecond still comes through, given ids that sort in the
+/// order they're made (`MESSAGE_ID_FORMAT=ulid`). Bare epoch millis from older clients are
+/// read as `poll_cursor` of them.
+pub async fn poll_messages_handler(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    cursor: &str,
+    viewer: Option<&str>,
+) -> Result<PollMessagesResponse, HandlerError> {
+    let room_id = validate_room_id(&room_id)?;
+    let cursor = match cursor.parse::<i64>() {
+        Ok(millis) => poll_cursor(millis),
+        Err(_) => cursor.to_string(),
+    };
+    let query = MessageQuery { after: Some(cursor.clone()), ..Default::default() };
+    let mut messages = store.get_messages(&room_id, &query).await?;
+
+    let now = store.clock().now();
+    let cursor = match messages.last() {
+        Some(newest) => store::message_sort_key(newest),
+        None => cursor.max(poll_cursor(now.timestamp_millis() - POLL_CURSOR_LAG_MS)),
+    };
+    messages.retain(|message| visible_to(message, viewer));
+    messages.iter_mut().for_each(redact_hidden);
+    Ok(PollMessagesResponse { room_id, messages, cursor, server_time: now })
+}
+
+/// What a socket reconnecting to the room missed since `last_seen_ts` (epoch milliseconds):
+/// the oldest `limit` messages after it that `viewer` may see, oldest first. Anything past
+/// `limit` is left for the client to page in over REST from the last one sent.
+pub async fn missed_messages(
+    store: &dyn MessageStore,
+    room_id: &str,
+    viewer: &str,
+    last_seen_ts: i64,
+    limit: usize,
+) -> Result<Vec<ChatMessage>, String> {
+    let room_id = validate_room_id(room_id)?;
+    let mut query = MessageQuery {
+        created_after: Some(last_seen_ts.saturating_add(1)),
+        limit: Some(limit.clamp(1, store::MAX_MESSAGE_PAGE_SIZE)),
+        ..Default::default()
+    };
+    let mut missed = Vec::new();
+    // Other users' direct messages are dropped from each page, so read on until enough are left
+    while missed.len() < limit {
+        let page = store.get_messages(&room_id, &query).await?;
+        let full = page.len() == query.page_size();
+        let Some(last) = page.last() else {
+            break;
+        };
+        query.after = Some(store::message_sort_key(last));
+        query.created_after = None;
+        missed.extend(page.into_iter().filter(|message| visible_to(message, Some(viewer))).map(
+            |mut message| {
+                redact_hidden(&mut message);
+                message
+            },
+        ));
+        if !full {
+            break;
+        }
+    }
+    missed.truncate(limit);
+    Ok(missed)
+}
+
+/// Most messages one search returns
+pub const MAX_SEARCH_RESULTS: usize = 25;
+
+/// Messages in the room matching `query`, newest first. With a search index configured the
+/// index answers; without one, or if it fails, the room is filtered in DynamoDB instead.
+pub async fn search_messages_handler(
+    store: &dyn MessageStore,
+    index: Option<&dyn SearchIndex>,
+    room_id: RoomId,
+    query: String,
+) -> Result<SearchMessagesResponse, HandlerError> {
+    let mut errors = Vec::new();
+    let room_id = validate_room_id(&room_id).unwrap_or_else(|message| {
+        errors.push(ValidationError::new("room_id", message));
+        String::new()
+    });
+    // Search text is held to the same limit as message text
+    let query = query.trim().to_string();
+    if query.is_empty() {
+        errors.push(ValidationError::new("q", "Search text cannot be empty"));
+    } else if let Err(error) = Limits::check_length("q", "Search text", &query, LIMITS.message_text)
+    {
+        errors.push(error);
+    }
+    if !errors.is_empty() {
+        return Err(HandlerError::Validation(errors));
+    }
+
+    let indexed = match index {
+        Some(index) => match index.search(&room_id, &query, MAX_SEARCH_RESULTS).await {
+            Ok(hits) => Some(hits),
+            Err(e) => {
+                warn!("Search index failed for room {}, filtering instead: {}", room_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut messages = match indexed {
+        Some(hits) => hits,
+        None => store.search_messages(&room_id, &query, MAX_SEARCH_RESULTS).await?,
+    };
+    // Searches are anonymous, so no direct message is anyone's to find. Nor is a hidden one,
+    // whose match would give away the text it no longer shows.
+    messages.retain(|message| visible_to(message, None) && message.status != MessageStatus::Hidden);
+    info!("Search in room {} matched {} message(s)", room_id, messages.len());
+    Ok(SearchMessagesResponse { room_id, query, messages })
+}
+
+/// Most rooms one `POST /chat/messages/latest` may ask for
+pub const MAX_LATEST_ROOMS: usize = 20;
+
+/// Most messages per room one `POST /chat/messages/latest` may ask for
+pub const MAX_LATEST_PER_ROOM: usize = 25;
+
+// Room queries a bulk fetch keeps in flight at once
+const LATEST_CONCURRENCY: usize = 5;
+
+/// The newest `per_room` messages (newest first) of each requested room, queried concurrently.
+/// Room ids are normalized like everywhere else, so the maps are keyed by the normalized id. A
+/// room whose query fails is reported in `errors` rather than failing the rest; only when every
+/// room fails is the whole request an error.
+pub async fn latest_messages_handler(
+    store: &dyn MessageStore,
+    request: LatestMessagesRequest,
+) -> Result<LatestMessagesResponse, HandlerError> {
+    request.validate().map_err(HandlerError::Validation)?;
+    let room_ids: HashSet<String> = request
+        .room_ids
+        .iter()
+        .map(|room_id| validate_room_id(room_id))
+        .collect::<Result<_, _>>()?;
+    let per_room = usize::from(request.per_room);
+
+    let results: Vec<(String, Result<Vec<ChatMessage>, StoreError>)> = stream::iter(room_ids)
+        .map(|room_id| async move {
+            let messages = store.latest_messages(&room_id, per_room).await.map(|mut messages| {
+                // Anonymous like searches, so direct messages are left out
+                messages.retain(|message| visible_to(message, None));
+                messages.iter_mut().for_each(redact_hidden);
+                messages
+            });
+            (room_id, messages)
+        })
+        .buffer_unordered(LATEST_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut latest = LatestMessagesResponse::default();
+    let mut first_error = None;
+    for (room_id, result) in results {
+        match result {
+            Ok(messages) => {
+                latest.rooms.insert(room_id, messages);
+            }
+            Err(e) => {
+                warn!("Failed to get latest messages of room {}: {}", room_id, e);
+                latest.errors.insert(room_id, e.to_string());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(error) if latest.rooms.is_empty() => Err(error.into()),
+        _ => Ok(latest),
+    }
+}
+
+// Newest messages scanned for distinct participants, keeping room stats to one small query
+const ROOM_STATS_SAMPLE: usize = 100;
+
+/// Aggregates for one room, gathered concurrently. `connections` is the connections table to
+/// count from; without one the room reports no active connections.
+pub async fn room_stats_handler(
+    store: &dyn MessageStore,
+    connections: Option<(&DynamoDbClient, &str)>,
+    room_id: RoomId,
+) -> Result<RoomStats, HandlerError> {
+    let room_id = validate_room_id(&room_id)?;
+    let active_connections = async {
+        match connections {
+            Some((ddb, table)) => Ok(count_room_connections(ddb, table, &room_id).await?),
+            None => Ok(0),
+        }
+    };
+
+    let (message_count, recent, active_connections) = tokio::try_join!(
+        async { Ok::<_, HandlerError>(store.count_messages(&room_id).await?) },
+        async { Ok(store.latest_messages(&room_id, ROOM_STATS_SAMPLE).await?) },
+        active_connections,
+    )?;
+
+    let participants: HashSet<&str> = recent.iter().map(|m| m.core.username.as_str()).collect();
+    Ok(RoomStats {
+        unique_participants: participants.len() as u32,
+        sample_size: recent.len() as u32,
+        last_activity: recent.first().map(|newest| newest.core.created_at),
+        room_id,
+        message_count,
+        active_connections,
+    })
+}
+
+/// Connections currently in the room, counted on the `room-index` GSI
+pub async fn count_room_connections(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    room_id: &str,
+) -> Result<u32, String> {
+    let mut pages = ddb
+        .query()
+        .table_name(connections_table)
+        .index_name("room-index")
+        .key_condition_expression("room_id = :room_id")
+        .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+        .select(Select::Count)
+        .into_paginator()
+        .send();
+
+    let mut count = 0;
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to count connections: {:?}", e))?;
+        count += page.count() as u32;
+    }
+    Ok(count)
+}
+
+/// Who's connected to a room, one username per user. `connections` is the connections table to
+/// read from; without one the room is empty. `now` is in epoch seconds.
+pub async fn room_presence_handler(
+    connections: Option<(&DynamoDbClient, &str)>,
+    room_id: RoomId,
+    now: i64,
+) -> Result<RoomPresence, String> {
+    let room_id = validate_room_id(&room_id)?;
+    let users = match connections {
+        Some((ddb, table)) => {
+            presence_users(&live_room_connections(ddb, table, &room_id, now).await?)
+        }
+        None => Vec::new(),
+    };
+    Ok(RoomPresence { room_id, users })
+}
+
+/// Connections in the room on the `room-index` GSI, less those whose TTL has passed: TTL
+/// deletion can lag by hours, and an expired row is a client that never said goodbye.
+pub async fn live_room_connections(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    room_id: &str,
+    now: i64,
+) -> Result<Vec<HashMap<String, AttributeValue>>, String> {
+    let mut pages = ddb
+        .query()
+        .table_name(connections_table)
+        .index_name("room-index")
+        .key_condition_expression("room_id = :room_id")
+        .filter_expression("attribute_not_exists(#ttl) OR #ttl > :now")
+        .expression_attribute_names("#ttl", "ttl")
+        .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .into_paginator()
+        .send();
+
+    let mut connections = Vec::new();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to query connections: {:?}", e))?;
+        connections.extend(page.items.unwrap_or_default());
+    }
+    Ok(connections)
+}
+
+/// The usernames behind `connections`, one per user id, sorted. A user with several
+/// connections goes by the name on their newest; rows without a user id are skipped.
+pub fn presence_users(connections: &[HashMap<String, AttributeValue>]) -> Vec<String> {
+    let mut newest: HashMap<&str, (i64, String)> = HashMap::new();
+    for connection in connections {
+        let Some(user_id) = connection.get("user_id").and_then(|v| v.as_s().ok()) else {
+            continue;
+        };
+        let connected_at = connection
+            .get("connected_at")
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<i64>().ok())
+            .unwrap_or(0);
+        if newest.get(user_id.as_str()).is_some_and(|(seen, _)| *seen > connected_at) {
+            continue;
+        }
+        let username = connection
+            .get("username")
+            .and_then(|v| v.as_s().ok())
+            .cloned()
+            .unwrap_or_else(|| display_name_fallback(Some(user_id)));
+        newest.insert(user_id, (connected_at, username));
+    }
+    let mut users: Vec<String> = newest.into_values().map(|(_, username)| username).collect();
+    users.sort();
+    users
+}
+
+/// One message by id, if `viewer` may see it. A direct message to someone else is reported
+/// missing, as it would be from a page, so its id says nothing about it.
+pub async fn get_message_handler(
+    store: &dyn MessageStore,
+    room_id: RoomId,
+    message_id: MessageId,
+    viewer: Option<&str>,
+) -> Result<Option<ChatMessage>, HandlerError> {
+    let room_id = validate_room_id(&room_id)?;
+    let mut message = store
+        .get_message(&room_id, &message_id)
+        .await?
+        .filter(|message| visible_to(message, viewer));
+    if let Some(message) = &mut message {
+        redact_hidden(message);
+    }
+    if message.is_none() {
+        info!("Message {} not found in room {}", message_id, room_id);
+    }
+    Ok(message)
+}
+
+/// Delete a message on its poster's behalf; no one else may. The message comes back as it was
+/// stored, for the caller to tell the room. None if there's no such message (or it's been
+/// compacted, and can't be found by id any more).
+pub async fn delete_message_handler(
+    store: &dyn MessageStore,
+    metrics: &MetricsHelper,
+    room_id: RoomId,
+    message_id: MessageId,
+    user_id: Option<&str>,
+) -> Result<Option<ChatMessage>, HandlerError> {
+    let room_id = validate_room_id(&room_id).map_err(|message| {
+        HandlerError::Validation(vec![ValidationError::new("room_id", message)])
+    })?;
+    let user_id = validate_user_id(user_id.unwrap_or_default())?;
+    let Some(message) = store.get_message(&room_id, &message_id).await? else {
+        info!("Message {} not found in room {}; nothing to delete", message_id, room_id);
+        return Ok(None);
+    };
+    if message.core.user_id != user_id {
+        return Err(HandlerError::Forbidden(format!(
+            "Only the poster may delete message {}",
+            message_id
+        )));
+    }
+
+    // Deleted in between, by an earlier try of the same request
+    if !store.delete_message(&message).await? {
+        info!("Message {} was already gone", message_id);
+        return Ok(None);
+    }
+    info!("{} deleted message {} from room {}", user_id, message_id, room_id);
+    metrics.emit_message_deleted(&room_id).await;
+    Ok(Some(message))
+}
+
+/// Replace a message's text on its poster's behalf; no one else may. The message comes back
+/// as edited, stamped with `edited_at`. None if there's no such message (or it's been
+/// compacted).
+pub async fn edit_message_handler(
+    store: &dyn MessageStore,
+    context: &RequestContext,
+    room_id: RoomId,
+    message_id: MessageId,
+    request: EditMessageRequest,
+    is_admin: bool,
+) -> Result<Option<ChatMessage>, HandlerError> {
+    edit_message(store, context, room_id, message_id, request, is_admin, &TEXT_PIPELINE).await
+}
+
+// The new text goes through `pipeline`, as posts' text does, unless the message is code
+async fn edit_message(
+    store: &dyn MessageStore,
+    context: &RequestContext,
+    room_id: RoomId,
+    message_id: MessageId,
+    request: EditMessageRequest,
+    is_admin: bool,
+    pipeline: &TextPipeline,
+) -> Result<Option<ChatMessage>, HandlerError> {
+    if let Err(errors) = request.validate() {
+        count_blocked(&room_id, &errors).await;
+        return Err(HandlerError::Validation(errors));
+    }
+    let room_id = validate_room_id(&room_id).map_err(|message| {
+        HandlerError::Validation(vec![ValidationError::new("room_id", message)])
+    })?;
+    let user_id = validate_user_id(&request.user_id)?;
+    validate_message_text(&request.message_text)?;
+    let Some(message) = store.get_message(&room_id, &message_id).await? else {
+        info!("Message {} not found in room {}; nothing to edit", message_id, room_id);
+        return Ok(None);
+    };
+    // Transformed as the message's format is, which an edit keeps
+    let message_text = message_text(&request.message_text, &message.format, pipeline)?;
+    if message.core.user_id != user_id {
+        return Err(HandlerError::Forbidden(format!(
+            "Only the poster may edit message {}",
+            message_id
+        )));
+    }
+    // Admins may fix up older messages too
+    let now = context.clock.now();
+    if !is_admin && now - message.core.created_at > *EDIT_WINDOW {
+        info!(
+            "Refused edit of message {}: older than {} seconds",
+            message_id,
+            EDIT_WINDOW.num_seconds()
+        );
+        return Err(HandlerError::Forbidden(EDIT_WINDOW_EXPIRED.to_string()));
+    }
+
+    let edited = ChatMessage {
+        core: MessageCore { message_text, ..message.core },
+        edited_at: Some(now),
+        link_preview: None,
+        ..message
+    };
+    if !store.edit_message(&edited).await? {
+        info!("Message {} was deleted before it could be edited", message_id);
+        return Ok(None);
+    }
+    info!("{} edited message {} in room {}", user_id, message_id, room_id);
+    Ok(Some(edited))
+}
+
+/// Look a message up by id alone. The DynamoDB store has no index on `id` and scans, so this
+/// backs admin tooling, not the request path.
+pub async fn find_message_by_id(
+    store: &dyn MessageStore,
+    message_id: &str,
+) -> Result<Option<ChatMessage>, String> {
+    let message = store.find_message(message_id).await?;
+    if message.is_none() {
+        info!("Message {} not found", message_id);
+    }
+    Ok(message)
+}
+
+/// A message with its recorded sender origin, for admin-token routes only
+pub async fn admin_message_handler(
+    store: &dyn MessageStore,
+    message_id: &str,
+) -> Result<Option<AdminMessageView>, String> {
+    let Some(message) = find_message_by_id(store, message_id).await? else {
+        return Ok(None);
+    };
+    let origin = store.message_origin(&message.core.room_id, &message.core.id).await?;
+    Ok(Some(AdminMessageView {
+        origin_hash: origin.as_ref().map(|origin| origin.hash.clone()),
+        origin_country: origin.and_then(|origin| origin.country),
+        message,
+    }))
+}
+
+/// Flag a message for moderators on a user's behalf. Flags are counted per client origin, so
+/// the flag from the `AUTO_HIDE_FLAGS`th origin hides it. None if there's no such message in
+/// the room; each client may flag a message only once. `client_ip`, like a post's, is only
+/// hashed.
+pub async fn flag_message_handler(
+    store: &dyn MessageStore,
+    context: &RequestContext,
+    room_id: RoomId,
+    message_id: MessageId,
+    request: FlagMessageRequest,
+    client_ip: Option<IpAddr>,
+) -> Result<Option<FlagMessageResponse>, HandlerError> {
+    let origin = ORIGIN_CAPTURE.capture(client_ip);
+    record_flag(store, context, room_id, message_id, request, origin.as_ref()).await
+}
+
+async fn record_flag(
+    store: &dyn MessageStore,
+    context: &RequestContext,
+    room_id: RoomId,
+    message_id: MessageId,
+    request: FlagMessageRequest,
+    origin: Option<&MessageOrigin>,
+) -> Result<Option<FlagMessageResponse>, HandlerError> {
+    let room_id = validate_room_id(&room_id).map_err(|message| {
+        HandlerError::Validation(vec![ValidationError::new("room_id", message)])
+    })?;
+    request.validate().map_err(HandlerError::Validation)?;
+    let Some(message) = store.get_message(&room_id, &message_id).await? else {
+        info!("Message {} not found in room {}; nothing to flag", message_id, room_id);
+        return Ok(None);
+    };
+    let flag = MessageFlag {
+        message_id: message.core.id.clone(),
+        room_id: message.core.room_id.clone(),
+        user_id: validate_user_id(&request.user_id)?,
+        reason: validate_flag_reason(&request.reason)?,
+        created_at: context.clock.now(),
+    };
+
+    // Without an origin there's only the user_id to go by, which doesn't count towards hiding
+    let flagger = origin.map_or(flag.user_id.as_str(), |origin| origin.hash.as_str());
+    let Some(flag_count) = store.flag_message(&message, &flag, flagger).await? else {
+        return Err(HandlerError::Conflict(format!(
+            "Message {} has already been flagged from here",
+            flag.message_id
+        )));
+    };
+    info!("{} flagged message {} ({} flags)", flag.user_id, flag.message_id, flag_count);
+
+    let mut hidden = message.status == MessageStatus::Hidden;
+    if !hidden && origin.is_some() && flag_count >= *AUTO_HIDE_FLAGS {
+        store.hide_message(&message).await?;
+        info!("Hid message {} after {} flags", message.core.id, flag_count);
+        hidden = true;
+    }
+    Ok(Some(FlagMessageResponse {
+        message_id: flag.message_id,
+        room_id: flag.room_id,
+        flag_count,
+        hidden,
+    }))
+}
+
+// Most flags the moderator queue returns at once
+const MAX_FLAGS_LISTED: usize = 100;
+
+/// Flags awaiting moderators, for admin-token routes only
+pub async fn flags_handler(store: &dyn MessageStore) -> Result<Vec<MessageFlag>, String> {
+    store.list_flags(MAX_FLAGS_LISTED).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        search::MemorySearchIndex,
+        store::{DynamoMessageStore, MemoryMessageStore},
+        test_support::{
+            create_connections_table, create_messages_table, create_table, key, local_ddb, Capture,
+        },
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_check_key_schema() {
+        let schema = [key("room_id", KeyType::Hash), key("ts", KeyType::Range)];
+        assert!(check_key_schema("chat-messages", &schema, "room_id", Some("ts")).is_ok());
+
+        let err =
+            check_key_schema("chat-messages", &schema[..1], "room_id", Some("ts")).unwrap_err();
+        assert_eq!(
+            err,
+            "Table 'chat-messages' has key schema [room_id HASH] but expected [room_id HASH, ts RANGE]"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_the_messages_table() {
+        let store = MemoryMessageStore::new();
+        let at = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let context = RequestContext::deterministic(at);
+        let health = health_handler(&store, &context).await;
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.timestamp, at);
+        assert_eq!(health.checks["messages_table"], HealthStatus::Healthy);
+
+        store.fail_probes_with(ProbeError::Unreachable("connection refused".to_string()));
+        let health = health_handler(&store, &context).await;
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.checks["messages_table"], HealthStatus::Degraded);
+
+        store.fail_probes_with(ProbeError::Missing("no such table".to_string()));
+        assert_eq!(health_handler(&store, &context).await.status, HealthStatus::Unhealthy);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_tables_verify_against_local_dynamodb() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "verify-test-rooms".to_string(),
+            messages: "verify-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        assert!(tables.verify(&ddb).await.is_ok());
+
+        // Messages table keyed only by id is the classic misconfiguration
+        create_table(&ddb, &tables.messages, &[("id", KeyType::Hash)]).await;
+        let err = tables.verify(&ddb).await.unwrap_err();
+        assert!(err.contains("expected [room_id HASH, sk RANGE]"), "{}", err);
+
+        // So is a table that hasn't been migrated to the sk sort key
+        create_table(&ddb, &tables.messages, &[("room_id", KeyType::Hash), ("ts", KeyType::Range)])
+            .await;
+        assert!(tables.verify(&ddb).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_created_message_location_is_fetchable() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "location-test-rooms".to_string(),
+            messages: "location-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+
+        let request = SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: "Hello!".to_string(),
+            client_message_id: None,
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        };
+        let created = post_message_handler(&store, &RequestContext::default(), request, None)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(
+            message_location(&created),
+            format!("/chat/messages/general/{}", created.core.id)
+        );
+
+        let fetched =
+            get_message_handler(&store, "general".into(), created.core.id.clone().into(), None)
+                .await
+                .unwrap()
+                .expect("created message should be fetchable");
+        assert_eq!(fetched.core.id, created.core.id);
+        assert_eq!(fetched.core.message_text, "Hello!");
+
+        let missing =
+            get_message_handler(&store, "general".into(), "missing".into(), None).await.unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn test_bad_request_bodies_say_what_is_wrong() {
+        for empty in [&b""[..], b"  \n"] {
+            let err = parse_json_body::<SendMessageRequest>(empty).unwrap_err();
+            assert_eq!((err.status(), err.to_string()), (400, "Request body is empty".to_string()));
+        }
+
+        let err = parse_json_body::<SendMessageRequest>(b"{\"room_id\": ").unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert!(err.to_string().starts_with("Request body is not valid JSON: "), "{}", err);
+        // Well-formed JSON of the wrong shape is malformed too
+        let err = parse_json_body::<SendMessageRequest>(b"[1, 2]").unwrap_err();
+        assert_eq!(err.status(), 400);
+
+        let request: SendMessageRequest = parse_json_body(
+            br#"{"room_id":"general","user_id":"u1","username":"ann","message_text":"hi"}"#,
+        )
+        .unwrap();
+        assert_eq!(request.message_text, "hi");
+
+        assert_eq!(check_body_size(DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_BODY_BYTES), Ok(()));
+        let err = check_body_size(DEFAULT_MAX_BODY_BYTES + 1, DEFAULT_MAX_BODY_BYTES).unwrap_err();
+        assert_eq!((err.status(), err.to_string()), (413, "Request body too large".to_string()));
+    }
+
+    #[test]
+    fn test_deterministic_message_ids() {
+        let (general, random) = (RoomId::from("general"), RoomId::from("random"));
+        let user = UserId::from("01ARZ3NDEKTSV4RRFFQ69G5FB1");
+        let ids = crate::id_generator::UuidV4Generator;
+        let id = message_id(&general, &user, Some("client-1"), true, &ids);
+        assert_eq!(id, message_id(&general, &user, Some("client-1"), true, &ids));
+        assert_ne!(id, message_id(&random, &user, Some("client-1"), true, &ids));
+        assert_ne!(id, message_id(&general, &user, Some("client-2"), true, &ids));
+        assert_ne!(id, message_id(&general, &"someone-else".into(), Some("client-1"), true, &ids));
+
+        // Without the flag or a client id every message gets a fresh id
+        assert_ne!(
+            message_id(&general, &user, Some("client-1"), false, &ids),
+            message_id(&general, &user, Some("client-1"), false, &ids)
+        );
+        assert_ne!(
+            message_id(&general, &user, None, true, &ids),
+            message_id(&general, &user, None, true, &ids)
+        );
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_get_messages_reports_server_time() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "server-time-test-rooms".to_string(),
+            messages: "server-time-test-messages".to_string(),
+        };
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+
+        let response =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let skew = (Utc::now() - response.server_time).num_milliseconds().abs();
+        assert!(skew < 5_000, "server_time is {}ms from now", skew);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_deterministic_retry_stores_one_row() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "dedupe-test-rooms".to_string(),
+            messages: "dedupe-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+
+        let request = |client_message_id: &str| SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: "Hello!".to_string(),
+            client_message_id: Some(client_message_id.to_string()),
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        };
+        let options = PostOptions { deterministic_ids: true, ..Default::default() };
+        let first =
+            store_message(&store, &RequestContext::default(), request("client-1"), options, None)
+                .await
+                .unwrap()
+                .message;
+        let retry =
+            store_message(&store, &RequestContext::default(), request("client-1"), options, None)
+                .await
+                .unwrap()
+                .message;
+        let other =
+            store_message(&store, &RequestContext::default(), request("client-2"), options, None)
+                .await
+                .unwrap()
+                .message;
+        assert_eq!(first.core.id, retry.core.id);
+        assert_eq!(
+            first.core.created_at.timestamp_millis(),
+            retry.core.created_at.timestamp_millis()
+        );
+        assert_ne!(first.core.id, other.core.id);
+
+        let stored =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert_eq!(stored.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_client_message_id_retries_and_races_store_one_row() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "idempotent-test-rooms".to_string(),
+            messages: "idempotent-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+        let context = RequestContext::default();
+        let post = |client_message_id: &str| SendMessageRequest {
+            client_message_id: Some(client_message_id.to_string()),
+            ..send_request("general", "alice", "Hello!")
+        };
+        let options = PostOptions::default();
+
+        let first = store_message(&store, &context, post("client-1"), options, None).await.unwrap();
+        assert!(!first.duplicate);
+        let retry = store_message(&store, &context, post("client-1"), options, None).await.unwrap();
+        assert!(retry.duplicate);
+        assert_eq!(retry.message.core.id, first.message.core.id);
+
+        // Both miss the index, so the claim decides which one stores
+        let (a, b) = tokio::join!(
+            store_message(&store, &context, post("client-2"), options, None),
+            store_message(&store, &context, post("client-2"), options, None),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.message.core.id, b.message.core.id);
+        assert!(a.duplicate != b.duplicate);
+
+        let stored =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert_eq!(stored.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_message_id_retry_gets_the_stored_message() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let post = |user_id: &str| SendMessageRequest {
+            user_id: user_id.to_string(),
+            client_message_id: Some("client-1".to_string()),
+            ..send_request("general", "alice", "Hello!")
+        };
+        let options = PostOptions::default();
+
+        let first = store_message(&store, &context, post("alice"), options, None).await.unwrap();
+        assert!(!first.duplicate);
+        let retry = store_message(&store, &context, post("alice"), options, None).await.unwrap();
+        assert!(retry.duplicate);
+        assert_eq!(retry.message.core.id, first.message.core.id);
+        // Another sender's client ids are their own
+        let other = store_message(&store, &context, post("bob"), options, None).await.unwrap();
+        assert!(!other.duplicate);
+
+        // A retry that raced past the lookup is stopped at the put
+        let mut late = first.message.clone();
+        late.core.id = "late".to_string();
+        match store.put_message(&late, None).await {
+            Err(PutMessageError::ClientMessageTaken(id)) => assert_eq!(id, first.message.core.id),
+            other => panic!("expected the client message to be taken, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_room_message_cap_evicts_oldest() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "cap-test-rooms".to_string(),
+            messages: "cap-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+
+        let options = PostOptions { room_message_cap: Some(5), ..Default::default() };
+        let mut posted = Vec::new();
+        for i in 0..6 {
+            let request = SendMessageRequest {
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: format!("message {}", i),
+                client_message_id: None,
+                expires_in_secs: None,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                client_seq: None,
+                to_user_id: None,
+            };
+            posted.push(
+                store_message(&store, &RequestContext::default(), request, options, None)
+                    .await
+                    .unwrap()
+                    .message,
+            );
+            // Keep each message on its own ts sort key
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+            let stored = get_messages_handler(&store, "general".into(), MessageQuery::default())
+                .await
+                .unwrap();
+            assert!(stored.messages.len() <= 5);
+        }
+
+        let stored =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let ids: Vec<_> = stored.messages.iter().map(|m| m.core.id.clone()).collect();
+        let expected: Vec<_> = posted[1..].iter().map(|m| m.core.id.clone()).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn test_ephemeral_message_expiry() {
+        let now = Utc::now();
+        assert_eq!(message_expiry(now, None), None);
+        assert_eq!(message_expiry(now, Some(30)), Some(now + chrono::Duration::seconds(30)));
+
+        assert!(validate_expires_in(Some(0)).is_err());
+        assert!(validate_expires_in(Some(MAX_EXPIRES_IN_SECS + 1)).is_err());
+        assert_eq!(validate_expires_in(Some(60)), Ok(Some(60)));
+    }
+
+    fn ephemeral_request(expires_in_secs: u64) -> SendMessageRequest {
+        SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: "Gone soon".to_string(),
+            client_message_id: None,
+            expires_in_secs: Some(expires_in_secs),
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_times_come_from_the_context_clock() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let clock = Arc::new(MockClock::new(at));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+
+        let posted =
+            store_message(&store, &context, ephemeral_request(30), PostOptions::default(), None)
+                .await
+                .unwrap()
+                .message;
+        assert_eq!(posted.core.created_at, at);
+        assert_eq!(posted.expires_at, Some(at + chrono::Duration::seconds(30)));
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert_eq!(page.server_time, at);
+        assert_eq!(page.messages.len(), 1);
+
+        // Expiry is judged by the same clock, to the second
+        clock.advance(chrono::Duration::seconds(30));
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert!(page.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missed_messages_fill_the_limit_past_one_page() {
+        let last_seen_ts = 1_700_000_000_000;
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(last_seen_ts).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        for n in 0..store::MESSAGE_PAGE_SIZE * 3 {
+            clock.advance(chrono::Duration::milliseconds(1));
+            // Every third is someone else's direct message, which the viewer never gets
+            let request = SendMessageRequest {
+                to_user_id: (n % 3 == 2).then(|| "carol".to_string()),
+                ..send_request("general", "alice", &format!("m{}", n))
+            };
+            post_message_handler(&store, &context, request, None).await.unwrap();
+        }
+
+        let limit = store::MESSAGE_PAGE_SIZE * 2;
+        let missed = missed_messages(&store, "general", "bob", last_seen_ts, limit).await.unwrap();
+        let texts: Vec<_> = missed.iter().map(|m| m.core.message_text.clone()).collect();
+        let expected: Vec<_> = (0..store::MESSAGE_PAGE_SIZE * 3)
+            .filter(|n| n % 3 != 2)
+            .map(|n| format!("m{}", n))
+            .collect();
+        assert_eq!(texts, expected);
+        assert_eq!(texts.len(), limit);
+
+        // The oldest `limit` of them, when there are more
+        let missed = missed_messages(&store, "general", "bob", last_seen_ts, 30).await.unwrap();
+        let texts: Vec<_> = missed.iter().map(|m| m.core.message_text.clone()).collect();
+        assert_eq!(texts, expected[..30]);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_context_pins_ids_and_times() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let context = RequestContext::deterministic(at);
+        let store = MemoryMessageStore::new().with_clock(context.clock.clone());
+
+        for expected in ["msg-00000001", "msg-00000002"] {
+            let request = send_request("general", "alice", "Hello!");
+            let posted =
+                post_message_handler(&store, &context, request, None).await.unwrap().message;
+            assert_eq!(posted.core.id.as_str(), expected);
+            assert_eq!(posted.core.created_at, at);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_seq_orders_messages_from_the_same_millisecond() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        // Ids that sort in arrival order, so only the counter can put the page back in order
+        let context = RequestContext::deterministic(at);
+        let store = MemoryMessageStore::new().with_clock(context.clock.clone());
+
+        // A queued burst arriving out of order, all within one millisecond
+        for client_seq in [3, 1, 4, 2] {
+            let request = SendMessageRequest {
+                message_text: format!("queued {}", client_seq),
+                client_seq: Some(client_seq),
+                ..ephemeral_request(30)
+            };
+            store_message(&store, &context, request, PostOptions::default(), None).await.unwrap();
+        }
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let seqs: Vec<_> = page.messages.iter().map(|message| message.client_seq).collect();
+        assert_eq!(seqs, vec![Some(1), Some(2), Some(3), Some(4)]);
+        assert!(page.messages.iter().all(|message| message.core.created_at == at));
+    }
+
+    #[tokio::test]
+    async fn test_poll_picks_up_a_message_from_the_cursors_millisecond() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let context = RequestContext::deterministic(at);
+        let store = MemoryMessageStore::new().with_clock(context.clock.clone());
+        let post = |text: &str| {
+            let request = send_request("general", "alice", text);
+            let (store, context) = (&store, &context);
+            async move { post_message_handler(store, context, request, None).await.unwrap() }
+        };
+
+        post("first").await;
+        let polled = poll_messages_handler(&store, "general".into(), "0", None).await.unwrap();
+        assert_eq!(polled.messages.len(), 1);
+
+        // Stored after that poll answered, in the same millisecond as the message it ended on
+        post("second").await;
+        let polled =
+            poll_messages_handler(&store, "general".into(), &polled.cursor, None).await.unwrap();
+        let texts: Vec<_> = polled.messages.iter().map(|m| m.core.message_text.as_str()).collect();
+        assert_eq!(texts, vec!["second"]);
+
+        let polled =
+            poll_messages_handler(&store, "general".into(), &polled.cursor, None).await.unwrap();
+        assert!(polled.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_whispers_are_hidden_from_other_room_members() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        const BOB: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB2";
+        const CAROL: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB3";
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+
+        for (text, to_user_id) in [("Hello all", None), ("Psst, Bob", Some(BOB.to_string()))] {
+            clock.advance(chrono::Duration::milliseconds(1));
+            let request = SendMessageRequest {
+                message_text: text.to_string(),
+                expires_in_secs: None,
+                to_user_id,
+                ..ephemeral_request(1)
+            };
+            store_message(&store, &context, request, PostOptions::default(), None).await.unwrap();
+        }
+
+        let read = |user_id: Option<&str>| {
+            let query = MessageQuery { user_id: user_id.map(str::to_string), ..Default::default() };
+            let store = &store;
+            async move {
+                let page = get_messages_handler(store, "general".into(), query).await.unwrap();
+                page.messages.into_iter().map(|m| m.core.message_text).collect::<Vec<_>>()
+            }
+        };
+        // The sender and recipient see the whisper; everyone else only the room message
+        assert_eq!(read(Some(ALICE)).await, vec!["Hello all", "Psst, Bob"]);
+        assert_eq!(read(Some(BOB)).await, vec!["Hello all", "Psst, Bob"]);
+        assert_eq!(read(Some(CAROL)).await, vec!["Hello all"]);
+        assert_eq!(read(None).await, vec!["Hello all"]);
+
+        let polled =
+            poll_messages_handler(&store, "general".into(), "0", Some(CAROL)).await.unwrap();
+        assert_eq!(polled.messages.len(), 1);
+        let polled = poll_messages_handler(&store, "general".into(), "0", Some(BOB)).await.unwrap();
+        assert_eq!(polled.messages[1].visibility, MessageVisibility::Direct);
+        assert_eq!(polled.messages[1].to_user_id.as_deref(), Some(BOB));
+    }
+
+    #[tokio::test]
+    async fn test_a_whisper_read_by_id_is_missing_to_other_viewers() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        const BOB: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB2";
+        const CAROL: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB3";
+        let store = MemoryMessageStore::new();
+        let request = SendMessageRequest {
+            message_text: "Psst, Bob".to_string(),
+            expires_in_secs: None,
+            to_user_id: Some(BOB.to_string()),
+            ..ephemeral_request(1)
+        };
+        let whisper = store_message(
+            &store,
+            &RequestContext::default(),
+            request,
+            PostOptions::default(),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+
+        let read = |viewer: Option<&'static str>| {
+            let (store, id) = (&store, whisper.core.id.clone());
+            async move { get_message_handler(store, "general".into(), id.into(), viewer).await.unwrap() }
+        };
+        assert!(read(Some(ALICE)).await.is_some());
+        assert!(read(Some(BOB)).await.is_some());
+        assert!(read(Some(CAROL)).await.is_none());
+        assert!(read(None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_only_the_poster_may_delete_a_message() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        const BOB: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB2";
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let request = SendMessageRequest { expires_in_secs: None, ..ephemeral_request(1) };
+        let message = store_message(&store, &context, request, PostOptions::default(), None)
+            .await
+            .unwrap()
+            .message;
+        let capture = Arc::new(Capture::default());
+        let metrics = MetricsHelper::with_sink(capture.clone());
+        let delete = |user_id: Option<&'static str>| {
+            delete_message_handler(
+                &store,
+                &metrics,
+                "general".into(),
+                message.core.id.as_str().into(),
+                user_id,
+            )
+        };
+
+        assert!(matches!(delete(None).await, Err(HandlerError::Validation(_))));
+        assert!(matches!(delete(Some(BOB)).await, Err(HandlerError::Forbidden(_))));
+        assert_eq!(store.count_messages("general").await.unwrap(), 1);
+
+        let deleted = delete(Some(ALICE)).await.unwrap().unwrap();
+        assert_eq!(deleted.core.id, message.core.id);
+        assert_eq!(store.count_messages("general").await.unwrap(), 0);
+        // Counted through the caller's helper
+        let emitted: Vec<_> =
+            capture.0.lock().unwrap().iter().map(|(name, ..)| name.clone()).collect();
+        assert_eq!(emitted, vec!["MessagesDeleted"]);
+        // Gone for everyone now, the poster included
+        assert!(delete(Some(ALICE)).await.unwrap().is_none());
+        assert!(delete(Some(BOB)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_only_the_poster_may_edit_a_message() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        const BOB: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB2";
+        let sent_at = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(MockClock::new(sent_at));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        let request = SendMessageRequest { expires_in_secs: None, ..ephemeral_request(1) };
+        let message = store_message(&store, &context, request, PostOptions::default(), None)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(message.edited_at, None);
+        let edit = |user_id: &str, message_text: &str| {
+            let request = EditMessageRequest {
+                user_id: user_id.to_string(),
+                message_text: message_text.to_string(),
+            };
+            let message_id = message.core.id.as_str().into();
+            edit_message_handler(&store, &context, "general".into(), message_id, request, false)
+        };
+
+        assert!(matches!(edit(BOB, "Not mine").await, Err(HandlerError::Forbidden(_))));
+        assert!(matches!(edit(ALICE, "   ").await, Err(HandlerError::Validation(_))));
+
+        clock.advance(chrono::Duration::seconds(5));
+        let edited = edit(ALICE, " Gone later ").await.unwrap().unwrap();
+        assert_eq!(edited.core.message_text, "Gone later");
+        assert_eq!(edited.edited_at, Some(sent_at + chrono::Duration::seconds(5)));
+        // Still where it was sent, not moved to the end
+        assert_eq!(edited.core.created_at, sent_at);
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].core.message_text, "Gone later");
+        assert_eq!(page.messages[0].edited_at, edited.edited_at);
+
+        let message_id = message.core.id.as_str().into();
+        let metrics = MetricsHelper::with_sink(Arc::new(Capture::default()));
+        delete_message_handler(&store, &metrics, "general".into(), message_id, Some(ALICE))
+            .await
+            .unwrap();
+        assert!(edit(ALICE, "Too late").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_edits_are_transformed_like_posts() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let pipeline = TextPipeline::from_spec("trim,collapse_ws,emoji");
+        let request = SendMessageRequest { expires_in_secs: None, ..ephemeral_request(1) };
+        let message = store_message(&store, &context, request, PostOptions::default(), None)
+            .await
+            .unwrap()
+            .message;
+
+        let text = "  Party   time :tada:  ";
+        let request =
+            EditMessageRequest { user_id: ALICE.to_string(), message_text: text.to_string() };
+        let message_id = message.core.id.as_str().into();
+        let edited =
+            edit_message(&store, &context, "general".into(), message_id, request, false, &pipeline)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(edited.core.message_text, "Party time 🎉");
+        assert_eq!(
+            edited.core.message_text,
+            message_text(text, &MessageFormat::Plain, &pipeline).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_code_is_kept_exactly_as_sent() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let code = "    fn main() {\n        let  party = \":tada:\";\n    }\n";
+        let request = SendMessageRequest {
+            message_text: code.to_string(),
+            format: MessageFormat::Code { lang: Some("rust".to_string()) },
+            ..send_request("general", "alice", "")
+        };
+        let posted = store_message(&store, &context, request, PostOptions::default(), None)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(posted.core.message_text, code);
+
+        // Edits of code skip the transforms too
+        let pipeline = TextPipeline::from_spec("trim,collapse_ws,emoji");
+        let edit = format!("{}// done\n", code);
+        let request = EditMessageRequest { user_id: ALICE.to_string(), message_text: edit.clone() };
+        let message_id = posted.core.id.as_str().into();
+        let edited =
+            edit_message(&store, &context, "general".into(), message_id, request, false, &pipeline)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(edited.core.message_text, edit);
+
+        // Blank code is still refused
+        let request = SendMessageRequest {
+            message_text: "  \n  ".to_string(),
+            format: MessageFormat::Code { lang: None },
+            ..send_request("general", "alice", "")
+        };
+        let err = store_message(&store, &context, request, PostOptions::default(), None).await;
+        assert!(matches!(err, Err(HandlerError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_edits_are_limited_to_the_edit_window() {
+        const ALICE: &str = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        let request = SendMessageRequest { expires_in_secs: None, ..ephemeral_request(1) };
+        let message = store_message(&store, &context, request, PostOptions::default(), None)
+            .await
+            .unwrap()
+            .message;
+        let edit = |message_text: &str, is_admin: bool| {
+            let request = EditMessageRequest {
+                user_id: ALICE.to_string(),
+                message_text: message_text.to_string(),
+            };
+            let message_id = message.core.id.as_str().into();
+            edit_message_handler(&store, &context, "general".into(), message_id, request, is_admin)
+        };
+
+        clock.advance(chrono::Duration::seconds(DEFAULT_EDIT_WINDOW_SECS));
+        assert_eq!(
+            edit("Just in time", false).await.unwrap().unwrap().core.message_text,
+            "Just in time"
+        );
+
+        clock.advance(chrono::Duration::seconds(1));
+        let Err(HandlerError::Forbidden(reason)) = edit("Too late", false).await else {
+            panic!("expected the edit to be refused");
+        };
+        assert_eq!(reason, EDIT_WINDOW_EXPIRED);
+
+        // Admins aren't held to the window
+        let edited = edit("Fixed by an admin", true).await.unwrap().unwrap();
+        assert_eq!(edited.core.message_text, "Fixed by an admin");
+    }
+
+    #[tokio::test]
+    async fn test_identical_rapid_posts_are_deduplicated() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        let options = PostOptions {
+            content_dedup: Some(chrono::Duration::seconds(3)),
+            ..PostOptions::default()
+        };
+        let post = |client_message_id: &str| SendMessageRequest {
+            message_text: "Double-clicked".to_string(),
+            client_message_id: Some(client_message_id.to_string()),
+            expires_in_secs: None,
+            ..ephemeral_request(1)
+        };
+
+        let first =
+            store_message(&store, &context, post("c1"), options, None).await.unwrap().message;
+        clock.advance(chrono::Duration::milliseconds(200));
+        let second =
+            store_message(&store, &context, post("c2"), options, None).await.unwrap().message;
+        assert_eq!(second.core.id, first.core.id);
+
+        // Different text isn't a duplicate
+        let other = SendMessageRequest { message_text: "Something else".to_string(), ..post("c3") };
+        let other = store_message(&store, &context, other, options, None).await.unwrap().message;
+        assert_ne!(other.core.id, first.core.id);
+
+        // Once the window has passed, the same text is a new message
+        clock.advance(chrono::Duration::seconds(4));
+        let later =
+            store_message(&store, &context, post("c4"), options, None).await.unwrap().message;
+        assert_ne!(later.core.id, first.core.id);
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert_eq!(page.messages.len(), 3);
+
+        // Found however busy the room was since, not only among the window's oldest messages
+        clock.advance(chrono::Duration::seconds(4));
+        let busy = store::MESSAGE_PAGE_SIZE * 5;
+        for n in 0..busy {
+            let chatter = SendMessageRequest {
+                message_text: format!("Chatter {}", n),
+                ..post(&format!("chatter-{}", n))
+            };
+            store_message(&store, &context, chatter, options, None).await.unwrap();
+        }
+        let first =
+            store_message(&store, &context, post("c5"), options, None).await.unwrap().message;
+        assert_ne!(first.core.id, later.core.id);
+        clock.advance(chrono::Duration::milliseconds(200));
+        let second =
+            store_message(&store, &context, post("c6"), options, None).await.unwrap().message;
+        assert_eq!(second.core.id, first.core.id);
+    }
+
+    #[test]
+    fn test_whisper_recipient_must_be_a_valid_user_id() {
+        let request = SendMessageRequest {
+            to_user_id: Some("bob smith".to_string()),
+            ..ephemeral_request(1)
+        };
+        let errors = request.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "to_user_id");
+    }
+
+    #[test]
+    fn test_client_seq_must_fit_a_javascript_number() {
+        assert_eq!(validate_client_seq(Some(MAX_CLIENT_SEQ)), Ok(Some(MAX_CLIENT_SEQ)));
+        assert!(validate_client_seq(Some(MAX_CLIENT_SEQ + 1)).is_err());
+        assert_eq!(validate_client_seq(None), Ok(None));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_stored_ts_and_ttl_come_from_the_store_clock() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "clock-test-rooms".to_string(),
+            messages: "clock-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let context = RequestContext::default().with_clock(Arc::new(MockClock::new(at)));
+        let store =
+            DynamoMessageStore::new(ddb.clone(), tables.clone()).with_clock(context.clock.clone());
+
+        store_message(&store, &context, ephemeral_request(30), PostOptions::default(), None)
+            .await
+            .unwrap();
+
+        let item = ddb
+            .scan()
+            .table_name(&tables.messages)
+            .send()
+            .await
+            .unwrap()
+            .items
+            .unwrap_or_default()
+            .pop()
+            .expect("message should be stored");
+        assert_eq!(item["ts"], AttributeValue::N("1700000000123".to_string()));
+        assert_eq!(item["ttl"], AttributeValue::N("1700000030".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_second_post_to_a_room_skips_the_existence_check() {
+        let store = MemoryMessageStore::new().with_known_rooms(Arc::default());
+        let options = PostOptions::default();
+
+        store_message(&store, &RequestContext::default(), ephemeral_request(30), options, None)
+            .await
+            .unwrap();
+        assert_eq!(store.room_checks(), 1);
+        store_message(&store, &RequestContext::default(), ephemeral_request(30), options, None)
+            .await
+            .unwrap();
+        assert_eq!(store.room_checks(), 1);
+
+        // Another room is checked once too
+        let random = SendMessageRequest { room_id: "random".to_string(), ..ephemeral_request(30) };
+        store_message(&store, &RequestContext::default(), random.clone(), options, None)
+            .await
+            .unwrap();
+        store_message(&store, &RequestContext::default(), random, options, None).await.unwrap();
+        assert_eq!(store.room_checks(), 2);
+
+        // Without the cache every post checks
+        let uncached = MemoryMessageStore::new();
+        for _ in 0..2 {
+            store_message(
+                &uncached,
+                &RequestContext::default(),
+                ephemeral_request(30),
+                options,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(uncached.room_checks(), 2);
+    }
+
+    fn request_composed_at(text: &str, client_created_at: DateTime<Utc>) -> SendMessageRequest {
+        SendMessageRequest {
+            message_text: text.to_string(),
+            expires_in_secs: None,
+            client_created_at: Some(client_created_at),
+            client_seq: None,
+            ..ephemeral_request(1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_client_skew_is_flagged_but_server_time_orders() {
+        let at = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(MockClock::new(at));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        let options = PostOptions::default();
+
+        // Queued offline ten minutes ago and only sent now
+        let queued = request_composed_at("written offline", at - chrono::Duration::minutes(10));
+        let queued = store_message(&store, &context, queued, options, None).await.unwrap().message;
+        assert_eq!(queued.core.created_at, at);
+        assert_eq!(queued.clock_skew_ms, Some(-600_000));
+
+        // A second behind is within the threshold, so the clocks are taken to agree
+        clock.advance(chrono::Duration::seconds(1));
+        let prompt = request_composed_at("sent straight away", at);
+        let prompt = store_message(&store, &context, prompt, options, None).await.unwrap().message;
+        assert_eq!(prompt.client_created_at, Some(at));
+        assert_eq!(prompt.clock_skew_ms, None);
+
+        // A client clock running an hour fast still can't put its message ahead of later ones
+        clock.advance(chrono::Duration::seconds(1));
+        let fast = request_composed_at("from the future", at + chrono::Duration::hours(1));
+        let fast = store_message(&store, &context, fast, options, None).await.unwrap().message;
+        assert_eq!(fast.clock_skew_ms, Some(3_600_000 - 2_000));
+        clock.advance(chrono::Duration::seconds(1));
+        let last = ephemeral_request(1);
+        let last =
+            SendMessageRequest { message_text: "last".into(), expires_in_secs: None, ..last };
+        store_message(&store, &context, last, options, None).await.unwrap();
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let texts: Vec<_> = page.messages.iter().map(|m| m.core.message_text.as_str()).collect();
+        assert_eq!(texts, ["written offline", "sent straight away", "from the future", "last"]);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_client_timestamp_and_skew_are_stored() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "skew-test-rooms".to_string(),
+            messages: "skew-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let at = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let context = RequestContext::default().with_clock(Arc::new(MockClock::new(at)));
+        let store =
+            DynamoMessageStore::new(ddb.clone(), tables.clone()).with_clock(context.clock.clone());
+
+        let client_created_at = at - chrono::Duration::minutes(10);
+        let request = request_composed_at("written offline", client_created_at);
+        store_message(&store, &context, request, PostOptions::default(), None).await.unwrap();
+
+        let page =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let message = &page.messages[0];
+        assert_eq!(message.core.created_at, at);
+        assert_eq!(message.client_created_at, Some(client_created_at));
+        assert_eq!(message.clock_skew_ms, Some(-600_000));
+    }
+
+    struct FailingIndex;
+
+    #[async_trait::async_trait]
+    impl SearchIndex for FailingIndex {
+        async fn index(&self, _message: &ChatMessage) -> Result<(), String> {
+            Err("index unavailable".to_string())
+        }
+
+        async fn remove(&self, _room_id: &str, _message_id: &str) -> Result<(), String> {
+            Err("index unavailable".to_string())
+        }
+
+        async fn search(&self, _: &str, _: &str, _: usize) -> Result<Vec<ChatMessage>, String> {
+            Err("index unavailable".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_uses_the_index_and_falls_back_to_the_store() {
+        let store = MemoryMessageStore::new();
+        let index = MemorySearchIndex::default();
+        for text in ["Deploy went out", "lunch?", "deploy rolled back"] {
+            let request = SendMessageRequest {
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: text.to_string(),
+                client_message_id: None,
+                expires_in_secs: None,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                client_seq: None,
+                to_user_id: None,
+            };
+            let posted = store_message(
+                &store,
+                &RequestContext::default(),
+                request,
+                PostOptions::default(),
+                None,
+            )
+            .await
+            .unwrap()
+            .message;
+            index.index(&posted).await.unwrap();
+        }
+        let texts = |response: SearchMessagesResponse| -> Vec<String> {
+            response.messages.into_iter().map(|message| message.core.message_text).collect()
+        };
+
+        // The index matches words whatever their case
+        let indexed =
+            search_messages_handler(&store, Some(&index), "General".into(), " deploy ".into())
+                .await
+                .unwrap();
+        assert_eq!(indexed.room_id, "general");
+        assert_eq!(indexed.query, "deploy");
+        assert_eq!(texts(indexed), ["deploy rolled back", "Deploy went out"]);
+
+        // The DynamoDB filter is a plain substring match, so only the lowercase one matches
+        let filtered =
+            search_messages_handler(&store, None, "general".into(), "deploy".into()).await.unwrap();
+        assert_eq!(texts(filtered), ["deploy rolled back"]);
+        let failed =
+            search_messages_handler(&store, Some(&FailingIndex), "general".into(), "lunch".into())
+                .await
+                .unwrap();
+        assert_eq!(texts(failed), ["lunch?"]);
+
+        let Err(HandlerError::Validation(errors)) =
+            search_messages_handler(&store, Some(&index), " ".into(), "  ".into()).await
+        else {
+            panic!("blank room and query should be rejected");
+        };
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, ["room_id", "q"]);
+    }
+
+    #[test]
+    fn test_message_query_bounded_window() {
+        let query = MessageQuery {
+            created_after: Some(1_000),
+            created_before: Some(2_000),
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+
+        let (condition, bounds) = query.key_condition();
+        assert_eq!(condition, "room_id = :room_id AND sk BETWEEN :lo AND :hi");
+        assert_eq!(
+            bounds,
+            vec![(":lo", "0000000001000".to_string()), (":hi", "0000000002000$".to_string())]
+        );
+
+        // Every message in the boundary milliseconds is inside the window
+        assert!(query.contains("0000000001000#01ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        assert!(query.contains("0000000002000#zzzzzzzz"));
+        assert!(!query.contains("0000000000999#zzzzzzzz"));
+        assert!(!query.contains("0000000002001#00000000"));
+    }
+
+    #[test]
+    fn test_message_query_open_ended() {
+        let after = MessageQuery { created_after: Some(1_000), ..Default::default() };
+        assert_eq!(
+            after.key_condition(),
+            ("room_id = :room_id AND sk >= :lo", vec![(":lo", "0000000001000".to_string())])
+        );
+
+        let before = MessageQuery { created_before: Some(2_000), ..Default::default() };
+        assert_eq!(
+            before.key_condition(),
+            ("room_id = :room_id AND sk <= :hi", vec![(":hi", "0000000002000$".to_string())])
+        );
+
+        // The cursor itself is excluded
+        let cursor = "0000000001000#b".to_string();
+        let after = MessageQuery { after: Some(cursor.clone()), ..Default::default() };
+        assert!(!after.contains(&cursor));
+        assert!(after.contains("0000000001000#c"));
+        assert!(!after.contains("0000000001000#a"));
+
+        assert_eq!(MessageQuery::default().key_condition(), ("room_id = :room_id", Vec::new()));
+    }
+
+    #[test]
+    fn test_message_query_rejects_inverted_range() {
+        let query = MessageQuery {
+            created_after: Some(2_000),
+            created_before: Some(1_000),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = MessageQuery {
+            created_after: Some(1_000),
+            after: Some("0000000001000#a".to_string()),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+    }
+
+    fn send_request(room_id: &str, username: &str, message_text: &str) -> SendMessageRequest {
+        SendMessageRequest {
+            room_id: room_id.to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: username.to_string(),
+            message_text: message_text.to_string(),
+            client_message_id: None,
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_message_against_memory_store() {
+        let store = MemoryMessageStore::new();
+        let request = SendMessageRequest {
+            client_message_id: Some("client-1".to_string()),
+            ..send_request("General", " alice ", " Hello! ")
+        };
+
+        let posted = post_message_handler(&store, &RequestContext::default(), request, None)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(posted.core.room_id, "general");
+        assert_eq!(posted.core.username, "alice");
+        assert_eq!(posted.core.message_text, "Hello!");
+        assert_eq!(posted.client_message_id.as_deref(), Some("client-1"));
+        assert_eq!(posted.status, MessageStatus::Stored);
+        assert!(store.has_room("general"));
+
+        let listed =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let ids: Vec<_> = listed.messages.iter().map(|message| message.core.id.as_str()).collect();
+        assert_eq!(ids, vec![posted.core.id.as_str()]);
+
+        let found = find_message_by_id(&store, &posted.core.id).await.unwrap().unwrap();
+        assert_eq!(found.core.message_text, "Hello!");
+    }
+
+    #[tokio::test]
+    async fn test_posts_cannot_create_rooms_with_reserved_ids() {
+        let store = MemoryMessageStore::new();
+
+        for room_id in ["admin", " Health ", "ws"] {
+            let err = post_message_handler(
+                &store,
+                &RequestContext::default(),
+                send_request(room_id, "alice", "hi"),
+                None,
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(&err, HandlerError::BadRequest(code) if code == RESERVED_ROOM_ID),
+                "{}: {:?}",
+                room_id,
+                err
+            );
+        }
+        assert!(!store.has_room("admin"));
+        post_message_handler(
+            &store,
+            &RequestContext::default(),
+            send_request("admins", "alice", "hi"),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // A room that already has a reserved id stays readable
+        store.ensure_room("metrics").await.unwrap();
+        let listed =
+            get_messages_handler(&store, "metrics".into(), MessageQuery::default()).await.unwrap();
+        assert!(listed.messages.is_empty());
+
+        let configured = ReservedRoomIds::new(&["Lobby", " "]);
+        assert!(configured.contains("lobby"));
+        assert!(!configured.contains("admin"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_post_stores_nothing() {
+        let store = MemoryMessageStore::new();
+
+        let err = post_message_handler(
+            &store,
+            &RequestContext::default(),
+            send_request("general", "", "   "),
+            None,
+        )
+        .await
+        .unwrap_err();
+        let HandlerError::Validation(errors) = err else {
+            panic!("expected a validation error, got {}", err);
+        };
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["username", "message_text"]);
+
+        assert!(!store.has_room("general"));
+        let listed =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        assert!(listed.messages.is_empty());
+    }
+
+    // Post with and without origin capture, then check only the admin view carries it
+    async fn assert_origin_is_admin_only(store: &dyn MessageStore) {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        for enabled in [true, false] {
+            let origin = OriginCapture::new(enabled, "salt".to_string(), None).capture(Some(ip));
+            let request = send_request("general", "alice", "Hello!");
+            let posted = store_message(
+                store,
+                &RequestContext::default(),
+                request,
+                PostOptions::default(),
+                origin.as_ref(),
+            )
+            .await
+            .unwrap()
+            .message;
+
+            let admin = admin_message_handler(store, &posted.core.id).await.unwrap().unwrap();
+            assert_eq!(admin.origin_hash.is_some(), enabled);
+            assert_eq!(admin.origin_hash, origin.map(|origin| origin.hash));
+
+            // Normal reads carry neither the IP nor its hash
+            let read =
+                get_message_handler(store, "general".into(), posted.core.id.clone().into(), None)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let listed = get_messages_handler(store, "general".into(), MessageQuery::default())
+                .await
+                .unwrap();
+            for body in [serde_json::to_string(&read), serde_json::to_string(&listed)] {
+                let body = body.unwrap();
+                assert!(!body.contains("origin") && !body.contains("203.0.113"), "{}", body);
+            }
+        }
+    }
+
+    // Page through a room whose messages all share one millisecond, oldest first and then
+    // newest first
+    async fn assert_same_millisecond_pages_are_exact(store: &dyn MessageStore) {
+        store.ensure_room("burst").await.unwrap();
+        let created_at = Utc::now();
+        let mut ids = Vec::new();
+        for n in 0..60 {
+            let message = ChatMessage {
+                core: MessageCore {
+                    id: ulid::Ulid::new().to_string(),
+                    room_id: "burst".to_string(),
+                    user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                    username: "alice".to_string(),
+                    message_text: format!("message {}", n),
+                    created_at,
+                },
+                client_message_id: None,
+                ephemeral: false,
+                expires_at: None,
+                status: MessageStatus::Stored,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                clock_skew_ms: None,
+                delivered_count: None,
+                client_seq: None,
+                visibility: MessageVisibility::Room,
+                to_user_id: None,
+                link_preview: None,
+                avatar_seed: None,
+                edited_at: None,
+            };
+            store.put_message(&message, None).await.unwrap();
+            ids.push(message.core.id);
+        }
+        ids.sort();
+
+        let mut seen = Vec::new();
+        let mut query = MessageQuery::default();
+        loop {
+            let page = get_messages_handler(store, "burst".into(), query).await.unwrap();
+            seen.extend(page.messages.into_iter().map(|message| message.core.id));
+            match page.next_cursor {
+                Some(cursor) => query = MessageQuery { after: Some(cursor), ..Default::default() },
+                None => break,
+            }
+        }
+        assert_eq!(seen, ids);
+
+        let mut seen = Vec::new();
+        let newest_first = MessageQuery { order: SortOrder::Desc, ..Default::default() };
+        let mut query = newest_first.clone();
+        loop {
+            let page = get_messages_handler(store, "burst".into(), query).await.unwrap();
+            seen.extend(page.messages.into_iter().map(|message| message.core.id));
+            match page.next_cursor {
+                Some(cursor) => {
+                    query = MessageQuery { after: Some(cursor), ..newest_first.clone() }
+                }
+                None => break,
+            }
+        }
+        ids.reverse();
+        assert_eq!(seen, ids);
+    }
+
+    #[tokio::test]
+    async fn test_same_millisecond_messages_paginate_exactly() {
+        assert_same_millisecond_pages_are_exact(&MemoryMessageStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_paging_back_from_before_reaches_the_first_message() {
+        let store = MemoryMessageStore::new();
+        store.ensure_room("history").await.unwrap();
+        // Two messages a millisecond, so page boundaries fall inside one
+        let mut ids = Vec::new();
+        for n in 0..11 {
+            let message = ChatMessage {
+                core: MessageCore {
+                    id: format!("m{:02}", n),
+                    room_id: "history".to_string(),
+                    user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                    username: "alice".to_string(),
+                    message_text: format!("message {}", n),
+                    created_at: DateTime::from_timestamp_millis(1_000 + n / 2).unwrap(),
+                },
+                client_message_id: None,
+                ephemeral: false,
+                expires_at: None,
+                status: MessageStatus::Stored,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                clock_skew_ms: None,
+                delivered_count: None,
+                client_seq: None,
+                visibility: MessageVisibility::Room,
+                to_user_id: None,
+                link_preview: None,
+                avatar_seed: None,
+                edited_at: None,
+            };
+            store.put_message(&message, None).await.unwrap();
+            ids.push(message.core.id);
+        }
+
+        let mut pages = Vec::new();
+        let mut before = Some(i64::MAX);
+        while let Some(cursor) = before {
+            let query = MessageQuery { before: Some(cursor), limit: Some(4), ..Default::default() };
+            let page = get_messages_handler(&store, "history".into(), query).await.unwrap();
+            let page_ids: Vec<_> = page.messages.iter().map(|m| m.core.id.clone()).collect();
+            before = page.before_cursor;
+            pages.push(page_ids);
+        }
+        // Each page oldest first, the oldest millisecond of a full one held over to the next
+        assert_eq!(pages[0], ["m08", "m09", "m10"]);
+        assert_eq!(pages[1], ["m06", "m07"]);
+        let seen: Vec<String> = pages.into_iter().rev().flatten().collect();
+        assert_eq!(seen, ids);
+
+        let too_many =
+            MessageQuery { limit: Some(store::MAX_MESSAGE_PAGE_SIZE + 1), ..Default::default() };
+        assert!(too_many.validate().is_err());
+        let both =
+            MessageQuery { before: Some(1_000), after: Some("x".into()), ..Default::default() };
+        assert!(both.validate().is_err());
+
+        // Newest first, the same pages come back the other way round
+        let query = MessageQuery { limit: Some(4), order: SortOrder::Desc, ..Default::default() };
+        let page = get_messages_handler(&store, "history".into(), query).await.unwrap();
+        let page_ids: Vec<_> = page.messages.iter().map(|m| m.core.id.as_str()).collect();
+        assert_eq!(page_ids, ["m10", "m09", "m08", "m07"]);
+
+        // A cursor continues from the end the order starts at
+        let desc = |created_before| MessageQuery {
+            after: Some("x".into()),
+            created_before,
+            order: SortOrder::Desc,
+            ..Default::default()
+        };
+        assert!(desc(None).validate().is_ok());
+        assert!(desc(Some(1_000)).validate().is_err());
+        assert_eq!(SortOrder::parse("desc"), Ok(SortOrder::Desc));
+        assert!(SortOrder::parse("newest").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scrolling_past_the_max_page_depth_is_refused() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        let context = RequestContext::default().with_clock(clock.clone());
+        for n in 0..store::MESSAGE_PAGE_SIZE * 4 {
+            clock.advance(chrono::Duration::milliseconds(1));
+            let request = SendMessageRequest {
+                message_text: format!("message {}", n),
+                expires_in_secs: None,
+                ..ephemeral_request(1)
+            };
+            store_message(&store, &context, request, PostOptions::default(), None).await.unwrap();
+        }
+        let cursors = PageCursors::new("secret".to_string(), Some(3));
+
+        // The first three pages scroll back as usual
+        let mut query = MessageQuery::default();
+        for _ in 0..3 {
+            let page = list_messages(&store, "general".into(), query, &cursors).await.unwrap();
+            assert_eq!(page.messages.len(), store::MESSAGE_PAGE_SIZE);
+            query = MessageQuery { after: page.next_cursor, ..Default::default() };
+        }
+
+        // The fourth is one too deep
+        let refused = list_messages(&store, "general".into(), query, &cursors).await.unwrap_err();
+        assert!(
+            matches!(&refused, HandlerError::BadRequest(code) if code == PAGINATION_DEPTH_EXCEEDED),
+            "{:?}",
+            refused
+        );
+
+        // Nor can a bare sort key stand in for a cursor to get around it
+        let bare =
+            MessageQuery { after: Some("1700000000075#x".to_string()), ..Default::default() };
+        let refused = list_messages(&store, "general".into(), bare, &cursors).await.unwrap_err();
+        assert!(matches!(refused, HandlerError::Validation(_)), "{:?}", refused);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_same_millisecond_messages_paginate_exactly_in_dynamo() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "paging-test-rooms".to_string(),
+            messages: "paging-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        assert_same_millisecond_pages_are_exact(&DynamoMessageStore::new(ddb, tables)).await;
+    }
+
+    #[tokio::test]
+    async fn test_origin_is_only_visible_to_admins() {
+        assert_origin_is_admin_only(&MemoryMessageStore::new()).await;
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_origin_round_trips_through_dynamo() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "origin-test-rooms".to_string(),
+            messages: "origin-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        assert_origin_is_admin_only(&DynamoMessageStore::new(ddb, tables)).await;
+    }
+
+    // Post formatted messages and read them back, listed and by id
+    async fn assert_format_round_trips(store: &dyn MessageStore) {
+        let formats = [
+            (MessageFormat::Code { lang: Some("Rust".to_string()) }, "code"),
+            (MessageFormat::Quote, "quote"),
+        ];
+        for (format, room_id) in formats {
+            let request =
+                SendMessageRequest { format, ..send_request(room_id, "alice", "fn main() {}") };
+            let posted = post_message_handler(store, &RequestContext::default(), request, None)
+                .await
+                .unwrap()
+                .message;
+            let read =
+                get_message_handler(store, room_id.into(), posted.core.id.clone().into(), None)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let listed =
+                get_messages_handler(store, room_id.into(), MessageQuery::default()).await.unwrap();
+            assert_eq!(read.format, posted.format);
+            assert_eq!(listed.messages[0].format, posted.format);
+        }
+
+        let code =
+            get_messages_handler(store, "code".into(), MessageQuery::default()).await.unwrap();
+        // The language is stored as normalized by validation
+        assert_eq!(code.messages[0].format, MessageFormat::Code { lang: Some("rust".to_string()) });
+    }
+
+    #[tokio::test]
+    async fn test_format_round_trips_through_memory_store() {
+        assert_format_round_trips(&MemoryMessageStore::new()).await;
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_format_round_trips_through_dynamo() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "format-test-rooms".to_string(),
+            messages: "format-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        assert_format_round_trips(&DynamoMessageStore::new(ddb, tables)).await;
+    }
+
+    #[tokio::test]
+    async fn test_post_without_user_id_follows_anonymous_policy() {
+        let store = MemoryMessageStore::new();
+        let mut request = send_request("general", "alice", "Hello!");
+        request.user_id = String::new();
+
+        let err = store_message(
+            &store,
+            &RequestContext::default(),
+            request.clone(),
+            PostOptions::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, HandlerError::Unauthorized(_)), "{}", err);
+
+        let options =
+            PostOptions { anonymous: AnonymousPolicy { allow: true }, ..Default::default() };
+        let posted = store_message(&store, &RequestContext::default(), request, options, None)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(posted.core.user_id, crate::identity::anonymous_id("alice"));
+    }
+
+    // Three messages from two people, the newest by alice
+    async fn seed_room_stats(store: &dyn MessageStore) -> ChatMessage {
+        let mut newest = None;
+        for username in ["alice", "bob", "alice"] {
+            let request = send_request("general", username, "Hello!");
+            newest = Some(
+                store_message(
+                    store,
+                    &RequestContext::default(),
+                    request,
+                    PostOptions::default(),
+                    None,
+                )
+                .await
+                .unwrap()
+                .message,
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        newest.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_latest_messages_across_rooms() {
+        let store = MemoryMessageStore::new();
+        let mut newest = HashMap::new();
+        for room_id in ["general", "random", "help"] {
+            for text in ["first", "second"] {
+                let request = send_request(room_id, "alice", &format!("{} in {}", text, room_id));
+                let posted = store_message(
+                    &store,
+                    &RequestContext::default(),
+                    request,
+                    PostOptions::default(),
+                    None,
+                )
+                .await;
+                newest.insert(room_id.to_string(), posted.unwrap().message.core.id);
+                tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            }
+        }
+
+        let request = LatestMessagesRequest {
+            room_ids: vec!["general".to_string(), "Random".to_string(), "help".to_string()],
+            per_room: 1,
+        };
+        let latest = latest_messages_handler(&store, request).await.unwrap();
+        assert!(latest.errors.is_empty());
+        let ids: HashMap<_, _> = latest
+            .rooms
+            .into_iter()
+            .map(|(room_id, messages)| {
+                let ids: Vec<_> = messages.into_iter().map(|message| message.core.id).collect();
+                (room_id, ids)
+            })
+            .collect();
+        assert_eq!(ids.len(), 3);
+        for (room_id, id) in &newest {
+            assert_eq!(ids[room_id], vec![id.clone()], "{}", room_id);
+        }
+
+        let too_many = LatestMessagesRequest {
+            room_ids: (0..=MAX_LATEST_ROOMS).map(|n| format!("room-{}", n)).collect(),
+            per_room: 0,
+        };
+        let err = latest_messages_handler(&store, too_many).await.unwrap_err();
+        let HandlerError::Validation(errors) = err else {
+            panic!("expected a validation error, got {}", err);
+        };
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["room_ids", "per_room"]);
+    }
+
+    #[tokio::test]
+    async fn test_latest_messages_report_a_failing_room_alongside_the_rest() {
+        let store = MemoryMessageStore::new();
+        for room_id in ["general", "random", "help"] {
+            let request = send_request(room_id, "alice", &format!("Hello, {}!", room_id));
+            store_message(
+                &store,
+                &RequestContext::default(),
+                request,
+                PostOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        store.fail_reads_of("random");
+
+        let request = LatestMessagesRequest {
+            room_ids: vec!["general".to_string(), "random".to_string(), "help".to_string()],
+            per_room: 5,
+        };
+        let latest = latest_messages_handler(&store, request).await.unwrap();
+        let mut rooms: Vec<_> = latest.rooms.keys().cloned().collect();
+        rooms.sort();
+        assert_eq!(rooms, vec!["general", "help"]);
+        assert_eq!(latest.rooms["help"][0].core.message_text, "Hello, help!");
+        assert_eq!(latest.errors.len(), 1);
+        assert!(latest.errors["random"].contains("random"), "{:?}", latest.errors);
+
+        // With nothing to show, it's an error after all
+        let request = LatestMessagesRequest { room_ids: vec!["random".to_string()], per_room: 5 };
+        let err = latest_messages_handler(&store, request).await.unwrap_err();
+        assert!(matches!(err, HandlerError::Internal(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_room_stats_against_memory_store() {
+        let store = MemoryMessageStore::new();
+        let newest = seed_room_stats(&store).await;
+
+        let stats = room_stats_handler(&store, None, "General".into()).await.unwrap();
+        assert_eq!(stats.room_id, "general");
+        assert_eq!(stats.message_count, 3);
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!((stats.unique_participants, stats.sample_size), (2, 3));
+        assert_eq!(stats.last_activity, Some(newest.core.created_at));
+
+        let empty = room_stats_handler(&store, None, "quiet".into()).await.unwrap();
+        assert_eq!((empty.message_count, empty.last_activity), (0, None));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_room_stats_against_dynamo() {
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "stats-test-rooms".to_string(),
+            messages: "stats-test-messages".to_string(),
+        };
+        let connections = "stats-test-connections";
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        create_connections_table(&ddb, connections).await;
+        for (connection_id, room_id) in
+            [("conn-1", "general"), ("conn-2", "general"), ("conn-3", "other")]
+        {
+            ddb.put_item()
+                .table_name(connections)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S(room_id.to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+        let store = DynamoMessageStore::new(ddb.clone(), tables);
+        let newest = seed_room_stats(&store).await;
+
+        let stats =
+            room_stats_handler(&store, Some((&ddb, connections)), "general".into()).await.unwrap();
+        assert_eq!(stats.message_count, 3);
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!((stats.unique_participants, stats.sample_size), (2, 3));
+        assert_eq!(
+            stats.last_activity.map(|at| at.timestamp_millis()),
+            Some(newest.core.created_at.timestamp_millis())
+        );
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_presence_lists_each_live_user_once() {
+        let ddb = local_ddb().await;
+        let connections = "presence-test-connections";
+        create_connections_table(&ddb, connections).await;
+        let now = 1_700_000_000;
+        // (connection, room, user, username, connected_at, ttl)
+        let rows = [
+            ("conn-1", "general", Some("u-alice"), "alice", 1, Some(now + 60)),
+            // Alice again from another tab, renamed since; the newest connection names her
+            ("conn-2", "general", Some("u-alice"), "alice2", 2, Some(now + 60)),
+            ("conn-3", "general", Some("u-bob"), "bob", 3, None),
+            // Expired, but not yet deleted by TTL
+            ("conn-4", "general", Some("u-carol"), "carol", 4, Some(now - 1)),
+            ("conn-5", "general", None, "nobody", 5, Some(now + 60)),
+            ("conn-6", "other", Some("u-dave"), "dave", 6, Some(now + 60)),
+        ];
+        for (connection_id, room_id, user_id, username, connected_at, ttl) in rows {
+            let mut put = ddb
+                .put_item()
+                .table_name(connections)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S(room_id.to_string()))
+                .item("username", AttributeValue::S(username.to_string()))
+                .item("connected_at", AttributeValue::N(connected_at.to_string()));
+            if let Some(user_id) = user_id {
+                put = put.item("user_id", AttributeValue::S(user_id.to_string()));
+            }
+            if let Some(ttl) = ttl {
+                put = put.item("ttl", AttributeValue::N(ttl.to_string()));
+            }
+            put.send().await.unwrap();
+        }
+
+        let presence =
+            room_presence_handler(Some((&ddb, connections)), "general".into(), now).await.unwrap();
+        assert_eq!(presence.room_id, "general");
+        assert_eq!(presence.users, vec!["alice2".to_string(), "bob".to_string()]);
+
+        let empty = room_presence_handler(None, "general".into(), now).await.unwrap();
+        assert!(empty.users.is_empty());
+    }
+
+    fn flag(user_id: &str) -> FlagMessageRequest {
+        FlagMessageRequest { user_id: user_id.to_string(), reason: " spam ".to_string() }
+    }
+
+    // A flag from `user_id`, sent from a client of its own
+    async fn flag_message(
+        store: &dyn MessageStore,
+        context: &RequestContext,
+        room_id: &str,
+        message_id: &str,
+        user_id: &str,
+    ) -> Result<Option<FlagMessageResponse>, HandlerError> {
+        let origin = MessageOrigin { hash: format!("origin-of-{}", user_id), country: None };
+        let request = flag(user_id);
+        record_flag(store, context, room_id.into(), message_id.into(), request, Some(&origin)).await
+    }
+
+    #[tokio::test]
+    async fn test_flagging_counts_each_client_once() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let posted = post_message_handler(
+            &store,
+            &context,
+            send_request("general", "alice", "Buy now"),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+
+        let flagged =
+            flag_message(&store, &context, "general", &posted.core.id, "bob").await.unwrap();
+        assert_eq!(
+            flagged,
+            Some(FlagMessageResponse {
+                message_id: posted.core.id.clone(),
+                room_id: "general".to_string(),
+                flag_count: 1,
+                hidden: false
+            })
+        );
+        let flagged =
+            flag_message(&store, &context, "general", &posted.core.id, "carol").await.unwrap();
+        assert_eq!(flagged.unwrap().flag_count, 2);
+
+        // A second flag from the same client is refused and counts nothing, whoever it says
+        // it's from
+        let err =
+            flag_message(&store, &context, "general", &posted.core.id, "bob").await.unwrap_err();
+        assert!(matches!(err, HandlerError::Conflict(_)));
+        let bob = MessageOrigin { hash: "origin-of-bob".to_string(), country: None };
+        let id = || posted.core.id.as_str().into();
+        let err =
+            record_flag(&store, &context, "general".into(), id(), flag("mallory"), Some(&bob))
+                .await
+                .unwrap_err();
+        assert!(matches!(err, HandlerError::Conflict(_)));
+
+        let queue = flags_handler(&store).await.unwrap();
+        let flaggers: Vec<_> = queue.iter().map(|flag| flag.user_id.as_str()).collect();
+        assert_eq!(flaggers, vec!["bob", "carol"]);
+        assert_eq!(queue[0].reason, "spam");
+        assert_eq!(queue[0].room_id, "general");
+
+        assert!(flag_message(&store, &context, "general", "missing", "bob")
+            .await
+            .unwrap()
+            .is_none());
+        // Messages are found within the room they were posted to
+        let elsewhere = flag_message(&store, &context, "random", &posted.core.id, "bob").await;
+        assert!(elsewhere.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_crossing_the_flag_threshold_hides_the_message() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let posted = post_message_handler(
+            &store,
+            &context,
+            send_request("general", "alice", "Buy now"),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+
+        for user_id in ["bob", "carol"] {
+            flag_message(&store, &context, "general", &posted.core.id, user_id).await.unwrap();
+        }
+        let found =
+            get_message_handler(&store, "general".into(), posted.core.id.as_str().into(), None)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(found.status, MessageStatus::Stored);
+
+        let flagged = flag_message(&store, &context, "general", &posted.core.id, "dave")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(flagged.flag_count, DEFAULT_AUTO_HIDE_FLAGS);
+        assert!(flagged.hidden);
+        let found =
+            get_message_handler(&store, "general".into(), posted.core.id.as_str().into(), None)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(found.status, MessageStatus::Hidden);
+        assert_eq!(found.core.message_text, "");
+
+        // Later flags still count, and report it as hidden
+        let flagged = flag_message(&store, &context, "general", &posted.core.id, "erin")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(flagged.flag_count, 4);
+        assert!(flagged.hidden);
+    }
+
+    #[tokio::test]
+    async fn test_flags_without_an_origin_never_hide() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let posted = post_message_handler(
+            &store,
+            &context,
+            send_request("general", "alice", "Buy now"),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+
+        // Anyone can claim any user_id, so these go to moderators but don't hide anything
+        for (count, user_id) in ["bob", "carol", "dave", "erin"].into_iter().enumerate() {
+            let id = posted.core.id.as_str().into();
+            let flagged = record_flag(&store, &context, "general".into(), id, flag(user_id), None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(flagged.flag_count, count as u32 + 1);
+            assert!(!flagged.hidden);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hidden_text_is_blank_on_every_read() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemoryMessageStore::new());
+        let context = RequestContext::default();
+        let posted = post_message_handler(
+            store.as_ref(),
+            &context,
+            send_request("general", "alice", "Buy now"),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+        store.hide_message(&posted).await.unwrap();
+
+        let page = get_messages_handler(store.as_ref(), "general".into(), MessageQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(page.messages[0].status, MessageStatus::Hidden);
+        assert_eq!(page.messages[0].core.message_text, "");
+        let polled =
+            poll_messages_handler(store.as_ref(), "general".into(), "0", None).await.unwrap();
+        assert_eq!(polled.messages[0].core.message_text, "");
+        let request = LatestMessagesRequest { room_ids: vec!["general".to_string()], per_room: 5 };
+        let latest = latest_messages_handler(store.as_ref(), request).await.unwrap();
+        assert_eq!(latest.rooms["general"][0].core.message_text, "");
+        let missed = missed_messages(store.as_ref(), "general", "bob", 0, 10).await.unwrap();
+        assert_eq!(missed[0].core.message_text, "");
+
+        // Searching for the text doesn't find it either
+        let found =
+            search_messages_handler(store.as_ref(), None, "general".into(), "Buy".to_string())
+                .await
+                .unwrap();
+        assert!(found.messages.is_empty());
+
+        let export = crate::export::export_messages(
+            store.clone(),
+            "general".into(),
+            crate::export::ExportFormat::Jsonl,
+        )
+        .unwrap();
+        let lines: Vec<String> = futures_util::TryStreamExt::try_collect(export).await.unwrap();
+        assert!(!lines.concat().contains("Buy now"));
+    }
+
+    #[tokio::test]
+    async fn test_archived_rooms_refuse_posts_but_stay_readable() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let posted =
+            post_message_handler(&store, &context, send_request("general", "alice", "Hi"), None)
+                .await
+                .unwrap()
+                .message;
+
+        let archived =
+            set_room_archived_handler(&store, "General".into(), true).await.unwrap().unwrap();
+        assert_eq!(archived, RoomArchiveState { room_id: "general".to_string(), archived: true });
+
+        let err =
+            post_message_handler(&store, &context, send_request("general", "alice", "Hi?"), None)
+                .await
+                .unwrap_err();
+        assert!(matches!(err, HandlerError::Forbidden(ref code) if code == ROOM_ARCHIVED));
+
+        let listed =
+            get_messages_handler(&store, "general".into(), MessageQuery::default()).await.unwrap();
+        let ids: Vec<_> = listed.messages.iter().map(|message| message.core.id.as_str()).collect();
+        assert_eq!(ids, vec![posted.core.id.as_str()]);
+
+        // Unarchiving opens it again
+        set_room_archived_handler(&store, "general".into(), false).await.unwrap().unwrap();
+        post_message_handler(&store, &context, send_request("general", "alice", "Back"), None)
+            .await
+            .unwrap();
+
+        assert!(set_room_archived_handler(&store, "missing".into(), true).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_room_list_leaves_out_archived_rooms_by_default() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        for room_id in ["general", "random", "old"] {
+            store.ensure_room(room_id).await.unwrap();
+            clock.advance(chrono::Duration::seconds(1));
+        }
+        set_room_archived_handler(&store, "old".into(), true).await.unwrap();
+
+        let page = list_rooms_handler(&store, RoomQuery::default()).await.unwrap();
+        let ids: Vec<_> = page.rooms.iter().map(|room| room.id.as_str()).collect();
+        // Newest first
+        assert_eq!(ids, vec!["random", "general"]);
+        assert_eq!(page.rooms[1].name, "General");
+        assert_eq!(page.next_cursor, None);
+
+        let query = RoomQuery { include_archived: true, ..Default::default() };
+        let page = list_rooms_handler(&store, query).await.unwrap();
+        let archived: Vec<_> =
+            page.rooms.iter().map(|room| (room.id.as_str(), room.archived)).collect();
+        assert_eq!(archived, vec![("old", true), ("random", false), ("general", false)]);
+    }
+
+    #[tokio::test]
+    async fn test_room_list_pages_with_a_capped_limit() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = MemoryMessageStore::new().with_clock(clock.clone());
+        for n in 0..60 {
+            store.ensure_room(&format!("room-{:02}", n)).await.unwrap();
+            // Every other pair shares a creation time, so ties are ordered by id
+            if n % 2 == 1 {
+                clock.advance(chrono::Duration::seconds(1));
+            }
+        }
+
+        let query = RoomQuery { limit: Some(500), ..Default::default() };
+        let page = list_rooms_handler(&store, query).await.unwrap();
+        assert_eq!(page.rooms.len(), MAX_ROOMS_PAGE);
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let query = RoomQuery { limit: Some(7), after, ..Default::default() };
+            let page = list_rooms_handler(&store, query).await.unwrap();
+            seen.extend(page.rooms.into_iter().map(|room| room.id));
+            match page.next_cursor {
+                Some(cursor) => after = Some(cursor),
+                None => break,
+            }
+        }
+        let expected: Vec<_> = (0..60)
+            .rev()
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .flat_map(|pair| {
+                pair.iter().rev().map(|n| format!("room-{:02}", n)).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(seen, expected);
+
+        let query = RoomQuery { after: Some("not-a-cursor".to_string()), ..Default::default() };
+        assert!(matches!(
+            list_rooms_handler(&store, query).await,
+            Err(HandlerError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_avatar_seed_survives_a_rename() {
+        let store = MemoryMessageStore::new();
+        let context = RequestContext::default();
+        let user_id = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+
+        // Without one chosen, the seed is the user id
+        let first =
+            post_message_handler(&store, &context, send_request("general", "alice", "Hi"), None)
+                .await
+                .unwrap()
+                .message;
+        assert_eq!(first.avatar_seed.as_deref(), Some(user_id));
+
+        let request = UpdateAvatarSeedRequest { avatar_seed: Some(" sunflower ".to_string()) };
+        let profile =
+            set_avatar_seed_handler(&store, UserId::from(user_id), request).await.unwrap();
+        assert_eq!(profile.avatar_seed.as_deref(), Some("sunflower"));
+
+        let request = UpdateUsernameRequest { username: "alicia".to_string() };
+        rename_user_handler(&store, UserId::from(user_id), request).await.unwrap();
+        let second = post_message_handler(
+            &store,
+            &context,
+            send_request("general", "alice", "Hi again"),
+            None,
+        )
+        .await
+        .unwrap()
+        .message;
+        assert_eq!(second.core.username, "alicia");
+        assert_eq!(second.avatar_seed.as_deref(), Some("sunflower"));
+
+        // Clearing it goes back to the user id, and the name is left alone
+        let request = UpdateAvatarSeedRequest { avatar_seed: None };
+        let profile =
+            set_avatar_seed_handler(&store, UserId::from(user_id), request).await.unwrap();
+        assert_eq!(profile.username.as_deref(), Some("alicia"));
+        assert_eq!(profile.avatar_seed.as_deref(), Some(user_id));
+
+        let request = UpdateAvatarSeedRequest { avatar_seed: Some("x".repeat(65)) };
+        let err =
+            set_avatar_seed_handler(&store, UserId::from(user_id), request).await.unwrap_err();
+        assert!(matches!(err, HandlerError::Validation(_)));
+    }
 }
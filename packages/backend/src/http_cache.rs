@@ -0,0 +1,145 @@
+use sha2::{Digest, Sha256};
+use std::env;
+use types::{ChatMessage, MessageStatus};
+
+const DEFAULT_MESSAGES_MAX_AGE_SECS: u32 = 5;
+
+/// `Cache-Control` for `/health`, which must always reflect the live service
+pub const HEALTH_CACHE_CONTROL: &str = "no-store";
+
+/// How long clients and proxies may reuse a room's message list (`MESSAGES_MAX_AGE_SECS`,
+/// default 5). Afterwards they revalidate with the list's ETag and get a 304 while the room
+/// is unchanged. 0 makes every read revalidate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub messages_max_age_secs: u32,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self { messages_max_age_secs: DEFAULT_MESSAGES_MAX_AGE_SECS }
+    }
+}
+
+impl CachePolicy {
+    pub fn from_env() -> Self {
+        let messages_max_age_secs = env::var("MESSAGES_MAX_AGE_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MESSAGES_MAX_AGE_SECS);
+        Self { messages_max_age_secs }
+    }
+
+    /// `Cache-Control` for `GET /chat/messages/:room_id`
+    pub fn messages_cache_control(&self) -> String {
+        match self.messages_max_age_secs {
+            0 => "no-cache".to_string(),
+            secs => format!("max-age={}", secs),
+        }
+    }
+}
+
+/// Weak ETag for a message list: the newest message's `ts`, the number of messages, the
+/// latest edit and a digest of every message's status, so a post, an expiry, an edit and a
+/// message being hidden all change it. Weak because `server_time` differs on every response.
+pub fn messages_etag(messages: &[ChatMessage]) -> String {
+    let latest_ts = messages
+        .iter()
+        .map(|message| message.core.created_at.timestamp_millis())
+        .max()
+        .unwrap_or(0);
+    let latest_edit = messages
+        .iter()
+        .filter_map(|message| message.edited_at)
+        .map(|edited_at| edited_at.timestamp_millis())
+        .max()
+        .unwrap_or(0);
+    let mut statuses = Sha256::new();
+    for message in messages {
+        statuses.update(message.core.id.as_bytes());
+        statuses.update(match message.status {
+            MessageStatus::Stored => b"s",
+            MessageStatus::Broadcast => b"b",
+            MessageStatus::Hidden => b"h",
+        });
+    }
+    let statuses = hex::encode(&statuses.finalize()[..8]);
+    format!("W/\"{}-{}-{}-{}\"", latest_ts, messages.len(), latest_edit, statuses)
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using weak comparison
+pub fn if_none_match(header: Option<&str>, etag: &str) -> bool {
+    let Some(header) = header else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    header.split(',').any(|candidate| candidate.trim() == "*" || opaque(candidate) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration};
+    use types::{MessageCore, MessageFormat, MessageVisibility};
+
+    fn message(id: &str, created_at_millis: i64) -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: id.to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: format!("message {}", id),
+                created_at: DateTime::from_timestamp_millis(created_at_millis).unwrap(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[test]
+    fn test_if_none_match_uses_weak_comparison() {
+        let etag = "W/\"1700000000000-3\"";
+        assert!(if_none_match(Some(etag), etag));
+        assert!(if_none_match(Some("\"1700000000000-3\""), etag));
+        assert!(if_none_match(Some("\"other\", W/\"1700000000000-3\""), etag));
+        assert!(if_none_match(Some("*"), etag));
+        assert!(!if_none_match(Some("W/\"1700000000000-4\""), etag));
+        assert!(!if_none_match(None, etag));
+    }
+
+    #[test]
+    fn test_edits_and_status_changes_change_the_etag() {
+        let mut messages = vec![message("m1", 1_700_000_000_000), message("m2", 1_700_000_001_000)];
+        let original = messages_etag(&messages);
+        assert_eq!(messages_etag(&messages), original);
+
+        // Same ids, same count, same newest message; only the text and edited_at move
+        messages[0].core.message_text = "edited".to_string();
+        messages[0].edited_at = Some(messages[1].core.created_at + Duration::seconds(5));
+        let edited = messages_etag(&messages);
+        assert_ne!(edited, original);
+
+        messages[1].status = MessageStatus::Hidden;
+        assert_ne!(messages_etag(&messages), edited);
+    }
+
+    #[test]
+    fn test_zero_max_age_always_revalidates() {
+        assert_eq!(CachePolicy::default().messages_cache_control(), "max-age=5");
+        assert_eq!(CachePolicy { messages_max_age_secs: 0 }.messages_cache_control(), "no-cache");
+    }
+}
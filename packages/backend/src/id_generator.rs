@@ -0,0 +1,124 @@
+use crate::clock::Clock;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tracing::warn;
+use ulid::{Generator, Ulid};
+use uuid::Uuid;
+
+/// Source of new message ids (and other server-assigned ids), so the scheme can be swapped for
+/// a sortable one or pinned in tests
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> String;
+}
+
+/// Random UUIDs. What every store uses unless given another.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV4Generator;
+
+impl IdGenerator for UuidV4Generator {
+    fn next_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// ULIDs stamped from `clock`. They sort by creation time, and ids made within the same
+/// millisecond still sort in the order they were made, which makes them a usable tiebreaker.
+pub struct UlidGenerator {
+    clock: Arc<dyn Clock>,
+    generator: Mutex<Generator>,
+}
+
+impl UlidGenerator {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, generator: Mutex::new(Generator::new()) }
+    }
+}
+
+impl IdGenerator for UlidGenerator {
+    fn next_id(&self) -> String {
+        let now = SystemTime::from(self.clock.now());
+        // Only fails once 2^80 ids have been made in one millisecond
+        match self.generator.lock().unwrap().generate_from_datetime(now) {
+            Ok(id) => id.to_string(),
+            Err(_) => Ulid::from_datetime(now).to_string(),
+        }
+    }
+}
+
+/// `<prefix>00000001`, `<prefix>00000002`, ... for tests that need to know the ids in advance
+#[derive(Debug)]
+pub struct SeqIdGenerator {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SeqIdGenerator {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into(), next: AtomicU64::new(1) }
+    }
+}
+
+impl IdGenerator for SeqIdGenerator {
+    fn next_id(&self) -> String {
+        format!("{}{:08}", self.prefix, self.next.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+/// Random UUIDs, shared
+pub fn uuid_v4() -> Arc<dyn IdGenerator> {
+    Arc::new(UuidV4Generator)
+}
+
+/// The generator selected by `MESSAGE_ID_FORMAT`: `uuid` (the default) or `ulid`, the latter
+/// stamped from `clock`
+pub fn from_env(clock: Arc<dyn Clock>) -> Arc<dyn IdGenerator> {
+    match env::var("MESSAGE_ID_FORMAT").unwrap_or_default().as_str() {
+        "" | "uuid" => uuid_v4(),
+        "ulid" => Arc::new(UlidGenerator::new(clock)),
+        other => {
+            warn!("Unknown MESSAGE_ID_FORMAT '{}'; using random UUIDs", other);
+            uuid_v4()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_ulids_sort_by_time_then_creation_order() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let ids = UlidGenerator::new(clock.clone());
+
+        let mut made = Vec::new();
+        for _ in 0..3 {
+            // Several in the same millisecond, then move on
+            made.extend((0..50).map(|_| ids.next_id()));
+            clock.advance(chrono::Duration::milliseconds(1));
+        }
+
+        let mut sorted = made.clone();
+        sorted.sort();
+        assert_eq!(sorted, made);
+        let first: Ulid = made[0].parse().unwrap();
+        assert_eq!(first.timestamp_ms(), 1_700_000_000_000);
+    }
+
+    #[test]
+    fn test_seq_ids_are_predictable() {
+        let ids = SeqIdGenerator::new("msg-");
+        assert_eq!(ids.next_id(), "msg-00000001");
+        assert_eq!(ids.next_id(), "msg-00000002");
+        assert_eq!(SeqIdGenerator::new("msg-").next_id(), "msg-00000001");
+    }
+}
@@ -0,0 +1,121 @@
+use sha2::{Digest, Sha256};
+use std::env;
+
+// Hex characters of the seed hash in generated anonymous ids
+const ANONYMOUS_ID_CHARS: usize = 8;
+
+// Hex characters of the user id hash in fallback display names
+const FALLBACK_NAME_CHARS: usize = 6;
+
+/// Who a connection or post belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub user_id: String,
+    pub username: String,
+}
+
+/// What to do with requests that don't say who they are (`ALLOW_ANONYMOUS`). Off by default,
+/// so they are rejected with a 401; when on, each gets an `anon-<hash>` id derived from a seed
+/// the caller picks (e.g. the connection id) rather than a shared literal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AnonymousPolicy {
+    pub allow: bool,
+}
+
+impl AnonymousPolicy {
+    pub fn from_env() -> Self {
+        let allow = env::var("ALLOW_ANONYMOUS")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        Self { allow }
+    }
+
+    /// The identity to use, filling in whatever is missing from `seed` when anonymous users are
+    /// allowed. Blank values count as missing.
+    pub fn resolve(
+        &self,
+        user_id: Option<&str>,
+        username: Option<&str>,
+        seed: &str,
+    ) -> Result<Identity, String> {
+        let user_id = user_id.map(str::trim).filter(|value| !value.is_empty());
+        let username = username.map(str::trim).filter(|value| !value.is_empty());
+        if let (Some(user_id), Some(username)) = (user_id, username) {
+            return Ok(Identity { user_id: user_id.to_string(), username: username.to_string() });
+        }
+        if !self.allow {
+            return Err("A user_id and username are required".to_string());
+        }
+
+        let user_id = user_id.map_or_else(|| anonymous_id(seed), str::to_string);
+        let username =
+            username.map_or_else(|| display_name_fallback(Some(&user_id)), str::to_string);
+        Ok(Identity { user_id, username })
+    }
+}
+
+/// `anon-<hash of seed>`: the same seed always maps to the same id
+pub fn anonymous_id(seed: &str) -> String {
+    let mut hash = hex::encode(Sha256::digest(seed.as_bytes()));
+    hash.truncate(ANONYMOUS_ID_CHARS);
+    format!("anon-{}", hash)
+}
+
+/// The name shown for a user who has none: `User-<hash of user_id>`, the same for a user
+/// every time and different between users, or `Guest` when even the user id is unknown
+pub fn display_name_fallback(user_id: Option<&str>) -> String {
+    match user_id.map(str::trim).filter(|user_id| !user_id.is_empty()) {
+        Some(user_id) => {
+            let mut hash = hex::encode(Sha256::digest(user_id.as_bytes()));
+            hash.truncate(FALLBACK_NAME_CHARS);
+            format!("User-{}", hash)
+        }
+        None => "Guest".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_identity_rejected_when_disabled() {
+        let policy = AnonymousPolicy { allow: false };
+        assert!(policy.resolve(None, Some("alice"), "conn-1").is_err());
+        assert!(policy.resolve(Some("  "), Some("alice"), "conn-1").is_err());
+        assert!(policy.resolve(Some("user-1"), None, "conn-1").is_err());
+        assert_eq!(
+            policy.resolve(Some("user-1"), Some(" alice "), "conn-1"),
+            Ok(Identity { user_id: "user-1".to_string(), username: "alice".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_anonymous_ids_are_unique_per_seed() {
+        let policy = AnonymousPolicy { allow: true };
+        let first = policy.resolve(None, None, "conn-1").unwrap();
+        let second = policy.resolve(None, None, "conn-2").unwrap();
+
+        assert!(first.user_id.starts_with("anon-") && first.user_id.len() == 13);
+        assert_ne!(first.user_id, second.user_id);
+        assert_eq!(first.username, display_name_fallback(Some(&first.user_id)));
+        assert_eq!(policy.resolve(None, None, "conn-1").unwrap(), first);
+
+        // Whatever the client did send is kept
+        let named = policy.resolve(None, Some("alice"), "conn-1").unwrap();
+        assert_eq!(
+            (named.user_id.as_str(), named.username.as_str()),
+            (first.user_id.as_str(), "alice")
+        );
+    }
+
+    #[test]
+    fn test_display_name_fallback_is_stable_per_user() {
+        let alice = display_name_fallback(Some("01ARZ3NDEKTSV4RRFFQ69G5FB1"));
+        assert!(alice.starts_with("User-") && alice.len() == 11);
+        assert_eq!(display_name_fallback(Some("01ARZ3NDEKTSV4RRFFQ69G5FB1")), alice);
+        assert_ne!(display_name_fallback(Some("01ARZ3NDEKTSV4RRFFQ69G5FB2")), alice);
+        assert_eq!(display_name_fallback(None), "Guest");
+        assert_eq!(display_name_fallback(Some(" ")), "Guest");
+    }
+}
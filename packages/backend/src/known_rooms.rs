@@ -0,0 +1,96 @@
+use lru::LruCache;
+use std::{
+    env,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_CAPACITY: usize = 1024;
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Rooms recently confirmed to exist, so posts to an established room skip the rooms table
+/// read. Only ever a shortcut past the read: creating a room still goes through the
+/// conditional put, which is what keeps instances that disagree safe.
+#[derive(Debug)]
+pub struct KnownRooms {
+    // When each room was confirmed
+    rooms: Mutex<LruCache<String, Instant>>,
+    ttl: Duration,
+}
+
+impl Default for KnownRooms {
+    fn default() -> Self {
+        Self::new(NonZeroUsize::new(DEFAULT_CAPACITY).unwrap(), DEFAULT_TTL)
+    }
+}
+
+impl KnownRooms {
+    /// Remembers up to `capacity` rooms, each for `ttl`. A zero `ttl` remembers nothing.
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self { rooms: Mutex::new(LruCache::new(capacity)), ttl }
+    }
+
+    /// `KNOWN_ROOMS_CAPACITY` rooms (default 1024), each for `KNOWN_ROOMS_TTL_SECS` (default
+    /// 300; 0 turns the cache off)
+    pub fn from_env() -> Self {
+        let capacity = env::var("KNOWN_ROOMS_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(NonZeroUsize::new(DEFAULT_CAPACITY).unwrap());
+        let ttl = env::var("KNOWN_ROOMS_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .map_or(DEFAULT_TTL, Duration::from_secs);
+        Self::new(capacity, ttl)
+    }
+
+    /// Whether the room was confirmed within the TTL
+    pub fn contains(&self, room_id: &str) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        match rooms.get(room_id) {
+            Some(confirmed) if confirmed.elapsed() < self.ttl => true,
+            Some(_) => {
+                rooms.pop(room_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Note that the room exists, as of now
+    pub fn insert(&self, room_id: &str) {
+        if !self.ttl.is_zero() {
+            self.rooms.lock().unwrap().put(room_id.to_string(), Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rooms_are_remembered_up_to_capacity() {
+        let rooms = KnownRooms::new(NonZeroUsize::new(2).unwrap(), DEFAULT_TTL);
+        assert!(!rooms.contains("general"));
+
+        rooms.insert("general");
+        rooms.insert("random");
+        assert!(rooms.contains("general"));
+
+        // "random" is the least recently used, so it makes way
+        rooms.insert("rust");
+        assert!(rooms.contains("general"));
+        assert!(rooms.contains("rust"));
+        assert!(!rooms.contains("random"));
+    }
+
+    #[test]
+    fn test_zero_ttl_remembers_nothing() {
+        let rooms = KnownRooms::new(NonZeroUsize::new(2).unwrap(), Duration::ZERO);
+        rooms.insert("general");
+        assert!(!rooms.contains("general"));
+    }
+}
@@ -0,0 +1,64 @@
+use backend::{
+    clients,
+    compaction::{Compaction, CompactionReport},
+    handlers, required_env,
+    store::{DynamoMessageStore, MessageStore},
+};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+use tracing::{info, warn};
+
+// Tables configuration
+static TABLES: LazyLock<handlers::Tables> = LazyLock::new(handlers::Tables::from_env);
+
+static COMPACTION: LazyLock<Compaction> = LazyLock::new(Compaction::from_env);
+
+// Runs on a schedule; the event itself carries nothing we need
+async fn function_handler(_event: LambdaEvent<Value>) -> Result<Value, Error> {
+    // AWS clients and metrics helper are cached across warm invocations
+    let clients = clients::shared().await;
+    let store = DynamoMessageStore::new(clients.ddb.clone(), TABLES.clone())
+        .with_clock(clients.context.clock.clone());
+
+    // Archived rooms are read-only, which makes them the best candidates
+    let rooms = store.list_rooms(true).await?;
+    let mut total = CompactionReport::default();
+    let mut failed = 0;
+    for room in &rooms {
+        // One room failing shouldn't hold up the rest; the next run picks it up again
+        match store.compact_room(&room.id, &COMPACTION).await {
+            Ok(report) => total.add(&report),
+            Err(e) => {
+                warn!("Failed to compact room {}: {}", room.id, e);
+                failed += 1;
+            }
+        }
+    }
+    info!(
+        "Compacted {} messages into {} segments across {} rooms ({} failed)",
+        total.messages_compacted,
+        total.segments_written,
+        rooms.len(),
+        failed
+    );
+    Ok(json!({ "rooms": rooms.len(), "failed": failed, "report": total }))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Initialize tracing with JSON format for CloudWatch
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .json()
+        .with_current_span(false)
+        .with_span_list(false)
+        .init();
+
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::COMPACT_HISTORY)?;
+
+    LazyLock::force(&TABLES);
+
+    run(service_fn(function_handler)).await
+}
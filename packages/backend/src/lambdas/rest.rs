@@ -1,24 +1,183 @@
-use aws_sdk_dynamodb::Client as DynamoDbClient;
-use lambda_http::{run, service_fn, Body, Error, Request, Response};
-use std::sync::LazyLock;
-use tracing::{debug, error, info, warn, Level};
-use types::SendMessageRequest;
+use futures_util::TryStreamExt;
+use lambda_http::{
+    request::RequestContext, run, service_fn, Body, Error, Request, RequestExt, Response,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+};
+use tracing::{error, info, warn, Level};
+use types::{
+    ApiError, EditMessageRequest, FlagMessageRequest, LatestMessagesRequest, RoomId,
+    SendMessageRequest, UpdateAvatarSeedRequest, UpdateTopicRequest, UpdateUsernameRequest, UserId,
+};
 
-use backend::handlers;
+use backend::{
+    admin,
+    body_log::BodyLogger,
+    broadcast, clients,
+    dependencies::DependencyHealth,
+    export::{self, ExportFormat},
+    handlers,
+    http_cache::{self, CachePolicy},
+    known_rooms::KnownRooms,
+    origin, required_env,
+    search::{self, SearchIndex},
+    store::DynamoMessageStore,
+};
 
 // Tables configuration
-static TABLES: LazyLock<handlers::Tables> = LazyLock::new(|| handlers::Tables::from_env());
+static TABLES: LazyLock<handlers::Tables> = LazyLock::new(handlers::Tables::from_env);
+
+// Opt-in debug logging of request/response bodies (LOG_BODIES)
+static BODY_LOGGER: LazyLock<BodyLogger> = LazyLock::new(BodyLogger::from_env);
+
+// Cache-Control for message lists (MESSAGES_MAX_AGE_SECS)
+static CACHE_POLICY: LazyLock<CachePolicy> = LazyLock::new(CachePolicy::from_env);
+
+// Largest request body parsed before answering 413 (MAX_BODY_BYTES)
+static MAX_BODY_BYTES: LazyLock<usize> = LazyLock::new(handlers::max_body_bytes_from_env);
+
+// User profiles for renames; without it renames fail and posts keep the name they carry
+// (CHAT_USERS_TABLE)
+static USERS_TABLE: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("CHAT_USERS_TABLE").ok());
+
+// Users' flags on messages; without it flagging fails and the moderator queue is empty
+// (CHAT_FLAGS_TABLE)
+static FLAGS_TABLE: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("CHAT_FLAGS_TABLE").ok());
+
+// Only needed for admin rebroadcasts and room connection counts, so its absence disables
+// rebroadcast (and zeroes active_connections in room stats) instead of the lambda
+static CONNECTIONS_TABLE: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("CONNECTIONS_TABLE").ok());
+
+// Rooms confirmed to exist, kept across warm invocations so posts to established rooms skip
+// the rooms table read (KNOWN_ROOMS_CAPACITY, KNOWN_ROOMS_TTL_SECS)
+static KNOWN_ROOMS: LazyLock<Arc<KnownRooms>> = LazyLock::new(|| Arc::new(KnownRooms::from_env()));
+
+// Full-text index searches go to, when SEARCH_BACKEND configures one. The broadcast Lambda
+// keeps it filled from the messages stream.
+static SEARCH_INDEX: LazyLock<Option<Arc<dyn SearchIndex>>> = LazyLock::new(search::from_env);
+
+// Parse the optional created_after/created_before filters, after/before cursors, page limit,
+// fields projection, reading user and order from the query string
+fn parse_message_query(event: &Request) -> Result<handlers::MessageQuery, String> {
+    let params = event.query_string_parameters();
+    let parse = |name: &str| {
+        params
+            .first(name)
+            .map(|value| {
+                value.parse::<i64>().map_err(|_| format!("{} must be epoch milliseconds", name))
+            })
+            .transpose()
+    };
+
+    let query = handlers::MessageQuery {
+        created_after: parse("created_after")?,
+        created_before: parse("created_before")?,
+        after: params.first("after").map(str::to_string),
+        before: parse("before")?,
+        limit: params
+            .first("limit")
+            .map(|value| value.parse::<usize>().map_err(|_| "limit must be a number".to_string()))
+            .transpose()?,
+        fields: params.first("fields").map(str::to_string),
+        user_id: params.first("user_id").map(str::to_string),
+        order: params
+            .first("order")
+            .map_or(Ok(handlers::SortOrder::requested_default()), handlers::SortOrder::parse)?,
+    };
+    query.validate()?;
+    Ok(query)
+}
+
+// Parse whether archived rooms are wanted, the page limit and the after cursor
+fn parse_room_query(event: &Request) -> Result<handlers::RoomQuery, String> {
+    let params = event.query_string_parameters();
+    Ok(handlers::RoomQuery {
+        include_archived: params.first("include_archived").is_some_and(|value| value == "true"),
+        limit: params
+            .first("limit")
+            .map(|value| value.parse::<usize>().map_err(|_| "limit must be a number".to_string()))
+            .transpose()?,
+        after: params.first("after").map(str::to_string),
+    })
+}
+
+// The sender's IP as seen by API Gateway, falling back to X-Forwarded-For
+fn client_ip(event: &Request) -> Option<IpAddr> {
+    let source_ip = match event.request_context_ref() {
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.as_deref(),
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.as_deref(),
+        _ => None,
+    };
+    let forwarded_for =
+        event.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok());
+    origin::client_ip(source_ip, forwarded_for)
+}
+
+// The JSON body, or why it can't be read (answered with `body_error`)
+fn parse_body<T: DeserializeOwned>(event: &Request) -> Result<T, handlers::BodyError> {
+    handlers::parse_json_body(event.body().as_ref()).inspect_err(|err| {
+        warn!("Rejecting request body: {}", err);
+    })
+}
+
+fn body_error(err: &handlers::BodyError) -> Response<Body> {
+    json_error(err.status(), &err.to_string())
+}
+
+fn bad_request(message: &str) -> Response<Body> {
+    json_error(400, message)
+}
+
+fn json_error(status: u16, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "error": message, "code": status });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .body(Body::Text(body.to_string()))
+        .unwrap()
+}
 
 async fn handler(event: Request) -> Result<Response<Body>, Error> {
+    let method = event.method().to_string();
+    let path = event.uri().path().to_string();
+
+    // Bodies only, and only at debug: headers may carry credentials
+    BODY_LOGGER.log_request(&method, &path, event.body().as_ref());
+    let response = route(event).await?;
+    BODY_LOGGER.log_response(&method, &path, response.status().as_u16(), response.body().as_ref());
+    Ok(response)
+}
+
+async fn route(event: Request) -> Result<Response<Body>, Error> {
     let method = event.method().as_str();
     let path = event.uri().path();
 
     info!("Lambda handler called: {} {}", method, path);
-    debug!("Full request: {:?}", event);
 
-    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    let ddb = DynamoDbClient::new(&aws_config);
-    let tables = TABLES.clone();
+    // API Gateway has already buffered the body; refuse to parse an oversized one
+    let body_len = event.body().as_ref().len();
+    if let Err(err) = handlers::check_body_size(body_len, *MAX_BODY_BYTES) {
+        warn!("Rejecting {} byte body for {} {}", body_len, method, path);
+        return Ok(body_error(&err));
+    }
+
+    let clients = clients::shared().await;
+    let ddb = &clients.ddb;
+    let store = DynamoMessageStore::new(ddb.clone(), TABLES.clone())
+        .with_users_table(USERS_TABLE.clone())
+        .with_flags_table(FLAGS_TABLE.clone())
+        .with_clock(clients.context.clock.clone())
+        .with_write_limiter(clients.write_limiter.clone())
+        .with_cipher(clients.cipher.clone())
+        .with_known_rooms(KNOWN_ROOMS.clone());
 
     info!("Handler processing: {} {}", method, path);
 
@@ -50,19 +209,92 @@ async fn handler(event: Request) -> Result<Response<Body>, Error> {
     match (method, clean_path.as_str()) {
         ("GET", "/health") => {
             info!("Processing health endpoint");
-            match handlers::health_handler().await {
-                Ok(health_check) => {
-                    let body = serde_json::to_string(&health_check)?;
+            let health_check = handlers::health_handler(&store, &clients.context).await;
+            let body = serde_json::to_string(&health_check)?;
+            Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", http_cache::HEALTH_CACHE_CONTROL)
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .body(Body::Text(body))
+                .unwrap())
+        }
+        ("GET", "/health/dependencies") => {
+            info!("Processing dependency health endpoint");
+            let report = DependencyHealth::from_env(&clients.aws_config).check().await;
+            let body = serde_json::to_string(&report)?;
+            Ok(Response::builder()
+                .status(if report.healthy { 200 } else { 503 })
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", http_cache::HEALTH_CACHE_CONTROL)
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .body(Body::Text(body))
+                .unwrap())
+        }
+        ("POST", "/chat/messages") => {
+            info!("Processing POST /chat/messages");
+            let request: SendMessageRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            match handlers::post_message_handler(
+                &store,
+                &clients.context,
+                request,
+                client_ip(&event),
+            )
+            .await
+            {
+                // A retry of a stored post gets the stored message, with 200 rather than 201
+                Ok(handlers::Posted { message, duplicate }) => {
+                    let body = serde_json::to_string(&message)?;
                     Ok(Response::builder()
-                        .status(200)
+                        .status(if duplicate { 200 } else { 201 })
                         .header("Content-Type", "application/json")
+                        .header("Location", handlers::message_location(&message))
+                        .header("Access-Control-Expose-Headers", "Location")
                         .header("Access-Control-Allow-Origin", "*")
                         .header("Access-Control-Allow-Headers", "*")
                         .body(Body::Text(body))
                         .unwrap())
                 }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::BadRequest(message)) => Ok(bad_request(&message)),
+                Err(handlers::HandlerError::Unauthorized(message)) => {
+                    warn!("Rejecting anonymous post: {}", message);
+                    Ok(json_error(401, &message))
+                }
+                Err(handlers::HandlerError::Forbidden(message)) => {
+                    warn!("Rejecting post: {}", message);
+                    Ok(json_error(403, &message))
+                }
+                Err(handlers::HandlerError::Conflict(message)) => {
+                    warn!("Message not stored: {}", message);
+                    Ok(json_error(409, &message))
+                }
+                Err(handlers::HandlerError::Throttled(message)) => {
+                    warn!("Message not stored: {}", message);
+                    Ok(json_error(429, &message))
+                }
                 Err(err) => {
-                    error!("Health check failed: {}", err);
+                    error!("Failed to post message: {}", err);
                     Ok(Response::builder()
                         .status(500)
                         .header("Access-Control-Allow-Origin", "*")
@@ -72,24 +304,620 @@ async fn handler(event: Request) -> Result<Response<Body>, Error> {
                 }
             }
         }
-        ("POST", "/chat/messages") => {
-            info!("Processing POST /chat/messages");
-            let bytes = event.body().as_ref().to_owned();
-            let request: SendMessageRequest = serde_json::from_slice(&bytes)?;
+        ("POST", "/chat/messages/latest") => {
+            info!("Processing POST /chat/messages/latest");
+            let request: LatestMessagesRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            match handlers::latest_messages_handler(&store, request).await {
+                Ok(latest) => {
+                    // Some rooms couldn't be read; the rest are still worth showing
+                    let status = if latest.errors.is_empty() { 200 } else { 207 };
+                    let body = serde_json::to_string(&latest)?;
+                    Ok(Response::builder()
+                        .status(status)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to get latest messages: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("POST", path) if path.starts_with("/chat/messages/") && path.ends_with("/rebroadcast") => {
+            let message_id = path
+                .trim_start_matches("/chat/messages/")
+                .trim_end_matches("/rebroadcast")
+                .to_string();
+            info!("Processing rebroadcast of message {}", message_id);
+
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(token) {
+                warn!("Rejected rebroadcast of message {} without a valid admin token", message_id);
+                return Ok(json_error(403, "Admin token required"));
+            }
+
+            let Some(connections_table) = CONNECTIONS_TABLE.as_deref() else {
+                error!("CONNECTIONS_TABLE is not set; rebroadcast is unavailable");
+                return Ok(json_error(503, "Rebroadcast is not configured"));
+            };
+
+            let message = match handlers::find_message_by_id(&store, &message_id).await {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(json_error(404, "Message not found")),
+                Err(err) => {
+                    error!("Failed to look up message {}: {}", message_id, err);
+                    return Ok(json_error(500, "Internal server error"));
+                }
+            };
+
+            let clients = clients::shared().await;
+            let api_gateway = broadcast::management_clients(&clients.aws_config).await;
+            match broadcast::broadcast_message(ddb, api_gateway, connections_table, &message, None)
+                .await
+            {
+                Ok(stats) => {
+                    clients
+                        .metrics
+                        .emit_message_broadcast(
+                            &message.core.room_id,
+                            stats.connections,
+                            stats.successful_sends,
+                        )
+                        .await;
+                    let body = serde_json::json!({
+                        "message_id": message.core.id,
+                        "room_id": message.core.room_id,
+                        "connections": stats.connections,
+                        "successful_sends": stats.successful_sends,
+                    });
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(err) => {
+                    error!("Failed to rebroadcast message {}: {}", message_id, err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("POST", path)
+            if path.strip_prefix("/chat/messages/").is_some_and(|rest| {
+                rest.strip_suffix("/flag").is_some_and(|ids| ids.contains('/'))
+            }) =>
+        {
+            let (room_id, message_id) = path
+                .trim_start_matches("/chat/messages/")
+                .trim_end_matches("/flag")
+                .split_once('/')
+                .unwrap();
+            info!("Processing flag of message {} in room {}", message_id, room_id);
+            let request: FlagMessageRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            match handlers::flag_message_handler(
+                &store,
+                &clients.context,
+                room_id.into(),
+                message_id.into(),
+                request,
+                client_ip(&event),
+            )
+            .await
+            {
+                Ok(Some(flagged)) => {
+                    let body = serde_json::to_string(&flagged)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Ok(None) => Ok(json_error(404, "Message not found")),
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to flag message {}: {}", message_id, err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", "/chat/flags") => {
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(token) {
+                warn!("Rejected moderator queue read without a valid admin token");
+                return Ok(json_error(403, "Admin token required"));
+            }
+
+            match handlers::flags_handler(&store).await {
+                Ok(flags) => {
+                    let body = serde_json::to_string(&flags)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(err) => {
+                    error!("Failed to list flags: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("PUT", path) if path.starts_with("/chat/users/") && path.ends_with("/username") => {
+            let user_id =
+                UserId::from(path.trim_start_matches("/chat/users/").trim_end_matches("/username"));
+            info!("Processing rename of user {}", user_id);
+            let request: UpdateUsernameRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            // Unlike the local server, connected clients aren't told live; they pick the new
+            // name up from the next message
+            match handlers::rename_user_handler(&store, user_id, request).await {
+                Ok(renamed) => {
+                    let body = serde_json::to_string(&renamed)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to rename user: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("PUT", path) if path.starts_with("/chat/users/") && path.ends_with("/avatar-seed") => {
+            let user_id = UserId::from(
+                path.trim_start_matches("/chat/users/").trim_end_matches("/avatar-seed"),
+            );
+            info!("Processing avatar seed of user {}", user_id);
+            let request: UpdateAvatarSeedRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
 
-            match handlers::post_message_handler(&ddb, &tables, request).await {
-                Ok(message) => {
+            match handlers::set_avatar_seed_handler(&store, user_id, request).await {
+                Ok(profile) => {
+                    let body = serde_json::to_string(&profile)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(err) => {
+                    error!("Failed to set avatar seed: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", "/chat/rooms") => {
+            let query = match parse_room_query(&event) {
+                Ok(query) => query,
+                Err(message) => return Ok(bad_request(&message)),
+            };
+            match handlers::list_rooms_handler(&store, query).await {
+                Ok(page) => {
+                    let body = serde_json::to_string(&page)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::BadRequest(message)) => Ok(json_error(400, &message)),
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to list rooms: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("POST", path)
+            if path.starts_with("/chat/rooms/")
+                && (path.ends_with("/archive") || path.ends_with("/unarchive")) =>
+        {
+            let archived = path.ends_with("/archive");
+            let room_id = RoomId::from(
+                path.trim_start_matches("/chat/rooms/")
+                    .trim_end_matches("/archive")
+                    .trim_end_matches("/unarchive"),
+            );
+            info!("Processing archive state of room {}", room_id);
+
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(token) {
+                warn!("Rejected archive change of room {} without a valid admin token", room_id);
+                return Ok(json_error(403, "Admin token required"));
+            }
+
+            match handlers::set_room_archived_handler(&store, room_id, archived).await {
+                Ok(Some(changed)) => {
+                    let body = serde_json::to_string(&changed)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Ok(None) => Ok(json_error(404, "Room not found")),
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(err) => {
+                    error!("Failed to change archive state of a room: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("PUT", path) if path.starts_with("/chat/rooms/") && path.ends_with("/topic") => {
+            let room_id =
+                RoomId::from(path.trim_start_matches("/chat/rooms/").trim_end_matches("/topic"));
+            info!("Processing topic of room {}", room_id);
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            let is_admin = admin::is_authorized(token);
+            let request: UpdateTopicRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            // As with renames, connected clients aren't told live here; they see the topic the
+            // next time they load the room
+            match handlers::set_room_topic_handler(&store, room_id, request, is_admin).await {
+                Ok(Some(changed)) => {
+                    let body = serde_json::to_string(&changed)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Ok(None) => Ok(json_error(404, "Room not found")),
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Forbidden(message)) => Ok(json_error(403, &message)),
+                Err(err) => {
+                    error!("Failed to set room topic: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path) if path.starts_with("/chat/rooms/") && path.ends_with("/stats") => {
+            let room_id =
+                RoomId::from(path.trim_start_matches("/chat/rooms/").trim_end_matches("/stats"));
+            info!("Processing stats for room {}", room_id);
+
+            let connections = CONNECTIONS_TABLE.as_deref().map(|table| (ddb, table));
+            match handlers::room_stats_handler(&store, connections, room_id).await {
+                Ok(stats) => {
+                    let body = serde_json::to_string(&stats)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to get room stats: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path) if path.starts_with("/chat/rooms/") && path.ends_with("/presence") => {
+            let room_id =
+                RoomId::from(path.trim_start_matches("/chat/rooms/").trim_end_matches("/presence"));
+            info!("Processing presence for room {}", room_id);
+
+            let connections = CONNECTIONS_TABLE.as_deref().map(|table| (ddb, table));
+            let now = clients.context.clock.now().timestamp();
+            match handlers::room_presence_handler(connections, room_id, now).await {
+                Ok(presence) => {
+                    let body = serde_json::to_string(&presence)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(err) => {
+                    error!("Failed to get room presence: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path) if path.starts_with("/chat/rooms/") && path.ends_with("/export") => {
+            let room_id =
+                RoomId::from(path.trim_start_matches("/chat/rooms/").trim_end_matches("/export"));
+            info!("Processing export of room {}", room_id);
+
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(token) {
+                warn!("Rejected export of room {} without a valid admin token", room_id);
+                return Ok(json_error(403, "Admin token required"));
+            }
+
+            let format = match event.query_string_parameters().first("format") {
+                Some(format) => match format.parse::<ExportFormat>() {
+                    Ok(format) => format,
+                    Err(err) => return Ok(bad_request(&err)),
+                },
+                None => ExportFormat::default(),
+            };
+            let disposition = format.content_disposition(&room_id);
+            let chunks = match export::export_messages(Arc::new(store), room_id, format) {
+                Ok(chunks) => chunks,
+                Err(err) => return Ok(bad_request(&err)),
+            };
+
+            // API Gateway buffers Lambda responses, so the pages are gathered before replying
+            match chunks.try_collect::<String>().await {
+                Ok(body) => Ok(Response::builder()
+                    .status(200)
+                    .header("Content-Type", format.content_type())
+                    .header("Content-Disposition", disposition)
+                    .header("Access-Control-Expose-Headers", "Content-Disposition")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .body(Body::Text(body))
+                    .unwrap()),
+                Err(err) => {
+                    error!("Failed to export messages: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path) if path.starts_with("/chat/rooms/") && path.ends_with("/search") => {
+            let room_id =
+                RoomId::from(path.trim_start_matches("/chat/rooms/").trim_end_matches("/search"));
+            let query = event.query_string_parameters().first("q").unwrap_or_default().to_string();
+            info!("Processing search in room {}", room_id);
+
+            let index = SEARCH_INDEX.as_deref();
+            match handlers::search_messages_handler(&store, index, room_id, query).await {
+                Ok(response) => {
+                    let body = serde_json::to_string(&response)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
+                Err(err) => {
+                    error!("Failed to search messages: {}", err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path) if path.starts_with("/admin/messages/") => {
+            let message_id = path.trim_start_matches("/admin/messages/").trim_end_matches('/');
+            info!("Processing admin view of message {}", message_id);
+
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !admin::is_authorized(token) {
+                warn!("Rejected admin view of message {} without a valid admin token", message_id);
+                return Ok(json_error(403, "Admin token required"));
+            }
+
+            match handlers::admin_message_handler(&store, message_id).await {
+                Ok(Some(view)) => {
+                    let body = serde_json::to_string(&view)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Ok(None) => Ok(json_error(404, "Message not found")),
+                Err(err) => {
+                    error!("Failed to look up message {}: {}", message_id, err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("GET", path)
+            if path
+                .strip_prefix("/chat/messages/")
+                .is_some_and(|rest| rest.trim_end_matches('/').contains('/')) =>
+        {
+            let (room_id, message_id) = path
+                .trim_start_matches("/chat/messages/")
+                .trim_end_matches('/')
+                .split_once('/')
+                .unwrap();
+            info!("Processing GET message {} in room {}", message_id, room_id);
+
+            let viewer = event.query_string_parameters().first("user_id").map(str::to_string);
+            let message = handlers::get_message_handler(
+                &store,
+                room_id.into(),
+                message_id.into(),
+                viewer.as_deref(),
+            )
+            .await;
+            match message {
+                Ok(Some(message)) => {
                     let body = serde_json::to_string(&message)?;
                     Ok(Response::builder()
-                        .status(201)
+                        .status(200)
                         .header("Content-Type", "application/json")
                         .header("Access-Control-Allow-Origin", "*")
                         .header("Access-Control-Allow-Headers", "*")
                         .body(Body::Text(body))
                         .unwrap())
                 }
+                Ok(None) => Ok(Response::builder()
+                    .status(404)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .body(Body::Empty)
+                    .unwrap()),
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
                 Err(err) => {
-                    error!("Failed to post message: {}", err);
+                    error!("Failed to get message: {}", err);
                     Ok(Response::builder()
                         .status(500)
                         .header("Access-Control-Allow-Origin", "*")
@@ -99,22 +927,192 @@ async fn handler(event: Request) -> Result<Response<Body>, Error> {
                 }
             }
         }
+        ("PATCH", path)
+            if path
+                .strip_prefix("/chat/messages/")
+                .is_some_and(|rest| rest.trim_end_matches('/').contains('/')) =>
+        {
+            let (room_id, message_id) = path
+                .trim_start_matches("/chat/messages/")
+                .trim_end_matches('/')
+                .split_once('/')
+                .unwrap();
+            info!("Processing edit of message {} in room {}", message_id, room_id);
+            let token = event
+                .headers()
+                .get(admin::ADMIN_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok());
+            let is_admin = admin::is_authorized(token);
+            let request: EditMessageRequest = match parse_body(&event) {
+                Ok(request) => request,
+                Err(err) => return Ok(body_error(&err)),
+            };
+
+            match handlers::edit_message_handler(
+                &store,
+                &clients.context,
+                room_id.into(),
+                message_id.into(),
+                request,
+                is_admin,
+            )
+            .await
+            {
+                Ok(Some(message)) => {
+                    let body = serde_json::to_string(&message)?;
+                    Ok(Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body))
+                        .unwrap())
+                }
+                Ok(None) => Ok(json_error(404, "Message not found")),
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Forbidden(message)) => Ok(json_error(403, &message)),
+                Err(err) => {
+                    error!("Failed to edit message {}: {}", message_id, err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
+        ("DELETE", path)
+            if path
+                .strip_prefix("/chat/messages/")
+                .is_some_and(|rest| rest.trim_end_matches('/').contains('/')) =>
+        {
+            let (room_id, message_id) = path
+                .trim_start_matches("/chat/messages/")
+                .trim_end_matches('/')
+                .split_once('/')
+                .unwrap();
+            info!("Processing DELETE of message {} in room {}", message_id, room_id);
+            let params = event.query_string_parameters();
+
+            match handlers::delete_message_handler(
+                &store,
+                &clients.metrics,
+                room_id.into(),
+                message_id.into(),
+                params.first("user_id"),
+            )
+            .await
+            {
+                Ok(Some(_)) => Ok(Response::builder()
+                    .status(204)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .body(Body::Empty)
+                    .unwrap()),
+                Ok(None) => Ok(json_error(404, "Message not found")),
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::Forbidden(message)) => Ok(json_error(403, &message)),
+                Err(err) => {
+                    error!("Failed to delete message {}: {}", message_id, err);
+                    Ok(json_error(500, "Internal server error"))
+                }
+            }
+        }
         ("GET", path) if path.starts_with("/chat/messages/") => {
             info!("Processing GET messages for path: {}", path);
-            let room_id = path.trim_start_matches("/chat/messages/").to_string();
+            let room_id = RoomId::from(path.trim_start_matches("/chat/messages/"));
             info!("Extracted room_id: {}", room_id);
 
-            match handlers::get_messages_handler(&ddb, &tables, room_id).await {
+            let query = match parse_message_query(&event) {
+                Ok(query) => query,
+                Err(err) => {
+                    warn!("Rejecting message query: {}", err);
+                    return Ok(bad_request(&err));
+                }
+            };
+
+            // Already validated with the rest of the query
+            let fields = query.fields()?;
+            match handlers::get_messages_handler(&store, room_id, query).await {
                 Ok(response) => {
-                    let body = serde_json::to_string(&response)?;
+                    let etag = http_cache::messages_etag(&response.messages);
+                    let if_none_match =
+                        event.headers().get("if-none-match").and_then(|value| value.to_str().ok());
+                    if http_cache::if_none_match(if_none_match, &etag) {
+                        return Ok(Response::builder()
+                            .status(304)
+                            .header("ETag", etag)
+                            .header("Cache-Control", CACHE_POLICY.messages_cache_control())
+                            .header("Access-Control-Expose-Headers", "ETag")
+                            .header("Access-Control-Allow-Origin", "*")
+                            .header("Access-Control-Allow-Headers", "*")
+                            .body(Body::Empty)
+                            .unwrap());
+                    }
+
+                    let body = match &fields {
+                        Some(fields) => {
+                            serde_json::to_string(&handlers::project_messages(&response, fields)?)?
+                        }
+                        None => serde_json::to_string(&response)?,
+                    };
                     Ok(Response::builder()
                         .status(200)
                         .header("Content-Type", "application/json")
+                        .header(handlers::SERVER_TIME_HEADER, response.server_time.to_rfc3339())
+                        .header("ETag", etag)
+                        .header("Cache-Control", CACHE_POLICY.messages_cache_control())
+                        .header(
+                            "Access-Control-Expose-Headers",
+                            format!("{}, ETag", handlers::SERVER_TIME_HEADER),
+                        )
                         .header("Access-Control-Allow-Origin", "*")
                         .header("Access-Control-Allow-Headers", "*")
                         .body(Body::Text(body))
                         .unwrap())
                 }
+                Err(handlers::HandlerError::Validation(errors)) => {
+                    let errors: Vec<ApiError> = errors.into_iter().map(ApiError::from).collect();
+                    let body = serde_json::json!({
+                        "error": "Invalid request",
+                        "code": 400,
+                        "errors": errors,
+                    });
+                    Ok(Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .body(Body::Text(body.to_string()))
+                        .unwrap())
+                }
+                Err(handlers::HandlerError::BadRequest(message)) => Ok(bad_request(&message)),
+                Err(handlers::HandlerError::Throttled(message)) => Ok(json_error(429, &message)),
+                Err(handlers::HandlerError::Conflict(message)) => Ok(json_error(409, &message)),
                 Err(err) => {
                     error!("Failed to get messages: {}", err);
                     Ok(Response::builder()
@@ -131,8 +1129,11 @@ async fn handler(event: Request) -> Result<Response<Body>, Error> {
             Ok(Response::builder()
                 .status(204)
                 .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "GET,POST,OPTIONS")
-                .header("Access-Control-Allow-Headers", "content-type,authorization")
+                .header("Access-Control-Allow-Methods", "GET,POST,PUT,PATCH,DELETE,OPTIONS")
+                .header(
+                    "Access-Control-Allow-Headers",
+                    "content-type,authorization,x-admin-token,if-none-match",
+                )
                 .body(Body::Empty)
                 .unwrap())
         }
@@ -157,5 +1158,12 @@ async fn main() -> Result<(), Error> {
         .with_current_span(false)
         .with_span_list(false)
         .init();
+
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::REST)?;
+
+    // Verify table schemas once per cold start so misconfiguration fails the init phase
+    TABLES.verify(&clients::shared().await.ddb).await?;
+
     run(service_fn(handler)).await
 }
@@ -1,12 +1,17 @@
-use aws_sdk_apigatewaymanagement::{primitives::Blob, Client as ApiGatewayClient};
-use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-use backend::MetricsHelper;
-use chrono::{DateTime, Utc};
+use backend::{
+    broadcast, clients, clock, handlers, required_env,
+    retry_queue::RetryQueue,
+    search::{self, SearchIndex},
+    stream_event::{self, DynamoDBStreamEvent, StreamContext, DEFAULT_STREAM_RECORD_CONCURRENCY},
+    unfurl::Unfurler,
+    webhook::Webhook,
+};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-#[cfg(feature = "dev")]
-use reqwest::Client as HttpClient;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env, sync::LazyLock};
+use serde::Serialize;
+use std::{
+    env,
+    sync::{Arc, LazyLock, OnceLock},
+};
 use tracing::{error, info};
 
 // Static constants for required environment variables - will panic at startup if not set
@@ -14,58 +19,30 @@ static CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
     env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
 });
 
-static WS_API_ID: LazyLock<String> =
-    LazyLock::new(|| env::var("WS_API_ID").expect("WS_API_ID environment variable must be set"));
+// Messages table delivery counts are written back to (CHAT_MESSAGES_TABLE); unset skips them
+static MESSAGES_TABLE: LazyLock<Option<String>> =
+    LazyLock::new(|| env::var("CHAT_MESSAGES_TABLE").ok());
 
-static WS_STAGE: LazyLock<String> =
-    LazyLock::new(|| env::var("WS_STAGE").expect("WS_STAGE environment variable must be set"));
+// Optional outbound webhook for new messages (WEBHOOK_URL)
+static WEBHOOK: LazyLock<Option<Webhook>> = LazyLock::new(Webhook::from_env);
 
-static AWS_REGION: LazyLock<String> =
-    LazyLock::new(|| env::var("AWS_REGION").expect("AWS_REGION environment variable must be set"));
+// Optional at-least-once delivery (BROADCAST_DURABLE with RETRY_TABLE), set at startup
+static RETRY_QUEUE: OnceLock<Option<RetryQueue>> = OnceLock::new();
 
-// Optional: when set, broadcast locally via HTTP to the dev server
-#[cfg(feature = "dev")]
-static DEV_BROADCAST_URL: LazyLock<Option<String>> =
-    LazyLock::new(|| env::var("DEV_BROADCAST_URL").ok());
+// Optional full-text index new messages are added to (SEARCH_BACKEND)
+static SEARCH_INDEX: LazyLock<Option<Arc<dyn SearchIndex>>> = LazyLock::new(search::from_env);
 
-#[derive(Deserialize)]
-struct DynamoDBStreamEvent {
-    #[serde(rename = "Records")]
-    records: Vec<DynamoDBRecord>,
-}
-
-#[derive(Deserialize)]
-struct DynamoDBRecord {
-    #[serde(rename = "eventName")]
-    event_name: String,
-    dynamodb: Option<DynamoDBStreamRecord>,
-}
-
-#[derive(Deserialize)]
-struct DynamoDBStreamRecord {
-    #[serde(rename = "NewImage")]
-    new_image: Option<HashMap<String, AttributeValueWrapper>>,
-}
+// Optional link previews for new messages (ENABLE_UNFURL)
+static UNFURLER: LazyLock<Option<Unfurler>> = LazyLock::new(Unfurler::from_env);
 
-#[derive(Deserialize)]
-struct AttributeValueWrapper {
-    #[serde(rename = "S")]
-    s: Option<String>,
-    #[serde(rename = "N")]
-    n: Option<String>,
-}
-
-#[derive(Debug, Serialize)]
-struct ChatMessage {
-    id: String,
-    room_id: String,
-    user_id: String,
-    username: String,
-    message_text: String,
-    created_at: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    client_message_id: Option<String>,
-}
+// Rooms broadcast at once within a batch (STREAM_RECORD_CONCURRENCY)
+static STREAM_RECORD_CONCURRENCY: LazyLock<usize> = LazyLock::new(|| {
+    env::var("STREAM_RECORD_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|concurrency| *concurrency > 0)
+        .unwrap_or(DEFAULT_STREAM_RECORD_CONCURRENCY)
+});
 
 #[derive(Serialize)]
 struct LambdaResponse {
@@ -80,206 +57,36 @@ async fn function_handler(
 
     info!("DynamoDB Stream event with {} records", event.records.len());
 
-    // Initialize AWS clients
-    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    let ddb = DynamoDbClient::new(&aws_config);
-
-    // Optional HTTP client for dev per-connection push
-    #[cfg(feature = "dev")]
-    let http_client = HttpClient::new();
-
-    // Build WebSocket management client using static constants for prod
-    let ws_endpoint = format!(
-        "https://{}.execute-api.{}.amazonaws.com/{}",
-        &*WS_API_ID, &*AWS_REGION, &*WS_STAGE
-    );
-    let api_gateway_config = aws_sdk_apigatewaymanagement::config::Builder::from(&aws_config)
-        .endpoint_url(ws_endpoint)
-        .build();
-    let api_gateway = ApiGatewayClient::from_conf(api_gateway_config);
-
-    for record in event.records {
-        if let Err(e) = process_record(&ddb, &api_gateway, &CONNECTIONS_TABLE, record).await {
-            error!("Failed to process record: {:?}", e);
-            // Continue processing other records even if one fails
-        }
-    }
-
-    Ok(LambdaResponse { status_code: 200 })
-}
-
-async fn process_record(
-    ddb: &DynamoDbClient,
-    api_gateway: &ApiGatewayClient,
-    connections_table: &str,
-    record: DynamoDBRecord,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize metrics helper
-    let metrics = MetricsHelper::new().await;
-    // Only process INSERT events (new messages)
-    if record.event_name != "INSERT" {
-        info!("Skipping event: {}", record.event_name);
-        return Ok(());
-    }
-
-    let stream_record = record.dynamodb.ok_or("No dynamodb data in record")?;
-    let image = stream_record.new_image.ok_or("No NewImage in record")?;
-
-    // Extract message data from DynamoDB stream record
-    let room_id = image.get("room_id").and_then(|v| v.s.as_ref()).ok_or("Missing room_id")?;
-    let message_id = image.get("id").and_then(|v| v.s.as_ref()).ok_or("Missing id")?;
-    let username = image.get("username").and_then(|v| v.s.as_ref()).ok_or("Missing username")?;
-    let message_text =
-        image.get("message_text").and_then(|v| v.s.as_ref()).ok_or("Missing message_text")?;
-    let ts = image
-        .get("ts")
-        .and_then(|v| v.n.as_ref())
-        .and_then(|n| n.parse::<i64>().ok())
-        .ok_or("Missing or invalid ts")?;
-
-    // Extract user_id and client_message_id (may be missing for older messages)
-    let user_id = image
-        .get("user_id")
-        .and_then(|v| v.s.as_ref())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
-
-    let client_message_id = image.get("client_message_id").and_then(|v| v.s.as_ref()).cloned();
-
-    // Create the message payload to broadcast
-    let message_payload = ChatMessage {
-        id: message_id.clone(),
-        room_id: room_id.clone(),
-        user_id,
-        username: username.clone(),
-        message_text: message_text.clone(),
-        created_at: DateTime::from_timestamp_millis(ts).unwrap_or_else(Utc::now).to_rfc3339(),
-        client_message_id,
+    // AWS clients are cached across warm invocations
+    let clients = clients::shared().await;
+
+    let api_gateway = broadcast::management_clients(&clients.aws_config).await;
+
+    let context = StreamContext {
+        ddb: &clients.ddb,
+        api_gateway,
+        metrics: &clients.metrics,
+        connections_table: &CONNECTIONS_TABLE,
+        webhook: WEBHOOK.as_ref(),
+        retry: RETRY_QUEUE.get().and_then(Option::as_ref),
+        search: SEARCH_INDEX.as_deref(),
+        messages_table: MESSAGES_TABLE.as_deref(),
+        cipher: clients.cipher.as_deref(),
+        unfurler: UNFURLER.as_ref(),
     };
-
-    info!("Broadcasting message to room {}: {:?}", room_id, message_payload);
-
-    // Query for all connections in this room using GSI
-    let connections_result = ddb
-        .query()
-        .table_name(connections_table)
-        .index_name("room-index")
-        .key_condition_expression("room_id = :room_id")
-        .expression_attribute_values(":room_id", AttributeValue::S(room_id.clone()))
-        .send()
-        .await?;
-
-    let connections = connections_result.items.unwrap_or_default();
-    info!("Found {} connections in room {}", connections.len(), room_id);
-
-    // Broadcast to each connection and track metrics
-    let message_json = serde_json::to_string(&message_payload)?;
-    let message_blob = Blob::new(message_json.as_bytes());
-
-    let total_connections = connections.len() as i32;
-    let mut successful_sends = 0;
-
-    // Emit message sent metrics
-    metrics.emit_message_sent(room_id, message_text.len()).await;
-
-    // Send per connection according to its transport
-    for connection in connections {
-        // Determine transport; default to apigw if missing
-        let transport = connection
-            .get("transport")
-            .and_then(|v| v.as_s().ok())
-            .map(|s| s.as_str())
-            .unwrap_or("apigw");
-
-        match transport {
-            "apigw" => {
-                if let Some(AttributeValue::S(connection_id)) = connection.get("connection_id") {
-                    match api_gateway
-                        .post_to_connection()
-                        .connection_id(connection_id)
-                        .data(message_blob.clone())
-                        .send()
-                        .await
-                    {
-                        Ok(_) => {
-                            info!("Sent via API Gateway to connection {}", connection_id);
-                            successful_sends += 1;
-                        }
-                        Err(e) => {
-                            error!("Failed to send via API Gateway to {}: {:?}", connection_id, e);
-                            if let Some(service_err) = e.as_service_error() {
-                                if service_err.is_gone_exception() {
-                                    info!("Removing stale connection {}", connection_id);
-                                    if let Err(delete_err) = ddb
-                                        .delete_item()
-                                        .table_name(connections_table)
-                                        .key(
-                                            "connection_id",
-                                            AttributeValue::S(connection_id.clone()),
-                                        )
-                                        .send()
-                                        .await
-                                    {
-                                        error!(
-                                            "Failed to delete stale connection {}: {:?}",
-                                            connection_id, delete_err
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            #[cfg(feature = "dev")]
-            "dev" => {
-                // Use per-connection push_url
-                if let Some(AttributeValue::S(push_url)) = connection.get("push_url") {
-                    match http_client.post(push_url).json(&message_payload).send().await {
-                        Ok(resp) => {
-                            if resp.status().is_success() {
-                                info!("Sent via dev push_url to {}", push_url);
-                                successful_sends += 1;
-                            } else if resp.status().as_u16() == 404 || resp.status().as_u16() == 410
-                            {
-                                // Remove stale connection
-                                if let Some(AttributeValue::S(connection_id)) =
-                                    connection.get("connection_id")
-                                {
-                                    let _ = ddb
-                                        .delete_item()
-                                        .table_name(connections_table)
-                                        .key(
-                                            "connection_id",
-                                            AttributeValue::S(connection_id.clone()),
-                                        )
-                                        .send()
-                                        .await;
-                                }
-                            } else {
-                                error!("Dev push_url responded with status {}", resp.status());
-                            }
-                        }
-                        Err(e) => {
-                            error!("HTTP error sending to dev push_url {}: {:?}", push_url, e);
-                        }
-                    }
-                } else {
-                    error!("Missing push_url for dev transport connection");
-                }
-            }
-            _ => {
-                // Unknown transport; skip
-                info!("Skipping connection with unknown transport: {}", transport);
+    // Rooms are broadcast concurrently, each room's messages in order
+    stream_event::process_in_room_order(event.records, *STREAM_RECORD_CONCURRENCY, |record| {
+        let context = &context;
+        async move {
+            if let Err(e) = stream_event::process_record(context, record).await {
+                error!("Failed to process record: {:?}", e);
+                // Continue processing other records even if one fails
             }
         }
-    }
-
-    // Emit broadcast metrics
-    metrics.emit_message_broadcast(room_id, total_connections, successful_sends).await;
+    })
+    .await;
 
-    info!("Finished broadcasting message {} to room {}", message_id, room_id);
-    Ok(())
+    Ok(LambdaResponse { status_code: 200 })
 }
 
 #[tokio::main]
@@ -292,5 +99,18 @@ async fn main() -> Result<(), Error> {
         .with_span_list(false)
         .init();
 
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_BROADCAST)?;
+    // A malformed management endpoint would fail every post; fail the cold start instead
+    broadcast::check_endpoint_config(&clients::shared().await.metrics).await?;
+
+    // Verify the connections table once per cold start so misconfiguration fails the init phase
+    handlers::verify_connections_table(&clients::shared().await.ddb, &CONNECTIONS_TABLE).await?;
+
+    // Queued payloads are sealed like message text, when that's encrypted at rest
+    let cipher = clients::shared().await.cipher.clone();
+    let retry = RetryQueue::from_env(clock::system()).map(|queue| queue.with_cipher(cipher));
+    let _ = RETRY_QUEUE.set(retry);
+
     run(service_fn(function_handler)).await
 }
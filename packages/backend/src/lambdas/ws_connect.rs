@@ -1,15 +1,41 @@
-use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-use backend::MetricsHelper;
+use aws_sdk_dynamodb::types::AttributeValue;
+use backend::{
+    audit::{self, AuditEvent, AuditLog},
+    broadcast, clients, clock, connection_gauge,
+    connection_limit::{ConnectionLimit, IpConnectionLimit, TOO_MANY_CONNECTIONS},
+    handlers,
+    identity::AnonymousPolicy,
+    origin::{self, OriginCapture},
+    required_env,
+};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, env, sync::LazyLock};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use types::{ConnectionId, RoomId};
 
 // Static constant for required environment variable - will panic at startup if not set
 static CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
     env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
 });
 
+// Whether clients may connect without identifying themselves (ALLOW_ANONYMOUS)
+static ANONYMOUS_POLICY: LazyLock<AnonymousPolicy> = LazyLock::new(AnonymousPolicy::from_env);
+
+// Simultaneous connections allowed per user (MAX_CONNECTIONS_PER_USER)
+static CONNECTION_LIMIT: LazyLock<ConnectionLimit> = LazyLock::new(ConnectionLimit::from_env);
+
+// Simultaneous connections allowed per client IP (MAX_CONNECTIONS_PER_IP)
+static IP_CONNECTION_LIMIT: LazyLock<IpConnectionLimit> =
+    LazyLock::new(IpConnectionLimit::from_env);
+
+// Client IPs are only counted by their salted hash (ORIGIN_HASH_SALT)
+static ORIGIN: LazyLock<OriginCapture> = LazyLock::new(OriginCapture::from_env);
+
+// Optional connection audit log (AUDIT_TABLE)
+static AUDIT_LOG: LazyLock<Option<AuditLog>> =
+    LazyLock::new(|| AuditLog::from_env(clock::system()));
+
 #[derive(Debug, Deserialize, Serialize)]
 struct WebSocketEvent {
     #[serde(rename = "requestContext")]
@@ -21,10 +47,17 @@ struct WebSocketEvent {
 #[derive(Debug, Deserialize, Serialize)]
 struct RequestContext {
     #[serde(rename = "connectionId")]
-    connection_id: String,
+    connection_id: ConnectionId,
     #[serde(rename = "domainName")]
     domain_name: Option<String>,
     stage: Option<String>,
+    identity: Option<RequestIdentity>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RequestIdentity {
+    #[serde(rename = "sourceIp")]
+    source_ip: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -38,40 +71,109 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
 
     info!("WebSocket connection event: {:?}", event);
 
-    // Initialize AWS config, DynamoDB client, and metrics helper
-    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    let ddb = DynamoDbClient::new(&aws_config);
-    let metrics = MetricsHelper::new().await;
+    // AWS clients and metrics helper are cached across warm invocations
+    let clients = clients::shared().await;
+    let ddb = &clients.ddb;
+    let metrics = &clients.metrics;
 
     let connection_id = &event.request_context.connection_id;
     let domain_name = event.request_context.domain_name.as_deref().unwrap_or("unknown");
     let stage = event.request_context.stage.as_deref().unwrap_or("unknown");
 
     // Extract query parameters with defaults
-    let room_id = event
-        .query_string_parameters
-        .as_ref()
-        .and_then(|params| params.get("room_id"))
-        .map(|s| s.as_str())
-        .unwrap_or("general");
-
-    let username = event
-        .query_string_parameters
-        .as_ref()
-        .and_then(|params| params.get("username"))
-        .map(|s| s.as_str())
-        .unwrap_or("anon");
-
-    let user_id = event
-        .query_string_parameters
-        .as_ref()
-        .and_then(|params| params.get("userId"))
-        .map(|s| s.as_str())
-        .unwrap_or("anon");
-
-    let now = chrono::Utc::now().timestamp_millis();
+    let room_id = RoomId::from(
+        event
+            .query_string_parameters
+            .as_ref()
+            .and_then(|params| params.get("room_id"))
+            .map(|s| s.as_str())
+            .unwrap_or("general"),
+    );
+
+    let param = |name: &str| {
+        event
+            .query_string_parameters
+            .as_ref()
+            .and_then(|params| params.get(name))
+            .map(String::as_str)
+    };
+    // Anonymous connections are told apart by connection id
+    let identity = match ANONYMOUS_POLICY.resolve(param("userId"), param("username"), connection_id)
+    {
+        Ok(identity) => identity,
+        Err(message) => {
+            warn!("Rejecting connection {}: {}", connection_id, message);
+            return Ok(LambdaResponse { status_code: 401 });
+        }
+    };
+    // Like the connection limit, a malformed id can only be refused at the handshake
+    if let Err(e) = handlers::validate_user_id(&identity.user_id) {
+        warn!("Rejecting connection {}: {}", connection_id, e);
+        return Ok(LambdaResponse { status_code: 400 });
+    }
+    let (user_id, username) = (identity.user_id.as_str(), identity.username.as_str());
+
+    // API Gateway can't send a close frame from $connect, so the handshake is refused with 429.
+    // A failed count lets the connection through rather than locking everyone out.
+    match CONNECTION_LIMIT.admits(ddb, &CONNECTIONS_TABLE, user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(
+                "Rejecting connection {} ({}): user {} already holds {} connection(s)",
+                connection_id, TOO_MANY_CONNECTIONS, user_id, CONNECTION_LIMIT.max_per_user
+            );
+            return Ok(LambdaResponse { status_code: 429 });
+        }
+        Err(e) => error!("Failed to check connection limit for {}: {}", user_id, e),
+    }
+
+    let now = clients.context.clock.now().timestamp_millis();
     let ttl = now / 1000 + (60 * 60 * 24); // 24 hours from now
 
+    // The per-IP slot is taken last among the checks and given back if the connection isn't
+    // stored, so only stored connections hold one (ws_disconnect releases it by `ip_hash`)
+    let source_ip = event.request_context.identity.as_ref().and_then(|i| i.source_ip.as_deref());
+    let ip = origin::client_ip(source_ip, None);
+    let ip_hash = ip.map(|ip| ORIGIN.keyed_hash(&ip.to_string()));
+    if let Some(ip_hash) = &ip_hash {
+        match IP_CONNECTION_LIMIT.acquire(ddb, &CONNECTIONS_TABLE, ip_hash, now / 1000).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    "Rejecting connection {} ({}): IP {} already holds {} connection(s)",
+                    connection_id, TOO_MANY_CONNECTIONS, ip_hash, IP_CONNECTION_LIMIT.max_per_ip
+                );
+                return Ok(LambdaResponse { status_code: 429 });
+            }
+            Err(e) => error!("Failed to check IP connection limit for {}: {}", ip_hash, e),
+        }
+    }
+    let release_ip = || async {
+        if let Some(ip_hash) = &ip_hash {
+            if let Err(e) = IP_CONNECTION_LIMIT.release(ddb, &CONNECTIONS_TABLE, ip_hash).await {
+                error!("{}", e);
+            }
+        }
+    };
+
+    // Deployments that audit connections need a record of every one, so a connection that
+    // can't be audited is refused
+    if let Err(e) = audit::audit_connection(
+        ddb,
+        AUDIT_LOG.as_ref(),
+        AuditEvent::Connect,
+        connection_id,
+        &identity,
+        &room_id,
+        ip,
+    )
+    .await
+    {
+        error!("Refusing connection {}: {}", connection_id, e);
+        release_ip().await;
+        return Ok(LambdaResponse { status_code: 500 });
+    }
+
     info!(
         "Connecting user '{}' to room '{}' with connectionId: {}",
         username, room_id, connection_id
@@ -81,7 +183,7 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
     let connections_table = &*CONNECTIONS_TABLE;
 
     let mut item = HashMap::new();
-    item.insert("connection_id".to_string(), AttributeValue::S(connection_id.clone()));
+    item.insert("connection_id".to_string(), AttributeValue::S(connection_id.to_string()));
     item.insert("room_id".to_string(), AttributeValue::S(room_id.to_string()));
     item.insert("user_id".to_string(), AttributeValue::S(user_id.to_string())); // Store user_id
     item.insert("username".to_string(), AttributeValue::S(username.to_string()));
@@ -91,6 +193,9 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
     // Explicitly mark transport for broadcaster
     item.insert("transport".to_string(), AttributeValue::S("apigw".to_string()));
     item.insert("ttl".to_string(), AttributeValue::N(ttl.to_string()));
+    if let Some(ip_hash) = &ip_hash {
+        item.insert("ip_hash".to_string(), AttributeValue::S(ip_hash.clone()));
+    }
 
     match ddb.put_item().table_name(connections_table).set_item(Some(item)).send().await {
         Ok(_) => {
@@ -100,18 +205,33 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
             );
 
             // Emit connection metrics
-            metrics.emit_connection_event("connect", room_id, None).await;
+            let count =
+                connection_gauge::count_after_change(ddb, &CONNECTIONS_TABLE, &room_id).await;
+            metrics.emit_connection_event("connect", &room_id, count).await;
+
+            // Tell the room who's there now. Best effort: the next join or leave corrects it.
+            let api_gateway = broadcast::management_clients(&clients.aws_config).await;
+            if let Err(e) = broadcast::broadcast_presence(
+                ddb,
+                api_gateway,
+                &CONNECTIONS_TABLE,
+                &room_id,
+                Some(connection_id.as_str()),
+                now / 1000,
+            )
+            .await
+            {
+                error!("Failed to broadcast presence in room {}: {}", room_id, e);
+            }
 
             Ok(LambdaResponse { status_code: 200 })
         }
         Err(e) => {
             error!("Failed to store connection: {:?}", e);
+            release_ip().await;
 
             // Emit error metric
-            let mut dimensions = HashMap::new();
-            dimensions.insert("ErrorType".to_string(), "DatabaseError".to_string());
-            dimensions.insert("RoomId".to_string(), room_id.to_string());
-            metrics.emit_count("ConnectionErrors", 1.0, Some(dimensions)).await;
+            metrics.emit_connection_error("connect", &room_id).await;
 
             Ok(LambdaResponse { status_code: 500 })
         }
@@ -128,5 +248,12 @@ async fn main() -> Result<(), Error> {
         .with_span_list(false)
         .init();
 
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_CONNECT)?;
+    broadcast::check_endpoint_config(&clients::shared().await.metrics).await?;
+
+    // Verify the connections table once per cold start so misconfiguration fails the init phase
+    handlers::verify_connections_table(&clients::shared().await.ddb, &CONNECTIONS_TABLE).await?;
+
     run(service_fn(function_handler)).await
 }
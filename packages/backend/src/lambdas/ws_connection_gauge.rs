@@ -0,0 +1,58 @@
+use backend::{clients, connection_gauge, required_env};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    env,
+    sync::{LazyLock, Mutex},
+};
+use tracing::info;
+
+// Static constants for required environment variables - will panic at startup if not set
+static CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
+    env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
+});
+
+// Rooms with connections on the last run, kept while the container stays warm. A cold start
+// begins empty, so a room emptied just before one goes without its final 0.
+static LAST_TICK: LazyLock<Mutex<BTreeSet<String>>> = LazyLock::new(Mutex::default);
+
+// Runs on a schedule; the event itself carries nothing we need
+async fn function_handler(_event: LambdaEvent<Value>) -> Result<Value, Error> {
+    // AWS clients and metrics helper are cached across warm invocations
+    let clients = clients::shared().await;
+
+    let last_tick = LAST_TICK.lock().unwrap().clone();
+    let counts = connection_gauge::emit_connection_gauges(
+        &clients.ddb,
+        &CONNECTIONS_TABLE,
+        &last_tick,
+        &clients.metrics,
+    )
+    .await?;
+    *LAST_TICK.lock().unwrap() = connection_gauge::connected_rooms(&counts);
+    info!(
+        "Emitted ActiveConnections for {} rooms, {} connections in total",
+        counts.len(),
+        counts.values().sum::<u32>()
+    );
+    Ok(serde_json::to_value(counts)?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Initialize tracing with JSON format for CloudWatch
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .json()
+        .with_current_span(false)
+        .with_span_list(false)
+        .init();
+
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_CONNECTION_GAUGE)?;
+
+    LazyLock::force(&CONNECTIONS_TABLE);
+
+    run(service_fn(function_handler)).await
+}
@@ -1,6 +1,26 @@
+use aws_sdk_apigatewaymanagement::primitives::Blob;
+use aws_sdk_apigatewaymanagement::Client as ApiGatewayClient;
+use backend::{
+    broadcast, clients,
+    echo::EchoMode,
+    handlers, required_env, ws_error,
+    ws_policy::{self, WsPolicy, WsVerdict},
+};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::{env, sync::LazyLock};
+use tracing::{error, info, warn};
+use types::WsError;
+
+// Static constants for required environment variables - will panic at startup if not set
+static CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
+    env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
+});
+
+static WS_POLICY: LazyLock<WsPolicy> = LazyLock::new(WsPolicy::from_env);
+
+// Whether `echo` frames are answered (WS_ECHO_ENABLED)
+static WS_ECHO: LazyLock<EchoMode> = LazyLock::new(EchoMode::from_env);
 
 #[derive(Debug, Deserialize, Serialize)]
 struct WebSocketEvent {
@@ -21,6 +41,19 @@ struct LambdaResponse {
     status_code: i32,
 }
 
+// Tell the sender what went wrong with its frame
+async fn send_error(api_gateway: &ApiGatewayClient, connection_id: &str, error: &WsError) {
+    if let Err(e) = api_gateway
+        .post_to_connection()
+        .connection_id(connection_id)
+        .data(Blob::new(ws_error::encode(error)))
+        .send()
+        .await
+    {
+        error!("Failed to notify connection {}: {:?}", connection_id, e);
+    }
+}
+
 async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaResponse, Error> {
     let (event, _context) = event.into_parts();
 
@@ -29,10 +62,66 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
 
     info!("WebSocket default route - connectionId: {}, message: {}", connection_id, body);
 
-    // For now, this is a no-op handler that just logs the message
-    // In the future, this could handle specific message types or echo back
+    let clients = clients::shared().await;
+    let now = clients.context.clock.now().timestamp();
+    let usage =
+        match ws_policy::record_frame(&clients.ddb, &CONNECTIONS_TABLE, connection_id, now).await {
+            Ok(usage) => usage,
+            Err(e) => {
+                // Don't drop traffic because the counter is unavailable
+                error!("Failed to apply WebSocket policy: {}", e);
+                return Ok(LambdaResponse { status_code: 200 });
+            }
+        };
+
+    let (violation, closing) = match WS_POLICY.judge(body.len(), usage, now) {
+        WsVerdict::Allow => {
+            // Echo frames go back to the sender alone; everything else is only logged for now
+            let api_gateway = broadcast::api_gateway_client(&clients.aws_config).await;
+            if let Some(reply) = WS_ECHO.reply(body) {
+                if let Err(e) = api_gateway
+                    .post_to_connection()
+                    .connection_id(connection_id)
+                    .data(Blob::new(reply))
+                    .send()
+                    .await
+                {
+                    error!("Failed to echo to connection {}: {:?}", connection_id, e);
+                }
+            } else if let Err(bad_frame) = ws_error::check_frame(body, WS_POLICY.max_frame_bytes)
+                .and_then(|()| ws_error::parse_client_frame(body))
+            {
+                warn!("Connection {} sent a bad frame: {}", connection_id, bad_frame.message);
+                send_error(api_gateway, connection_id, &bad_frame).await;
+                return Ok(LambdaResponse { status_code: 400 });
+            }
+            return Ok(LambdaResponse { status_code: 200 });
+        }
+        WsVerdict::Warn(violation) => (violation, false),
+        WsVerdict::Close(violation) => (violation, true),
+    };
 
-    Ok(LambdaResponse { status_code: 200 })
+    warn!(
+        "Connection {} violated WebSocket policy: {} (closing: {})",
+        connection_id, violation.message, closing
+    );
+    clients.metrics.emit_ws_rate_limited(if closing { "close" } else { "warn" }).await;
+
+    // API Gateway can't send a close code itself, so the client learns why from this frame
+    let api_gateway = broadcast::api_gateway_client(&clients.aws_config).await;
+    send_error(api_gateway, connection_id, &violation).await;
+
+    if closing {
+        if let Err(e) = api_gateway.delete_connection().connection_id(connection_id).send().await {
+            error!("Failed to close connection {}: {:?}", connection_id, e);
+        }
+    } else if let Err(e) =
+        ws_policy::record_warning(&clients.ddb, &CONNECTIONS_TABLE, connection_id).await
+    {
+        error!("{}", e);
+    }
+
+    Ok(LambdaResponse { status_code: 429 })
 }
 
 #[tokio::main]
@@ -45,5 +134,13 @@ async fn main() -> Result<(), Error> {
         .with_span_list(false)
         .init();
 
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_DEFAULT)?;
+    // A malformed management endpoint would fail every post; fail the cold start instead
+    broadcast::check_endpoint_config(&clients::shared().await.metrics).await?;
+
+    // Verify the connections table once per cold start so misconfiguration fails the init phase
+    handlers::verify_connections_table(&clients::shared().await.ddb, &CONNECTIONS_TABLE).await?;
+
     run(service_fn(function_handler)).await
 }
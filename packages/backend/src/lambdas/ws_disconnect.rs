@@ -1,15 +1,31 @@
-use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-use backend::MetricsHelper;
+use aws_sdk_dynamodb::types::AttributeValue;
+use backend::{
+    audit::{self, AuditEvent, AuditLog},
+    broadcast, clients, clock, connection_gauge,
+    connection_limit::IpConnectionLimit,
+    handlers,
+    identity::{display_name_fallback, Identity},
+    origin, required_env,
+};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, env, sync::LazyLock};
 use tracing::{error, info};
+use types::{ConnectionId, RoomId};
 
 // Static constant for required environment variable - will panic at startup if not set
 static CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
     env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
 });
 
+// Optional connection audit log (AUDIT_TABLE)
+static AUDIT_LOG: LazyLock<Option<AuditLog>> =
+    LazyLock::new(|| AuditLog::from_env(clock::system()));
+
+// Per-IP connection counters taken in ws_connect (MAX_CONNECTIONS_PER_IP)
+static IP_CONNECTION_LIMIT: LazyLock<IpConnectionLimit> =
+    LazyLock::new(IpConnectionLimit::from_env);
+
 #[derive(Debug, Deserialize, Serialize)]
 struct WebSocketEvent {
     #[serde(rename = "requestContext")]
@@ -19,7 +35,14 @@ struct WebSocketEvent {
 #[derive(Debug, Deserialize, Serialize)]
 struct RequestContext {
     #[serde(rename = "connectionId")]
-    connection_id: String,
+    connection_id: ConnectionId,
+    identity: Option<RequestIdentity>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RequestIdentity {
+    #[serde(rename = "sourceIp")]
+    source_ip: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -33,43 +56,95 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
 
     info!("WebSocket disconnection event: {:?}", event);
 
-    // Initialize AWS config, DynamoDB client, and metrics helper
-    let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    let ddb = DynamoDbClient::new(&aws_config);
-    let metrics = MetricsHelper::new().await;
+    // AWS clients and metrics helper are cached across warm invocations
+    let clients = clients::shared().await;
+    let ddb = &clients.ddb;
+    let metrics = &clients.metrics;
 
     let connection_id = &event.request_context.connection_id;
 
     info!("Disconnecting connectionId: {}", connection_id);
 
-    // First, get connection info to extract room_id for metrics
+    // First, get connection info to extract room_id (and, for the audit log, the user)
     let mut key = HashMap::new();
-    key.insert("connection_id".to_string(), AttributeValue::S(connection_id.clone()));
+    key.insert("connection_id".to_string(), AttributeValue::S(connection_id.to_string()));
 
-    let room_id = match ddb
+    let connection = ddb
         .get_item()
         .table_name(&*CONNECTIONS_TABLE)
         .set_key(Some(key.clone()))
         .send()
         .await
-    {
-        Ok(response) => response
-            .item
-            .as_ref()
-            .and_then(|item| item.get("room_id"))
+        .ok()
+        .and_then(|response| response.item)
+        .unwrap_or_default();
+    let attribute = |name: &str| {
+        connection
+            .get(name)
             .and_then(|attr| attr.as_s().ok())
             .map(|s| s.to_string())
-            .unwrap_or_else(|| "unknown".to_string()),
-        Err(_) => "unknown".to_string(),
+            .unwrap_or_else(|| "unknown".to_string())
     };
+    let room_id = RoomId::from(attribute("room_id"));
+
+    // The connection is gone either way, so a failed audit write is only logged
+    let username =
+        connection.get("username").and_then(|attr| attr.as_s().ok()).cloned().unwrap_or_else(
+            || {
+                display_name_fallback(
+                    connection.get("user_id").and_then(|attr| attr.as_s().ok()).map(String::as_str),
+                )
+            },
+        );
+    let identity = Identity { user_id: attribute("user_id"), username };
+    let source_ip = event.request_context.identity.as_ref().and_then(|i| i.source_ip.as_deref());
+    if let Err(e) = audit::audit_connection(
+        ddb,
+        AUDIT_LOG.as_ref(),
+        AuditEvent::Disconnect,
+        connection_id,
+        &identity,
+        &room_id,
+        origin::client_ip(source_ip, None),
+    )
+    .await
+    {
+        error!("Failed to audit disconnect of {}: {}", connection_id, e);
+    }
 
     // Delete connection from DynamoDB using static constant
     match ddb.delete_item().table_name(&*CONNECTIONS_TABLE).set_key(Some(key)).send().await {
         Ok(_) => {
             info!("Successfully removed connection {}", connection_id);
 
+            // Free the client IP's slot, if the connection took one
+            if let Some(ip_hash) = connection.get("ip_hash").and_then(|attr| attr.as_s().ok()) {
+                if let Err(e) = IP_CONNECTION_LIMIT.release(ddb, &CONNECTIONS_TABLE, ip_hash).await
+                {
+                    error!("{}", e);
+                }
+            }
+
             // Emit disconnection metrics
-            metrics.emit_connection_event("disconnect", &room_id, None).await;
+            let count =
+                connection_gauge::count_after_change(ddb, &CONNECTIONS_TABLE, &room_id).await;
+            metrics.emit_connection_event("disconnect", &room_id, count).await;
+
+            // Tell whoever's left. Best effort: the next join or leave corrects it.
+            let api_gateway = broadcast::management_clients(&clients.aws_config).await;
+            let now = clients.context.clock.now().timestamp();
+            if let Err(e) = broadcast::broadcast_presence(
+                ddb,
+                api_gateway,
+                &CONNECTIONS_TABLE,
+                &room_id,
+                None,
+                now,
+            )
+            .await
+            {
+                error!("Failed to broadcast presence in room {}: {}", room_id, e);
+            }
 
             Ok(LambdaResponse { status_code: 200 })
         }
@@ -77,10 +152,7 @@ async fn function_handler(event: LambdaEvent<WebSocketEvent>) -> Result<LambdaRe
             error!("Failed to remove connection: {:?}", e);
 
             // Emit error metric
-            let mut dimensions = HashMap::new();
-            dimensions.insert("ErrorType".to_string(), "DatabaseError".to_string());
-            dimensions.insert("RoomId".to_string(), room_id.clone());
-            metrics.emit_count("DisconnectionErrors", 1.0, Some(dimensions)).await;
+            metrics.emit_connection_error("disconnect", &room_id).await;
 
             // Even if deletion fails, we should return 200 to avoid reconnection loops
             Ok(LambdaResponse { status_code: 200 })
@@ -98,5 +170,12 @@ async fn main() -> Result<(), Error> {
         .with_span_list(false)
         .init();
 
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_DISCONNECT)?;
+    broadcast::check_endpoint_config(&clients::shared().await.metrics).await?;
+
+    // Verify the connections table once per cold start so misconfiguration fails the init phase
+    handlers::verify_connections_table(&clients::shared().await.ddb, &CONNECTIONS_TABLE).await?;
+
     run(service_fn(function_handler)).await
 }
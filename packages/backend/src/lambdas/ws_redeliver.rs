@@ -0,0 +1,47 @@
+use backend::{broadcast, clients, clock, required_env, retry_queue::RetryQueue};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde_json::Value;
+use std::sync::OnceLock;
+use tracing::info;
+
+// Required configuration, set at startup; will panic if durable broadcast isn't configured
+static RETRY_QUEUE: OnceLock<RetryQueue> = OnceLock::new();
+
+// Runs on a schedule; the event itself carries nothing we need
+async fn function_handler(_event: LambdaEvent<Value>) -> Result<Value, Error> {
+    // AWS clients are cached across warm invocations
+    let clients = clients::shared().await;
+    let api_gateway = broadcast::management_clients(&clients.aws_config).await;
+
+    let queue = RETRY_QUEUE.get().expect("the retry queue is set at startup");
+    let stats = queue.redeliver(&clients.ddb, api_gateway).await?;
+    info!(
+        "Redelivery pass: {} pending, {} delivered, {} gone, {} failed",
+        stats.pending, stats.delivered, stats.gone, stats.failed
+    );
+    Ok(serde_json::to_value(stats)?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Initialize tracing with JSON format for CloudWatch
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .json()
+        .with_current_span(false)
+        .with_span_list(false)
+        .init();
+
+    // Fail the cold start on missing configuration, before anything reads it
+    required_env::validate_env(required_env::WS_REDELIVER)?;
+    // A malformed management endpoint would fail every post; fail the cold start instead
+    broadcast::check_endpoint_config(&clients::shared().await.metrics).await?;
+
+    // Sealed payloads are opened with the same cipher the broadcaster sealed them with
+    let queue = RetryQueue::from_env(clock::system())
+        .expect("BROADCAST_DURABLE and RETRY_TABLE environment variables must be set")
+        .with_cipher(clients::shared().await.cipher.clone());
+    let _ = RETRY_QUEUE.set(queue);
+
+    run(service_fn(function_handler)).await
+}
@@ -1,331 +1,345 @@
-use serde_json::json;
-use std::{collections::HashMap, env};
-
-pub mod handlers {
-    use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-    use chrono::Utc;
-    use std::{collections::HashMap, env};
-    use tracing::info;
-    use types::{ChatMessage, GetMessagesResponse, HealthCheck, HealthStatus, SendMessageRequest};
-    use uuid::Uuid;
-
-    // Table names structure
-    #[derive(Clone)]
-    pub struct Tables {
-        pub rooms: String,
-        pub messages: String,
-    }
-
-    impl Tables {
-        pub fn from_env() -> Self {
-            Self {
-                rooms: env::var("CHAT_ROOMS_TABLE").expect("CHAT_ROOMS_TABLE must be set"),
-                messages: env::var("CHAT_MESSAGES_TABLE").expect("CHAT_MESSAGES_TABLE must be set"),
-            }
-        }
-    }
-
-    // Shared validation functions
-    pub fn validate_username(username: &str) -> Result<String, String> {
-        let trimmed = username.trim();
-        if trimmed.is_empty() {
-            return Err("Username cannot be empty".to_string());
-        }
-        if trimmed.len() > 50 {
-            return Err("Username cannot be longer than 50 characters".to_string());
-        }
-        Ok(trimmed.to_string())
-    }
+use broadcast::StageDeliveries;
+use clock::Clock;
+use metric_manifest::MetricDescriptor;
+use metric_sink::{MetricRecord, MetricSink};
+use metric_snapshot::{MetricCounters, MetricsSnapshot};
+use metric_timer::TimerGuard;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+pub mod access_log;
+pub mod admin;
+pub mod audit;
+pub mod body_log;
+pub mod broadcast;
+pub mod clients;
+pub mod clock;
+pub mod codec;
+pub mod compaction;
+pub mod connection_gauge;
+pub mod connection_limit;
+pub mod ddb;
+pub mod dependencies;
+pub mod echo;
+pub mod encryption;
+pub mod export;
+pub mod handlers;
+pub mod http_cache;
+pub mod id_generator;
+pub mod identity;
+pub mod known_rooms;
+pub mod message_cache;
+pub mod metric_manifest;
+pub mod metric_sink;
+pub mod metric_snapshot;
+pub mod metric_timer;
+pub mod migrate;
+pub mod moderation;
+pub mod origin;
+pub mod page_cursor;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod request_context;
+pub mod required_env;
+pub mod retry_queue;
+pub mod search;
+pub mod selftest;
+pub mod send_order;
+pub mod send_queue;
+pub mod store;
+pub mod stream_event;
+#[cfg(test)]
+mod test_support;
+pub mod text_pipeline;
+pub mod typing;
+pub mod unfurl;
+pub mod user_connections;
+pub mod validation;
+pub mod webhook;
+pub mod write_limiter;
+pub mod ws_error;
+pub mod ws_policy;
+
+/// Metrics to be written together, as few EMF records as their dimensions allow, when
+/// `emit` is called. From `MetricsHelper::batch`.
+pub struct MetricsBatch {
+    sink: Arc<dyn MetricSink>,
+    records: Vec<MetricRecord>,
+}
 
-    pub fn validate_message_text(message_text: &str) -> Result<String, String> {
-        let trimmed = message_text.trim();
-        if trimmed.is_empty() {
-            return Err("Message text cannot be empty".to_string());
-        }
-        if trimmed.len() > 500 {
-            return Err("Message text cannot be longer than 500 characters".to_string());
-        }
-        Ok(trimmed.to_string())
+impl MetricsBatch {
+    /// Add a data point; `dimensions` as for `MetricsHelper::emit_count`
+    pub fn add(
+        &mut self,
+        metric_name: &str,
+        value: f64,
+        unit: &str,
+        dimensions: Option<HashMap<String, String>>,
+    ) -> &mut Self {
+        self.records.push(MetricRecord::new(
+            metric_name,
+            value,
+            unit,
+            dimensions.unwrap_or_default(),
+        ));
+        self
     }
 
-    pub fn validate_room_id(room_id: &str) -> Result<String, String> {
-        let trimmed = room_id.trim();
-        if trimmed.is_empty() {
-            return Err("Room ID cannot be empty".to_string());
-        }
-        Ok(trimmed.to_lowercase())
+    pub fn count(
+        &mut self,
+        metric_name: &str,
+        value: f64,
+        dimensions: Option<HashMap<String, String>>,
+    ) -> &mut Self {
+        self.add(metric_name, value, "Count", dimensions)
     }
 
-    // Shared business logic functions
-    pub async fn health_handler() -> Result<HealthCheck, String> {
-        let health_check = HealthCheck {
-            status: HealthStatus::Healthy,
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            timestamp: Utc::now(),
-        };
-        Ok(health_check)
+    pub fn gauge(
+        &mut self,
+        metric_name: &str,
+        value: f64,
+        dimensions: Option<HashMap<String, String>>,
+    ) -> &mut Self {
+        self.add(metric_name, value, "None", dimensions)
     }
 
-    pub async fn ensure_room_exists(
-        ddb: &DynamoDbClient,
-        tables: &Tables,
-        room_id: &str,
-    ) -> Result<(), String> {
-        let get_item_result = ddb
-            .get_item()
-            .table_name(&tables.rooms)
-            .key("id", AttributeValue::S(room_id.to_string()))
-            .send()
-            .await;
-
-        match get_item_result {
-            Ok(output) => {
-                if output.item.is_none() {
-                    // Room doesn't exist, create it
-                    let now = Utc::now();
-                    let room_name = if room_id == "general" {
-                        "General".to_string()
-                    } else {
-                        room_id.to_string()
-                    };
-
-                    let mut item = HashMap::new();
-                    item.insert("id".to_string(), AttributeValue::S(room_id.to_string()));
-                    item.insert("name".to_string(), AttributeValue::S(room_name));
-                    item.insert("created_at_iso".to_string(), AttributeValue::S(now.to_rfc3339()));
-                    item.insert(
-                        "created_at_epoch".to_string(),
-                        AttributeValue::N(now.timestamp().to_string()),
-                    );
-
-                    ddb.put_item()
-                        .table_name(&tables.rooms)
-                        .set_item(Some(item))
-                        .condition_expression("attribute_not_exists(id)")
-                        .send()
-                        .await
-                        .map_err(|e| format!("Failed to create room: {:?}", e))?;
-
-                    info!("Created new room: {}", room_id);
-                }
-                Ok(())
-            }
-            Err(e) => Err(format!("DynamoDB error: {:?}", e)),
-        }
+    pub fn duration_ms(
+        &mut self,
+        metric_name: &str,
+        duration_ms: f64,
+        dimensions: Option<HashMap<String, String>>,
+    ) -> &mut Self {
+        self.add(metric_name, duration_ms, "Milliseconds", dimensions)
     }
 
-    pub async fn post_message_handler(
-        ddb: &DynamoDbClient,
-        tables: &Tables,
-        request: SendMessageRequest,
-    ) -> Result<ChatMessage, String> {
-        // Validate input
-        let room_id = validate_room_id(&request.room_id)?;
-        let user_id = request.user_id.clone();
-        let username = validate_username(&request.username)?;
-        let message_text = validate_message_text(&request.message_text)?;
-
-        // Ensure room exists
-        ensure_room_exists(ddb, tables, &room_id).await?;
-
-        // Create message
-        let now = Utc::now();
-        let message_id = Uuid::new_v4().to_string();
-        let timestamp_millis = now.timestamp_millis();
-
-        let mut item = HashMap::new();
-        item.insert("id".to_string(), AttributeValue::S(message_id.clone()));
-        item.insert("room_id".to_string(), AttributeValue::S(room_id.clone()));
-        item.insert("user_id".to_string(), AttributeValue::S(user_id.clone()));
-        item.insert("username".to_string(), AttributeValue::S(username.clone()));
-        item.insert("message_text".to_string(), AttributeValue::S(message_text.clone()));
-        item.insert("ts".to_string(), AttributeValue::N(timestamp_millis.to_string()));
-        item.insert("created_at_iso".to_string(), AttributeValue::S(now.to_rfc3339()));
-
-        // Store client_message_id if provided
-        if let Some(client_message_id) = &request.client_message_id {
-            item.insert(
-                "client_message_id".to_string(),
-                AttributeValue::S(client_message_id.clone()),
-            );
+    /// Write out everything added
+    pub fn emit(self) {
+        if !self.records.is_empty() {
+            self.sink.emit_batch(&self.records);
         }
-
-        // Store message in DynamoDB
-        ddb.put_item()
-            .table_name(&tables.messages)
-            .set_item(Some(item))
-            .send()
-            .await
-            .map_err(|e| format!("DynamoDB error: {:?}", e))?;
-
-        info!("Stored message {} in room {}", message_id, room_id);
-
-        // Create response message
-        let message = ChatMessage {
-            id: message_id.clone(),
-            room_id: room_id.clone(),
-            user_id: user_id.clone(),
-            username: username.clone(),
-            message_text: message_text.clone(),
-            created_at: now,
-            client_message_id: request.client_message_id.clone(),
-        };
-
-        Ok(message)
-    }
-
-    pub async fn get_messages_handler(
-        ddb: &DynamoDbClient,
-        tables: &Tables,
-        room_id: String,
-    ) -> Result<GetMessagesResponse, String> {
-        let room_id = validate_room_id(&room_id)?;
-
-        // Query messages from DynamoDB
-        let result = ddb
-            .query()
-            .table_name(&tables.messages)
-            .key_condition_expression("room_id = :room_id")
-            .expression_attribute_values(":room_id", AttributeValue::S(room_id.clone()))
-            .scan_index_forward(true) // Oldest first
-            .limit(25)
-            .send()
-            .await
-            .map_err(|e| format!("DynamoDB error: {:?}", e))?;
-
-        let messages: Vec<ChatMessage> = result
-            .items
-            .unwrap_or_default()
-            .into_iter()
-            .filter_map(|item| {
-                // Convert DynamoDB item to ChatMessage struct
-                let id = item.get("id")?.as_s().ok()?.clone();
-                let user_id = item
-                    .get("user_id")
-                    .and_then(|v| v.as_s().ok())
-                    .map(|s| s.to_string())
-                    .unwrap_or_else(|| "unknown".to_string());
-                let username = item.get("username")?.as_s().ok()?.clone();
-                let message_text = item.get("message_text")?.as_s().ok()?.clone();
-                let ts = item.get("ts")?.as_n().ok()?.parse::<i64>().ok()?;
-                let created_at = chrono::DateTime::from_timestamp_millis(ts)?;
-                let client_message_id =
-                    item.get("client_message_id").and_then(|v| v.as_s().ok()).cloned();
-
-                Some(ChatMessage {
-                    id,
-                    room_id: room_id.clone(),
-                    user_id,
-                    username,
-                    message_text,
-                    created_at: created_at.with_timezone(&Utc),
-                    client_message_id,
-                })
-            })
-            .collect();
-
-        info!("Retrieved {} messages for room {}", messages.len(), room_id);
-
-        let response = GetMessagesResponse { room_id, messages };
-        Ok(response)
     }
 }
 
+// Running totals every helper in the process adds to, so a helper built on the spot to emit
+// one metric still counts towards the server's snapshot
+static PROCESS_COUNTERS: LazyLock<Arc<MetricCounters>> = LazyLock::new(Arc::default);
+
 #[derive(Clone)]
 pub struct MetricsHelper {
-    namespace: String,
-    stage: String,
+    // EMF by default; METRICS_SINK=tracing for tracing events
+    sink: Arc<dyn MetricSink>,
+    // Running totals behind `snapshot`; the process's unless `with_own_counters`
+    counters: Arc<MetricCounters>,
 }
 
 impl MetricsHelper {
     pub async fn new() -> Self {
+        Self::with_clock(clock::system())
+    }
+
+    /// Like `new`, with records timestamped by `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         let stage = env::var("STAGE").unwrap_or_else(|_| "unknown".to_string());
         let namespace = format!("SwflcodersChat/{}", stage);
 
-        Self { namespace, stage }
+        Self::with_sink(metric_sink::from_env(namespace, stage, clock))
     }
 
-    /// Emit a count metric using EMF
+    /// Like `new`, but fails instead of falling back to an `unknown` stage
+    pub fn try_new() -> Result<Self, String> {
+        let stage = env::var("STAGE").map_err(|_| "STAGE must be set".to_string())?;
+        if stage.trim().is_empty() {
+            return Err("STAGE must not be empty".to_string());
+        }
+        let namespace = format!("SwflcodersChat/{}", stage);
+
+        Ok(Self::with_sink(metric_sink::from_env(namespace, stage, clock::system())))
+    }
+
+    /// Send metrics to a sink of the caller's choosing rather than the one `METRICS_SINK` picks
+    pub fn with_sink(sink: Arc<dyn MetricSink>) -> Self {
+        Self { sink, counters: PROCESS_COUNTERS.clone() }
+    }
+
+    /// Like `with_sink`, with totals of its own (shared by its clones) instead of the
+    /// process's, for counting exactly what one caller emits
+    pub fn with_own_counters(sink: Arc<dyn MetricSink>) -> Self {
+        Self { sink, counters: Arc::default() }
+    }
+
+    /// Totals of what this process has emitted so far: messages posted, connections open,
+    /// broadcast outcomes and errors by metric
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.counters.snapshot()
+    }
+
+    /// Every metric the convenience methods below emit, for generating dashboards and alarms
+    pub fn manifest() -> Vec<MetricDescriptor> {
+        metric_manifest::manifest()
+    }
+
+    /// Emit a count metric
     pub async fn emit_count(
         &self,
         metric_name: &str,
         value: f64,
         dimensions: Option<HashMap<String, String>>,
     ) {
-        self.emit_emf_metric(metric_name, value, "Count", dimensions).await;
+        self.emit_metric(metric_name, value, "Count", dimensions).await;
     }
 
-    /// Emit a gauge metric (for things like number of connections) using EMF
+    /// Emit a gauge metric (for things like number of connections)
     pub async fn emit_gauge(
         &self,
         metric_name: &str,
         value: f64,
         dimensions: Option<HashMap<String, String>>,
     ) {
-        self.emit_emf_metric(metric_name, value, "None", dimensions).await;
+        self.emit_metric(metric_name, value, "None", dimensions).await;
     }
 
-    /// Emit a duration metric in milliseconds using EMF
+    /// Emit a duration metric in milliseconds
     pub async fn emit_duration_ms(
         &self,
         metric_name: &str,
         duration_ms: f64,
         dimensions: Option<HashMap<String, String>>,
     ) {
-        self.emit_emf_metric(metric_name, duration_ms, "Milliseconds", dimensions).await;
+        self.emit_metric(metric_name, duration_ms, "Milliseconds", dimensions).await;
+    }
+
+    /// Collect several metrics to write out together with `MetricsBatch::emit`
+    pub fn batch(&self) -> MetricsBatch {
+        MetricsBatch { sink: self.sink.clone(), records: Vec::new() }
     }
 
-    async fn emit_emf_metric(
+    /// Time the scope the returned guard lives in, emitting the duration in milliseconds as
+    /// `metric_name` when it's dropped
+    pub fn start_timer(
+        &self,
+        metric_name: &str,
+        dimensions: Option<HashMap<String, String>>,
+    ) -> TimerGuard {
+        TimerGuard::new(self.sink.clone(), metric_name, dimensions.unwrap_or_default())
+    }
+
+    async fn emit_metric(
         &self,
         metric_name: &str,
         value: f64,
         unit: &str,
         dimensions: Option<HashMap<String, String>>,
     ) {
-        let mut emf_log = json!({
-            "_aws": {
-                "Timestamp": chrono::Utc::now().timestamp_millis(),
-                "CloudWatchMetrics": [{
-                    "Namespace": self.namespace,
-                    "Dimensions": [["Stage"]],
-                    "Metrics": [{
-                        "Name": metric_name,
-                        "Unit": unit
-                    }]
-                }]
-            },
-            "Stage": self.stage,
-            metric_name: value
-        });
-
-        // Add custom dimensions if provided
-        if let Some(custom_dims) = dimensions {
-            let mut dimension_keys = vec!["Stage".to_string()];
-
-            for (key, dim_value) in custom_dims {
-                emf_log[key.clone()] = json!(dim_value);
-                dimension_keys.push(key);
-            }
-
-            // Update the dimension arrays in the CloudWatchMetrics
-            emf_log["_aws"]["CloudWatchMetrics"][0]["Dimensions"] = json!([dimension_keys]);
-        }
+        self.sink.emit(metric_name, value, unit, &dimensions.unwrap_or_default());
+    }
 
-        // Log the EMF formatted JSON to stdout - CloudWatch Logs will automatically parse this
-        println!("{}", emf_log);
+    /// Write out anything the sink is still holding, e.g. before the process exits
+    pub async fn flush(&self) {
+        self.sink.flush();
+    }
 
-        tracing::debug!("Emitted EMF metric: {} = {}", metric_name, value);
+    /// Convenience method to emit a server stopping, with how long it ran
+    pub async fn emit_server_shutdown(&self, uptime: Duration) {
+        self.emit_metric("ServerShutdown", uptime.as_secs_f64(), "Seconds", None).await;
     }
 
     /// Convenience method to emit message-related metrics
     pub async fn emit_message_sent(&self, room_id: &str, message_length: usize) {
+        self.counters.message_posted();
         let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
 
+        let mut batch = self.batch();
         // Count of messages sent
-        self.emit_count("MessagesPosted", 1.0, Some(dimensions.clone())).await;
-
+        batch.count("MessagesPosted", 1.0, Some(dimensions.clone()));
         // Message length distribution
-        self.emit_gauge("MessageLength", message_length as f64, Some(dimensions)).await;
+        batch.gauge("MessageLength", message_length as f64, Some(dimensions));
+        batch.emit();
+    }
+
+    /// Convenience method to emit room message cap evictions
+    pub async fn emit_message_evicted(&self, room_id: &str, evicted: usize) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("MessageEvicted", evicted as f64, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a retried post answered with the message already stored
+    pub async fn emit_duplicate_suppressed(&self, room_id: &str) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("DuplicateSuppressed", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a post or edit refused by the message blocklist
+    pub async fn emit_message_rejected_moderation(&self, room_id: &str) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("MessageRejectedModeration", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a message deleted by its poster
+    pub async fn emit_message_deleted(&self, room_id: &str) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("MessagesDeleted", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a stored message attribute that couldn't be read
+    pub async fn emit_corrupt_item(&self, field: &str) {
+        self.counters.error("CorruptItem");
+        let dimensions = HashMap::from([("Field".to_string(), field.to_string())]);
+        self.emit_count("CorruptItem", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a handler panic answered with a 500, by route template
+    pub async fn emit_handler_panic(&self, route: &str) {
+        self.counters.error("HandlerPanic");
+        let dimensions = HashMap::from([("Route".to_string(), route.to_string())]);
+        self.emit_count("HandlerPanic", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a request answered 504 for running past its deadline
+    pub async fn emit_request_timeout(&self, route: &str) {
+        self.counters.error("RequestTimeout");
+        let dimensions = HashMap::from([("Route".to_string(), route.to_string())]);
+        self.emit_count("RequestTimeout", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a management endpoint that couldn't be built from the
+    /// environment, refused before anything was posted through it
+    pub async fn emit_broadcast_config_error(&self) {
+        self.counters.error("BroadcastConfigError");
+        self.emit_count("BroadcastConfigError", 1.0, None).await;
+    }
+
+    /// Convenience method to emit a post refused for being over its user's rate limit
+    pub async fn emit_rate_limited(&self, room_id: &str, user_id: &str) {
+        let dimensions = HashMap::from([
+            ("RoomId".to_string(), room_id.to_string()),
+            ("UserId".to_string(), user_id.to_string()),
+        ]);
+        self.emit_count("RateLimited", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit WebSocket policy enforcement (`warn` or `close`)
+    pub async fn emit_ws_rate_limited(&self, action: &str) {
+        let dimensions = HashMap::from([("Action".to_string(), action.to_string())]);
+        self.emit_count("WsRateLimited", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit frames discarded by a full per-connection send queue
+    pub async fn emit_ws_frame_dropped(&self, policy: &str) {
+        let dimensions = HashMap::from([("Policy".to_string(), policy.to_string())]);
+        self.emit_count("WsFramesDropped", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit the outcome of a webhook delivery
+    pub async fn emit_webhook_delivery(&self, delivered: bool) {
+        let metric_name = if delivered { "WebhookDelivered" } else { "WebhookFailed" };
+        if !delivered {
+            self.counters.error(metric_name);
+        }
+        self.emit_count(metric_name, 1.0, None).await;
     }
 
     /// Convenience method to emit connection-related metrics
@@ -333,20 +347,97 @@ impl MetricsHelper {
         &self,
         event_type: &str,
         room_id: &str,
-        total_connections: Option<i32>,
+        total_connections: Option<u32>,
     ) {
+        self.counters.connection_event(event_type);
         let dimensions = HashMap::from([
             ("EventType".to_string(), event_type.to_string()),
             ("RoomId".to_string(), room_id.to_string()),
         ]);
 
+        let mut batch = self.batch();
         // Count of connection events
-        self.emit_count("ConnectionEvents", 1.0, Some(dimensions.clone())).await;
-
+        batch.count("ConnectionEvents", 1.0, Some(dimensions));
         // Current connection count if provided
         if let Some(count) = total_connections {
-            self.emit_gauge("ActiveConnections", count as f64, Some(dimensions)).await;
+            let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+            batch.gauge("ActiveConnections", count as f64, Some(dimensions));
+        }
+        batch.emit();
+    }
+
+    /// Convenience method to emit a connection (`connect`) or disconnection (`disconnect`) the
+    /// connections table write for which failed
+    pub async fn emit_connection_error(&self, event_type: &str, room_id: &str) {
+        let metric_name =
+            if event_type == "disconnect" { "DisconnectionErrors" } else { "ConnectionErrors" };
+        self.counters.error(metric_name);
+        let dimensions = HashMap::from([
+            ("ErrorType".to_string(), "DatabaseError".to_string()),
+            ("RoomId".to_string(), room_id.to_string()),
+        ]);
+        self.emit_count(metric_name, 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit a room's current connection count. Only keyed by room, so
+    /// event-driven and periodic samples land in the same series.
+    pub async fn emit_active_connections(&self, room_id: &str, count: u32) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_gauge("ActiveConnections", count as f64, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit the adaptive message write concurrency limit, when it changes
+    pub async fn emit_write_concurrency_limit(&self, limit: usize) {
+        self.emit_gauge("WriteConcurrencyLimit", limit as f64, None).await;
+    }
+
+    /// Convenience method to emit how a room's fan-out was paced: the chunks it was sent in and
+    /// how long the posts took overall
+    pub async fn emit_broadcast_pacing(&self, room_id: &str, chunks: usize, elapsed: Duration) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        let mut batch = self.batch();
+        batch.count("BroadcastChunks", chunks as f64, Some(dimensions.clone())).duration_ms(
+            "BroadcastFanOutDuration",
+            elapsed.as_secs_f64() * 1000.0,
+            Some(dimensions),
+        );
+        batch.emit();
+    }
+
+    /// Convenience method to emit how many gone connections a broadcast deleted
+    pub async fn emit_stale_connections_reaped(&self, room_id: &str, count: usize) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("StaleConnectionsReaped", count as f64, Some(dimensions)).await;
+    }
+
+    /// Convenience method to count an edit broadcast to a room, apart from new messages
+    pub async fn emit_message_update_broadcast(&self, room_id: &str) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("MessageUpdatesBroadcast", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to count a deletion notice broadcast to a room
+    pub async fn emit_message_delete_broadcast(&self, room_id: &str) {
+        let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        self.emit_count("MessageDeletesBroadcast", 1.0, Some(dimensions)).await;
+    }
+
+    /// Convenience method to emit one broadcast's API Gateway posts per stage and domain, which
+    /// comes out as one record per stage. The API Gateway stage goes in `ApiStage`, as `Stage`
+    /// is the deployment stage every metric already carries.
+    pub async fn emit_broadcast_stages(&self, room_id: &str, stages: &[StageDeliveries]) {
+        let mut batch = self.batch();
+        for stage in stages {
+            let dimensions = HashMap::from([
+                ("RoomId".to_string(), room_id.to_string()),
+                ("ApiStage".to_string(), stage.stage.clone()),
+                ("Domain".to_string(), stage.domain.clone()),
+            ]);
+            batch
+                .count("BroadcastSuccesses", stage.successes as f64, Some(dimensions.clone()))
+                .count("BroadcastFailures", stage.failures as f64, Some(dimensions));
         }
+        batch.emit();
     }
 
     /// Convenience method to emit broadcast metrics
@@ -356,22 +447,19 @@ impl MetricsHelper {
         connection_count: i32,
         successful_sends: i32,
     ) {
+        self.counters.broadcast(connection_count.max(0) as u64, successful_sends.max(0) as u64);
         let dimensions = HashMap::from([("RoomId".to_string(), room_id.to_string())]);
 
-        // Total broadcast attempts
-        self.emit_count("BroadcastAttempts", connection_count as f64, Some(dimensions.clone()))
-            .await;
-
-        // Successful broadcasts
-        self.emit_count("BroadcastSuccesses", successful_sends as f64, Some(dimensions.clone()))
-            .await;
-
-        // Failed broadcasts
-        self.emit_count(
-            "BroadcastFailures",
-            (connection_count - successful_sends) as f64,
-            Some(dimensions),
-        )
-        .await;
+        // Attempts, successes and failures go out as one record
+        let mut batch = self.batch();
+        batch
+            .count("BroadcastAttempts", connection_count as f64, Some(dimensions.clone()))
+            .count("BroadcastSuccesses", successful_sends as f64, Some(dimensions.clone()))
+            .count(
+                "BroadcastFailures",
+                (connection_count - successful_sends) as f64,
+                Some(dimensions),
+            );
+        batch.emit();
     }
 }
@@ -1,89 +1,401 @@
+use axum::extract::ws::{close_code, CloseFrame};
 use axum::{
+    async_trait,
+    body::{Bytes, HttpBody, StreamBody},
+    error_handling::HandleErrorLayer,
     extract::{
         ws::{Message, WebSocket},
-        Path, Query, State, WebSocketUpgrade,
+        ConnectInfo, FromRequest, FromRequestParts, MatchedPath, Path, Query, State,
+        WebSocketUpgrade,
     },
-    http::StatusCode,
+    http::{
+        header::{
+            ACCEPT, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+            LOCATION, RETRY_AFTER,
+        },
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Request, StatusCode,
+    },
+    middleware::{self, Next},
     response::{IntoResponse, Json, Response},
-    routing::{get, post},
-    Router,
+    routing::{get, post, put},
+    BoxError, Router,
 };
 #[cfg(feature = "dev")]
-use types::ChatMessage;
+use backend::connection_limit::{ConnectionLimit, TOO_MANY_CONNECTIONS};
+#[cfg(feature = "dev")]
+use backend::send_queue::{Enqueued, SendQueue};
 #[cfg(feature = "dev")]
-use uuid::Uuid;
+use backend::typing::TypingTracker;
+use std::time::Instant;
+#[cfg(feature = "dev")]
+use types::{TypingIndicator, WsClientMessage, WsServerMessage};
 // WebSocket support imports - will be used for message handling
 // use futures_util::{sink::SinkExt, stream::StreamExt};
 
-use std::net::SocketAddr;
-#[cfg(feature = "dev")]
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-#[cfg(feature = "dev")]
-use tokio::sync::{broadcast, RwLock};
-use tower_http::cors::CorsLayer;
-// use tower_http::trace::TraceLayer;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch, RwLock};
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
+use tower_http::{catch_panic::CatchPanicLayer, cors::CorsLayer, limit::RequestBodyLimitLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use types::{HealthCheck, SendMessageRequest};
+use types::{
+    ApiError, ChatMessage, LatestMessagesRequest, MessageId, ReconnectReason, RoomId,
+    SendMessageRequest, UserId, WsError, WsErrorCode,
+};
 // use tower::ServiceExt; // Unused for now, but will be needed for Lambda
 use aws_sdk_dynamodb::Client as DynamoDbClient;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use std::{env, sync::LazyLock};
-#[cfg(feature = "dev")]
-use tokio::sync::mpsc;
 
-use backend::handlers;
+use backend::{
+    access_log::{AccessLogger, REQUEST_ID_HEADER},
+    admin,
+    body_log::BodyLogger,
+    codec::Format,
+    dependencies::DependencyHealth,
+    echo::EchoMode,
+    encryption::TextCipher,
+    export::{self, ExportFormat},
+    handlers,
+    http_cache::{self, CachePolicy},
+    identity::AnonymousPolicy,
+    known_rooms::KnownRooms,
+    message_cache::MessageCache,
+    metric_snapshot::MetricsSnapshot,
+    origin,
+    rate_limit::{MessageRateLimiter, Quota},
+    reconnect::{self, ReconnectBackoff},
+    request_context::RequestContext,
+    search::{self, SearchIndex},
+    store::{DynamoMessageStore, MessageStore},
+    write_limiter::WriteLimiter,
+    ws_error,
+    ws_policy::WsPolicy,
+};
 
 // Tables configuration
-static TABLES: LazyLock<handlers::Tables> = LazyLock::new(|| handlers::Tables::from_env());
+static TABLES: LazyLock<handlers::Tables> = LazyLock::new(handlers::Tables::from_env);
+
+// One structured line per request (ACCESS_LOG, ACCESS_LOG_QUIET_PATHS)
+static ACCESS_LOGGER: LazyLock<AccessLogger> = LazyLock::new(AccessLogger::from_env);
+
+// Opt-in debug logging of request/response bodies (LOG_BODIES)
+static BODY_LOGGER: LazyLock<BodyLogger> = LazyLock::new(BodyLogger::from_env);
+
+// Cache-Control for message lists (MESSAGES_MAX_AGE_SECS)
+static CACHE_POLICY: LazyLock<CachePolicy> = LazyLock::new(CachePolicy::from_env);
+
+// Whether WebSocket clients may connect without identifying themselves (ALLOW_ANONYMOUS)
+static ANONYMOUS_POLICY: LazyLock<AnonymousPolicy> = LazyLock::new(AnonymousPolicy::from_env);
+
+// Whether `echo` frames are answered, as on the `$default` route (WS_ECHO_ENABLED)
+static WS_ECHO: LazyLock<EchoMode> = LazyLock::new(EchoMode::from_env);
+
+// Largest frame accepted from clients, as on the `$default` route (WS_MAX_FRAME_BYTES)
+static WS_POLICY: LazyLock<WsPolicy> = LazyLock::new(WsPolicy::from_env);
+
+// When sockets the server closes are told to reconnect (RECONNECT_BASE_MS, RECONNECT_MAX_MS)
+static RECONNECT_BACKOFF: LazyLock<ReconnectBackoff> = LazyLock::new(ReconnectBackoff::from_env);
 
 #[cfg(feature = "dev")]
 static CHAT_CONNECTIONS_TABLE: LazyLock<String> = LazyLock::new(|| {
     env::var("CONNECTIONS_TABLE").expect("CONNECTIONS_TABLE environment variable must be set")
 });
 
+// Simultaneous connections allowed per user (MAX_CONNECTIONS_PER_USER)
+#[cfg(feature = "dev")]
+static CONNECTION_LIMIT: LazyLock<ConnectionLimit> = LazyLock::new(ConnectionLimit::from_env);
+
 #[cfg(feature = "dev")]
 static DEV_PUBLIC_BASE_URL: LazyLock<Option<String>> =
     LazyLock::new(|| env::var("DEV_PUBLIC_BASE_URL").ok());
 
+// Whether posted messages go straight out to the room's dev sockets (DEV_INPROCESS_BROADCAST,
+// on by default). Turn it off when a broadcaster Lambda pushes to this server through
+// DEV_PUBLIC_BASE_URL, or sockets get every message twice.
+#[cfg(feature = "dev")]
+static DEV_INPROCESS_BROADCAST: LazyLock<bool> = LazyLock::new(|| {
+    env::var("DEV_INPROCESS_BROADCAST")
+        .map(|value| !(value == "0" || value.eq_ignore_ascii_case("false")))
+        .unwrap_or(true)
+});
+
+// How often the dev server emits ActiveConnections gauges (CONNECTION_GAUGE_INTERVAL_SECS)
+#[cfg(feature = "dev")]
+static CONNECTION_GAUGE_INTERVAL: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("CONNECTION_GAUGE_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(60),
+    )
+});
+
+// User profiles for renames; without it renames fail and posts keep the name they carry
+// (CHAT_USERS_TABLE)
+static USERS_TABLE: LazyLock<Option<String>> = LazyLock::new(|| env::var("CHAT_USERS_TABLE").ok());
+
+// Users' flags on messages; without it flagging fails and the moderator queue is empty
+// (CHAT_FLAGS_TABLE)
+static FLAGS_TABLE: LazyLock<Option<String>> = LazyLock::new(|| env::var("CHAT_FLAGS_TABLE").ok());
+
+// Largest request body read before answering 413 (MAX_BODY_BYTES)
+static MAX_BODY_BYTES: LazyLock<usize> = LazyLock::new(handlers::max_body_bytes_from_env);
+
+// Deadline for a request's response, past which it's answered with a 504 (REQUEST_TIMEOUT_MS)
+static REQUEST_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(
+        env::var("REQUEST_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|ms| *ms > 0)
+            .unwrap_or(10_000),
+    )
+});
+
+// Most missed messages replayed to a socket reconnecting with `last_seen_ts`
+// (RESUME_MAX_MESSAGES)
+static RESUME_MAX_MESSAGES: LazyLock<usize> = LazyLock::new(|| {
+    env::var("RESUME_MAX_MESSAGES").ok().and_then(|value| value.parse().ok()).unwrap_or(50)
+});
+
+// Longest a poll is held open before answering with an empty page
+static POLL_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_secs(
+        env::var("POLL_TIMEOUT_SECS").ok().and_then(|value| value.parse().ok()).unwrap_or(25),
+    )
+});
+
+// How long shutdown waits for open WebSockets to finish closing
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where the server listens unless BIND_ADDR says otherwise
+const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3001);
+
+// Traffic on a room's local broadcast channel
+#[derive(Debug, Clone)]
+enum RoomEvent {
+    // Frame relayed as-is to the room's dev WebSocket clients
+    #[cfg_attr(not(feature = "dev"), allow(dead_code))]
+    Frame(String),
+    // A message was stored in the room. Wakes long-polls, and dev sockets forward it unless
+    // the broadcaster delivers it instead (DEV_INPROCESS_BROADCAST).
+    #[cfg_attr(not(feature = "dev"), allow(dead_code))]
+    MessagePosted(Arc<ChatMessage>),
+    // A message in the room was edited; dev sockets forward it as they do new ones, for clients
+    // to replace the copy they have
+    #[cfg_attr(not(feature = "dev"), allow(dead_code))]
+    MessageEdited(Arc<ChatMessage>),
+    // A message in the room was deleted. Dev sockets that could see it are told to drop it; the
+    // broadcaster's dev push only delivers messages, so it's always up to them.
+    #[cfg_attr(not(feature = "dev"), allow(dead_code))]
+    MessageDeleted(Arc<ChatMessage>),
+}
+
 #[derive(Clone)]
 struct AppState {
+    // Connection records for dev WebSocket clients (dev only)
+    #[cfg(feature = "dev")]
     ddb: DynamoDbClient,
-    tables: handlers::Tables,
+    store: Arc<dyn MessageStore>,
     metrics: backend::MetricsHelper,
-    // In-memory broadcast channels keyed by room id (dev only)
+    // Time and new ids; its clock is shared by the store and metrics, so a test can pin time
+    // across all of them
+    context: RequestContext,
+    // Full-text index posts are added to, when SEARCH_BACKEND configures one
+    search: Option<Arc<dyn SearchIndex>>,
+    // Latest page per room, enabled by ENABLE_MESSAGE_CACHE
+    message_cache: Option<Arc<MessageCache>>,
+    // Probes behind GET /health/dependencies
+    dependencies: Arc<DependencyHealth>,
+    // Per-user post budget (MESSAGES_PER_MINUTE); per instance, so best effort
+    rate_limiter: Arc<MessageRateLimiter>,
+    // In-memory broadcast channels keyed by room id
+    channels: Arc<RwLock<std::collections::HashMap<String, broadcast::Sender<RoomEvent>>>>,
+    // Set once at shutdown. Every open WebSocket watches it, so its receiver count is how many
+    // are open.
+    shutting_down: Arc<watch::Sender<bool>>,
+    // Per-connection outbound queues for targeted push (dev only)
     #[cfg(feature = "dev")]
-    channels: Arc<RwLock<std::collections::HashMap<String, broadcast::Sender<String>>>>,
-    // Per-connection senders for targeted push (dev only)
+    conn_senders: Arc<RwLock<std::collections::HashMap<String, Arc<SendQueue>>>>,
+    // Typing indicators awaiting an explicit stop or server-side timeout (dev only)
     #[cfg(feature = "dev")]
-    conn_senders: Arc<RwLock<std::collections::HashMap<String, mpsc::Sender<String>>>>,
+    typing: Arc<std::sync::Mutex<TypingTracker>>,
 }
 
-// Error handling for the API
+// Error handling for the API. Every variant answers with the same `{ "error", "code" }` body,
+// plus per-field `errors` for validation failures.
 #[derive(Debug)]
-struct AppError {
-    message: String,
-    status_code: StatusCode,
+enum AppError {
+    NotFound(String),
+    BadRequest(String),
+    // 400 with what was wrong with each field
+    Invalid(Vec<ApiError>),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    // 429, with Retry-After when it's known how long to wait
+    Throttled { message: String, retry_after: Option<Duration> },
+    // 504 for a request that ran past its deadline
+    Timeout,
+    Internal(String),
+}
+
+impl AppError {
+    // The generic 500, for failures whose details belong in the log only
+    fn internal() -> Self {
+        AppError::Internal("Internal server error".to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) | AppError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Throttled { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        let body = json!({
-            "error": self.message,
-            "code": self.status_code.as_u16()
+        let status_code = self.status_code();
+        let mut errors = Vec::new();
+        let mut retry_after_secs = None;
+        let message = match self {
+            AppError::Invalid(invalid) => {
+                errors = invalid;
+                "Invalid request".to_string()
+            }
+            AppError::Throttled { message, retry_after } => {
+                // Whole seconds, rounded up so a client waiting that long finds a token
+                retry_after_secs = retry_after.map(|retry_after| {
+                    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+                });
+                message
+            }
+            AppError::Timeout => "Request timed out".to_string(),
+            AppError::NotFound(message)
+            | AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Conflict(message)
+            | AppError::Internal(message) => message,
+        };
+
+        let mut body = json!({
+            "error": message,
+            "code": status_code.as_u16()
         });
+        if !errors.is_empty() {
+            body["errors"] = json!(errors);
+        }
+        let mut response = (status_code, Json(body)).into_response();
+        if let Some(secs) = retry_after_secs {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
 
-        (self.status_code, Json(body)).into_response()
+impl From<handlers::HandlerError> for AppError {
+    fn from(err: handlers::HandlerError) -> Self {
+        use handlers::HandlerError;
+        match err {
+            HandlerError::Validation(errors) => {
+                AppError::Invalid(errors.into_iter().map(ApiError::from).collect())
+            }
+            HandlerError::BadRequest(message) => AppError::BadRequest(message),
+            HandlerError::Unauthorized(message) => AppError::Unauthorized(message),
+            HandlerError::Forbidden(message) => AppError::Forbidden(message),
+            HandlerError::Conflict(message) => AppError::Conflict(message),
+            HandlerError::Throttled(message) => AppError::Throttled { message, retry_after: None },
+            HandlerError::Internal(message) => AppError::Internal(message),
+        }
     }
 }
 
-// Helper to create AppError from any error
-impl AppError {
-    fn from_error<E: std::fmt::Debug>(err: E) -> Self {
-        tracing::error!("DynamoDB error: {:?}", err);
-        Self {
-            message: "Internal server error".to_string(),
-            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+// Response format negotiated from the request's Accept header
+#[derive(Debug, Clone, Copy)]
+struct ResponseFormat(Format);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accept = parts.headers.get(ACCEPT).and_then(|value| value.to_str().ok());
+        Ok(ResponseFormat(Format::from_accept(accept)))
+    }
+}
+
+// Request body decoded as MessagePack when the Content-Type asks for it, JSON otherwise
+struct Payload<T>(T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Payload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        match Format::from_content_type(content_type) {
+            Format::MessagePack => {
+                let bytes =
+                    Bytes::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+                Format::MessagePack
+                    .decode(&bytes)
+                    .map(Payload)
+                    .map_err(|err| AppError::BadRequest(err).into_response())
+            }
+            // Keep the stock JSON extractor (and its rejections) as the default path
+            Format::Json => Json::<T>::from_request(req, state)
+                .await
+                .map(|Json(value)| Payload(value))
+                .map_err(IntoResponse::into_response),
+        }
+    }
+}
+
+// Response body encoded in the negotiated format
+struct Negotiated<T> {
+    format: Format,
+    status: StatusCode,
+    value: T,
+}
+
+impl<T: Serialize> Negotiated<T> {
+    fn new(ResponseFormat(format): ResponseFormat, status: StatusCode, value: T) -> Self {
+        Self { format, status, value }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        match self.format {
+            Format::Json => (self.status, Json(self.value)).into_response(),
+            Format::MessagePack => match self.format.encode(&self.value) {
+                Ok(bytes) => (self.status, [(CONTENT_TYPE, self.format.content_type())], bytes)
+                    .into_response(),
+                Err(err) => {
+                    tracing::error!("Failed to encode MessagePack response: {}", err);
+                    AppError::internal().into_response()
+                }
+            },
         }
     }
 }
@@ -99,6 +411,13 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Print the metrics this service emits, for building dashboards and alarms as code
+    if env::args().any(|arg| arg == "--dump-metrics") {
+        let manifest = backend::MetricsHelper::manifest();
+        println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+        return;
+    }
+
     // Initialize AWS config and DynamoDB client
     let aws_config = if let Ok(endpoint) = env::var("DYNAMODB_ENDPOINT") {
         // Use local DynamoDB for development
@@ -114,24 +433,140 @@ async fn main() {
 
     let ddb_client = DynamoDbClient::new(&aws_config);
 
+    // Preflight mode: check configuration and table access, print the report, then exit
+    let selftest = env::args().any(|arg| arg == "--selftest")
+        || env::var("SELFTEST").is_ok_and(|value| value == "1");
+    if selftest {
+        let connections_table = env::var("CONNECTIONS_TABLE").ok();
+        let report = backend::selftest::run(
+            &aws_config,
+            handlers::Tables::try_from_env(),
+            connections_table.as_deref(),
+        )
+        .await;
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        std::process::exit(if report.passed { 0 } else { 1 });
+    }
+
+    // One-off copy of a messages table keyed by (room_id, ts) into CHAT_MESSAGES_TABLE, which
+    // is keyed by (room_id, sk); see the backend README. `--migrate-since` takes the epoch
+    // millis an earlier run started at, to copy only what's been written since.
+    let migrate_from = env::args().skip_while(|arg| arg != "--migrate-messages-from").nth(1);
+    if let Some(from) = migrate_from {
+        let to = &TABLES.messages;
+        let since = env::args().skip_while(|arg| arg != "--migrate-since").nth(1);
+        let since = match since.map(|since| since.parse::<i64>()).transpose() {
+            Ok(since) => since,
+            Err(err) => {
+                tracing::error!("--migrate-since must be epoch millis: {}", err);
+                std::process::exit(1);
+            }
+        };
+        tracing::info!("Copying messages from {} to {}", from, to);
+        let started_at = backend::clock::system().now().timestamp_millis();
+        let copied = backend::migrate::copy_messages_with_sort_keys(
+            &ddb_client,
+            &from,
+            to,
+            since,
+            started_at,
+        )
+        .await;
+        match copied {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                std::process::exit(0);
+            }
+            Err(err) => {
+                tracing::error!("Migration failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // One-off backfill putting rooms created before the room listing index on it; see the
+    // backend README
+    if env::args().any(|arg| arg == "--backfill-room-listing") {
+        let rooms = &TABLES.rooms;
+        tracing::info!("Backfilling the room listing of {}", rooms);
+        match backend::migrate::backfill_room_listing(&ddb_client, rooms).await {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                std::process::exit(0);
+            }
+            Err(err) => {
+                tracing::error!("Backfill failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // Use static constants for table names - will panic at startup if not set
     let tables = TABLES.clone();
 
     tracing::info!("Using tables: rooms={}, messages={}", tables.rooms, tables.messages);
 
+    // Fail fast on missing tables or unexpected key schemas
+    if let Err(err) = tables.verify(&ddb_client).await {
+        tracing::error!("Table verification failed: {}", err);
+        std::process::exit(1);
+    }
+    #[cfg(feature = "dev")]
+    if let Err(err) = handlers::verify_connections_table(&ddb_client, &CHAT_CONNECTIONS_TABLE).await
+    {
+        tracing::error!("Table verification failed: {}", err);
+        std::process::exit(1);
+    }
+
+    // Storing plaintext when encryption was asked for is worse than not starting
+    let cipher = match TextCipher::from_env(&aws_config) {
+        Ok(cipher) => cipher.map(Arc::new),
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
     // Initialize metrics helper
-    let metrics = backend::MetricsHelper::new().await;
+    let context = RequestContext::from_env();
+    let metrics = backend::MetricsHelper::with_clock(context.clock.clone());
+    let write_limiter =
+        WriteLimiter::from_env().map(|limiter| Arc::new(limiter.with_metrics(metrics.clone())));
+    let rate_limiter = Arc::new(MessageRateLimiter::from_env(context.clock.clone()));
+    let message_cache = MessageCache::from_env(context.clock.clone()).map(Arc::new);
 
     let state = AppState {
+        store: Arc::new(
+            DynamoMessageStore::new(ddb_client.clone(), tables)
+                .with_users_table(USERS_TABLE.clone())
+                .with_flags_table(FLAGS_TABLE.clone())
+                .with_clock(context.clock.clone())
+                .with_write_limiter(write_limiter)
+                .with_cipher(cipher)
+                .with_known_rooms(Arc::new(KnownRooms::from_env())),
+        ),
+        #[cfg(feature = "dev")]
         ddb: ddb_client,
-        tables,
         metrics,
-        #[cfg(feature = "dev")]
+        context,
+        search: search::from_env(),
+        message_cache,
+        dependencies: Arc::new(DependencyHealth::from_env(&aws_config)),
+        rate_limiter,
         channels: Arc::new(RwLock::new(std::collections::HashMap::new())),
+        shutting_down: Arc::new(watch::channel(false).0),
         #[cfg(feature = "dev")]
         conn_senders: Arc::new(RwLock::new(std::collections::HashMap::new())),
+        #[cfg(feature = "dev")]
+        typing: Arc::new(std::sync::Mutex::new(TypingTracker::from_env())),
     };
 
+    tokio::spawn(rate_limit_pruner(state.rate_limiter.clone()));
+    #[cfg(feature = "dev")]
+    tokio::spawn(typing_sweeper(state.clone()));
+    #[cfg(feature = "dev")]
+    tokio::spawn(connection_gauge_emitter(state.clone()));
+
     // Check if running in AWS Lambda
     if std::env::var("AWS_LAMBDA_FUNCTION_NAME").is_ok() {
         tracing::warn!("Lambda mode detected but integration temporarily disabled. Running in compatibility mode.");
@@ -139,338 +574,2952 @@ async fn main() {
     }
 
     // Running locally - use axum server
+    let shutdown = Arc::new(ShutdownHook::new(state.clone()));
     let app = create_app(state);
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3001));
+    let addr = match bind_addr(env::var("BIND_ADDR").ok(), env::var("PORT").ok()) {
+        Ok(addr) => addr,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
     tracing::info!("listening on {}", addr);
-    axum::Server::bind(&addr).serve(app.into_make_service()).await.unwrap();
+    let on_signal = shutdown.clone();
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            // Report before draining, which waits on open polls
+            on_signal.run().await;
+        })
+        .await
+        .unwrap();
+    shutdown.run().await;
+}
+
+/// Where the server listens: `BIND_ADDR` (`127.0.0.1:3001` by default; `0.0.0.0:3001` to be
+/// reachable from outside a container), with its port replaced by `PORT` when that's set, as
+/// platforms that assign the port expect
+fn bind_addr(bind_addr: Option<String>, port: Option<String>) -> Result<SocketAddr, String> {
+    let mut addr = match bind_addr {
+        Some(value) => value.trim().parse::<SocketAddr>().map_err(|_| {
+            format!("BIND_ADDR must be an IP address and port like 0.0.0.0:3001, got {:?}", value)
+        })?,
+        None => DEFAULT_BIND_ADDR,
+    };
+    if let Some(value) = port {
+        let port = value
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("PORT must be a port number, got {:?}", value))?;
+        addr.set_port(port);
+    }
+    Ok(addr)
+}
+
+// Ctrl-C, or SIGTERM where there is one
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Shutting down");
+}
+
+// Last words of the local server: WebSockets closed with a reconnect hint and drained, room
+// channels closed so long-polls answer, final room and connection counts, a ServerShutdown
+// metric with the uptime, and a flush of the metrics sink. Only the first call does anything,
+// so a second signal (or the server returning after the first) doesn't report twice.
+struct ShutdownHook {
+    state: AppState,
+    started: std::time::Instant,
+    ran: std::sync::atomic::AtomicBool,
+}
+
+impl ShutdownHook {
+    fn new(state: AppState) -> Self {
+        Self { state, started: std::time::Instant::now(), ran: Default::default() }
+    }
+
+    // Whether this call was the one that reported
+    async fn run(&self) -> bool {
+        if self.ran.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            return false;
+        }
+
+        // Open sockets close with a reconnect hint; each drops its receiver once it's done
+        let open = self.state.shutting_down.receiver_count();
+        self.state.shutting_down.send_replace(true);
+        let _ =
+            tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, self.state.shutting_down.closed()).await;
+        let left = self.state.shutting_down.receiver_count();
+        tracing::info!("Drained {} of {} WebSocket connections", open - left.min(open), open);
+
+        let uptime = self.started.elapsed();
+        {
+            let channels = self.state.channels.read().await;
+            let listeners: usize = channels.values().map(broadcast::Sender::receiver_count).sum();
+            tracing::info!(
+                "Stopping after {:.0?}: {} rooms with {} local listeners",
+                uptime,
+                channels.len(),
+                listeners
+            );
+        }
+        // Dropping the room senders wakes any long-poll still waiting
+        self.state.channels.write().await.clear();
+        #[cfg(feature = "dev")]
+        tracing::info!(
+            "{} dev WebSocket connections open at shutdown",
+            self.state.conn_senders.read().await.len()
+        );
+
+        self.state.metrics.emit_server_shutdown(uptime).await;
+        self.state.metrics.flush().await;
+        true
+    }
 }
 
 fn create_app(state: AppState) -> Router {
-    let base = Router::new()
+    let timed = Router::new()
         .route("/health", get(health_handler))
+        .route("/health/dependencies", get(dependencies_handler))
         .route("/chat/messages", post(post_message_handler))
+        // Takes precedence over /:room_id, so a room named "latest" can't be listed here
+        .route("/chat/messages/latest", post(latest_messages_handler))
         .route("/chat/messages/:room_id", get(get_messages_handler))
-        .route("/ws", get(websocket_handler));
+        .route(
+            "/chat/messages/:room_id/:id",
+            get(get_message_handler).patch(edit_message_handler).delete(delete_message_handler),
+        )
+        .route("/chat/messages/:room_id/:id/flag", post(flag_message_handler))
+        .route("/chat/flags", get(flags_handler))
+        .route("/chat/rooms/:room_id/stats", get(room_stats_handler))
+        .route("/chat/rooms/:room_id/presence", get(room_presence_handler))
+        .route("/chat/rooms/:room_id/search", get(search_messages_handler))
+        .route("/chat/rooms/:room_id/export", get(export_messages_handler))
+        .route("/chat/rooms", get(list_rooms_handler))
+        .route("/chat/rooms/:room_id/topic", put(set_room_topic_handler))
+        .route("/chat/rooms/:room_id/archive", post(archive_room_handler))
+        .route("/chat/rooms/:room_id/unarchive", post(unarchive_room_handler))
+        .route("/chat/users/:user_id/username", put(rename_user_handler))
+        .route("/chat/users/:user_id/avatar-seed", put(set_avatar_seed_handler))
+        .route("/admin/metrics/snapshot", get(metrics_snapshot_handler));
 
     #[cfg(feature = "dev")]
-    let base = base.route("/dev/conn/:connection_id/send", post(dev_conn_send_handler));
+    let timed = timed.route("/dev/conn/:connection_id/send", post(dev_conn_send_handler));
 
-    base.with_state(state)
+    let base = with_request_timeout(timed, *REQUEST_TIMEOUT, state.metrics.clone())
+        // Both wait on purpose: a poll for up to POLL_TIMEOUT_SECS, a WebSocket while it's open
+        .route("/chat/messages/:room_id/poll", get(poll_messages_handler))
+        .route("/ws", get(websocket_handler));
+
+    let metrics = state.metrics.clone();
+    let app = base
+        .with_state(state)
+        // Cut oversized bodies off while they're read. LOG_BODIES buffers the body first, so with
+        // it on the limit still answers 413 but only after the body is in memory.
+        .layer(RequestBodyLimitLayer::new(*MAX_BODY_BYTES))
+        .layer(middleware::from_fn(log_bodies));
+    catch_panics(app, metrics)
         // Enable CORS for development
         .layer(CorsLayer::permissive())
-    // TODO: Re-add tracing layer after fixing HTTP version conflicts
-    // .layer(TraceLayer::new_for_http())
+        // Outermost, so timeouts, panics and CORS preflights are logged with what they answered
+        .layer(middleware::from_fn(log_access))
 }
 
-async fn health_handler() -> Result<Json<HealthCheck>, StatusCode> {
-    match handlers::health_handler().await {
-        Ok(health_check) => Ok(Json(health_check)),
-        Err(err) => {
-            tracing::error!("Health check failed: {}", err);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+// Answer requests still running after `timeout` with a 504, counted under their route
+// (RequestTimeout). Only the deadline for the response is bounded, so a streamed body (like an
+// export) can take longer.
+fn with_request_timeout<S, B>(
+    routes: Router<S, B>,
+    timeout: Duration,
+    metrics: backend::MetricsHelper,
+) -> Router<S, B>
+where
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    let timed_out = move |path: MatchedPath, _: BoxError| {
+        let metrics = metrics.clone();
+        async move {
+            tracing::warn!("Request to {} timed out after {:?}", path.as_str(), timeout);
+            metrics.emit_request_timeout(path.as_str()).await;
+            AppError::Timeout
         }
-    }
+    };
+    routes.route_layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(timed_out))
+            .layer(TimeoutLayer::new(timeout)),
+    )
 }
 
-// POST /chat/messages - Send a new message
-async fn post_message_handler(
-    State(state): State<AppState>,
-    Json(request): Json<SendMessageRequest>,
-) -> Result<impl IntoResponse, AppError> {
-    tracing::info!("Received message request for room: {}", request.room_id);
-
-    match handlers::post_message_handler(&state.ddb, &state.tables, request).await {
-        Ok(message) => {
-            // Emit metrics for REST message post
-            state.metrics.emit_message_sent(&message.room_id, message.message_text.len()).await;
-            Ok((StatusCode::CREATED, Json(message)))
-        }
-        Err(err) => {
-            tracing::error!("Failed to post message: {}", err);
-            Err(AppError { message: err, status_code: StatusCode::INTERNAL_SERVER_ERROR })
-        }
-    }
+// A panicking handler answers 500 like any other internal error, and is counted (HandlerPanic)
+fn catch_panics(app: Router, metrics: backend::MetricsHelper) -> Router {
+    app.layer(CatchPanicLayer::custom(panic_response))
+        .layer(middleware::from_fn_with_state(metrics, count_panics))
 }
 
-// GET /chat/messages/:room_id - Retrieve last 25 messages
-async fn get_messages_handler(
-    State(state): State<AppState>,
-    Path(room_id): Path<String>,
-) -> Result<impl IntoResponse, AppError> {
-    tracing::info!("Retrieving messages for room: {}", room_id);
+// Marks the response CatchPanicLayer built for a panic, for count_panics to find
+#[derive(Debug, Clone, Copy)]
+struct Panicked;
 
-    match handlers::get_messages_handler(&state.ddb, &state.tables, room_id).await {
-        Ok(response) => Ok(Json(response)),
-        Err(err) => {
-            tracing::error!("Failed to get messages: {}", err);
-            Err(AppError { message: err, status_code: StatusCode::INTERNAL_SERVER_ERROR })
-        }
-    }
-}
+// The standard 500 body. The panic hook has already logged the message and location; this
+// ties it to the response.
+fn panic_response(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let details = match (panic.downcast_ref::<String>(), panic.downcast_ref::<&str>()) {
+        (Some(message), _) => message.as_str(),
+        (None, Some(message)) => message,
+        (None, None) => "no message",
+    };
+    tracing::error!("Handler panicked, answering 500: {}", details);
 
-// WebSocket query parameters
-#[derive(Debug, Deserialize)]
-struct WebSocketParams {
-    room_id: Option<String>,
-    #[serde(rename = "userId")]
-    user_id: Option<String>,
-    username: Option<String>,
+    let mut response = AppError::internal().into_response();
+    response.extensions_mut().insert(Panicked);
+    response
 }
 
-// WebSocket handler for development
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    Query(params): Query<WebSocketParams>,
-    #[cfg(feature = "dev")] State(state): State<AppState>,
+// Emit HandlerPanic for panics caught further in, under the route that was matched
+async fn count_panics(
+    State(metrics): State<backend::MetricsHelper>,
+    request: Request<axum::body::Body>,
+    next: Next<axum::body::Body>,
 ) -> Response {
-    let room_id = params.room_id.unwrap_or_else(|| "general".to_string());
-    let user_id = params.user_id.unwrap_or_else(|| "dev-user".to_string());
-    let username = params.username.unwrap_or_else(|| "Developer".to_string());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let response = next.run(request).await;
+    if response.extensions().get::<Panicked>().is_some() {
+        metrics.emit_handler_panic(&route).await;
+    }
+    response
+}
 
-    tracing::info!(
-        "WebSocket connection request: room={}, user={}, username={}",
-        room_id,
-        user_id,
-        username
-    );
+// Log each request once it's answered (ACCESS_LOG), under the caller's request id or a new one
+async fn log_access(request: Request<axum::body::Body>, next: Next<axum::body::Body>) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty())
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
 
-    ws.on_upgrade(move |socket| {
-        handle_websocket(
-            socket,
-            room_id,
-            user_id,
-            username,
-            #[cfg(feature = "dev")]
-            state,
-        )
-    })
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    ACCESS_LOGGER.log(&method, &path, response.status().as_u16(), latency_ms, &request_id);
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
 }
 
-// WebSocket connection handler
-async fn handle_websocket(
-    mut socket: WebSocket,
-    room_id: String,
-    user_id: String,
-    username: String,
-    #[cfg(feature = "dev")] state: AppState,
-) {
-    tracing::info!("WebSocket connected: {} ({}) in room {}", username, user_id, room_id);
+// Buffer and log request/response bodies when LOG_BODIES is set; a no-op otherwise
+async fn log_bodies(request: Request<axum::body::Body>, next: Next<axum::body::Body>) -> Response {
+    if !BODY_LOGGER.enabled() {
+        return next.run(request).await;
+    }
 
-    #[cfg(feature = "dev")]
-    let tx = {
-        let mut channels = state.channels.write().await;
-        if let Some(existing) = channels.get(&room_id) {
-            existing.clone()
-        } else {
-            let (tx, _rx) = broadcast::channel::<String>(100);
-            channels.insert(room_id.clone(), tx.clone());
-            tx
-        }
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let (parts, body) = request.into_parts();
+    let Ok(body) = collect_body(body).await else {
+        return StatusCode::BAD_REQUEST.into_response();
     };
+    BODY_LOGGER.log_request(&method, &path, &body);
 
-    #[cfg(feature = "dev")]
-    let mut rx = tx.subscribe();
+    let response = next.run(Request::from_parts(parts, axum::body::Body::from(body))).await;
+    // WebSocket upgrades hand the connection over; there is no body to read
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
 
-    // For development, create a per-connection sender and store connection in DynamoDB
-    #[cfg(feature = "dev")]
-    let connection_id = Uuid::new_v4().to_string();
-    #[cfg(feature = "dev")]
-    let (conn_tx, mut conn_rx) = mpsc::channel::<String>(100);
-    #[cfg(feature = "dev")]
-    {
-        use std::collections::HashMap;
+    let (parts, body) = response.into_parts();
+    let Ok(body) = collect_body(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    BODY_LOGGER.log_response(&method, &path, parts.status.as_u16(), &body);
+    Response::from_parts(parts, axum::body::boxed(axum::body::Full::from(body)))
+}
 
-        use aws_sdk_dynamodb::types::AttributeValue;
+async fn collect_body<B>(mut body: B) -> Result<Bytes, B::Error>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+{
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(Bytes::from(bytes))
+}
 
-        state.conn_senders.write().await.insert(connection_id.clone(), conn_tx);
+// GET /health - Service health, with the messages table probed. Always 200; a sick table shows
+// as a Degraded or Unhealthy status.
+async fn health_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let health_check = handlers::health_handler(state.store.as_ref(), &state.context).await;
+    (
+        [(CACHE_CONTROL, http_cache::HEALTH_CACHE_CONTROL)],
+        Negotiated::new(format, StatusCode::OK, health_check),
+    )
+}
 
-        // Compute public push URL (for broadcaster Lambda to call)
-        let base = DEV_PUBLIC_BASE_URL.clone();
-        let base = base.as_deref().unwrap_or("http://localhost:3001");
-        let push_url = format!("{}/dev/conn/{}/send", base.trim_end_matches('/'), connection_id);
+// GET /health/dependencies - Reachability and latency of every external dependency, for on-call.
+// 503 when any probe failed; unconfigured dependencies are skipped, not failed.
+async fn dependencies_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+) -> impl IntoResponse {
+    let report = state.dependencies.check().await;
+    let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    ([(CACHE_CONTROL, http_cache::HEALTH_CACHE_CONTROL)], Negotiated::new(format, status, report))
+}
 
-        // Write connection record to DynamoDB
-        let now = chrono::Utc::now().timestamp_millis();
-        let ttl = now / 1000 + (60 * 60 * 24);
+// POST /chat/messages - Send a new message. Once the request is valid enough to say who's
+// posting, every answer, refusals included, says where the poster's rate limit stands.
+async fn post_message_handler(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    format: ResponseFormat,
+    Payload(request): Payload<SendMessageRequest>,
+) -> Response {
+    // Buckets are keyed by the resolved id, so anonymous posters don't share one and made-up
+    // ids never reach the limiter or the metrics
+    let poster = match handlers::resolve_poster(&request).await {
+        Ok(poster) => poster,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let mut response =
+        post_message(&state, &poster, peer, headers, format, request).await.into_response();
+    rate_limit_headers(response.headers_mut(), state.rate_limiter.quota(&poster.user_id));
+    response
+}
 
-        let mut item = HashMap::new();
-        item.insert("connection_id".to_string(), AttributeValue::S(connection_id.clone()));
-        item.insert("room_id".to_string(), AttributeValue::S(room_id.clone()));
-        item.insert("user_id".to_string(), AttributeValue::S(user_id.clone()));
-        item.insert("username".to_string(), AttributeValue::S(username.clone()));
-        item.insert("connected_at".to_string(), AttributeValue::N(now.to_string()));
-        item.insert("domain".to_string(), AttributeValue::S("local".to_string()));
-        item.insert("stage".to_string(), AttributeValue::S("local".to_string()));
-        item.insert("transport".to_string(), AttributeValue::S("dev".to_string()));
-        item.insert("push_url".to_string(), AttributeValue::S(push_url));
-        item.insert("ttl".to_string(), AttributeValue::N(ttl.to_string()));
+// X-RateLimit-Limit, -Remaining and -Reset (Unix seconds) from a poster's bucket
+fn rate_limit_headers(headers: &mut HeaderMap, quota: Quota) {
+    headers.insert("x-ratelimit-limit", HeaderValue::from(quota.limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(quota.remaining));
+    headers.insert("x-ratelimit-reset", HeaderValue::from(quota.reset_at.timestamp()));
+}
 
-        if let Err(e) = state
-            .ddb
-            .put_item()
-            .table_name(&*CHAT_CONNECTIONS_TABLE)
-            .set_item(Some(item))
-            .send()
-            .await
-        {
-            tracing::error!("Failed to write dev connection record: {:?}", e);
-        }
+async fn post_message(
+    state: &AppState,
+    poster: &handlers::Poster,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    format: ResponseFormat,
+    request: SendMessageRequest,
+) -> Result<Response, AppError> {
+    tracing::info!("Received message request for room: {}", poster.room_id);
+
+    if let Err(retry_after) = state.rate_limiter.check(&poster.user_id) {
+        tracing::warn!("{} is posting too fast; refused for {:?}", poster.user_id, retry_after);
+        state.metrics.emit_rate_limited(&poster.room_id, &poster.user_id).await;
+        return Err(AppError::Throttled {
+            message: "Too many messages; slow down".to_string(),
+            retry_after: Some(retry_after),
+        });
     }
 
-    // Handle incoming messages
-    #[cfg(feature = "dev")]
+    let source_ip = peer.map(|ConnectInfo(addr)| addr.ip().to_string());
+    let forwarded_for = headers.get("x-forwarded-for").and_then(|value| value.to_str().ok());
+    let client_ip = origin::client_ip(source_ip.as_deref(), forwarded_for);
+
+    match handlers::post_message_handler(state.store.as_ref(), &state.context, request, client_ip)
+        .await
     {
-        loop {
-            tokio::select! {
-                // Outbound server -> client messages (room fan-out)
-                received = rx.recv() => {
-                    match received {
-                        Ok(payload) => {
-                            if let Err(e) = socket.send(Message::Text(payload)).await {
-                                tracing::warn!("Failed to send to {} in room {}: {}", username, room_id, e);
-                                break;
-                            }
-                        }
-                        Err(broadcast::error::RecvError::Closed) => {
-                            tracing::info!("Broadcast channel closed for room {}", room_id);
-                            break;
-                        }
-                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
-                            tracing::warn!("WebSocket for user {} lagged by {} messages in room {}", username, skipped, room_id);
-                        }
+        // A retry of a stored post: it was announced the first time, so just hand it back
+        Ok(handlers::Posted { message, duplicate: true }) => {
+            let location = handlers::message_location(&message);
+            Ok(([(LOCATION, location)], Negotiated::new(format, StatusCode::OK, message))
+                .into_response())
+        }
+        Ok(handlers::Posted { message, duplicate: false }) => {
+            // Invalidate before responding so the sender's next read sees its own message
+            if let Some(cache) = &state.message_cache {
+                cache.invalidate(&message.core.room_id);
+            }
+            // Wake anyone long-polling the room, and in dev hand it to the room's sockets
+            if let Some(tx) = state.channels.read().await.get(&message.core.room_id) {
+                let _ = tx.send(RoomEvent::MessagePosted(Arc::new(message.clone())));
+            }
+            // There's no stream here to index from, so index off the request path
+            if let Some(search) = state.search.clone() {
+                let message = message.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = search.index(&message).await {
+                        tracing::warn!("Failed to index message {}: {}", message.core.id, e);
                     }
-                }
-                // Targeted per-connection push
-                msg_to_send = conn_rx.recv() => {
-                    if let Some(payload) = msg_to_send {
-                        if let Err(e) = socket.send(Message::Text(payload)).await {
-                            tracing::warn!("Failed to send targeted message to {}: {}", username, e);
-                            break;
-                        }
-                    } else {
-                        // Sender dropped
-                        break;
+                });
+            }
+            // Emit metrics for REST message post
+            state
+                .metrics
+                .emit_message_sent(&message.core.room_id, message.core.message_text.len())
+                .await;
+            let location = handlers::message_location(&message);
+            Ok(([(LOCATION, location)], Negotiated::new(format, StatusCode::CREATED, message))
+                .into_response())
+        }
+        Err(err @ (handlers::HandlerError::Conflict(_) | handlers::HandlerError::Throttled(_))) => {
+            tracing::warn!("Message not stored: {}", err);
+            Err(err.into())
+        }
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to post message: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// GET /chat/rooms - A page of the rooms there are, newest first; archived ones only with
+// ?include_archived=true. Page with ?limit= (at most 50) and ?after={next_cursor}.
+async fn list_rooms_handler(
+    State(state): State<AppState>,
+    Query(query): Query<handlers::RoomQuery>,
+) -> Result<Json<types::ListRoomsResponse>, AppError> {
+    match handlers::list_rooms_handler(state.store.as_ref(), query).await {
+        Ok(page) => Ok(Json(page)),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to list rooms: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// POST /chat/rooms/:room_id/archive - Close a room to new messages (admin token required)
+async fn archive_room_handler(
+    State(state): State<AppState>,
+    Path(room_id): Path<RoomId>,
+    headers: HeaderMap,
+) -> Result<Json<types::RoomArchiveState>, AppError> {
+    set_room_archived(state, room_id, &headers, true).await
+}
+
+// POST /chat/rooms/:room_id/unarchive - Reopen an archived room (admin token required)
+async fn unarchive_room_handler(
+    State(state): State<AppState>,
+    Path(room_id): Path<RoomId>,
+    headers: HeaderMap,
+) -> Result<Json<types::RoomArchiveState>, AppError> {
+    set_room_archived(state, room_id, &headers, false).await
+}
+
+async fn set_room_archived(
+    state: AppState,
+    room_id: RoomId,
+    headers: &HeaderMap,
+    archived: bool,
+) -> Result<Json<types::RoomArchiveState>, AppError> {
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    if !admin::is_authorized(token) {
+        return Err(AppError::Forbidden("Admin token required".to_string()));
+    }
+    match handlers::set_room_archived_handler(state.store.as_ref(), room_id, archived).await {
+        Ok(Some(changed)) => Ok(Json(changed)),
+        Ok(None) => Err(AppError::NotFound("Room not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to change archive state of a room: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// POST /chat/messages/:room_id/:id/flag - Flag a message for moderators; enough flags hide it
+async fn flag_message_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Path((room_id, message_id)): Path<(RoomId, MessageId)>,
+    Payload(request): Payload<types::FlagMessageRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Flagging message {} in room {}", message_id, room_id);
+    let source_ip = peer.map(|ConnectInfo(addr)| addr.ip().to_string());
+    let forwarded_for = headers.get("x-forwarded-for").and_then(|value| value.to_str().ok());
+    let flagged = handlers::flag_message_handler(
+        state.store.as_ref(),
+        &state.context,
+        room_id,
+        message_id.clone(),
+        request,
+        origin::client_ip(source_ip.as_deref(), forwarded_for),
+    )
+    .await;
+    match flagged {
+        Ok(Some(flagged)) => {
+            // Hidden messages read back as such, so the cached page is out of date
+            if let (true, Some(cache)) = (flagged.hidden, &state.message_cache) {
+                cache.invalidate(&flagged.room_id);
+            }
+            Ok(Negotiated::new(format, StatusCode::OK, flagged))
+        }
+        Ok(None) => Err(AppError::NotFound("Message not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to flag message {}: {}", message_id, message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// GET /chat/flags - Moderator queue of flagged messages (admin token required)
+async fn flags_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<types::MessageFlag>>, AppError> {
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    if !admin::is_authorized(token) {
+        return Err(AppError::Forbidden("Admin token required".to_string()));
+    }
+    match handlers::flags_handler(state.store.as_ref()).await {
+        Ok(flags) => Ok(Json(flags)),
+        Err(err) => {
+            tracing::error!("Failed to list flags: {}", err);
+            Err(AppError::Internal(err))
+        }
+    }
+}
+
+// PUT /chat/rooms/:room_id/topic - Set or clear a room's topic, relayed live to the room
+async fn set_room_topic_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(room_id): Path<RoomId>,
+    headers: HeaderMap,
+    Payload(request): Payload<types::UpdateTopicRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Setting topic of room {}", room_id);
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    let is_admin = admin::is_authorized(token);
+
+    match handlers::set_room_topic_handler(state.store.as_ref(), room_id, request, is_admin).await {
+        Ok(Some(changed)) => {
+            match serde_json::to_string(&changed) {
+                Ok(payload) => {
+                    if let Some(tx) = state.channels.read().await.get(&changed.room_id) {
+                        let _ = tx.send(RoomEvent::Frame(payload));
                     }
                 }
-                // Inbound client -> server messages (ignored in dev)
-                msg = socket.recv() => {
-                    match msg {
-                        Some(Ok(Message::Text(text))) => {
-                            tracing::info!("Received WebSocket message from {}: {}", username, text);
-                        }
-                        Some(Ok(Message::Close(_))) | None => {
-                            tracing::info!("WebSocket connection closed for user {}", username);
-                            break;
-                        }
-                        Some(Err(e)) => {
-                            tracing::error!("WebSocket error for user {}: {}", username, e);
-                            break;
-                        }
-                        _ => {}
+                Err(err) => tracing::error!("Failed to encode topic change: {}", err),
+            }
+            Ok(Negotiated::new(format, StatusCode::OK, changed))
+        }
+        Ok(None) => Err(AppError::NotFound("Room not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to set room topic: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// PUT /chat/users/:user_id/username - Change a user's display name for future messages
+async fn rename_user_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(user_id): Path<UserId>,
+    Payload(request): Payload<types::UpdateUsernameRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Renaming user {}", user_id);
+
+    match handlers::rename_user_handler(state.store.as_ref(), user_id, request).await {
+        Ok(renamed) => {
+            // Every room, as the user may be connected to any of them
+            match serde_json::to_string(&renamed) {
+                Ok(payload) => {
+                    for tx in state.channels.read().await.values() {
+                        let _ = tx.send(RoomEvent::Frame(payload.clone()));
                     }
                 }
+                Err(err) => tracing::error!("Failed to encode rename: {}", err),
             }
+            Ok(Negotiated::new(format, StatusCode::OK, renamed))
         }
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to rename user: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
     }
+}
 
-    #[cfg(not(feature = "dev"))]
-    {
-        // Minimal loop: only consume client messages and close
-        while let Some(msg) = socket.recv().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    tracing::info!("Received WebSocket message from {}: {}", username, text);
-                }
-                Ok(Message::Close(_)) => {
-                    tracing::info!("WebSocket connection closed for user {}", username);
-                    break;
-                }
-                Err(e) => {
-                    tracing::error!("WebSocket error for user {}: {}", username, e);
-                    break;
-                }
-                _ => {}
+// PUT /chat/users/:user_id/avatar-seed - Set or clear the seed a user's avatar is drawn from
+async fn set_avatar_seed_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(user_id): Path<UserId>,
+    Payload(request): Payload<types::UpdateAvatarSeedRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Setting avatar seed of user {}", user_id);
+
+    match handlers::set_avatar_seed_handler(state.store.as_ref(), user_id, request).await {
+        Ok(profile) => Ok(Negotiated::new(format, StatusCode::OK, profile)),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to set avatar seed: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// GET /chat/messages/:room_id - Retrieve a page of messages (25, or `limit`), newest first
+// unless `order=asc`, optionally within a ts window or read back from a `before` cursor
+async fn get_messages_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    headers: HeaderMap,
+    Path(room_id): Path<RoomId>,
+    Query(query): Query<handlers::MessageQuery>,
+) -> Result<Response, AppError> {
+    tracing::info!("Retrieving messages for room: {}", room_id);
+    let if_none_match = headers.get(IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+
+    query.validate().map_err(AppError::BadRequest)?;
+    let fields = query.fields().map_err(AppError::BadRequest)?;
+
+    // Only the whole default page is cached, as an anonymous reader sees it; range, projected
+    // and per-user queries always go to DynamoDB
+    let cache = state
+        .message_cache
+        .as_ref()
+        .filter(|_| query.is_unbounded() && fields.is_none() && query.user_id.is_none());
+    if let (Some(cache), Ok(key)) = (cache, handlers::validate_room_id(&room_id)) {
+        if let Some(response) = cache.get(&key) {
+            return Ok(messages_response(format, if_none_match, response, None));
+        }
+    }
+    let generation = cache.map(|cache| cache.generation());
+
+    match handlers::get_messages_handler(state.store.as_ref(), room_id, query).await {
+        Ok(response) => {
+            if let (Some(cache), Some(generation)) = (cache, generation) {
+                cache.insert(&response.room_id, response.clone(), generation);
             }
+            Ok(messages_response(format, if_none_match, response, fields.as_deref()))
+        }
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to get messages: {}", message);
+            Err(AppError::Internal(message))
         }
+        Err(err) => Err(err.into()),
     }
+}
 
-    tracing::info!("WebSocket disconnected: {} ({}) from room {}", username, user_id, room_id);
+// The page, trimmed to `fields` if given, or a bare 304 when the client already holds it
+fn messages_response(
+    format: ResponseFormat,
+    if_none_match: Option<&str>,
+    response: types::GetMessagesResponse,
+    fields: Option<&[&str]>,
+) -> Response {
+    let etag = http_cache::messages_etag(&response.messages);
+    let cache_control = CACHE_POLICY.messages_cache_control();
+    if http_cache::if_none_match(if_none_match, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag), (CACHE_CONTROL, cache_control)])
+            .into_response();
+    }
 
-    // Cleanup dev connection mapping and DynamoDB record
+    let server_time = response.server_time.to_rfc3339();
+    let body = match fields.map(|fields| handlers::project_messages(&response, fields)) {
+        None => Negotiated::new(format, StatusCode::OK, response).into_response(),
+        Some(Ok(page)) => Negotiated::new(format, StatusCode::OK, page).into_response(),
+        Some(Err(err)) => {
+            tracing::error!("Failed to project messages: {}", err);
+            AppError::internal().into_response()
+        }
+    };
+    (
+        [
+            (HeaderName::from_static(handlers::SERVER_TIME_HEADER), server_time),
+            (ETAG, etag),
+            (CACHE_CONTROL, cache_control),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct GetMessageQuery {
+    // The reader, so a direct message to or from them can be read
+    user_id: Option<String>,
+}
+
+// GET /chat/messages/:room_id/:id?user_id= - Retrieve a single message
+async fn get_message_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path((room_id, message_id)): Path<(RoomId, MessageId)>,
+    Query(query): Query<GetMessageQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Retrieving message {} in room {}", message_id, room_id);
+
+    let viewer = query.user_id.as_deref();
+    match handlers::get_message_handler(state.store.as_ref(), room_id, message_id, viewer).await {
+        Ok(Some(message)) => Ok(Negotiated::new(format, StatusCode::OK, message)),
+        Ok(None) => Err(AppError::NotFound("Message not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to get message: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// PATCH /chat/messages/:room_id/:id - Edit a message's text, by its poster only
+async fn edit_message_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path((room_id, message_id)): Path<(RoomId, MessageId)>,
+    headers: HeaderMap,
+    Payload(request): Payload<types::EditMessageRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::info!("Editing message {} in room {}", message_id, room_id);
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    let edited = handlers::edit_message_handler(
+        state.store.as_ref(),
+        &state.context,
+        room_id,
+        message_id.clone(),
+        request,
+        admin::is_authorized(token),
+    )
+    .await;
+    match edited {
+        Ok(Some(message)) => {
+            if let Some(cache) = &state.message_cache {
+                cache.invalidate(&message.core.room_id);
+            }
+            if let Some(tx) = state.channels.read().await.get(&message.core.room_id) {
+                let _ = tx.send(RoomEvent::MessageEdited(Arc::new(message.clone())));
+            }
+            // Replaces the indexed copy, as the stream does when deployed
+            if let Some(search) = state.search.clone() {
+                let message = message.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = search.index(&message).await {
+                        tracing::warn!("Failed to index message {}: {}", message.core.id, e);
+                    }
+                });
+            }
+            Ok(Negotiated::new(format, StatusCode::OK, message))
+        }
+        Ok(None) => Err(AppError::NotFound("Message not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to edit message {}: {}", message_id, message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteMessageQuery {
+    // The requester, who must be the message's poster
+    user_id: Option<String>,
+}
+
+// DELETE /chat/messages/:room_id/:id?user_id= - Delete a message, by its poster only
+async fn delete_message_handler(
+    State(state): State<AppState>,
+    Path((room_id, message_id)): Path<(RoomId, MessageId)>,
+    Query(query): Query<DeleteMessageQuery>,
+) -> Result<StatusCode, AppError> {
+    tracing::info!("Deleting message {} in room {}", message_id, room_id);
+    let deleted = handlers::delete_message_handler(
+        state.store.as_ref(),
+        &state.metrics,
+        room_id,
+        message_id.clone(),
+        query.user_id.as_deref(),
+    )
+    .await;
+    match deleted {
+        Ok(Some(message)) => {
+            if let Some(cache) = &state.message_cache {
+                cache.invalidate(&message.core.room_id);
+            }
+            let room_id = message.core.room_id.clone();
+            let message = Arc::new(message);
+            if let Some(tx) = state.channels.read().await.get(&room_id) {
+                let _ = tx.send(RoomEvent::MessageDeleted(message.clone()));
+            }
+            // Drops it from the index, as the stream does when deployed
+            if let Some(search) = state.search.clone() {
+                tokio::spawn(async move {
+                    if let Err(e) = search.remove(&room_id, &message.core.id).await {
+                        tracing::warn!("Failed to unindex message {}: {}", message.core.id, e);
+                    }
+                });
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(None) => Err(AppError::NotFound("Message not found".to_string())),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to delete message {}: {}", message_id, message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// POST /chat/messages/latest - Newest messages of several rooms at once
+async fn latest_messages_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Payload(request): Payload<LatestMessagesRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    match handlers::latest_messages_handler(state.store.as_ref(), request).await {
+        Ok(latest) => {
+            // Some rooms couldn't be read; the rest are still worth showing
+            let status =
+                if latest.errors.is_empty() { StatusCode::OK } else { StatusCode::MULTI_STATUS };
+            Ok(Negotiated::new(format, status, latest))
+        }
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to get latest messages: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// GET /chat/rooms/:room_id/stats - Message, connection and participant counts for dashboards
+async fn room_stats_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(room_id): Path<RoomId>,
+) -> Result<impl IntoResponse, AppError> {
+    // Connections are only recorded for dev WebSocket clients
     #[cfg(feature = "dev")]
-    {
-        use aws_sdk_dynamodb::types::AttributeValue;
-        state.conn_senders.write().await.remove(&connection_id);
-        if let Err(e) = state
-            .ddb
-            .delete_item()
-            .table_name(&*CHAT_CONNECTIONS_TABLE)
-            .key("connection_id", AttributeValue::S(connection_id))
-            .send()
-            .await
-        {
-            tracing::warn!("Failed to delete dev connection record: {:?}", e);
+    let connections = Some((&state.ddb, CHAT_CONNECTIONS_TABLE.as_str()));
+    #[cfg(not(feature = "dev"))]
+    let connections = None;
+
+    match handlers::room_stats_handler(state.store.as_ref(), connections, room_id).await {
+        Ok(stats) => Ok(Negotiated::new(format, StatusCode::OK, stats)),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to get room stats: {}", message);
+            Err(AppError::Internal(message))
         }
+        Err(err) => Err(err.into()),
     }
 }
 
-// Dev-only: Per-connection send endpoint for broadcaster Lambda to push to a specific connection
-#[cfg(feature = "dev")]
-async fn dev_conn_send_handler(
+// GET /chat/rooms/:room_id/presence - Who's connected to the room, one username per user
+async fn room_presence_handler(
     State(state): State<AppState>,
-    Path(connection_id): Path<String>,
-    Json(message): Json<ChatMessage>,
+    format: ResponseFormat,
+    Path(room_id): Path<RoomId>,
 ) -> Result<impl IntoResponse, AppError> {
-    let payload = serde_json::to_string(&message).map_err(AppError::from_error)?;
+    // Connections are only recorded for dev WebSocket clients
+    #[cfg(feature = "dev")]
+    let connections = Some((&state.ddb, CHAT_CONNECTIONS_TABLE.as_str()));
+    #[cfg(not(feature = "dev"))]
+    let connections = None;
 
-    let maybe_sender = { state.conn_senders.read().await.get(&connection_id).cloned() };
-    if let Some(sender) = maybe_sender {
-        if let Err(_e) = sender.send(payload).await {
-            return Ok((StatusCode::GONE, Json(json!({ "status": "gone" }))));
+    let now = state.context.clock.now().timestamp();
+    match handlers::room_presence_handler(connections, room_id, now).await {
+        Ok(presence) => Ok(Negotiated::new(format, StatusCode::OK, presence)),
+        Err(err) => {
+            tracing::error!("Failed to get room presence: {}", err);
+            Err(AppError::Internal(err))
         }
-        Ok((StatusCode::OK, Json(json!({ "status": "ok" }))))
-    } else {
-        Ok((StatusCode::NOT_FOUND, Json(json!({ "status": "not_found" }))))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::{
-        body::Body,
-        http::{Method, Request, StatusCode},
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+// GET /chat/rooms/:room_id/search?q= - Messages matching the text, newest first
+async fn search_messages_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(room_id): Path<RoomId>,
+    Query(query): Query<SearchQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let search = state.search.as_deref();
+    match handlers::search_messages_handler(state.store.as_ref(), search, room_id, query.q).await {
+        Ok(response) => Ok(Negotiated::new(format, StatusCode::OK, response)),
+        Err(handlers::HandlerError::Internal(message)) => {
+            tracing::error!("Failed to search messages: {}", message);
+            Err(AppError::Internal(message))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+// GET /chat/rooms/:room_id/export - Admin download of a room's messages as CSV or JSONL,
+// streamed a page at a time
+async fn export_messages_handler(
+    State(state): State<AppState>,
+    Path(room_id): Path<RoomId>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    if !admin::is_authorized(token) {
+        return Err(AppError::Forbidden("Admin token required".to_string()));
+    }
+
+    let format = match query.format {
+        Some(format) => format.parse::<ExportFormat>().map_err(AppError::BadRequest)?,
+        None => ExportFormat::default(),
     };
-    use backend::handlers::Tables;
-    // use http_body_util::BodyExt; // Unused due to test simplification
-    use tower::ServiceExt;
+    let disposition = format.content_disposition(&room_id);
+    let chunks = export::export_messages(state.store.clone(), room_id, format)
+        .map_err(AppError::BadRequest)?;
 
-    #[tokio::test]
-    #[ignore] // TODO: Fix body collection issue
-    async fn test_health_endpoint() {
-        // Create a mock DynamoDB client for testing
-        let aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest()).load().await;
-        let ddb_client = DynamoDbClient::new(&aws_config);
-        let metrics = backend::MetricsHelper::new().await;
-        let state = AppState {
-            ddb: ddb_client,
-            tables: Tables {
-                messages: "chat-messages".to_string(),
-                rooms: "chat-rooms".to_string(),
-            },
-            metrics,
-        };
+    // A failed page ends the response early; the headers are already on their way by then
+    let body = StreamBody::new(futures_util::TryStreamExt::map_err(chunks, |err| {
+        tracing::error!("Failed to export messages: {}", err);
+        std::io::Error::other(err)
+    }));
+    Ok((
+        [(CONTENT_TYPE, format.content_type().to_string()), (CONTENT_DISPOSITION, disposition)],
+        body,
+    )
+        .into_response())
+}
 
-        let app = create_app(state);
+// GET /admin/metrics/snapshot - Admin JSON overview of what this server has counted in-process
+// (messages posted, connections open, broadcast success rate, errors), without CloudWatch
+async fn metrics_snapshot_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<MetricsSnapshot>, AppError> {
+    let token = headers.get(admin::ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    if !admin::is_authorized(token) {
+        return Err(AppError::Forbidden("Admin token required".to_string()));
+    }
+    Ok(Json(state.metrics.snapshot()))
+}
 
-        let response = app
-            .oneshot(
-                Request::builder().method(Method::GET).uri("/health").body(Body::empty()).unwrap(),
-            )
-            .await
-            .unwrap();
+#[derive(Debug, Deserialize)]
+struct PollQuery {
+    // The last response's cursor, or epoch millis of the newest message the client has;
+    // defaults to now
+    cursor: Option<String>,
+    // The reader, so direct messages to them come through
+    user_id: Option<String>,
+}
 
+// GET /chat/messages/:room_id/poll - Long-poll fallback for clients that can't use the WebSocket.
+// Answers as soon as there are messages past the cursor, or with an empty page after POLL_TIMEOUT.
+async fn poll_messages_handler(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(room_id): Path<RoomId>,
+    Query(query): Query<PollQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let room_key = handlers::validate_room_id(&room_id).map_err(AppError::BadRequest)?;
+    let cursor = query
+        .cursor
+        .unwrap_or_else(|| handlers::poll_cursor(state.context.clock.now().timestamp_millis()));
+
+    // Subscribe before the first read so a post landing in between still wakes us
+    let mut events = room_channel(&state, &room_key).await.subscribe();
+    let deadline = tokio::time::Instant::now() + *POLL_TIMEOUT;
+    loop {
+        let response = handlers::poll_messages_handler(
+            state.store.as_ref(),
+            room_id.clone(),
+            &cursor,
+            query.user_id.as_deref(),
+        )
+        .await
+        .map_err(|err| match err {
+            handlers::HandlerError::Internal(message) => {
+                tracing::error!("Failed to poll messages: {}", message);
+                AppError::Internal(message)
+            }
+            err => err.into(),
+        })?;
+        if !response.messages.is_empty() || !wait_for_post(&mut events, deadline).await {
+            return Ok(Negotiated::new(format, StatusCode::OK, response));
+        }
+    }
+}
+
+// Wait for a message to be posted to the room; false once the deadline passes
+async fn wait_for_post(
+    events: &mut broadcast::Receiver<RoomEvent>,
+    deadline: tokio::time::Instant,
+) -> bool {
+    loop {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Err(_) => return false,
+            // Missed events may have included a post, so re-check the store
+            Ok(Ok(RoomEvent::MessagePosted(_)))
+            | Ok(Err(broadcast::error::RecvError::Lagged(_))) => return true,
+            Ok(Ok(
+                RoomEvent::Frame(_) | RoomEvent::MessageEdited(_) | RoomEvent::MessageDeleted(_),
+            )) => {}
+            // The room's channel only closes at shutdown; answer now so the server can drain
+            Ok(Err(broadcast::error::RecvError::Closed)) => return false,
+        }
+    }
+}
+
+// The room's local broadcast channel, created on first use
+async fn room_channel(state: &AppState, room_id: &str) -> broadcast::Sender<RoomEvent> {
+    let mut channels = state.channels.write().await;
+    channels
+        .entry(room_id.to_string())
+        .or_insert_with(|| broadcast::channel::<RoomEvent>(100).0)
+        .clone()
+}
+
+// WebSocket query parameters
+#[derive(Debug, Deserialize)]
+struct WebSocketParams {
+    room_id: Option<String>,
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+    username: Option<String>,
+    // When a reconnecting client last heard from the room (epoch milliseconds)
+    last_seen_ts: Option<i64>,
+}
+
+// Drop rate limit buckets that have refilled, so users who stopped posting aren't kept
+async fn rate_limit_pruner(limiter: Arc<MessageRateLimiter>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        limiter.prune();
+    }
+}
+
+// Broadcast `is_typing: false` for indicators that were never cleared by their client
+#[cfg(feature = "dev")]
+async fn typing_sweeper(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        interval.tick().await;
+        let cleared = state.typing.lock().unwrap().sweep(Instant::now());
+        if cleared.is_empty() {
+            continue;
+        }
+
+        let channels = state.channels.read().await;
+        for indicator in cleared {
+            tracing::debug!(
+                "Typing indicator for {} in room {} timed out",
+                indicator.username,
+                indicator.room_id
+            );
+            let room_id = indicator.room_id.clone();
+            if let (Some(tx), Ok(payload)) =
+                (channels.get(&room_id), serde_json::to_string(&WsServerMessage::Typing(indicator)))
+            {
+                let _ = tx.send(RoomEvent::Frame(payload));
+            }
+        }
+    }
+}
+
+// Tell the room's sockets on this server who's there now, after a join or leave
+#[cfg(feature = "dev")]
+async fn send_presence(state: &AppState, tx: &broadcast::Sender<RoomEvent>, room_id: &str) {
+    let now = state.context.clock.now().timestamp();
+    match handlers::live_room_connections(&state.ddb, &CHAT_CONNECTIONS_TABLE, room_id, now).await {
+        Ok(connections) => {
+            let frame = WsServerMessage::Presence {
+                room_id: room_id.to_string(),
+                users: handlers::presence_users(&connections),
+            };
+            if let Ok(payload) = serde_json::to_string(&frame) {
+                let _ = tx.send(RoomEvent::Frame(payload));
+            }
+        }
+        Err(e) => tracing::warn!("Failed to read presence of room {}: {}", room_id, e),
+    }
+}
+
+// Periodic ActiveConnections gauges, as the scheduled ws-connection-gauge lambda emits them
+#[cfg(feature = "dev")]
+async fn connection_gauge_emitter(state: AppState) {
+    let mut interval = tokio::time::interval(*CONNECTION_GAUGE_INTERVAL);
+    let mut last_tick = std::collections::BTreeSet::new();
+    loop {
+        interval.tick().await;
+        match backend::connection_gauge::emit_connection_gauges(
+            &state.ddb,
+            &CHAT_CONNECTIONS_TABLE,
+            &last_tick,
+            &state.metrics,
+        )
+        .await
+        {
+            Ok(counts) => last_tick = backend::connection_gauge::connected_rooms(&counts),
+            Err(err) => tracing::warn!("Failed to emit connection gauges: {}", err),
+        }
+    }
+}
+
+// WebSocket handler for development
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<WebSocketParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let room_id = params.room_id.unwrap_or_else(|| "general".to_string());
+    let seed = state.context.ids.next_id();
+    let identity = match ANONYMOUS_POLICY.resolve(
+        params.user_id.as_deref(),
+        params.username.as_deref(),
+        &seed,
+    ) {
+        Ok(identity) => identity,
+        Err(message) => {
+            tracing::warn!("Rejecting WebSocket connection to room {}: {}", room_id, message);
+            return (StatusCode::UNAUTHORIZED, message).into_response();
+        }
+    };
+    if let Err(e) = handlers::validate_user_id(&identity.user_id) {
+        tracing::warn!("Rejecting WebSocket connection to room {}: {}", room_id, e);
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    let (user_id, username) = (identity.user_id, identity.username);
+
+    tracing::info!(
+        "WebSocket connection request: room={}, user={}, username={}",
+        room_id,
+        user_id,
+        username
+    );
+
+    let last_seen_ts = params.last_seen_ts;
+    ws.on_upgrade(move |socket| {
+        handle_websocket(socket, room_id, user_id, username, last_seen_ts, state)
+    })
+}
+
+// Close a socket the server is giving up on, telling the client when to reconnect. The
+// window grows with the number of sockets open, as they're likely being closed together.
+async fn close_with_hint(socket: &mut WebSocket, reason: ReconnectReason, state: &AppState) {
+    let open = state.shutting_down.receiver_count();
+    let jitter = uuid::Uuid::new_v4().as_u64_pair().0 as f64 / u64::MAX as f64;
+    let hint = RECONNECT_BACKOFF.hint(reason, open, jitter);
+    let (code, error) = match reason {
+        ReconnectReason::Shutdown => {
+            (close_code::AWAY, (WsErrorCode::ServerShutdown, "Server is shutting down"))
+        }
+        ReconnectReason::Overloaded => {
+            (close_code::AGAIN, (WsErrorCode::RateLimited, "Too many messages waiting to be sent"))
+        }
+    };
+    let error = WsError {
+        code: error.0,
+        message: error.1.to_string(),
+        retry_after_ms: Some(hint.after_ms.into()),
+    };
+    let _ = socket.send(Message::Text(ws_error::encode(&error))).await;
+    let close = CloseFrame { code, reason: reconnect::close_reason(&hint).into() };
+    let _ = socket.send(Message::Close(Some(close))).await;
+}
+
+// Send a reconnecting socket the room's messages since `last_seen_ts`, as live deliveries look
+async fn replay_missed(
+    socket: &mut WebSocket,
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    last_seen_ts: i64,
+) {
+    let missed = match handlers::missed_messages(
+        state.store.as_ref(),
+        room_id,
+        user_id,
+        last_seen_ts,
+        *RESUME_MAX_MESSAGES,
+    )
+    .await
+    {
+        Ok(missed) => missed,
+        Err(err) => {
+            tracing::error!("Failed to read missed messages in room {}: {}", room_id, err);
+            return;
+        }
+    };
+    tracing::info!(
+        "Replaying {} missed message(s) to {} in room {}",
+        missed.len(),
+        user_id,
+        room_id
+    );
+    for message in missed {
+        let payload = backend::broadcast::message_frame(&message);
+        if socket.send(Message::Text(payload)).await.is_err() {
+            return;
+        }
+    }
+}
+
+// WebSocket connection handler
+async fn handle_websocket(
+    mut socket: WebSocket,
+    room_id: String,
+    user_id: String,
+    username: String,
+    last_seen_ts: Option<i64>,
+    state: AppState,
+) {
+    tracing::info!("WebSocket connected: {} ({}) in room {}", username, user_id, room_id);
+    let mut shutting_down = state.shutting_down.subscribe();
+
+    // Connections are only recorded in dev, so that's where the per-user limit applies
+    #[cfg(feature = "dev")]
+    {
+        match CONNECTION_LIMIT.admits(&state.ddb, &CHAT_CONNECTIONS_TABLE, &user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    "Closing WebSocket for {}: over {} connection(s)",
+                    user_id,
+                    CONNECTION_LIMIT.max_per_user
+                );
+                let close =
+                    CloseFrame { code: close_code::POLICY, reason: TOO_MANY_CONNECTIONS.into() };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                return;
+            }
+            Err(e) => tracing::error!("Failed to check connection limit for {}: {}", user_id, e),
+        }
+    }
+    state.metrics.emit_connection_event("connect", &room_id, None).await;
+
+    #[cfg(feature = "dev")]
+    let tx = room_channel(&state, &room_id).await;
+
+    #[cfg(feature = "dev")]
+    let mut rx = tx.subscribe();
+
+    // For development, create a per-connection sender and store connection in DynamoDB
+    #[cfg(feature = "dev")]
+    let connection_id = state.context.ids.next_id();
+    #[cfg(feature = "dev")]
+    let send_queue = Arc::new(SendQueue::from_env());
+    #[cfg(feature = "dev")]
+    {
+        use std::collections::HashMap;
+
+        use aws_sdk_dynamodb::types::AttributeValue;
+
+        state.conn_senders.write().await.insert(connection_id.clone(), send_queue.clone());
+
+        // Compute public push URL (for broadcaster Lambda to call)
+        let base = DEV_PUBLIC_BASE_URL.clone();
+        let base = base.as_deref().unwrap_or("http://localhost:3001");
+        let push_url = format!("{}/dev/conn/{}/send", base.trim_end_matches('/'), connection_id);
+
+        // Write connection record to DynamoDB
+        let now = state.context.clock.now().timestamp_millis();
+        let ttl = now / 1000 + (60 * 60 * 24);
+
+        let mut item = HashMap::new();
+        item.insert("connection_id".to_string(), AttributeValue::S(connection_id.clone()));
+        item.insert("room_id".to_string(), AttributeValue::S(room_id.clone()));
+        item.insert("user_id".to_string(), AttributeValue::S(user_id.clone()));
+        item.insert("username".to_string(), AttributeValue::S(username.clone()));
+        item.insert("connected_at".to_string(), AttributeValue::N(now.to_string()));
+        item.insert("domain".to_string(), AttributeValue::S("local".to_string()));
+        item.insert("stage".to_string(), AttributeValue::S("local".to_string()));
+        item.insert("transport".to_string(), AttributeValue::S("dev".to_string()));
+        item.insert("push_url".to_string(), AttributeValue::S(push_url));
+        item.insert("ttl".to_string(), AttributeValue::N(ttl.to_string()));
+
+        if let Err(e) = state
+            .ddb
+            .put_item()
+            .table_name(&*CHAT_CONNECTIONS_TABLE)
+            .set_item(Some(item))
+            .send()
+            .await
+        {
+            tracing::error!("Failed to write dev connection record: {:?}", e);
+        }
+        send_presence(&state, &tx, &room_id).await;
+    }
+
+    // A reconnecting client gets what it missed before live traffic resumes. In dev the room is
+    // already subscribed, so a message posted meanwhile can arrive twice; clients drop repeats
+    // by id.
+    if let Some(last_seen_ts) = last_seen_ts {
+        replay_missed(&mut socket, &state, &room_id, &user_id, last_seen_ts).await;
+    }
+
+    // Handle incoming messages
+    #[cfg(feature = "dev")]
+    {
+        loop {
+            tokio::select! {
+                // Outbound server -> client messages (room fan-out)
+                received = rx.recv() => {
+                    match received {
+                        Ok(RoomEvent::Frame(payload)) => {
+                            if let Err(e) = socket.send(Message::Text(payload)).await {
+                                tracing::warn!("Failed to send to {} in room {}: {}", username, room_id, e);
+                                break;
+                            }
+                        }
+                        Ok(RoomEvent::MessagePosted(message) | RoomEvent::MessageEdited(message)) => {
+                            // Direct messages only reach their sender and recipient
+                            if !*DEV_INPROCESS_BROADCAST || !handlers::visible_to(&message, Some(&user_id)) {
+                                continue;
+                            }
+                            let payload = backend::broadcast::message_frame(&message);
+                            if let Err(e) = socket.send(Message::Text(payload)).await {
+                                tracing::warn!("Failed to send to {} in room {}: {}", username, room_id, e);
+                                break;
+                            }
+                        }
+                        Ok(RoomEvent::MessageDeleted(message)) => {
+                            if !handlers::visible_to(&message, Some(&user_id)) {
+                                continue;
+                            }
+                            let payload = backend::broadcast::deletion_frame(&message);
+                            if let Err(e) = socket.send(Message::Text(payload)).await {
+                                tracing::warn!("Failed to send to {} in room {}: {}", username, room_id, e);
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            tracing::info!("Broadcast channel closed for room {}", room_id);
+                            break;
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!("WebSocket for user {} lagged by {} messages in room {}", username, skipped, room_id);
+                        }
+                    }
+                }
+                // Targeted per-connection push
+                msg_to_send = send_queue.pop() => {
+                    if let Some(payload) = msg_to_send {
+                        if let Err(e) = socket.send(Message::Text(payload)).await {
+                            tracing::warn!("Failed to send targeted message to {}: {}", username, e);
+                            break;
+                        }
+                    } else {
+                        tracing::warn!("Send queue for {} overflowed, disconnecting", username);
+                        close_with_hint(&mut socket, ReconnectReason::Overloaded, &state).await;
+                        break;
+                    }
+                }
+                _ = shutting_down.changed() => {
+                    close_with_hint(&mut socket, ReconnectReason::Shutdown, &state).await;
+                    break;
+                }
+                // Inbound client -> server messages (typing frames are relayed, echo frames answered)
+                msg = socket.recv() => {
+                    match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            tracing::info!("Received WebSocket message from {}: {}", username, text);
+                            if let Err(error) = ws_error::check_frame(&text, WS_POLICY.max_frame_bytes) {
+                                tracing::warn!("Bad frame from {}: {}", username, error.message);
+                                if let Err(e) = socket.send(Message::Text(ws_error::encode(&error))).await {
+                                    tracing::warn!("Failed to send error to {}: {}", username, e);
+                                    break;
+                                }
+                            } else if let Some(reply) = WS_ECHO.reply(&text) {
+                                if let Err(e) = socket.send(Message::Text(reply.to_string())).await {
+                                    tracing::warn!("Failed to echo to {}: {}", username, e);
+                                    break;
+                                }
+                            } else {
+                                match ws_error::parse_client_frame(&text) {
+                                    Ok(WsClientMessage::Typing { is_typing }) => {
+                                        let indicator = TypingIndicator {
+                                            room_id: room_id.clone(),
+                                            user_id: user_id.clone(),
+                                            username: username.clone(),
+                                            is_typing,
+                                        };
+                                        state.typing.lock().unwrap().update(&indicator, Instant::now());
+                                        if let Ok(payload) = serde_json::to_string(&WsServerMessage::Typing(indicator)) {
+                                            let _ = tx.send(RoomEvent::Frame(payload));
+                                        }
+                                    }
+                                    // Echoing is off
+                                    Ok(WsClientMessage::Echo) => {}
+                                    Err(error) => {
+                                        tracing::warn!("Unknown frame from {}", username);
+                                        if let Err(e) = socket.send(Message::Text(ws_error::encode(&error))).await {
+                                            tracing::warn!("Failed to send error to {}: {}", username, e);
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => {
+                            tracing::info!("WebSocket connection closed for user {}", username);
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            tracing::error!("WebSocket error for user {}: {}", username, e);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "dev"))]
+    {
+        // Minimal loop: answer echo frames, consume everything else and close
+        loop {
+            let msg = tokio::select! {
+                _ = shutting_down.changed() => {
+                    close_with_hint(&mut socket, ReconnectReason::Shutdown, &state).await;
+                    break;
+                }
+                msg = socket.recv() => msg,
+            };
+            match msg {
+                Some(Ok(Message::Text(text))) => {
+                    tracing::info!("Received WebSocket message from {}: {}", username, text);
+                    if let Err(error) = ws_error::check_frame(&text, WS_POLICY.max_frame_bytes) {
+                        tracing::warn!("Bad frame from {}: {}", username, error.message);
+                        if let Err(e) = socket.send(Message::Text(ws_error::encode(&error))).await {
+                            tracing::warn!("Failed to send error to {}: {}", username, e);
+                            break;
+                        }
+                    } else if let Some(reply) = WS_ECHO.reply(&text) {
+                        if let Err(e) = socket.send(Message::Text(reply.to_string())).await {
+                            tracing::warn!("Failed to echo to {}: {}", username, e);
+                            break;
+                        }
+                    } else if let Err(error) = ws_error::parse_client_frame(&text) {
+                        tracing::warn!("Unknown frame from {}", username);
+                        if let Err(e) = socket.send(Message::Text(ws_error::encode(&error))).await {
+                            tracing::warn!("Failed to send error to {}: {}", username, e);
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    tracing::info!("WebSocket connection closed for user {}", username);
+                    break;
+                }
+                Some(Err(e)) => {
+                    tracing::error!("WebSocket error for user {}: {}", username, e);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    tracing::info!("WebSocket disconnected: {} ({}) from room {}", username, user_id, room_id);
+    state.metrics.emit_connection_event("disconnect", &room_id, None).await;
+
+    // Cleanup dev connection mapping and DynamoDB record
+    #[cfg(feature = "dev")]
+    {
+        use aws_sdk_dynamodb::types::AttributeValue;
+        send_queue.close();
+        state.conn_senders.write().await.remove(&connection_id);
+        if let Err(e) = state
+            .ddb
+            .delete_item()
+            .table_name(&*CHAT_CONNECTIONS_TABLE)
+            .key("connection_id", AttributeValue::S(connection_id))
+            .send()
+            .await
+        {
+            tracing::warn!("Failed to delete dev connection record: {:?}", e);
+        }
+        send_presence(&state, &tx, &room_id).await;
+    }
+}
+
+// Dev-only: Per-connection send endpoint for broadcaster Lambda to push to a specific connection
+#[cfg(feature = "dev")]
+async fn dev_conn_send_handler(
+    State(state): State<AppState>,
+    Path(connection_id): Path<String>,
+    Json(message): Json<ChatMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let payload =
+        serde_json::to_string(&WsServerMessage::Message(Box::new(message))).map_err(|err| {
+            tracing::error!("Failed to encode pushed message: {}", err);
+            AppError::internal()
+        })?;
+
+    let maybe_queue = { state.conn_senders.read().await.get(&connection_id).cloned() };
+    if let Some(queue) = maybe_queue {
+        match queue.push(payload) {
+            Enqueued::Queued => {}
+            Enqueued::Dropped(_) => {
+                tracing::warn!("Send queue full for connection {}, dropped a frame", connection_id);
+                state.metrics.emit_ws_frame_dropped(queue.policy().as_str()).await;
+            }
+            Enqueued::Closed => {
+                state.metrics.emit_ws_frame_dropped(queue.policy().as_str()).await;
+                return Ok((StatusCode::GONE, Json(json!({ "status": "gone" }))));
+            }
+        }
+        Ok((StatusCode::OK, Json(json!({ "status": "ok" }))))
+    } else {
+        Ok((StatusCode::NOT_FOUND, Json(json!({ "status": "not_found" }))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_config::SdkConfig;
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use backend::{
+        dependencies::{Dependency, Probe},
+        handlers::Tables,
+        store::{message_sort_key, MemoryMessageStore, PutMessageError, StoreError},
+    };
+    // use http_body_util::BodyExt; // Unused due to test simplification
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    #[ignore] // TODO: Fix body collection issue
+    async fn test_health_endpoint() {
+        // Create a mock DynamoDB client for testing
+        let aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest()).load().await;
+        let ddb_client = DynamoDbClient::new(&aws_config);
+        let metrics = backend::MetricsHelper::new().await;
+        let tables =
+            Tables { messages: "chat-messages".to_string(), rooms: "chat-rooms".to_string() };
+        let state = AppState {
+            store: Arc::new(DynamoMessageStore::new(ddb_client.clone(), tables)),
+            #[cfg(feature = "dev")]
+            ddb: ddb_client,
+            metrics,
+            context: RequestContext::default(),
+            search: None,
+            message_cache: None,
+            dependencies: Arc::new(DependencyHealth::new(&offline_config(), Vec::new())),
+            rate_limiter: Arc::new(MessageRateLimiter::new(30.0, backend::clock::system())),
+            channels: Arc::default(),
+            shutting_down: Arc::new(watch::channel(false).0),
+            #[cfg(feature = "dev")]
+            conn_senders: Arc::default(),
+            #[cfg(feature = "dev")]
+            typing: Arc::new(std::sync::Mutex::new(TypingTracker::from_env())),
+        };
+
+        let app = create_app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder().method(Method::GET).uri("/health").body(Body::empty()).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // TODO: Add body deserialization test when body collection is fixed
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    // Echo route exercising the same extractor/responder pair as the chat handlers
+    fn negotiation_app() -> Router {
+        async fn echo(
+            format: ResponseFormat,
+            Payload(message): Payload<types::ChatMessage>,
+        ) -> Negotiated<types::ChatMessage> {
+            Negotiated::new(format, StatusCode::CREATED, message)
+        }
+
+        Router::new().route("/echo", post(echo))
+    }
+
+    fn sample_message() -> types::ChatMessage {
+        types::ChatMessage {
+            core: types::MessageCore {
+                id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: chrono::Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: types::MessageStatus::Stored,
+            format: types::MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: types::MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_request_and_response() {
+        let message = sample_message();
+        let body = rmp_serde::to_vec_named(&message).unwrap();
+
+        let response = negotiation_app()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/echo")
+                    .header(CONTENT_TYPE, "application/msgpack")
+                    .header(ACCEPT, "application/msgpack")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/msgpack");
+
+        let echoed: types::ChatMessage =
+            rmp_serde::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(serde_json::to_value(&echoed).unwrap(), serde_json::to_value(&message).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_json_remains_default() {
+        let message = sample_message();
+
+        let response = negotiation_app()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/echo")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&message).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let echoed: serde_json::Value =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(echoed, serde_json::to_value(&message).unwrap());
+    }
+
+    // AWS config for clients that are never called
+    fn offline_config() -> SdkConfig {
+        SdkConfig::builder().behavior_version(aws_config::BehaviorVersion::latest()).build()
+    }
+
+    // State backed by an in-memory store, so no DynamoDB is needed
+    async fn offline_state() -> AppState {
+        AppState {
+            store: Arc::new(MemoryMessageStore::new()),
+            #[cfg(feature = "dev")]
+            ddb: DynamoDbClient::new(&offline_config()),
+            metrics: backend::MetricsHelper::new().await,
+            context: RequestContext::default(),
+            search: None,
+            message_cache: None,
+            dependencies: Arc::new(DependencyHealth::new(&offline_config(), Vec::new())),
+            rate_limiter: Arc::new(MessageRateLimiter::new(30.0, backend::clock::system())),
+            channels: Arc::default(),
+            shutting_down: Arc::new(watch::channel(false).0),
+            #[cfg(feature = "dev")]
+            conn_senders: Arc::default(),
+            #[cfg(feature = "dev")]
+            typing: Arc::new(std::sync::Mutex::new(TypingTracker::from_env())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_post_lists_every_problem() {
+        let request = json!({
+            "room_id": "   ",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "",
+            "message_text": "x".repeat(501),
+            "client_message_id": null,
+        });
+
+        let response = create_app(offline_state().await)
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["code"], 400);
+        let fields: Vec<_> =
+            body["errors"].as_array().unwrap().iter().map(|e| e["field"].clone()).collect();
+        assert_eq!(fields, vec!["room_id", "username", "message_text"]);
+        // The over-length field says how long it may be and how long it was
+        assert_eq!(body["errors"][2]["limit"], 500);
+        assert_eq!(body["errors"][2]["actual"], 501);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_post_is_rejected_before_the_store() {
+        let store = Arc::new(MemoryMessageStore::new());
+        let state = AppState { store: store.clone(), ..offline_state().await };
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "x".repeat(*MAX_BODY_BYTES),
+            "client_message_id": null,
+        });
+
+        let response = create_app(state)
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!store.has_room("general"));
+    }
+
+    #[tokio::test]
+    async fn test_retried_post_answers_200_with_the_stored_message() {
+        let store = Arc::new(MemoryMessageStore::new());
+        let app = create_app(AppState { store: store.clone(), ..offline_state().await });
+        let post = || {
+            let request = json!({
+                "room_id": "general",
+                "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+                "username": "alice",
+                "message_text": "Hello!",
+                "client_message_id": "client-1",
+            });
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        let first = post().await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let first: serde_json::Value = serde_json::from_slice(&body_bytes(first).await).unwrap();
+        let retry = post().await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        let retry: serde_json::Value = serde_json::from_slice(&body_bytes(retry).await).unwrap();
+        assert_eq!(retry["id"], first["id"]);
+    }
+
+    #[tokio::test]
+    async fn test_posts_past_the_rate_limit_get_429_until_a_token_refills() {
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(backend::clock::MockClock::new(start));
+        let sink = Arc::new(BufferedSink::default());
+        let state = AppState {
+            store: Arc::new(MemoryMessageStore::new().with_clock(clock.clone())),
+            metrics: backend::MetricsHelper::with_sink(sink.clone()),
+            context: RequestContext::default().with_clock(clock.clone()),
+            rate_limiter: Arc::new(MessageRateLimiter::new(2.0, clock.clone())),
+            ..offline_state().await
+        };
+        let app = create_app(state);
+        let post = |text: &str| {
+            let request = json!({
+                "room_id": "general",
+                "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+                "username": "alice",
+                "message_text": text,
+                "client_message_id": null,
+            });
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(post("one").await.unwrap().status(), StatusCode::CREATED);
+        assert_eq!(post("two").await.unwrap().status(), StatusCode::CREATED);
+        let refused = post("three").await.unwrap();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()[RETRY_AFTER], "30");
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(refused).await).unwrap();
+        assert_eq!(body["code"], 429);
+        let names = sink.pending.lock().unwrap().clone();
+        let limited = names.iter().position(|name| name == "RateLimited").unwrap();
+        assert_eq!(
+            sink.dimensions.lock().unwrap()[limited]["UserId"],
+            "01ARZ3NDEKTSV4RRFFQ69G5FB1"
+        );
+
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(post("three").await.unwrap().status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_posts_say_how_much_of_the_rate_limit_is_left() {
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(backend::clock::MockClock::new(start));
+        let state = AppState {
+            store: Arc::new(MemoryMessageStore::new().with_clock(clock.clone())),
+            context: RequestContext::default().with_clock(clock.clone()),
+            rate_limiter: Arc::new(MessageRateLimiter::new(2.0, clock.clone())),
+            ..offline_state().await
+        };
+        let app = create_app(state);
+        let post = |text: &str| {
+            let request = json!({
+                "room_id": "general",
+                "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+                "username": "alice",
+                "message_text": text,
+                "client_message_id": null,
+            });
+            let response = app.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            );
+            async move {
+                let response = response.await.unwrap();
+                let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+                (response.status(), header("x-ratelimit-remaining"), header("x-ratelimit-reset"))
+            }
+        };
+        let at = |secs: i64| (start.timestamp() + secs).to_string();
+
+        assert_eq!(post("one").await, (StatusCode::CREATED, "1".to_string(), at(30)));
+        assert_eq!(post("two").await, (StatusCode::CREATED, "0".to_string(), at(60)));
+        // Refusals carry them too
+        assert_eq!(post("three").await, (StatusCode::TOO_MANY_REQUESTS, "0".to_string(), at(60)));
+
+        // Back to a full bucket once the window has passed
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(post("three").await, (StatusCode::CREATED, "1".to_string(), at(90)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_posts_are_refused_before_the_rate_limit() {
+        let sink = Arc::new(BufferedSink::default());
+        let state = AppState {
+            metrics: backend::MetricsHelper::with_sink(sink.clone()),
+            rate_limiter: Arc::new(MessageRateLimiter::new(2.0, backend::clock::system())),
+            ..offline_state().await
+        };
+        let app = create_app(state);
+        let post = |user_id: &str| {
+            let request = json!({
+                "room_id": "general",
+                "user_id": user_id,
+                "username": "alice",
+                "message_text": "Hi",
+                "client_message_id": null,
+            });
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        // Made-up ids get no bucket of their own, and nothing is counted against them
+        for _ in 0..3 {
+            let refused = post("not a user id").await.unwrap();
+            assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+            assert!(refused.headers().get("x-ratelimit-remaining").is_none());
+        }
+        assert!(!sink.pending.lock().unwrap().iter().any(|name| name == "RateLimited"));
+
+        let posted = post("01ARZ3NDEKTSV4RRFFQ69G5FB1").await.unwrap();
+        assert_eq!(posted.status(), StatusCode::CREATED);
+        assert_eq!(posted.headers()["x-ratelimit-remaining"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_auto_hidden_messages_are_not_served_from_the_cache() {
+        let state = AppState {
+            message_cache: Some(Arc::new(MessageCache::new(
+                std::num::NonZeroUsize::new(4).unwrap(),
+                backend::clock::system(),
+            ))),
+            ..offline_state().await
+        };
+        let store = state.store.clone();
+        let app = create_app(state);
+        let send = |method: Method, uri: String, body: serde_json::Value| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                    .unwrap(),
+            )
+        };
+        let statuses = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/chat/messages/general").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let page: types::GetMessagesResponse =
+                serde_json::from_slice(&body_bytes(response).await).unwrap();
+            page.messages.into_iter().map(|message| message.status).collect::<Vec<_>>()
+        };
+
+        let posted = send(
+            Method::POST,
+            "/chat/messages".to_string(),
+            json!({
+                "room_id": "general",
+                "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+                "username": "alice",
+                "message_text": "Buy now",
+                "client_message_id": null,
+            }),
+        )
+        .await
+        .unwrap();
+        let posted: types::ChatMessage = serde_json::from_slice(&body_bytes(posted).await).unwrap();
+        // Read once so the page is cached
+        assert_eq!(statuses().await, vec![types::MessageStatus::Stored]);
+
+        // Auto-hiding counts flags by client origin, which tests don't capture, so the message
+        // is hidden behind the cache's back and the flag reports it hidden
+        store.hide_message(&posted).await.unwrap();
+        let flag_uri = format!("/chat/messages/general/{}/flag", posted.core.id);
+        let flagged = send(
+            Method::POST,
+            flag_uri,
+            json!({ "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB2", "reason": "spam" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(flagged.status(), StatusCode::OK);
+        let flagged: types::FlagMessageResponse =
+            serde_json::from_slice(&body_bytes(flagged).await).unwrap();
+        assert!(flagged.hidden);
+        assert_eq!(statuses().await, vec![types::MessageStatus::Hidden]);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_messages_leave_the_cache_the_room_and_the_index() {
+        let search = Arc::new(backend::search::MemorySearchIndex::default());
+        let state = AppState {
+            search: Some(search.clone()),
+            message_cache: Some(Arc::new(MessageCache::new(
+                std::num::NonZeroUsize::new(4).unwrap(),
+                backend::clock::system(),
+            ))),
+            ..offline_state().await
+        };
+        let (tx, mut events) = broadcast::channel(8);
+        state.channels.write().await.insert("general".to_string(), tx);
+        let app = create_app(state);
+        let request = |method: Method, uri: &str, body: Body| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .unwrap(),
+            )
+        };
+        let page = || async {
+            let response = request(Method::GET, "/chat/messages/general", Body::empty()).await;
+            let page: types::GetMessagesResponse =
+                serde_json::from_slice(&body_bytes(response.unwrap()).await).unwrap();
+            page.messages.len()
+        };
+        let indexed = || async { search.search("general", "lunch", 10).await.unwrap().len() };
+        let eventually = |expected: usize| async move {
+            let settled = async {
+                while indexed().await != expected {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), settled).await.unwrap();
+        };
+
+        let post = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "Lunch?",
+            "client_message_id": null,
+        });
+        let posted = request(Method::POST, "/chat/messages", Body::from(post.to_string()));
+        let posted: types::ChatMessage =
+            serde_json::from_slice(&body_bytes(posted.await.unwrap()).await).unwrap();
+        let _ = events.recv().await;
+        eventually(1).await;
+        // Read once so the page is cached
+        assert_eq!(page().await, 1);
+
+        let uri =
+            format!("/chat/messages/general/{}?user_id=01ARZ3NDEKTSV4RRFFQ69G5FB1", posted.core.id);
+        let deleted = request(Method::DELETE, &uri, Body::empty()).await.unwrap();
+        assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+
+        assert_eq!(page().await, 0);
+        let Ok(RoomEvent::MessageDeleted(message)) = events.try_recv() else {
+            panic!("deletion was not relayed to the room");
+        };
+        assert_eq!(message.core.id, posted.core.id);
+        eventually(0).await;
+    }
+
+    #[test]
+    fn test_bind_addr_from_env() {
+        let bind = |addr: Option<&str>, port: Option<&str>| {
+            bind_addr(addr.map(String::from), port.map(String::from))
+        };
+        assert_eq!(bind(None, None), Ok("127.0.0.1:3001".parse().unwrap()));
+        assert_eq!(bind(Some("0.0.0.0:8080"), None), Ok("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(bind(Some("[::]:3001"), Some("9000")), Ok("[::]:9000".parse().unwrap()));
+        assert_eq!(bind(None, Some(" 9000 ")), Ok("127.0.0.1:9000".parse().unwrap()));
+
+        assert!(bind(Some("0.0.0.0"), None).unwrap_err().starts_with("BIND_ADDR"));
+        assert!(bind(Some("localhost:3001"), None).unwrap_err().starts_with("BIND_ADDR"));
+        assert!(bind(None, Some("70000")).unwrap_err().starts_with("PORT"));
+    }
+
+    #[tokio::test]
+    async fn test_store_errors_answer_by_what_the_store_said() {
+        let answer =
+            |error: StoreError| AppError::from(handlers::HandlerError::from(error)).into_response();
+
+        let throttled = answer(StoreError::Throttled);
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(throttled).await).unwrap();
+        assert_eq!(body["code"], 429);
+        assert_eq!(answer(StoreError::Conflict).status(), StatusCode::CONFLICT);
+        // A post still losing races on its room after the store's retries is asked to back off
+        let contended = AppError::from(handlers::HandlerError::from(PutMessageError::Contended));
+        assert_eq!(contended.into_response().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            answer(StoreError::Other("DynamoDB error".to_string())).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "dev", ignore)] // Dev sockets are recorded in CHAT_CONNECTIONS_TABLE
+    async fn test_echo_frame_comes_straight_back_and_is_not_stored() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        // Nothing else reads it, and it's read once
+        std::env::set_var("WS_ECHO_ENABLED", "true");
+        let store = Arc::new(MemoryMessageStore::new());
+        let app = create_app(AppState { store: store.clone(), ..offline_state().await });
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let url = format!("ws://{}/ws?room_id=general&userId=user-1&username=alice", addr);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let frame = r#"{"type":"echo","message_text":"ping","sent_at":1700000000123}"#;
+        socket.send(WsMessage::Text(frame.to_string())).await.unwrap();
+
+        let reply = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+        assert_eq!(reply.unwrap().unwrap(), WsMessage::Text(frame.to_string()));
+        assert!(!store.has_room("general"));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "dev", ignore)] // Dev sockets are recorded in CHAT_CONNECTIONS_TABLE
+    async fn test_bad_and_oversized_frames_are_answered_with_error_codes() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            create_app(offline_state().await).into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let url = format!("ws://{}/ws?room_id=general&userId=user-1&username=alice", addr);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let oversized =
+            format!(r#"{{"message_text":"{}"}}"#, "x".repeat(WS_POLICY.max_frame_bytes));
+        for (frame, code) in
+            [("not json", WsErrorCode::BadFrame), (oversized.as_str(), WsErrorCode::FrameTooLarge)]
+        {
+            socket.send(WsMessage::Text(frame.to_string())).await.unwrap();
+            let reply = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+            let Some(Ok(WsMessage::Text(text))) = reply else {
+                panic!("expected an error frame, got {:?}", reply);
+            };
+            let error: WsError = serde_json::from_str(&text).unwrap();
+            assert_eq!(error.code, code);
+            assert_eq!(error.retry_after_ms, None);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "dev", ignore)] // Dev sockets are recorded in CHAT_CONNECTIONS_TABLE
+    async fn test_reconnect_replays_exactly_the_missed_messages() {
+        use backend::clock::Clock;
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(backend::clock::MockClock::new(start));
+        let store = Arc::new(MemoryMessageStore::new().with_clock(clock.clone()));
+        let state = AppState {
+            store: store.clone(),
+            context: RequestContext::default().with_clock(clock.clone()),
+            ..offline_state().await
+        };
+        let app = create_app(state);
+        let post = |text: &str| {
+            let request = json!({
+                "room_id": "general",
+                "user_id": "user-2",
+                "username": "bob",
+                "message_text": text,
+                "client_message_id": null,
+            });
+            app.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        // Seen before the client dropped
+        post("seen").await.unwrap();
+        let last_seen_ts = clock.now().timestamp_millis();
+        clock.advance(chrono::Duration::seconds(1));
+        post("missed one").await.unwrap();
+        clock.advance(chrono::Duration::seconds(1));
+        post("missed two").await.unwrap();
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(app.clone().into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let url = format!(
+            "ws://{}/ws?room_id=general&userId=user-1&username=alice&last_seen_ts={}",
+            addr, last_seen_ts
+        );
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let mut replayed = Vec::new();
+        for _ in 0..2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+            let Some(Ok(WsMessage::Text(text))) = frame else {
+                panic!("expected a missed message, got {:?}", frame);
+            };
+            let message: types::ChatMessage = serde_json::from_str(&text).unwrap();
+            replayed.push(message.core.message_text);
+        }
+        assert_eq!(replayed, vec!["missed one", "missed two"]);
+        let extra = tokio::time::timeout(Duration::from_millis(200), socket.next()).await;
+        assert!(extra.is_err(), "unexpected frame {:?}", extra);
+    }
+
+    #[tokio::test]
+    async fn test_post_unblocks_open_poll() {
+        let app = create_app(offline_state().await);
+
+        let poll = tokio::spawn(
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/chat/messages/general/poll?cursor=0")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+        );
+        // Let the poll find the room empty and start waiting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!poll.is_finished());
+
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "anyone there?",
+            "client_message_id": null,
+        });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let posted: types::ChatMessage =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(5), poll).await.unwrap().unwrap();
+        let page: types::PollMessagesResponse =
+            serde_json::from_slice(&body_bytes(response.unwrap()).await).unwrap();
+        let ids: Vec<_> = page.messages.iter().map(|message| message.core.id.as_str()).collect();
+        assert_eq!(ids, vec![posted.core.id.as_str()]);
+        assert_eq!(page.cursor, message_sort_key(&posted));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_room_answers_conditional_get_with_304() {
+        let app = create_app(offline_state().await);
+        let get = |etag: Option<&str>| {
+            let mut request = Request::builder().uri("/chat/messages/general");
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get(None).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
-        // TODO: Add body deserialization test when body collection is fixed
+        assert!(response.headers()[CACHE_CONTROL].to_str().unwrap().starts_with("max-age="));
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+
+        let response = get(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(body_bytes(response).await.is_empty());
+
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "something new",
+            "client_message_id": null,
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = get(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+        let page: types::GetMessagesResponse =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(page.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fields_trims_messages_and_rejects_unknown_names() {
+        let app = create_app(offline_state().await);
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "hello",
+            "client_message_id": null,
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chat/messages/general?fields=id,message_text")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(page["room_id"], "general");
+        let message = page["messages"][0].as_object().unwrap();
+        let mut keys: Vec<_> = message.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["id", "message_text"]);
+        assert_eq!(message["message_text"], "hello");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/chat/messages/general?fields=id,password")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_messages_come_newest_first_unless_asked() {
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(backend::clock::MockClock::new(start));
+        let app = create_app(AppState {
+            store: Arc::new(MemoryMessageStore::new().with_clock(clock.clone())),
+            context: RequestContext::default().with_clock(clock.clone()),
+            ..offline_state().await
+        });
+        for text in ["first", "second"] {
+            clock.advance(chrono::Duration::milliseconds(1));
+            let request = json!({
+                "room_id": "general",
+                "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+                "username": "alice",
+                "message_text": text,
+                "client_message_id": null,
+            });
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(Method::POST)
+                        .uri("/chat/messages")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let texts = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let page: serde_json::Value =
+                    serde_json::from_slice(&body_bytes(response).await).unwrap();
+                page["messages"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|message| message["message_text"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(texts("/chat/messages/general").await, ["second", "first"]);
+        assert_eq!(texts("/chat/messages/general?order=desc").await, ["second", "first"]);
+        assert_eq!(texts("/chat/messages/general?order=asc").await, ["first", "second"]);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/chat/messages/general?order=newest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn rename(app: &Router, user_id: &str, username: &str) -> Response {
+        let body = json!({ "username": username });
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(format!("/chat/users/{}/username", user_id))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rename_applies_to_new_messages_and_is_relayed() {
+        let state = offline_state().await;
+        let (tx, mut frames) = broadcast::channel(8);
+        state.channels.write().await.insert("general".to_string(), tx);
+        let app = create_app(state);
+        let user_id = "01ARZ3NDEKTSV4RRFFQ69G5FB1";
+
+        let response = rename(&app, user_id, "  alicia ").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let renamed: types::UserRenamed =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(renamed.old_username, None);
+        assert_eq!(renamed.username, "alicia");
+
+        let Ok(RoomEvent::Frame(frame)) = frames.try_recv() else {
+            panic!("rename was not relayed to the room");
+        };
+        assert_eq!(serde_json::from_str::<types::UserRenamed>(&frame).unwrap(), renamed);
+
+        let request = json!({
+            "room_id": "general",
+            "user_id": user_id,
+            "username": "alice",
+            "message_text": "new name, who dis",
+            "client_message_id": null,
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let posted: types::ChatMessage =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(posted.core.username, "alicia");
+
+        let response = rename(&app, user_id, "ally").await;
+        let renamed: types::UserRenamed =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(renamed.old_username.as_deref(), Some("alicia"));
+    }
+
+    async fn set_topic(app: &Router, room_id: &str, topic: &str) -> Response {
+        let body = json!({ "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1", "topic": topic });
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(format!("/chat/rooms/{}/topic", room_id))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_room_topic_is_stored_and_relayed_to_the_room() {
+        let store = Arc::new(MemoryMessageStore::new());
+        store.ensure_room("general").await.unwrap();
+        let state = AppState { store: store.clone(), ..offline_state().await };
+        let (tx, mut frames) = broadcast::channel(8);
+        let (other_tx, mut other_frames) = broadcast::channel(8);
+        state.channels.write().await.insert("general".to_string(), tx);
+        state.channels.write().await.insert("random".to_string(), other_tx);
+        let app = create_app(state);
+
+        let response = set_topic(&app, "General", "  Release planning ").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let changed: types::TopicChanged =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(changed.room_id, "general");
+        assert_eq!(changed.topic.as_deref(), Some("Release planning"));
+        assert_eq!(changed.by, "01ARZ3NDEKTSV4RRFFQ69G5FB1");
+        assert_eq!(store.room_topic("general").as_deref(), Some("Release planning"));
+
+        let Ok(RoomEvent::Frame(frame)) = frames.try_recv() else {
+            panic!("topic change was not relayed to the room");
+        };
+        assert_eq!(serde_json::from_str::<types::TopicChanged>(&frame).unwrap(), changed);
+        assert!(other_frames.try_recv().is_err());
+
+        // A blank topic clears it
+        let response = set_topic(&app, "general", " ").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(store.room_topic("general"), None);
+
+        let response = set_topic(&app, "nowhere", "Hello").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_over_long_topic_is_rejected() {
+        let store = Arc::new(MemoryMessageStore::new());
+        store.ensure_room("general").await.unwrap();
+        let app = create_app(AppState { store: store.clone(), ..offline_state().await });
+
+        let response = set_topic(&app, "general", &"x".repeat(201)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(error["errors"][0]["field"], "topic");
+        assert_eq!(error["errors"][0]["limit"], 200);
+        assert_eq!(store.room_topic("general"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_username_is_refused() {
+        let app = create_app(offline_state().await);
+
+        for username in ["Admin", "anon-1234abcd"] {
+            let response = rename(&app, "01ARZ3NDEKTSV4RRFFQ69G5FB1", username).await;
+            assert_eq!(response.status(), StatusCode::CONFLICT, "{}", username);
+        }
+        let response = rename(&app, "01ARZ3NDEKTSV4RRFFQ69G5FB1", " ").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Holds metrics back until flushed, like a batching exporter would
+    #[derive(Default)]
+    struct BufferedSink {
+        pending: std::sync::Mutex<Vec<String>>,
+        flushed: std::sync::Mutex<Vec<String>>,
+        // Dimensions of each emitted metric, in emit order
+        dimensions: std::sync::Mutex<Vec<std::collections::HashMap<String, String>>>,
+    }
+
+    impl backend::metric_sink::MetricSink for BufferedSink {
+        fn emit(
+            &self,
+            name: &str,
+            _value: f64,
+            _unit: &str,
+            dimensions: &std::collections::HashMap<String, String>,
+        ) {
+            self.pending.lock().unwrap().push(name.to_string());
+            self.dimensions.lock().unwrap().push(dimensions.clone());
+        }
+
+        fn flush(&self) {
+            let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+            self.flushed.lock().unwrap().extend(pending);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_reports_once_and_flushes_metrics() {
+        let sink = Arc::new(BufferedSink::default());
+        let state = AppState {
+            metrics: backend::MetricsHelper::with_sink(sink.clone()),
+            ..offline_state().await
+        };
+        let hook = ShutdownHook::new(state);
+
+        assert!(hook.run().await);
+        assert!(!hook.run().await);
+
+        assert!(sink.pending.lock().unwrap().is_empty());
+        assert_eq!(*sink.flushed.lock().unwrap(), vec!["ServerShutdown"]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_answers_waiting_long_polls() {
+        let state = offline_state().await;
+        let mut events = room_channel(&state, "general").await.subscribe();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        let poll = tokio::spawn(async move { wait_for_post(&mut events, deadline).await });
+
+        ShutdownHook::new(state.clone()).run().await;
+
+        let posted = tokio::time::timeout(Duration::from_secs(5), poll).await.unwrap().unwrap();
+        assert!(!posted);
+        assert!(state.channels.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "dev", ignore)] // Dev sockets are recorded in CHAT_CONNECTIONS_TABLE
+    async fn test_shutdown_closes_sockets_with_a_reconnect_hint() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Message as WsMessage,
+        };
+
+        let state = offline_state().await;
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(create_app(state.clone()).into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let url = format!("ws://{}/ws?room_id=general&userId=user-1&username=alice", addr);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        // The handshake completes before the socket starts watching for shutdown
+        while state.shutting_down.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        ShutdownHook::new(state.clone()).run().await;
+        // Shutdown waited for the socket to finish closing
+        assert_eq!(state.shutting_down.receiver_count(), 0);
+
+        // An error frame explains the close before it arrives
+        let frame = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+        let Some(Ok(WsMessage::Text(text))) = frame else {
+            panic!("expected an error frame, got {:?}", frame);
+        };
+        let error: WsError = serde_json::from_str(&text).unwrap();
+        assert_eq!(error.code, WsErrorCode::ServerShutdown);
+        assert!(error.retry_after_ms.is_some_and(|ms| ms > 0));
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+        let Some(Ok(WsMessage::Close(Some(close)))) = frame else {
+            panic!("expected a close frame, got {:?}", frame);
+        };
+        assert_eq!(close.code, CloseCode::Away);
+        let hint: types::ReconnectHint = serde_json::from_str(&close.reason).unwrap();
+        assert_eq!(hint.reason, ReconnectReason::Shutdown);
+        assert!(hint.after_ms > 0);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "dev", ignore)] // Dev sockets are recorded in CHAT_CONNECTIONS_TABLE
+    async fn test_metrics_snapshot_counts_posts_and_connections() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        // The only test here that reads ADMIN_TOKEN, so it's set before first use
+        env::set_var("ADMIN_TOKEN", "snapshot-secret");
+        // Totals of its own, as other tests post and connect in the same process
+        let state = AppState {
+            metrics: backend::MetricsHelper::with_own_counters(Arc::new(BufferedSink::default())),
+            ..offline_state().await
+        };
+        let app = create_app(state.clone());
+        let snapshot = |token: &'static str| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    Request::builder()
+                        .uri("/admin/metrics/snapshot")
+                        .header(admin::ADMIN_TOKEN_HEADER, token)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+            }
+        };
+        assert_eq!(snapshot("wrong").await.status(), StatusCode::FORBIDDEN);
+
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "hello",
+            "client_message_id": null,
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/chat/messages")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(app.clone().into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let url = format!("ws://{}/ws?room_id=general&userId=user-1&username=alice", addr);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        // The connection is counted once the socket is being served
+        while state.shutting_down.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let response = snapshot("snapshot-secret").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["messages_posted"], 1);
+        assert_eq!(body["active_connections"], 1);
+        assert_eq!(body["broadcast_success_rate"], serde_json::Value::Null);
+
+        // Closing the socket frees the connection
+        socket.send(WsMessage::Close(None)).await.unwrap();
+        let disconnected = async {
+            while state.metrics.snapshot().active_connections > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), disconnected).await.unwrap();
+    }
+
+    async fn panicking_handler() -> &'static str {
+        panic!("deliberate")
+    }
+
+    async fn slow_handler() -> &'static str {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        "too late"
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_answers_504_json_and_is_counted() {
+        let sink = Arc::new(BufferedSink::default());
+        let metrics = backend::MetricsHelper::with_sink(sink.clone());
+        let routes = Router::new()
+            .route("/slow/:id", get(slow_handler))
+            .route("/health", get(health_handler));
+        let app = with_request_timeout(routes, Duration::from_millis(50), metrics)
+            .with_state(offline_state().await);
+
+        let started = std::time::Instant::now();
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/slow/1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body, json!({ "error": "Request timed out", "code": 504 }));
+        assert_eq!(*sink.pending.lock().unwrap(), vec!["RequestTimeout"]);
+        assert_eq!(sink.dimensions.lock().unwrap()[0]["Route"], "/slow/:id");
+
+        let response = app
+            .oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic_answers_500_json_and_is_counted() {
+        let sink = Arc::new(BufferedSink::default());
+        let state = AppState {
+            metrics: backend::MetricsHelper::with_sink(sink.clone()),
+            ..offline_state().await
+        };
+        let routes = Router::new()
+            .route("/boom/:id", get(panicking_handler))
+            .route("/health", get(health_handler))
+            .with_state(state.clone());
+        let app = catch_panics(routes, state.metrics);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/boom/42").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body, json!({ "error": "Internal server error", "code": 500 }));
+
+        assert_eq!(*sink.pending.lock().unwrap(), vec!["HandlerPanic"]);
+        assert_eq!(sink.dimensions.lock().unwrap()[0]["Route"], "/boom/:id");
+
+        // The server keeps serving, and ordinary responses aren't counted
+        let response = app
+            .oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sink.pending.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_is_never_cached() {
+        let response = create_app(offline_state().await)
+            .oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+    }
+
+    #[tokio::test]
+    async fn test_dependency_report_answers_503_when_a_probe_fails() {
+        let get = |app: Router| async move {
+            app.oneshot(Request::builder().uri("/health/dependencies").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        };
+        let unconfigured = Dependency { name: "kms", probe: None };
+
+        let dependencies = DependencyHealth::new(&offline_config(), vec![unconfigured.clone()]);
+        let state = AppState { dependencies: Arc::new(dependencies), ..offline_state().await };
+        let response = get(create_app(state)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+        let report: serde_json::Value =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(report["healthy"], true);
+        assert_eq!(report["checks"][0]["status"], "skipped");
+
+        let misconfigured = Dependency {
+            name: "management_api",
+            probe: Some(Probe::Misconfigured("WS_STAGE environment variable must be set".into())),
+        };
+        let dependencies =
+            DependencyHealth::new(&offline_config(), vec![unconfigured, misconfigured]);
+        let state = AppState { dependencies: Arc::new(dependencies), ..offline_state().await };
+        let response = get(create_app(state)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let report: serde_json::Value =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(report["healthy"], false);
+        assert_eq!(report["checks"][1]["status"], "fail");
+    }
+
+    // Collects formatted log output so tests can inspect it
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_request_gets_one_access_log_line() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // The test runtime is single-threaded, so the whole request runs under it
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let app = create_app(offline_state().await);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chat/messages/general")
+                    .header(REQUEST_ID_HEADER, "req-42")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-42");
+
+        // Health checks log at debug, below the info level captured here
+        let response = app
+            .oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers()[REQUEST_ID_HEADER].is_empty());
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().filter(|line| line.contains(" request ")).collect();
+        assert_eq!(lines.len(), 1, "{}", output);
+        for field in [
+            r#"method="GET""#,
+            r#"path="/chat/messages/general""#,
+            "status=200",
+            "latency_ms=",
+            r#"request_id="req-42""#,
+        ] {
+            assert!(lines[0].contains(field), "{} missing from {}", field, lines[0]);
+        }
+        assert!(lines[0].contains(" INFO "), "{}", lines[0]);
     }
 }
@@ -0,0 +1,162 @@
+use crate::clock::Clock;
+use lru::LruCache;
+use std::{
+    env,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use types::GetMessagesResponse;
+
+const DEFAULT_CAPACITY: usize = 64;
+
+// Latest unfiltered message page per room for the local server, so repeated reads during
+// frontend work skip DynamoDB. Posts invalidate the room before responding.
+pub struct MessageCache {
+    pages: Mutex<LruCache<String, GetMessagesResponse>>,
+    // Bumped on every invalidation; a read that raced a post must not repopulate the cache
+    generation: AtomicU64,
+    // Judges ephemeral expiry and stamps `server_time`, as the store reading the page would
+    clock: Arc<dyn Clock>,
+}
+
+impl MessageCache {
+    pub fn new(capacity: NonZeroUsize, clock: Arc<dyn Clock>) -> Self {
+        Self { pages: Mutex::new(LruCache::new(capacity)), generation: AtomicU64::new(0), clock }
+    }
+
+    /// Cache enabled by `ENABLE_MESSAGE_CACHE`, holding `MESSAGE_CACHE_ROOMS` rooms (default 64)
+    pub fn from_env(clock: Arc<dyn Clock>) -> Option<Self> {
+        let enabled = env::var("ENABLE_MESSAGE_CACHE")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+
+        let capacity = env::var("MESSAGE_CACHE_ROOMS")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(NonZeroUsize::new(DEFAULT_CAPACITY).unwrap());
+        Some(Self::new(capacity, clock))
+    }
+
+    /// Token to take before querying DynamoDB and hand back to `insert`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Cached page for a room, with expired ephemeral messages dropped and a fresh `server_time`
+    pub fn get(&self, room_id: &str) -> Option<GetMessagesResponse> {
+        let mut response = self.pages.lock().unwrap().get(room_id)?.clone();
+        let now = self.clock.now();
+        response.messages.retain(|message| message.expires_at.is_none_or(|at| at > now));
+        response.server_time = now;
+        Some(response)
+    }
+
+    /// Store a page read at `generation`, unless the cache was invalidated in the meantime
+    pub fn insert(&self, room_id: &str, response: GetMessagesResponse, generation: u64) {
+        let mut pages = self.pages.lock().unwrap();
+        // Checked under the lock so an invalidation can't slip in between check and insert
+        if self.generation() == generation {
+            pages.put(room_id.to_string(), response);
+        }
+    }
+
+    pub fn invalidate(&self, room_id: &str) {
+        let mut pages = self.pages.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        pages.pop(room_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use chrono::Utc;
+    use types::ChatMessage;
+
+    fn page(room_id: &str) -> GetMessagesResponse {
+        GetMessagesResponse {
+            room_id: room_id.to_string(),
+            messages: Vec::new(),
+            server_time: Utc::now(),
+            next_cursor: None,
+            before_cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_cache_hit_after_first_read() {
+        let cache = MessageCache::new(NonZeroUsize::new(2).unwrap(), clock::system());
+        assert!(cache.get("general").is_none());
+
+        cache.insert("general", page("general"), cache.generation());
+        assert_eq!(cache.get("general").unwrap().room_id, "general");
+        assert!(cache.get("general").is_some());
+    }
+
+    #[test]
+    fn test_post_invalidates_room() {
+        let cache = MessageCache::new(NonZeroUsize::new(2).unwrap(), clock::system());
+        cache.insert("general", page("general"), cache.generation());
+        cache.insert("random", page("random"), cache.generation());
+
+        cache.invalidate("general");
+        assert!(cache.get("general").is_none());
+        assert!(cache.get("random").is_some());
+
+        // A read that started before the post must not put its stale page back
+        let stale_generation = cache.generation();
+        cache.invalidate("general");
+        cache.insert("general", page("general"), stale_generation);
+        assert!(cache.get("general").is_none());
+    }
+
+    #[test]
+    fn test_expiry_and_server_time_follow_the_cache_clock() {
+        let start = chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(clock::MockClock::new(start));
+        let cache = MessageCache::new(NonZeroUsize::new(2).unwrap(), clock.clone());
+        let mut message: ChatMessage = serde_json::from_value(serde_json::json!({
+            "id": "m1",
+            "room_id": "general",
+            "user_id": "u1",
+            "username": "alice",
+            "message_text": "Gone soon",
+            "created_at": start,
+        }))
+        .unwrap();
+        message.ephemeral = true;
+        message.expires_at = Some(start + chrono::Duration::seconds(30));
+        let response = GetMessagesResponse { messages: vec![message], ..page("general") };
+        cache.insert("general", response, cache.generation());
+
+        let cached = cache.get("general").unwrap();
+        assert_eq!(cached.server_time, start);
+        assert_eq!(cached.messages.len(), 1);
+
+        clock.advance(chrono::Duration::seconds(30));
+        let cached = cache.get("general").unwrap();
+        assert_eq!(cached.server_time, start + chrono::Duration::seconds(30));
+        assert!(cached.messages.is_empty());
+    }
+
+    #[test]
+    fn test_least_recently_used_room_is_evicted() {
+        let cache = MessageCache::new(NonZeroUsize::new(2).unwrap(), clock::system());
+        cache.insert("a", page("a"), cache.generation());
+        cache.insert("b", page("b"), cache.generation());
+        cache.get("a");
+        cache.insert("c", page("c"), cache.generation());
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+}
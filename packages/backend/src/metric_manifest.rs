@@ -0,0 +1,154 @@
+use serde::Serialize;
+
+/// One metric `MetricsHelper` emits, under one set of dimensions. A metric emitted with
+/// different dimension sets (e.g. `BroadcastSuccesses`) has an entry per set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MetricDescriptor {
+    pub name: &'static str,
+    /// CloudWatch unit, as written to the EMF record
+    pub unit: &'static str,
+    /// Every dimension the data points carry, `Stage` included
+    pub dimensions: Vec<&'static str>,
+    pub description: &'static str,
+}
+
+// (name, unit, dimensions besides Stage, description)
+type Entry = (&'static str, &'static str, &'static [&'static str], &'static str);
+
+// Kept next to the emit methods' names by `test_manifest_covers_every_emitted_metric`
+const MANIFEST: &[Entry] = &[
+    ("ServerShutdown", "Seconds", &[], "How long the server ran before stopping"),
+    ("MessagesPosted", "Count", &["RoomId"], "Messages stored"),
+    ("MessageLength", "None", &["RoomId"], "Length of each stored message's text"),
+    ("MessageEvicted", "Count", &["RoomId"], "Messages removed by the room message cap"),
+    ("MessagesDeleted", "Count", &["RoomId"], "Messages deleted by their posters"),
+    ("MessageRejectedModeration", "Count", &["RoomId"], "Posts and edits refused by the blocklist"),
+    (
+        "DuplicateSuppressed",
+        "Count",
+        &["RoomId"],
+        "Posts retried with a stored client_message_id, answered with the stored message",
+    ),
+    ("CorruptItem", "Count", &["Field"], "Stored attributes that couldn't be read"),
+    ("HandlerPanic", "Count", &["Route"], "Handler panics answered with a 500"),
+    ("RequestTimeout", "Count", &["Route"], "Requests answered with a 504 at their deadline"),
+    ("BroadcastConfigError", "Count", &[], "Cold starts refused over a malformed endpoint"),
+    ("RateLimited", "Count", &["RoomId", "UserId"], "Posts refused over the poster's rate limit"),
+    ("WsRateLimited", "Count", &["Action"], "WebSocket policy enforcements (warn or close)"),
+    ("WsFramesDropped", "Count", &["Policy"], "Frames discarded by a full send queue"),
+    ("WebhookDelivered", "Count", &[], "Webhook deliveries that succeeded"),
+    ("WebhookFailed", "Count", &[], "Webhook deliveries that failed"),
+    ("ConnectionEvents", "Count", &["EventType", "RoomId"], "WebSocket connects and disconnects"),
+    ("ConnectionErrors", "Count", &["ErrorType", "RoomId"], "Connections that couldn't be stored"),
+    (
+        "DisconnectionErrors",
+        "Count",
+        &["ErrorType", "RoomId"],
+        "Connections that couldn't be removed",
+    ),
+    ("ActiveConnections", "None", &["RoomId"], "Connections currently in the room"),
+    ("WriteConcurrencyLimit", "None", &[], "Message writes allowed in flight, when it changes"),
+    ("BroadcastChunks", "Count", &["RoomId"], "Chunks a room's fan-out was paced into"),
+    ("BroadcastFanOutDuration", "Milliseconds", &["RoomId"], "Time spent posting a fan-out"),
+    ("BroadcastAttempts", "Count", &["RoomId"], "Connections a message was sent to"),
+    ("StaleConnectionsReaped", "Count", &["RoomId"], "Gone connections a broadcast deleted"),
+    ("MessageUpdatesBroadcast", "Count", &["RoomId"], "Edits broadcast to their rooms"),
+    ("MessageDeletesBroadcast", "Count", &["RoomId"], "Deletion notices broadcast to rooms"),
+    ("BroadcastSuccesses", "Count", &["RoomId"], "Connections a message reached"),
+    ("BroadcastFailures", "Count", &["RoomId"], "Connections a message didn't reach"),
+    (
+        "BroadcastSuccesses",
+        "Count",
+        &["RoomId", "ApiStage", "Domain"],
+        "API Gateway posts that succeeded, per API stage and domain",
+    ),
+    (
+        "BroadcastFailures",
+        "Count",
+        &["RoomId", "ApiStage", "Domain"],
+        "API Gateway posts that failed, per API stage and domain",
+    ),
+];
+
+pub(crate) fn manifest() -> Vec<MetricDescriptor> {
+    MANIFEST
+        .iter()
+        .map(|&(name, unit, dimensions, description)| MetricDescriptor {
+            name,
+            unit,
+            dimensions: std::iter::once("Stage").chain(dimensions.iter().copied()).collect(),
+            description,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{broadcast::StageDeliveries, test_support::Capture, MetricsHelper};
+    use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_manifest_covers_every_emitted_metric() {
+        let capture = Arc::new(Capture::default());
+        let metrics = MetricsHelper::with_sink(capture.clone());
+
+        metrics.emit_server_shutdown(Duration::from_secs(1)).await;
+        metrics.emit_message_sent("general", 5).await;
+        metrics.emit_message_evicted("general", 1).await;
+        metrics.emit_duplicate_suppressed("general").await;
+        metrics.emit_message_rejected_moderation("general").await;
+        metrics.emit_message_deleted("general").await;
+        metrics.emit_corrupt_item("ts").await;
+        metrics.emit_handler_panic("/messages").await;
+        metrics.emit_request_timeout("/messages").await;
+        metrics.emit_broadcast_config_error().await;
+        metrics.emit_rate_limited("general", "u1").await;
+        metrics.emit_ws_rate_limited("warn").await;
+        metrics.emit_ws_frame_dropped("drop_oldest").await;
+        metrics.emit_webhook_delivery(true).await;
+        metrics.emit_webhook_delivery(false).await;
+        metrics.emit_connection_event("connect", "general", Some(1)).await;
+        metrics.emit_connection_error("connect", "general").await;
+        metrics.emit_connection_error("disconnect", "general").await;
+        metrics.emit_broadcast_pacing("general", 1, Duration::from_millis(5)).await;
+        metrics.emit_message_broadcast("general", 2, 1).await;
+        metrics.emit_stale_connections_reaped("general", 1).await;
+        metrics.emit_message_update_broadcast("general").await;
+        metrics.emit_message_delete_broadcast("general").await;
+        metrics.emit_write_concurrency_limit(8).await;
+        let stage = StageDeliveries {
+            stage: "prod".to_string(),
+            domain: "example.com".to_string(),
+            successes: 1,
+            failures: 0,
+        };
+        metrics.emit_broadcast_stages("general", &[stage]).await;
+
+        let listed: BTreeSet<(&str, Vec<&str>)> = manifest()
+            .into_iter()
+            .map(|metric| {
+                let mut dimensions: Vec<_> =
+                    metric.dimensions.into_iter().filter(|d| *d != "Stage").collect();
+                dimensions.sort();
+                (metric.name, dimensions)
+            })
+            .collect();
+        let emitted = capture.0.lock().unwrap();
+        for (name, _, dimensions) in emitted.iter() {
+            let mut keys: Vec<&str> = dimensions.keys().map(String::as_str).collect();
+            keys.sort();
+            assert!(
+                listed.contains(&(name.as_str(), keys.clone())),
+                "{} with dimensions {:?} is missing from the manifest",
+                name,
+                keys
+            );
+        }
+
+        // And nothing is listed that isn't emitted
+        let emitted: BTreeSet<&str> = emitted.iter().map(|(name, _, _)| name.as_str()).collect();
+        let listed: BTreeSet<&str> = listed.iter().map(|(name, _)| *name).collect();
+        assert_eq!(emitted, listed);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::clock::Clock;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    env,
+    io::Write,
+    sync::{mpsc, Arc, LazyLock},
+    thread,
+    time::Duration,
+};
+use tracing::Level;
+
+// Longest `flush` waits for the writer to catch up, so a wedged stdout can't hold up exit
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The writer behind every queued EMF sink in the process, started by the first one
+static STDOUT_QUEUE: LazyLock<Arc<LineQueue>> =
+    LazyLock::new(|| Arc::new(LineQueue::spawn(std::io::stdout())));
+
+/// Target of the events `TracingSink` emits, for filtering them into a metrics pipeline
+pub const METRICS_TARGET: &str = "metrics";
+
+/// One data point of a batch. `dimensions` excludes the stage, as in `MetricSink::emit`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricRecord {
+    pub name: String,
+    pub value: f64,
+    pub unit: String,
+    pub dimensions: HashMap<String, String>,
+}
+
+impl MetricRecord {
+    pub fn new(name: &str, value: f64, unit: &str, dimensions: HashMap<String, String>) -> Self {
+        Self { name: name.to_string(), value, unit: unit.to_string(), dimensions }
+    }
+}
+
+/// Where `MetricsHelper` sends each data point. `dimensions` excludes the stage, which every
+/// sink adds itself.
+pub trait MetricSink: Send + Sync {
+    fn emit(&self, name: &str, value: f64, unit: &str, dimensions: &HashMap<String, String>);
+
+    /// Several metrics emitted together, each with its own unit and dimensions. Sinks that can
+    /// write them as fewer records override this; the rest emit them one by one.
+    fn emit_batch(&self, records: &[MetricRecord]) {
+        for record in records {
+            self.emit(&record.name, record.value, &record.unit, &record.dimensions);
+        }
+    }
+
+    /// Push out anything emitted but not yet written. Sinks that write as they go need not
+    /// override it.
+    fn flush(&self) {}
+}
+
+/// The sink selected by `METRICS_SINK`: `tracing` for `TracingSink`, anything else for EMF
+/// records stamped by `clock`
+pub fn from_env(namespace: String, stage: String, clock: Arc<dyn Clock>) -> Arc<dyn MetricSink> {
+    match env::var("METRICS_SINK").as_deref() {
+        Ok("tracing") => Arc::new(TracingSink { namespace, stage }),
+        _ => Arc::new(EmfSink {
+            namespace,
+            stage,
+            pretty: pretty_from_env(),
+            clock,
+            queue: (!sync_from_env()).then(|| STDOUT_QUEUE.clone()),
+        }),
+    }
+}
+
+// METRICS_SYNC=1 to print records on the emitting thread, as Lambda does by default: its
+// sandbox is frozen between invocations, background writer and all. METRICS_SYNC=0 queues them
+// even there.
+fn sync_from_env() -> bool {
+    match env::var("METRICS_SYNC") {
+        Ok(value) => value == "1" || value.eq_ignore_ascii_case("true"),
+        Err(_) => env::var("AWS_LAMBDA_FUNCTION_NAME").is_ok(),
+    }
+}
+
+// METRICS_PRETTY=true outside Lambda. CloudWatch only parses one-line records, so Lambda is
+// always compact.
+fn pretty_from_env() -> bool {
+    let requested = env::var("METRICS_PRETTY")
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    requested && env::var("AWS_LAMBDA_FUNCTION_NAME").is_err()
+}
+
+/// CloudWatch Embedded Metric Format records on stdout, which CloudWatch Logs turns into
+/// metrics. The default.
+pub struct EmfSink {
+    namespace: String,
+    stage: String,
+    // Multi-line EMF records for reading locally; never used in Lambda
+    pretty: bool,
+    clock: Arc<dyn Clock>,
+    // Background writer records are handed to, unless METRICS_SYNC has them printed in place
+    queue: Option<Arc<LineQueue>>,
+}
+
+// One item for the writer thread
+enum Line {
+    Record(String),
+    // Write out everything before this, then acknowledge
+    Flush(mpsc::Sender<()>),
+}
+
+/// EMF records written out by a dedicated thread, so emitting one costs a channel send rather
+/// than a turn on the stdout lock. A thread rather than a task, so it outlives whichever
+/// runtime started it.
+pub struct LineQueue {
+    sender: mpsc::Sender<Line>,
+}
+
+impl LineQueue {
+    /// Start a writer thread for `out`
+    pub fn spawn(mut out: impl Write + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let writer = move || {
+            for line in receiver {
+                match line {
+                    Line::Record(record) => {
+                        if let Err(err) = writeln!(out, "{}", record) {
+                            tracing::warn!("Failed to write EMF metrics: {}", err);
+                        }
+                    }
+                    Line::Flush(ack) => {
+                        if let Err(err) = out.flush() {
+                            tracing::warn!("Failed to flush EMF metrics: {}", err);
+                        }
+                        let _ = ack.send(());
+                    }
+                }
+            }
+        };
+        thread::Builder::new()
+            .name("metrics-writer".to_string())
+            .spawn(writer)
+            .expect("Failed to start the metrics writer thread");
+        Self { sender }
+    }
+
+    pub fn push(&self, record: String) {
+        // Only if the writer is gone; the record is printed here instead of lost
+        if let Err(mpsc::SendError(Line::Record(record))) = self.sender.send(Line::Record(record)) {
+            println!("{}", record);
+        }
+    }
+
+    /// Wait (up to FLUSH_TIMEOUT) until every record pushed so far is written and flushed
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.sender.send(Line::Flush(ack)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err() {
+            tracing::warn!("Timed out flushing EMF metrics");
+        }
+    }
+}
+
+// Records bound for one EMF document. Its metric values and dimension values share the
+// document's top level, so a record only joins if its name is new to the document and its
+// dimensions don't disagree with any already there.
+#[derive(Default)]
+struct EmfDocument<'a> {
+    // Each dimension set (sorted keys, Stage excluded) and the records under it, in the order
+    // the sets first appeared
+    sets: Vec<(Vec<&'a str>, Vec<&'a MetricRecord>)>,
+    dimension_values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> EmfDocument<'a> {
+    fn admits(&self, record: &MetricRecord) -> bool {
+        let name_taken =
+            self.sets.iter().flat_map(|(_, records)| records).any(|r| r.name == record.name);
+        !name_taken
+            && record.dimensions.iter().all(|(key, value)| {
+                self.dimension_values.get(key.as_str()).is_none_or(|v| *v == value)
+            })
+    }
+
+    fn add(&mut self, record: &'a MetricRecord) {
+        for (key, value) in &record.dimensions {
+            self.dimension_values.insert(key, value);
+        }
+        let mut keys: Vec<&str> = record.dimensions.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        match self.sets.iter_mut().find(|(set_keys, _)| *set_keys == keys) {
+            Some((_, records)) => records.push(record),
+            None => self.sets.push((keys, vec![record])),
+        }
+    }
+}
+
+impl EmfSink {
+    fn emf_record(
+        &self,
+        metric_name: &str,
+        value: f64,
+        unit: &str,
+        dimensions: &HashMap<String, String>,
+    ) -> Value {
+        let record = MetricRecord::new(metric_name, value, unit, dimensions.clone());
+        self.emf_batch_records(std::slice::from_ref(&record)).remove(0)
+    }
+
+    // As few records as carry every metric in `records`: one per set of records whose names
+    // and dimension values don't collide, with a metric directive per dimension set
+    fn emf_batch_records(&self, records: &[MetricRecord]) -> Vec<Value> {
+        let mut documents: Vec<EmfDocument> = Vec::new();
+        for record in records {
+            match documents.iter_mut().find(|document| document.admits(record)) {
+                Some(document) => document.add(record),
+                None => {
+                    let mut document = EmfDocument::default();
+                    document.add(record);
+                    documents.push(document);
+                }
+            }
+        }
+        documents.iter().map(|document| self.emf_document(document)).collect()
+    }
+
+    fn emf_document(&self, document: &EmfDocument) -> Value {
+        let directives: Vec<Value> = document
+            .sets
+            .iter()
+            .map(|(keys, records)| {
+                let dimension_keys: Vec<&str> =
+                    std::iter::once("Stage").chain(keys.iter().copied()).collect();
+                let definitions: Vec<Value> = records
+                    .iter()
+                    .map(|record| json!({ "Name": record.name, "Unit": record.unit }))
+                    .collect();
+                json!({
+                    "Namespace": self.namespace,
+                    "Dimensions": [dimension_keys],
+                    "Metrics": definitions
+                })
+            })
+            .collect();
+        let mut emf_log = json!({
+            "_aws": {
+                "Timestamp": self.clock.now().timestamp_millis(),
+                "CloudWatchMetrics": directives
+            },
+            "Stage": self.stage
+        });
+        for (key, value) in &document.dimension_values {
+            emf_log[*key] = json!(value);
+        }
+        for record in document.sets.iter().flat_map(|(_, records)| records) {
+            emf_log[record.name.as_str()] = json!(record.value);
+        }
+        emf_log
+    }
+
+    fn write(&self, emf_log: &Value) {
+        let record = self.render(emf_log);
+        match &self.queue {
+            Some(queue) => queue.push(record),
+            // Log the EMF formatted JSON to stdout - CloudWatch Logs will automatically parse this
+            None => println!("{}", record),
+        }
+    }
+
+    fn render(&self, emf_log: &Value) -> String {
+        if self.pretty {
+            serde_json::to_string_pretty(emf_log).unwrap_or_else(|_| emf_log.to_string())
+        } else {
+            emf_log.to_string()
+        }
+    }
+}
+
+impl MetricSink for EmfSink {
+    fn emit(&self, name: &str, value: f64, unit: &str, dimensions: &HashMap<String, String>) {
+        let emf_log = self.emf_record(name, value, unit, dimensions);
+        self.write(&emf_log);
+
+        tracing::debug!("Emitted EMF metric: {} = {}", name, value);
+    }
+
+    fn emit_batch(&self, records: &[MetricRecord]) {
+        for emf_log in self.emf_batch_records(records) {
+            self.write(&emf_log);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(queue) = &self.queue {
+            return queue.flush();
+        }
+        // Records are whole lines, but stdout isn't line-buffered when piped
+        if let Err(err) = std::io::stdout().flush() {
+            tracing::warn!("Failed to flush EMF metrics: {}", err);
+        }
+    }
+}
+
+/// Each metric as an INFO `tracing` event on the `metrics` target, with `metric.name`,
+/// `metric.value`, `metric.unit`, `metric.namespace` and `metric.dimensions` (a JSON object,
+/// stage included) fields for an OpenTelemetry layer to forward
+pub struct TracingSink {
+    namespace: String,
+    stage: String,
+}
+
+impl MetricSink for TracingSink {
+    fn emit(&self, name: &str, value: f64, unit: &str, dimensions: &HashMap<String, String>) {
+        let mut all = dimensions.clone();
+        all.insert("Stage".to_string(), self.stage.clone());
+        let dimensions = json!(all).to_string();
+        tracing::event!(
+            target: METRICS_TARGET,
+            Level::INFO,
+            metric.name = name,
+            metric.value = value,
+            metric.unit = unit,
+            metric.namespace = self.namespace.as_str(),
+            metric.dimensions = dimensions.as_str()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{self, MockClock},
+        MetricsHelper,
+    };
+    use std::{fmt, sync::Mutex};
+    use tracing::{
+        field::{Field, Visit},
+        Event, Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+    // Records the fields of every event on the metrics target
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() == METRICS_TARGET {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+        }
+    }
+
+    fn sink(pretty: bool) -> EmfSink {
+        EmfSink {
+            namespace: "SwflcodersChat/test".to_string(),
+            stage: "test".to_string(),
+            pretty,
+            clock: clock::system(),
+            queue: None,
+        }
+    }
+
+    // Bytes written by a queue's thread, for reading back in a test
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_records_are_all_written_by_flush() {
+        let out = SharedBuffer::default();
+        let sink = Arc::new(EmfSink {
+            queue: Some(Arc::new(LineQueue::spawn(out.clone()))),
+            ..sink(false)
+        });
+        let metrics = MetricsHelper::with_sink(sink);
+        for n in 0..500 {
+            let dimensions = HashMap::from([("RoomId".to_string(), format!("room-{}", n))]);
+            metrics.emit_count("MessagesPosted", 1.0, Some(dimensions)).await;
+        }
+        metrics.flush().await;
+
+        let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let rooms: Vec<String> = written
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["RoomId"].to_string())
+            .collect();
+        let expected: Vec<String> = (0..500).map(|n| format!("\"room-{}\"", n)).collect();
+        assert_eq!(rooms, expected);
+    }
+
+    #[test]
+    fn test_batch_is_one_record_with_every_metric() {
+        let dimensions = HashMap::from([("Domain".to_string(), "chat.example.com".to_string())]);
+        let records = sink(false).emf_batch_records(&[
+            MetricRecord::new("BroadcastSuccesses", 3.0, "Count", dimensions.clone()),
+            MetricRecord::new("BroadcastFailures", 1.0, "Count", dimensions),
+        ]);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+
+        let definitions = &record["_aws"]["CloudWatchMetrics"][0]["Metrics"];
+        assert_eq!(
+            definitions,
+            &json!([
+                { "Name": "BroadcastSuccesses", "Unit": "Count" },
+                { "Name": "BroadcastFailures", "Unit": "Count" }
+            ])
+        );
+        assert_eq!(record["BroadcastSuccesses"], json!(3.0));
+        assert_eq!(record["BroadcastFailures"], json!(1.0));
+    }
+
+    #[test]
+    fn test_batch_groups_metrics_by_dimension_set_and_splits_on_collisions() {
+        let room = |room_id: &str| HashMap::from([("RoomId".to_string(), room_id.to_string())]);
+        let records = sink(false).emf_batch_records(&[
+            MetricRecord::new("MessagesPosted", 1.0, "Count", room("general")),
+            MetricRecord::new("MessageLength", 12.0, "None", room("general")),
+            MetricRecord::new("WriteConcurrencyLimit", 8.0, "None", HashMap::new()),
+            // Can't share a document: RoomId is already general there
+            MetricRecord::new("MessagesDeleted", 1.0, "Count", room("random")),
+            // Nor can a metric already in it
+            MetricRecord::new("MessagesPosted", 1.0, "Count", room("random")),
+        ]);
+        assert_eq!(records.len(), 2);
+
+        let first = &records[0];
+        assert_eq!(
+            first["_aws"]["CloudWatchMetrics"],
+            json!([
+                {
+                    "Namespace": "SwflcodersChat/test",
+                    "Dimensions": [["Stage", "RoomId"]],
+                    "Metrics": [
+                        { "Name": "MessagesPosted", "Unit": "Count" },
+                        { "Name": "MessageLength", "Unit": "None" }
+                    ]
+                },
+                {
+                    "Namespace": "SwflcodersChat/test",
+                    "Dimensions": [["Stage"]],
+                    "Metrics": [{ "Name": "WriteConcurrencyLimit", "Unit": "None" }]
+                }
+            ])
+        );
+        assert_eq!(first["RoomId"], "general");
+        assert_eq!(first["MessageLength"], json!(12.0));
+        assert_eq!(first["WriteConcurrencyLimit"], json!(8.0));
+
+        let second = &records[1];
+        assert_eq!(second["RoomId"], "random");
+        assert_eq!(second["MessagesDeleted"], json!(1.0));
+        assert_eq!(second["MessagesPosted"], json!(1.0));
+        assert_eq!(second["_aws"]["CloudWatchMetrics"].as_array().unwrap().len(), 1);
+    }
+
+    // Keeps each batch it's handed, whole
+    #[derive(Default)]
+    struct Batches(Mutex<Vec<Vec<MetricRecord>>>);
+
+    impl MetricSink for Batches {
+        fn emit(&self, name: &str, value: f64, unit: &str, dimensions: &HashMap<String, String>) {
+            let record = MetricRecord::new(name, value, unit, dimensions.clone());
+            self.0.lock().unwrap().push(vec![record]);
+        }
+
+        fn emit_batch(&self, records: &[MetricRecord]) {
+            self.0.lock().unwrap().push(records.to_vec());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_counts_go_out_in_one_batch_and_one_record() {
+        let batches = Arc::new(Batches::default());
+        let metrics = MetricsHelper::with_sink(batches.clone());
+
+        metrics.emit_message_broadcast("general", 3, 2).await;
+
+        let batches = batches.0.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        let names: Vec<_> = batches[0].iter().map(|record| record.name.as_str()).collect();
+        assert_eq!(names, vec!["BroadcastAttempts", "BroadcastSuccesses", "BroadcastFailures"]);
+        assert_eq!(batches[0][2].value, 1.0);
+        assert_eq!(sink(false).emf_batch_records(&batches[0]).len(), 1);
+    }
+
+    #[test]
+    fn test_emf_timestamp_is_the_clock_time() {
+        let at = chrono::DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let sink = EmfSink { clock: Arc::new(MockClock::new(at)), ..sink(false) };
+
+        let record = sink.emf_record("MessagesPosted", 1.0, "Count", &HashMap::new());
+        assert_eq!(record["_aws"]["Timestamp"], json!(1_700_000_000_123_i64));
+    }
+
+    #[test]
+    fn test_pretty_only_changes_formatting() {
+        let dimensions = HashMap::from([("RoomId".to_string(), "general".to_string())]);
+        let record = sink(false).emf_record("MessagesPosted", 1.0, "Count", &dimensions);
+
+        let compact = sink(false).render(&record);
+        let pretty = sink(true).render(&record);
+        assert_eq!(compact.lines().count(), 1);
+        assert!(pretty.lines().count() > 1, "{}", pretty);
+        assert_eq!(
+            serde_json::from_str::<Value>(&compact).unwrap(),
+            serde_json::from_str::<Value>(&pretty).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracing_sink_emits_metric_fields() {
+        let capture = Capture::default();
+        let _guard = tracing_subscriber::registry().with(capture.clone()).set_default();
+        let sink =
+            TracingSink { namespace: "SwflcodersChat/test".to_string(), stage: "test".to_string() };
+        let metrics = MetricsHelper::with_sink(Arc::new(sink));
+
+        let dimensions = HashMap::from([("RoomId".to_string(), "general".to_string())]);
+        metrics.emit_count("MessagesPosted", 2.0, Some(dimensions)).await;
+
+        let events = capture.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["metric.name"], "MessagesPosted");
+        assert_eq!(event["metric.value"], "2.0");
+        assert_eq!(event["metric.unit"], "Count");
+        assert_eq!(event["metric.namespace"], "SwflcodersChat/test");
+        let dimensions: HashMap<String, String> =
+            serde_json::from_str(&event["metric.dimensions"]).unwrap();
+        assert_eq!(
+            dimensions,
+            HashMap::from([
+                ("RoomId".to_string(), "general".to_string()),
+                ("Stage".to_string(), "test".to_string()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// In-process running totals kept by `MetricsHelper` as it emits, for a numeric overview
+/// without CloudWatch. Counts since the process started; each Lambda instance keeps its own.
+#[derive(Debug, Default)]
+pub struct MetricCounters {
+    messages_posted: AtomicU64,
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    broadcast_attempts: AtomicU64,
+    broadcast_successes: AtomicU64,
+    // By metric name, e.g. `ConnectionErrors` or `HandlerPanic`
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// What `GET /admin/metrics/snapshot` answers with
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub messages_posted: u64,
+    pub active_connections: u64,
+    pub broadcast_attempts: u64,
+    pub broadcast_successes: u64,
+    // Successes over attempts; None before anything was broadcast
+    pub broadcast_success_rate: Option<f64>,
+    pub errors: BTreeMap<String, u64>,
+}
+
+impl MetricCounters {
+    pub fn message_posted(&self) {
+        self.messages_posted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_event(&self, event_type: &str) {
+        match event_type {
+            "connect" => self.connects.fetch_add(1, Ordering::Relaxed),
+            "disconnect" => self.disconnects.fetch_add(1, Ordering::Relaxed),
+            _ => return,
+        };
+    }
+
+    pub fn broadcast(&self, attempts: u64, successes: u64) {
+        self.broadcast_attempts.fetch_add(attempts, Ordering::Relaxed);
+        self.broadcast_successes.fetch_add(successes, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, metric_name: &'static str) {
+        *self.errors.lock().unwrap().entry(metric_name).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let attempts = self.broadcast_attempts.load(Ordering::Relaxed);
+        let successes = self.broadcast_successes.load(Ordering::Relaxed);
+        // A disconnect can be seen without its connect (e.g. one opened before a restart)
+        let active_connections = self
+            .connects
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.disconnects.load(Ordering::Relaxed));
+        MetricsSnapshot {
+            messages_posted: self.messages_posted.load(Ordering::Relaxed),
+            active_connections,
+            broadcast_attempts: attempts,
+            broadcast_successes: successes,
+            broadcast_success_rate: (attempts > 0).then(|| successes as f64 / attempts as f64),
+            errors: self
+                .errors
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, count)| (name.to_string(), *count))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::Capture, MetricsHelper};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_totals_rate_broadcasts_and_never_go_below_zero_connections() {
+        let counters = MetricCounters::default();
+        assert_eq!(counters.snapshot().broadcast_success_rate, None);
+
+        counters.connection_event("connect");
+        counters.connection_event("connect");
+        counters.connection_event("disconnect");
+        counters.connection_event("typing");
+        counters.broadcast(4, 3);
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.active_connections, 1);
+        assert_eq!(snapshot.broadcast_success_rate, Some(0.75));
+
+        // Stray disconnects don't take the count below zero
+        counters.connection_event("disconnect");
+        counters.connection_event("disconnect");
+        assert_eq!(counters.snapshot().active_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_helpers_built_on_the_spot_count_towards_the_process_totals() {
+        let server = MetricsHelper::with_sink(Arc::new(Capture::default()));
+        let corrupt = |snapshot: MetricsSnapshot| snapshot.errors.get("CorruptItem").copied();
+        let before = corrupt(server.snapshot()).unwrap_or(0);
+
+        // As the store does when it skips an unreadable item
+        MetricsHelper::new().await.emit_corrupt_item("message_text").await;
+        // Other tests may be counting too, so only a lower bound holds
+        assert!(corrupt(server.snapshot()).is_some_and(|after| after > before));
+
+        let own = MetricsHelper::with_own_counters(Arc::new(Capture::default()));
+        MetricsHelper::new().await.emit_corrupt_item("message_text").await;
+        assert_eq!(corrupt(own.snapshot()), None);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::metric_sink::MetricSink;
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+/// Times a scope, emitting how long it lived as a duration metric when it's dropped, so the
+/// metric goes out however the scope exits: early returns and `?` included. Made by
+/// `MetricsHelper::start_timer`. Sinks emit synchronously, so `Drop` can emit directly.
+#[must_use = "the scope is timed until the guard is dropped"]
+pub struct TimerGuard {
+    sink: Arc<dyn MetricSink>,
+    metric_name: String,
+    dimensions: HashMap<String, String>,
+    started: Instant,
+}
+
+impl TimerGuard {
+    pub(crate) fn new(
+        sink: Arc<dyn MetricSink>,
+        metric_name: &str,
+        dimensions: HashMap<String, String>,
+    ) -> Self {
+        Self { sink, metric_name: metric_name.to_string(), dimensions, started: Instant::now() }
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        let elapsed_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        self.sink.emit(&self.metric_name, elapsed_ms, "Milliseconds", &self.dimensions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::Capture, MetricsHelper};
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    fn timed_parse(metrics: &MetricsHelper, input: &str) -> Result<i64, String> {
+        let _timer = metrics.start_timer(
+            "ParseDuration",
+            Some(HashMap::from([("Route".to_string(), "/parse".to_string())])),
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        let value = input.parse::<i64>().map_err(|e| e.to_string())?;
+        Ok(value * 2)
+    }
+
+    #[test]
+    fn test_timer_guard_emits_on_scope_exit_including_errors() {
+        let capture = Arc::new(Capture::default());
+        let metrics = MetricsHelper::with_sink(capture.clone());
+
+        assert_eq!(timed_parse(&metrics, "21"), Ok(42));
+        // Leaves through `?`, and is timed all the same
+        assert!(timed_parse(&metrics, "twenty-one").is_err());
+
+        let points = capture.0.lock().unwrap();
+        assert_eq!(points.len(), 2);
+        for (name, value, dimensions) in points.iter() {
+            assert_eq!(name, "ParseDuration");
+            assert!(*value >= 5.0, "{} should cover the sleep", value);
+            assert_eq!(dimensions["Route"], "/parse");
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::store::{self, ROOM_LISTING};
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
+use chrono::DateTime;
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Marks a row the migration copied. Its INSERT stream record is old history, so it isn't
+/// broadcast, pushed to the webhook or queued for redelivery.
+pub const MIGRATED_ATTRIBUTE: &str = "migrated";
+
+/// Outcome of copying a messages table into one keyed by `sk`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MigrationReport {
+    pub copied: usize,
+    // Rows the new table already has, left as they are there
+    pub existing: usize,
+    // Rows without a string `id` or numeric `ts` to build a sort key from
+    pub skipped: usize,
+    // Epoch millis the copy started at; a re-run passes it as `since`
+    pub started_at: i64,
+}
+
+/// Copy the messages in `from` (keyed by room_id/ts) into `to` (keyed by room_id/sk), adding
+/// the `sk` sort key, and only those from `since` (epoch millis) on when it's given. A row is
+/// only written where `to` doesn't have it, so the copy never undoes an edit, a flag or a
+/// hide made in the new table, and can be re-run after an interruption. A message deleted
+/// from the new table would be copied again, though; re-runs to pick up what the old code
+/// wrote meanwhile pass the first run's `started_at` as `since` to leave older rows alone.
+pub async fn copy_messages_with_sort_keys(
+    ddb: &DynamoDbClient,
+    from: &str,
+    to: &str,
+    since: Option<i64>,
+    started_at: i64,
+) -> Result<MigrationReport, String> {
+    let mut scan = ddb.scan().table_name(from);
+    if let Some(since) = since {
+        scan = scan
+            .filter_expression("ts >= :since")
+            .expression_attribute_values(":since", AttributeValue::N(since.to_string()));
+    }
+    let mut pages = scan.into_paginator().send();
+    let mut report = MigrationReport { started_at, ..Default::default() };
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to scan {}: {:?}", from, e))?;
+        for item in page.items() {
+            let id = item.get("id").and_then(|value| value.as_s().ok());
+            let ts = item.get("ts").and_then(|value| value.as_n().ok()?.parse::<i64>().ok());
+            let (Some(id), Some(ts)) = (id, ts) else {
+                warn!("Not migrating message row without a usable id and ts: {:?}", item.keys());
+                report.skipped += 1;
+                continue;
+            };
+
+            let mut item = item.clone();
+            item.insert("sk".to_string(), AttributeValue::S(store::sort_key(ts, None, id)));
+            item.insert(MIGRATED_ATTRIBUTE.to_string(), AttributeValue::Bool(true));
+            let result = ddb
+                .put_item()
+                .table_name(to)
+                .set_item(Some(item))
+                .condition_expression("attribute_not_exists(sk)")
+                .send()
+                .await;
+            match result {
+                Ok(_) => report.copied += 1,
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+                {
+                    report.existing += 1
+                }
+                Err(e) => return Err(format!("Failed to copy message {}: {:?}", id, e)),
+            }
+        }
+    }
+    Ok(report)
+}
+
+// Anything past this is millis, not seconds: seconds this large are over 3000 years off
+const MAX_EPOCH_SECONDS: i64 = 100_000_000_000;
+
+/// Outcome of adding the room listing keys to rows written before the listing index
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ListingBackfillReport {
+    pub updated: usize,
+    // Rows that were already listed
+    pub listed: usize,
+    // Rows with neither a `created_at_epoch` nor a `created_at_iso` to list them by
+    pub skipped: usize,
+}
+
+/// The epoch seconds a room row is listed by: its `created_at_epoch`, brought down to seconds
+/// when it was written in millis (as the deploy's seeded `general` room was), or else its
+/// `created_at_iso`
+fn listing_epoch(item: &HashMap<String, AttributeValue>) -> Option<i64> {
+    let epoch = item.get("created_at_epoch").and_then(|value| value.as_n().ok()?.parse().ok());
+    match epoch {
+        Some(epoch) if epoch >= MAX_EPOCH_SECONDS => Some(epoch / 1000),
+        Some(epoch) => Some(epoch),
+        None => {
+            let iso = item.get("created_at_iso")?.as_s().ok()?;
+            Some(DateTime::parse_from_rfc3339(iso).ok()?.timestamp())
+        }
+    }
+}
+
+/// Put every room in `rooms` on the room listing: set `listing` on rows that don't have it,
+/// and `created_at_epoch` in seconds where it's missing or in millis. Rooms are only updated,
+/// never recreated, so a room deleted meanwhile stays deleted, and the backfill can be re-run.
+pub async fn backfill_room_listing(
+    ddb: &DynamoDbClient,
+    rooms: &str,
+) -> Result<ListingBackfillReport, String> {
+    let mut pages = ddb.scan().table_name(rooms).into_paginator().send();
+    let mut report = ListingBackfillReport::default();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to scan {}: {:?}", rooms, e))?;
+        for item in page.items() {
+            let Some(id) = item.get("id").and_then(|value| value.as_s().ok()) else {
+                report.skipped += 1;
+                continue;
+            };
+            let Some(epoch) = listing_epoch(item) else {
+                warn!("Not listing room {} without a creation time", id);
+                report.skipped += 1;
+                continue;
+            };
+            let epoch = AttributeValue::N(epoch.to_string());
+            let listed = item
+                .get("listing")
+                .and_then(|value| value.as_s().ok())
+                .is_some_and(|listing| listing == ROOM_LISTING);
+            if listed && item.get("created_at_epoch") == Some(&epoch) {
+                report.listed += 1;
+                continue;
+            }
+
+            let result = ddb
+                .update_item()
+                .table_name(rooms)
+                .key("id", AttributeValue::S(id.clone()))
+                .update_expression("SET listing = :listing, created_at_epoch = :epoch")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_values(":listing", AttributeValue::S(ROOM_LISTING.into()))
+                .expression_attribute_values(":epoch", epoch)
+                .send()
+                .await;
+            match result {
+                Ok(_) => report.updated += 1,
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+                {
+                    warn!("Room {} was deleted during the backfill", id)
+                }
+                Err(e) => return Err(format!("Failed to list room {}: {:?}", id, e)),
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::{MessageQuery, Tables},
+        store::{DynamoMessageStore, MessageStore},
+        test_support::{create_rooms_table, create_table, local_ddb},
+    };
+    use aws_sdk_dynamodb::types::KeyType;
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_copy_adds_sort_keys() {
+        let ddb = local_ddb().await;
+        let (old, new) = ("migrate-test-messages-v1", "migrate-test-messages-v2");
+        create_table(&ddb, old, &[("room_id", KeyType::Hash), ("ts", KeyType::Range)]).await;
+        create_table(&ddb, new, &[("room_id", KeyType::Hash), ("sk", KeyType::Range)]).await;
+
+        for (id, ts) in [("msg-1", "1700000000000"), ("msg-2", "1700000000001")] {
+            ddb.put_item()
+                .table_name(old)
+                .item("room_id", AttributeValue::S("general".to_string()))
+                .item("ts", AttributeValue::N(ts.to_string()))
+                .item("id", AttributeValue::S(id.to_string()))
+                .item("user_id", AttributeValue::S("01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string()))
+                .item("username", AttributeValue::S("alice".to_string()))
+                .item("message_text", AttributeValue::S("Hello".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+        ddb.put_item()
+            .table_name(old)
+            .item("room_id", AttributeValue::S("general".to_string()))
+            .item("ts", AttributeValue::N("1700000000002".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let report = copy_messages_with_sort_keys(&ddb, old, new, None, 1).await.unwrap();
+        assert_eq!(report, MigrationReport { copied: 2, existing: 0, skipped: 1, started_at: 1 });
+
+        let tables = Tables { rooms: "unused".to_string(), messages: new.to_string() };
+        let store = DynamoMessageStore::new(ddb.clone(), tables);
+        let messages = store.get_messages("general", &MessageQuery::default()).await.unwrap();
+        let ids: Vec<_> = messages.iter().map(|message| message.core.id.as_str()).collect();
+        assert_eq!(ids, vec!["msg-1", "msg-2"]);
+
+        // Re-running leaves what the new table has alone, edits included
+        let mut edited = messages[0].clone();
+        edited.core.message_text = "Hello, edited".to_string();
+        edited.edited_at = chrono::DateTime::from_timestamp_millis(1_700_000_001_000);
+        assert!(store.edit_message(&edited).await.unwrap());
+        let report = copy_messages_with_sort_keys(&ddb, old, new, None, 2).await.unwrap();
+        assert_eq!((report.copied, report.existing), (0, 2));
+        let text = store.get_message("general", "msg-1").await.unwrap().unwrap().core.message_text;
+        assert_eq!(text, "Hello, edited");
+
+        // From `since` on, older rows aren't looked at
+        let report =
+            copy_messages_with_sort_keys(&ddb, old, new, Some(1_700_000_000_001), 3).await.unwrap();
+        assert_eq!((report.copied, report.existing), (0, 1));
+    }
+
+    #[test]
+    fn test_rooms_are_listed_by_their_creation_time_in_seconds() {
+        let row = |attributes: &[(&str, AttributeValue)]| {
+            attributes.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()
+        };
+        let seconds = AttributeValue::N("1700000000".to_string());
+        let millis = AttributeValue::N("1700000000123".to_string());
+        let iso = AttributeValue::S("2023-11-14T22:13:20Z".to_string());
+
+        assert_eq!(listing_epoch(&row(&[("created_at_epoch", seconds)])), Some(1_700_000_000));
+        assert_eq!(listing_epoch(&row(&[("created_at_epoch", millis)])), Some(1_700_000_000));
+        assert_eq!(listing_epoch(&row(&[("created_at_iso", iso)])), Some(1_700_000_000));
+        assert_eq!(listing_epoch(&row(&[])), None);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_backfill_lists_rooms_from_before_the_index() {
+        let ddb = local_ddb().await;
+        let rooms = "migrate-test-rooms";
+        create_rooms_table(&ddb, rooms).await;
+        let put = |id: &str, created_at_epoch: &str| {
+            ddb.put_item()
+                .table_name(rooms)
+                .item("id", AttributeValue::S(id.to_string()))
+                .item("name", AttributeValue::S(id.to_string()))
+                .item("created_at_iso", AttributeValue::S("2023-11-14T22:13:20Z".to_string()))
+                .item("created_at_epoch", AttributeValue::N(created_at_epoch.to_string()))
+                .send()
+        };
+        put("general", "1700000000123").await.unwrap();
+        put("random", "1700000001").await.unwrap();
+
+        let report = backfill_room_listing(&ddb, rooms).await.unwrap();
+        assert_eq!(report, ListingBackfillReport { updated: 2, listed: 0, skipped: 0 });
+
+        let tables = Tables { rooms: rooms.to_string(), messages: "unused".to_string() };
+        let store = DynamoMessageStore::new(ddb.clone(), tables);
+        let page = store.rooms_page(false, None, 10).await.unwrap();
+        let ids: Vec<_> = page.rooms.iter().map(|room| room.id.as_str()).collect();
+        assert_eq!(ids, vec!["random", "general"]);
+
+        let report = backfill_room_listing(&ddb, rooms).await.unwrap();
+        assert_eq!(report, ListingBackfillReport { updated: 0, listed: 2, skipped: 0 });
+    }
+}
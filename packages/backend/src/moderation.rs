@@ -0,0 +1,113 @@
+use std::{collections::HashMap, env};
+
+// Terms used for `MESSAGE_BLOCKLIST=default`, one per line
+const DEFAULT_BLOCKLIST: &str = include_str!("moderation_blocklist.txt");
+
+/// Words and phrases message text may not contain (`MESSAGE_BLOCKLIST`, comma-separated, or
+/// `default` for the bundled list). Matching ignores case and goes by whole words, so `ass`
+/// blocks "Ass!" but not "class"; a phrase matches its words in order, whatever separates them.
+/// Unset or empty, nothing is blocked.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    // Each term's words, by its first word
+    terms: HashMap<String, Vec<Vec<String>>>,
+}
+
+impl Blocklist {
+    pub fn new<'a>(terms: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut blocklist = Self::default();
+        for term in terms {
+            let words = words(term);
+            if let Some(first) = words.first() {
+                blocklist.terms.entry(first.clone()).or_default().push(words);
+            }
+        }
+        blocklist
+    }
+
+    pub fn from_env() -> Self {
+        match env::var("MESSAGE_BLOCKLIST") {
+            Ok(value) if value.trim() == "default" => Self::new(DEFAULT_BLOCKLIST.lines()),
+            Ok(value) => Self::new(value.split(',')),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The first blocked term in `text`, as written in the text
+    pub fn find(&self, text: &str) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let spans = word_spans(text);
+        let lowered: Vec<String> =
+            spans.iter().map(|(start, end)| text[*start..*end].to_lowercase()).collect();
+        for (i, word) in lowered.iter().enumerate() {
+            let Some(terms) = self.terms.get(word) else {
+                continue;
+            };
+            if let Some(term) = terms.iter().find(|term| lowered[i..].starts_with(term)) {
+                let (start, _) = spans[i];
+                let (_, end) = spans[i + term.len() - 1];
+                return Some(text[start..end].to_string());
+            }
+        }
+        None
+    }
+}
+
+// Byte ranges of the runs of letters and digits in `text`
+fn word_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+fn words(text: &str) -> Vec<String> {
+    word_spans(text).into_iter().map(|(start, end)| text[start..end].to_lowercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_terms_match_whole_words_in_any_case() {
+        let blocklist = Blocklist::new(["ass", " Darn It ", "", "scheiße", "дурак"]);
+        assert_eq!(blocklist.find("What an ASS!"), Some("ASS".to_string()));
+        assert_eq!(blocklist.find("well, darn   it."), Some("darn   it".to_string()));
+        assert_eq!(blocklist.find("Ach, SCHEISSE"), None);
+        assert_eq!(blocklist.find("Ach, SCHEIßE!"), Some("SCHEIßE".to_string()));
+        assert_eq!(blocklist.find("ты Дурак"), Some("Дурак".to_string()));
+
+        // Inside a longer word, or only part of a phrase, is fine
+        assert_eq!(blocklist.find("First class assets"), None);
+        assert_eq!(blocklist.find("darn, I did it"), None);
+        assert_eq!(blocklist.find("дураки"), None);
+        assert_eq!(blocklist.find("Hello 👋 world"), None);
+    }
+
+    #[test]
+    fn test_empty_blocklist_allows_everything() {
+        let blocklist = Blocklist::new(["", " , "]);
+        assert!(blocklist.is_empty());
+        assert_eq!(blocklist.find("anything at all"), None);
+        assert!(!Blocklist::new(DEFAULT_BLOCKLIST.lines()).is_empty());
+    }
+}
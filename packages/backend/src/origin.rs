@@ -0,0 +1,144 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use tracing::warn;
+
+// Hex characters of the HMAC kept as the origin hash (64 bits)
+const ORIGIN_HASH_CHARS: usize = 16;
+
+/// Where a message was sent from, as stored for moderators. The IP itself is never kept: only a
+/// salted hash of its network prefix and, when a lookup is configured, a country code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageOrigin {
+    pub hash: String,
+    pub country: Option<String>,
+}
+
+/// Coarse IP geolocation for `origin_country`
+pub trait GeoLookup: Send + Sync {
+    /// ISO 3166-1 alpha-2 country code, if known
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// Turns a client IP into a `MessageOrigin`. On unless `CAPTURE_ORIGIN=false`; hashes are keyed
+/// with `ORIGIN_HASH_SALT` so they can't be reversed by hashing every address, and without a
+/// salt nothing is captured.
+pub struct OriginCapture {
+    enabled: bool,
+    salt: String,
+    geo: Option<Box<dyn GeoLookup>>,
+}
+
+impl OriginCapture {
+    pub fn new(enabled: bool, salt: String, geo: Option<Box<dyn GeoLookup>>) -> Self {
+        Self { enabled, salt, geo }
+    }
+
+    pub fn from_env() -> Self {
+        let enabled = env::var("CAPTURE_ORIGIN")
+            .map(|value| !(value == "0" || value.eq_ignore_ascii_case("false")))
+            .unwrap_or(true);
+        Self::salted(enabled, env::var("ORIGIN_HASH_SALT").ok().filter(|salt| !salt.is_empty()))
+    }
+
+    // There are few enough /24s and /48s in use to hash them all, so an unkeyed hash of one is
+    // as good as the prefix itself
+    fn salted(enabled: bool, salt: Option<String>) -> Self {
+        if enabled && salt.is_none() {
+            warn!("CAPTURE_ORIGIN is on without ORIGIN_HASH_SALT; not capturing message origins");
+        }
+        Self::new(enabled && salt.is_some(), salt.unwrap_or_default(), None)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// None when capture is disabled or the client IP is unknown
+    pub fn capture(&self, ip: Option<IpAddr>) -> Option<MessageOrigin> {
+        let ip = ip.filter(|_| self.enabled)?;
+        let hash = self.keyed_hash(&truncate(ip).to_string());
+        let country = self.geo.as_ref().and_then(|geo| geo.country(ip));
+        Some(MessageOrigin { hash, country })
+    }
+
+    /// Salted hash of any identifier, in the same form as origin hashes. Unlike `capture` it
+    /// hashes with or without a salt, so callers that rely on it need `ORIGIN_HASH_SALT` set.
+    pub fn keyed_hash(&self, value: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.salt.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(value.as_bytes());
+        let mut hash = hex::encode(mac.finalize().into_bytes());
+        hash.truncate(ORIGIN_HASH_CHARS);
+        hash
+    }
+}
+
+// Keep the network (/24 for IPv4, /48 for IPv6) so the hash identifies a neighbourhood, not a host
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & 0xffff_ff00)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+    }
+}
+
+/// The client IP, preferring the address API Gateway saw over the spoofable `X-Forwarded-For`,
+/// whose first entry is used only when no source IP is available (e.g. behind a local proxy)
+pub fn client_ip(source_ip: Option<&str>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+    source_ip
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| forwarded_for?.split(',').next()?.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedCountry;
+
+    impl GeoLookup for FixedCountry {
+        fn country(&self, _ip: IpAddr) -> Option<String> {
+            Some("NZ".to_string())
+        }
+    }
+
+    #[test]
+    fn test_origin_is_hashed_by_network() {
+        let capture = OriginCapture::new(true, "salt".to_string(), Some(Box::new(FixedCountry)));
+        let origin = capture.capture("203.0.113.7".parse().ok()).unwrap();
+        assert_eq!(origin.hash.len(), ORIGIN_HASH_CHARS);
+        assert_eq!(origin.country.as_deref(), Some("NZ"));
+        assert!(!origin.hash.contains("203"));
+
+        // Same /24, same hash; a different salt or network changes it
+        assert_eq!(capture.capture("203.0.113.200".parse().ok()).unwrap().hash, origin.hash);
+        assert_ne!(capture.capture("203.0.114.7".parse().ok()).unwrap().hash, origin.hash);
+        let other_salt = OriginCapture::new(true, "pepper".to_string(), None);
+        assert_ne!(other_salt.capture("203.0.113.7".parse().ok()).unwrap().hash, origin.hash);
+    }
+
+    #[test]
+    fn test_disabled_capture_records_nothing() {
+        let capture = OriginCapture::new(false, "salt".to_string(), Some(Box::new(FixedCountry)));
+        assert_eq!(capture.capture("203.0.113.7".parse().ok()), None);
+    }
+
+    #[test]
+    fn test_capture_is_off_without_a_salt() {
+        assert!(!OriginCapture::salted(true, None).enabled());
+        assert_eq!(OriginCapture::salted(true, None).capture("203.0.113.7".parse().ok()), None);
+        assert!(OriginCapture::salted(true, Some("salt".to_string())).enabled());
+        assert!(!OriginCapture::salted(false, Some("salt".to_string())).enabled());
+    }
+
+    #[test]
+    fn test_client_ip_prefers_source_ip() {
+        let forwarded = Some("198.51.100.1, 10.0.0.1");
+        assert_eq!(client_ip(Some("203.0.113.7"), forwarded), "203.0.113.7".parse().ok());
+        assert_eq!(client_ip(None, forwarded), "198.51.100.1".parse().ok());
+        assert_eq!(client_ip(Some("garbage"), None), None);
+    }
+}
@@ -0,0 +1,146 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+use tracing::warn;
+
+/// `error` of the 400 answered to a cursor past `MAX_PAGE_DEPTH`
+pub const PAGINATION_DEPTH_EXCEEDED: &str = "pagination_depth_exceeded";
+
+const DEFAULT_MAX_PAGE_DEPTH: u32 = 50;
+
+// Hex characters of the HMAC kept as a cursor's signature (64 bits)
+const SIGNATURE_CHARS: usize = 16;
+
+/// Why a cursor was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CursorError {
+    // Not one we issued, or tampered with
+    Invalid,
+    DepthExceeded { max_depth: u32 },
+}
+
+/// Issues and opens the `next_cursor`s of message pages. A cursor is
+/// `{depth}.{signature}.{sort key}`: the sort key the next page starts after, and how many
+/// pages have been read to get there, signed with `CURSOR_SECRET` so a client can't reset the
+/// count. Scrolling past `MAX_PAGE_DEPTH` pages (default 50, 0 for no limit) is refused, so deep
+/// history goes through search or `created_before` instead of walking a giant room. Without a
+/// secret, cursors are signed with a random key of the process's own, which only it can open.
+#[derive(Debug, Clone)]
+pub struct PageCursors {
+    secret: String,
+    max_depth: Option<u32>,
+}
+
+impl PageCursors {
+    pub fn new(secret: String, max_depth: Option<u32>) -> Self {
+        Self { secret, max_depth }
+    }
+
+    pub fn from_env() -> Self {
+        let secret = env::var("CURSOR_SECRET").ok().filter(|secret| !secret.is_empty());
+        if secret.is_none() {
+            warn!("CURSOR_SECRET is unset; page cursors only open in the process that issued them");
+        }
+        let secret = secret.unwrap_or_else(random_secret);
+        let max_depth = env::var("MAX_PAGE_DEPTH")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAX_PAGE_DEPTH);
+        Self::new(secret, (max_depth > 0).then_some(max_depth))
+    }
+
+    /// Cursor for the page after `sort_key`, reached by reading `depth` pages
+    pub fn issue(&self, sort_key: &str, depth: u32) -> String {
+        let signature = hex::encode(self.mac(depth, sort_key).finalize().into_bytes());
+        format!("{}.{}.{}", depth, &signature[..SIGNATURE_CHARS], sort_key)
+    }
+
+    /// The sort key a cursor resumes after and the pages read to get there, if the page it
+    /// asks for is within the limit
+    pub fn open(&self, cursor: &str) -> Result<(String, u32), CursorError> {
+        let mut parts = cursor.splitn(3, '.');
+        let (Some(depth), Some(signature), Some(sort_key)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(CursorError::Invalid);
+        };
+        let depth = depth.parse::<u32>().map_err(|_| CursorError::Invalid)?;
+        let signature = hex::decode(signature).map_err(|_| CursorError::Invalid)?;
+        if signature.len() * 2 != SIGNATURE_CHARS {
+            return Err(CursorError::Invalid);
+        }
+        self.mac(depth, sort_key)
+            .verify_truncated_left(&signature)
+            .map_err(|_| CursorError::Invalid)?;
+
+        match self.max_depth {
+            Some(max_depth) if depth >= max_depth => Err(CursorError::DepthExceeded { max_depth }),
+            _ => Ok((sort_key.to_string(), depth)),
+        }
+    }
+
+    fn mac(&self, depth: u32, sort_key: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(format!("{}.{}", depth, sort_key).as_bytes());
+        mac
+    }
+}
+
+// A key no client can know, so none can sign a cursor of its own
+fn random_secret() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    hex::encode(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip_and_tampering() {
+        let cursors = PageCursors::new("secret".to_string(), Some(50));
+        let sort_key = "1700000000000#01ARZ3NDEKTSV4RRFFQ69G5FAV";
+        let cursor = cursors.issue(sort_key, 3);
+        assert_eq!(cursors.open(&cursor), Ok((sort_key.to_string(), 3)));
+
+        // Resetting the page count breaks the signature, as does a different secret
+        let reset = cursor.replacen("3.", "1.", 1);
+        assert_eq!(cursors.open(&reset), Err(CursorError::Invalid));
+        let other = PageCursors::new("other".to_string(), Some(50));
+        assert_eq!(other.open(&cursor), Err(CursorError::Invalid));
+
+        assert_eq!(cursors.open(sort_key), Err(CursorError::Invalid));
+        assert_eq!(cursors.open("1.zz.1700000000000#a"), Err(CursorError::Invalid));
+    }
+
+    #[test]
+    fn test_cursors_signed_with_a_random_key_only_open_where_issued() {
+        let (first, second) = (random_secret(), random_secret());
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second);
+
+        let cursors = PageCursors::new(first, Some(50));
+        let cursor = cursors.issue("1700000000000#a", 3);
+        assert!(cursors.open(&cursor).is_ok());
+        assert_eq!(PageCursors::new(second, Some(50)).open(&cursor), Err(CursorError::Invalid));
+        // Nor can a client sign one with the empty key
+        let forged = PageCursors::new(String::new(), Some(50)).issue("1700000000000#a", 0);
+        assert_eq!(cursors.open(&forged), Err(CursorError::Invalid));
+    }
+
+    #[test]
+    fn test_cursors_past_the_max_depth_are_refused() {
+        let cursors = PageCursors::new(String::new(), Some(2));
+        assert!(cursors.open(&cursors.issue("1700000000000#a", 1)).is_ok());
+        assert_eq!(
+            cursors.open(&cursors.issue("1700000000000#a", 2)),
+            Err(CursorError::DepthExceeded { max_depth: 2 })
+        );
+
+        let unlimited = PageCursors::new(String::new(), None);
+        assert!(unlimited.open(&unlimited.issue("1700000000000#a", 10_000)).is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::clock::Clock;
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, env, sync::Arc, sync::Mutex, time::Duration};
+
+const DEFAULT_MESSAGES_PER_MINUTE: f64 = 30.0;
+
+// A user's tokens as of `refilled_at`
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    refilled_at: DateTime<Utc>,
+    tokens: f64,
+}
+
+/// Where a user's bucket stands, for the `X-RateLimit-*` headers on their posts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Posts a full bucket allows
+    pub limit: u64,
+    /// Posts allowed right now
+    pub remaining: u64,
+    /// When the bucket will be full again, to the second
+    pub reset_at: DateTime<Utc>,
+}
+
+/// Per-user token bucket on message posts (`MESSAGES_PER_MINUTE`, 30 by default). Each user
+/// may burst a minute's worth of posts, then gets one more per `60 / rate` seconds.
+///
+/// Best effort: the buckets live in this process's memory, so each instance of the local
+/// server counts on its own and a restart forgets them. The REST Lambda would need the
+/// buckets in DynamoDB to share them across invocations, and doesn't limit posts, so its
+/// answers carry no `X-RateLimit-*` headers either.
+pub struct MessageRateLimiter {
+    per_minute: f64,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MessageRateLimiter {
+    /// `per_minute` posts a minute per user; anything below one is treated as one
+    pub fn new(per_minute: f64, clock: Arc<dyn Clock>) -> Self {
+        Self { per_minute: per_minute.max(1.0), clock, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn from_env(clock: Arc<dyn Clock>) -> Self {
+        let per_minute = env::var("MESSAGES_PER_MINUTE")
+            .ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|per_minute| per_minute.is_finite())
+            .unwrap_or(DEFAULT_MESSAGES_PER_MINUTE);
+        Self::new(per_minute, clock)
+    }
+
+    /// Take one of `user_id`'s tokens. When they have none, how long until the next one.
+    pub fn check(&self, user_id: &str) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(user_id.to_string())
+            .or_insert(Bucket { refilled_at: now, tokens: self.per_minute });
+
+        let elapsed_secs = (now - bucket.refilled_at).num_milliseconds().max(0) as f64 / 1000.0;
+        bucket.tokens =
+            (bucket.tokens + elapsed_secs * self.per_minute / 60.0).min(self.per_minute);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        // To the millisecond, so float error doesn't show up as a stray nanosecond
+        let wait_ms = ((1.0 - bucket.tokens) * 60_000.0 / self.per_minute).round();
+        Err(Duration::from_millis(wait_ms as u64))
+    }
+
+    /// `user_id`'s bucket as of now, without taking from it
+    pub fn quota(&self, user_id: &str) -> Quota {
+        let now = self.clock.now();
+        let tokens = match self.buckets.lock().unwrap().get(user_id) {
+            Some(bucket) => {
+                let elapsed_secs =
+                    (now - bucket.refilled_at).num_milliseconds().max(0) as f64 / 1000.0;
+                (bucket.tokens + elapsed_secs * self.per_minute / 60.0).min(self.per_minute)
+            }
+            None => self.per_minute,
+        };
+        let full_in_secs = ((self.per_minute - tokens) * 60.0 / self.per_minute).ceil();
+        Quota {
+            limit: self.per_minute as u64,
+            remaining: tokens as u64,
+            reset_at: now + chrono::Duration::seconds(full_in_secs as i64),
+        }
+    }
+
+    /// Forget users whose buckets have refilled, as they'd start full anyway
+    pub fn prune(&self) {
+        let now = self.clock.now();
+        let full_after = chrono::Duration::seconds(60);
+        self.buckets.lock().unwrap().retain(|_, bucket| now - bucket.refilled_at < full_after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_exhausted_bucket_recovers_as_time_passes() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let limiter = MessageRateLimiter::new(6.0, clock.clone());
+
+        for _ in 0..6 {
+            assert_eq!(limiter.check("alice"), Ok(()));
+        }
+        // One token comes back every ten seconds
+        assert_eq!(limiter.check("alice"), Err(Duration::from_secs(10)));
+        // Someone else's bucket is their own
+        assert_eq!(limiter.check("bob"), Ok(()));
+
+        clock.advance(chrono::Duration::seconds(4));
+        assert_eq!(limiter.check("alice"), Err(Duration::from_secs(6)));
+
+        clock.advance(chrono::Duration::seconds(6));
+        assert_eq!(limiter.check("alice"), Ok(()));
+        assert!(limiter.check("alice").is_err());
+
+        // Refills never go past the burst
+        clock.advance(chrono::Duration::hours(1));
+        for _ in 0..6 {
+            assert_eq!(limiter.check("alice"), Ok(()));
+        }
+        assert!(limiter.check("alice").is_err());
+    }
+
+    #[test]
+    fn test_quota_counts_down_and_resets_once_refilled() {
+        let start = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let clock = Arc::new(MockClock::new(start));
+        let limiter = MessageRateLimiter::new(6.0, clock.clone());
+        assert_eq!(limiter.quota("alice"), Quota { limit: 6, remaining: 6, reset_at: start });
+
+        limiter.check("alice").unwrap();
+        limiter.check("alice").unwrap();
+        let quota = limiter.quota("alice");
+        assert_eq!(quota.remaining, 4);
+        assert_eq!(quota.reset_at, start + chrono::Duration::seconds(20));
+
+        clock.advance(chrono::Duration::seconds(15));
+        assert_eq!(limiter.quota("alice").remaining, 5);
+        clock.advance(chrono::Duration::seconds(5));
+        assert_eq!(limiter.quota("alice").remaining, 6);
+        assert_eq!(limiter.quota("alice").reset_at, clock.now());
+    }
+
+    #[test]
+    fn test_prune_forgets_only_refilled_buckets() {
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let limiter = MessageRateLimiter::new(1.0, clock.clone());
+        limiter.check("alice").unwrap();
+        clock.advance(chrono::Duration::seconds(30));
+        limiter.check("bob").unwrap();
+
+        clock.advance(chrono::Duration::seconds(30));
+        limiter.prune();
+        assert_eq!(limiter.buckets.lock().unwrap().keys().collect::<Vec<_>>(), vec!["bob"]);
+    }
+}
@@ -0,0 +1,90 @@
+use std::env;
+use types::{ReconnectHint, ReconnectReason};
+
+const DEFAULT_RECONNECT_BASE_MS: u32 = 1_000;
+const DEFAULT_RECONNECT_MAX_MS: u32 = 30_000;
+// Open connections per doubling of the reconnect window
+const CONNECTIONS_PER_DOUBLING: usize = 100;
+// Keeps the doubling shift well inside u32
+const MAX_DOUBLINGS: usize = 16;
+
+/// Reconnect hints sent when the server closes connections itself (`RECONNECT_BASE_MS`,
+/// default 1000; `RECONNECT_MAX_MS`, default 30000). Each client is told a point somewhere in
+/// a window that doubles with load, so a crowd closed together comes back spread out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectBackoff {
+    pub base_ms: u32,
+    pub max_ms: u32,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self { base_ms: DEFAULT_RECONNECT_BASE_MS, max_ms: DEFAULT_RECONNECT_MAX_MS }
+    }
+}
+
+impl ReconnectBackoff {
+    pub fn from_env() -> Self {
+        fn parse(name: &str) -> Option<u32> {
+            env::var(name).ok().and_then(|value| value.parse().ok()).filter(|ms| *ms > 0)
+        }
+
+        let defaults = Self::default();
+        Self {
+            base_ms: parse("RECONNECT_BASE_MS").unwrap_or(defaults.base_ms),
+            max_ms: parse("RECONNECT_MAX_MS").unwrap_or(defaults.max_ms),
+        }
+    }
+
+    /// Longest wait suggested with `open_connections` connections: `base_ms` doubled for every
+    /// 100 of them, and once more when the server is overloaded, capped at `max_ms`
+    pub fn window_ms(&self, reason: ReconnectReason, open_connections: usize) -> u32 {
+        let overloaded = usize::from(reason == ReconnectReason::Overloaded);
+        let doublings =
+            (open_connections / CONNECTIONS_PER_DOUBLING + overloaded).min(MAX_DOUBLINGS);
+        self.base_ms.saturating_mul(1 << doublings).min(self.max_ms).max(self.base_ms)
+    }
+
+    /// A hint `jitter` (0 to 1) of the way through the window, never shorter than `base_ms`
+    pub fn hint(
+        &self,
+        reason: ReconnectReason,
+        open_connections: usize,
+        jitter: f64,
+    ) -> ReconnectHint {
+        let window = self.window_ms(reason, open_connections);
+        let spread = f64::from(window - self.base_ms) * jitter.clamp(0.0, 1.0);
+        ReconnectHint { reason, after_ms: self.base_ms + spread as u32 }
+    }
+}
+
+/// `hint` as the reason of a close frame
+pub fn close_reason(hint: &ReconnectHint) -> String {
+    serde_json::to_string(hint).expect("reconnect hints serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_doubles_with_load_up_to_the_cap() {
+        let backoff = ReconnectBackoff::default();
+        assert_eq!(backoff.window_ms(ReconnectReason::Shutdown, 1), 1_000);
+        assert_eq!(backoff.window_ms(ReconnectReason::Shutdown, 250), 4_000);
+        assert_eq!(backoff.window_ms(ReconnectReason::Overloaded, 250), 8_000);
+        assert_eq!(backoff.window_ms(ReconnectReason::Shutdown, 1_000_000), 30_000);
+
+        let hint = |jitter| backoff.hint(ReconnectReason::Shutdown, 250, jitter).after_ms;
+        assert_eq!((hint(0.0), hint(0.5), hint(1.0)), (1_000, 2_500, 4_000));
+    }
+
+    #[test]
+    fn test_close_reason_fits_a_close_frame() {
+        let hint = ReconnectHint { reason: ReconnectReason::Overloaded, after_ms: 30_000 };
+        let reason = close_reason(&hint);
+        assert_eq!(reason, r#"{"type":"reconnect","reason":"Overloaded","after_ms":30000}"#);
+        // Close frame reasons are limited to 123 bytes
+        assert!(reason.len() <= 123);
+    }
+}
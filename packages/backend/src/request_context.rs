@@ -0,0 +1,49 @@
+use crate::{
+    clock::{self, Clock, MockClock},
+    id_generator::{self, IdGenerator, SeqIdGenerator},
+};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+/// Where a handler gets the time and new ids from: the one place both are injected. The
+/// system clock and `MESSAGE_ID_FORMAT` ids in production; a stopped clock and sequential ids
+/// in tests, so every id and timestamp a handler hands back can be asserted exactly.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<dyn IdGenerator>,
+}
+
+impl Default for RequestContext {
+    // The system clock and random UUIDs, as stores default to
+    fn default() -> Self {
+        Self { clock: clock::system(), ids: id_generator::uuid_v4() }
+    }
+}
+
+impl RequestContext {
+    pub fn new(clock: Arc<dyn Clock>, ids: Arc<dyn IdGenerator>) -> Self {
+        Self { clock, ids }
+    }
+
+    /// The system clock, with ids in the format `MESSAGE_ID_FORMAT` picks
+    pub fn from_env() -> Self {
+        let clock = clock::system();
+        Self { ids: id_generator::from_env(clock.clone()), clock }
+    }
+
+    /// Time stopped at `now`, and ids `msg-00000001`, `msg-00000002`, ...
+    pub fn deterministic(now: DateTime<Utc>) -> Self {
+        Self { clock: Arc::new(MockClock::new(now)), ids: Arc::new(SeqIdGenerator::new("msg-")) }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_ids(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+
+/// Variables the REST Lambda can't serve without. CONNECTIONS_TABLE, CHAT_USERS_TABLE and
+/// CHAT_FLAGS_TABLE only switch features on, so they aren't required.
+pub const REST: &[&str] = &["CHAT_ROOMS_TABLE", "CHAT_MESSAGES_TABLE"];
+
+pub const COMPACT_HISTORY: &[&str] = &["CHAT_ROOMS_TABLE", "CHAT_MESSAGES_TABLE"];
+
+// Lambdas posting to connections also need WS_API_ID, WS_STAGE and AWS_REGION, which the
+// management endpoint is built from (see `broadcast::api_gateway_client`). Connect and
+// disconnect post presence to the room. Connect also keys the hash it counts client IPs by
+// with ORIGIN_HASH_SALT.
+pub const WS_CONNECT: &[&str] =
+    &["CONNECTIONS_TABLE", "WS_API_ID", "WS_STAGE", "AWS_REGION", "ORIGIN_HASH_SALT"];
+
+pub const WS_DISCONNECT: &[&str] = &["CONNECTIONS_TABLE", "WS_API_ID", "WS_STAGE", "AWS_REGION"];
+
+pub const WS_DEFAULT: &[&str] = &["CONNECTIONS_TABLE", "WS_API_ID", "WS_STAGE", "AWS_REGION"];
+
+pub const WS_BROADCAST: &[&str] = &["CONNECTIONS_TABLE", "WS_API_ID", "WS_STAGE", "AWS_REGION"];
+
+pub const WS_CONNECTION_GAUGE: &[&str] = &["CONNECTIONS_TABLE"];
+
+pub const WS_REDELIVER: &[&str] =
+    &["BROADCAST_DURABLE", "RETRY_TABLE", "WS_API_ID", "WS_STAGE", "AWS_REGION"];
+
+/// Check that every variable in `required` is set (and not empty). Called first thing in each
+/// Lambda's `main`, so a missing one fails the cold start with its name instead of a panic on
+/// the first invocation.
+pub fn validate_env(required: &[&str]) -> Result<(), String> {
+    validate_with(required, |name| env::var(name).ok())
+}
+
+fn validate_with(required: &[&str], lookup: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    match required.iter().find(|name| lookup(name).is_none_or(|value| value.is_empty())) {
+        Some(missing) => Err(format!("{} must be set", missing)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_validate_env_names_the_first_missing_variable() {
+        let vars: HashMap<&str, &str> =
+            HashMap::from([("CONNECTIONS_TABLE", "connections"), ("WS_STAGE", "")]);
+        let lookup = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        assert_eq!(validate_with(&["CONNECTIONS_TABLE"], lookup), Ok(()));
+        // WS_API_ID is the first one missing; an empty WS_STAGE counts as missing too
+        assert_eq!(validate_with(WS_BROADCAST, lookup), Err("WS_API_ID must be set".to_string()));
+        assert_eq!(
+            validate_with(&["CONNECTIONS_TABLE", "WS_STAGE"], lookup),
+            Err("WS_STAGE must be set".to_string())
+        );
+    }
+}
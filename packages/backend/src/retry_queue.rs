@@ -0,0 +1,424 @@
+use crate::{broadcast::ManagementClients, clock::Clock, encryption::TextCipher};
+use aws_sdk_apigatewaymanagement::primitives::Blob;
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, DeleteRequest, PutRequest, WriteRequest},
+    Client as DynamoDbClient,
+};
+use serde::Serialize;
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+const DEFAULT_RETRY_TTL: Duration = Duration::from_secs(3600);
+
+// BatchWriteItem accepts at most 25 requests per call
+const BATCH_WRITE_LIMIT: usize = 25;
+
+// Passes over a batch's UnprocessedItems before giving up on them
+const BATCH_WRITE_ATTEMPTS: u32 = 3;
+
+// Stored attribute holding an encrypted entry's payload, in place of `payload`
+const ENCRYPTED_PAYLOAD_ATTRIBUTE: &str = "payload_enc";
+
+/// One undelivered `(connection_id, message_id)` pair, with what is needed to post it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryEntry {
+    pub connection_id: String,
+    pub message_id: String,
+    pub room_id: String,
+    // Management endpoint the connection was made through; None for the default endpoint
+    pub endpoint: Option<String>,
+    // The broadcast envelope as sent to the connection
+    pub payload: String,
+}
+
+// Outcome of one redelivery pass
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RedeliveryStats {
+    pub pending: i32,
+    pub delivered: i32,
+    pub gone: i32,
+    pub failed: i32,
+}
+
+/// Retry table for at-least-once broadcast, enabled by `BROADCAST_DURABLE` with the table named
+/// by `RETRY_TABLE`. Fan-out records every API Gateway connection it is about to post to and
+/// clears each one once it is delivered (or gone), so whatever a failed post or a crashed
+/// invocation leaves behind is redelivered by `ws-redeliver` until `RETRY_TTL_SECS` runs out.
+#[derive(Clone)]
+pub struct RetryQueue {
+    table: String,
+    ttl: Duration,
+    // Sets entries' TTLs and judges which have run out
+    clock: Arc<dyn Clock>,
+    // Seals payloads as the store seals message text, when that's encrypted at rest
+    cipher: Option<Arc<TextCipher>>,
+}
+
+impl RetryQueue {
+    pub fn new(table: String, ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self { table, ttl, clock, cipher: None }
+    }
+
+    /// Encrypt queued payloads with `cipher` (ENCRYPT_AT_REST). A payload carries the whole
+    /// message, so queuing it in the clear would undo the encryption of its text.
+    pub fn with_cipher(mut self, cipher: Option<Arc<TextCipher>>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// None unless `BROADCAST_DURABLE` is on and `RETRY_TABLE` is set
+    pub fn from_env(clock: Arc<dyn Clock>) -> Option<Self> {
+        let durable = env::var("BROADCAST_DURABLE")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if !durable {
+            return None;
+        }
+        let Some(table) = env::var("RETRY_TABLE").ok().filter(|table| !table.is_empty()) else {
+            warn!("BROADCAST_DURABLE is set without RETRY_TABLE; broadcasts are best-effort");
+            return None;
+        };
+        let ttl = env::var("RETRY_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RETRY_TTL);
+        Some(Self::new(table, ttl, clock))
+    }
+
+    pub async fn enqueue(
+        &self,
+        ddb: &DynamoDbClient,
+        entries: &[RetryEntry],
+    ) -> Result<(), String> {
+        let mut requests = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let put = PutRequest::builder()
+                .set_item(Some(self.item(entry).await?))
+                .build()
+                .map_err(|e| e.to_string())?;
+            requests.push(WriteRequest::builder().put_request(put).build());
+        }
+        self.batch_write(ddb, requests).await
+    }
+
+    // The row an entry is queued as, its payload sealed when there's a cipher
+    async fn item(&self, entry: &RetryEntry) -> Result<HashMap<String, AttributeValue>, String> {
+        let expires = self.clock.now().timestamp() + self.ttl.as_secs() as i64;
+        let (payload_attribute, payload) = match &self.cipher {
+            Some(cipher) => (
+                ENCRYPTED_PAYLOAD_ATTRIBUTE,
+                cipher.encrypt(&entry.message_id, &entry.payload).await?,
+            ),
+            None => ("payload", entry.payload.clone()),
+        };
+        let mut item = HashMap::from([
+            ("connection_id".to_string(), AttributeValue::S(entry.connection_id.clone())),
+            ("message_id".to_string(), AttributeValue::S(entry.message_id.clone())),
+            ("room_id".to_string(), AttributeValue::S(entry.room_id.clone())),
+            (payload_attribute.to_string(), AttributeValue::S(payload)),
+            ("ttl".to_string(), AttributeValue::N(expires.to_string())),
+        ]);
+        if let Some(endpoint) = &entry.endpoint {
+            item.insert("endpoint".to_string(), AttributeValue::S(endpoint.clone()));
+        }
+        Ok(item)
+    }
+
+    /// Clear the entries for `message_id` to the given connections
+    pub async fn remove(
+        &self,
+        ddb: &DynamoDbClient,
+        message_id: &str,
+        connection_ids: &[String],
+    ) -> Result<(), String> {
+        let requests = connection_ids
+            .iter()
+            .map(|connection_id| {
+                let delete = DeleteRequest::builder()
+                    .key("connection_id", AttributeValue::S(connection_id.clone()))
+                    .key("message_id", AttributeValue::S(message_id.to_string()))
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                Ok(WriteRequest::builder().delete_request(delete).build())
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        self.batch_write(ddb, requests).await
+    }
+
+    /// Entries still waiting for delivery. Expired ones are skipped, since TTL deletion can lag.
+    pub async fn pending(&self, ddb: &DynamoDbClient) -> Result<Vec<RetryEntry>, String> {
+        let mut entries = Vec::new();
+        let mut start_key = None;
+        loop {
+            let page = ddb
+                .scan()
+                .table_name(&self.table)
+                .filter_expression("#ttl > :now")
+                .expression_attribute_names("#ttl", "ttl")
+                .expression_attribute_values(
+                    ":now",
+                    AttributeValue::N(self.clock.now().timestamp().to_string()),
+                )
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| format!("Failed to scan retry table: {:?}", e))?;
+            for item in page.items.unwrap_or_default() {
+                entries.extend(entry_from_item(&item, self.cipher.as_deref()).await);
+            }
+            start_key = page.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Post every pending entry again, removing those delivered and those whose connection is
+    /// gone. Anything else stays queued for the next pass.
+    pub async fn redeliver(
+        &self,
+        ddb: &DynamoDbClient,
+        api_gateway: &ManagementClients,
+    ) -> Result<RedeliveryStats, String> {
+        let entries = self.pending(ddb).await?;
+        let mut stats = RedeliveryStats { pending: entries.len() as i32, ..Default::default() };
+
+        for entry in entries {
+            let result = api_gateway
+                .client(entry.endpoint.as_deref())
+                .post_to_connection()
+                .connection_id(&entry.connection_id)
+                .data(Blob::new(entry.payload.as_bytes()))
+                .send()
+                .await;
+            match result {
+                Ok(_) => {
+                    info!("Redelivered message {} to {}", entry.message_id, entry.connection_id);
+                    stats.delivered += 1;
+                }
+                Err(e) if e.as_service_error().is_some_and(|err| err.is_gone_exception()) => {
+                    info!("Dropping retry for gone connection {}", entry.connection_id);
+                    stats.gone += 1;
+                }
+                Err(e) => {
+                    warn!("Redelivery to {} failed: {:?}", entry.connection_id, e);
+                    stats.failed += 1;
+                    continue;
+                }
+            }
+            if let Err(e) = self
+                .remove(ddb, &entry.message_id, std::slice::from_ref(&entry.connection_id))
+                .await
+            {
+                error!("Failed to clear retry for {}: {}", entry.connection_id, e);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    async fn batch_write(
+        &self,
+        ddb: &DynamoDbClient,
+        requests: Vec<WriteRequest>,
+    ) -> Result<(), String> {
+        for chunk in requests.chunks(BATCH_WRITE_LIMIT) {
+            let mut pending = HashMap::from([(self.table.clone(), chunk.to_vec())]);
+            for attempt in 1..=BATCH_WRITE_ATTEMPTS {
+                let output = ddb
+                    .batch_write_item()
+                    .set_request_items(Some(pending))
+                    .send()
+                    .await
+                    .map_err(|e| format!("Failed to write retry table: {:?}", e))?;
+                pending = output.unprocessed_items.unwrap_or_default();
+                if pending.values().all(Vec::is_empty) {
+                    break;
+                }
+                if attempt == BATCH_WRITE_ATTEMPTS {
+                    return Err("Retry table writes were left unprocessed".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// An entry as queued. One whose payload is sealed and can't be opened (or there's no cipher
+// for) is skipped, and left for its TTL to clear.
+async fn entry_from_item(
+    item: &HashMap<String, AttributeValue>,
+    cipher: Option<&TextCipher>,
+) -> Option<RetryEntry> {
+    let string = |name: &str| item.get(name).and_then(|value| value.as_s().ok()).cloned();
+    let message_id = string("message_id")?;
+    let payload = match (string("payload"), string(ENCRYPTED_PAYLOAD_ATTRIBUTE), cipher) {
+        (Some(payload), _, _) => payload,
+        (None, Some(envelope), Some(cipher)) => {
+            match cipher.decrypt(&message_id, &envelope).await {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Skipping retry for message {}: {}", message_id, e);
+                    return None;
+                }
+            }
+        }
+        (None, Some(_), None) => {
+            warn!("Skipping encrypted retry for message {} with no cipher", message_id);
+            return None;
+        }
+        (None, None, _) => return None,
+    };
+    Some(RetryEntry {
+        connection_id: string("connection_id")?,
+        message_id,
+        room_id: string("room_id")?,
+        endpoint: string("endpoint"),
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broadcast::broadcast_message,
+        clock,
+        test_support::{
+            create_connections_table, create_table, local_config, serve, StaticDataKeys,
+        },
+    };
+    use aws_sdk_apigatewaymanagement::Client as ApiGatewayClient;
+    use aws_sdk_dynamodb::types::KeyType;
+    use axum::{extract::Path, http::StatusCode, routing::post, Router};
+    use chrono::Utc;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+    use types::{ChatMessage, MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+
+    #[tokio::test]
+    async fn test_payloads_are_sealed_when_a_cipher_is_configured() {
+        let entry = RetryEntry {
+            connection_id: "conn-1".to_string(),
+            message_id: "msg-1".to_string(),
+            room_id: "general".to_string(),
+            endpoint: None,
+            payload: r#"{"message_text":"Hello, vault!"}"#.to_string(),
+        };
+        let queue = RetryQueue::new("retries".to_string(), DEFAULT_RETRY_TTL, clock::system())
+            .with_cipher(Some(Arc::new(TextCipher::new(Box::new(StaticDataKeys)))));
+
+        let item = queue.item(&entry).await.unwrap();
+        assert!(!item.contains_key("payload"));
+        let sealed = item[ENCRYPTED_PAYLOAD_ATTRIBUTE].as_s().unwrap();
+        assert!(!sealed.contains("Hello") && !sealed.contains(&hex::encode("Hello")));
+
+        // Redelivery opens it again, in a process that didn't seal it
+        let reader = TextCipher::new(Box::new(StaticDataKeys));
+        assert_eq!(entry_from_item(&item, Some(&reader)).await, Some(entry.clone()));
+        assert_eq!(entry_from_item(&item, None).await, None);
+
+        // Without a cipher the payload is queued, and read, as it is
+        let plain = RetryQueue::new("retries".to_string(), DEFAULT_RETRY_TTL, clock::system());
+        let item = plain.item(&entry).await.unwrap();
+        assert_eq!(item["payload"].as_s().unwrap(), &entry.payload);
+        assert_eq!(entry_from_item(&item, None).await, Some(entry));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_partial_failure_is_redelivered() {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let connections_table = "durable-test-connections";
+        let queue =
+            RetryQueue::new("durable-test-retries".to_string(), DEFAULT_RETRY_TTL, clock::system());
+        create_connections_table(&ddb, connections_table).await;
+        create_table(
+            &ddb,
+            &queue.table,
+            &[("connection_id", KeyType::Hash), ("message_id", KeyType::Range)],
+        )
+        .await;
+
+        // A management API that can't reach conn-2 until it comes back up
+        let up = Arc::new(AtomicBool::new(false));
+        let delivered: Arc<Mutex<Vec<String>>> = Arc::default();
+        let (flag, log) = (up.clone(), delivered.clone());
+        let url = serve(Router::new().route(
+            "/prod/@connections/:connection_id",
+            post(move |Path(connection_id): Path<String>| async move {
+                if connection_id == "conn-2" && !flag.load(Ordering::SeqCst) {
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                log.lock().unwrap().push(connection_id);
+                StatusCode::OK
+            }),
+        ))
+        .await;
+        let domain = url.trim_start_matches("http://").to_string();
+
+        for connection_id in ["conn-1", "conn-2"] {
+            ddb.put_item()
+                .table_name(connections_table)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S("general".to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .item("transport", AttributeValue::S("apigw".to_string()))
+                .item("domain", AttributeValue::S(domain.clone()))
+                .item("stage", AttributeValue::S("prod".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let clients = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let stats = broadcast_message(&ddb, &clients, connections_table, &message, Some(&queue))
+            .await
+            .unwrap();
+        assert_eq!((stats.connections, stats.successful_sends), (2, 1));
+
+        // Only the undelivered connection is left queued
+        let pending = queue.pending(&ddb).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            (pending[0].connection_id.as_str(), pending[0].message_id.as_str()),
+            ("conn-2", "msg-1")
+        );
+        let payload: ChatMessage = serde_json::from_str(&pending[0].payload).unwrap();
+        assert_eq!(payload.status, MessageStatus::Broadcast);
+
+        up.store(true, Ordering::SeqCst);
+        let stats = queue.redeliver(&ddb, &clients).await.unwrap();
+        assert_eq!(stats, RedeliveryStats { pending: 1, delivered: 1, gone: 0, failed: 0 });
+        assert!(queue.pending(&ddb).await.unwrap().is_empty());
+        assert_eq!(*delivered.lock().unwrap(), vec!["conn-1", "conn-2"]);
+    }
+}
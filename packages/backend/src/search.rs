@@ -0,0 +1,274 @@
+use async_trait::async_trait;
+use reqwest::Client as HttpClient;
+use serde_json::{json, Value};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+use types::ChatMessage;
+
+const DEFAULT_INDEX: &str = "chat-messages";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Full-text index of messages, kept alongside DynamoDB. Indexing is best effort: a message
+/// that fails to index is still stored and broadcast, it just won't turn up in searches.
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+    /// Add or replace a message
+    async fn index(&self, message: &ChatMessage) -> Result<(), String>;
+
+    /// Drop a deleted message; one that was never indexed is already gone
+    async fn remove(&self, room_id: &str, message_id: &str) -> Result<(), String>;
+
+    /// Up to `limit` messages in the room matching every term of `text`, newest first
+    async fn search(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, String>;
+}
+
+/// The index selected by `SEARCH_BACKEND`, if any. `opensearch` reads `SEARCH_URL` and
+/// optionally `SEARCH_INDEX`; unset means searches fall back to filtering in DynamoDB.
+pub fn from_env() -> Option<Arc<dyn SearchIndex>> {
+    match env::var("SEARCH_BACKEND").ok().filter(|backend| !backend.is_empty())?.as_str() {
+        "opensearch" => {
+            let Some(url) = env::var("SEARCH_URL").ok().filter(|url| !url.is_empty()) else {
+                warn!("SEARCH_BACKEND=opensearch without SEARCH_URL; search stays on DynamoDB");
+                return None;
+            };
+            let index = env::var("SEARCH_INDEX").unwrap_or_else(|_| DEFAULT_INDEX.to_string());
+            Some(Arc::new(OpenSearchIndex::new(url, index)))
+        }
+        "memory" => Some(Arc::new(MemorySearchIndex::default())),
+        other => {
+            warn!("Unknown SEARCH_BACKEND '{}'; search stays on DynamoDB", other);
+            None
+        }
+    }
+}
+
+/// An OpenSearch (or Elasticsearch) index holding each message as its JSON document, keyed
+/// by message id. Relies on dynamic mapping: `message_text` is analyzed text and `room_id`
+/// gets its usual `keyword` subfield.
+pub struct OpenSearchIndex {
+    client: HttpClient,
+    base_url: String,
+    index: String,
+}
+
+impl OpenSearchIndex {
+    pub fn new(base_url: String, index: String) -> Self {
+        let client = HttpClient::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self { client, base_url: base_url.trim_end_matches('/').to_string(), index }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}/{}", self.base_url, self.index, path)
+    }
+}
+
+// Body of the search request: the room as a filter, every term required, newest first
+fn search_body(room_id: &str, text: &str, limit: usize) -> Value {
+    json!({
+        "size": limit,
+        "sort": [{ "created_at": { "order": "desc" } }],
+        "query": {
+            "bool": {
+                "filter": [{ "term": { "room_id.keyword": room_id } }],
+                "must": [{ "match": { "message_text": { "query": text, "operator": "and" } } }]
+            }
+        }
+    })
+}
+
+#[async_trait]
+impl SearchIndex for OpenSearchIndex {
+    async fn index(&self, message: &ChatMessage) -> Result<(), String> {
+        let response = self
+            .client
+            .put(self.url(&format!("_doc/{}", message.core.id)))
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| format!("Search index request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Search index responded with status {}", response.status()));
+        }
+        Ok(())
+    }
+
+    // Documents are keyed by message id alone, so the room isn't needed to find it
+    async fn remove(&self, _room_id: &str, message_id: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .delete(self.url(&format!("_doc/{}", message_id)))
+            .send()
+            .await
+            .map_err(|e| format!("Search index request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() && status != reqwest::StatusCode::NOT_FOUND {
+            return Err(format!("Search index responded with status {}", status));
+        }
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, String> {
+        let response = self
+            .client
+            .post(self.url("_search"))
+            .json(&search_body(room_id, text, limit))
+            .send()
+            .await
+            .map_err(|e| format!("Search request failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Search responded with status {}", response.status()));
+        }
+        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        hits.into_iter()
+            .map(|mut hit| serde_json::from_value(hit["_source"].take()).map_err(|e| e.to_string()))
+            .collect()
+    }
+}
+
+/// In-process index for tests and offline runs. Matches whole words, case-insensitively.
+#[derive(Default)]
+pub struct MemorySearchIndex {
+    messages: Mutex<Vec<ChatMessage>>,
+}
+
+// Lowercased words of `text`, with surrounding punctuation dropped
+fn words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+#[async_trait]
+impl SearchIndex for MemorySearchIndex {
+    async fn index(&self, message: &ChatMessage) -> Result<(), String> {
+        let mut messages = self.messages.lock().unwrap();
+        messages.retain(|indexed| indexed.core.id != message.core.id);
+        messages.push(message.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, room_id: &str, message_id: &str) -> Result<(), String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .retain(|indexed| indexed.core.room_id != room_id || indexed.core.id != message_id);
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, String> {
+        let terms = words(text);
+        let mut hits: Vec<ChatMessage> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.core.room_id == room_id)
+            .filter(|message| {
+                let text = words(&message.core.message_text);
+                terms.iter().all(|term| text.contains(term))
+            })
+            .cloned()
+            .collect();
+        hits.sort_by_key(|message| std::cmp::Reverse(message.core.created_at));
+        hits.truncate(limit);
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use types::{MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+
+    fn message(id: &str, room_id: &str, text: &str, created_at_millis: i64) -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: id.to_string(),
+                room_id: room_id.to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: text.to_string(),
+                created_at: DateTime::from_timestamp_millis(created_at_millis).unwrap(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hits_are_matching_messages_newest_first() {
+        let index = MemorySearchIndex::default();
+        for message in [
+            message("m1", "general", "Deploy went out", 1_000),
+            message("m2", "general", "Lunch anyone?", 2_000),
+            message("m3", "general", "the deploy is rolling back!", 3_000),
+            message("m4", "random", "deploy deploy deploy", 4_000),
+            message("m5", "general", "Deploy fixed, rolling forward", 5_000),
+        ] {
+            index.index(&message).await.unwrap();
+        }
+
+        let ids = |hits: Vec<ChatMessage>| -> Vec<String> {
+            hits.into_iter().map(|message| message.core.id).collect()
+        };
+        assert_eq!(ids(index.search("general", "DEPLOY", 10).await.unwrap()), ["m5", "m3", "m1"]);
+        assert_eq!(ids(index.search("general", "deploy rolling", 10).await.unwrap()), ["m5", "m3"]);
+        assert_eq!(ids(index.search("general", "deploy", 2).await.unwrap()), ["m5", "m3"]);
+        assert!(index.search("general", "dinner", 10).await.unwrap().is_empty());
+
+        // Re-indexing a message replaces it rather than duplicating it
+        index.index(&message("m1", "general", "Lunch went out", 1_000)).await.unwrap();
+        assert_eq!(ids(index.search("general", "lunch", 10).await.unwrap()), ["m2", "m1"]);
+
+        // Removed messages stop turning up, and removing one twice is fine
+        index.remove("general", "m1").await.unwrap();
+        index.remove("general", "m1").await.unwrap();
+        assert_eq!(ids(index.search("general", "lunch", 10).await.unwrap()), ["m2"]);
+    }
+
+    #[test]
+    fn test_opensearch_query_filters_room_and_requires_every_term() {
+        let body = search_body("general", "deploy rolling", 25);
+        assert_eq!(body["size"], 25);
+        assert_eq!(body["sort"][0]["created_at"]["order"], "desc");
+        assert_eq!(body["query"]["bool"]["filter"][0]["term"]["room_id.keyword"], "general");
+        assert_eq!(body["query"]["bool"]["must"][0]["match"]["message_text"]["operator"], "and");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    handlers::{self, Tables},
+    MetricsHelper,
+};
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{config::ProvideCredentials, Client as DynamoDbClient};
+use serde::Serialize;
+
+// Outcome of a single preflight check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+// Report printed by `--selftest`; the process exits non-zero unless every check passed
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    fn new(checks: Vec<Check>) -> Self {
+        let passed = checks.iter().all(|check| check.passed);
+        Self { passed, checks }
+    }
+}
+
+fn check(name: &'static str, result: Result<String, String>) -> Check {
+    match result {
+        Ok(detail) => Check { name, passed: true, detail },
+        Err(detail) => Check { name, passed: false, detail },
+    }
+}
+
+/// Run every preflight check without starting the server. `tables` is the result of
+/// `Tables::try_from_env`, so missing table env vars are reported rather than panicking. The
+/// connections table and its `room-index` GSI are only checked when a name is given.
+pub async fn run(
+    aws_config: &SdkConfig,
+    tables: Result<Tables, String>,
+    connections_table: Option<&str>,
+) -> SelfTestReport {
+    let ddb = DynamoDbClient::new(aws_config);
+    let mut checks = Vec::new();
+
+    checks.push(check(
+        "env",
+        tables
+            .as_ref()
+            .map(|tables| format!("rooms={}, messages={}", tables.rooms, tables.messages))
+            .map_err(Clone::clone),
+    ));
+
+    let credentials = match aws_config.credentials_provider() {
+        Some(provider) => provider
+            .provide_credentials()
+            .await
+            .map(|credentials| format!("resolved access key {}", credentials.access_key_id()))
+            .map_err(|e| format!("Failed to resolve AWS credentials: {}", e)),
+        None => Err("No AWS credentials provider configured".to_string()),
+    };
+    checks.push(check("aws_credentials", credentials));
+
+    let table_access = match &tables {
+        Ok(tables) => tables.verify(&ddb).await.map(|_| "key schemas match".to_string()),
+        Err(_) => Err("Skipped: table names are not configured".to_string()),
+    };
+    checks.push(check("tables", table_access));
+
+    if let Some(table) = connections_table {
+        checks.push(check(
+            "connections_table",
+            handlers::verify_connections_table(&ddb, table)
+                .await
+                .map(|_| "key schema and room-index GSI present".to_string()),
+        ));
+    }
+
+    checks.push(check(
+        "metrics",
+        MetricsHelper::try_new().map(|_| "metrics namespace configured".to_string()),
+    ));
+
+    SelfTestReport::new(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_messages_table, create_table, local_config};
+    use aws_sdk_dynamodb::types::KeyType;
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_selftest_against_local_dynamodb() {
+        std::env::set_var("STAGE", "selftest");
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let tables = Tables {
+            rooms: "selftest-rooms".to_string(),
+            messages: "selftest-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+
+        let report = run(&config, Ok(tables.clone()), None).await;
+        assert!(report.passed, "{:?}", report);
+        assert_eq!(report.checks.len(), 4);
+
+        let _ = ddb.delete_table().table_name(&tables.messages).send().await;
+        let report = run(&config, Ok(tables), None).await;
+        assert!(!report.passed);
+        let failed: Vec<_> = report.checks.iter().filter(|check| !check.passed).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "tables");
+        assert!(failed[0].detail.contains("selftest-messages"), "{}", failed[0].detail);
+    }
+
+    #[tokio::test]
+    async fn test_selftest_reports_missing_table_env() {
+        let config = local_config().await;
+        let report = run(&config, Err("CHAT_ROOMS_TABLE must be set".to_string()), None).await;
+
+        assert!(!report.passed);
+        let env_check = &report.checks[0];
+        assert_eq!(env_check.name, "env");
+        assert!(!env_check.passed);
+        assert_eq!(env_check.detail, "CHAT_ROOMS_TABLE must be set");
+        assert!(report.checks.iter().any(|check| check.name == "tables" && !check.passed));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, env, sync::Mutex};
+use tokio::sync::oneshot;
+
+// Queues kept before released ones are swept out
+const SWEEP_ABOVE: usize = 1024;
+
+/// Strict per-connection ordering of broadcasts (`BROADCAST_ORDERED`). Off by default: posts
+/// to connections all run concurrently, so when two messages to a room are broadcast close
+/// together one connection can get the second first. With it on, a send to a connection waits
+/// for every earlier broadcast's send to that same connection to finish; different connections
+/// still go in parallel. The cost is latency: one slow or throttled post holds up everything
+/// after it on that connection, and a room's broadcasts line up while each looks up its
+/// connections.
+///
+/// Keys are queued independently. Turns are taken synchronously, and come up in the order
+/// they were taken whatever order they're waited on in.
+#[derive(Debug, Default)]
+pub struct SendOrder {
+    // Released when the newest turn on each key is over
+    tails: Mutex<HashMap<String, oneshot::Receiver<()>>>,
+}
+
+/// A place in one key's queue, over when dropped
+#[derive(Debug)]
+pub struct Turn {
+    previous: Option<oneshot::Receiver<()>>,
+    _release: oneshot::Sender<()>,
+}
+
+impl SendOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// None unless `BROADCAST_ORDERED` is set
+    pub fn from_env() -> Option<Self> {
+        env::var("BROADCAST_ORDERED")
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .then(Self::new)
+    }
+
+    /// A turn at the back of `key`'s queue
+    pub fn take_turn(&self, key: &str) -> Turn {
+        self.take_turns([key]).remove(key).unwrap()
+    }
+
+    /// A turn on each key at once. Taking them all under one lock keeps every queue in the
+    /// same relative order, so two callers that share keys can never wait on each other.
+    pub fn take_turns<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> HashMap<String, Turn> {
+        let mut tails = self.tails.lock().unwrap();
+        let turns = keys
+            .into_iter()
+            .map(|key| {
+                let (release, tail) = oneshot::channel();
+                let previous = tails.insert(key.to_string(), tail);
+                (key.to_string(), Turn { previous, _release: release })
+            })
+            .collect();
+        if tails.len() > SWEEP_ABOVE {
+            // A queue whose last turn is over holds nothing up
+            tails.retain(|_, tail| tail.try_recv() != Err(oneshot::error::TryRecvError::Closed));
+        }
+        turns
+    }
+}
+
+impl Turn {
+    /// Wait until every turn taken before this one on its key is over
+    pub async fn ready(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // Only ever closed, never sent on
+            let _ = previous.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_turns_come_up_in_the_order_taken() {
+        let order = SendOrder::new();
+        let received: Arc<Mutex<Vec<&str>>> = Arc::default();
+        let send = |mut turn: Turn, message: &'static str, delay_ms: u64| {
+            let received = received.clone();
+            async move {
+                turn.ready().await;
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                received.lock().unwrap().push(message);
+            }
+        };
+
+        // The first message's send to conn-1 is slow; the second's would overtake it unordered
+        let first = send(order.take_turn("conn-1"), "first", 50);
+        let second = send(order.take_turn("conn-1"), "second", 0);
+        let other = send(order.take_turn("conn-2"), "other", 0);
+        tokio::join!(second, other, first);
+
+        // conn-2 didn't wait behind conn-1
+        assert_eq!(*received.lock().unwrap(), vec!["other", "first", "second"]);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::VecDeque, env, str::FromStr, sync::Mutex};
+use tokio::sync::Notify;
+
+const DEFAULT_WS_SEND_QUEUE_LEN: usize = 100;
+
+/// What a full connection queue does with one more frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    // Make room by discarding the oldest queued frame
+    #[default]
+    DropOldest,
+    // Discard the incoming frame
+    DropNewest,
+    // Close the connection; the client reconnects and catches up from history
+    Disconnect,
+}
+
+impl DropPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropPolicy::DropOldest => "drop_oldest",
+            DropPolicy::DropNewest => "drop_newest",
+            DropPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "drop_oldest" => Ok(DropPolicy::DropOldest),
+            "drop_newest" => Ok(DropPolicy::DropNewest),
+            "disconnect" => Ok(DropPolicy::Disconnect),
+            other => Err(format!("Unknown drop policy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Enqueued {
+    Queued,
+    // The queue was full and this frame (the oldest or the new one) was discarded
+    Dropped(String),
+    // The queue was full under `Disconnect`, or already closed; nothing was queued
+    Closed,
+}
+
+#[derive(Default)]
+struct QueueState {
+    frames: VecDeque<String>,
+    closed: bool,
+}
+
+/// Bounded outbound frames for one WebSocket connection, so a slow reader costs at most
+/// `WS_SEND_QUEUE_LEN` frames of memory. `WS_DROP_POLICY` decides what happens when it is full.
+pub struct SendQueue {
+    capacity: usize,
+    policy: DropPolicy,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let capacity = env::var("WS_SEND_QUEUE_LEN")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_WS_SEND_QUEUE_LEN);
+        let policy = env::var("WS_DROP_POLICY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default();
+        Self::new(capacity, policy)
+    }
+
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    pub fn push(&self, frame: String) -> Enqueued {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Enqueued::Closed;
+        }
+
+        let outcome = if state.frames.len() < self.capacity {
+            state.frames.push_back(frame);
+            Enqueued::Queued
+        } else {
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    let oldest = state.frames.pop_front().unwrap_or_default();
+                    state.frames.push_back(frame);
+                    Enqueued::Dropped(oldest)
+                }
+                DropPolicy::DropNewest => Enqueued::Dropped(frame),
+                DropPolicy::Disconnect => {
+                    state.closed = true;
+                    state.frames.clear();
+                    Enqueued::Closed
+                }
+            }
+        };
+        drop(state);
+        self.notify.notify_one();
+        outcome
+    }
+
+    /// Next frame to send, or None once the queue is closed. Cancel-safe: a frame is only taken
+    /// from the queue by the poll that returns it.
+    pub async fn pop(&self) -> Option<String> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(frame) = state.frames.pop_front() {
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overfill(policy: DropPolicy) -> (SendQueue, Vec<Enqueued>) {
+        let queue = SendQueue::new(2, policy);
+        let outcomes = ["a", "b", "c"].iter().map(|frame| queue.push(frame.to_string())).collect();
+        (queue, outcomes)
+    }
+
+    async fn drain(queue: &SendQueue) -> Vec<String> {
+        queue.close();
+        let mut frames = Vec::new();
+        while let Some(frame) = queue.pop().await {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_applies_drop_policy() {
+        let (queue, outcomes) = overfill(DropPolicy::DropOldest);
+        assert_eq!(outcomes[2], Enqueued::Dropped("a".to_string()));
+        assert_eq!(drain(&queue).await, vec!["b", "c"]);
+
+        let (queue, outcomes) = overfill(DropPolicy::DropNewest);
+        assert_eq!(outcomes[2], Enqueued::Dropped("c".to_string()));
+        assert_eq!(drain(&queue).await, vec!["a", "b"]);
+
+        let (queue, outcomes) = overfill(DropPolicy::Disconnect);
+        assert_eq!(outcomes, vec![Enqueued::Queued, Enqueued::Queued, Enqueued::Closed]);
+        assert_eq!(queue.pop().await, None);
+        assert_eq!(queue.push("d".to_string()), Enqueued::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits_for_a_frame() {
+        let queue = std::sync::Arc::new(SendQueue::new(4, DropPolicy::default()));
+        let reader = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        tokio::task::yield_now().await;
+        queue.push("hello".to_string());
+        assert_eq!(reader.await.unwrap().as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn test_drop_policy_parses() {
+        assert_eq!("Drop_Newest".parse(), Ok(DropPolicy::DropNewest));
+        assert_eq!("disconnect".parse(), Ok(DropPolicy::Disconnect));
+        assert!("block".parse::<DropPolicy>().is_err());
+    }
+}
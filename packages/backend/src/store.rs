@@ -0,0 +1,3340 @@
+pub use crate::ddb::{CorruptField, FieldError};
+use crate::{
+    clock::{self, Clock},
+    compaction::{self, Compaction, CompactionReport},
+    ddb::{Item, ItemBuilder, ItemReader},
+    encryption::{TextCipher, ENCRYPTED_TEXT_ATTRIBUTE},
+    handlers::{MessageQuery, Tables},
+    identity::display_name_fallback,
+    known_rooms::KnownRooms,
+    origin::MessageOrigin,
+    write_limiter::{WriteLimiter, WriteOutcome},
+    MetricsHelper,
+};
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::transact_write_items::TransactWriteItemsError,
+    primitives::Blob,
+    types::{
+        AttributeValue, Put, ReturnValue, ReturnValuesOnConditionCheckFailure, TransactWriteItem,
+        Update,
+    },
+    Client as DynamoDbClient,
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+use types::{
+    time, ChatMessage, LinkPreview, MessageCore, MessageFlag, MessageFormat, MessageStatus,
+    MessageVisibility, Room, UserProfile,
+};
+
+/// Messages returned per room listing
+pub const MESSAGE_PAGE_SIZE: usize = 25;
+
+/// Largest page a listing may ask for with `limit`
+pub const MAX_MESSAGE_PAGE_SIZE: usize = 100;
+
+/// Zero-padded epoch millis, the leading part of every sort key in that millisecond
+pub fn sort_key_prefix(created_at_millis: i64) -> String {
+    format!("{:013}", created_at_millis)
+}
+
+/// The messages table's sort key, `{ts:013}#{id}`. Unlike `ts` alone it's unique within a
+/// room, so messages from the same millisecond keep a total, stable order across pages.
+/// Messages sent with a `client_seq` get `{ts:013}#{client_seq:020}#{id}` instead, so one
+/// client's messages from the same millisecond sort in the order it numbered them. `ts` still
+/// leads, so the counter never moves a message past one stored in another millisecond.
+pub fn sort_key(created_at_millis: i64, client_seq: Option<u64>, id: &str) -> String {
+    match client_seq {
+        Some(client_seq) => {
+            format!("{}#{:020}#{}", sort_key_prefix(created_at_millis), client_seq, id)
+        }
+        None => format!("{}#{}", sort_key_prefix(created_at_millis), id),
+    }
+}
+
+pub fn message_sort_key(message: &ChatMessage) -> String {
+    sort_key(message.core.created_at.timestamp_millis(), message.client_seq, &message.core.id)
+}
+
+/// GSI on the messages table over (room_id, client_message_id), where a client's retry finds
+/// the message its first attempt stored
+pub const CLIENT_MESSAGE_INDEX: &str = "client-message-index";
+
+/// GSI on the rooms table over (`listing`, `created_at_epoch`), which lists rooms newest first
+/// a page at a time. Every room row carries `listing = ROOM_LISTING`, so they share a
+/// partition; rows from before the index get it from `migrate::backfill_room_listing`.
+pub const ROOMS_BY_CREATED_INDEX: &str = "created-index";
+
+/// The one partition of `ROOMS_BY_CREATED_INDEX`
+pub const ROOM_LISTING: &str = "rooms";
+
+/// Where the room listing is up to: a room's creation time in epoch seconds and its id, which
+/// together order rooms newest first, ties by id
+pub type RoomKey = (i64, String);
+
+/// A page of the room listing, with the key of its last room when there are more after it
+#[derive(Debug, Clone, Default)]
+pub struct RoomPage {
+    pub rooms: Vec<Room>,
+    pub next: Option<RoomKey>,
+}
+
+/// Set on the items claiming a (room, user, `client_message_id`) for the first message sent
+/// with it. The stream filter leaves them out, as they aren't messages.
+pub const CLIENT_CLAIM_ATTRIBUTE: &str = "client_claim";
+
+// How long a claim holds; it only has to outlast the index catching up with its message
+const CLIENT_CLAIM_TTL_SECS: i64 = 24 * 60 * 60;
+
+// Room ids are lowercased, so this partition is never a room's
+fn client_claim_partition(room_id: &str) -> String {
+    format!("CLIENT#{}", room_id)
+}
+
+// NUL separates the two, as user ids may contain anything else
+fn client_claim_sort_key(user_id: &str, client_message_id: &str) -> String {
+    format!("{}\0{}", user_id, client_message_id)
+}
+
+/// The `format` and `format_lang` attributes a message's format is stored as. Plain text
+/// stores neither.
+pub fn format_attributes(format: &MessageFormat) -> Vec<(&'static str, String)> {
+    match format {
+        MessageFormat::Plain => vec![],
+        MessageFormat::Quote => vec![("format", "quote".to_string())],
+        MessageFormat::Code { lang } => {
+            let mut attributes = vec![("format", "code".to_string())];
+            attributes.extend(lang.iter().map(|lang| ("format_lang", lang.clone())));
+            attributes
+        }
+    }
+}
+
+/// The format stored as `format`/`format_lang`, or None for an unknown `format`
+pub fn stored_format(format: Option<&str>, lang: Option<&str>) -> Option<MessageFormat> {
+    match format {
+        None | Some("plain") => Some(MessageFormat::Plain),
+        Some("quote") => Some(MessageFormat::Quote),
+        Some("code") => Some(MessageFormat::Code { lang: lang.map(String::from) }),
+        Some(_) => None,
+    }
+}
+
+/// The visibility stored as `visibility`. Room messages store none; an unknown value (written
+/// by a newer version) is kept to the sender and recipient, like a direct message.
+pub fn stored_visibility(visibility: Option<&str>) -> MessageVisibility {
+    match visibility {
+        None | Some("room") => MessageVisibility::Room,
+        Some(_) => MessageVisibility::Direct,
+    }
+}
+
+/// Record how many connections a message's broadcast reached. Conditional on the message
+/// still being there, so one deleted mid-broadcast isn't brought back as a stub.
+pub async fn record_delivered_count(
+    ddb: &DynamoDbClient,
+    messages_table: &str,
+    message: &ChatMessage,
+    delivered_count: u32,
+) -> Result<(), String> {
+    let result = ddb
+        .update_item()
+        .table_name(messages_table)
+        .key("room_id", AttributeValue::S(message.core.room_id.clone()))
+        .key("sk", AttributeValue::S(message_sort_key(message)))
+        .update_expression("SET delivered_count = :count")
+        .condition_expression("attribute_exists(sk)")
+        .expression_attribute_values(":count", AttributeValue::N(delivered_count.to_string()))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            info!("Message {} is gone, not recording its delivery count", message.core.id);
+            Ok(())
+        }
+        Err(e) => Err(format!("Failed to record delivery count: {:?}", e)),
+    }
+}
+
+/// Whether the message at `sk` was removed by compaction rather than deleted: compaction
+/// removes a run's items newest first once its segment is written, so the next item along is
+/// a segment that starts at or before it.
+pub async fn compacted_into_segment(
+    ddb: &DynamoDbClient,
+    messages_table: &str,
+    room_id: &str,
+    sk: &str,
+) -> Result<bool, String> {
+    let next = ddb
+        .query()
+        .table_name(messages_table)
+        .key_condition_expression("room_id = :room_id AND sk > :sk")
+        .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+        .expression_attribute_values(":sk", AttributeValue::S(sk.to_string()))
+        .limit(1)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to look past removed message: {:?}", e))?;
+    Ok(next.items().first().is_some_and(|item| {
+        compaction::is_segment(item)
+            && ItemReader::new(item)
+                .string(compaction::SEGMENT_FIRST_SK_ATTRIBUTE)
+                .ok()
+                .flatten()
+                .is_some_and(|first| first.as_str() <= sk)
+    }))
+}
+
+/// Attach a link preview to a stored message, as JSON in its `link_preview` attribute. Like
+/// `record_delivered_count`, conditional on the message still being there.
+pub async fn record_link_preview(
+    ddb: &DynamoDbClient,
+    messages_table: &str,
+    message: &ChatMessage,
+    preview: &LinkPreview,
+) -> Result<(), String> {
+    let preview = serde_json::to_string(preview).map_err(|e| e.to_string())?;
+    let result = ddb
+        .update_item()
+        .table_name(messages_table)
+        .key("room_id", AttributeValue::S(message.core.room_id.clone()))
+        .key("sk", AttributeValue::S(message_sort_key(message)))
+        .update_expression("SET link_preview = :preview")
+        .condition_expression("attribute_exists(sk)")
+        .expression_attribute_values(":preview", AttributeValue::S(preview))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.as_service_error().is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            info!("Message {} is gone, not recording its link preview", message.core.id);
+            Ok(())
+        }
+        Err(e) => Err(format!("Failed to record link preview: {:?}", e)),
+    }
+}
+
+/// Why `put_message` stored nothing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PutMessageError {
+    // Another message already has this key
+    Duplicate(String),
+    // The room the message would be counted against has no record
+    RoomMissing(String),
+    // The room is archived, so it takes no new messages
+    RoomArchived(String),
+    // Lost a race with a concurrent write to the same room or message; safe to retry
+    Contended,
+    // DynamoDB is out of write capacity; safe to retry later
+    Throttled,
+    // The sender already has a message with this `client_message_id` in the room: this one,
+    // which won a race to store it
+    ClientMessageTaken(String),
+    Other(String),
+}
+
+impl fmt::Display for PutMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutMessageError::Duplicate(id) => write!(f, "Message {} already exists", id),
+            PutMessageError::RoomMissing(room_id) => write!(f, "Room {} does not exist", room_id),
+            PutMessageError::RoomArchived(room_id) => write!(f, "Room {} is archived", room_id),
+            PutMessageError::Contended => f.write_str("Message write conflicted, please retry"),
+            PutMessageError::Throttled => f.write_str("Message writes are throttled, please retry"),
+            PutMessageError::ClientMessageTaken(id) => {
+                write!(f, "Message {} was already sent with this client_message_id", id)
+            }
+            PutMessageError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+// Tries at a message write that keeps losing races on its room's item before giving up. Every
+// post to a room updates the room, so a busy room contends with itself.
+const CONTENDED_ATTEMPTS: u32 = 4;
+
+// Wait before retrying a contended write; doubled each time, plus up to as much again at
+// random so the racing writers don't collide again in lockstep
+const CONTENDED_BACKOFF: Duration = Duration::from_millis(20);
+
+// `write`, tried again after a jittered backoff while it comes back `Contended`. Still
+// contended after the last try, that's what's returned.
+async fn retry_contended<F, Fut>(mut write: F) -> Result<(), PutMessageError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), PutMessageError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Err(PutMessageError::Contended) if attempt < CONTENDED_ATTEMPTS => {
+                let jitter = uuid::Uuid::new_v4().as_u64_pair().0 as f64 / u64::MAX as f64;
+                let doubled = CONTENDED_BACKOFF * (1 << (attempt - 1));
+                warn!("Message write contended (attempt {}); retrying", attempt);
+                tokio::time::sleep(doubled.mul_f64(1.0 + jitter)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Why the store's health probe failed
+#[derive(Debug, Clone)]
+pub enum ProbeError {
+    // The messages table doesn't exist
+    Missing(String),
+    // It couldn't be reached, or answered with an error
+    Unreachable(String),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Missing(message) | ProbeError::Unreachable(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Why a read from the store failed, by what the caller can do about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    // DynamoDB is out of read capacity; safe to retry later
+    Throttled,
+    // Lost a race with a concurrent write; safe to retry
+    Conflict,
+    Other(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Throttled => f.write_str("Too many requests; please retry"),
+            StoreError::Conflict => f.write_str("Conflicts with a concurrent change; please retry"),
+            StoreError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<String> for StoreError {
+    fn from(message: String) -> Self {
+        StoreError::Other(message)
+    }
+}
+
+impl From<StoreError> for String {
+    fn from(error: StoreError) -> Self {
+        error.to_string()
+    }
+}
+
+// A failed DynamoDB call, by what the service said: out of capacity is the caller's cue to back
+// off, and a refused condition is a clash with existing state. The rest is ours to fix.
+impl<E, R> From<SdkError<E, R>> for StoreError
+where
+    E: ProvideErrorMetadata + fmt::Debug,
+    R: fmt::Debug,
+{
+    fn from(err: SdkError<E, R>) -> Self {
+        match err.as_service_error().and_then(|service| service.code()) {
+            Some(
+                "ProvisionedThroughputExceededException"
+                | "ThrottlingException"
+                | "RequestLimitExceeded"
+                | "LimitExceededException",
+            ) => {
+                warn!("DynamoDB call throttled: {:?}", err);
+                StoreError::Throttled
+            }
+            Some("ConditionalCheckFailedException" | "TransactionConflictException") => {
+                StoreError::Conflict
+            }
+            _ => StoreError::Other(format!("DynamoDB error: {:?}", err)),
+        }
+    }
+}
+
+/// Where rooms and messages live. Handlers only talk to this trait, so the business logic runs
+/// unchanged against DynamoDB in production and in memory in tests.
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// Create the room on first use
+    async fn ensure_room(&self, room_id: &str) -> Result<(), String>;
+
+    /// Store a new message, with its sender's origin if captured, and record it against its
+    /// room. Both happen or neither does; fails if the id is taken or the room was never created.
+    async fn put_message(
+        &self,
+        message: &ChatMessage,
+        origin: Option<&MessageOrigin>,
+    ) -> Result<(), PutMessageError>;
+
+    /// Oldest-first page of the room's unexpired messages within the query's range
+    async fn get_messages(
+        &self,
+        room_id: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<ChatMessage>, StoreError>;
+
+    /// Number of unexpired messages in the room
+    async fn count_messages(&self, room_id: &str) -> Result<u32, String>;
+
+    /// Up to `limit` of the room's newest unexpired messages, newest first
+    async fn latest_messages(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError>;
+
+    /// Up to `limit` of the room's unexpired messages whose text contains `text` exactly (case
+    /// included), newest first. Reads through the whole room, so it's only what search falls
+    /// back to without a search index.
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError>;
+
+    /// A single unexpired message within its room
+    async fn get_message(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<ChatMessage>, StoreError>;
+
+    /// The message the user sent to the room with this `client_message_id`, if it's stored
+    async fn find_client_message(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        client_message_id: &str,
+    ) -> Result<Option<ChatMessage>, String>;
+
+    /// A message looked up by id alone, in any room
+    async fn find_message(&self, message_id: &str) -> Result<Option<ChatMessage>, String>;
+
+    /// The sender origin recorded with a message, for moderators only
+    async fn message_origin(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<MessageOrigin>, String>;
+
+    /// Delete the room's oldest non-ephemeral messages until it is back within `cap`, returning
+    /// how many went
+    async fn enforce_room_cap(&self, room_id: &str, cap: u64) -> Result<usize, String>;
+
+    /// Set the room's topic, or clear it with None. False if the room doesn't exist.
+    async fn set_room_topic(&self, room_id: &str, topic: Option<&str>) -> Result<bool, String>;
+
+    /// Archive the room, closing it to new messages, or reopen it. False if the room doesn't
+    /// exist.
+    async fn set_room_archived(&self, room_id: &str, archived: bool) -> Result<bool, String>;
+
+    /// Every room, or only those still open to new messages. The DynamoDB store scans the whole
+    /// rooms table for this, so it's for batch jobs; requests page with `rooms_page`.
+    async fn list_rooms(&self, include_archived: bool) -> Result<Vec<Room>, String>;
+
+    /// Up to `limit` rooms, newest first, starting after `after`; archived ones only with
+    /// `include_archived`
+    async fn rooms_page(
+        &self,
+        include_archived: bool,
+        after: Option<&RoomKey>,
+        limit: usize,
+    ) -> Result<RoomPage, StoreError>;
+
+    /// What the user has set on their profile, if they have one. `avatar_seed` is only what
+    /// they chose, not defaulted.
+    async fn profile(&self, user_id: &str) -> Result<Option<UserProfile>, String>;
+
+    /// Save a display name to the user's profile, returning the one it replaced
+    async fn set_profile_username(
+        &self,
+        user_id: &str,
+        username: &str,
+    ) -> Result<Option<String>, String>;
+
+    /// Save an avatar seed to the user's profile, or clear it, returning the profile as it
+    /// now stands
+    async fn set_profile_avatar_seed(
+        &self,
+        user_id: &str,
+        avatar_seed: Option<&str>,
+    ) -> Result<UserProfile, String>;
+
+    /// Record a flag on a message and count it against the message, returning how many flags
+    /// it has now. Flags are told apart by `flagger`; None if that flagger had already flagged
+    /// it, which counts nothing.
+    async fn flag_message(
+        &self,
+        message: &ChatMessage,
+        flag: &MessageFlag,
+        flagger: &str,
+    ) -> Result<Option<u32>, String>;
+
+    /// Mark a message Hidden, pending a moderator
+    async fn hide_message(&self, message: &ChatMessage) -> Result<(), String>;
+
+    /// Delete a message, as long as it's still there and still `message.core.user_id`'s, and
+    /// take it off its room's count. False if it was already gone or isn't theirs.
+    async fn delete_message(&self, message: &ChatMessage) -> Result<bool, String>;
+
+    /// Replace a message's text with `message`'s and mark it edited at `message.edited_at`,
+    /// as long as it's still there and still `message.core.user_id`'s. The link preview,
+    /// which was of the old text, is dropped. False if it was gone or isn't theirs.
+    async fn edit_message(&self, message: &ChatMessage) -> Result<bool, String>;
+
+    /// Up to `limit` flags for moderators to review
+    async fn list_flags(&self, limit: usize) -> Result<Vec<MessageFlag>, String>;
+
+    /// What "now" is for this store's reads: expiry checks and page times read it. New
+    /// messages are stamped from the handler's `RequestContext`, which shares it in production.
+    fn clock(&self) -> &dyn Clock;
+
+    /// Rooms recently confirmed to exist, whose `ensure_room` posts may skip
+    fn known_rooms(&self) -> Option<&KnownRooms>;
+
+    /// A cheap look at the messages table, for the health check
+    async fn probe(&self) -> Result<(), ProbeError>;
+}
+
+// Cancellation reasons come back in the order of the transaction's items: the message put,
+// then the room update
+fn put_message_error(
+    message: &ChatMessage,
+    err: SdkError<TransactWriteItemsError>,
+) -> PutMessageError {
+    let cancelled = match err.as_service_error() {
+        Some(TransactWriteItemsError::TransactionCanceledException(cancelled)) => cancelled,
+        Some(
+            TransactWriteItemsError::ProvisionedThroughputExceededException(_)
+            | TransactWriteItemsError::RequestLimitExceeded(_)
+            | TransactWriteItemsError::ThrottlingException(_),
+        ) => return PutMessageError::Throttled,
+        _ => return PutMessageError::Other(format!("DynamoDB error: {:?}", err)),
+    };
+    let reasons = cancelled.cancellation_reasons();
+    let code = |index: usize| reasons.get(index).and_then(|reason| reason.code());
+    match (code(0), code(1)) {
+        (Some("ConditionalCheckFailed"), _) => PutMessageError::Duplicate(message.core.id.clone()),
+        // The room update returns the room it refused, so an archived one can be told apart
+        (_, Some("ConditionalCheckFailed")) => {
+            let archived = reasons[1]
+                .item()
+                .is_some_and(|room| room.get("archived") == Some(&AttributeValue::Bool(true)));
+            if archived {
+                PutMessageError::RoomArchived(message.core.room_id.clone())
+            } else {
+                PutMessageError::RoomMissing(message.core.room_id.clone())
+            }
+        }
+        // The claim on the client_message_id returns the message holding it
+        _ if code(2) == Some("ConditionalCheckFailed") => {
+            let holder = reasons[2].item().and_then(|claim| claim.get("message_id"));
+            match holder.and_then(|id| id.as_s().ok()) {
+                Some(id) => PutMessageError::ClientMessageTaken(id.clone()),
+                None => PutMessageError::Other("Client message claim has no message_id".into()),
+            }
+        }
+        _ if reasons.iter().any(|reason| reason.code() == Some("TransactionConflict")) => {
+            PutMessageError::Contended
+        }
+        _ if reasons.iter().any(|reason| {
+            matches!(reason.code(), Some("ThrottlingError" | "ProvisionedThroughputExceeded"))
+        }) =>
+        {
+            PutMessageError::Throttled
+        }
+        _ => PutMessageError::Other(format!("Message transaction cancelled: {:?}", reasons)),
+    }
+}
+
+// DynamoDB-backed store: rooms keyed by `id`, messages keyed by (room_id, sk) and, when
+// configured, user profiles keyed by `user_id` and message flags keyed by (message_id, user_id)
+#[derive(Clone)]
+pub struct DynamoMessageStore {
+    ddb: DynamoDbClient,
+    tables: Tables,
+    users: Option<String>,
+    flags: Option<String>,
+    clock: Arc<dyn Clock>,
+    known_rooms: Option<Arc<KnownRooms>>,
+    write_limiter: Option<Arc<WriteLimiter>>,
+    cipher: Option<Arc<TextCipher>>,
+}
+
+impl DynamoMessageStore {
+    pub fn new(ddb: DynamoDbClient, tables: Tables) -> Self {
+        Self {
+            ddb,
+            tables,
+            users: None,
+            flags: None,
+            clock: clock::system(),
+            known_rooms: None,
+            write_limiter: None,
+            cipher: None,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Hold message writes to `write_limiter`'s adaptive concurrency limit, if any. Shared like
+    /// `known_rooms`, so the limit carries over between invocations.
+    pub fn with_write_limiter(mut self, write_limiter: Option<Arc<WriteLimiter>>) -> Self {
+        self.write_limiter = write_limiter;
+        self
+    }
+
+    /// Encrypt the text of new messages with `cipher` (ENCRYPT_AT_REST), and open encrypted
+    /// text on the way out. Rows written without it stay plaintext and read as before. The
+    /// table-scanning search fallback can't see into encrypted text.
+    pub fn with_cipher(mut self, cipher: Option<Arc<TextCipher>>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Skip the rooms table read for rooms in `known_rooms`. Shared rather than owned, since
+    /// the Lambdas build a store per invocation and the cache has to outlive it.
+    pub fn with_known_rooms(mut self, known_rooms: Arc<KnownRooms>) -> Self {
+        self.known_rooms = Some(known_rooms);
+        self
+    }
+
+    /// Keep user profiles in `users`. Without one, nobody has a profile and renames fail.
+    pub fn with_users_table(mut self, users: Option<String>) -> Self {
+        self.users = users;
+        self
+    }
+
+    /// Keep message flags in `flags`. Without one, flagging fails and the queue is empty.
+    pub fn with_flags_table(mut self, flags: Option<String>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn tables(&self) -> &Tables {
+        &self.tables
+    }
+
+    // Non-ephemeral messages in the room, as counted by put_message and eviction
+    async fn message_count(&self, room_id: &str) -> Result<i64, String> {
+        let output = self
+            .ddb
+            .get_item()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(room_id.to_string()))
+            .projection_expression("message_count")
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to read message count: {:?}", e))?;
+
+        Ok(output
+            .item()
+            .and_then(|item| item.get("message_count"))
+            .and_then(|value| value.as_n().ok())
+            .and_then(|n| n.parse::<i64>().ok())
+            .unwrap_or(0))
+    }
+
+    // Atomically add `delta` to the room's message_count and return the new value
+    async fn adjust_message_count(&self, room_id: &str, delta: i64) -> Result<i64, String> {
+        let output = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(room_id.to_string()))
+            .update_expression("ADD message_count :delta")
+            .expression_attribute_values(":delta", AttributeValue::N(delta.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to update message count: {:?}", e))?;
+
+        output
+            .attributes()
+            .and_then(|attributes| attributes.get("message_count"))
+            .and_then(|value| value.as_n().ok())
+            .and_then(|n| n.parse::<i64>().ok())
+            .ok_or_else(|| format!("Room {} returned no message_count", room_id))
+    }
+
+    /// Roll the room's old history into segments, as `compaction` says (see `Compaction`).
+    /// Safe to run again after it stops partway, and alongside readers and writers: a segment
+    /// is written before the items it replaces are removed, and reads collapse the copies.
+    ///
+    /// Compacted messages lose what's stored beside them but isn't part of the message (the
+    /// `origin_*` moderation metadata), and can no longer be found by id: `get_message`,
+    /// `find_message` and `message_origin` only look at plain items.
+    pub async fn compact_room(
+        &self,
+        room_id: &str,
+        compaction: &Compaction,
+    ) -> Result<CompactionReport, String> {
+        let mut report = CompactionReport::default();
+        let Some(boundary) = self.compaction_boundary(room_id, compaction.keep_recent).await?
+        else {
+            return Ok(report);
+        };
+
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id AND sk < :boundary")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(":boundary", AttributeValue::S(boundary))
+            .scan_index_forward(true)
+            .into_paginator()
+            .send();
+
+        let now = self.clock.now();
+        // Consecutive messages not yet in a segment, with their sort keys
+        let mut run: Vec<(String, ChatMessage)> = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            for item in page.items() {
+                let Some(AttributeValue::S(sk)) = item.get("sk") else {
+                    continue;
+                };
+                if compaction::is_segment(item) {
+                    report.leftovers_removed +=
+                        self.remove_leftovers(room_id, item, std::mem::take(&mut run)).await?;
+                    continue;
+                }
+                // Ephemeral messages leave through TTL, which a segment can't do for them
+                let compactable =
+                    !item.contains_key("ttl") && !item.contains_key(ENCRYPTED_TEXT_ATTRIBUTE);
+                let parsed = compactable.then(|| parse_message_item(item, room_id, now, None));
+                match parsed {
+                    Some(ParsedItem { message: Some(message), corrupt }) if corrupt.is_empty() => {
+                        run.push((sk.clone(), message));
+                    }
+                    _ => run.clear(),
+                }
+                if run.len() == compaction.segment_size {
+                    self.write_segments(room_id, &std::mem::take(&mut run), &mut report).await?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // Sort key of the `keep_recent`th newest plain message; only items before it are
+    // compacted. None when the room has no more than that.
+    async fn compaction_boundary(
+        &self,
+        room_id: &str,
+        keep_recent: usize,
+    ) -> Result<Option<String>, String> {
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression("attribute_not_exists(#segment)")
+            .expression_attribute_names("#segment", compaction::SEGMENT_ATTRIBUTE)
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .projection_expression("sk")
+            .scan_index_forward(false) // Newest first
+            .into_paginator()
+            .send();
+
+        let mut seen = 0;
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            for item in page.items() {
+                seen += 1;
+                if seen == keep_recent {
+                    return Ok(item.get("sk").and_then(|sk| sk.as_s().ok()).cloned());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn write_segments(
+        &self,
+        room_id: &str,
+        run: &[(String, ChatMessage)],
+        report: &mut CompactionReport,
+    ) -> Result<(), String> {
+        let messages: Vec<ChatMessage> = run.iter().map(|(_, message)| message.clone()).collect();
+        let mut start = 0;
+        for (count, encoded) in compaction::encode_segments(&messages)? {
+            let sort_keys: Vec<&String> =
+                run[start..start + count].iter().map(|(sk, _)| sk).collect();
+            start += count;
+            let (first, last) = (sort_keys[0], sort_keys[sort_keys.len() - 1]);
+            self.put_segment(compaction::segment_item(room_id, first, last, count, encoded))
+                .await?;
+            // Newest first, so what's left is always the oldest of the run with the segment
+            // right after it, and a page read from anywhere in it still comes out whole
+            for sk in sort_keys.into_iter().rev() {
+                self.delete_message_item(room_id, sk).await?;
+            }
+            report.segments_written += 1;
+            report.messages_compacted += count;
+        }
+        Ok(())
+    }
+
+    // Remove the plain items in `run` that `segment` already holds, left by a compaction
+    // that stopped before removing them all; returns how many there were
+    async fn remove_leftovers(
+        &self,
+        room_id: &str,
+        segment: &Item,
+        run: Vec<(String, ChatMessage)>,
+    ) -> Result<usize, String> {
+        let first =
+            ItemReader::new(segment).string(compaction::SEGMENT_FIRST_SK_ATTRIBUTE).ok().flatten();
+        let candidates: Vec<_> =
+            run.into_iter().filter(|(sk, _)| first.is_some_and(|first| sk >= first)).collect();
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+        let held: HashSet<String> =
+            compaction::read_segment(segment)?.into_iter().map(|message| message.core.id).collect();
+        let mut removed = 0;
+        for (sk, message) in candidates {
+            if held.contains(&message.core.id) {
+                self.delete_message_item(room_id, &sk).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Drop a segment's `count` oldest messages, for the room cap
+    async fn trim_segment(
+        &self,
+        room_id: &str,
+        segment: &Item,
+        count: usize,
+    ) -> Result<(), String> {
+        let Some(AttributeValue::S(sk)) = segment.get("sk") else {
+            return Err(format!("Segment in room {} has no sort key", room_id));
+        };
+        let kept = compaction::read_segment(segment)?.split_off(count);
+        let Some(first) = kept.first() else {
+            return self.delete_message_item(room_id, sk).await;
+        };
+        let encoded = compaction::encode_segment(&kept)?;
+        self.put_segment(compaction::segment_item(
+            room_id,
+            &message_sort_key(first),
+            compaction::last_sort_key(sk),
+            kept.len(),
+            encoded,
+        ))
+        .await
+    }
+
+    // Hide a message that's been compacted, in the segment holding it. The first item after a
+    // compacted message's key is its segment, as the rest of the run went with it. False if
+    // no segment holds it.
+    async fn hide_in_segment(&self, message: &ChatMessage) -> Result<bool, String> {
+        let room_id = &message.core.room_id;
+        let result = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id AND sk > :sk")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.clone()))
+            .expression_attribute_values(":sk", AttributeValue::S(message_sort_key(message)))
+            .limit(1)
+            .send()
+            .await
+            .map_err(|e| format!("DynamoDB error: {:?}", e))?;
+        let Some(segment) = result.items().first().filter(|item| compaction::is_segment(item))
+        else {
+            return Ok(false);
+        };
+        let mut messages = compaction::read_segment(segment)?;
+        let Some(hidden) = messages.iter_mut().find(|held| held.core.id == message.core.id) else {
+            return Ok(false);
+        };
+        hidden.status = MessageStatus::Hidden;
+
+        let mut segment = segment.clone();
+        segment.insert(
+            compaction::SEGMENT_ATTRIBUTE.to_string(),
+            AttributeValue::B(Blob::new(compaction::encode_segment(&messages)?)),
+        );
+        self.put_segment(segment).await?;
+        Ok(true)
+    }
+
+    async fn put_segment(&self, segment: Item) -> Result<(), String> {
+        self.ddb
+            .put_item()
+            .table_name(&self.tables.messages)
+            .set_item(Some(segment))
+            .send()
+            .await
+            .map(drop)
+            .map_err(|e| format!("Failed to write segment: {:?}", e))
+    }
+
+    async fn delete_message_item(&self, room_id: &str, sk: &str) -> Result<(), String> {
+        self.ddb
+            .delete_item()
+            .table_name(&self.tables.messages)
+            .key("room_id", AttributeValue::S(room_id.to_string()))
+            .key("sk", AttributeValue::S(sk.to_string()))
+            .send()
+            .await
+            .map(drop)
+            .map_err(|e| format!("Failed to delete message item: {:?}", e))
+    }
+}
+
+#[async_trait]
+impl MessageStore for DynamoMessageStore {
+    async fn ensure_room(&self, room_id: &str) -> Result<(), String> {
+        let get_item_result = self
+            .ddb
+            .get_item()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(room_id.to_string()))
+            .send()
+            .await;
+
+        match get_item_result {
+            Ok(output) => {
+                if output.item.is_none() {
+                    // Room doesn't exist, create it
+                    let now = self.clock.now();
+                    let room_name = room_name(room_id);
+
+                    let mut item = HashMap::new();
+                    item.insert("id".to_string(), AttributeValue::S(room_id.to_string()));
+                    item.insert("name".to_string(), AttributeValue::S(room_name));
+                    item.insert("created_at_iso".to_string(), AttributeValue::S(now.to_rfc3339()));
+                    item.insert(
+                        "created_at_epoch".to_string(),
+                        AttributeValue::N(now.timestamp().to_string()),
+                    );
+                    // With created_at_epoch, the keys that put it in the room listing
+                    item.insert("listing".to_string(), AttributeValue::S(ROOM_LISTING.to_string()));
+
+                    self.ddb
+                        .put_item()
+                        .table_name(&self.tables.rooms)
+                        .set_item(Some(item))
+                        .condition_expression("attribute_not_exists(id)")
+                        .send()
+                        .await
+                        .map_err(|e| format!("Failed to create room: {:?}", e))?;
+
+                    info!("Created new room: {}", room_id);
+                }
+                Ok(())
+            }
+            Err(e) => Err(format!("DynamoDB error: {:?}", e)),
+        }
+    }
+
+    async fn put_message(
+        &self,
+        message: &ChatMessage,
+        origin: Option<&MessageOrigin>,
+    ) -> Result<(), PutMessageError> {
+        let core = &message.core;
+        let (text_attribute, text) = match &self.cipher {
+            Some(cipher) => (
+                ENCRYPTED_TEXT_ATTRIBUTE,
+                cipher
+                    .encrypt(&core.id, &core.message_text)
+                    .await
+                    .map_err(PutMessageError::Other)?,
+            ),
+            None => ("message_text", core.message_text.clone()),
+        };
+        let mut item = ItemBuilder::new()
+            .string("id", &core.id)
+            .string("room_id", &core.room_id)
+            .string("user_id", &core.user_id)
+            .string("username", &core.username)
+            .string(text_attribute, text)
+            .string("sk", message_sort_key(message))
+            .number("ts", core.created_at.timestamp_millis())
+            .string("created_at_iso", core.created_at.to_rfc3339())
+            .optional_string("client_message_id", message.client_message_id.as_deref())
+            .optional_string("avatar_seed", message.avatar_seed.as_deref())
+            .optional_number(
+                "edited_at",
+                message.edited_at.map(|edited_at| edited_at.timestamp_millis()),
+            )
+            .optional_number(
+                "client_ts",
+                message
+                    .client_created_at
+                    .map(|client_created_at| client_created_at.timestamp_millis()),
+            )
+            .optional_number("clock_skew_ms", message.clock_skew_ms)
+            .optional_number(
+                "client_seq",
+                message.client_seq.and_then(|seq| i64::try_from(seq).ok()),
+            );
+
+        // Self-destructing messages are removed by the table's TTL
+        if let Some(expires_at) = message.expires_at {
+            item = item.number("ttl", expires_at.timestamp()).bool("ephemeral", message.ephemeral);
+        }
+
+        for (name, value) in format_attributes(&message.format) {
+            item = item.string(name, value);
+        }
+
+        if message.visibility == MessageVisibility::Direct {
+            item = item
+                .string("visibility", "direct")
+                .optional_string("to_user_id", message.to_user_id.as_deref());
+        }
+
+        // Moderation metadata; parse_message_item never reads these back
+        if let Some(origin) = origin {
+            item = item
+                .string("origin_hash", &origin.hash)
+                .optional_string("origin_country", origin.country.as_deref());
+        }
+
+        let put = Put::builder()
+            .table_name(&self.tables.messages)
+            .set_item(Some(item.build()))
+            .condition_expression("attribute_not_exists(id)")
+            .build()
+            .map_err(|e| PutMessageError::Other(format!("Invalid message put: {:?}", e)))?;
+
+        // Ephemeral messages leave through TTL, so they stay out of the room's message_count
+        let ts = AttributeValue::N(message.core.created_at.timestamp_millis().to_string());
+        let room_update = Update::builder()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(message.core.room_id.clone()))
+            .condition_expression(
+                "attribute_exists(id) AND (attribute_not_exists(archived) OR archived = :open)",
+            )
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .expression_attribute_values(":open", AttributeValue::Bool(false))
+            .expression_attribute_values(":ts", ts);
+        let room_update = match message.expires_at {
+            Some(_) => room_update.update_expression("SET last_message_at = :ts"),
+            None => room_update
+                .update_expression("SET last_message_at = :ts ADD message_count :one")
+                .expression_attribute_values(":one", AttributeValue::N("1".to_string())),
+        }
+        .build()
+        .map_err(|e| PutMessageError::Other(format!("Invalid room update: {:?}", e)))?;
+
+        // Two posts racing with the same client_message_id both miss the index, so this is
+        // what stops the second
+        let claim = message
+            .client_message_id
+            .as_deref()
+            .map(|client_message_id| {
+                let claim = ItemBuilder::new()
+                    .string("room_id", client_claim_partition(&core.room_id))
+                    .string("sk", client_claim_sort_key(&core.user_id, client_message_id))
+                    .string("message_id", &core.id)
+                    .bool(CLIENT_CLAIM_ATTRIBUTE, true)
+                    .number("ttl", core.created_at.timestamp() + CLIENT_CLAIM_TTL_SECS);
+                Put::builder()
+                    .table_name(&self.tables.messages)
+                    .set_item(Some(claim.build()))
+                    .condition_expression("attribute_not_exists(sk)")
+                    .return_values_on_condition_check_failure(
+                        ReturnValuesOnConditionCheckFailure::AllOld,
+                    )
+                    .build()
+            })
+            .transpose()
+            .map_err(|e| {
+                PutMessageError::Other(format!("Invalid client message claim: {:?}", e))
+            })?;
+
+        let permit = match &self.write_limiter {
+            Some(limiter) => Some(limiter.acquire().await),
+            None => None,
+        };
+        let mut transaction = self
+            .ddb
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().put(put).build())
+            .transact_items(TransactWriteItem::builder().update(room_update).build());
+        if let Some(claim) = claim {
+            transaction =
+                transaction.transact_items(TransactWriteItem::builder().put(claim).build());
+        }
+        let result =
+            retry_contended(|| {
+                let transaction = transaction.clone();
+                async move {
+                    transaction.send().await.map(drop).map_err(|e| put_message_error(message, e))
+                }
+            })
+            .await;
+        if let Some(permit) = permit {
+            let outcome = match &result {
+                Ok(()) => WriteOutcome::Succeeded,
+                Err(PutMessageError::Throttled) => WriteOutcome::Throttled,
+                Err(_) => WriteOutcome::Failed,
+            };
+            permit.finish(outcome).await;
+        }
+        result
+    }
+
+    async fn get_messages(
+        &self,
+        room_id: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        let (key_condition, bounds) = query.key_condition();
+        let hi =
+            bounds.iter().find(|(placeholder, _)| *placeholder == ":hi").map(|(_, hi)| hi.clone());
+
+        let mut request = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression(key_condition)
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()));
+        for (placeholder, value) in bounds {
+            request = request.expression_attribute_values(placeholder, AttributeValue::S(value));
+        }
+        let fields = query.fields()?;
+        if let Some(fields) = &fields {
+            // Placeholders throughout, as `ttl` and `format` are reserved words
+            let attributes = projected_attributes(fields);
+            let placeholders: Vec<String> =
+                (0..attributes.len()).map(|i| format!("#p{}", i)).collect();
+            request = request.projection_expression(placeholders.join(", "));
+            for (placeholder, attribute) in placeholders.into_iter().zip(attributes) {
+                request = request.expression_attribute_names(placeholder, attribute);
+            }
+        }
+
+        // Oldest first, unless paging back from `before`
+        let page_size = query.page_size();
+        let request = request.scan_index_forward(!query.newest_first()).limit(page_size as i32);
+
+        // A segment read from partway in, or expired rows, can leave a page of items short of
+        // a page of messages, so read on until it's full or the range runs out
+        let now = self.clock.now();
+        let mut messages = Vec::new();
+        let mut start_key = None;
+        loop {
+            let result = request
+                .clone()
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(StoreError::from)?;
+            messages.extend(page_of(
+                query,
+                messages_from_items(
+                    result.items(),
+                    room_id,
+                    now,
+                    fields.as_deref(),
+                    self.cipher.as_deref(),
+                )
+                .await,
+            ));
+            start_key = result.last_evaluated_key;
+            if messages.len() >= page_size || start_key.is_none() {
+                break;
+            }
+        }
+
+        // A segment's key is its newest message's, so one holding the end of the range can
+        // sort just past `hi`; only ever the first item after it. Read newest first, that end
+        // of the range is where the page is.
+        if let (Some(hi), true) = (hi, query.newest_first() || messages.len() < page_size) {
+            let result = self
+                .ddb
+                .query()
+                .table_name(&self.tables.messages)
+                .key_condition_expression("room_id = :room_id AND sk > :hi")
+                .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+                .expression_attribute_values(":hi", AttributeValue::S(hi))
+                .scan_index_forward(true)
+                .limit(1)
+                .send()
+                .await
+                .map_err(StoreError::from)?;
+            let straddling: Vec<Item> = result
+                .items()
+                .iter()
+                .filter(|item| compaction::is_segment(item))
+                .cloned()
+                .collect();
+            messages.extend(page_of(
+                query,
+                messages_from_items(&straddling, room_id, now, None, None).await,
+            ));
+        }
+        Ok(page_of(query, messages))
+    }
+
+    async fn count_messages(&self, room_id: &str) -> Result<u32, String> {
+        // TTL deletion lags, so leave out rows that have expired but are still in the table. A
+        // segment counts for the messages in it.
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression("attribute_not_exists(#ttl) OR #ttl > :now")
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(
+                ":now",
+                AttributeValue::N(self.clock.now().timestamp().to_string()),
+            )
+            .projection_expression(compaction::SEGMENT_COUNT_ATTRIBUTE)
+            .into_paginator()
+            .send();
+
+        let mut count = 0;
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            for item in page.items() {
+                let in_item = ItemReader::new(item)
+                    .number(compaction::SEGMENT_COUNT_ATTRIBUTE)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(1);
+                count += in_item.max(0) as u32;
+            }
+        }
+        Ok(count)
+    }
+
+    async fn latest_messages(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        let result = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .scan_index_forward(false) // Newest first
+            .limit(limit as i32)
+            .send()
+            .await
+            .map_err(StoreError::from)?;
+
+        let now = self.clock.now();
+        let messages =
+            messages_from_items(result.items(), room_id, now, None, self.cipher.as_deref()).await;
+        let mut messages = in_order(messages, true);
+        messages.truncate(limit);
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        let mut query = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression(search_filter(self.cipher.is_some()))
+            .expression_attribute_names("#segment", compaction::SEGMENT_ATTRIBUTE);
+        if self.cipher.is_some() {
+            query = query.expression_attribute_names("#encrypted", ENCRYPTED_TEXT_ATTRIBUTE);
+        }
+        let mut pages = query
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(":text", AttributeValue::S(text.to_string()))
+            .scan_index_forward(false) // Newest first
+            .into_paginator()
+            .send();
+
+        let now = self.clock.now();
+        let mut hits = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(StoreError::from)?;
+            let messages =
+                messages_from_items(page.items(), room_id, now, None, self.cipher.as_deref()).await;
+            hits.extend(
+                in_order(messages, true)
+                    .into_iter()
+                    .filter(|message| message.core.message_text.contains(text)),
+            );
+            if hits.len() >= limit {
+                break;
+            }
+        }
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    async fn get_message(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<ChatMessage>, StoreError> {
+        // The table is keyed by (room_id, ts), so find the id within the room's partition
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression("id = :id")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(":id", AttributeValue::S(message_id.to_string()))
+            .into_paginator()
+            .send();
+
+        let now = self.clock.now();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(StoreError::from)?;
+            if let Some(message) =
+                messages_from_items(page.items(), room_id, now, None, self.cipher.as_deref())
+                    .await
+                    .into_iter()
+                    .next()
+            {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn find_client_message(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        client_message_id: &str,
+    ) -> Result<Option<ChatMessage>, String> {
+        // Other users may have picked the same client_message_id; only the sender's counts
+        let output = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .index_name(CLIENT_MESSAGE_INDEX)
+            .key_condition_expression(
+                "room_id = :room_id AND client_message_id = :client_message_id",
+            )
+            .filter_expression("user_id = :user_id")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(
+                ":client_message_id",
+                AttributeValue::S(client_message_id.to_string()),
+            )
+            .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+            .send()
+            .await
+            .map_err(|e| format!("DynamoDB error: {:?}", e))?;
+
+        let now = self.clock.now();
+        let messages =
+            messages_from_items(output.items(), room_id, now, None, self.cipher.as_deref()).await;
+        Ok(messages.into_iter().next())
+    }
+
+    async fn find_message(&self, message_id: &str) -> Result<Option<ChatMessage>, String> {
+        // There is no index on `id`, so this scans; it backs admin tooling, not the request path
+        let mut pages = self
+            .ddb
+            .scan()
+            .table_name(&self.tables.messages)
+            .filter_expression("id = :id")
+            .expression_attribute_values(":id", AttributeValue::S(message_id.to_string()))
+            .into_paginator()
+            .send();
+
+        let now = self.clock.now();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            for item in page.items() {
+                // room_id is the partition key, so it's always a string
+                let Some(AttributeValue::S(room_id)) = item.get("room_id") else {
+                    continue;
+                };
+                let found = messages_from_items(
+                    std::slice::from_ref(item),
+                    room_id,
+                    now,
+                    None,
+                    self.cipher.as_deref(),
+                )
+                .await;
+                if let Some(message) = found.into_iter().next() {
+                    return Ok(Some(message));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn message_origin(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<MessageOrigin>, String> {
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression("id = :id")
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .expression_attribute_values(":id", AttributeValue::S(message_id.to_string()))
+            .projection_expression("origin_hash, origin_country")
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            if let Some(item) = page.items().first() {
+                let hash = item.get("origin_hash").and_then(|v| v.as_s().ok()).cloned();
+                let country = item.get("origin_country").and_then(|v| v.as_s().ok()).cloned();
+                return Ok(hash.map(|hash| MessageOrigin { hash, country }));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn enforce_room_cap(&self, room_id: &str, cap: u64) -> Result<usize, String> {
+        let message_count = self.message_count(room_id).await?;
+        let excess = (message_count - cap as i64).max(0) as usize;
+        if excess == 0 {
+            return Ok(0);
+        }
+
+        // Limit applies before the filter, so page through until enough rows turn up
+        let mut pages = self
+            .ddb
+            .query()
+            .table_name(&self.tables.messages)
+            .key_condition_expression("room_id = :room_id")
+            .filter_expression("attribute_not_exists(#ttl)")
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_names("#segment", compaction::SEGMENT_ATTRIBUTE)
+            .expression_attribute_values(":room_id", AttributeValue::S(room_id.to_string()))
+            .projection_expression("room_id, sk, #segment, segment_count")
+            .scan_index_forward(true)
+            .limit(excess as i32)
+            .into_paginator()
+            .send();
+
+        // The oldest items, with how many messages each holds: one, or a segment's worth
+        let mut oldest = Vec::new();
+        let mut held = 0;
+        'pages: while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            for item in page.items() {
+                if held >= excess {
+                    break 'pages;
+                }
+                let count = ItemReader::new(item)
+                    .number(compaction::SEGMENT_COUNT_ATTRIBUTE)
+                    .ok()
+                    .flatten()
+                    .map_or(1, |count| count.max(1) as usize);
+                held += count;
+                oldest.push((item.clone(), count));
+            }
+        }
+
+        let mut evicted = 0;
+        for (item, count) in &oldest {
+            let Some(sk) = item.get("sk") else {
+                continue;
+            };
+            let over = excess - evicted;
+            if compaction::is_segment(item) && *count > over {
+                // Only the segment's oldest messages are over the cap
+                self.trim_segment(room_id, item, over).await?;
+                evicted += over;
+                continue;
+            }
+            self.ddb
+                .delete_item()
+                .table_name(&self.tables.messages)
+                .key("room_id", AttributeValue::S(room_id.to_string()))
+                .key("sk", sk.clone())
+                .send()
+                .await
+                .map_err(|e| format!("Failed to evict message: {:?}", e))?;
+            evicted += *count;
+        }
+
+        if evicted > 0 {
+            self.adjust_message_count(room_id, -(evicted as i64)).await?;
+        }
+        Ok(evicted)
+    }
+
+    async fn set_room_topic(&self, room_id: &str, topic: Option<&str>) -> Result<bool, String> {
+        let update = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(room_id.to_string()))
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":now", AttributeValue::S(self.clock.now().to_rfc3339()));
+        let update = match topic {
+            Some(topic) => update
+                .update_expression("SET topic = :topic, topic_updated_at_iso = :now")
+                .expression_attribute_values(":topic", AttributeValue::S(topic.to_string())),
+            None => update.update_expression("SET topic_updated_at_iso = :now REMOVE topic"),
+        };
+
+        match update.send().await {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(format!("Failed to set topic of room {}: {:?}", room_id, e)),
+        }
+    }
+
+    async fn set_room_archived(&self, room_id: &str, archived: bool) -> Result<bool, String> {
+        let result = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.rooms)
+            .key("id", AttributeValue::S(room_id.to_string()))
+            .condition_expression("attribute_exists(id)")
+            .update_expression("SET archived = :archived")
+            .expression_attribute_values(":archived", AttributeValue::Bool(archived))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(format!("Failed to archive room {}: {:?}", room_id, e)),
+        }
+    }
+
+    async fn list_rooms(&self, include_archived: bool) -> Result<Vec<Room>, String> {
+        let mut pages = self.ddb.scan().table_name(&self.tables.rooms).into_paginator().send();
+
+        let mut rooms = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            rooms.extend(
+                page.items()
+                    .iter()
+                    .filter_map(parse_room_item)
+                    .filter(|room| include_archived || !room.archived),
+            );
+        }
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(rooms)
+    }
+
+    async fn rooms_page(
+        &self,
+        include_archived: bool,
+        after: Option<&RoomKey>,
+        limit: usize,
+    ) -> Result<RoomPage, StoreError> {
+        let mut start_key = after.map(|(created_at_epoch, id)| {
+            HashMap::from([
+                ("id".to_string(), AttributeValue::S(id.clone())),
+                ("listing".to_string(), AttributeValue::S(ROOM_LISTING.to_string())),
+                ("created_at_epoch".to_string(), AttributeValue::N(created_at_epoch.to_string())),
+            ])
+        });
+
+        // One room past the page says whether there are more. The archived filter applies
+        // after `Limit`, so a page of the index can come back short and the next one is read.
+        let mut rooms: Vec<(RoomKey, Room)> = Vec::new();
+        loop {
+            let mut query = self
+                .ddb
+                .query()
+                .table_name(&self.tables.rooms)
+                .index_name(ROOMS_BY_CREATED_INDEX)
+                .key_condition_expression("listing = :listing")
+                .expression_attribute_values(
+                    ":listing",
+                    AttributeValue::S(ROOM_LISTING.to_string()),
+                )
+                .scan_index_forward(false)
+                .limit((limit + 1 - rooms.len()) as i32)
+                .set_exclusive_start_key(start_key.take());
+            if !include_archived {
+                query = query
+                    .filter_expression("attribute_not_exists(archived) OR archived = :open")
+                    .expression_attribute_values(":open", AttributeValue::Bool(false));
+            }
+            let output = query.send().await.map_err(StoreError::from)?;
+
+            rooms.extend(output.items().iter().filter_map(|item| {
+                let created_at_epoch =
+                    ItemReader::new(item).number("created_at_epoch").ok().flatten()?;
+                let room = parse_room_item(item)?;
+                Some(((created_at_epoch, room.id.clone()), room))
+            }));
+            start_key = output.last_evaluated_key().cloned();
+            if rooms.len() > limit || start_key.is_none() {
+                break;
+            }
+        }
+
+        let more = rooms.len() > limit;
+        rooms.truncate(limit);
+        let next = more.then(|| rooms.last().map(|(key, _)| key.clone())).flatten();
+        Ok(RoomPage { rooms: rooms.into_iter().map(|(_, room)| room).collect(), next })
+    }
+
+    async fn profile(&self, user_id: &str) -> Result<Option<UserProfile>, String> {
+        let Some(users) = &self.users else {
+            return Ok(None);
+        };
+        let output = self
+            .ddb
+            .get_item()
+            .table_name(users)
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .projection_expression("username, avatar_seed")
+            .send()
+            .await
+            .map_err(|e| format!("Failed to read profile of {}: {:?}", user_id, e))?;
+
+        Ok(output.item().and_then(|item| parse_profile_item(user_id, item)))
+    }
+
+    async fn set_profile_username(
+        &self,
+        user_id: &str,
+        username: &str,
+    ) -> Result<Option<String>, String> {
+        let users = self.users.as_ref().ok_or("User profiles are not configured")?;
+        let output = self
+            .ddb
+            .update_item()
+            .table_name(users)
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .update_expression("SET username = :username, updated_at_iso = :now")
+            .expression_attribute_values(":username", AttributeValue::S(username.to_string()))
+            .expression_attribute_values(":now", AttributeValue::S(self.clock.now().to_rfc3339()))
+            .return_values(ReturnValue::UpdatedOld)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to update profile of {}: {:?}", user_id, e))?;
+
+        Ok(output
+            .attributes()
+            .and_then(|attributes| attributes.get("username"))
+            .and_then(|value| value.as_s().ok())
+            .cloned())
+    }
+
+    async fn set_profile_avatar_seed(
+        &self,
+        user_id: &str,
+        avatar_seed: Option<&str>,
+    ) -> Result<UserProfile, String> {
+        let users = self.users.as_ref().ok_or("User profiles are not configured")?;
+        let update = self
+            .ddb
+            .update_item()
+            .table_name(users)
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .expression_attribute_values(":now", AttributeValue::S(self.clock.now().to_rfc3339()))
+            .return_values(ReturnValue::AllNew);
+        let update = match avatar_seed {
+            Some(avatar_seed) => update
+                .update_expression("SET avatar_seed = :seed, updated_at_iso = :now")
+                .expression_attribute_values(":seed", AttributeValue::S(avatar_seed.to_string())),
+            None => update.update_expression("SET updated_at_iso = :now REMOVE avatar_seed"),
+        };
+        let output = update
+            .send()
+            .await
+            .map_err(|e| format!("Failed to update profile of {}: {:?}", user_id, e))?;
+
+        Ok(output.attributes().and_then(|item| parse_profile_item(user_id, item)).unwrap_or(
+            UserProfile {
+                user_id: user_id.to_string(),
+                username: None,
+                avatar_seed: avatar_seed.map(str::to_string),
+            },
+        ))
+    }
+
+    async fn flag_message(
+        &self,
+        message: &ChatMessage,
+        flag: &MessageFlag,
+        flagger: &str,
+    ) -> Result<Option<u32>, String> {
+        let flags = self.flags.as_ref().ok_or("Message flags are not configured")?;
+        let item = ItemBuilder::new()
+            .string("message_id", &flag.message_id)
+            .string("flagger", flagger)
+            .string("user_id", &flag.user_id)
+            .string("room_id", &flag.room_id)
+            .string("reason", &flag.reason)
+            .string("created_at_iso", flag.created_at.to_rfc3339())
+            .build();
+        let result = self
+            .ddb
+            .put_item()
+            .table_name(flags)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(flagger)")
+            .send()
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(format!("Failed to record flag: {:?}", e)),
+        }
+
+        // The flag is recorded first, so a retry after a failure here is refused as a repeat
+        // rather than counted twice
+        let output = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.messages)
+            .key("room_id", AttributeValue::S(message.core.room_id.clone()))
+            .key("sk", AttributeValue::S(message_sort_key(message)))
+            .update_expression("ADD flag_count :one")
+            .condition_expression("attribute_exists(sk)")
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to count flag on message {}: {:?}", message.core.id, e))?;
+        let count = output
+            .attributes()
+            .map(ItemReader::new)
+            .and_then(|row| row.number("flag_count").ok().flatten())
+            .and_then(|count| u32::try_from(count).ok())
+            .ok_or("DynamoDB returned no flag count")?;
+        Ok(Some(count))
+    }
+
+    async fn hide_message(&self, message: &ChatMessage) -> Result<(), String> {
+        let result = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.messages)
+            .key("room_id", AttributeValue::S(message.core.room_id.clone()))
+            .key("sk", AttributeValue::S(message_sort_key(message)))
+            .update_expression("SET hidden = :hidden")
+            .condition_expression("attribute_exists(sk)")
+            .expression_attribute_values(":hidden", AttributeValue::Bool(true))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                if !self.hide_in_segment(message).await? {
+                    info!("Message {} is gone, not hiding it", message.core.id);
+                }
+                Ok(())
+            }
+            Err(e) => Err(format!("Failed to hide message {}: {:?}", message.core.id, e)),
+        }
+    }
+
+    async fn delete_message(&self, message: &ChatMessage) -> Result<bool, String> {
+        let room_id = &message.core.room_id;
+        let result = self
+            .ddb
+            .delete_item()
+            .table_name(&self.tables.messages)
+            .key("room_id", AttributeValue::S(room_id.clone()))
+            .key("sk", AttributeValue::S(message_sort_key(message)))
+            .condition_expression("attribute_exists(id) AND user_id = :user_id")
+            .expression_attribute_values(
+                ":user_id",
+                AttributeValue::S(message.core.user_id.clone()),
+            )
+            .send()
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                return Ok(false);
+            }
+            Err(e) => return Err(format!("Failed to delete message {}: {:?}", message.core.id, e)),
+        }
+
+        // Ephemeral messages were never counted
+        if message.expires_at.is_none() {
+            self.adjust_message_count(room_id, -1).await?;
+        }
+        Ok(true)
+    }
+
+    async fn edit_message(&self, message: &ChatMessage) -> Result<bool, String> {
+        let core = &message.core;
+        let edited_at = message.edited_at.ok_or("An edited message needs its edit time")?;
+        // Stored the way put_message would store it now, sealed or not
+        let (text_attribute, text, update) = match &self.cipher {
+            Some(cipher) => (
+                ENCRYPTED_TEXT_ATTRIBUTE,
+                cipher.encrypt(&core.id, &core.message_text).await?,
+                "SET #text = :text, edited_at = :edited_at REMOVE message_text, link_preview",
+            ),
+            None => (
+                "message_text",
+                core.message_text.clone(),
+                "SET #text = :text, edited_at = :edited_at REMOVE link_preview",
+            ),
+        };
+        let result = self
+            .ddb
+            .update_item()
+            .table_name(&self.tables.messages)
+            .key("room_id", AttributeValue::S(core.room_id.clone()))
+            .key("sk", AttributeValue::S(message_sort_key(message)))
+            .update_expression(update)
+            .condition_expression("attribute_exists(id) AND user_id = :user_id")
+            .expression_attribute_names("#text", text_attribute)
+            .expression_attribute_values(":text", AttributeValue::S(text))
+            .expression_attribute_values(
+                ":edited_at",
+                AttributeValue::N(edited_at.timestamp_millis().to_string()),
+            )
+            .expression_attribute_values(":user_id", AttributeValue::S(core.user_id.clone()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(format!("Failed to edit message {}: {:?}", core.id, e)),
+        }
+    }
+
+    async fn list_flags(&self, limit: usize) -> Result<Vec<MessageFlag>, String> {
+        let Some(flags) = &self.flags else {
+            return Ok(Vec::new());
+        };
+        let mut pages = self.ddb.scan().table_name(flags).into_paginator().send();
+
+        let mut listed = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| format!("DynamoDB error: {:?}", e))?;
+            listed.extend(page.items().iter().filter_map(parse_flag_item));
+            if listed.len() >= limit {
+                break;
+            }
+        }
+        listed.truncate(limit);
+        Ok(listed)
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn known_rooms(&self) -> Option<&KnownRooms> {
+        self.known_rooms.as_deref()
+    }
+
+    async fn probe(&self) -> Result<(), ProbeError> {
+        let table = &self.tables.messages;
+        match self.ddb.describe_table().table_name(table).send().await {
+            Ok(_) => Ok(()),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_resource_not_found_exception()) => {
+                Err(ProbeError::Missing(format!("Table '{}' does not exist", table)))
+            }
+            Err(e) => Err(ProbeError::Unreachable(format!(
+                "Failed to describe table '{}': {:?}",
+                table, e
+            ))),
+        }
+    }
+}
+
+/// The display name a new room is created with
+pub fn room_name(room_id: &str) -> String {
+    if room_id == "general" {
+        "General".to_string()
+    } else {
+        room_id.to_string()
+    }
+}
+
+// Room rows without an id or creation time are skipped
+fn parse_room_item(item: &Item) -> Option<Room> {
+    let row = ItemReader::new(item);
+    let id = row.string("id").ok().flatten()?.clone();
+    let created_at = row
+        .string("created_at_iso")
+        .ok()
+        .flatten()
+        .and_then(|iso| DateTime::parse_from_rfc3339(iso).ok())?
+        .with_timezone(&Utc);
+    Some(Room {
+        name: row.string("name").ok().flatten().cloned().unwrap_or_else(|| room_name(&id)),
+        created_at,
+        topic: row.string("topic").ok().flatten().cloned(),
+        archived: row.bool("archived").ok().flatten().unwrap_or(false),
+        id,
+    })
+}
+
+// Flag rows missing an attribute are skipped
+fn parse_flag_item(item: &Item) -> Option<MessageFlag> {
+    let row = ItemReader::new(item);
+    let string = |field| row.string(field).ok().flatten().cloned();
+    let created_at = string("created_at_iso")
+        .and_then(|iso| DateTime::parse_from_rfc3339(&iso).ok())?
+        .with_timezone(&Utc);
+    Some(MessageFlag {
+        message_id: string("message_id")?,
+        room_id: string("room_id")?,
+        user_id: string("user_id")?,
+        reason: string("reason")?,
+        created_at,
+    })
+}
+
+static CORRUPT_ITEMS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many message rows in this process had attributes that couldn't be used
+pub fn corrupt_items() -> usize {
+    CORRUPT_ITEMS.load(Ordering::SeqCst)
+}
+
+// A message row as read: the message, unless it has expired or lost a field it can't do
+// without, and every attribute that had to be defaulted or ignored along the way
+#[derive(Debug)]
+struct ParsedItem {
+    message: Option<ChatMessage>,
+    corrupt: Vec<CorruptField>,
+}
+
+// A field the message needs: recorded as corrupt when missing or unusable
+fn required<T>(
+    corrupt: &mut Vec<CorruptField>,
+    field: &'static str,
+    value: Result<Option<T>, CorruptField>,
+) -> Option<T> {
+    match value {
+        Ok(Some(value)) => Some(value),
+        Ok(None) => {
+            corrupt.push(CorruptField { field, error: FieldError::Missing });
+            None
+        }
+        Err(e) => {
+            corrupt.push(e);
+            None
+        }
+    }
+}
+
+// A field that may legitimately be absent: recorded as corrupt only when unusable
+fn optional<T>(
+    corrupt: &mut Vec<CorruptField>,
+    value: Result<Option<T>, CorruptField>,
+) -> Option<T> {
+    value.unwrap_or_else(|e| {
+        corrupt.push(e);
+        None
+    })
+}
+
+// Stored attributes a `fields` projection reads. The id, creation time, client counter and
+// TTL are always fetched, since pages are ordered, cursored and expired by them, and so are
+// the sender, visibility and recipient reads are filtered on and `hidden`, which reads blank
+// the text of. Segments come back whole.
+fn projected_attributes(fields: &[&str]) -> Vec<&'static str> {
+    let mut attributes = vec![
+        "id",
+        "ts",
+        "created_at_iso",
+        "client_seq",
+        "ttl",
+        "user_id",
+        "visibility",
+        "to_user_id",
+        "hidden",
+        compaction::SEGMENT_ATTRIBUTE,
+    ];
+    for field in fields {
+        let stored: &[&'static str] = match *field {
+            "username" => &["username"],
+            "message_text" => &["message_text", ENCRYPTED_TEXT_ATTRIBUTE],
+            "client_message_id" => &["client_message_id"],
+            "ephemeral" => &["ephemeral"],
+            "format" => &["format", "format_lang"],
+            "client_created_at" => &["client_ts"],
+            "clock_skew_ms" => &["clock_skew_ms"],
+            "delivered_count" => &["delivered_count"],
+            "link_preview" => &["link_preview"],
+            "avatar_seed" => &["avatar_seed"],
+            "edited_at" => &["edited_at"],
+            // Derived from the attributes above, or not stored at all
+            _ => &[],
+        };
+        attributes.extend(stored);
+    }
+    attributes
+}
+
+// A corrupt profile is ignored, as if the user had none
+fn parse_profile_item(user_id: &str, item: &Item) -> Option<UserProfile> {
+    let row = ItemReader::new(item);
+    let parsed = row
+        .string("username")
+        .and_then(|username| Ok((username.cloned(), row.string("avatar_seed")?.cloned())));
+    match parsed {
+        Ok((username, avatar_seed)) => {
+            Some(UserProfile { user_id: user_id.to_string(), username, avatar_seed })
+        }
+        Err(corrupt) => {
+            warn!("Ignoring profile of {}: `{}` {}", user_id, corrupt.field, corrupt.error);
+            None
+        }
+    }
+}
+
+// Rows missing their id, text or any usable creation time are skipped; anything else that's
+// unusable is defaulted (username, user_id) or treated as absent (the optional attributes).
+// Text and username left out by a `fields` projection are defaulted without complaint.
+fn parse_message_item(
+    item: &Item,
+    room_id: &str,
+    now: DateTime<Utc>,
+    projection: Option<&[&str]>,
+) -> ParsedItem {
+    let row = ItemReader::new(item);
+    let mut corrupt = Vec::new();
+    let fetched = |field| projection.is_none_or(|fields| fields.contains(&field));
+
+    let id = required(&mut corrupt, "id", row.string("id")).cloned();
+    // Encrypted text is opened by `messages_from_items`, which can wait on the key
+    let message_text = if fetched("message_text") && !item.contains_key(ENCRYPTED_TEXT_ATTRIBUTE) {
+        required(&mut corrupt, "message_text", row.string("message_text")).cloned()
+    } else {
+        Some(String::new())
+    };
+    let username = fetched("username")
+        .then(|| required(&mut corrupt, "username", row.string("username")))
+        .flatten()
+        .cloned();
+    // Older messages predate user_id, so its absence isn't corruption
+    let user_id = optional(&mut corrupt, row.string("user_id")).cloned();
+    let username = username.unwrap_or_else(|| display_name_fallback(user_id.as_deref()));
+    let user_id = user_id.unwrap_or_else(|| "unknown".to_string());
+
+    let ts = required(&mut corrupt, "ts", row.number("ts"));
+    let created_at_iso = optional(&mut corrupt, row.string("created_at_iso"));
+    let created_at = match ts {
+        Some(ts) => time::stored_created_at(ts, created_at_iso.map(String::as_str))
+            .inspect_err(|_| corrupt.push(CorruptField { field: "ts", error: FieldError::Invalid }))
+            .ok(),
+        None => created_at_iso
+            .and_then(|iso| DateTime::parse_from_rfc3339(iso).ok())
+            .map(|created_at| created_at.with_timezone(&Utc)),
+    };
+
+    let client_message_id = optional(&mut corrupt, row.string("client_message_id")).cloned();
+    let expires_at = optional(&mut corrupt, row.number("ttl"))
+        .and_then(|secs| DateTime::from_timestamp(secs, 0));
+    let ephemeral = optional(&mut corrupt, row.bool("ephemeral"));
+    let client_created_at =
+        optional(&mut corrupt, row.number("client_ts")).and_then(DateTime::from_timestamp_millis);
+    let clock_skew_ms = optional(&mut corrupt, row.number("clock_skew_ms"));
+    let client_seq =
+        optional(&mut corrupt, row.number("client_seq")).and_then(|seq| u64::try_from(seq).ok());
+    let visibility =
+        stored_visibility(optional(&mut corrupt, row.string("visibility")).map(String::as_str));
+    let to_user_id = optional(&mut corrupt, row.string("to_user_id")).cloned();
+    // Messages stored before seeds existed fall back to the sender's id, as they'd get now
+    let avatar_seed = optional(&mut corrupt, row.string("avatar_seed"))
+        .cloned()
+        .unwrap_or_else(|| user_id.clone());
+    let delivered_count = optional(&mut corrupt, row.number("delivered_count"))
+        .and_then(|count| u32::try_from(count).ok());
+    // Stored as JSON by `record_link_preview`
+    let link_preview = optional(&mut corrupt, row.string("link_preview")).and_then(|json| {
+        serde_json::from_str(json)
+            .inspect_err(|_| {
+                corrupt.push(CorruptField { field: "link_preview", error: FieldError::Invalid })
+            })
+            .ok()
+    });
+    // Set by `edit_message`, in epoch millis
+    let edited_at =
+        optional(&mut corrupt, row.number("edited_at")).and_then(DateTime::from_timestamp_millis);
+    // Set by `hide_message`; any other status is never stored
+    let hidden = optional(&mut corrupt, row.bool("hidden")).unwrap_or(false);
+    let format_kind = optional(&mut corrupt, row.string("format"));
+    let format_lang = optional(&mut corrupt, row.string("format_lang"));
+    let format = stored_format(format_kind.map(String::as_str), format_lang.map(String::as_str))
+        .unwrap_or_else(|| {
+            corrupt.push(CorruptField { field: "format", error: FieldError::Invalid });
+            MessageFormat::Plain
+        });
+
+    // TTL deletion lags, so hide expired messages that DynamoDB hasn't removed yet
+    let expired = expires_at.is_some_and(|expires_at| expires_at <= now);
+    let message = match (id, message_text, created_at) {
+        (Some(id), Some(message_text), Some(created_at)) if !expired => Some(ChatMessage {
+            core: MessageCore {
+                id,
+                room_id: room_id.to_string(),
+                user_id,
+                username,
+                message_text,
+                created_at,
+            },
+            client_message_id,
+            ephemeral: ephemeral.unwrap_or(expires_at.is_some()),
+            expires_at,
+            status: if hidden { MessageStatus::Hidden } else { MessageStatus::Stored },
+            format,
+            client_created_at,
+            clock_skew_ms,
+            delivered_count,
+            client_seq,
+            visibility,
+            to_user_id,
+            link_preview,
+            avatar_seed: Some(avatar_seed),
+            edited_at,
+        }),
+        _ => None,
+    };
+    ParsedItem { message, corrupt }
+}
+
+// The server-side filter for a text search. Segments are compressed and encrypted text is
+// sealed, so neither can be matched until it's read; both pass, to be matched after.
+fn search_filter(encrypted: bool) -> &'static str {
+    if encrypted {
+        "contains(message_text, :text) OR attribute_exists(#segment) \
+         OR attribute_exists(#encrypted)"
+    } else {
+        "contains(message_text, :text) OR attribute_exists(#segment)"
+    }
+}
+
+// Convert DynamoDB items to messages, skipping already-expired rows. Unusable attributes are
+// logged with the message id and field and counted (CorruptItem) rather than dropped silently;
+// so is encrypted text that `cipher` can't open, or that there's no cipher for.
+async fn messages_from_items(
+    items: &[Item],
+    room_id: &str,
+    now: DateTime<Utc>,
+    projection: Option<&[&str]>,
+    cipher: Option<&TextCipher>,
+) -> Vec<ChatMessage> {
+    let mut messages = Vec::new();
+    let mut corrupt = Vec::new();
+    for item in items {
+        if compaction::is_segment(item) {
+            // Compaction leaves out messages with a TTL, so none of these expire
+            match compaction::read_segment(item) {
+                Ok(segment) => messages.extend(segment),
+                Err(e) => {
+                    warn!("Skipping a segment of room {}: {}", room_id, e);
+                    CORRUPT_ITEMS.fetch_add(1, Ordering::SeqCst);
+                    corrupt.push(CorruptField { field: "segment", error: FieldError::Invalid });
+                }
+            }
+            continue;
+        }
+        let mut parsed = parse_and_report(item, room_id, now, projection);
+        let envelope = ItemReader::new(item).string(ENCRYPTED_TEXT_ATTRIBUTE).ok().flatten();
+        if let (Some(message), Some(envelope)) = (&mut parsed.message, envelope) {
+            let opened = match cipher {
+                Some(cipher) => cipher.decrypt(&message.core.id, envelope).await,
+                None => Err("ENCRYPT_AT_REST is off".to_string()),
+            };
+            match opened {
+                Ok(text) => message.core.message_text = text,
+                Err(e) => {
+                    warn!("Skipping message {} in room {}: {}", message.core.id, room_id, e);
+                    CORRUPT_ITEMS.fetch_add(1, Ordering::SeqCst);
+                    corrupt
+                        .push(CorruptField { field: "message_text", error: FieldError::Invalid });
+                    parsed.message = None;
+                }
+            }
+        }
+        messages.extend(parsed.message);
+        corrupt.extend(parsed.corrupt);
+    }
+
+    if !corrupt.is_empty() {
+        let metrics = MetricsHelper::new().await;
+        for field in &corrupt {
+            metrics.emit_corrupt_item(field.field).await;
+        }
+    }
+    messages
+}
+
+// Messages in sort key order, newest first if asked. While compaction runs, a message can be
+// read from its segment and its own item both; the copies are the same, so one is dropped.
+fn in_order(mut messages: Vec<ChatMessage>, newest_first: bool) -> Vec<ChatMessage> {
+    messages.sort_by_cached_key(message_sort_key);
+    messages.dedup_by(|a, b| a.core.id == b.core.id);
+    if newest_first {
+        messages.reverse();
+    }
+    messages
+}
+
+// The page of `query` among `messages`, which can hold messages out of its range and
+// duplicates when read from segments. Oldest first, however it was read.
+fn page_of(query: &MessageQuery, messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    let in_range =
+        messages.into_iter().filter(|message| query.contains(&message_sort_key(message))).collect();
+    let mut page = in_order(in_range, query.newest_first());
+    page.truncate(query.page_size());
+    if query.newest_first() {
+        page.reverse();
+    }
+    page
+}
+
+// Parse one row, logging (and counting) whatever was wrong with it
+fn parse_and_report(
+    item: &Item,
+    room_id: &str,
+    now: DateTime<Utc>,
+    projection: Option<&[&str]>,
+) -> ParsedItem {
+    let parsed = parse_message_item(item, room_id, now, projection);
+    if parsed.corrupt.is_empty() {
+        return parsed;
+    }
+
+    CORRUPT_ITEMS.fetch_add(1, Ordering::SeqCst);
+    let id = match item.get("id") {
+        Some(AttributeValue::S(id)) => id.as_str(),
+        _ => "<unknown>",
+    };
+    for field in &parsed.corrupt {
+        warn!("Message {} in room {}: `{}` {}", id, room_id, field.field, field.error);
+    }
+    if parsed.message.is_none() {
+        warn!("Skipping message {} in room {}: required attributes are unusable", id, room_id);
+    }
+    parsed
+}
+
+// In-memory store for tests and offline runs. Nothing is persisted and TTL is applied on read.
+pub struct MemoryMessageStore {
+    // Creation times keyed by room id
+    rooms: Mutex<HashMap<String, DateTime<Utc>>>,
+    archived_rooms: Mutex<HashSet<String>>,
+    // Messages per room, oldest first
+    messages: Mutex<HashMap<String, Vec<ChatMessage>>>,
+    // Sender origins keyed by message id
+    origins: Mutex<HashMap<String, MessageOrigin>>,
+    // Profiles keyed by user id
+    profiles: Mutex<HashMap<String, UserProfile>>,
+    // Room topics keyed by room id
+    topics: Mutex<HashMap<String, String>>,
+    // Message flags, oldest first
+    // By flagger
+    flags: Mutex<Vec<(String, MessageFlag)>>,
+    // Rooms whose message reads fail, see `fail_reads_of`
+    failing_rooms: Mutex<HashSet<String>>,
+    // Calls to ensure_room, which stands in for the rooms table read
+    room_checks: AtomicUsize,
+    // What every probe answers with, see `fail_probes_with`
+    probe_failure: Mutex<Option<ProbeError>>,
+    clock: Arc<dyn Clock>,
+    known_rooms: Option<Arc<KnownRooms>>,
+}
+
+impl Default for MemoryMessageStore {
+    fn default() -> Self {
+        Self {
+            rooms: Mutex::default(),
+            archived_rooms: Mutex::default(),
+            messages: Mutex::default(),
+            origins: Mutex::default(),
+            profiles: Mutex::default(),
+            topics: Mutex::default(),
+            flags: Mutex::default(),
+            failing_rooms: Mutex::default(),
+            room_checks: AtomicUsize::new(0),
+            probe_failure: Mutex::default(),
+            clock: clock::system(),
+            known_rooms: None,
+        }
+    }
+}
+
+impl MemoryMessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_known_rooms(mut self, known_rooms: Arc<KnownRooms>) -> Self {
+        self.known_rooms = Some(known_rooms);
+        self
+    }
+
+    pub fn has_room(&self, room_id: &str) -> bool {
+        self.rooms.lock().unwrap().contains_key(room_id)
+    }
+
+    pub fn room_topic(&self, room_id: &str) -> Option<String> {
+        self.topics.lock().unwrap().get(room_id).cloned()
+    }
+
+    /// How many times a room's existence has been checked
+    pub fn room_checks(&self) -> usize {
+        self.room_checks.load(Ordering::SeqCst)
+    }
+
+    /// Make reads of `room_id`'s messages fail from now on, as a throttled or broken query would
+    pub fn fail_reads_of(&self, room_id: &str) {
+        self.failing_rooms.lock().unwrap().insert(room_id.to_string());
+    }
+
+    /// Make health probes fail with `err` from now on, as a missing or unreachable table would
+    pub fn fail_probes_with(&self, err: ProbeError) {
+        *self.probe_failure.lock().unwrap() = Some(err);
+    }
+
+    fn check_readable(&self, room_id: &str) -> Result<(), String> {
+        if self.failing_rooms.lock().unwrap().contains(room_id) {
+            return Err(format!("Failed to query messages of room {}", room_id));
+        }
+        Ok(())
+    }
+}
+
+fn is_live(message: &ChatMessage, now: DateTime<Utc>) -> bool {
+    message.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+#[async_trait]
+impl MessageStore for MemoryMessageStore {
+    async fn ensure_room(&self, room_id: &str) -> Result<(), String> {
+        self.room_checks.fetch_add(1, Ordering::SeqCst);
+        let now = self.clock.now();
+        self.rooms.lock().unwrap().entry(room_id.to_string()).or_insert(now);
+        Ok(())
+    }
+
+    async fn put_message(
+        &self,
+        message: &ChatMessage,
+        origin: Option<&MessageOrigin>,
+    ) -> Result<(), PutMessageError> {
+        if !self.has_room(&message.core.room_id) {
+            return Err(PutMessageError::RoomMissing(message.core.room_id.clone()));
+        }
+        if self.archived_rooms.lock().unwrap().contains(&message.core.room_id) {
+            return Err(PutMessageError::RoomArchived(message.core.room_id.clone()));
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.values().flatten().any(|existing| existing.core.id == message.core.id) {
+            return Err(PutMessageError::Duplicate(message.core.id.clone()));
+        }
+        if let Some(holder) = message.client_message_id.as_ref().and_then(|client_message_id| {
+            messages.get(&message.core.room_id).into_iter().flatten().find(|existing| {
+                existing.core.user_id == message.core.user_id
+                    && existing.client_message_id.as_ref() == Some(client_message_id)
+            })
+        }) {
+            return Err(PutMessageError::ClientMessageTaken(holder.core.id.clone()));
+        }
+
+        let room = messages.entry(message.core.room_id.clone()).or_default();
+        room.push(message.clone());
+        room.sort_by_key(message_sort_key);
+        if let Some(origin) = origin {
+            self.origins.lock().unwrap().insert(message.core.id.clone(), origin.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_messages(
+        &self,
+        room_id: &str,
+        query: &MessageQuery,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        self.check_readable(room_id)?;
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        let in_range = messages
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .filter(|message| query.contains(&message_sort_key(message)));
+        let page: Vec<&ChatMessage> = if query.newest_first() {
+            let mut page: Vec<_> = in_range.rev().take(query.page_size()).collect();
+            page.reverse();
+            page
+        } else {
+            in_range.take(query.page_size()).collect()
+        };
+        Ok(page.into_iter().filter(|message| is_live(message, now)).cloned().collect())
+    }
+
+    async fn count_messages(&self, room_id: &str) -> Result<u32, String> {
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages.get(room_id).into_iter().flatten().filter(|m| is_live(m, now)).count() as u32)
+    }
+
+    async fn latest_messages(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        self.check_readable(room_id)?;
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .take(limit)
+            .filter(|message| is_live(message, now))
+            .cloned()
+            .collect())
+    }
+
+    async fn search_messages(
+        &self,
+        room_id: &str,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, StoreError> {
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|message| is_live(message, now) && message.core.message_text.contains(text))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_message(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<ChatMessage>, StoreError> {
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .find(|message| message.core.id == message_id && is_live(message, now))
+            .cloned())
+    }
+
+    async fn find_client_message(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        client_message_id: &str,
+    ) -> Result<Option<ChatMessage>, String> {
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .find(|message| {
+                message.core.user_id == user_id
+                    && message.client_message_id.as_deref() == Some(client_message_id)
+                    && is_live(message, now)
+            })
+            .cloned())
+    }
+
+    async fn find_message(&self, message_id: &str) -> Result<Option<ChatMessage>, String> {
+        let now = self.clock.now();
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .values()
+            .flatten()
+            .find(|message| message.core.id == message_id && is_live(message, now))
+            .cloned())
+    }
+
+    async fn message_origin(
+        &self,
+        room_id: &str,
+        message_id: &str,
+    ) -> Result<Option<MessageOrigin>, String> {
+        if self.get_message(room_id, message_id).await?.is_none() {
+            return Ok(None);
+        }
+        Ok(self.origins.lock().unwrap().get(message_id).cloned())
+    }
+
+    async fn enforce_room_cap(&self, room_id: &str, cap: u64) -> Result<usize, String> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(room) = messages.get_mut(room_id) else {
+            return Ok(0);
+        };
+
+        let kept = room.iter().filter(|message| message.expires_at.is_none()).count();
+        let mut excess = kept.saturating_sub(cap as usize);
+        let evicted = excess;
+        room.retain(|message| {
+            if excess > 0 && message.expires_at.is_none() {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+        Ok(evicted)
+    }
+
+    async fn set_room_topic(&self, room_id: &str, topic: Option<&str>) -> Result<bool, String> {
+        if !self.has_room(room_id) {
+            return Ok(false);
+        }
+        let mut topics = self.topics.lock().unwrap();
+        match topic {
+            Some(topic) => topics.insert(room_id.to_string(), topic.to_string()),
+            None => topics.remove(room_id),
+        };
+        Ok(true)
+    }
+
+    async fn set_room_archived(&self, room_id: &str, archived: bool) -> Result<bool, String> {
+        if !self.has_room(room_id) {
+            return Ok(false);
+        }
+        let mut archived_rooms = self.archived_rooms.lock().unwrap();
+        if archived {
+            archived_rooms.insert(room_id.to_string());
+        } else {
+            archived_rooms.remove(room_id);
+        }
+        Ok(true)
+    }
+
+    async fn list_rooms(&self, include_archived: bool) -> Result<Vec<Room>, String> {
+        let archived_rooms = self.archived_rooms.lock().unwrap();
+        let topics = self.topics.lock().unwrap();
+        let mut rooms: Vec<Room> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, created_at)| Room {
+                id: id.clone(),
+                name: room_name(id),
+                created_at: *created_at,
+                topic: topics.get(id).cloned(),
+                archived: archived_rooms.contains(id),
+            })
+            .filter(|room| include_archived || !room.archived)
+            .collect();
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(rooms)
+    }
+
+    async fn rooms_page(
+        &self,
+        include_archived: bool,
+        after: Option<&RoomKey>,
+        limit: usize,
+    ) -> Result<RoomPage, StoreError> {
+        let key = |room: &Room| (room.created_at.timestamp(), room.id.clone());
+        let newest_first =
+            |(created_at, id): &RoomKey| (std::cmp::Reverse(*created_at), id.clone());
+        let mut rooms = self.list_rooms(include_archived).await?;
+        rooms.sort_by_key(|room| newest_first(&key(room)));
+        if let Some(after) = after {
+            rooms.retain(|room| newest_first(&key(room)) > newest_first(after));
+        }
+        let more = rooms.len() > limit;
+        rooms.truncate(limit);
+        let next = more.then(|| rooms.last().map(key)).flatten();
+        Ok(RoomPage { rooms, next })
+    }
+
+    async fn profile(&self, user_id: &str) -> Result<Option<UserProfile>, String> {
+        Ok(self.profiles.lock().unwrap().get(user_id).cloned())
+    }
+
+    async fn set_profile_username(
+        &self,
+        user_id: &str,
+        username: &str,
+    ) -> Result<Option<String>, String> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let profile = profiles.entry(user_id.to_string()).or_insert_with(|| UserProfile {
+            user_id: user_id.to_string(),
+            username: None,
+            avatar_seed: None,
+        });
+        Ok(profile.username.replace(username.to_string()))
+    }
+
+    async fn set_profile_avatar_seed(
+        &self,
+        user_id: &str,
+        avatar_seed: Option<&str>,
+    ) -> Result<UserProfile, String> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let profile = profiles.entry(user_id.to_string()).or_insert_with(|| UserProfile {
+            user_id: user_id.to_string(),
+            username: None,
+            avatar_seed: None,
+        });
+        profile.avatar_seed = avatar_seed.map(str::to_string);
+        Ok(profile.clone())
+    }
+
+    async fn flag_message(
+        &self,
+        message: &ChatMessage,
+        flag: &MessageFlag,
+        flagger: &str,
+    ) -> Result<Option<u32>, String> {
+        let mut flags = self.flags.lock().unwrap();
+        let on_message =
+            |(_, existing): &&(String, MessageFlag)| existing.message_id == message.core.id;
+        if flags.iter().filter(on_message).any(|(existing, _)| existing == flagger) {
+            return Ok(None);
+        }
+        flags.push((flagger.to_string(), flag.clone()));
+        Ok(Some(flags.iter().filter(on_message).count() as u32))
+    }
+
+    async fn hide_message(&self, message: &ChatMessage) -> Result<(), String> {
+        let mut messages = self.messages.lock().unwrap();
+        let stored = messages
+            .get_mut(&message.core.room_id)
+            .into_iter()
+            .flatten()
+            .find(|stored| stored.core.id == message.core.id);
+        if let Some(stored) = stored {
+            stored.status = MessageStatus::Hidden;
+        }
+        Ok(())
+    }
+
+    async fn delete_message(&self, message: &ChatMessage) -> Result<bool, String> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some(stored) = messages.get_mut(&message.core.room_id) else {
+            return Ok(false);
+        };
+        let before = stored.len();
+        stored.retain(|stored| {
+            stored.core.id != message.core.id || stored.core.user_id != message.core.user_id
+        });
+        Ok(stored.len() < before)
+    }
+
+    async fn edit_message(&self, message: &ChatMessage) -> Result<bool, String> {
+        let mut messages = self.messages.lock().unwrap();
+        let stored = messages.get_mut(&message.core.room_id).into_iter().flatten().find(|stored| {
+            stored.core.id == message.core.id && stored.core.user_id == message.core.user_id
+        });
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        stored.core.message_text = message.core.message_text.clone();
+        stored.edited_at = message.edited_at;
+        stored.link_preview = None;
+        Ok(true)
+    }
+
+    async fn list_flags(&self, limit: usize) -> Result<Vec<MessageFlag>, String> {
+        Ok(self.flags.lock().unwrap().iter().take(limit).map(|(_, flag)| flag.clone()).collect())
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn known_rooms(&self) -> Option<&KnownRooms> {
+        self.known_rooms.as_deref()
+    }
+
+    async fn probe(&self) -> Result<(), ProbeError> {
+        self.probe_failure.lock().unwrap().clone().map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, created_at_millis: i64) -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: id.to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: format!("message {}", id),
+                created_at: DateTime::from_timestamp_millis(created_at_millis).unwrap(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    fn stored_row(ts: AttributeValue, created_at_iso: Option<&str>) -> Item {
+        let mut item = HashMap::from([
+            ("id".to_string(), AttributeValue::S("msg-1".to_string())),
+            ("room_id".to_string(), AttributeValue::S("general".to_string())),
+            ("username".to_string(), AttributeValue::S("alice".to_string())),
+            ("message_text".to_string(), AttributeValue::S("Hello".to_string())),
+            ("ts".to_string(), ts),
+        ]);
+        if let Some(iso) = created_at_iso {
+            item.insert("created_at_iso".to_string(), AttributeValue::S(iso.to_string()));
+        }
+        item
+    }
+
+    #[tokio::test]
+    async fn test_string_ts_is_reported_not_silently_dropped() {
+        let now = Utc::now();
+        let ts = AttributeValue::S("1700000000000".to_string());
+        let wrong_type =
+            CorruptField { field: "ts", error: FieldError::WrongType { expected: "N" } };
+
+        // created_at_iso still dates the message, so it's kept
+        let row = stored_row(ts.clone(), Some("2023-11-14T22:13:20.000Z"));
+        let parsed = parse_message_item(&row, "general", now, None);
+        assert_eq!(parsed.corrupt, vec![wrong_type]);
+        let message = parsed.message.unwrap();
+        assert_eq!(message.core.created_at.timestamp_millis(), 1_700_000_000_000);
+
+        // Without it there's no creation time, so the row is skipped, but still counted
+        let before = corrupt_items();
+        let row = stored_row(ts, None);
+        assert_eq!(parse_message_item(&row, "general", now, None).corrupt, vec![wrong_type]);
+        assert!(messages_from_items(&[row], "general", now, None, None).await.is_empty());
+        assert!(corrupt_items() > before);
+    }
+
+    #[tokio::test]
+    async fn test_contended_writes_are_retried_a_few_times() {
+        let attempts = AtomicUsize::new(0);
+        let contended_twice = retry_contended(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(PutMessageError::Contended),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(contended_twice.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicUsize::new(0);
+        let always = retry_contended(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(PutMessageError::Contended)
+        })
+        .await;
+        assert!(matches!(always, Err(PutMessageError::Contended)));
+        assert_eq!(attempts.load(Ordering::SeqCst), CONTENDED_ATTEMPTS as usize);
+
+        // Anything else isn't retried
+        let attempts = AtomicUsize::new(0);
+        let _ = retry_contended(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(PutMessageError::Throttled)
+        })
+        .await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_projections_always_read_whether_a_message_is_hidden() {
+        // Whatever the fields, a hidden message's text must be blanked out
+        assert!(projected_attributes(&["message_text"]).contains(&"hidden"));
+        assert!(projected_attributes(&[]).contains(&"hidden"));
+    }
+
+    #[test]
+    fn test_read_errors_are_told_apart_by_what_the_service_said() {
+        use aws_sdk_dynamodb::{error::ErrorMetadata, operation::query::QueryError};
+        let classify = |code: &str| {
+            let err = QueryError::generic(ErrorMetadata::builder().code(code).build());
+            StoreError::from(SdkError::<_, ()>::service_error(err, ()))
+        };
+
+        assert_eq!(classify("ProvisionedThroughputExceededException"), StoreError::Throttled);
+        assert_eq!(classify("ThrottlingException"), StoreError::Throttled);
+        assert_eq!(classify("TransactionConflictException"), StoreError::Conflict);
+        assert!(matches!(classify("ValidationException"), StoreError::Other(_)));
+    }
+
+    #[test]
+    fn test_missing_and_wrong_type_are_distinguished() {
+        let now = Utc::now();
+        let mut row = stored_row(AttributeValue::N("1700000000000".to_string()), None);
+        assert!(parse_message_item(&row, "general", now, None).corrupt.is_empty());
+
+        row.remove("username");
+        row.insert("user_id".to_string(), AttributeValue::N("7".to_string()));
+        row.insert("ephemeral".to_string(), AttributeValue::S("yes".to_string()));
+        let parsed = parse_message_item(&row, "general", now, None);
+        assert_eq!(
+            parsed.corrupt,
+            vec![
+                CorruptField { field: "username", error: FieldError::Missing },
+                CorruptField { field: "user_id", error: FieldError::WrongType { expected: "S" } },
+                CorruptField {
+                    field: "ephemeral",
+                    error: FieldError::WrongType { expected: "BOOL" }
+                },
+            ]
+        );
+        // None of those is critical, so the message is kept with defaults
+        let message = parsed.message.unwrap();
+        assert_eq!(
+            (message.core.username.as_str(), message.core.user_id.as_str()),
+            ("Guest", "unknown")
+        );
+        assert!(!message.ephemeral);
+
+        row.insert("message_text".to_string(), AttributeValue::L(vec![]));
+        assert!(parse_message_item(&row, "general", now, None).message.is_none());
+    }
+
+    #[test]
+    fn test_projected_out_fields_are_not_corrupt() {
+        let mut row = stored_row(AttributeValue::N("1700000000000".to_string()), None);
+        row.remove("username");
+        row.remove("message_text");
+
+        let parsed = parse_message_item(&row, "general", Utc::now(), Some(&["id"]));
+        assert!(parsed.corrupt.is_empty());
+        assert_eq!(parsed.message.unwrap().core.id, "msg-1");
+        assert_eq!(projected_attributes(&["id", "format"]).len(), 12);
+    }
+
+    #[test]
+    fn test_avatar_seed_is_read_back_or_falls_back_to_the_sender() {
+        let now = Utc::now();
+        let mut row = stored_row(AttributeValue::N("1700000000000".to_string()), None);
+        row.insert("user_id".to_string(), AttributeValue::S("u1".to_string()));
+        let message = parse_message_item(&row, "general", now, None).message.unwrap();
+        assert_eq!(message.avatar_seed.as_deref(), Some("u1"));
+
+        row.insert("avatar_seed".to_string(), AttributeValue::S("sunflower".to_string()));
+        let message = parse_message_item(&row, "general", now, None).message.unwrap();
+        assert_eq!(message.avatar_seed.as_deref(), Some("sunflower"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_filters_and_evicts() {
+        let store = MemoryMessageStore::new();
+        store.ensure_room("general").await.unwrap();
+        for (id, ts) in [("c", 3_000), ("a", 1_000), ("b", 2_000)] {
+            store.put_message(&message(id, ts), None).await.unwrap();
+        }
+        assert!(store.put_message(&message("a", 4_000), None).await.is_err());
+
+        let query = MessageQuery { created_after: Some(2_000), ..Default::default() };
+        let ids: Vec<_> = store
+            .get_messages("general", &query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.core.id)
+            .collect();
+        assert_eq!(ids, vec!["b", "c"]);
+
+        assert_eq!(store.enforce_room_cap("general", 2).await.unwrap(), 1);
+        assert!(store.get_message("general", "a").await.unwrap().is_none());
+        assert_eq!(store.find_message("c").await.unwrap().unwrap().core.room_id, "general");
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_projected_query_reads_only_requested_attributes() {
+        use crate::test_support::{create_messages_table, create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "projection-test-rooms".to_string(),
+            messages: "projection-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb, tables);
+        store.ensure_room("general").await.unwrap();
+        store.put_message(&message("a", 1_000), None).await.unwrap();
+
+        let query = MessageQuery { fields: Some("id,format".to_string()), ..Default::default() };
+        let messages = store.get_messages("general", &query).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].core.id, "a");
+        assert_eq!(messages[0].core.created_at.timestamp_millis(), 1_000);
+        assert!(messages[0].core.message_text.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_profile_rename_returns_previous_name() {
+        use crate::test_support::{create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let users = "profile-test-users";
+        create_table(&ddb, users, &[("user_id", KeyType::Hash)]).await;
+        let tables = Tables { rooms: "unused".to_string(), messages: "unused".to_string() };
+        let store = DynamoMessageStore::new(ddb, tables);
+        assert_eq!(store.profile("u1").await.unwrap(), None);
+        assert!(store.set_profile_username("u1", "alicia").await.is_err());
+
+        let store = store.with_users_table(Some(users.to_string()));
+        assert_eq!(store.set_profile_username("u1", "alicia").await.unwrap(), None);
+        assert_eq!(
+            store.set_profile_username("u1", "ally").await.unwrap().as_deref(),
+            Some("alicia")
+        );
+        let profile = store.profile("u1").await.unwrap().unwrap();
+        assert_eq!(profile.username.as_deref(), Some("ally"));
+        assert_eq!(store.profile("u2").await.unwrap(), None);
+
+        // The seed is kept through renames and cleared without touching the name
+        let profile = store.set_profile_avatar_seed("u1", Some("sunflower")).await.unwrap();
+        assert_eq!(profile.avatar_seed.as_deref(), Some("sunflower"));
+        store.set_profile_username("u1", "alicia").await.unwrap();
+        let profile = store.profile("u1").await.unwrap().unwrap();
+        assert_eq!(profile.avatar_seed.as_deref(), Some("sunflower"));
+        let profile = store.set_profile_avatar_seed("u1", None).await.unwrap();
+        assert_eq!(profile.username.as_deref(), Some("alicia"));
+        assert_eq!(profile.avatar_seed, None);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_room_topic_is_set_and_cleared() {
+        use crate::test_support::{create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let rooms = "topic-test-rooms";
+        create_table(&ddb, rooms, &[("id", KeyType::Hash)]).await;
+        let tables = Tables { rooms: rooms.to_string(), messages: "unused".to_string() };
+        let store = DynamoMessageStore::new(ddb.clone(), tables);
+        assert!(!store.set_room_topic("general", Some("Planning")).await.unwrap());
+
+        store.ensure_room("general").await.unwrap();
+        let topic = || async {
+            let output = ddb
+                .get_item()
+                .table_name(rooms)
+                .key("id", AttributeValue::S("general".to_string()))
+                .send()
+                .await
+                .unwrap();
+            output.item.unwrap().get("topic").and_then(|v| v.as_s().ok()).cloned()
+        };
+        assert!(store.set_room_topic("general", Some("Planning")).await.unwrap());
+        assert_eq!(topic().await.as_deref(), Some("Planning"));
+        assert!(store.set_room_topic("general", None).await.unwrap());
+        assert_eq!(topic().await, None);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_failed_room_update_rolls_back_message() {
+        use crate::test_support::{create_messages_table, create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "transaction-test-rooms".to_string(),
+            messages: "transaction-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+        let stored_rows = || async {
+            let scan = ddb.scan().table_name(&tables.messages).send().await.unwrap();
+            scan.count()
+        };
+
+        // No room record, so the room update's condition fails and the put is undone with it
+        let message = message("a", 1_000);
+        let err = store.put_message(&message, None).await.unwrap_err();
+        assert_eq!(err, PutMessageError::RoomMissing("general".to_string()));
+        assert_eq!(stored_rows().await, 0);
+        assert!(store.get_message("general", "a").await.unwrap().is_none());
+
+        store.ensure_room("general").await.unwrap();
+        store.put_message(&message, None).await.unwrap();
+        assert_eq!(store.message_count("general").await.unwrap(), 1);
+
+        // A duplicate leaves the room's count alone
+        let err = store.put_message(&message, None).await.unwrap_err();
+        assert_eq!(err, PutMessageError::Duplicate("a".to_string()));
+        assert_eq!(store.message_count("general").await.unwrap(), 1);
+        assert_eq!(stored_rows().await, 1);
+
+        let room = ddb
+            .get_item()
+            .table_name(&tables.rooms)
+            .key("id", AttributeValue::S("general".to_string()))
+            .send()
+            .await
+            .unwrap();
+        let last_message_at = room.item().and_then(|item| item.get("last_message_at"));
+        assert_eq!(last_message_at, Some(&AttributeValue::N("1000".to_string())));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_archived_room_refuses_messages_atomically() {
+        use crate::test_support::{create_messages_table, create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "archive-test-rooms".to_string(),
+            messages: "archive-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+        store.ensure_room("general").await.unwrap();
+        store.put_message(&message("a", 1_000), None).await.unwrap();
+
+        assert!(store.set_room_archived("general", true).await.unwrap());
+        let err = store.put_message(&message("b", 2_000), None).await.unwrap_err();
+        assert_eq!(err, PutMessageError::RoomArchived("general".to_string()));
+        assert!(store.get_message("general", "b").await.unwrap().is_none());
+        assert!(store.list_rooms(false).await.unwrap().is_empty());
+        assert!(store.list_rooms(true).await.unwrap()[0].archived);
+
+        assert!(store.set_room_archived("general", false).await.unwrap());
+        store.put_message(&message("b", 2_000), None).await.unwrap();
+        assert!(!store.set_room_archived("missing", true).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_rooms_page_through_the_created_index() {
+        use crate::{
+            clock::MockClock,
+            test_support::{create_messages_table, create_rooms_table, local_ddb},
+        };
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "rooms-page-test-rooms".to_string(),
+            messages: "rooms-page-test-messages".to_string(),
+        };
+        create_rooms_table(&ddb, &tables.rooms).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let clock =
+            Arc::new(MockClock::new(DateTime::from_timestamp_millis(1_700_000_000_000).unwrap()));
+        let store = DynamoMessageStore::new(ddb, tables).with_clock(clock.clone());
+        for n in 0..7 {
+            store.ensure_room(&format!("room-{}", n)).await.unwrap();
+            clock.advance(chrono::Duration::seconds(1));
+        }
+        store.set_room_archived("room-5", true).await.unwrap();
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store.rooms_page(false, after.as_ref(), 2).await.unwrap();
+            assert!(page.rooms.len() <= 2);
+            seen.extend(page.rooms.into_iter().map(|room| room.id));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, ["room-6", "room-4", "room-3", "room-2", "room-1", "room-0"]);
+
+        let page = store.rooms_page(true, None, 2).await.unwrap();
+        let ids: Vec<_> = page.rooms.iter().map(|room| room.id.as_str()).collect();
+        assert_eq!(ids, ["room-6", "room-5"]);
+        assert_eq!(page.next, Some((1_700_000_005_000, "room-5".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_rows_are_opened_on_read() {
+        use crate::test_support::StaticDataKeys;
+
+        let cipher = TextCipher::new(Box::new(StaticDataKeys));
+        let now = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let mut row = stored_row(AttributeValue::N("1700000000000".to_string()), None);
+        row.remove("message_text");
+        let envelope = cipher.encrypt("msg-1", "Hello").await.unwrap();
+        row.insert(ENCRYPTED_TEXT_ATTRIBUTE.to_string(), AttributeValue::S(envelope.clone()));
+
+        let messages =
+            messages_from_items(&[row.clone()], "general", now, None, Some(&cipher)).await;
+        assert_eq!(messages[0].core.message_text, "Hello");
+
+        // Without the cipher, or with the text altered, the row is skipped rather than shown
+        assert!(messages_from_items(&[row.clone()], "general", now, None, None).await.is_empty());
+        let tampered = envelope.replacen("v1.", "v1.00", 1);
+        row.insert(ENCRYPTED_TEXT_ATTRIBUTE.to_string(), AttributeValue::S(tampered));
+        assert!(messages_from_items(&[row], "general", now, None, Some(&cipher)).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_encrypted_store_keeps_no_plaintext() {
+        use crate::test_support::{create_messages_table, create_table, local_ddb, StaticDataKeys};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "encryption-test-rooms".to_string(),
+            messages: "encryption-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let plain = DynamoMessageStore::new(ddb.clone(), tables.clone());
+        let encrypted =
+            plain.clone().with_cipher(Some(Arc::new(TextCipher::new(Box::new(StaticDataKeys)))));
+        encrypted.ensure_room("general").await.unwrap();
+
+        // Rows written before encryption was turned on stay plaintext and still read
+        plain.put_message(&message("a", 1_000), None).await.unwrap();
+        encrypted.put_message(&message("b", 2_000), None).await.unwrap();
+
+        let scan = ddb.scan().table_name(&tables.messages).send().await.unwrap();
+        let row = scan.items().iter().find(|item| item["id"].as_s().unwrap() == "b").unwrap();
+        assert!(!row.contains_key("message_text"));
+        assert!(!row[ENCRYPTED_TEXT_ATTRIBUTE].as_s().unwrap().contains("message b"));
+
+        let texts: Vec<_> = encrypted
+            .get_messages("general", &MessageQuery::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.core.message_text)
+            .collect();
+        assert_eq!(texts, vec!["message a", "message b"]);
+
+        // Sealed text is matched once it's opened
+        let hits = encrypted.search_messages("general", "message b", 10).await.unwrap();
+        assert_eq!(hits.iter().map(|m| m.core.id.as_str()).collect::<Vec<_>>(), ["b"]);
+        let hits = encrypted.search_messages("general", "message", 10).await.unwrap();
+        assert_eq!(hits.len(), 2);
+    }
+
+    // A message as `put_message` would store it
+    fn live_item(message: &ChatMessage) -> Item {
+        ItemBuilder::new()
+            .string("id", &message.core.id)
+            .string("room_id", &message.core.room_id)
+            .string("user_id", &message.core.user_id)
+            .string("username", &message.core.username)
+            .string("message_text", &message.core.message_text)
+            .string("sk", message_sort_key(message))
+            .number("ts", message.core.created_at.timestamp_millis())
+            .build()
+    }
+
+    // The items a DynamoDB query for `query` returns: those in its key range, in key order, a
+    // page at most
+    fn query_items(table: &[Item], query: &MessageQuery) -> Vec<Item> {
+        let (_, bounds) = query.key_condition();
+        let bound = |name| bounds.iter().find(|(placeholder, _)| *placeholder == name);
+        let (lo, hi) = (bound(":lo"), bound(":hi"));
+        let mut items: Vec<Item> = table
+            .iter()
+            .filter(|item| {
+                let sk = item["sk"].as_s().unwrap();
+                lo.is_none_or(|(_, lo)| sk >= lo) && hi.is_none_or(|(_, hi)| sk <= hi)
+            })
+            .cloned()
+            .collect();
+        items.sort_by_key(|item| item["sk"].as_s().unwrap().clone());
+        items.truncate(MESSAGE_PAGE_SIZE);
+        items
+    }
+
+    #[tokio::test]
+    async fn test_pages_span_segmented_and_live_history() {
+        let now = Utc::now();
+        let originals: Vec<_> =
+            (0..60).map(|i| message(&format!("m{:02}", i), 1_000 + i)).collect();
+        let live: Vec<Item> = originals.iter().map(live_item).collect();
+        let before = messages_from_items(&live, "general", now, None, None).await;
+        let json = |messages: &[ChatMessage]| serde_json::to_value(messages).unwrap();
+
+        // The oldest 50 compacted, caught while the last of their items are still being removed
+        let segment = compaction::segment_item(
+            "general",
+            live[0]["sk"].as_s().unwrap(),
+            live[49]["sk"].as_s().unwrap(),
+            50,
+            compaction::encode_segment(&before[..50]).unwrap(),
+        );
+        let mut table = vec![segment.clone()];
+        table.extend(live[45..].iter().cloned());
+
+        let mut read = Vec::new();
+        let mut query = MessageQuery::default();
+        loop {
+            let items = query_items(&table, &query);
+            let page =
+                page_of(&query, messages_from_items(&items, "general", now, None, None).await);
+            read.extend(page.iter().cloned());
+            if page.len() < MESSAGE_PAGE_SIZE {
+                break;
+            }
+            query.after = Some(message_sort_key(page.last().unwrap()));
+        }
+        // Two pages out of the segment, one crossing into live items, each message once
+        assert_eq!(json(&read), json(&before));
+
+        // A range ending inside the segment misses it by key, so it's read as the first item
+        // past the range
+        let query = MessageQuery { created_before: Some(1_030), ..MessageQuery::default() };
+        assert!(query_items(&table, &query).is_empty());
+        let page =
+            page_of(&query, messages_from_items(&[segment], "general", now, None, None).await);
+        assert_eq!(json(&page), json(&before[..MESSAGE_PAGE_SIZE]));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_compacted_history_reads_as_before() {
+        use crate::test_support::{create_messages_table, create_table, local_ddb};
+        use aws_sdk_dynamodb::types::KeyType;
+
+        let ddb = local_ddb().await;
+        let tables = Tables {
+            rooms: "compaction-test-rooms".to_string(),
+            messages: "compaction-test-messages".to_string(),
+        };
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        let store = DynamoMessageStore::new(ddb.clone(), tables.clone());
+        store.ensure_room("general").await.unwrap();
+        for i in 0..30 {
+            store.put_message(&message(&format!("m{:02}", i), 1_000 + i), None).await.unwrap();
+        }
+        let read_all = || async {
+            let mut read = Vec::new();
+            let mut query = MessageQuery::default();
+            loop {
+                let page = store.get_messages("general", &query).await.unwrap();
+                read.extend(page.iter().cloned());
+                if page.len() < MESSAGE_PAGE_SIZE {
+                    return serde_json::to_value(read).unwrap();
+                }
+                query.after = Some(message_sort_key(page.last().unwrap()));
+            }
+        };
+        let before = read_all().await;
+
+        // The newest 5 stay; of the 25 before them, two full runs of 10 are compacted
+        let compaction = Compaction::new(10, 5);
+        let report = store.compact_room("general", &compaction).await.unwrap();
+        assert_eq!(
+            report,
+            CompactionReport { segments_written: 2, messages_compacted: 20, leftovers_removed: 0 }
+        );
+        let items = ddb.scan().table_name(&tables.messages).send().await.unwrap();
+        assert_eq!(items.count(), 12);
+        assert_eq!(read_all().await, before);
+        assert_eq!(store.count_messages("general").await.unwrap(), 30);
+        let hits = store.search_messages("general", "message m05", 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        // Running again finds nothing more to do
+        let again = store.compact_room("general", &compaction).await.unwrap();
+        assert_eq!(again, CompactionReport::default());
+
+        // Hiding and the room cap reach into segments
+        store.hide_message(&message("m03", 1_003)).await.unwrap();
+        let hidden = store.get_messages("general", &MessageQuery::default()).await.unwrap();
+        assert_eq!(hidden[3].status, MessageStatus::Hidden);
+        assert_eq!(store.enforce_room_cap("general", 25).await.unwrap(), 5);
+        let capped = store.get_messages("general", &MessageQuery::default()).await.unwrap();
+        assert_eq!(capped[0].core.id, "m05");
+        assert_eq!(store.count_messages("general").await.unwrap(), 25);
+    }
+}
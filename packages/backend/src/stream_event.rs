@@ -0,0 +1,927 @@
+use crate::{
+    broadcast, compaction,
+    encryption::{TextCipher, ENCRYPTED_TEXT_ATTRIBUTE},
+    identity::display_name_fallback,
+    migrate,
+    retry_queue::RetryQueue,
+    search::SearchIndex,
+    store,
+    unfurl::{self, Unfurler},
+    webhook::Webhook,
+    MetricsHelper,
+};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::DateTime;
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use std::{collections::HashMap, future::Future};
+use tracing::{info, warn};
+use types::{time, ChatMessage, DeliveryReceipt, MessageCore, MessageStatus};
+
+/// A DynamoDB Streams event on the messages table, as delivered to the broadcast Lambda
+#[derive(Deserialize)]
+pub struct DynamoDBStreamEvent {
+    #[serde(rename = "Records")]
+    pub records: Vec<DynamoDBRecord>,
+}
+
+#[derive(Deserialize)]
+pub struct DynamoDBRecord {
+    #[serde(rename = "eventName")]
+    pub event_name: String,
+    pub dynamodb: Option<DynamoDBStreamRecord>,
+    // Set when DynamoDB itself made the change, i.e. a TTL expiry
+    #[serde(rename = "userIdentity", default)]
+    pub user_identity: Option<UserIdentity>,
+}
+
+#[derive(Deserialize)]
+pub struct DynamoDBStreamRecord {
+    #[serde(rename = "NewImage")]
+    pub new_image: Option<HashMap<String, AttributeValueWrapper>>,
+    // On MODIFYs, where it tells an edit from the other updates a message gets, and on
+    // REMOVEs, where it's the deleted message
+    #[serde(rename = "OldImage")]
+    pub old_image: Option<HashMap<String, AttributeValueWrapper>>,
+}
+
+#[derive(Deserialize)]
+pub struct UserIdentity {
+    #[serde(rename = "principalId")]
+    pub principal_id: Option<String>,
+}
+
+impl UserIdentity {
+    // TTL deletions are the only changes DynamoDB's own service principal makes
+    fn is_ttl_expiry(&self) -> bool {
+        self.principal_id.as_deref() == Some("dynamodb.amazonaws.com")
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AttributeValueWrapper {
+    #[serde(rename = "S")]
+    pub s: Option<String>,
+    #[serde(rename = "N")]
+    pub n: Option<String>,
+    #[serde(rename = "BOOL")]
+    pub bool: Option<bool>,
+}
+
+/// Rooms whose records are processed at once, by default (STREAM_RECORD_CONCURRENCY)
+pub const DEFAULT_STREAM_RECORD_CONCURRENCY: usize = 10;
+
+/// Run `process` over a batch's records, up to `concurrency` rooms at a time. Each room's
+/// records still go one after another in stream order, so its messages are broadcast in the
+/// order they were written; records with no room share a group of their own.
+pub async fn process_in_room_order<F, Fut>(
+    records: Vec<DynamoDBRecord>,
+    concurrency: usize,
+    process: F,
+) where
+    F: Fn(DynamoDBRecord) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut rooms: Vec<Vec<DynamoDBRecord>> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        let room_id = record_room_id(&record).unwrap_or_default().to_string();
+        let position = *positions.entry(room_id).or_insert_with(|| {
+            rooms.push(Vec::new());
+            rooms.len() - 1
+        });
+        rooms[position].push(record);
+    }
+
+    let process = &process;
+    stream::iter(rooms)
+        .map(|records| async move {
+            for record in records {
+                process(record).await;
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<()>()
+        .await;
+}
+
+// A REMOVE has only the image of what was removed
+fn record_room_id(record: &DynamoDBRecord) -> Option<&str> {
+    let stream_record = record.dynamodb.as_ref()?;
+    let image = stream_record.new_image.as_ref().or(stream_record.old_image.as_ref())?;
+    image.get("room_id")?.s.as_deref()
+}
+
+/// What broadcasting a stream record goes through, set up once per container
+#[derive(Clone, Copy)]
+pub struct StreamContext<'a> {
+    pub ddb: &'a DynamoDbClient,
+    pub api_gateway: &'a broadcast::ManagementClients,
+    pub metrics: &'a MetricsHelper,
+    pub connections_table: &'a str,
+    pub webhook: Option<&'a Webhook>,
+    pub retry: Option<&'a RetryQueue>,
+    // New messages are indexed alongside the broadcast
+    pub search: Option<&'a dyn SearchIndex>,
+    // Where each message's delivery count is recorded; without it no count or receipt is sent
+    pub messages_table: Option<&'a str>,
+    // Opens text stored encrypted (ENCRYPT_AT_REST), so connections get plaintext
+    pub cipher: Option<&'a TextCipher>,
+    // Previews the first link in each message once it's been delivered (ENABLE_UNFURL)
+    pub unfurler: Option<&'a Unfurler>,
+}
+
+/// Broadcast the message written by one stream record, and index it when a search index is
+/// configured. INSERTs are broadcast as new messages and MODIFYs that change `edited_at` as
+/// edits, for clients to replace the copy they have; REMOVEs go out as deletion notices (see
+/// `process_removal`). Anything else on the table (delivery counts, flags, link previews) is
+/// skipped, as is a record missing the image it needs. Edits aren't counted as sent, sent to
+/// the webhook or given a delivery count. A failure to index is logged and doesn't hold up or
+/// fail the broadcast.
+pub async fn process_record(
+    context: &StreamContext<'_>,
+    record: DynamoDBRecord,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let StreamContext {
+        ddb,
+        api_gateway,
+        metrics,
+        connections_table,
+        webhook,
+        retry,
+        search,
+        messages_table,
+        cipher,
+        unfurler,
+    } = *context;
+    // New messages, and edits to them
+    let edit = match record.event_name.as_str() {
+        "INSERT" => false,
+        "MODIFY" => true,
+        "REMOVE" => return process_removal(context, record).await,
+        _ => {
+            info!("Skipping event: {}", record.event_name);
+            return Ok(());
+        }
+    };
+
+    let Some(stream_record) = record.dynamodb else {
+        warn!("Skipping {} with no dynamodb data", record.event_name);
+        return Ok(());
+    };
+    if edit && !is_edit(&stream_record) {
+        info!("Skipping MODIFY that isn't an edit");
+        return Ok(());
+    }
+    let Some(image) = stream_record.new_image else {
+        warn!("Skipping {} with no NewImage", record.event_name);
+        return Ok(());
+    };
+    // Compaction writes old history back as segments; nothing in them is new
+    if image.contains_key(compaction::SEGMENT_ATTRIBUTE) {
+        info!("Skipping segment written by compaction");
+        return Ok(());
+    }
+    // A post's client_message_id claim, not a message of its own
+    if image.contains_key(store::CLIENT_CLAIM_ATTRIBUTE) {
+        info!("Skipping client_message_id claim");
+        return Ok(());
+    }
+    // History the sort-key migration copied over; it was delivered when it was first posted
+    if !edit && image.contains_key(migrate::MIGRATED_ATTRIBUTE) {
+        info!("Skipping message copied by the migration");
+        return Ok(());
+    }
+
+    // Extract message data from DynamoDB stream record
+    let room_id = image.get("room_id").and_then(|v| v.s.as_ref()).ok_or("Missing room_id")?;
+    let message_id = image.get("id").and_then(|v| v.s.as_ref()).ok_or("Missing id")?;
+    let encrypted = image.contains_key(ENCRYPTED_TEXT_ATTRIBUTE);
+    let message_text = match image.get(ENCRYPTED_TEXT_ATTRIBUTE).and_then(|v| v.s.as_ref()) {
+        Some(envelope) => {
+            let cipher = cipher.ok_or("Message text is encrypted but ENCRYPT_AT_REST is off")?;
+            &cipher.decrypt(message_id, envelope).await?
+        }
+        None => {
+            image.get("message_text").and_then(|v| v.s.as_ref()).ok_or("Missing message_text")?
+        }
+    };
+    let ts = image
+        .get("ts")
+        .and_then(|v| v.n.as_ref())
+        .and_then(|n| n.parse::<i64>().ok())
+        .ok_or("Missing or invalid ts")?;
+    let created_at_iso = image.get("created_at_iso").and_then(|v| v.s.as_deref());
+    let created_at = time::stored_created_at(ts, created_at_iso)
+        .map_err(|e| format!("Message {} has no usable creation time: {}", message_id, e))?;
+
+    // Extract user_id and client_message_id (may be missing for older messages)
+    let user_id = image.get("user_id").and_then(|v| v.s.as_deref());
+    let username = image
+        .get("username")
+        .and_then(|v| v.s.clone())
+        .unwrap_or_else(|| display_name_fallback(user_id));
+    let user_id = user_id.unwrap_or("unknown").to_string();
+
+    let client_message_id = image.get("client_message_id").and_then(|v| v.s.as_ref()).cloned();
+
+    // Self-destructing messages carry their TTL so clients can render a countdown
+    let expires_at = image
+        .get("ttl")
+        .and_then(|v| v.n.as_ref())
+        .and_then(|n| n.parse::<i64>().ok())
+        .and_then(|secs| DateTime::from_timestamp(secs, 0));
+    let ephemeral = image.get("ephemeral").and_then(|v| v.bool).unwrap_or(expires_at.is_some());
+
+    // Offline-first clients send their own timestamp; the skew was judged when it was stored
+    let number = |name: &str| image.get(name).and_then(|v| v.n.as_ref())?.parse::<i64>().ok();
+    let client_created_at = number("client_ts").and_then(DateTime::from_timestamp_millis);
+    let clock_skew_ms = number("clock_skew_ms");
+    let client_seq = number("client_seq").and_then(|seq| u64::try_from(seq).ok());
+    let edited_at = number("edited_at").and_then(DateTime::from_timestamp_millis);
+
+    // Unknown formats (written by a newer version) are broadcast as plain text
+    let format = store::stored_format(
+        image.get("format").and_then(|v| v.s.as_deref()),
+        image.get("format_lang").and_then(|v| v.s.as_deref()),
+    )
+    .unwrap_or_default();
+
+    // Direct messages only go out to their two parties
+    let visibility = store::stored_visibility(image.get("visibility").and_then(|v| v.s.as_deref()));
+    let to_user_id = image.get("to_user_id").and_then(|v| v.s.as_ref()).cloned();
+    let avatar_seed =
+        image.get("avatar_seed").and_then(|v| v.s.clone()).unwrap_or_else(|| user_id.clone());
+
+    // Create the message payload to broadcast
+    let message = ChatMessage {
+        core: MessageCore {
+            id: message_id.clone(),
+            room_id: room_id.clone(),
+            user_id,
+            username,
+            message_text: message_text.clone(),
+            created_at,
+        },
+        client_message_id,
+        ephemeral,
+        expires_at,
+        status: MessageStatus::Stored,
+        format,
+        client_created_at,
+        clock_skew_ms,
+        delivered_count: None,
+        client_seq,
+        visibility,
+        to_user_id,
+        link_preview: None,
+        avatar_seed: Some(avatar_seed),
+        edited_at,
+    };
+
+    // Emit message sent metrics; an edit was counted when it was sent
+    if !edit {
+        metrics.emit_message_sent(room_id, message_text.len()).await;
+    }
+
+    let index = async {
+        if let Some(search) = search {
+            if let Err(e) = search.index(&message).await {
+                warn!("Failed to index message {}: {}", message.core.id, e);
+            }
+        }
+    };
+    let (stats, ()) = tokio::join!(
+        broadcast::broadcast_and_notify(
+            ddb,
+            api_gateway,
+            connections_table,
+            &message,
+            webhook.filter(|_| !edit),
+            retry,
+            metrics,
+        ),
+        index
+    );
+    let stats = stats?;
+
+    // Emit broadcast metrics
+    metrics.emit_message_broadcast(room_id, stats.connections, stats.successful_sends).await;
+    metrics.emit_broadcast_stages(room_id, &stats.stages).await;
+    if edit {
+        metrics.emit_message_update_broadcast(room_id).await;
+    }
+
+    // Record the delivery count of a new message, then let the author know. The update is a
+    // MODIFY that leaves `edited_at` alone, which the stream filter and the edit check above
+    // both keep from coming back here.
+    if let Some(messages_table) = messages_table.filter(|_| !edit) {
+        let delivered_count = stats.successful_sends.max(0) as u32;
+        store::record_delivered_count(ddb, messages_table, &message, delivered_count).await?;
+        let receipt = DeliveryReceipt {
+            message_id: message.core.id.clone(),
+            room_id: room_id.clone(),
+            delivered_count,
+        };
+        broadcast::send_delivery_receipt(api_gateway, &stats.author_connections, &receipt).await;
+    }
+
+    // The preview comes last, so a slow page never holds up delivery. Encrypted messages
+    // aren't unfurled: the preview would be stored in plaintext beside the sealed text.
+    if let Some(unfurler) = unfurler.filter(|_| !encrypted) {
+        attach_link_preview(context, unfurler, message).await;
+    }
+    Ok(())
+}
+
+// Tell the room a message is gone, from a REMOVE's OldImage. TTL expiries are skipped, as
+// clients drop expiring messages themselves, and so are segments, claims and messages
+// compaction rolled into a segment, which are all still in the room's history. The text
+// isn't needed for the notice, so an encrypted message isn't opened.
+async fn process_removal(
+    context: &StreamContext<'_>,
+    record: DynamoDBRecord,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let StreamContext {
+        ddb, api_gateway, metrics, connections_table, retry, messages_table, ..
+    } = *context;
+    if record.user_identity.as_ref().is_some_and(UserIdentity::is_ttl_expiry) {
+        info!("Skipping TTL expiry");
+        return Ok(());
+    }
+    let Some(image) = record.dynamodb.and_then(|stream_record| stream_record.old_image) else {
+        warn!("Skipping REMOVE with no OldImage");
+        return Ok(());
+    };
+    if image.contains_key(compaction::SEGMENT_ATTRIBUTE)
+        || image.contains_key(store::CLIENT_CLAIM_ATTRIBUTE)
+    {
+        info!("Skipping removal of a segment or claim");
+        return Ok(());
+    }
+    let string = |name: &str| image.get(name).and_then(|v| v.s.clone());
+    let (Some(room_id), Some(message_id), Some(sk)) =
+        (string("room_id"), string("id"), string("sk"))
+    else {
+        warn!("Skipping REMOVE of an item that isn't a message");
+        return Ok(());
+    };
+    if let Some(messages_table) = messages_table {
+        if store::compacted_into_segment(ddb, messages_table, &room_id, &sk).await? {
+            info!("Skipping message {} compacted into a segment", message_id);
+            return Ok(());
+        }
+    }
+
+    // Enough of the message to tell who could see it
+    let user_id = string("user_id").unwrap_or_else(|| "unknown".to_string());
+    let message = ChatMessage {
+        core: MessageCore {
+            id: message_id,
+            room_id: room_id.clone(),
+            username: string("username").unwrap_or_else(|| display_name_fallback(Some(&user_id))),
+            user_id,
+            message_text: String::new(),
+            created_at: DateTime::default(),
+        },
+        client_message_id: None,
+        ephemeral: false,
+        expires_at: None,
+        status: MessageStatus::Stored,
+        format: Default::default(),
+        client_created_at: None,
+        clock_skew_ms: None,
+        delivered_count: None,
+        client_seq: None,
+        visibility: store::stored_visibility(string("visibility").as_deref()),
+        to_user_id: string("to_user_id"),
+        link_preview: None,
+        avatar_seed: None,
+        edited_at: None,
+    };
+    let stats =
+        broadcast::broadcast_deletion(ddb, api_gateway, connections_table, &message, retry).await?;
+
+    metrics.emit_message_broadcast(&room_id, stats.connections, stats.successful_sends).await;
+    metrics.emit_broadcast_stages(&room_id, &stats.stages).await;
+    metrics.emit_message_delete_broadcast(&room_id).await;
+    Ok(())
+}
+
+// Whether a MODIFY is an edit: `edit_message` sets `edited_at`, which nothing else touches
+fn is_edit(record: &DynamoDBStreamRecord) -> bool {
+    let edited_at = |image: &Option<HashMap<String, AttributeValueWrapper>>| {
+        image.as_ref()?.get("edited_at")?.n.clone()
+    };
+    let edited_at_now = edited_at(&record.new_image);
+    edited_at_now.is_some() && edited_at_now != edited_at(&record.old_image)
+}
+
+// Unfurl the message's first link, store the preview and broadcast the message again with it
+// attached, for clients to replace the copy they have. Best effort: a link that can't be
+// previewed is logged and the message goes without.
+async fn attach_link_preview(
+    context: &StreamContext<'_>,
+    unfurler: &Unfurler,
+    message: ChatMessage,
+) {
+    let Some(url) = unfurl::first_url(&message.core.message_text) else {
+        return;
+    };
+    let link_preview = match unfurler.unfurl(url).await {
+        Ok(link_preview) => link_preview,
+        Err(e) => {
+            info!("No link preview for message {}: {}", message.core.id, e);
+            return;
+        }
+    };
+    if let Some(messages_table) = context.messages_table {
+        let result =
+            store::record_link_preview(context.ddb, messages_table, &message, &link_preview).await;
+        if let Err(e) = result {
+            warn!("Failed to store link preview of message {}: {}", message.core.id, e);
+        }
+    }
+    let message = ChatMessage { link_preview: Some(link_preview), ..message };
+    let result = broadcast::broadcast_message(
+        context.ddb,
+        context.api_gateway,
+        context.connections_table,
+        &message,
+        None,
+    )
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to broadcast link preview of message {}: {}", message.core.id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        broadcast::ManagementClients,
+        ddb::Item,
+        handlers::{self, Tables},
+        request_context::RequestContext,
+        search::MemorySearchIndex,
+        store::{DynamoMessageStore, MessageStore},
+        test_support::{
+            create_connections_table, create_messages_table, create_table, local_config, serve,
+        },
+    };
+    use aws_sdk_apigatewaymanagement::Client as ApiGatewayClient;
+    use aws_sdk_dynamodb::types::{AttributeValue, KeyType};
+    use axum::{
+        body::Bytes,
+        extract::Path,
+        http::{HeaderMap, HeaderValue, StatusCode},
+        routing::post,
+        Router,
+    };
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use types::{MessageFormat, SendMessageRequest};
+
+    // Connection ids the stand-in management API answers as gone
+    const STALE: &str = "conn-stale";
+
+    struct Harness {
+        ddb: DynamoDbClient,
+        api_gateway: ManagementClients,
+        store: DynamoMessageStore,
+        messages_table: String,
+        connections_table: String,
+        // Connection id and body of every post the stand-in management API accepted
+        posted: Arc<Mutex<Vec<(String, Bytes)>>>,
+        // Domain of the stand-in management API, as connections record it
+        domain: String,
+        search: MemorySearchIndex,
+    }
+
+    // Fresh tables plus a stand-in API Gateway management API, with `connections` in "general"
+    async fn harness(prefix: &str, connections: &[&str]) -> Harness {
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let tables =
+            Tables { rooms: format!("{}-rooms", prefix), messages: format!("{}-messages", prefix) };
+        let connections_table = format!("{}-connections", prefix);
+        create_table(&ddb, &tables.rooms, &[("id", KeyType::Hash)]).await;
+        create_messages_table(&ddb, &tables.messages).await;
+        create_connections_table(&ddb, &connections_table).await;
+
+        let posted: Arc<Mutex<Vec<(String, Bytes)>>> = Arc::default();
+        let log = posted.clone();
+        let url = serve(Router::new().route(
+            "/prod/@connections/:connection_id",
+            post(move |Path(connection_id): Path<String>, body: Bytes| async move {
+                if connection_id == STALE {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("x-amzn-errortype", HeaderValue::from_static("GoneException"));
+                    return (StatusCode::GONE, headers, "{}");
+                }
+                log.lock().unwrap().push((connection_id, body));
+                (StatusCode::OK, HeaderMap::new(), "")
+            }),
+        ))
+        .await;
+
+        let harness = Harness {
+            api_gateway: ManagementClients::new(&config, ApiGatewayClient::new(&config)),
+            store: DynamoMessageStore::new(ddb.clone(), tables.clone()),
+            messages_table: tables.messages,
+            ddb,
+            connections_table,
+            posted,
+            domain: url.trim_start_matches("http://").to_string(),
+            search: MemorySearchIndex::default(),
+        };
+        for connection_id in connections {
+            harness.connect(connection_id, &format!("user-{}", connection_id)).await;
+        }
+        harness
+    }
+
+    impl Harness {
+        // A connection by `user_id` in "general", made through the stand-in API
+        async fn connect(&self, connection_id: &str, user_id: &str) {
+            self.ddb
+                .put_item()
+                .table_name(&self.connections_table)
+                .item("connection_id", AttributeValue::S(connection_id.to_string()))
+                .item("room_id", AttributeValue::S("general".to_string()))
+                .item("user_id", AttributeValue::S(user_id.to_string()))
+                .item("connected_at", AttributeValue::N("1".to_string()))
+                .item("transport", AttributeValue::S("apigw".to_string()))
+                .item("domain", AttributeValue::S(self.domain.clone()))
+                .item("stage", AttributeValue::S("prod".to_string()))
+                .send()
+                .await
+                .unwrap();
+        }
+
+        // Post through the REST handler, then feed the item it wrote to `process_record` as the
+        // INSERT record DynamoDB Streams would deliver
+        async fn post_and_stream(&self, request: SendMessageRequest) -> ChatMessage {
+            let posted = handlers::post_message_handler(
+                &self.store,
+                &RequestContext::default(),
+                request,
+                None,
+            )
+            .await
+            .unwrap()
+            .message;
+
+            let written = self.stored_item(&posted.core.id).await;
+            self.process(image_event("INSERT", "NewImage", &written)).await;
+            posted
+        }
+
+        // The messages table item for message `id` in "general"
+        async fn stored_item(&self, id: &str) -> Item {
+            let items = self
+                .ddb
+                .query()
+                .table_name(&self.messages_table)
+                .key_condition_expression("room_id = :room_id")
+                .expression_attribute_values(":room_id", AttributeValue::S("general".to_string()))
+                .send()
+                .await
+                .unwrap()
+                .items
+                .unwrap_or_default();
+            items
+                .into_iter()
+                .find(|item| item["id"] == AttributeValue::S(id.to_string()))
+                .expect("posted message should be written to the messages table")
+        }
+
+        // Run every record in `event` through `process_record`
+        async fn process(&self, event: DynamoDBStreamEvent) {
+            let metrics = MetricsHelper::new().await;
+            let context = StreamContext {
+                ddb: &self.ddb,
+                api_gateway: &self.api_gateway,
+                metrics: &metrics,
+                connections_table: &self.connections_table,
+                webhook: None,
+                retry: None,
+                search: Some(&self.search),
+                messages_table: Some(&self.messages_table),
+                cipher: None,
+                unfurler: None,
+            };
+            for record in event.records {
+                process_record(&context, record).await.unwrap();
+            }
+        }
+
+        async fn connection_exists(&self, connection_id: &str) -> bool {
+            self.ddb
+                .get_item()
+                .table_name(&self.connections_table)
+                .key("connection_id", AttributeValue::S(connection_id.to_string()))
+                .send()
+                .await
+                .unwrap()
+                .item
+                .is_some()
+        }
+    }
+
+    // The stream event for `item` as a record's `image` (NewImage or OldImage), in the JSON
+    // shape Lambda delivers it
+    fn image_event(event_name: &str, image: &str, item: &Item) -> DynamoDBStreamEvent {
+        let values: serde_json::Map<String, serde_json::Value> = item
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    AttributeValue::S(s) => json!({ "S": s }),
+                    AttributeValue::N(n) => json!({ "N": n }),
+                    AttributeValue::Bool(b) => json!({ "BOOL": b }),
+                    AttributeValue::Ss(values) => json!({ "SS": values }),
+                    other => panic!("Unexpected attribute type for {}: {:?}", name, other),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "Records": [{ "eventName": event_name, "dynamodb": { (image): values } }]
+        }))
+        .unwrap()
+    }
+
+    fn request(message_text: &str) -> SendMessageRequest {
+        SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: message_text.to_string(),
+            client_message_id: Some("client-1".to_string()),
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        }
+    }
+
+    // An INSERT record for message `id` in `room_id`
+    fn insert_record(room_id: &str, id: &str) -> DynamoDBRecord {
+        serde_json::from_value(json!({
+            "eventName": "INSERT",
+            "dynamodb": { "NewImage": { "room_id": { "S": room_id }, "id": { "S": id } } }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_only_modifies_that_change_edited_at_are_edits() {
+        let modify = |old: Option<&str>, new: Option<&str>| -> DynamoDBStreamRecord {
+            let image = |edited_at: Option<&str>| match edited_at {
+                Some(edited_at) => json!({ "id": { "S": "m1" }, "edited_at": { "N": edited_at } }),
+                None => json!({ "id": { "S": "m1" } }),
+            };
+            serde_json::from_value(json!({ "OldImage": image(old), "NewImage": image(new) }))
+                .unwrap()
+        };
+        assert!(is_edit(&modify(None, Some("1700000000000"))));
+        assert!(is_edit(&modify(Some("1700000000000"), Some("1700000005000"))));
+        // A delivery count, flag or link preview landing on a message edited earlier
+        assert!(!is_edit(&modify(Some("1700000000000"), Some("1700000000000"))));
+        assert!(!is_edit(&modify(None, None)));
+    }
+
+    #[tokio::test]
+    async fn test_records_without_a_message_are_skipped() {
+        // Nothing here may reach DynamoDB or the management API, which aren't running
+        let config = local_config().await;
+        let ddb = DynamoDbClient::new(&config);
+        let api_gateway = ManagementClients::new(&config, ApiGatewayClient::new(&config));
+        let metrics = MetricsHelper::new().await;
+        let context = StreamContext {
+            ddb: &ddb,
+            api_gateway: &api_gateway,
+            metrics: &metrics,
+            connections_table: "connections",
+            webhook: None,
+            retry: None,
+            search: None,
+            messages_table: Some("messages"),
+            cipher: None,
+            unfurler: None,
+        };
+        let message = json!({
+            "room_id": { "S": "general" },
+            "sk": { "S": "1700000000000#m1" },
+            "id": { "S": "m1" },
+        });
+        let records = [
+            json!({ "eventName": "INSERT" }),
+            json!({ "eventName": "INSERT", "dynamodb": {} }),
+            json!({
+                "eventName": "INSERT",
+                "dynamodb": { "NewImage": {
+                    "room_id": { "S": "general" },
+                    "sk": { "S": "1700000000000#m1" },
+                    "id": { "S": "m1" },
+                    "ts": { "N": "1700000000000" },
+                    "message_text": { "S": "Hello" },
+                    "migrated": { "BOOL": true },
+                } },
+            }),
+            json!({ "eventName": "REMOVE", "dynamodb": {} }),
+            json!({
+                "eventName": "REMOVE",
+                "userIdentity": { "type": "Service", "principalId": "dynamodb.amazonaws.com" },
+                "dynamodb": { "OldImage": message },
+            }),
+            json!({
+                "eventName": "REMOVE",
+                "dynamodb": { "OldImage": { "room_id": { "S": "general" }, "segment": { "B": "" } } },
+            }),
+            json!({ "eventName": "REMOVE", "dynamodb": { "OldImage": { "id": { "S": "m1" } } } }),
+        ];
+        for record in records {
+            let record: DynamoDBRecord = serde_json::from_value(record).unwrap();
+            process_record(&context, record).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_keep_room_order_while_rooms_run_concurrently() {
+        let records =
+            ["a1", "b1", "a2", "b2", "a3"].iter().map(|id| insert_record(&id[..1], id)).collect();
+        let events: Mutex<Vec<String>> = Mutex::default();
+
+        process_in_room_order(records, 2, |record| {
+            let id = record.dynamodb.unwrap().new_image.unwrap()["id"].s.clone().unwrap();
+            let events = &events;
+            async move {
+                events.lock().unwrap().push(format!("start {}", id));
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                events.lock().unwrap().push(format!("end {}", id));
+            }
+        })
+        .await;
+
+        let events = events.into_inner().unwrap();
+        let starts = |room: &str| -> Vec<String> {
+            events
+                .iter()
+                .filter_map(|event| event.strip_prefix("start "))
+                .filter(|id| id.starts_with(room))
+                .map(str::to_string)
+                .collect()
+        };
+        assert_eq!(starts("a"), vec!["a1", "a2", "a3"]);
+        assert_eq!(starts("b"), vec!["b1", "b2"]);
+        // Within a room each record finishes before the next starts...
+        let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+        assert!(position("end a1") < position("start a2"));
+        // ...while the other room's first record started before it finished
+        assert!(position("start b1") < position("end a1"));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_posted_message_is_broadcast_from_its_stream_record() {
+        let harness = harness("stream-test", &["conn-1", "conn-2"]).await;
+        let posted = harness.post_and_stream(request("Hello, stream!")).await;
+
+        let mut posted_to = harness.posted.lock().unwrap().clone();
+        posted_to.sort_by(|a, b| a.0.cmp(&b.0));
+        let ids: Vec<_> = posted_to.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["conn-1", "conn-2"]);
+
+        // What connections receive matches what was written, field for field
+        for (_, body) in &posted_to {
+            let received: ChatMessage = serde_json::from_slice(body).unwrap();
+            assert_eq!(received.core.id, posted.core.id);
+            assert_eq!(received.core.room_id, "general");
+            assert_eq!(received.core.user_id, "01ARZ3NDEKTSV4RRFFQ69G5FB1");
+            assert_eq!(received.core.username, "alice");
+            assert_eq!(received.core.message_text, "Hello, stream!");
+            // Stored at millisecond precision
+            assert_eq!(
+                received.core.created_at.timestamp_millis(),
+                posted.core.created_at.timestamp_millis()
+            );
+            assert_eq!(received.client_message_id.as_deref(), Some("client-1"));
+            assert_eq!(received.status, MessageStatus::Broadcast);
+            assert_eq!(received.format, MessageFormat::Plain);
+            assert!(!received.ephemeral);
+        }
+
+        // And it was indexed on the way through
+        let hits = harness.search.search("general", "stream", 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].core.id, posted.core.id);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_delivery_count_is_recorded_and_sent_to_the_author() {
+        let harness = harness("stream-delivery-test", &["conn-1", "conn-2", STALE]).await;
+        harness.connect("conn-author", "01ARZ3NDEKTSV4RRFFQ69G5FB1").await;
+        let posted = harness.post_and_stream(request("Did everyone get this?")).await;
+
+        // Three live connections got it; the stale one doesn't count
+        let stored = harness.store.get_message("general", &posted.core.id).await.unwrap().unwrap();
+        assert_eq!(stored.delivered_count, Some(3));
+
+        // The author's connection gets the message, then the receipt
+        let to_author: Vec<Bytes> = harness
+            .posted
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| id == "conn-author")
+            .map(|(_, body)| body.clone())
+            .collect();
+        assert_eq!(to_author.len(), 2);
+        let receipt: serde_json::Value = serde_json::from_slice(&to_author[1]).unwrap();
+        assert_eq!(
+            receipt,
+            json!({
+                "type": "delivery",
+                "message_id": posted.core.id,
+                "room_id": "general",
+                "delivered_count": 3,
+            })
+        );
+
+        // Nobody else gets a receipt
+        assert_eq!(harness.posted.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_deletions_are_broadcast_but_compaction_is_not() {
+        let harness = harness("stream-delete-test", &["conn-1", "conn-2"]).await;
+        let older = harness.post_and_stream(request("Old news")).await;
+        let newer = harness
+            .post_and_stream(SendMessageRequest {
+                client_message_id: Some("client-2".to_string()),
+                ..request("Oops")
+            })
+            .await;
+        harness.posted.lock().unwrap().clear();
+
+        // The poster deletes the newer message
+        let removed = harness.stored_item(&newer.core.id).await;
+        assert!(harness.store.delete_message(&newer).await.unwrap());
+        harness.process(image_event("REMOVE", "OldImage", &removed)).await;
+
+        let mut posted_to = harness.posted.lock().unwrap().clone();
+        posted_to.sort_by(|a, b| a.0.cmp(&b.0));
+        let ids: Vec<_> = posted_to.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["conn-1", "conn-2"]);
+        for (_, body) in &posted_to {
+            let notice: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(
+                notice,
+                json!({ "type": "message_deleted", "room_id": "general", "message_id": newer.core.id })
+            );
+        }
+        harness.posted.lock().unwrap().clear();
+
+        // Compaction rolls the older one into a segment, then removes its item
+        let compacted = harness.stored_item(&older.core.id).await;
+        let sk = store::message_sort_key(&older);
+        let segment = compaction::encode_segment(std::slice::from_ref(&older)).unwrap();
+        harness
+            .ddb
+            .put_item()
+            .table_name(&harness.messages_table)
+            .set_item(Some(compaction::segment_item("general", &sk, &sk, 1, segment)))
+            .send()
+            .await
+            .unwrap();
+        harness
+            .ddb
+            .delete_item()
+            .table_name(&harness.messages_table)
+            .key("room_id", AttributeValue::S("general".to_string()))
+            .key("sk", AttributeValue::S(sk))
+            .send()
+            .await
+            .unwrap();
+        harness.process(image_event("REMOVE", "OldImage", &compacted)).await;
+        assert!(harness.posted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_stale_connection_is_removed_during_stream_broadcast() {
+        let harness = harness("stream-stale-test", &["conn-live", STALE]).await;
+        harness.post_and_stream(request("Anyone there?")).await;
+
+        let posted_to: Vec<_> =
+            harness.posted.lock().unwrap().iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(posted_to, vec!["conn-live"]);
+        assert!(harness.connection_exists("conn-live").await);
+        assert!(!harness.connection_exists(STALE).await);
+    }
+}
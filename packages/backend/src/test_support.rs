@@ -0,0 +1,198 @@
+// Shared helpers for tests that run against a local DynamoDB
+use crate::{encryption::DataKeys, metric_sink::MetricSink};
+use async_trait::async_trait;
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::{
+    types::{
+        AttributeDefinition, BillingMode, GlobalSecondaryIndex, KeySchemaElement, KeyType,
+        Projection, ProjectionType, ScalarAttributeType,
+    },
+    Client as DynamoDbClient,
+};
+use std::{collections::HashMap, env, net::SocketAddr, sync::Mutex};
+
+pub fn key(name: &str, key_type: KeyType) -> KeySchemaElement {
+    KeySchemaElement::builder().attribute_name(name).key_type(key_type).build().unwrap()
+}
+
+// Config for a local DynamoDB (e.g. `docker run -p 8000:8000 amazon/dynamodb-local`)
+pub async fn local_config() -> SdkConfig {
+    let endpoint =
+        env::var("DYNAMODB_ENDPOINT").unwrap_or_else(|_| "http://localhost:8000".to_string());
+    aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .region(aws_config::Region::new("us-east-1"))
+        .credentials_provider(aws_sdk_dynamodb::config::Credentials::new(
+            "test", "test", None, None, "local",
+        ))
+        .load()
+        .await
+}
+
+pub async fn local_ddb() -> DynamoDbClient {
+    DynamoDbClient::new(&local_config().await)
+}
+
+pub async fn create_table(ddb: &DynamoDbClient, name: &str, keys: &[(&str, KeyType)]) {
+    let _ = ddb.delete_table().table_name(name).send().await;
+    let mut request = ddb.create_table().table_name(name).billing_mode(BillingMode::PayPerRequest);
+    for (attribute, key_type) in keys {
+        let attribute_type =
+            if *attribute == "ts" { ScalarAttributeType::N } else { ScalarAttributeType::S };
+        request = request
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name(*attribute)
+                    .attribute_type(attribute_type)
+                    .build()
+                    .unwrap(),
+            )
+            .key_schema(key(attribute, key_type.clone()));
+    }
+    request.send().await.unwrap();
+}
+
+// Messages table keyed by (room_id, sk), with the `client-message-index` GSI posts look
+// retries up in
+pub async fn create_messages_table(ddb: &DynamoDbClient, name: &str) {
+    let _ = ddb.delete_table().table_name(name).send().await;
+    let attribute = |name: &str| {
+        AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(ScalarAttributeType::S)
+            .build()
+            .unwrap()
+    };
+    ddb.create_table()
+        .table_name(name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attribute("room_id"))
+        .attribute_definitions(attribute("sk"))
+        .attribute_definitions(attribute("client_message_id"))
+        .key_schema(key("room_id", KeyType::Hash))
+        .key_schema(key("sk", KeyType::Range))
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(crate::store::CLIENT_MESSAGE_INDEX)
+                .key_schema(key("room_id", KeyType::Hash))
+                .key_schema(key("client_message_id", KeyType::Range))
+                .projection(Projection::builder().projection_type(ProjectionType::All).build())
+                .build()
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+}
+
+// Connections table with the `room-index` GSI used for fan-out and the `user-index` GSI used
+// for per-user connection limits and lookups
+pub async fn create_connections_table(ddb: &DynamoDbClient, name: &str) {
+    let _ = ddb.delete_table().table_name(name).send().await;
+    let attribute = |name: &str, attribute_type: ScalarAttributeType| {
+        AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(attribute_type)
+            .build()
+            .unwrap()
+    };
+    ddb.create_table()
+        .table_name(name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attribute("connection_id", ScalarAttributeType::S))
+        .attribute_definitions(attribute("room_id", ScalarAttributeType::S))
+        .attribute_definitions(attribute("connected_at", ScalarAttributeType::N))
+        .attribute_definitions(attribute("user_id", ScalarAttributeType::S))
+        .key_schema(key("connection_id", KeyType::Hash))
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name("room-index")
+                .key_schema(key("room_id", KeyType::Hash))
+                .key_schema(key("connected_at", KeyType::Range))
+                .projection(Projection::builder().projection_type(ProjectionType::All).build())
+                .build()
+                .unwrap(),
+        )
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name("user-index")
+                .key_schema(key("user_id", KeyType::Hash))
+                .key_schema(key("connected_at", KeyType::Range))
+                .projection(Projection::builder().projection_type(ProjectionType::KeysOnly).build())
+                .build()
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+}
+
+// Rooms table keyed by id, with the `created-index` GSI the room listing pages through
+pub async fn create_rooms_table(ddb: &DynamoDbClient, name: &str) {
+    let _ = ddb.delete_table().table_name(name).send().await;
+    let attribute = |name: &str, attribute_type: ScalarAttributeType| {
+        AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(attribute_type)
+            .build()
+            .unwrap()
+    };
+    ddb.create_table()
+        .table_name(name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attribute("id", ScalarAttributeType::S))
+        .attribute_definitions(attribute("listing", ScalarAttributeType::S))
+        .attribute_definitions(attribute("created_at_epoch", ScalarAttributeType::N))
+        .key_schema(key("id", KeyType::Hash))
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(crate::store::ROOMS_BY_CREATED_INDEX)
+                .key_schema(key("listing", KeyType::Hash))
+                .key_schema(key("created_at_epoch", KeyType::Range))
+                .projection(Projection::builder().projection_type(ProjectionType::All).build())
+                .build()
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+}
+
+// Serve `router` on an ephemeral local port and return its base URL
+pub async fn serve(router: axum::Router) -> String {
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service()));
+    format!("http://{}", addr)
+}
+
+// Name, value and dimensions of one emitted metric
+pub type DataPoint = (String, f64, HashMap<String, String>);
+
+// Metric sink that keeps every data point for assertions
+#[derive(Default)]
+pub struct Capture(pub Mutex<Vec<DataPoint>>);
+
+impl MetricSink for Capture {
+    fn emit(&self, name: &str, value: f64, _unit: &str, dimensions: &HashMap<String, String>) {
+        self.0.lock().unwrap().push((name.to_string(), value, dimensions.clone()));
+    }
+}
+
+// Data keys wrapped by XOR with a fixed mask, standing in for KMS
+pub struct StaticDataKeys;
+
+const DATA_KEY_MASK: u8 = 0x5a;
+
+#[async_trait]
+impl DataKeys for StaticDataKeys {
+    async fn generate(&self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let plaintext: Vec<u8> = (0..32).collect();
+        let wrapped = plaintext.iter().map(|byte| byte ^ DATA_KEY_MASK).collect();
+        Ok((plaintext, wrapped))
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(wrapped.iter().map(|byte| byte ^ DATA_KEY_MASK).collect())
+    }
+}
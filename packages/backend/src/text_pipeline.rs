@@ -0,0 +1,185 @@
+use std::env;
+use tracing::warn;
+
+// Emoji shortcodes understood by the `emoji` transform
+const EMOJI_SHORTCODES: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("laughing", "😆"),
+    ("wink", "😉"),
+    ("cry", "😢"),
+    ("heart", "❤️"),
+    ("thumbsup", "👍"),
+    ("thumbsdown", "👎"),
+    ("tada", "🎉"),
+    ("fire", "🔥"),
+    ("rocket", "🚀"),
+    ("eyes", "👀"),
+    ("wave", "👋"),
+];
+
+/// A single step applied to message text before it is stored
+pub trait TextTransform: Send + Sync {
+    /// Name used to enable the transform in `TEXT_TRANSFORMS`
+    fn name(&self) -> &'static str;
+
+    fn apply(&self, text: String) -> String;
+}
+
+// Strip leading and trailing whitespace
+pub struct Trim;
+
+impl TextTransform for Trim {
+    fn name(&self) -> &'static str {
+        "trim"
+    }
+
+    fn apply(&self, text: String) -> String {
+        text.trim().to_string()
+    }
+}
+
+// Replace every run of whitespace (including newlines) with a single space
+pub struct CollapseWhitespace;
+
+impl TextTransform for CollapseWhitespace {
+    fn name(&self) -> &'static str {
+        "collapse_ws"
+    }
+
+    fn apply(&self, text: String) -> String {
+        let mut collapsed = String::with_capacity(text.len());
+        let mut in_whitespace = false;
+        for c in text.chars() {
+            if c.is_whitespace() {
+                if !in_whitespace {
+                    collapsed.push(' ');
+                }
+                in_whitespace = true;
+            } else {
+                collapsed.push(c);
+                in_whitespace = false;
+            }
+        }
+        collapsed
+    }
+}
+
+// Expand known `:shortcode:`s to emoji, leaving unknown ones as typed
+pub struct EmojiShortcodes;
+
+impl TextTransform for EmojiShortcodes {
+    fn name(&self) -> &'static str {
+        "emoji"
+    }
+
+    fn apply(&self, text: String) -> String {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+
+        while let Some(start) = rest.find(':') {
+            expanded.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let emoji = after.find(':').and_then(|end| {
+                let code = &after[..end];
+                EMOJI_SHORTCODES
+                    .iter()
+                    .find(|(name, _)| *name == code)
+                    .map(|(_, emoji)| (*emoji, end))
+            });
+
+            match emoji {
+                Some((emoji, end)) => {
+                    expanded.push_str(emoji);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    // Not a shortcode; keep the colon and carry on from the next character
+                    expanded.push(':');
+                    rest = after;
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+fn builtin(name: &str) -> Option<Box<dyn TextTransform>> {
+    match name {
+        "trim" => Some(Box::new(Trim)),
+        "collapse_ws" => Some(Box::new(CollapseWhitespace)),
+        "emoji" => Some(Box::new(EmojiShortcodes)),
+        _ => None,
+    }
+}
+
+/// Ordered text transforms applied to every posted message
+#[derive(Default)]
+pub struct TextPipeline {
+    transforms: Vec<Box<dyn TextTransform>>,
+}
+
+impl TextPipeline {
+    pub fn new(transforms: Vec<Box<dyn TextTransform>>) -> Self {
+        Self { transforms }
+    }
+
+    /// Build from a comma-separated list of built-in names, e.g. `trim,collapse_ws,emoji`.
+    /// Unknown names are logged and skipped; an empty spec (or `none`) disables processing.
+    pub fn from_spec(spec: &str) -> Self {
+        let transforms = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "none")
+            .filter_map(|name| {
+                let transform = builtin(name);
+                if transform.is_none() {
+                    warn!("Ignoring unknown text transform '{}'", name);
+                }
+                transform
+            })
+            .collect();
+        Self::new(transforms)
+    }
+
+    /// Read `TEXT_TRANSFORMS`; unset means no transforms
+    pub fn from_env() -> Self {
+        Self::from_spec(&env::var("TEXT_TRANSFORMS").unwrap_or_default())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.transforms.iter().map(|transform| transform.name()).collect()
+    }
+
+    pub fn apply(&self, text: String) -> String {
+        self.transforms.iter().fold(text, |text, transform| transform.apply(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emoji_shortcodes_expand() {
+        let pipeline = TextPipeline::from_spec("emoji");
+        assert_eq!(pipeline.apply("hi :smile: :nope: 10:30".to_string()), "hi 😄 :nope: 10:30");
+        assert_eq!(pipeline.apply(":tada::fire:".to_string()), "🎉🔥");
+    }
+
+    #[test]
+    fn test_whitespace_is_collapsed_in_order() {
+        let pipeline = TextPipeline::from_spec("collapse_ws, trim, bogus");
+        assert_eq!(pipeline.names(), vec!["collapse_ws", "trim"]);
+        assert_eq!(pipeline.apply("  hello \n\n  there\t world ".to_string()), "hello there world");
+    }
+
+    #[test]
+    fn test_empty_pipeline_is_a_no_op() {
+        for spec in ["", "none"] {
+            let pipeline = TextPipeline::from_spec(spec);
+            assert!(pipeline.names().is_empty());
+            assert_eq!(pipeline.apply("  :smile:  a  ".to_string()), "  :smile:  a  ");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, Instant},
+};
+use types::TypingIndicator;
+
+const DEFAULT_TYPING_TIMEOUT_MS: u64 = 5_000;
+
+// Active typing indicators keyed by (room_id, user_id). A client that starts typing and then
+// disappears never sends `is_typing: false`, so each entry carries a deadline and `sweep`
+// produces the clearing broadcast once it passes.
+#[derive(Debug)]
+pub struct TypingTracker {
+    timeout: Duration,
+    active: HashMap<(String, String), (String, Instant)>,
+}
+
+impl TypingTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, active: HashMap::new() }
+    }
+
+    pub fn from_env() -> Self {
+        let timeout_ms = env::var("TYPING_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_TYPING_TIMEOUT_MS);
+        Self::new(Duration::from_millis(timeout_ms))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Record an indicator from a client; a repeated start refreshes the deadline
+    pub fn update(&mut self, indicator: &TypingIndicator, now: Instant) {
+        let key = (indicator.room_id.clone(), indicator.user_id.clone());
+        if indicator.is_typing {
+            self.active.insert(key, (indicator.username.clone(), now + self.timeout));
+        } else {
+            self.active.remove(&key);
+        }
+    }
+
+    /// Drop every indicator past its deadline, returning the `is_typing: false` broadcasts
+    pub fn sweep(&mut self, now: Instant) -> Vec<TypingIndicator> {
+        let expired: Vec<(String, String)> = self
+            .active
+            .iter()
+            .filter(|(_, (_, expires_at))| *expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|key| {
+                let (username, _) = self.active.remove(&key)?;
+                let (room_id, user_id) = key;
+                Some(TypingIndicator { room_id, user_id, username, is_typing: false })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indicator(is_typing: bool) -> TypingIndicator {
+        TypingIndicator {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            is_typing,
+        }
+    }
+
+    #[test]
+    fn test_typing_start_without_follow_up_is_cleared() {
+        let mut tracker = TypingTracker::new(Duration::from_millis(100));
+        let start = Instant::now();
+        tracker.update(&indicator(true), start);
+
+        assert!(tracker.sweep(start + Duration::from_millis(50)).is_empty());
+
+        let cleared = tracker.sweep(start + Duration::from_millis(100));
+        assert_eq!(cleared, vec![indicator(false)]);
+
+        // Only cleared once
+        assert!(tracker.sweep(start + Duration::from_millis(200)).is_empty());
+    }
+
+    #[test]
+    fn test_typing_refresh_and_explicit_stop() {
+        let mut tracker = TypingTracker::new(Duration::from_millis(100));
+        let start = Instant::now();
+        tracker.update(&indicator(true), start);
+        tracker.update(&indicator(true), start + Duration::from_millis(80));
+
+        assert!(tracker.sweep(start + Duration::from_millis(150)).is_empty());
+
+        tracker.update(&indicator(false), start + Duration::from_millis(160));
+        assert!(tracker.sweep(start + Duration::from_secs(1)).is_empty());
+    }
+}
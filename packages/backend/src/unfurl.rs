@@ -0,0 +1,342 @@
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE, LOCATION},
+    redirect::Policy,
+    Client as HttpClient, Url,
+};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use types::LinkPreview;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_BYTES: usize = 256 * 1024;
+const MAX_REDIRECTS: usize = 3;
+// Longer titles and descriptions are cut, so a page can't bloat every copy of the message
+const MAX_TEXT_CHARS: usize = 300;
+
+/// Fetches OpenGraph previews of links in messages (`ENABLE_UNFURL`, with `UNFURL_TIMEOUT_MS`
+/// and `UNFURL_MAX_BYTES`). Only public addresses are fetched: every address a host resolves
+/// to is checked, the request is pinned to those addresses, and redirects are followed by hand
+/// so each hop is checked too.
+pub struct Unfurler {
+    // Covers the whole unfurl, redirects and body included
+    timeout: Duration,
+    // Bytes of the page read; OpenGraph tags sit in the head, so the rest isn't needed
+    max_bytes: usize,
+    allow: fn(IpAddr) -> bool,
+}
+
+impl Unfurler {
+    pub fn new(timeout: Duration, max_bytes: usize) -> Self {
+        Self { timeout, max_bytes, allow: is_public }
+    }
+
+    /// None unless `ENABLE_UNFURL` is set
+    pub fn from_env() -> Option<Self> {
+        let enabled = env::var("ENABLE_UNFURL")
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+        if !enabled {
+            return None;
+        }
+        let timeout = env::var("UNFURL_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let max_bytes = env::var("UNFURL_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        Some(Self::new(timeout, max_bytes))
+    }
+
+    /// Preview of the page at `url`. Fails for private addresses, pages that aren't HTML or
+    /// have nothing to preview, and anything slower than the timeout.
+    pub async fn unfurl(&self, url: &str) -> Result<LinkPreview, String> {
+        tokio::time::timeout(self.timeout, self.fetch(url))
+            .await
+            .map_err(|_| format!("Timed out unfurling {}", url))?
+    }
+
+    async fn fetch(&self, url: &str) -> Result<LinkPreview, String> {
+        let mut url = Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+        for _ in 0..=MAX_REDIRECTS {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(format!("Refusing to unfurl {}: not http(s)", url));
+            }
+            let host = url.host_str().ok_or_else(|| format!("No host in {}", url))?.to_string();
+            let addrs = self.resolve(&url, &host).await?;
+
+            let response = HttpClient::builder()
+                .redirect(Policy::none())
+                .resolve_to_addrs(&host, &addrs)
+                .timeout(self.timeout)
+                .build()
+                .map_err(|e| e.to_string())?
+                .get(url.clone())
+                .header(ACCEPT, "text/html")
+                .send()
+                .await
+                .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or_else(|| format!("Redirect from {} has no location", url))?;
+                url =
+                    url.join(location).map_err(|e| format!("Bad redirect from {}: {}", url, e))?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(format!("{} responded with status {}", url, response.status()));
+            }
+            let is_html = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.contains("text/html"));
+            if !is_html {
+                return Err(format!("{} isn't an HTML page", url));
+            }
+
+            let mut response = response;
+            let mut body = Vec::new();
+            while body.len() < self.max_bytes {
+                match response.chunk().await.map_err(|e| e.to_string())? {
+                    Some(chunk) => body.extend_from_slice(&chunk),
+                    None => break,
+                }
+            }
+            body.truncate(self.max_bytes);
+            return parse_preview(&url, &String::from_utf8_lossy(&body))
+                .ok_or_else(|| format!("{} has nothing to preview", url));
+        }
+        Err(format!("Too many redirects unfurling {}", url))
+    }
+
+    // Every address the host resolves to, provided they're all allowed
+    async fn resolve(&self, url: &Url, host: &str) -> Result<Vec<SocketAddr>, String> {
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(format!("{} doesn't resolve", host));
+        }
+        if let Some(refused) = addrs.iter().find(|addr| !(self.allow)(addr.ip())) {
+            return Err(format!("Refusing to unfurl {}: {} isn't public", host, refused.ip()));
+        }
+        Ok(addrs)
+    }
+}
+
+/// False for loopback, private, link-local, shared (CGNAT) and other non-routable addresses,
+/// including IPv4 ones written as IPv6
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The first http(s) link in `text`, without the punctuation a sentence wraps it in
+pub fn first_url(text: &str) -> Option<&str> {
+    text.split_whitespace()
+        .map(|word| word.trim_start_matches(['<', '(', '[', '"', '\'']))
+        .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .map(|url| url.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']))
+}
+
+// OpenGraph title, description and image, falling back to the <title>. None when there's
+// neither a title nor a description.
+fn parse_preview(url: &Url, html: &str) -> Option<LinkPreview> {
+    let mut title = None;
+    let mut description = None;
+    let mut image = None;
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<meta").map(|start| rest + start) {
+        let end = lower[start..].find('>').map_or(html.len(), |end| start + end);
+        let tag = &html[start..end];
+        rest = end;
+        let property = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+        let Some(content) = attribute(tag, "content") else { continue };
+        match property.map(|property| property.to_ascii_lowercase()).as_deref() {
+            Some("og:title") => title = title.or(Some(content)),
+            Some("og:description") => description = description.or(Some(content)),
+            Some("description") if description.is_none() => description = Some(content),
+            Some("og:image") => image = image.or(Some(content)),
+            _ => {}
+        }
+    }
+    let title = title.or_else(|| {
+        let open = lower.find("<title")?;
+        let start = open + lower[open..].find('>')? + 1;
+        let end = start + lower[start..].find("</title")?;
+        Some(html[start..end].to_string())
+    });
+
+    let text = |value: String| {
+        let value = decode_entities(value.trim());
+        (!value.is_empty()).then(|| value.chars().take(MAX_TEXT_CHARS).collect::<String>())
+    };
+    let title = title.and_then(text);
+    let description = description.and_then(text);
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    // Relative images are resolved against the page; anything that isn't http(s) is dropped
+    let image = image
+        .and_then(|image| url.join(decode_entities(image.trim()).as_str()).ok())
+        .filter(|image| image.scheme() == "http" || image.scheme() == "https")
+        .map(String::from);
+    Some(LinkPreview { url: url.to_string(), title, description, image })
+}
+
+// Value of `name="..."` (or single-quoted) in a tag
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name).map(|found| from + found) {
+        from = found + name.len();
+        let preceded = lower[..found].ends_with(|c: char| c.is_ascii_whitespace());
+        let value = lower[from..].trim_start();
+        if !preceded || !value.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - value[1..].trim_start().len();
+        let quote = tag[value_start..].chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let value = &tag[value_start + 1..];
+        return value.find(quote).map(|end| value[..end].to_string());
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve;
+    use axum::{response::Html, routing::get, Router};
+
+    fn unfurler() -> Unfurler {
+        Unfurler::new(Duration::from_secs(2), DEFAULT_MAX_BYTES)
+    }
+
+    #[test]
+    fn test_first_url_skips_text_and_trailing_punctuation() {
+        assert_eq!(first_url("see https://example.com/a?b=1."), Some("https://example.com/a?b=1"));
+        assert_eq!(
+            first_url("(http://example.com) and https://b.example"),
+            Some("http://example.com")
+        );
+        assert_eq!(first_url("ftp://example.com example.com"), None);
+    }
+
+    #[test]
+    fn test_private_addresses_are_not_public() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{} should be refused", private);
+        }
+        for public in ["93.184.216.34", "8.8.8.8", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_public(public.parse().unwrap()), "{} should be allowed", public);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_page_yields_a_preview() {
+        let page = r#"<html><head><title>Fallback</title>
+            <meta property="og:title" content="Launch &amp; learn">
+            <meta name='description' content='Everything about the launch'>
+            <meta property="og:image" content="/cover.png" />
+            </head><body>...</body></html>"#;
+        let base = serve(
+            Router::new()
+                .route("/post", get(move || async move { Html(page) }))
+                .route("/moved", get(|| async { axum::response::Redirect::temporary("/post") })),
+        )
+        .await;
+        // The stand-in page is on loopback, so let this unfurler through to it
+        let unfurler = Unfurler { allow: |_| true, ..unfurler() };
+
+        let preview = unfurler.unfurl(&format!("{}/moved", base)).await.unwrap();
+        assert_eq!(
+            preview,
+            LinkPreview {
+                url: format!("{}/post", base),
+                title: Some("Launch & learn".to_string()),
+                description: Some("Everything about the launch".to_string()),
+                image: Some(format!("{}/cover.png", base)),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_address_is_refused() {
+        let base =
+            serve(Router::new().route("/", get(|| async { Html("<title>Hi</title>") }))).await;
+        let port = base.rsplit(':').next().unwrap();
+
+        for url in [
+            base.clone(),
+            format!("http://localhost:{}/", port),
+            format!("http://[::1]:{}/", port),
+            "http://169.254.169.254/latest/meta-data/".to_string(),
+        ] {
+            let error = unfurler().unfurl(&url).await.unwrap_err();
+            assert!(error.contains("isn't public"), "{}: {}", url, error);
+        }
+    }
+}
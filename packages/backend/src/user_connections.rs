@@ -0,0 +1,166 @@
+use crate::ddb::{Item, ItemReader};
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, KeysAndAttributes},
+    Client as DynamoDbClient,
+};
+use std::collections::HashMap;
+use tracing::warn;
+use types::{ConnectionId, RoomId};
+
+// BatchGetItem's limit on keys per request
+const BATCH_GET_LIMIT: usize = 100;
+
+/// One of a user's open connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserConnection {
+    pub connection_id: ConnectionId,
+    pub room_id: RoomId,
+    pub connected_at: i64,
+}
+
+/// Every connection the user currently holds, across rooms, oldest first. The `user-index` GSI
+/// only projects keys, so the ids it returns are read back from the table itself; that also
+/// drops connections removed since the index last caught up.
+pub async fn query_user_connections(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    user_id: &str,
+) -> Result<Vec<UserConnection>, String> {
+    let mut pages = ddb
+        .query()
+        .table_name(connections_table)
+        .index_name("user-index")
+        .key_condition_expression("user_id = :user_id")
+        .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+        .into_paginator()
+        .send();
+
+    let mut keys = Vec::new();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| format!("Failed to query user connections: {:?}", e))?;
+        for item in page.items() {
+            if let Some(connection_id) = item.get("connection_id") {
+                keys.push(HashMap::from([("connection_id".to_string(), connection_id.clone())]));
+            }
+        }
+    }
+
+    let mut connections = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(BATCH_GET_LIMIT) {
+        for item in batch_get(ddb, connections_table, chunk.to_vec()).await? {
+            match user_connection(&item) {
+                Some(connection) => connections.push(connection),
+                None => warn!("Skipping unreadable connection item for user {}", user_id),
+            }
+        }
+    }
+    connections.sort_by_key(|connection| connection.connected_at);
+    Ok(connections)
+}
+
+// Items for `keys`, re-requesting any DynamoDB leaves unprocessed
+async fn batch_get(
+    ddb: &DynamoDbClient,
+    table: &str,
+    mut keys: Vec<Item>,
+) -> Result<Vec<Item>, String> {
+    let mut items = Vec::new();
+    while !keys.is_empty() {
+        let request = KeysAndAttributes::builder()
+            .set_keys(Some(keys))
+            .projection_expression("connection_id, room_id, connected_at")
+            .build()
+            .map_err(|e| e.to_string())?;
+        let output = ddb
+            .batch_get_item()
+            .request_items(table, request)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to read user connections: {:?}", e))?;
+
+        if let Some(mut responses) = output.responses {
+            items.extend(responses.remove(table).unwrap_or_default());
+        }
+        keys = output
+            .unprocessed_keys
+            .and_then(|mut unprocessed| unprocessed.remove(table))
+            .map(|request| request.keys)
+            .unwrap_or_default();
+    }
+    Ok(items)
+}
+
+fn user_connection(item: &Item) -> Option<UserConnection> {
+    let reader = ItemReader::new(item);
+    Some(UserConnection {
+        connection_id: ConnectionId::from(reader.string("connection_id").ok()??.as_str()),
+        room_id: RoomId::from(reader.string("room_id").ok()??.as_str()),
+        connected_at: reader.number("connected_at").ok()??,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_connections_table, local_ddb};
+
+    async fn connect(
+        ddb: &DynamoDbClient,
+        table: &str,
+        connection_id: &str,
+        room_id: &str,
+        user_id: &str,
+        connected_at: i64,
+    ) {
+        ddb.put_item()
+            .table_name(table)
+            .item("connection_id", AttributeValue::S(connection_id.to_string()))
+            .item("room_id", AttributeValue::S(room_id.to_string()))
+            .item("user_id", AttributeValue::S(user_id.to_string()))
+            .item("connected_at", AttributeValue::N(connected_at.to_string()))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_user_connections_span_rooms() {
+        let ddb = local_ddb().await;
+        let table = "user-connections-test";
+        create_connections_table(&ddb, table).await;
+
+        connect(&ddb, table, "conn-1", "general", "user-1", 1).await;
+        connect(&ddb, table, "conn-2", "random", "user-1", 2).await;
+        connect(&ddb, table, "conn-3", "general", "user-2", 3).await;
+
+        let connections = query_user_connections(&ddb, table, "user-1").await.unwrap();
+        assert_eq!(
+            connections,
+            vec![
+                UserConnection {
+                    connection_id: "conn-1".into(),
+                    room_id: "general".into(),
+                    connected_at: 1,
+                },
+                UserConnection {
+                    connection_id: "conn-2".into(),
+                    room_id: "random".into(),
+                    connected_at: 2,
+                },
+            ]
+        );
+
+        // Disconnecting removes the connection
+        ddb.delete_item()
+            .table_name(table)
+            .key("connection_id", AttributeValue::S("conn-1".to_string()))
+            .send()
+            .await
+            .unwrap();
+        let connections = query_user_connections(&ddb, table, "user-1").await.unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].room_id, RoomId::from("random"));
+        assert!(query_user_connections(&ddb, table, "nobody").await.unwrap().is_empty());
+    }
+}
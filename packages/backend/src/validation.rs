@@ -0,0 +1,389 @@
+use crate::handlers::{
+    validate_avatar_seed, validate_client_seq, validate_expires_in, validate_flag_reason,
+    validate_format, validate_message_text, validate_room_id, validate_to_user_id, validate_topic,
+    validate_user_id, validate_username, MAX_LATEST_PER_ROOM, MAX_LATEST_ROOMS,
+};
+use std::{env, fmt};
+use types::{
+    ApiError, EditMessageRequest, FlagMessageRequest, LatestMessagesRequest, SendMessageRequest,
+    UpdateAvatarSeedRequest, UpdateTopicRequest, UpdateUsernameRequest,
+};
+
+const DEFAULT_MAX_USERNAME_LENGTH: usize = 50;
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 500;
+const DEFAULT_MAX_TOPIC_LENGTH: usize = 200;
+const DEFAULT_MAX_USER_ID_LENGTH: usize = 128;
+// Covers UUIDs, ULIDs and provider subjects like `auth0|abc123` or `google-oauth2|123`
+const DEFAULT_USER_ID_PUNCTUATION: &str = "-_.:|@";
+
+/// Longest value accepted for each length-limited field. Violations report the limit and the
+/// value's length, so clients can tell users exactly how far over they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub username: usize,
+    // Also the longest search text
+    pub message_text: usize,
+    pub topic: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            username: DEFAULT_MAX_USERNAME_LENGTH,
+            message_text: DEFAULT_MAX_MESSAGE_LENGTH,
+            topic: DEFAULT_MAX_TOPIC_LENGTH,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        fn parse(name: &str) -> Option<usize> {
+            env::var(name).ok().and_then(|value| value.parse().ok()).filter(|limit| *limit > 0)
+        }
+
+        let defaults = Self::default();
+        Self {
+            username: parse("MAX_USERNAME_LENGTH").unwrap_or(defaults.username),
+            message_text: parse("MAX_MESSAGE_LENGTH").unwrap_or(defaults.message_text),
+            topic: parse("MAX_TOPIC_LENGTH").unwrap_or(defaults.topic),
+        }
+    }
+
+    /// `value` if it fits within `limit`, otherwise an error naming both lengths
+    pub fn check_length(
+        field: &'static str,
+        label: &str,
+        value: &str,
+        limit: usize,
+    ) -> Result<(), ValidationError> {
+        let actual = value.len();
+        if actual <= limit {
+            return Ok(());
+        }
+        let message = format!("{} cannot be longer than {} characters", label, limit);
+        Err(ValidationError {
+            limit: Some(limit),
+            actual: Some(actual),
+            ..ValidationError::new(field, message)
+        })
+    }
+}
+
+/// What a `user_id` may look like: ASCII letters and digits plus the punctuation the auth
+/// provider uses in its subjects, up to `max_length` characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserIdFormat {
+    pub max_length: usize,
+    pub punctuation: String,
+}
+
+impl Default for UserIdFormat {
+    fn default() -> Self {
+        Self {
+            max_length: DEFAULT_MAX_USER_ID_LENGTH,
+            punctuation: DEFAULT_USER_ID_PUNCTUATION.to_string(),
+        }
+    }
+}
+
+impl UserIdFormat {
+    /// `MAX_USER_ID_LENGTH` (default 128) and `USER_ID_PUNCTUATION` (default `-_.:|@`)
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            max_length: env::var("MAX_USER_ID_LENGTH")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|limit| *limit > 0)
+                .unwrap_or(defaults.max_length),
+            punctuation: env::var("USER_ID_PUNCTUATION").unwrap_or(defaults.punctuation),
+        }
+    }
+
+    /// The trimmed id, if it's non-empty, short enough and uses only allowed characters
+    pub fn check(&self, user_id: &str) -> Result<String, ValidationError> {
+        let trimmed = user_id.trim();
+        if trimmed.is_empty() {
+            return Err(ValidationError::new("user_id", "User ID cannot be empty"));
+        }
+        Limits::check_length("user_id", "User ID", trimmed, self.max_length)?;
+        if trimmed.chars().any(char::is_control) {
+            return Err(ValidationError::new(
+                "user_id",
+                "User ID cannot contain control characters",
+            ));
+        }
+        if let Some(c) =
+            trimmed.chars().find(|c| !c.is_ascii_alphanumeric() && !self.punctuation.contains(*c))
+        {
+            return Err(ValidationError::new("user_id", format!("User ID cannot contain '{}'", c)));
+        }
+        Ok(trimmed.to_string())
+    }
+}
+
+// A single invalid field in a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: &'static str,
+    pub message: String,
+    // Set when the value was too long: the field's limit and the value's length
+    pub limit: Option<usize>,
+    pub actual: Option<usize>,
+    // Set when the text was refused by the blocklist, so the rejection can be counted
+    pub blocked: bool,
+}
+
+impl ValidationError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self { field, message: message.into(), limit: None, actual: None, blocked: false }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(error: ValidationError) -> Self {
+        ApiError {
+            field: error.field.to_string(),
+            message: error.message,
+            limit: error.limit,
+            actual: error.actual,
+        }
+    }
+}
+
+/// Request-level validation that checks every field and reports all problems together,
+/// instead of stopping at the first one like the per-field `validate_*` helpers
+pub trait Validate {
+    fn validate(&self) -> Result<(), Vec<ValidationError>>;
+}
+
+// Collects per-field results into one list
+#[derive(Default)]
+struct Errors(Vec<ValidationError>);
+
+impl Errors {
+    fn check<T>(&mut self, field: &'static str, result: Result<T, String>) {
+        if let Err(message) = result {
+            self.0.push(ValidationError::new(field, message));
+        }
+    }
+
+    // For validators that build the error themselves
+    fn add<T>(&mut self, result: Result<T, ValidationError>) {
+        if let Err(error) = result {
+            self.0.push(error);
+        }
+    }
+
+    fn finish(self) -> Result<(), Vec<ValidationError>> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self.0)
+        }
+    }
+}
+
+impl Validate for SendMessageRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.check("room_id", validate_room_id(&self.room_id));
+        // A blank id is left to the anonymous policy, which rejects it with a 401 or fills it in
+        if !self.user_id.trim().is_empty() {
+            errors.add(validate_user_id(&self.user_id));
+        }
+        errors.add(validate_username(&self.username));
+        errors.add(validate_message_text(&self.message_text));
+        errors.check("expires_in_secs", validate_expires_in(self.expires_in_secs));
+        errors.check("format", validate_format(&self.format));
+        errors.check("client_seq", validate_client_seq(self.client_seq));
+        errors.add(validate_to_user_id(self.to_user_id.as_deref()));
+        errors.finish()
+    }
+}
+
+impl Validate for UpdateUsernameRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.add(validate_username(&self.username));
+        errors.finish()
+    }
+}
+
+impl Validate for UpdateAvatarSeedRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.add(validate_avatar_seed(self.avatar_seed.as_deref()));
+        errors.finish()
+    }
+}
+
+impl Validate for UpdateTopicRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.add(validate_user_id(&self.user_id));
+        errors.add(validate_topic(self.topic.as_deref()));
+        errors.finish()
+    }
+}
+
+impl Validate for EditMessageRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.add(validate_user_id(&self.user_id));
+        errors.add(validate_message_text(&self.message_text));
+        errors.finish()
+    }
+}
+
+impl Validate for FlagMessageRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        errors.add(validate_user_id(&self.user_id));
+        errors.add(validate_flag_reason(&self.reason));
+        errors.finish()
+    }
+}
+
+impl Validate for LatestMessagesRequest {
+    fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        let room_ids = match self.room_ids.len() {
+            0 => Err("At least one room_id is required".to_string()),
+            n if n > MAX_LATEST_ROOMS => {
+                Err(format!("At most {} rooms may be fetched at once", MAX_LATEST_ROOMS))
+            }
+            _ => self.room_ids.iter().try_for_each(|room_id| validate_room_id(room_id).map(drop)),
+        };
+        errors.check("room_ids", room_ids);
+        if self.per_room == 0 || usize::from(self.per_room) > MAX_LATEST_PER_ROOM {
+            let message = format!("per_room must be between 1 and {}", MAX_LATEST_PER_ROOM);
+            errors.check::<()>("per_room", Err(message));
+        }
+        errors.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::MessageFormat;
+
+    fn request() -> SendMessageRequest {
+        SendMessageRequest {
+            room_id: "general".to_string(),
+            user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+            username: "alice".to_string(),
+            message_text: "Hello!".to_string(),
+            client_message_id: None,
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
+        }
+    }
+
+    #[test]
+    fn test_valid_request_passes() {
+        assert_eq!(request().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_all_problems_reported_together() {
+        let request = SendMessageRequest {
+            username: "   ".to_string(),
+            message_text: "x".repeat(501),
+            expires_in_secs: Some(0),
+            ..request()
+        };
+
+        let errors = request.validate().unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["username", "message_text", "expires_in_secs"]);
+        assert_eq!(errors[0].message, "Username cannot be empty");
+    }
+
+    #[test]
+    fn test_over_length_fields_report_limit_and_length() {
+        let too_long = SendMessageRequest {
+            username: "a".repeat(51),
+            message_text: format!("  {}  ", "x".repeat(612)),
+            ..request()
+        };
+
+        let errors = too_long.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            (errors[0].field, errors[0].limit, errors[0].actual),
+            ("username", Some(50), Some(51))
+        );
+        // Surrounding whitespace is trimmed before measuring, as it is before storing
+        let error = ApiError::from(errors[1].clone());
+        assert_eq!(error.field, "message_text");
+        assert_eq!((error.limit, error.actual), (Some(500), Some(612)));
+        assert_eq!(error.message, "Message text cannot be longer than 500 characters");
+
+        // Errors that aren't about length carry neither
+        let blank = SendMessageRequest { username: " ".to_string(), ..request() };
+        let error = ValidationError::new("username", "Username cannot be empty");
+        assert_eq!(blank.validate().unwrap_err(), [error]);
+    }
+
+    #[test]
+    fn test_limits_default_to_the_documented_lengths() {
+        assert_eq!(Limits::default(), Limits { username: 50, message_text: 500, topic: 200 });
+        assert_eq!(Limits::check_length("q", "Search text", "abc", 3), Ok(()));
+    }
+
+    #[test]
+    fn test_user_ids_must_match_the_format() {
+        let format = UserIdFormat::default();
+        for valid in
+            ["01ARZ3NDEKTSV4RRFFQ69G5FB1", "auth0|5f7c8ec7c33c6c004bbafe82", " user@example.com "]
+        {
+            assert_eq!(format.check(valid), Ok(valid.trim().to_string()));
+        }
+
+        assert_eq!(
+            format.check("  "),
+            Err(ValidationError::new("user_id", "User ID cannot be empty"))
+        );
+
+        let error = format.check(&"a".repeat(129)).unwrap_err();
+        assert_eq!((error.field, error.limit, error.actual), ("user_id", Some(128), Some(129)));
+        assert_eq!(format.check(&"a".repeat(128)), Ok("a".repeat(128)));
+
+        let error = format.check("user\u{0}1").unwrap_err();
+        assert_eq!(error.message, "User ID cannot contain control characters");
+        assert_eq!(format.check("user 1").unwrap_err().message, "User ID cannot contain ' '");
+        assert!(format.check("ユーザー").is_err());
+
+        // A bad id in a post is reported with the other fields
+        let errors = SendMessageRequest { user_id: "user\n1".to_string(), ..request() }
+            .validate()
+            .unwrap_err();
+        assert_eq!(errors.iter().map(|error| error.field).collect::<Vec<_>>(), ["user_id"]);
+    }
+
+    #[test]
+    fn test_unknown_code_language_is_rejected() {
+        let code = |lang: &str| SendMessageRequest {
+            format: MessageFormat::Code { lang: Some(lang.to_string()) },
+            ..request()
+        };
+        assert_eq!(code("Rust").validate(), Ok(()));
+
+        let errors = code("brainfuck").validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "format");
+        assert!(errors[0].message.contains("'brainfuck'"), "{}", errors[0].message);
+    }
+}
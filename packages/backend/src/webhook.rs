@@ -0,0 +1,198 @@
+use hmac::{Hmac, Mac};
+use reqwest::{Client as HttpClient, StatusCode};
+use sha2::Sha256;
+use std::{env, time::Duration};
+use tracing::{info, warn};
+use types::ChatMessage;
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Outbound webhook for new messages, configured by `WEBHOOK_URL` (and optionally
+/// `WEBHOOK_SECRET`, `WEBHOOK_TIMEOUT_MS`, `WEBHOOK_MAX_ATTEMPTS`)
+pub struct Webhook {
+    client: HttpClient,
+    url: String,
+    secret: Option<String>,
+    max_attempts: u32,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<String>, timeout: Duration, max_attempts: u32) -> Self {
+        let client = HttpClient::builder()
+            .timeout(timeout)
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self { client, url, secret, max_attempts: max_attempts.max(1) }
+    }
+
+    /// None when `WEBHOOK_URL` is unset or empty
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("WEBHOOK_URL").ok().filter(|url| !url.is_empty())?;
+        let secret = env::var("WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty());
+        let timeout = env::var("WEBHOOK_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let max_attempts = env::var("WEBHOOK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        Some(Self::new(url, secret, timeout, max_attempts))
+    }
+
+    /// POST the message as JSON, retrying timeouts, connection errors, 429s and 5xx responses.
+    /// Returns the number of attempts it took.
+    pub async fn deliver(&self, message: &ChatMessage) -> Result<u32, String> {
+        let body = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+        let signature = self.secret.as_deref().map(|secret| sign(secret, &body));
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let (error, retryable) = match request.send().await {
+                Ok(resp) if resp.status().is_success() => {
+                    info!(
+                        "Delivered message {} to webhook in {} attempt(s)",
+                        message.core.id, attempt
+                    );
+                    return Ok(attempt);
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    let retryable =
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+                    (format!("Webhook responded with status {}", status), retryable)
+                }
+                Err(e) => (format!("Webhook request failed: {}", e), true),
+            };
+
+            if !retryable || attempt >= self.max_attempts {
+                return Err(format!("{} after {} attempt(s)", error, attempt));
+            }
+            warn!("{} (attempt {}), retrying", error, attempt);
+            tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+        }
+    }
+}
+
+/// `sha256=<hex HMAC-SHA256 of the body>`, as sent in `X-Signature`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::serve;
+    use axum::{http::HeaderMap, routing::post, Router};
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+    use types::{MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+
+    // (X-Signature, body) of each request the mock endpoint received
+    type Received = Arc<Mutex<Vec<(Option<String>, Vec<u8>)>>>;
+
+    fn message() -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: "msg-1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_payload_and_signature() {
+        let received: Received = Arc::default();
+        let sink = received.clone();
+        let url = serve(Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                let signature = headers
+                    .get(SIGNATURE_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from);
+                sink.lock().unwrap().push((signature, body.to_vec()));
+                axum::http::StatusCode::NO_CONTENT
+            }),
+        ))
+        .await;
+
+        let webhook = Webhook::new(
+            format!("{}/hook", url),
+            Some("s3cret".to_string()),
+            Duration::from_secs(1),
+            3,
+        );
+        let message = message();
+        assert_eq!(webhook.deliver(&message).await, Ok(1));
+
+        let received = received.lock().unwrap();
+        let (signature, body) = &received[0];
+        assert_eq!(serde_json::from_slice::<ChatMessage>(body).unwrap().core.id, message.core.id);
+        assert_eq!(signature.as_deref(), Some(sign("s3cret", body).as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_retried_then_reported() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let url = serve(Router::new().route(
+            "/hook",
+            post(move || async move {
+                *counter.lock().unwrap() += 1;
+                (axum::http::StatusCode::BAD_GATEWAY, "down")
+            }),
+        ))
+        .await;
+
+        let webhook = Webhook::new(format!("{}/hook", url), None, Duration::from_secs(1), 2);
+        let err = webhook.deliver(&message()).await.unwrap_err();
+        assert!(err.contains("502"), "{}", err);
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_sign_matches_known_vector() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
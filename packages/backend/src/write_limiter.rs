@@ -0,0 +1,202 @@
+use crate::MetricsHelper;
+use std::{env, sync::Mutex};
+use tokio::sync::Notify;
+
+// How much of the limit a throttled write takes away
+const DECREASE_FACTOR: f64 = 0.5;
+
+/// How a write that held a permit went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Succeeded,
+    // DynamoDB refused it for capacity (after the SDK's own retries)
+    Throttled,
+    // Failed for some other reason, which says nothing about capacity
+    Failed,
+}
+
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    // Bumped on every decrease. Throttles from writes admitted before the latest decrease
+    // were already answered by it, so a burst of them only shrinks the limit once.
+    epoch: u64,
+}
+
+/// Caps concurrent message writes and adapts the cap to DynamoDB's capacity (AIMD): each
+/// throttled write halves it, each successful one grows it by `1 / limit`, so it climbs back
+/// by about one per limit's worth of successes. Shared by every store in the process, since
+/// the Lambdas build a store per invocation.
+pub struct WriteLimiter {
+    state: Mutex<LimiterState>,
+    freed: Notify,
+    max: f64,
+    metrics: Option<MetricsHelper>,
+}
+
+impl WriteLimiter {
+    /// Starts at, and never grows past, `max` concurrent writes; never shrinks below one
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1) as f64;
+        Self {
+            state: Mutex::new(LimiterState { limit: max, in_flight: 0, epoch: 0 }),
+            freed: Notify::new(),
+            max,
+            metrics: None,
+        }
+    }
+
+    /// `WRITE_CONCURRENCY_MAX` concurrent writes at most; unset or 0 leaves writes unlimited
+    pub fn from_env() -> Option<Self> {
+        env::var("WRITE_CONCURRENCY_MAX")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|max| *max > 0)
+            .map(Self::new)
+    }
+
+    /// Emit the limit as a `WriteConcurrencyLimit` gauge whenever it changes
+    pub fn with_metrics(mut self, metrics: MetricsHelper) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Writes currently allowed in flight
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    /// Wait for room under the limit. Hand the outcome to the permit once the write is done.
+    pub async fn acquire(&self) -> WritePermit<'_> {
+        loop {
+            let freed = self.freed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if (state.in_flight as f64) < state.limit.floor() {
+                    state.in_flight += 1;
+                    return WritePermit { limiter: self, epoch: state.epoch, finished: false };
+                }
+            }
+            freed.await;
+        }
+    }
+
+    // Adjust the limit for one outcome, returning it if its whole part changed
+    fn record(&self, epoch: u64, outcome: WriteOutcome) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.limit as usize;
+        match outcome {
+            WriteOutcome::Succeeded => {
+                state.limit = (state.limit + 1.0 / state.limit).min(self.max)
+            }
+            WriteOutcome::Throttled if epoch == state.epoch => {
+                state.limit = (state.limit * DECREASE_FACTOR).max(1.0);
+                state.epoch += 1;
+            }
+            WriteOutcome::Throttled | WriteOutcome::Failed => {}
+        }
+        let after = state.limit as usize;
+        (after != before).then_some(after)
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().in_flight -= 1;
+        self.freed.notify_waiters();
+    }
+}
+
+/// A slot under the limit, given back when dropped
+pub struct WritePermit<'a> {
+    limiter: &'a WriteLimiter,
+    epoch: u64,
+    finished: bool,
+}
+
+impl WritePermit<'_> {
+    /// Record how the write went and give the slot back
+    pub async fn finish(mut self, outcome: WriteOutcome) {
+        self.finished = true;
+        self.limiter.release();
+        if let Some(limit) = self.limiter.record(self.epoch, outcome) {
+            if let Some(metrics) = &self.limiter.metrics {
+                metrics.emit_write_concurrency_limit(limit).await;
+            }
+        }
+    }
+}
+
+impl Drop for WritePermit<'_> {
+    fn drop(&mut self) {
+        // A write abandoned before finishing (e.g. a cancelled request) still frees its slot
+        if !self.finished {
+            self.limiter.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+
+    async fn write(limiter: &WriteLimiter, outcome: WriteOutcome) {
+        limiter.acquire().await.finish(outcome).await;
+    }
+
+    #[tokio::test]
+    async fn test_throttling_shrinks_the_limit_and_successes_restore_it() {
+        let limiter = WriteLimiter::new(16);
+        assert_eq!(limiter.limit(), 16);
+
+        write(&limiter, WriteOutcome::Throttled).await;
+        assert_eq!(limiter.limit(), 8);
+        write(&limiter, WriteOutcome::Throttled).await;
+        assert_eq!(limiter.limit(), 4);
+        // Other failures say nothing about capacity
+        write(&limiter, WriteOutcome::Failed).await;
+        assert_eq!(limiter.limit(), 4);
+
+        // Grows back gradually: about one per limit's worth of successes
+        let mut limits = vec![];
+        for _ in 0..40 {
+            write(&limiter, WriteOutcome::Succeeded).await;
+            limits.push(limiter.limit());
+        }
+        assert!(limits.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", limits);
+        assert_eq!(limits[0], 4);
+        assert!(limits[39] > 8 && limits[39] <= 16, "{:?}", limits);
+
+        for _ in 0..1000 {
+            write(&limiter, WriteOutcome::Succeeded).await;
+        }
+        assert_eq!(limiter.limit(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_a_burst_of_throttles_shrinks_the_limit_once() {
+        let limiter = WriteLimiter::new(8);
+        let permits = vec![limiter.acquire().await, limiter.acquire().await];
+        for permit in permits {
+            permit.finish(WriteOutcome::Throttled).await;
+        }
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_writes_beyond_the_limit_wait_for_a_slot() {
+        let limiter = Arc::new(WriteLimiter::new(4));
+        write(&limiter, WriteOutcome::Throttled).await;
+        assert_eq!(limiter.limit(), 2);
+
+        let held = vec![limiter.acquire().await, limiter.acquire().await];
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { write(&limiter, WriteOutcome::Succeeded).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    }
+}
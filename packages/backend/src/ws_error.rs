@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use types::{WsClientMessage, WsError, WsErrorCode, WsServerMessage};
+
+/// An error frame with no suggested wait
+pub fn ws_error(code: WsErrorCode, message: impl Into<String>) -> WsError {
+    WsError { code, message: message.into(), retry_after_ms: None }
+}
+
+/// `error` as the text of a frame
+pub fn encode(error: &WsError) -> String {
+    serde_json::to_string(&WsServerMessage::from(error.clone())).expect("WsError always serializes")
+}
+
+// Typing frames as clients sent them before frames had a `type`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UntypedTypingFrame {
+    is_typing: bool,
+}
+
+/// Read a client frame that passed `check_frame`. A bare `{"is_typing": ...}` is still taken
+/// as a typing frame; anything else not in `WsClientMessage` is refused as a bad frame.
+pub fn parse_client_frame(text: &str) -> Result<WsClientMessage, WsError> {
+    if let Ok(frame) = serde_json::from_str::<WsClientMessage>(text) {
+        return Ok(frame);
+    }
+    match serde_json::from_str::<UntypedTypingFrame>(text) {
+        Ok(UntypedTypingFrame { is_typing }) => Ok(WsClientMessage::Typing { is_typing }),
+        Err(_) => Err(ws_error(WsErrorCode::BadFrame, "Unknown frame")),
+    }
+}
+
+/// Refuse a client frame over `max_frame_bytes`, or one that isn't a JSON object (every frame
+/// clients send is)
+pub fn check_frame(text: &str, max_frame_bytes: usize) -> Result<(), WsError> {
+    if text.len() > max_frame_bytes {
+        let message =
+            format!("Frame of {} bytes exceeds the {} byte limit", text.len(), max_frame_bytes);
+        return Err(ws_error(WsErrorCode::FrameTooLarge, message));
+    }
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(_)) => Ok(()),
+        _ => Err(ws_error(WsErrorCode::BadFrame, "Frames must be JSON objects")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_are_checked_for_size_and_shape() {
+        assert_eq!(check_frame(r#"{"is_typing":true}"#, 64), Ok(()));
+        assert_eq!(
+            check_frame(&format!(r#"{{"x":"{}"}}"#, "a".repeat(64)), 64).unwrap_err().code,
+            WsErrorCode::FrameTooLarge
+        );
+        for frame in ["hello", "[1,2]", "{\"unterminated\":"] {
+            assert_eq!(
+                check_frame(frame, 64).unwrap_err().code,
+                WsErrorCode::BadFrame,
+                "{}",
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_frames_parse_by_type_and_unknown_ones_are_refused() {
+        assert_eq!(
+            parse_client_frame(r#"{"type":"typing","is_typing":true}"#),
+            Ok(WsClientMessage::Typing { is_typing: true })
+        );
+        assert_eq!(
+            parse_client_frame(r#"{"is_typing":false}"#),
+            Ok(WsClientMessage::Typing { is_typing: false })
+        );
+        assert_eq!(
+            parse_client_frame(r#"{"type":"echo","nonce":"a1"}"#),
+            Ok(WsClientMessage::Echo)
+        );
+        for frame in [r#"{"type":"dance"}"#, r#"{"hello":"world"}"#, r#"{"type":"typing"}"#] {
+            assert_eq!(
+                parse_client_frame(frame).unwrap_err().code,
+                WsErrorCode::BadFrame,
+                "{}",
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_frames_are_tagged_with_their_code() {
+        let error = WsError {
+            retry_after_ms: Some(1500),
+            ..ws_error(WsErrorCode::RateLimited, "Slow down")
+        };
+        assert_eq!(
+            encode(&error),
+            r#"{"type":"error","code":"rate_limited","message":"Slow down","retry_after_ms":1500}"#
+        );
+    }
+}
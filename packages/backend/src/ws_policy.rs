@@ -0,0 +1,241 @@
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, ReturnValue},
+    Client as DynamoDbClient,
+};
+use std::{collections::HashMap, env};
+use types::{WsError, WsErrorCode};
+
+const DEFAULT_WS_MSG_RATE_PER_MIN: u32 = 60;
+const DEFAULT_WS_MAX_FRAME_BYTES: usize = 4096;
+const DEFAULT_WS_MAX_WARNINGS: u32 = 3;
+
+// Limits for frames arriving on the `$default` route. These are separate from the REST limits
+// because WebSocket clients can burst much faster than HTTP ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WsPolicy {
+    pub rate_per_min: u32,
+    pub max_frame_bytes: usize,
+    // Violations tolerated (each answered with a warning) before the connection is closed
+    pub max_warnings: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsVerdict {
+    Allow,
+    Warn(WsError),
+    Close(WsError),
+}
+
+// How much a connection has sent, as recorded on its connections table item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WsUsage {
+    pub sent_this_window: u32,
+    pub warnings: u32,
+}
+
+impl WsPolicy {
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+            env::var(name).ok().and_then(|value| value.parse().ok())
+        }
+
+        Self {
+            rate_per_min: parse("WS_MSG_RATE_PER_MIN").unwrap_or(DEFAULT_WS_MSG_RATE_PER_MIN),
+            max_frame_bytes: parse("WS_MAX_FRAME_BYTES").unwrap_or(DEFAULT_WS_MAX_FRAME_BYTES),
+            max_warnings: parse("WS_MAX_WARNINGS").unwrap_or(DEFAULT_WS_MAX_WARNINGS),
+        }
+    }
+
+    /// Judge a frame of `frame_bytes` given the connection's usage, which already counts it.
+    /// Rate-limited frames are told to retry when the current window ends.
+    pub fn judge(&self, frame_bytes: usize, usage: WsUsage, now_epoch_secs: i64) -> WsVerdict {
+        let violation = if frame_bytes > self.max_frame_bytes {
+            let message = format!(
+                "Frame of {} bytes exceeds the {} byte limit",
+                frame_bytes, self.max_frame_bytes
+            );
+            WsError { code: WsErrorCode::FrameTooLarge, message, retry_after_ms: None }
+        } else if usage.sent_this_window > self.rate_per_min {
+            let message =
+                format!("Rate limit of {} messages per minute exceeded", self.rate_per_min);
+            let retry_after_ms = (60 - now_epoch_secs.rem_euclid(60)) as u64 * 1000;
+            WsError {
+                code: WsErrorCode::RateLimited,
+                message,
+                retry_after_ms: Some(retry_after_ms),
+            }
+        } else {
+            return WsVerdict::Allow;
+        };
+
+        if usage.warnings >= self.max_warnings {
+            WsVerdict::Close(violation)
+        } else {
+            WsVerdict::Warn(violation)
+        }
+    }
+}
+
+/// Count a frame against the connection's current one-minute window. State lives on the
+/// connection item because successive frames can land on different Lambda containers.
+pub async fn record_frame(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    connection_id: &str,
+    now_epoch_secs: i64,
+) -> Result<WsUsage, String> {
+    let window = AttributeValue::N((now_epoch_secs / 60).to_string());
+    let one = AttributeValue::N("1".to_string());
+
+    // Same window: bump the counter
+    let same_window = ddb
+        .update_item()
+        .table_name(connections_table)
+        .key("connection_id", AttributeValue::S(connection_id.to_string()))
+        .update_expression("ADD ws_count :one")
+        .condition_expression("ws_window = :window")
+        .expression_attribute_values(":window", window.clone())
+        .expression_attribute_values(":one", one.clone())
+        .return_values(ReturnValue::AllNew)
+        .send()
+        .await;
+
+    let attributes = match same_window {
+        Ok(output) => output.attributes,
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|err| err.is_conditional_check_failed_exception()) =>
+        {
+            // New window (or first frame): restart the count, keeping earlier warnings
+            ddb.update_item()
+                .table_name(connections_table)
+                .key("connection_id", AttributeValue::S(connection_id.to_string()))
+                .update_expression("SET ws_window = :window, ws_count = :one")
+                .condition_expression("attribute_exists(connection_id)")
+                .expression_attribute_values(":window", window)
+                .expression_attribute_values(":one", one)
+                .return_values(ReturnValue::AllNew)
+                .send()
+                .await
+                .map_err(|e| format!("Failed to record frame for {}: {:?}", connection_id, e))?
+                .attributes
+        }
+        Err(e) => return Err(format!("Failed to record frame for {}: {:?}", connection_id, e)),
+    };
+
+    let attributes = attributes.unwrap_or_default();
+    Ok(WsUsage {
+        sent_this_window: number(&attributes, "ws_count"),
+        warnings: number(&attributes, "ws_warnings"),
+    })
+}
+
+/// Note that the connection was warned, so repeat offenders are eventually closed
+pub async fn record_warning(
+    ddb: &DynamoDbClient,
+    connections_table: &str,
+    connection_id: &str,
+) -> Result<(), String> {
+    ddb.update_item()
+        .table_name(connections_table)
+        .key("connection_id", AttributeValue::S(connection_id.to_string()))
+        .update_expression("ADD ws_warnings :one")
+        .condition_expression("attribute_exists(connection_id)")
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to record warning for {}: {:?}", connection_id, e))?;
+    Ok(())
+}
+
+fn number(attributes: &HashMap<String, AttributeValue>, name: &str) -> u32 {
+    attributes
+        .get(name)
+        .and_then(|value| value.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_connections_table, local_ddb};
+
+    fn policy() -> WsPolicy {
+        WsPolicy { rate_per_min: 3, max_frame_bytes: 16, max_warnings: 2 }
+    }
+
+    #[test]
+    fn test_rate_abuse_is_warned_then_closed() {
+        let policy = policy();
+        let mut warnings = 0;
+        let mut verdicts = Vec::new();
+
+        for sent_this_window in 1..=6 {
+            let verdict = policy.judge(5, WsUsage { sent_this_window, warnings }, 1_700_000_015);
+            if let WsVerdict::Warn(_) = verdict {
+                warnings += 1;
+            }
+            verdicts.push(verdict);
+        }
+
+        // 1_700_000_015 is 35s into its minute
+        let limit = WsError {
+            code: WsErrorCode::RateLimited,
+            message: "Rate limit of 3 messages per minute exceeded".to_string(),
+            retry_after_ms: Some(25_000),
+        };
+        assert_eq!(
+            verdicts,
+            vec![
+                WsVerdict::Allow,
+                WsVerdict::Allow,
+                WsVerdict::Allow,
+                WsVerdict::Warn(limit.clone()),
+                WsVerdict::Warn(limit.clone()),
+                WsVerdict::Close(limit),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oversized_frame_is_a_violation() {
+        let verdict = policy().judge(17, WsUsage { sent_this_window: 1, warnings: 0 }, 0);
+        let WsVerdict::Warn(error) = verdict else {
+            panic!("expected a warning, got {:?}", verdict);
+        };
+        assert_eq!(error.code, WsErrorCode::FrameTooLarge);
+        assert_eq!(error.message, "Frame of 17 bytes exceeds the 16 byte limit");
+        assert_eq!(error.retry_after_ms, None);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local DynamoDB at DYNAMODB_ENDPOINT
+    async fn test_frames_are_counted_per_window() {
+        let ddb = local_ddb().await;
+        let table = "ws-policy-test-connections";
+        create_connections_table(&ddb, table).await;
+        ddb.put_item()
+            .table_name(table)
+            .item("connection_id", AttributeValue::S("conn-1".to_string()))
+            .item("room_id", AttributeValue::S("general".to_string()))
+            .item("connected_at", AttributeValue::N("1".to_string()))
+            .send()
+            .await
+            .unwrap();
+
+        let minute = 1_700_000_040;
+        for expected in 1..=3 {
+            let usage = record_frame(&ddb, table, "conn-1", minute).await.unwrap();
+            assert_eq!(usage, WsUsage { sent_this_window: expected, warnings: 0 });
+        }
+        record_warning(&ddb, table, "conn-1").await.unwrap();
+
+        // Next minute starts a fresh count but remembers the warning
+        let usage = record_frame(&ddb, table, "conn-1", minute + 60).await.unwrap();
+        assert_eq!(usage, WsUsage { sent_this_window: 1, warnings: 1 });
+
+        // Unknown connections aren't created as a side effect
+        assert!(record_frame(&ddb, table, "missing", minute).await.is_err());
+    }
+}
@@ -10,6 +10,12 @@ export interface StageConfig {
     testAssumeRoleArn?: string
     isProd: boolean
     deployOrder: number
+    // At-least-once broadcast: undelivered fan-out is queued and redelivered on a schedule
+    broadcastDurable?: boolean
+    // Keep a permanent, append-only log of WebSocket connects and disconnects
+    auditConnections?: boolean
+    // Roll old message history into compressed segments nightly, to cut item count in long-lived rooms
+    compactHistory?: boolean
 }
 
 // Root domain configuration
@@ -114,8 +120,13 @@ export const pipelineConfig: PipelineConfig = {
 // DynamoDB Table Names and ARNs
 export const DYNAMODB_TABLES = {
     CHAT_ROOMS: 'chat-rooms',
-    CHAT_MESSAGES: 'chat-messages',
+    // Keyed by (room_id, sk) since v2; see packages/backend/README.md for the migration
+    CHAT_MESSAGES: 'chat-messages-v2',
     CHAT_CONNECTIONS: 'chat-connections',
+    CHAT_BROADCAST_RETRIES: 'chat-broadcast-retries',
+    CHAT_CONNECTION_AUDIT: 'chat-connection-audit',
+    CHAT_USERS: 'chat-users',
+    CHAT_FLAGS: 'chat-flags',
 } as const
 
 // DynamoDB Table ARN builders (requires region and account)
@@ -126,6 +137,10 @@ export const DYNAMODB_ARNS = {
         `arn:aws:dynamodb:${region}:${account}:table/${DYNAMODB_TABLES.CHAT_MESSAGES}`,
     CHAT_CONNECTIONS: (region: string, account: string) =>
         `arn:aws:dynamodb:${region}:${account}:table/${DYNAMODB_TABLES.CHAT_CONNECTIONS}`,
+    CHAT_USERS: (region: string, account: string) =>
+        `arn:aws:dynamodb:${region}:${account}:table/${DYNAMODB_TABLES.CHAT_USERS}`,
+    CHAT_FLAGS: (region: string, account: string) =>
+        `arn:aws:dynamodb:${region}:${account}:table/${DYNAMODB_TABLES.CHAT_FLAGS}`,
     CHAT_MESSAGES_STREAM: (region: string, account: string) =>
         `arn:aws:dynamodb:${region}:${account}:table/${DYNAMODB_TABLES.CHAT_MESSAGES}/stream/*`,
 } as const
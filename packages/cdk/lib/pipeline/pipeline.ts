@@ -152,6 +152,8 @@ export class PipelineStack extends Stack {
                 'yarn install',
                 'cd ../..',
                 'yarn build',
+                // Includes the jsonschema-gated tests, which nothing else compiles
+                'yarn workspace @swflcoders/types test',
             ],
             primaryOutputDirectory: 'packages/cdk/cdk.out',
         })
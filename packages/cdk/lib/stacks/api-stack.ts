@@ -3,6 +3,7 @@ import * as apigatewayv2 from 'aws-cdk-lib/aws-apigatewayv2'
 import * as apigatewayv2Integrations from 'aws-cdk-lib/aws-apigatewayv2-integrations'
 import * as lambda from 'aws-cdk-lib/aws-lambda'
 import * as iam from 'aws-cdk-lib/aws-iam'
+import * as secretsmanager from 'aws-cdk-lib/aws-secretsmanager'
 import * as route53 from 'aws-cdk-lib/aws-route53'
 import * as route53targets from 'aws-cdk-lib/aws-route53-targets'
 import * as certificatemanager from 'aws-cdk-lib/aws-certificatemanager'
@@ -27,6 +28,8 @@ export class ApiStack extends cdk.Stack {
         const chatRoomsTableArn = DYNAMODB_ARNS.CHAT_ROOMS(this.region, this.account)
         const chatMessagesTableArn = DYNAMODB_ARNS.CHAT_MESSAGES(this.region, this.account)
         const chatConnectionsTableArn = DYNAMODB_ARNS.CHAT_CONNECTIONS(this.region, this.account)
+        const chatUsersTableArn = DYNAMODB_ARNS.CHAT_USERS(this.region, this.account)
+        const chatFlagsTableArn = DYNAMODB_ARNS.CHAT_FLAGS(this.region, this.account)
 
         // === DNS/Certificates for Custom Domains ===
         // Use the hosted zone provided by DNS stack
@@ -44,6 +47,21 @@ export class ApiStack extends cdk.Stack {
 
         // === Lambda Functions ===
 
+        // Key for the hashes client IPs are stored and counted by (ORIGIN_HASH_SALT). Generated
+        // once per stage and resolved into the Lambdas' environment at deploy; without it the
+        // hashes could be reversed by hashing every network.
+        const originHashSalt = new secretsmanager.Secret(this, 'OriginHashSalt', {
+            description: `Origin hash salt for ${stageConfig.name}`,
+            generateSecretString: { passwordLength: 64, excludePunctuation: true },
+        })
+
+        // Key page cursors are signed with (CURSOR_SECRET), shared by every REST container so a
+        // cursor opens wherever the next page is asked for
+        const cursorSecret = new secretsmanager.Secret(this, 'CursorSecret', {
+            description: `Page cursor signing key for ${stageConfig.name}`,
+            generateSecretString: { passwordLength: 64, excludePunctuation: true },
+        })
+
         // Rust Lambda for chat REST endpoints
         const rustChatFn = new lambda.Function(this, 'RustChatFunction', {
             functionName: `rust-chat-${stageConfig.name}`,
@@ -55,8 +73,12 @@ export class ApiStack extends cdk.Stack {
             environment: {
                 CHAT_ROOMS_TABLE: DYNAMODB_TABLES.CHAT_ROOMS,
                 CHAT_MESSAGES_TABLE: DYNAMODB_TABLES.CHAT_MESSAGES,
+                CHAT_USERS_TABLE: DYNAMODB_TABLES.CHAT_USERS,
+                CHAT_FLAGS_TABLE: DYNAMODB_TABLES.CHAT_FLAGS,
                 STAGE: stageConfig.name,
                 DOMAIN: stageConfig.domain,
+                ORIGIN_HASH_SALT: originHashSalt.secretValue.unsafeUnwrap(),
+                CURSOR_SECRET: cursorSecret.secretValue.unsafeUnwrap(),
             },
             timeout: cdk.Duration.seconds(30),
         })
@@ -72,8 +94,16 @@ export class ApiStack extends cdk.Stack {
                     'dynamodb:DeleteItem',
                     'dynamodb:Query',
                     'dynamodb:Scan',
+                    'dynamodb:DescribeTable',
+                ],
+                resources: [
+                    chatRoomsTableArn,
+                    `${chatRoomsTableArn}/index/created-index`,
+                    chatMessagesTableArn,
+                    `${chatMessagesTableArn}/index/client-message-index`,
+                    chatUsersTableArn,
+                    chatFlagsTableArn,
                 ],
-                resources: [chatRoomsTableArn, chatMessagesTableArn],
             })
         )
 
@@ -124,6 +154,9 @@ export class ApiStack extends cdk.Stack {
                 allowMethods: [
                     apigatewayv2.CorsHttpMethod.GET,
                     apigatewayv2.CorsHttpMethod.POST,
+                    apigatewayv2.CorsHttpMethod.PUT,
+                    apigatewayv2.CorsHttpMethod.PATCH,
+                    apigatewayv2.CorsHttpMethod.DELETE,
                     apigatewayv2.CorsHttpMethod.OPTIONS,
                 ],
                 allowHeaders: [
@@ -159,16 +192,66 @@ export class ApiStack extends cdk.Stack {
             'ChatIntegration',
             rustChatFn
         )
+        // On-call diagnostic: probes every table, the management API and KMS from the Rust Lambda
+        httpApi.addRoutes({
+            path: '/health/dependencies',
+            methods: [apigatewayv2.HttpMethod.GET],
+            integration: chatIntegration,
+        })
         httpApi.addRoutes({
             path: '/chat/messages',
             methods: [apigatewayv2.HttpMethod.POST],
             integration: chatIntegration,
         })
+        httpApi.addRoutes({
+            path: '/chat/messages/latest',
+            methods: [apigatewayv2.HttpMethod.POST],
+            integration: chatIntegration,
+        })
         httpApi.addRoutes({
             path: '/chat/messages/{room_id}',
             methods: [apigatewayv2.HttpMethod.GET],
             integration: chatIntegration,
         })
+        httpApi.addRoutes({
+            path: '/chat/messages/{room_id}/{id}',
+            methods: [
+                apigatewayv2.HttpMethod.GET,
+                apigatewayv2.HttpMethod.PATCH,
+                apigatewayv2.HttpMethod.DELETE,
+            ],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/chat/messages/{id}/rebroadcast',
+            methods: [apigatewayv2.HttpMethod.POST],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/chat/rooms/{room_id}/stats',
+            methods: [apigatewayv2.HttpMethod.GET],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/chat/rooms/{room_id}/presence',
+            methods: [apigatewayv2.HttpMethod.GET],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/chat/rooms/{room_id}/search',
+            methods: [apigatewayv2.HttpMethod.GET],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/chat/users/{user_id}/username',
+            methods: [apigatewayv2.HttpMethod.PUT],
+            integration: chatIntegration,
+        })
+        httpApi.addRoutes({
+            path: '/admin/messages/{id}',
+            methods: [apigatewayv2.HttpMethod.GET],
+            integration: chatIntegration,
+        })
 
         // Custom domain for HTTP API (API Gateway v2)
         const restDomainName = new apigatewayv2.DomainName(this, 'HttpCustomDomainName', {
@@ -195,6 +278,7 @@ export class ApiStack extends cdk.Stack {
             environment: {
                 CONNECTIONS_TABLE: DYNAMODB_TABLES.CHAT_CONNECTIONS,
                 STAGE: stageConfig.name,
+                ORIGIN_HASH_SALT: originHashSalt.secretValue.unsafeUnwrap(),
             },
             timeout: cdk.Duration.seconds(10),
         })
@@ -208,6 +292,7 @@ export class ApiStack extends cdk.Stack {
             environment: {
                 CONNECTIONS_TABLE: DYNAMODB_TABLES.CHAT_CONNECTIONS,
                 STAGE: stageConfig.name,
+                ORIGIN_HASH_SALT: originHashSalt.secretValue.unsafeUnwrap(),
             },
             timeout: cdk.Duration.seconds(10),
         })
@@ -219,7 +304,10 @@ export class ApiStack extends cdk.Stack {
             handler: 'bootstrap',
             code: lambda.Code.fromAsset('../backend/target/lambda/ws-default'),
             environment: {
+                CONNECTIONS_TABLE: DYNAMODB_TABLES.CHAT_CONNECTIONS,
                 STAGE: stageConfig.name,
+                // Diagnostic echo frames, for QA outside production
+                WS_ECHO_ENABLED: stageConfig.isProd ? 'false' : 'true',
             },
             timeout: cdk.Duration.seconds(10),
         })
@@ -228,7 +316,7 @@ export class ApiStack extends cdk.Stack {
         const broadcastFunction = dbStack.broadcastFunction
 
         // Grant DynamoDB permissions using ARN constants
-        const wsFunctions = [onConnectFunction, onDisconnectFunction]
+        const wsFunctions = [onConnectFunction, onDisconnectFunction, defaultFunction]
         wsFunctions.forEach((fn) => {
             fn.addToRolePolicy(
                 new iam.PolicyStatement({
@@ -238,14 +326,31 @@ export class ApiStack extends cdk.Stack {
                         'dynamodb:PutItem',
                         'dynamodb:UpdateItem',
                         'dynamodb:DeleteItem',
+                        // Gone connections found while posting presence
+                        'dynamodb:BatchWriteItem',
                         'dynamodb:Query',
                         'dynamodb:Scan',
+                        'dynamodb:DescribeTable',
+                    ],
+                    resources: [
+                        chatConnectionsTableArn,
+                        `${chatConnectionsTableArn}/index/user-index`,
+                        // Post-change ActiveConnections counts and presence
+                        `${chatConnectionsTableArn}/index/room-index`,
                     ],
-                    resources: [chatConnectionsTableArn],
                 })
             )
         })
 
+        // Connect/disconnect may only append to the audit log, never change or remove rows
+        const auditTable = dbStack.connectionAuditTable
+        if (auditTable) {
+            for (const fn of [onConnectFunction, onDisconnectFunction]) {
+                fn.addEnvironment('AUDIT_TABLE', auditTable.tableName)
+                auditTable.grant(fn, 'dynamodb:PutItem')
+            }
+        }
+
         // WebSocket API
         const wsApi = new apigatewayv2.WebSocketApi(this, 'WebSocketApi', {
             apiName: `Chat WebSocket API - ${stageConfig.name}`,
@@ -293,19 +398,85 @@ export class ApiStack extends cdk.Stack {
         broadcastFunction.addEnvironment('WS_API_ID', wsApi.apiId)
         broadcastFunction.addEnvironment('WS_STAGE', wsStage.stageName)
 
+        // Redelivery posts to the same connections as the broadcast it retries
+        dbStack.redeliverFunction?.addEnvironment('WS_API_ID', wsApi.apiId)
+        dbStack.redeliverFunction?.addEnvironment('WS_STAGE', wsStage.stageName)
+
         // Note: Dev broadcaster uses per-connection push URLs; no global dev env var needed here
 
-        // Update WebSocket management permissions to broadcast function with specific API details
-        broadcastFunction.addToRolePolicy(
+        // $default replies to and closes connections that break the WebSocket rate/size policy
+        defaultFunction.addEnvironment('WS_API_ID', wsApi.apiId)
+        defaultFunction.addEnvironment('WS_STAGE', wsStage.stageName)
+
+        // Connect and disconnect post the room's presence to its other connections
+        for (const fn of [onConnectFunction, onDisconnectFunction]) {
+            fn.addEnvironment('WS_API_ID', wsApi.apiId)
+            fn.addEnvironment('WS_STAGE', wsStage.stageName)
+        }
+
+        // Admin rebroadcasts run the same fan-out from the REST Lambda (ADMIN_TOKEN is set out of band)
+        rustChatFn.addEnvironment('CONNECTIONS_TABLE', DYNAMODB_TABLES.CHAT_CONNECTIONS)
+        rustChatFn.addEnvironment('WS_API_ID', wsApi.apiId)
+        rustChatFn.addEnvironment('WS_STAGE', wsStage.stageName)
+        rustChatFn.addToRolePolicy(
+            new iam.PolicyStatement({
+                effect: iam.Effect.ALLOW,
+                actions: [
+                    'dynamodb:Query',
+                    'dynamodb:DeleteItem',
+                    'dynamodb:BatchWriteItem',
+                    'dynamodb:DescribeTable',
+                ],
+                resources: [chatConnectionsTableArn, `${chatConnectionsTableArn}/index/room-index`],
+            })
+        )
+        // /health/dependencies asks the management API about a connection that doesn't exist
+        rustChatFn.addToRolePolicy(
             new iam.PolicyStatement({
                 effect: iam.Effect.ALLOW,
                 actions: ['execute-api:ManageConnections'],
                 resources: [
-                    `arn:aws:execute-api:${this.region}:${this.account}:${wsApi.apiId}/${wsStage.stageName}/POST/@connections/*`,
+                    `arn:aws:execute-api:${this.region}:${this.account}:${wsApi.apiId}/${wsStage.stageName}/GET/@connections/*`,
                 ],
             })
         )
 
+        // Update WebSocket management permissions to broadcast and REST functions with specific API details
+        const wsManagementFunctions = [
+            broadcastFunction,
+            rustChatFn,
+            defaultFunction,
+            onConnectFunction,
+            onDisconnectFunction,
+        ]
+        if (dbStack.redeliverFunction) {
+            wsManagementFunctions.push(dbStack.redeliverFunction)
+        }
+        wsManagementFunctions.forEach((fn) =>
+            fn.addToRolePolicy(
+                new iam.PolicyStatement({
+                    effect: iam.Effect.ALLOW,
+                    actions: ['execute-api:ManageConnections'],
+                    resources: [
+                        `arn:aws:execute-api:${this.region}:${this.account}:${wsApi.apiId}/${wsStage.stageName}/POST/@connections/*`,
+                        `arn:aws:execute-api:${this.region}:${this.account}:${wsApi.apiId}/${wsStage.stageName}/DELETE/@connections/*`,
+                    ],
+                })
+            )
+        )
+
+        // Connections made against a failover region's WebSocket API are posted via that API,
+        // so fan-out may manage connections on any of this account's APIs for the stage
+        const failoverStatement = new iam.PolicyStatement({
+            effect: iam.Effect.ALLOW,
+            actions: ['execute-api:ManageConnections'],
+            resources: [`arn:aws:execute-api:*:${this.account}:*/${wsStage.stageName}/POST/@connections/*`],
+        })
+        broadcastFunction.addToRolePolicy(failoverStatement)
+        dbStack.redeliverFunction?.addToRolePolicy(failoverStatement)
+        onConnectFunction.addToRolePolicy(failoverStatement)
+        onDisconnectFunction.addToRolePolicy(failoverStatement)
+
         // === DNS Records ===
         // REST A-record (api.<domain>) -> API Gateway v2 HTTP custom domain
         new route53.ARecord(this, 'RestApiAliasRecord', {
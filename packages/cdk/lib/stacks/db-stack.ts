@@ -1,5 +1,7 @@
 import * as cdk from 'aws-cdk-lib'
 import * as dynamodb from 'aws-cdk-lib/aws-dynamodb'
+import * as events from 'aws-cdk-lib/aws-events'
+import * as eventsTargets from 'aws-cdk-lib/aws-events-targets'
 import * as lambda from 'aws-cdk-lib/aws-lambda'
 import * as lambdaEventSources from 'aws-cdk-lib/aws-lambda-event-sources'
 import * as iam from 'aws-cdk-lib/aws-iam'
@@ -15,7 +17,13 @@ export class DbStack extends cdk.Stack {
     public readonly chatRoomsTable: dynamodb.Table
     public readonly chatMessagesTable: dynamodb.Table
     public readonly chatConnectionsTable: dynamodb.Table
+    public readonly chatUsersTable: dynamodb.Table
+    public readonly chatFlagsTable: dynamodb.Table
+    public readonly connectionAuditTable?: dynamodb.Table
     public readonly broadcastFunction: lambda.Function
+    public readonly redeliverFunction?: lambda.Function
+    public readonly connectionGaugeFunction: lambda.Function
+    public readonly compactHistoryFunction?: lambda.Function
 
     constructor(scope: Construct, id: string, props: DbStackProps) {
         super(scope, id, props)
@@ -33,14 +41,18 @@ export class DbStack extends cdk.Stack {
             removalPolicy: isProd ? cdk.RemovalPolicy.RETAIN : cdk.RemovalPolicy.DESTROY,
         })
 
-        // Chat Messages Table (with DynamoDB Streams for real-time broadcasting)
+        // Chat Messages Table (with DynamoDB Streams for real-time broadcasting). The sort key is
+        // `{ts:013}#{id}`, so messages from the same millisecond neither collide nor reorder.
         this.chatMessagesTable = new dynamodb.Table(this, 'ChatMessagesTable', {
             tableName: DYNAMODB_TABLES.CHAT_MESSAGES,
             partitionKey: { name: 'room_id', type: dynamodb.AttributeType.STRING },
-            sortKey: { name: 'ts', type: dynamodb.AttributeType.NUMBER },
-            stream: dynamodb.StreamViewType.NEW_IMAGE,
+            sortKey: { name: 'sk', type: dynamodb.AttributeType.STRING },
+            // Old images let the broadcaster tell an edit from the other updates to a message
+            stream: dynamodb.StreamViewType.NEW_AND_OLD_IMAGES,
             billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
             removalPolicy: isProd ? cdk.RemovalPolicy.RETAIN : cdk.RemovalPolicy.DESTROY,
+            // TTL for self-destructing (ephemeral) messages
+            timeToLiveAttribute: 'ttl',
         })
 
         // Chat Connections Table (for WebSocket client management)
@@ -53,6 +65,24 @@ export class DbStack extends cdk.Stack {
             timeToLiveAttribute: 'ttl',
         })
 
+        // User profiles (display name changes), keyed by user_id
+        this.chatUsersTable = new dynamodb.Table(this, 'ChatUsersTable', {
+            tableName: DYNAMODB_TABLES.CHAT_USERS,
+            partitionKey: { name: 'user_id', type: dynamodb.AttributeType.STRING },
+            billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
+            removalPolicy: isProd ? cdk.RemovalPolicy.RETAIN : cdk.RemovalPolicy.DESTROY,
+        })
+
+        // Users' flags on messages for moderators, one per flagger (client origin hash) per
+        // message
+        this.chatFlagsTable = new dynamodb.Table(this, 'ChatFlagsTable', {
+            tableName: DYNAMODB_TABLES.CHAT_FLAGS,
+            partitionKey: { name: 'message_id', type: dynamodb.AttributeType.STRING },
+            sortKey: { name: 'flagger', type: dynamodb.AttributeType.STRING },
+            billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
+            removalPolicy: isProd ? cdk.RemovalPolicy.RETAIN : cdk.RemovalPolicy.DESTROY,
+        })
+
         // Add GSI for querying connections by room
         this.chatConnectionsTable.addGlobalSecondaryIndex({
             indexName: 'room-index',
@@ -60,7 +90,47 @@ export class DbStack extends cdk.Stack {
             sortKey: { name: 'connected_at', type: dynamodb.AttributeType.NUMBER },
         })
 
+        // Add GSI for finding a user's connections across rooms (MAX_CONNECTIONS_PER_USER,
+        // query_user_connections)
+        this.chatConnectionsTable.addGlobalSecondaryIndex({
+            indexName: 'user-index',
+            partitionKey: { name: 'user_id', type: dynamodb.AttributeType.STRING },
+            sortKey: { name: 'connected_at', type: dynamodb.AttributeType.NUMBER },
+            projectionType: dynamodb.ProjectionType.KEYS_ONLY,
+        })
+
+        // Add GSI for listing rooms newest first a page at a time (rooms_page). Every room row
+        // carries listing = "rooms"; rows written before it existed need it backfilled to be
+        // listed (see the backend README).
+        this.chatRoomsTable.addGlobalSecondaryIndex({
+            indexName: 'created-index',
+            partitionKey: { name: 'listing', type: dynamodb.AttributeType.STRING },
+            sortKey: { name: 'created_at_epoch', type: dynamodb.AttributeType.NUMBER },
+        })
+
+        // Add GSI for answering a retried post with the message it already stored
+        // (find_client_message); messages without a client_message_id stay out of it
+        this.chatMessagesTable.addGlobalSecondaryIndex({
+            indexName: 'client-message-index',
+            partitionKey: { name: 'room_id', type: dynamodb.AttributeType.STRING },
+            sortKey: { name: 'client_message_id', type: dynamodb.AttributeType.STRING },
+        })
+
+        // Connection audit log (opt-in): rows are written once and never expire, so no TTL and
+        // the table is kept even when the stack is torn down
+        if (stageConfig.auditConnections) {
+            this.connectionAuditTable = new dynamodb.Table(this, 'ChatConnectionAuditTable', {
+                tableName: DYNAMODB_TABLES.CHAT_CONNECTION_AUDIT,
+                partitionKey: { name: 'connection_id_hash', type: dynamodb.AttributeType.STRING },
+                sortKey: { name: 'event', type: dynamodb.AttributeType.STRING },
+                billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
+                removalPolicy: cdk.RemovalPolicy.RETAIN,
+                pointInTimeRecovery: true,
+            })
+        }
+
         // Seed default "general" room on deployment
+        const seededAt = new Date()
         new cr.AwsCustomResource(this, 'SeedGeneralRoom', {
             onCreate: {
                 service: 'DynamoDB',
@@ -70,8 +140,9 @@ export class DbStack extends cdk.Stack {
                     Item: {
                         id: { S: 'general' },
                         name: { S: 'General' },
-                        created_at_iso: { S: new Date().toISOString() },
-                        created_at_epoch: { N: `${Date.now()}` },
+                        created_at_iso: { S: seededAt.toISOString() },
+                        created_at_epoch: { N: `${Math.floor(seededAt.getTime() / 1000)}` },
+                        listing: { S: 'rooms' },
                     },
                     ConditionExpression: 'attribute_not_exists(id)',
                 },
@@ -92,6 +163,8 @@ export class DbStack extends cdk.Stack {
             code: lambda.Code.fromAsset('../backend/target/lambda/ws-broadcast'),
             environment: {
                 CONNECTIONS_TABLE: DYNAMODB_TABLES.CHAT_CONNECTIONS,
+                // Delivery counts are written back onto each message
+                CHAT_MESSAGES_TABLE: this.chatMessagesTable.tableName,
                 STAGE: stageConfig.name,
             },
             timeout: cdk.Duration.seconds(30),
@@ -99,6 +172,9 @@ export class DbStack extends cdk.Stack {
 
         // Grant DynamoDB permissions to broadcast function
         this.chatConnectionsTable.grantReadWriteData(this.broadcastFunction)
+        this.chatMessagesTable.grantWriteData(this.broadcastFunction)
+        // Reads past a removed message, to tell a deletion from compaction
+        this.chatMessagesTable.grant(this.broadcastFunction, 'dynamodb:Query')
 
         // Grant WebSocket management permissions to broadcast function
         // Note: The WebSocket API ID and stage will be added when this function is used in ApiStack
@@ -120,11 +196,119 @@ export class DbStack extends cdk.Stack {
                 filters: [
                     lambda.FilterCriteria.filter({
                         eventName: lambda.FilterRule.isEqual('INSERT'),
+                        // Segments written by history compaction hold no new messages, and
+                        // client_message_id claims hold none at all
+                        dynamodb: {
+                            NewImage: {
+                                segment: { B: lambda.FilterRule.notExists() },
+                                client_claim: { BOOL: lambda.FilterRule.notExists() },
+                            },
+                        },
+                    }),
+                    // Edits, and updates to edited messages; the function passes on all but the edit
+                    lambda.FilterCriteria.filter({
+                        eventName: lambda.FilterRule.isEqual('MODIFY'),
+                        dynamodb: { NewImage: { edited_at: { N: lambda.FilterRule.exists() } } },
+                    }),
+                    // Deleted messages; TTL expiries and compaction are passed on by the function
+                    lambda.FilterCriteria.filter({
+                        eventName: lambda.FilterRule.isEqual('REMOVE'),
+                        dynamodb: {
+                            OldImage: {
+                                segment: { B: lambda.FilterRule.notExists() },
+                                client_claim: { BOOL: lambda.FilterRule.notExists() },
+                            },
+                        },
                     }),
                 ],
             })
         )
 
+        // === Durable Broadcast (opt-in) ===
+        // Fan-out records pending deliveries here; a scheduled lambda redelivers what's left
+        if (stageConfig.broadcastDurable) {
+            const retryTable = new dynamodb.Table(this, 'ChatBroadcastRetriesTable', {
+                tableName: DYNAMODB_TABLES.CHAT_BROADCAST_RETRIES,
+                partitionKey: { name: 'connection_id', type: dynamodb.AttributeType.STRING },
+                sortKey: { name: 'message_id', type: dynamodb.AttributeType.STRING },
+                billingMode: dynamodb.BillingMode.PAY_PER_REQUEST,
+                removalPolicy: cdk.RemovalPolicy.DESTROY,
+                // Entries that can't be delivered before they expire are given up on
+                timeToLiveAttribute: 'ttl',
+            })
+
+            this.broadcastFunction.addEnvironment('BROADCAST_DURABLE', 'true')
+            this.broadcastFunction.addEnvironment('RETRY_TABLE', retryTable.tableName)
+            retryTable.grantReadWriteData(this.broadcastFunction)
+
+            this.redeliverFunction = new lambda.Function(this, 'RedeliverFunction', {
+                functionName: `ws-redeliver-${stageConfig.name}`,
+                runtime: lambda.Runtime.PROVIDED_AL2023,
+                architecture: lambda.Architecture.ARM_64,
+                handler: 'bootstrap',
+                code: lambda.Code.fromAsset('../backend/target/lambda/ws-redeliver'),
+                environment: {
+                    BROADCAST_DURABLE: 'true',
+                    RETRY_TABLE: retryTable.tableName,
+                    STAGE: stageConfig.name,
+                },
+                timeout: cdk.Duration.seconds(30),
+            })
+            retryTable.grantReadWriteData(this.redeliverFunction)
+
+            new events.Rule(this, 'RedeliverSchedule', {
+                schedule: events.Schedule.rate(cdk.Duration.minutes(1)),
+                targets: [new eventsTargets.LambdaFunction(this.redeliverFunction)],
+            })
+        }
+
+        // === Connection Gauge ===
+        // Emits per-room ActiveConnections every minute, so concurrency is graphed even when
+        // nobody connects or disconnects. Counts come from one scan of the connections table.
+        this.connectionGaugeFunction = new lambda.Function(this, 'ConnectionGaugeFunction', {
+            functionName: `ws-connection-gauge-${stageConfig.name}`,
+            runtime: lambda.Runtime.PROVIDED_AL2023,
+            architecture: lambda.Architecture.ARM_64,
+            handler: 'bootstrap',
+            code: lambda.Code.fromAsset('../backend/target/lambda/ws-connection-gauge'),
+            environment: {
+                CONNECTIONS_TABLE: DYNAMODB_TABLES.CHAT_CONNECTIONS,
+                STAGE: stageConfig.name,
+            },
+            timeout: cdk.Duration.seconds(30),
+        })
+        this.chatConnectionsTable.grantReadData(this.connectionGaugeFunction)
+
+        new events.Rule(this, 'ConnectionGaugeSchedule', {
+            schedule: events.Schedule.rate(cdk.Duration.minutes(1)),
+            targets: [new eventsTargets.LambdaFunction(this.connectionGaugeFunction)],
+        })
+
+        // === History Compaction (opt-in) ===
+        // Rolls old messages into compressed segments; the newest stay plain items
+        if (stageConfig.compactHistory) {
+            this.compactHistoryFunction = new lambda.Function(this, 'CompactHistoryFunction', {
+                functionName: `compact-history-${stageConfig.name}`,
+                runtime: lambda.Runtime.PROVIDED_AL2023,
+                architecture: lambda.Architecture.ARM_64,
+                handler: 'bootstrap',
+                code: lambda.Code.fromAsset('../backend/target/lambda/compact-history'),
+                environment: {
+                    CHAT_ROOMS_TABLE: DYNAMODB_TABLES.CHAT_ROOMS,
+                    CHAT_MESSAGES_TABLE: DYNAMODB_TABLES.CHAT_MESSAGES,
+                    STAGE: stageConfig.name,
+                },
+                timeout: cdk.Duration.minutes(15),
+            })
+            this.chatRoomsTable.grantReadData(this.compactHistoryFunction)
+            this.chatMessagesTable.grantReadWriteData(this.compactHistoryFunction)
+
+            new events.Rule(this, 'CompactHistorySchedule', {
+                schedule: events.Schedule.rate(cdk.Duration.days(1)),
+                targets: [new eventsTargets.LambdaFunction(this.compactHistoryFunction)],
+            })
+        }
+
         // === Outputs ===
         new cdk.CfnOutput(this, 'ChatRoomsTableName', {
             value: this.chatRoomsTable.tableName,
@@ -140,5 +324,15 @@ export class DbStack extends cdk.Stack {
             value: this.chatConnectionsTable.tableName,
             description: 'Chat connections DynamoDB table name',
         })
+
+        new cdk.CfnOutput(this, 'ChatUsersTableName', {
+            value: this.chatUsersTable.tableName,
+            description: 'Chat user profiles DynamoDB table name',
+        })
+
+        new cdk.CfnOutput(this, 'ChatFlagsTableName', {
+            value: this.chatFlagsTable.tableName,
+            description: 'Chat message flags DynamoDB table name',
+        })
     }
 }
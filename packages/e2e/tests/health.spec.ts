@@ -13,6 +13,7 @@ test.describe('Health Check', () => {
             expect(healthCheck.status).toBe('Healthy')
             expect(healthCheck.version).toBeTruthy()
             expect(healthCheck.timestamp).toBeTruthy()
+            expect(healthCheck.checks.messages_table).toBe('Healthy')
 
             // Log stage info for pipeline debugging
             if (process.env.STAGE) {
@@ -38,7 +38,7 @@ const AWS_REGION = process.env.AWS_DEFAULT_REGION || 'us-east-1'
 const TEST_ASSUME_ROLE_ARN = process.env.TEST_ASSUME_ROLE_ARN
 
 // DynamoDB table names from environment variables
-const CHAT_MESSAGES_TABLE = process.env.CHAT_MESSAGES_TABLE || 'chat-messages'
+const CHAT_MESSAGES_TABLE = process.env.CHAT_MESSAGES_TABLE || 'chat-messages-v2'
 // const CHAT_CONNECTIONS_TABLE = process.env.CHAT_CONNECTIONS_TABLE || 'chat-connections'
 
 // Test user data
@@ -197,7 +197,7 @@ async function deleteMessageFromDynamoDB(message: Message): Promise<void> {
             TableName: CHAT_MESSAGES_TABLE,
             Key: {
                 room_id: itemToDelete.room_id,
-                ts: itemToDelete.ts,
+                sk: itemToDelete.sk,
             },
         }
 
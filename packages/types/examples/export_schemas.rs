@@ -0,0 +1,14 @@
+// Write the JSON Schemas for the wire types: `cargo run -p types --example export_schemas
+// --features jsonschema [-- <dir>]` (defaults to `schemas/`)
+use std::path::PathBuf;
+
+fn main() -> std::io::Result<()> {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "schemas".into());
+    for path in types::schema::export_schemas(&dir)? {
+        println!("wrote {}", path.display());
+    }
+    Ok(())
+}
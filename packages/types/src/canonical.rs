@@ -0,0 +1,190 @@
+use crate::{ChatMessage, MessageFormat, MessageVisibility};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fmt::Write;
+
+/// Version of the `canonical_bytes` layout, written as its `sig_version` field. Signatures
+/// over a message are only comparable between signer and verifier on the same version, so
+/// any change to the layout (a field added, dropped, renamed, reordered or formatted
+/// differently) must come with a bump.
+pub const SIG_VERSION: u32 = 1;
+
+/// The message as signing input: a compact JSON object whose keys are written in a fixed
+/// order, independent of field order in `ChatMessage` or of how serde would lay it out.
+/// Timestamps are RFC 3339 in UTC with millisecond precision; absent values are `null`. Only
+/// what the sender decided is covered, so the delivery state (`status`, `delivered_count`)
+/// and the server's `clock_skew_ms` note can change without invalidating a signature.
+///
+/// Never change this without bumping `SIG_VERSION`.
+pub fn canonical_bytes(message: &ChatMessage) -> Vec<u8> {
+    let core = &message.core;
+    let (format, lang) = match &message.format {
+        MessageFormat::Plain => ("plain", None),
+        MessageFormat::Quote => ("quote", None),
+        MessageFormat::Code { lang } => ("code", lang.as_deref()),
+    };
+    let visibility = match message.visibility {
+        MessageVisibility::Room => "room",
+        MessageVisibility::Direct => "direct",
+    };
+
+    let mut out = String::new();
+    out.push('{');
+    field(&mut out, "sig_version", &SIG_VERSION.to_string());
+    string_field(&mut out, "id", Some(&core.id));
+    string_field(&mut out, "room_id", Some(&core.room_id));
+    string_field(&mut out, "user_id", Some(&core.user_id));
+    string_field(&mut out, "username", Some(&core.username));
+    string_field(&mut out, "message_text", Some(&core.message_text));
+    string_field(&mut out, "created_at", Some(&timestamp(core.created_at)));
+    string_field(
+        &mut out,
+        "client_message_id",
+        message.client_message_id.as_deref(),
+    );
+    string_field(
+        &mut out,
+        "client_created_at",
+        message.client_created_at.map(timestamp).as_deref(),
+    );
+    field(
+        &mut out,
+        "client_seq",
+        &message
+            .client_seq
+            .map_or("null".to_string(), |seq| seq.to_string()),
+    );
+    string_field(
+        &mut out,
+        "expires_at",
+        message.expires_at.map(timestamp).as_deref(),
+    );
+    string_field(&mut out, "format", Some(format));
+    string_field(&mut out, "format_lang", lang);
+    string_field(&mut out, "visibility", Some(visibility));
+    string_field(&mut out, "to_user_id", message.to_user_id.as_deref());
+    out.push('}');
+    out.into_bytes()
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// `"key":value`, comma-separated from the previous field
+fn field(out: &mut String, key: &str, value: &str) {
+    if !out.ends_with('{') {
+        out.push(',');
+    }
+    push_string(out, key);
+    out.push(':');
+    out.push_str(value);
+}
+
+fn string_field(out: &mut String, key: &str, value: Option<&str>) {
+    let mut quoted = String::new();
+    match value {
+        Some(value) => push_string(&mut quoted, value),
+        None => quoted.push_str("null"),
+    }
+    field(out, key, &quoted);
+}
+
+// A JSON string with the minimal escaping: quotes, backslashes and control characters only
+fn push_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageCore, MessageStatus};
+
+    fn message() -> ChatMessage {
+        ChatMessage {
+            core: MessageCore {
+                id: "m1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "u1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hi \"there\"\n\u{1}".to_string(),
+                created_at: DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+            },
+            client_message_id: Some("c1".to_string()),
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Code {
+                lang: Some("rust".to_string()),
+            },
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: Some(7),
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        }
+    }
+
+    #[test]
+    fn test_canonical_bytes_are_stable() {
+        // Pinned: if this changes, SIG_VERSION must change with it
+        let expected = concat!(
+            r#"{"sig_version":1,"id":"m1","room_id":"general","user_id":"u1","username":"alice","#,
+            r#""message_text":"Hi \"there\"\n\u0001","created_at":"2023-11-14T22:13:20.123Z","#,
+            r#""client_message_id":"c1","client_created_at":null,"client_seq":7,"expires_at":null,"#,
+            r#""format":"code","format_lang":"rust","visibility":"room","to_user_id":null}"#
+        );
+        assert_eq!(
+            String::from_utf8(canonical_bytes(&message())).unwrap(),
+            expected
+        );
+        assert_eq!(canonical_bytes(&message()), canonical_bytes(&message()));
+    }
+
+    #[test]
+    fn test_canonical_bytes_ignore_field_order_and_delivery_state() {
+        let message = message();
+        // The same message arriving with its keys in another order: serde_json's map sorts
+        // them alphabetically, where the struct writes them in field order
+        let json = serde_json::to_value(&message).unwrap();
+        let reordered: ChatMessage =
+            serde_json::from_str(&serde_json::to_string(&json).unwrap()).unwrap();
+        assert!(!serde_json::to_string(&json)
+            .unwrap()
+            .starts_with(r#"{"id""#));
+        assert_eq!(canonical_bytes(&reordered), canonical_bytes(&message));
+
+        let delivered = ChatMessage {
+            status: MessageStatus::Broadcast,
+            delivered_count: Some(3),
+            ..message.clone()
+        };
+        assert_eq!(canonical_bytes(&delivered), canonical_bytes(&message));
+
+        let edited = ChatMessage {
+            core: MessageCore {
+                message_text: "Hi".to_string(),
+                ..message.core.clone()
+            },
+            ..message.clone()
+        };
+        assert_ne!(canonical_bytes(&edited), canonical_bytes(&message));
+    }
+}
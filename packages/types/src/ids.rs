@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::Deref};
+use ts_rs::TS;
+
+// A kind of id. On the wire (and in TypeScript) it's a plain string, as serde writes newtype
+// structs as their contents; in Rust it's its own type, so passing a room id where a user id
+// belongs doesn't compile.
+macro_rules! string_id {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, TS)]
+        #[ts(export)]
+        #[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new(id: impl Into<String>) -> Self {
+                Self(id.into())
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_inner(self) -> String {
+                self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                Self(id.to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+    };
+}
+
+string_id!(
+    /// A chat room, e.g. `general`
+    RoomId
+);
+string_id!(
+    /// The stable id of a user, as issued by the auth provider
+    UserId
+);
+string_id!(
+    /// A stored message
+    MessageId
+);
+string_id!(
+    /// An API Gateway (or dev server) WebSocket connection
+    ConnectionId
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_serialize_as_plain_strings() {
+        let ids = (
+            RoomId::from("general"),
+            UserId::from("auth0|5f7c8ec7"),
+            MessageId::from("01ARZ3NDEKTSV4RRFFQ69G5FAV"),
+            ConnectionId::from("L0SM9cOFvHcCIhw="),
+        );
+        let json = serde_json::to_string(&ids).unwrap();
+        assert_eq!(
+            json,
+            serde_json::to_string(&(
+                "general",
+                "auth0|5f7c8ec7",
+                "01ARZ3NDEKTSV4RRFFQ69G5FAV",
+                "L0SM9cOFvHcCIhw="
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            serde_json::from_str::<(RoomId, UserId, MessageId, ConnectionId)>(&json).unwrap(),
+            ids
+        );
+    }
+
+    #[test]
+    fn test_ids_are_strings_in_typescript() {
+        assert_eq!(RoomId::inline(), "string");
+        assert_eq!(ConnectionId::decl(), "type ConnectionId = string;");
+    }
+}
@@ -2,9 +2,45 @@
 // These files are generated by ts-rs during the build process
 
 export * from '../bindings/HealthCheck'
+export * from '../bindings/RoomId'
+export * from '../bindings/UserId'
+export * from '../bindings/MessageId'
+export * from '../bindings/ConnectionId'
 export * from '../bindings/HealthStatus'
 export * from '../bindings/Room'
+export * from '../bindings/ListRoomsResponse'
 export * from '../bindings/Message'
+export * from '../bindings/MessageCore'
 export * from '../bindings/ChatMessage'
+export * from '../bindings/MessageStatus'
+export * from '../bindings/MessageFormat'
+export * from '../bindings/LinkPreview'
+export * from '../bindings/WsErrorCode'
+export * from '../bindings/error'
+export * from '../bindings/TypingIndicator'
+export * from '../bindings/WsServerMessage'
+export * from '../bindings/WsClientMessage'
+export * from '../bindings/UpdateUsernameRequest'
+export * from '../bindings/UserRenamed'
+export * from '../bindings/UpdateAvatarSeedRequest'
+export * from '../bindings/UserProfile'
+export * from '../bindings/UpdateTopicRequest'
+export * from '../bindings/TopicChanged'
+export * from '../bindings/RoomArchiveState'
+export * from '../bindings/DeliveryReceipt'
+export * from '../bindings/ReconnectReason'
+export * from '../bindings/ReconnectHint'
 export * from '../bindings/SendMessageRequest'
 export * from '../bindings/GetMessagesResponse'
+export * from '../bindings/PollMessagesResponse'
+export * from '../bindings/SearchMessagesResponse'
+export * from '../bindings/LatestMessagesRequest'
+export * from '../bindings/LatestMessagesResponse'
+export * from '../bindings/AdminMessageView'
+export * from '../bindings/EditMessageRequest'
+export * from '../bindings/FlagMessageRequest'
+export * from '../bindings/MessageFlag'
+export * from '../bindings/FlagMessageResponse'
+export * from '../bindings/RoomStats'
+export * from '../bindings/RoomPresence'
+export * from '../bindings/ApiError'
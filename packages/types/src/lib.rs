@@ -1,18 +1,34 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use ts_rs::TS;
 
+pub mod canonical;
+mod ids;
+#[cfg(feature = "jsonschema")]
+pub mod schema;
+pub mod time;
+
+pub use canonical::{canonical_bytes, SIG_VERSION};
+pub use ids::{ConnectionId, MessageId, RoomId, UserId};
+
 // Health Check Types
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct HealthCheck {
+    // The worst of `checks`
     pub status: HealthStatus,
     pub version: String,
     pub timestamp: DateTime<Utc>,
+    // Each dependency probed, by name
+    pub checks: HashMap<String, HealthStatus>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+// Declared best to worst, so the worst of several is their max
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, PartialOrd, Ord)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub enum HealthStatus {
     Healthy,
     Degraded,
@@ -22,14 +38,33 @@ pub enum HealthStatus {
 // Chat Types
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct Room {
     pub id: String,
     pub name: String,
     pub created_at: DateTime<Utc>,
+    // What the room is currently about; None until someone sets one
+    #[serde(default)]
+    pub topic: Option<String>,
+    // Frozen by an admin: still readable, but closed to new messages
+    #[serde(default)]
+    pub archived: bool,
+}
+
+// A page of `GET /chat/rooms`, newest room first
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct ListRoomsResponse {
+    pub rooms: Vec<Room>,
+    // Pass as `after` to fetch the next page; only set when there are more rooms
+    #[serde(default)]
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct Message {
     pub id: String, // ULID - unique message identifier
     #[ts(rename = "userId")]
@@ -37,11 +72,16 @@ pub struct Message {
     pub username: String, // Display name of the sender
     pub text: String, // Message content
     pub timestamp: DateTime<Utc>, // When the message was sent
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>, // When the text was last changed, if it has been
 }
 
+// Fields every stored message has, flattened into the richer message types so they serialize
+// side by side with the type's own fields
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
-pub struct ChatMessage {
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct MessageCore {
     pub id: String,
     pub room_id: String,
     #[ts(rename = "userId")]
@@ -49,13 +89,317 @@ pub struct ChatMessage {
     pub username: String,
     pub message_text: String,
     pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct ChatMessage {
+    #[serde(flatten)]
+    pub core: MessageCore,
     #[ts(rename = "clientMessageId")]
     pub client_message_id: Option<String>,
+    // Self-destructing message; the row is removed by TTL at `expires_at`
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    // Derived by the server for optimistic UIs; only Hidden is persisted
+    #[serde(default)]
+    pub status: MessageStatus,
+    #[serde(default)]
+    pub format: MessageFormat,
+    // The sender's own timestamp, when it sent one. Ordering always uses `created_at`.
+    #[serde(default)]
+    pub client_created_at: Option<DateTime<Utc>>,
+    // `client_created_at - created_at` in millis, set only when that's beyond the server's
+    // skew threshold (a queued offline message, or a client clock that's off)
+    #[serde(default)]
+    pub clock_skew_ms: Option<i64>,
+    // Connections the broadcast reached, recorded once fan-out finishes
+    #[serde(default)]
+    pub delivered_count: Option<u32>,
+    // The sender's own counter, when it sent one. Breaks ties between messages stored in the
+    // same millisecond; `created_at` still orders everything else.
+    #[serde(default)]
+    pub client_seq: Option<u64>,
+    #[serde(default)]
+    pub visibility: MessageVisibility,
+    // The recipient of a direct message
+    #[ts(rename = "toUserId")]
+    #[serde(default)]
+    pub to_user_id: Option<String>,
+    // Preview of the first link in the text, attached by the server after the message is sent
+    #[serde(default)]
+    pub link_preview: Option<LinkPreview>,
+    // What clients derive the sender's avatar and color from: the seed on their profile, or
+    // their user id. Unlike `username` it survives a rename.
+    #[serde(default)]
+    pub avatar_seed: Option<String>,
+    // When the sender last changed the text; None while it's as sent
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+// OpenGraph metadata of a page a message links to
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+// Who may see a message: everyone in its room, or only its sender and `to_user_id`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub enum MessageVisibility {
+    #[default]
+    Room,
+    Direct,
+}
+
+// How clients should render a message's text; the text itself never carries markup for it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "kind")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Code {
+        // Syntax highlighting language, from the server's allowlist
+        #[serde(default)]
+        lang: Option<String>,
+    },
+    Quote,
+}
+
+// How far a message has got: persisted, then fanned out to the room's connections. Hidden
+// messages were flagged by enough users to be held back for a moderator.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub enum MessageStatus {
+    #[default]
+    Stored,
+    Broadcast,
+    Hidden,
+}
+
+// Sent to the author's own connections once a message has been fanned out, for a "delivered
+// to N" indicator
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename = "delivery")]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+    pub room_id: String,
+    pub delivered_count: u32,
+}
+
+// Why the server closed a connection it expects the client to reopen
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub enum ReconnectReason {
+    Shutdown,
+    Overloaded,
+}
+
+// Carried in the reason of a close frame: how long to wait before reconnecting, so clients
+// closed together don't all come back at once
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename = "reconnect")]
+pub struct ReconnectHint {
+    pub reason: ReconnectReason,
+    pub after_ms: u32,
+}
+
+// What went wrong, in a WebSocket error frame, so clients can react without parsing the message:
+// re-authenticate, back off, or just show it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum WsErrorCode {
+    Unauthorized,
+    RateLimited,
+    FrameTooLarge,
+    BadFrame,
+    RoomFull,
+    ServerShutdown,
+    Internal,
+}
+
+// Sent on a WebSocket when a frame is refused or before the server closes the connection
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename = "error")]
+pub struct WsError {
+    pub code: WsErrorCode,
+    // For people, not for matching on
+    pub message: String,
+    // How long to wait before trying again, when waiting would help
+    #[serde(default)]
+    pub retry_after_ms: Option<u64>,
+}
+
+// Typing indicator relayed to the other members of a room
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct TypingIndicator {
+    pub room_id: String,
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    pub username: String,
+    pub is_typing: bool,
+}
+
+// Frames the server sends on a room's WebSocket, told apart by `type`
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsServerMessage {
+    // A new message, or a newer copy of one the client has (edited, or with a link preview).
+    // Boxed, as it's far bigger than the other frames.
+    Message(Box<ChatMessage>),
+    Typing(TypingIndicator),
+    // A message was deleted, for clients to drop their copy
+    MessageDeleted {
+        room_id: String,
+        message_id: String,
+    },
+    // Who's in the room: a username per connected user, sent when someone joins or leaves
+    Presence {
+        room_id: String,
+        users: Vec<String>,
+    },
+    // Same fields as `WsError`, which serializes to the same frame
+    Error {
+        code: WsErrorCode,
+        message: String,
+        #[serde(default)]
+        retry_after_ms: Option<u64>,
+    },
+}
+
+impl From<WsError> for WsServerMessage {
+    fn from(error: WsError) -> Self {
+        let WsError {
+            code,
+            message,
+            retry_after_ms,
+        } = error;
+        WsServerMessage::Error {
+            code,
+            message,
+            retry_after_ms,
+        }
+    }
+}
+
+// Frames clients send on a room's WebSocket, told apart by `type`
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsClientMessage {
+    // Started or stopped typing; relayed to the rest of the room
+    Typing { is_typing: bool },
+    // Diagnostic round trip, answered with the frame itself when WS_ECHO_ENABLED is on
+    Echo,
+}
+
+// Body of `PUT /chat/users/:user_id/username`
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct UpdateUsernameRequest {
+    pub username: String,
+}
+
+// A user's new display name, returned to the caller and relayed to connected clients so live
+// labels can follow. Messages already sent keep the name they were sent with.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct UserRenamed {
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    // None when the user had no profile yet
+    pub old_username: Option<String>,
+    pub username: String,
+}
+
+// Body of `PUT /chat/users/:user_id/avatar-seed`. None goes back to the user id.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct UpdateAvatarSeedRequest {
+    #[serde(default)]
+    pub avatar_seed: Option<String>,
+}
+
+// What a user has set on their profile. The server fills `avatar_seed` in with the user id
+// when it hands a profile out, so clients always have a seed to draw from.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct UserProfile {
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub avatar_seed: Option<String>,
+}
+
+// Body of `PUT /chat/rooms/:room_id/topic`
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct UpdateTopicRequest {
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    // None or blank clears the topic
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+// A room's new topic, returned to the caller and relayed to the room's connections so every
+// client's header updates live
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct TopicChanged {
+    pub room_id: String,
+    pub topic: Option<String>,
+    // User id of whoever set it
+    pub by: String,
+}
+
+// What `POST /chat/rooms/:room_id/archive` and `/unarchive` left the room at
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct RoomArchiveState {
+    pub room_id: String,
+    pub archived: bool,
 }
 
 // Legacy room-based API types (keep for backward compatibility)
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct SendMessageRequest {
     pub room_id: String,
     #[ts(rename = "userId")]
@@ -64,18 +408,174 @@ pub struct SendMessageRequest {
     pub message_text: String,
     #[ts(rename = "clientMessageId")]
     pub client_message_id: Option<String>,
+    // Optional self-destruct timer for the message, in seconds
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    #[serde(default)]
+    pub format: MessageFormat,
+    // When the client composed the message, for offline-first clients that queue sends
+    #[serde(default)]
+    pub client_created_at: Option<DateTime<Utc>>,
+    // Monotonic per-client counter, so a burst of queued sends stored in the same millisecond
+    // keeps the order it was composed in. Must fit a JavaScript number.
+    #[serde(default)]
+    pub client_seq: Option<u64>,
+    // Whisper: only this user and the sender see the message
+    #[ts(rename = "toUserId")]
+    #[serde(default)]
+    pub to_user_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct GetMessagesResponse {
     pub room_id: String,
     pub messages: Vec<ChatMessage>,
+    // Server clock when the response was built, so clients can correct for their own skew.
+    // Older servers omit it; the receiver's clock is the closest stand-in.
+    #[serde(default = "Utc::now")]
+    pub server_time: DateTime<Utc>,
+    // Pass as `after` to fetch the next page; only set when this page was full
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+    // Pass as `before` to fetch the page before this one: the oldest message's ts, only set
+    // when this page was read back from `before` and was full
+    #[serde(default)]
+    pub before_cursor: Option<i64>,
+}
+
+// Per-room aggregates for dashboards
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct RoomStats {
+    pub room_id: String,
+    // Unexpired messages currently stored in the room
+    pub message_count: u32,
+    pub active_connections: u32,
+    // Distinct usernames among the newest `sample_size` messages
+    pub unique_participants: u32,
+    pub sample_size: u32,
+    // When the newest message was posted; None for an empty room
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+// Who's connected to a room right now
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct RoomPresence {
+    pub room_id: String,
+    // One username per connected user, however many connections they have open, sorted
+    pub users: Vec<String>,
+}
+
+// Moderator-only view of a message with where it was sent from. Normal reads return plain
+// ChatMessages, which never carry origin data.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct AdminMessageView {
+    pub message: ChatMessage,
+    // Salted hash of the sender's network; absent when origin capture was off
+    pub origin_hash: Option<String>,
+    // Coarse ISO country code, when a geo lookup is configured
+    pub origin_country: Option<String>,
+}
+
+// Body of `POST /chat/messages/:room_id/:id/flag`
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct FlagMessageRequest {
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    pub reason: String,
+}
+
+// Body of `PATCH /chat/messages/:room_id/:id`; only the message's sender may edit it
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct EditMessageRequest {
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    pub message_text: String,
+}
+
+// One user's flag on a message, as listed in the moderator queue
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct MessageFlag {
+    pub message_id: String,
+    pub room_id: String,
+    #[ts(rename = "userId")]
+    pub user_id: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// What flagging a message left it at
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct FlagMessageResponse {
+    pub message_id: String,
+    pub room_id: String,
+    pub flag_count: u32,
+    // Whether the message is now hidden, by this flag or an earlier one
+    pub hidden: bool,
+}
+
+// Long-poll page: messages created after the request's cursor, and the cursor to send next
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct PollMessagesResponse {
+    pub room_id: String,
+    pub messages: Vec<ChatMessage>,
+    // Sort key of the newest message seen so far, sent back as the next poll's cursor
+    pub cursor: String,
+    pub server_time: DateTime<Utc>,
+}
+
+// Messages in a room whose text matches a search, newest first
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct SearchMessagesResponse {
+    pub room_id: String,
+    pub query: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+// Newest messages of several rooms at once, e.g. for a home screen. Answered with a
+// `LatestMessagesResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct LatestMessagesRequest {
+    pub room_ids: Vec<String>,
+    pub per_room: u8,
+}
+
+// Each room's newest messages, newest first, keyed by room id. Rooms that couldn't be read are
+// in `errors` instead, with why, and the response is a 207 rather than a 200.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct LatestMessagesResponse {
+    pub rooms: HashMap<String, Vec<ChatMessage>>,
+    #[serde(default)]
+    pub errors: HashMap<String, String>,
 }
 
 // New frontend-expected API types
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct SendMessageApiRequest {
     #[ts(rename = "userId")]
     pub user_id: String, // ULID - sender's unique identifier
@@ -85,6 +585,7 @@ pub struct SendMessageApiRequest {
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
 pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
@@ -94,6 +595,20 @@ pub struct ApiResponse<T> {
     pub code: Option<String>,
 }
 
+// One problem with a request, reported per field so forms can flag every bad input at once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+#[cfg_attr(feature = "jsonschema", derive(schemars::JsonSchema))]
+pub struct ApiError {
+    pub field: String,
+    pub message: String,
+    // For a value over its length limit: the limit, and the length that was sent
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub actual: Option<usize>,
+}
+
 // Export types for easy access - removed redundant pub use since types are already defined in this module
 
 #[cfg(test)]
@@ -106,6 +621,7 @@ mod tests {
             status: HealthStatus::Healthy,
             version: "0.1.0".to_string(),
             timestamp: Utc::now(),
+            checks: HashMap::from([("messages_table".to_string(), HealthStatus::Healthy)]),
         };
 
         assert_eq!(health.status, HealthStatus::Healthy);
@@ -120,6 +636,7 @@ mod tests {
             username: "alice".to_string(),
             text: "Hello world!".to_string(),
             timestamp: Utc::now(),
+            edited_at: None,
         };
 
         let json = serde_json::to_string(&message).unwrap();
@@ -139,6 +656,11 @@ mod tests {
             username: "alice".to_string(),
             message_text: "Hello!".to_string(),
             client_message_id: Some("01ARZ3NDEKTSV4RRFFQ69G5FB2".to_string()),
+            expires_in_secs: None,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            client_seq: None,
+            to_user_id: None,
         };
 
         let json = serde_json::to_string(&request).unwrap();
@@ -155,28 +677,61 @@ mod tests {
     fn test_get_messages_response() {
         let messages = vec![
             ChatMessage {
-                id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
-                room_id: "general".to_string(),
-                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
-                username: "alice".to_string(),
-                message_text: "Hello!".to_string(),
-                created_at: Utc::now(),
+                core: MessageCore {
+                    id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+                    room_id: "general".to_string(),
+                    user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                    username: "alice".to_string(),
+                    message_text: "Hello!".to_string(),
+                    created_at: Utc::now(),
+                },
                 client_message_id: None,
+                ephemeral: false,
+                expires_at: None,
+                status: MessageStatus::Stored,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                clock_skew_ms: None,
+                delivered_count: None,
+                client_seq: None,
+                visibility: MessageVisibility::Room,
+                to_user_id: None,
+                link_preview: None,
+                avatar_seed: None,
+                edited_at: None,
             },
             ChatMessage {
-                id: "01ARZ3NDEKTSV4RRFFQ69G5FB2".to_string(),
-                room_id: "general".to_string(),
-                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB3".to_string(),
-                username: "bob".to_string(),
-                message_text: "Hi Alice!".to_string(),
-                created_at: Utc::now(),
+                core: MessageCore {
+                    id: "01ARZ3NDEKTSV4RRFFQ69G5FB2".to_string(),
+                    room_id: "general".to_string(),
+                    user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB3".to_string(),
+                    username: "bob".to_string(),
+                    message_text: "Hi Alice!".to_string(),
+                    created_at: Utc::now(),
+                },
                 client_message_id: None,
+                ephemeral: false,
+                expires_at: None,
+                status: MessageStatus::Stored,
+                format: MessageFormat::Plain,
+                client_created_at: None,
+                clock_skew_ms: None,
+                delivered_count: None,
+                client_seq: None,
+                visibility: MessageVisibility::Room,
+                to_user_id: None,
+                link_preview: None,
+                avatar_seed: None,
+                edited_at: None,
             },
         ];
 
         let response = GetMessagesResponse {
             room_id: "general".to_string(),
             messages,
+            server_time: Utc::now(),
+            next_cursor: None,
+            before_cursor: None,
         };
 
         let json = serde_json::to_string(&response).unwrap();
@@ -184,7 +739,190 @@ mod tests {
 
         assert_eq!(response.room_id, deserialized.room_id);
         assert_eq!(response.messages.len(), deserialized.messages.len());
-        assert_eq!(response.messages[0].username, "alice");
-        assert_eq!(response.messages[1].username, "bob");
+        assert_eq!(response.messages[0].core.username, "alice");
+        assert_eq!(response.messages[1].core.username, "bob");
+    }
+
+    #[test]
+    fn test_get_messages_response_without_server_time() {
+        let before = Utc::now();
+        let response: GetMessagesResponse =
+            serde_json::from_str(r#"{"room_id":"general","messages":[]}"#).unwrap();
+
+        assert!(response.server_time >= before);
+        assert!(response.server_time <= Utc::now());
+    }
+
+    #[test]
+    fn test_message_status_defaults_to_stored() {
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"id":"m1","room_id":"general","user_id":"u1","username":"alice","message_text":"Hi","created_at":"2024-01-01T00:00:00Z","client_message_id":null}"#,
+        )
+        .unwrap();
+        assert_eq!(message.status, MessageStatus::Stored);
+
+        let json = serde_json::to_value(ChatMessage {
+            status: MessageStatus::Broadcast,
+            format: MessageFormat::Plain,
+            ..message
+        })
+        .unwrap();
+        assert_eq!(json["status"], "Broadcast");
+    }
+
+    #[test]
+    fn test_ephemeral_message_round_trip() {
+        let expires_at = Utc::now() + chrono::Duration::seconds(30);
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "one-time code: 1234".to_string(),
+                created_at: Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: true,
+            expires_at: Some(expires_at),
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+
+        let json = serde_json::to_string(&message).unwrap();
+        let deserialized: ChatMessage = serde_json::from_str(&json).unwrap();
+        assert!(deserialized.ephemeral);
+        assert_eq!(deserialized.expires_at, Some(expires_at));
+
+        // Payloads from before the field existed still parse
+        let legacy = r#"{"id":"1","room_id":"general","user_id":"u","username":"alice","message_text":"hi","created_at":"2024-01-01T00:00:00Z","client_message_id":null}"#;
+        let legacy: ChatMessage = serde_json::from_str(legacy).unwrap();
+        assert!(!legacy.ephemeral);
+        assert_eq!(legacy.expires_at, None);
+    }
+
+    #[test]
+    fn test_chat_message_wire_format_is_flat() {
+        let created_at = "2024-01-01T00:00:00Z".parse().unwrap();
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "m1".to_string(),
+                room_id: "general".to_string(),
+                user_id: "u1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hi".to_string(),
+                created_at,
+            },
+            client_message_id: Some("c1".to_string()),
+            ephemeral: false,
+            expires_at: None,
+            status: MessageStatus::Stored,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: Some("sunflower".to_string()),
+            edited_at: None,
+        };
+
+        // Same shape (and key order) as before the core fields were split out, plus `format`, the
+        // client timestamp fields, the delivery count, the client counter, the link preview, the avatar
+        // seed and the edit time
+        let json = r#"{"id":"m1","room_id":"general","user_id":"u1","username":"alice","message_text":"Hi","created_at":"2024-01-01T00:00:00Z","client_message_id":"c1","ephemeral":false,"expires_at":null,"status":"Stored","format":{"kind":"Plain"},"client_created_at":null,"clock_skew_ms":null,"delivered_count":null,"client_seq":null,"visibility":"Room","to_user_id":null,"link_preview":null,"avatar_seed":"sunflower","edited_at":null}"#;
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+
+        let parsed: ChatMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.core.id, "m1");
+        assert_eq!(parsed.core.created_at, created_at);
+        assert_eq!(parsed.avatar_seed.as_deref(), Some("sunflower"));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn test_chat_message_binding_is_flat() {
+        let decl = ChatMessage::decl();
+        assert!(!decl.contains("core"), "{}", decl);
+        for field in [
+            "id: string",
+            "room_id: string",
+            "message_text: string",
+            "status: MessageStatus",
+        ] {
+            assert!(decl.contains(field), "{} missing from {}", field, decl);
+        }
+    }
+
+    #[test]
+    fn test_code_message_round_trip() {
+        let legacy = r#"{"id":"m1","room_id":"general","user_id":"u1","username":"alice","message_text":"let x = 1;","created_at":"2024-01-01T00:00:00Z","client_message_id":null}"#;
+        let plain: ChatMessage = serde_json::from_str(legacy).unwrap();
+        assert_eq!(plain.format, MessageFormat::Plain);
+
+        let code = ChatMessage {
+            format: MessageFormat::Code {
+                lang: Some("rust".to_string()),
+            },
+            ..plain
+        };
+        let json = serde_json::to_value(&code).unwrap();
+        assert_eq!(
+            json["format"],
+            serde_json::json!({ "kind": "Code", "lang": "rust" })
+        );
+        let deserialized: ChatMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.format, code.format);
+
+        let request: SendMessageRequest = serde_json::from_str(
+            r#"{"room_id":"general","user_id":"u1","username":"alice","message_text":"> hi","client_message_id":null,"format":{"kind":"Quote"}}"#,
+        )
+        .unwrap();
+        assert_eq!(request.format, MessageFormat::Quote);
+    }
+
+    #[test]
+    fn test_ws_frames_are_tagged_by_type() {
+        let legacy = r#"{"id":"m1","room_id":"general","user_id":"u1","username":"alice","message_text":"Hi","created_at":"2024-01-01T00:00:00Z","client_message_id":null}"#;
+        let message: ChatMessage = serde_json::from_str(legacy).unwrap();
+        let frame =
+            serde_json::to_value(WsServerMessage::Message(Box::new(message.clone()))).unwrap();
+        // The message's own fields stay at the top level, so older clients still read them
+        assert_eq!(frame["type"], "message");
+        assert_eq!(frame["message_text"], "Hi");
+        let WsServerMessage::Message(parsed) = serde_json::from_value(frame).unwrap() else {
+            panic!("not a message frame");
+        };
+        assert_eq!(parsed.core.id, message.core.id);
+
+        let error = WsError {
+            code: WsErrorCode::BadFrame,
+            message: "Unknown frame".to_string(),
+            retry_after_ms: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&WsServerMessage::from(error.clone())).unwrap(),
+            serde_json::to_string(&error).unwrap()
+        );
+
+        let typing: WsClientMessage =
+            serde_json::from_str(r#"{"type":"typing","is_typing":true}"#).unwrap();
+        assert_eq!(typing, WsClientMessage::Typing { is_typing: true });
+        let echo: WsClientMessage =
+            serde_json::from_str(r#"{"type":"echo","sent_at":1700000000123}"#).unwrap();
+        assert_eq!(echo, WsClientMessage::Echo);
+        assert!(serde_json::from_str::<WsClientMessage>(r#"{"type":"dance"}"#).is_err());
+        assert!(serde_json::from_str::<WsClientMessage>(r#"{"is_typing":true}"#).is_err());
     }
 }
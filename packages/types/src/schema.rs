@@ -0,0 +1,103 @@
+use crate::{ChatMessage, GetMessagesResponse, HealthCheck, SendMessageRequest};
+use schemars::{schema::RootSchema, schema_for};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// JSON Schemas for the types sent over the wire, by type name. They follow the serde
+/// representation (field names, optional fields, flattening), not the TypeScript bindings.
+pub fn schemas() -> Vec<(&'static str, RootSchema)> {
+    vec![
+        ("ChatMessage", schema_for!(ChatMessage)),
+        ("SendMessageRequest", schema_for!(SendMessageRequest)),
+        ("GetMessagesResponse", schema_for!(GetMessagesResponse)),
+        ("HealthCheck", schema_for!(HealthCheck)),
+    ]
+}
+
+/// Write each schema to `<dir>/<Type>.schema.json`, returning the paths written
+pub fn export_schemas(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    schemas()
+        .into_iter()
+        .map(|(name, schema)| {
+            let path = dir.join(format!("{}.schema.json", name));
+            fs::write(&path, serde_json::to_string_pretty(&schema)?)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageCore, MessageFormat, MessageStatus, MessageVisibility};
+    use chrono::Utc;
+    use jsonschema::JSONSchema;
+    use serde_json::{json, Value};
+
+    fn compile(name: &str) -> JSONSchema {
+        let (_, schema) = schemas().into_iter().find(|(n, _)| *n == name).unwrap();
+        JSONSchema::compile(&serde_json::to_value(schema).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_chat_message_schema_validates_a_message() {
+        let message = ChatMessage {
+            core: MessageCore {
+                id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+                room_id: "general".to_string(),
+                user_id: "01ARZ3NDEKTSV4RRFFQ69G5FB1".to_string(),
+                username: "alice".to_string(),
+                message_text: "Hello!".to_string(),
+                created_at: Utc::now(),
+            },
+            client_message_id: None,
+            ephemeral: true,
+            expires_at: Some(Utc::now()),
+            status: MessageStatus::Broadcast,
+            format: MessageFormat::Plain,
+            client_created_at: None,
+            clock_skew_ms: None,
+            delivered_count: None,
+            client_seq: None,
+            visibility: MessageVisibility::Room,
+            to_user_id: None,
+            link_preview: None,
+            avatar_seed: None,
+            edited_at: None,
+        };
+        let schema = compile("ChatMessage");
+        let instance = serde_json::to_value(&message).unwrap();
+        assert!(schema.is_valid(&instance));
+
+        // The flattened core fields are required at the top level
+        let mut missing: Value = instance.clone();
+        missing.as_object_mut().unwrap().remove("message_text");
+        assert!(!schema.is_valid(&missing));
+        assert!(!schema.is_valid(&json!({ "core": instance })));
+    }
+
+    #[test]
+    fn test_optional_fields_may_be_omitted() {
+        let request = json!({
+            "room_id": "general",
+            "user_id": "01ARZ3NDEKTSV4RRFFQ69G5FB1",
+            "username": "alice",
+            "message_text": "Hi",
+        });
+        assert!(compile("SendMessageRequest").is_valid(&request));
+        assert!(compile("GetMessagesResponse")
+            .is_valid(&json!({ "room_id": "general", "messages": [] })));
+    }
+
+    #[test]
+    fn test_export_writes_one_file_per_type() {
+        let dir = std::env::temp_dir().join(format!("schemas-{}", std::process::id()));
+        let paths = export_schemas(&dir).unwrap();
+        assert_eq!(paths.len(), schemas().len());
+        assert!(dir.join("ChatMessage.schema.json").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
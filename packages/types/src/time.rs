@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// Earliest stored `ts` accepted: the Unix epoch. Nothing in the tables predates it.
+pub const MIN_TS_MILLIS: i64 = 0;
+
+/// Latest stored `ts` accepted: 9999-12-31T23:59:59.999Z, the last instant RFC 3339 (and so
+/// `created_at_iso`) can represent.
+pub const MAX_TS_MILLIS: i64 = 253_402_300_799_999;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeError {
+    // Epoch millis outside MIN_TS_MILLIS..=MAX_TS_MILLIS
+    OutOfRange(i64),
+}
+
+impl fmt::Display for TimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeError::OutOfRange(ms) => write!(f, "timestamp {}ms is out of range", ms),
+        }
+    }
+}
+
+impl std::error::Error for TimeError {}
+
+/// Convert a stored epoch-millis `ts` to a `DateTime`, rejecting values outside the supported
+/// range instead of letting them wrap or saturate
+pub fn ts_to_datetime(ms: i64) -> Result<DateTime<Utc>, TimeError> {
+    if !(MIN_TS_MILLIS..=MAX_TS_MILLIS).contains(&ms) {
+        return Err(TimeError::OutOfRange(ms));
+    }
+    DateTime::from_timestamp_millis(ms).ok_or(TimeError::OutOfRange(ms))
+}
+
+/// A stored message's creation time. `ts` wins; when it is corrupt, the `created_at_iso`
+/// written alongside it is used instead. Callers skip the message if both are unusable and
+/// never substitute the current time.
+pub fn stored_created_at(
+    ts: i64,
+    created_at_iso: Option<&str>,
+) -> Result<DateTime<Utc>, TimeError> {
+    ts_to_datetime(ts).or_else(|err| {
+        created_at_iso
+            .and_then(|iso| DateTime::parse_from_rfc3339(iso).ok())
+            .map(|created_at| created_at.with_timezone(&Utc))
+            .ok_or(err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_ts_converts() {
+        let created_at = ts_to_datetime(1_700_000_000_123).unwrap();
+        assert_eq!(created_at.timestamp_millis(), 1_700_000_000_123);
+        assert!(ts_to_datetime(MAX_TS_MILLIS).is_ok());
+    }
+
+    #[test]
+    fn test_absurd_ts_is_rejected() {
+        for ms in [-1, MAX_TS_MILLIS + 1, i64::MAX, i64::MIN] {
+            assert_eq!(ts_to_datetime(ms), Err(TimeError::OutOfRange(ms)));
+        }
+        assert_eq!(
+            stored_created_at(i64::MAX, None),
+            Err(TimeError::OutOfRange(i64::MAX))
+        );
+        assert!(stored_created_at(i64::MAX, Some("not a date")).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_ts_falls_back_to_iso() {
+        let created_at = stored_created_at(i64::MAX, Some("2024-05-01T12:30:00+02:00")).unwrap();
+        assert_eq!(created_at.to_rfc3339(), "2024-05-01T10:30:00+00:00");
+
+        // A usable ts is preferred over the iso copy
+        let created_at = stored_created_at(0, Some("2024-05-01T12:30:00Z")).unwrap();
+        assert_eq!(created_at.timestamp_millis(), 0);
+    }
+}